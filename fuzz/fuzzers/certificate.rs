@@ -0,0 +1,20 @@
+#![no_main]
+#[macro_use] extern crate libfuzzer_sys;
+extern crate rustls;
+
+use rustls::internal::msgs::handshake;
+use rustls::internal::msgs::codec::{Reader, Codec};
+
+fn try_type<T>(data: &[u8]) where T: Codec {
+    let mut rdr = Reader::init(data);
+    T::read(&mut rdr);
+}
+
+fuzz_target!(|data: &[u8]| {
+    try_type::<handshake::CertificatePayload>(data);
+    try_type::<handshake::CertificatePayloadTLS13>(data);
+    try_type::<handshake::CertificateEntry>(data);
+    try_type::<handshake::CertificateStatus>(data);
+    try_type::<handshake::CertificateRequestPayload>(data);
+    try_type::<handshake::CertificateRequestPayloadTLS13>(data);
+});
@@ -12,8 +12,7 @@ fuzz_target!(|data: &[u8]| {
     }
     dfm.has_pending();
 
-    while !dfm.frames.is_empty() {
-        let mut msg = dfm.frames.pop_front().unwrap();
+    while let Some(mut msg) = dfm.pop() {
         msg.decode_payload();
     }
 });
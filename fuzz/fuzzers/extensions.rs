@@ -0,0 +1,20 @@
+#![no_main]
+#[macro_use] extern crate libfuzzer_sys;
+extern crate rustls;
+
+use rustls::internal::msgs::handshake;
+use rustls::internal::msgs::codec::{Reader, Codec};
+
+fn try_type<T>(data: &[u8]) where T: Codec {
+    let mut rdr = Reader::init(data);
+    T::read(&mut rdr);
+}
+
+fuzz_target!(|data: &[u8]| {
+    try_type::<handshake::ClientExtension>(data);
+    try_type::<handshake::ServerExtension>(data);
+    try_type::<handshake::HelloRetryExtension>(data);
+    try_type::<handshake::CertificateExtension>(data);
+    try_type::<handshake::CertReqExtension>(data);
+    try_type::<handshake::NewSessionTicketExtension>(data);
+});
@@ -0,0 +1,15 @@
+#![no_main]
+#[macro_use] extern crate libfuzzer_sys;
+extern crate rustls;
+
+use rustls::internal::msgs::handshake::HandshakeMessagePayload;
+use rustls::internal::msgs::enums::ProtocolVersion;
+use rustls::internal::msgs::codec::Reader;
+
+fuzz_target!(|data: &[u8]| {
+    // Some messages (eg. Certificate) parse differently in TLS1.3.
+    for vers in &[ProtocolVersion::TLSv1_2, ProtocolVersion::TLSv1_3] {
+        let mut rdr = Reader::init(data);
+        HandshakeMessagePayload::read_version(&mut rdr, *vers);
+    }
+});
@@ -0,0 +1,56 @@
+#![no_main]
+#[macro_use] extern crate libfuzzer_sys;
+extern crate rustls;
+
+use rustls::internal::msgs::handshake;
+use rustls::internal::msgs::handshake::HandshakeMessagePayload;
+use rustls::internal::msgs::enums::ProtocolVersion;
+use rustls::internal::msgs::message::Message;
+use rustls::internal::msgs::codec::{Reader, Codec};
+
+// Whatever we accept, we must be able to encode, and the encoding
+// must read back as something which encodes the same way.  (The
+// first encoding needn't match the input: eg. some lengths are
+// recomputed.)
+
+fn check_encoding<F>(data: &[u8], read: F) where F: Fn(&[u8]) -> Option<Vec<u8>> {
+    if let Some(first) = read(data) {
+        let second = read(&first).expect("encoding did not read back");
+        assert_eq!(first, second);
+    }
+}
+
+fn try_type<T>(data: &[u8]) where T: Codec {
+    check_encoding(data, |buf| {
+        T::read(&mut Reader::init(buf)).map(|v| v.get_encoding())
+    });
+}
+
+fuzz_target!(|data: &[u8]| {
+    check_encoding(data, |buf| {
+        Message::read(&mut Reader::init(buf)).map(|mut msg| {
+            msg.decode_payload();
+            msg.get_encoding()
+        })
+    });
+
+    for vers in &[ProtocolVersion::TLSv1_2, ProtocolVersion::TLSv1_3] {
+        check_encoding(data, |buf| {
+            HandshakeMessagePayload::read_version(&mut Reader::init(buf), *vers)
+                .map(|hs| hs.get_encoding())
+        });
+    }
+
+    // Not ServerHelloPayload: it's read after the version and random,
+    // which HandshakeMessagePayload takes care of.
+    try_type::<handshake::ClientHelloPayload>(data);
+    try_type::<handshake::ClientExtension>(data);
+    try_type::<handshake::ServerExtension>(data);
+    try_type::<handshake::HelloRetryExtension>(data);
+    try_type::<handshake::CertificateExtension>(data);
+    try_type::<handshake::CertReqExtension>(data);
+    try_type::<handshake::NewSessionTicketExtension>(data);
+    try_type::<handshake::CertificatePayloadTLS13>(data);
+    try_type::<handshake::CertificateRequestPayloadTLS13>(data);
+    try_type::<handshake::NewSessionTicketPayloadTLS13>(data);
+});
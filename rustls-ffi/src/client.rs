@@ -0,0 +1,146 @@
+use rustls::{Certificate, ClientConfig, RootCertStore};
+
+use std::mem::ManuallyDrop;
+use std::ptr;
+use std::sync::Arc;
+
+use crate::error::rustls_result::{self, *};
+use crate::{rustls_slice_bytes, set_out, try_alpn_protocols, try_slice};
+
+/// Collects the settings for a `rustls_client_config`.
+///
+/// Each builder is used by a single thread, and is consumed by
+/// `rustls_client_config_builder_build` or freed with
+/// `rustls_client_config_builder_free`.
+pub struct rustls_client_config_builder {
+    roots: RootCertStore,
+    alpn_protocols: Vec<Vec<u8>>,
+}
+
+/// An immutable, reference-counted client configuration.  It may be
+/// shared between threads and used for any number of connections.
+pub struct rustls_client_config {
+    _private: [u8; 0],
+}
+
+/// Borrow the `ClientConfig` behind `config`, taking a new reference
+/// to it.
+pub(crate) unsafe fn clone_client_config(
+    config: *const rustls_client_config,
+) -> Option<Arc<ClientConfig>> {
+    if config.is_null() {
+        return None;
+    }
+    let arc = ManuallyDrop::new(Arc::from_raw(config as *const ClientConfig));
+    Some(Arc::clone(&arc))
+}
+
+/// Make a new client configuration builder, with no trusted roots.
+#[no_mangle]
+pub extern "C" fn rustls_client_config_builder_new() -> *mut rustls_client_config_builder {
+    ffi_panic_boundary! { ptr::null_mut(), {
+        Box::into_raw(Box::new(rustls_client_config_builder {
+            roots: RootCertStore::empty(),
+            alpn_protocols: Vec::new(),
+        }))
+    } }
+}
+
+/// Trust the DER-encoded certificate `der` (of `len` bytes) as a root
+/// when verifying servers.
+#[no_mangle]
+pub unsafe extern "C" fn rustls_client_config_builder_add_root_der(
+    builder: *mut rustls_client_config_builder,
+    der: *const u8,
+    len: usize,
+) -> rustls_result {
+    ffi_panic_boundary! { RUSTLS_RESULT_PANIC, {
+        let (builder, der) = match (builder.as_mut(), try_slice(der, len)) {
+            (Some(builder), Some(der)) => (builder, der),
+            _ => return RUSTLS_RESULT_NULL_PARAMETER,
+        };
+
+        match builder.roots.add(&Certificate(der.to_vec())) {
+            Ok(()) => RUSTLS_RESULT_OK,
+            Err(_) => RUSTLS_RESULT_CERTIFICATE_PARSE_ERROR,
+        }
+    } }
+}
+
+/// Offer the `count` ALPN protocols at `protocols`, in preference
+/// order.  This replaces any previously set.
+#[no_mangle]
+pub unsafe extern "C" fn rustls_client_config_builder_set_alpn_protocols(
+    builder: *mut rustls_client_config_builder,
+    protocols: *const rustls_slice_bytes,
+    count: usize,
+) -> rustls_result {
+    ffi_panic_boundary! { RUSTLS_RESULT_PANIC, {
+        match (builder.as_mut(), try_alpn_protocols(protocols, count)) {
+            (Some(builder), Some(protocols)) => {
+                builder.alpn_protocols = protocols;
+                RUSTLS_RESULT_OK
+            }
+            _ => RUSTLS_RESULT_NULL_PARAMETER,
+        }
+    } }
+}
+
+/// Turn `builder` into a client configuration, written to `config_out`.
+/// This uses rustls's safe default cipher suites, key exchange groups
+/// and protocol versions.  `builder` is consumed, even on error.
+#[no_mangle]
+pub unsafe extern "C" fn rustls_client_config_builder_build(
+    builder: *mut rustls_client_config_builder,
+    config_out: *mut *const rustls_client_config,
+) -> rustls_result {
+    ffi_panic_boundary! { RUSTLS_RESULT_PANIC, {
+        if builder.is_null() || config_out.is_null() {
+            return RUSTLS_RESULT_NULL_PARAMETER;
+        }
+        let builder = Box::from_raw(builder);
+
+        let mut config = match ClientConfig::builder()
+            .with_safe_default_cipher_suites()
+            .with_safe_default_kx_groups()
+            .with_safe_default_protocol_versions()
+        {
+            Ok(config) => config
+                .with_root_certificates(builder.roots, &[])
+                .with_no_client_auth(),
+            Err(err) => return rustls_result::from(&err),
+        };
+        config.alpn_protocols = builder.alpn_protocols;
+
+        set_out(
+            config_out,
+            Arc::into_raw(Arc::new(config)) as *const rustls_client_config,
+        );
+        RUSTLS_RESULT_OK
+    } }
+}
+
+/// Free a builder which was not passed to
+/// `rustls_client_config_builder_build`.  Null is ignored.
+#[no_mangle]
+pub unsafe extern "C" fn rustls_client_config_builder_free(
+    builder: *mut rustls_client_config_builder,
+) {
+    ffi_panic_boundary! { (), {
+        if !builder.is_null() {
+            drop(Box::from_raw(builder));
+        }
+    } }
+}
+
+/// Release this reference to `config`.  Connections made from it hold
+/// their own references, so this may be called while they are in use.
+/// Null is ignored.
+#[no_mangle]
+pub unsafe extern "C" fn rustls_client_config_free(config: *const rustls_client_config) {
+    ffi_panic_boundary! { (), {
+        if !config.is_null() {
+            drop(Arc::from_raw(config as *const ClientConfig));
+        }
+    } }
+}
@@ -0,0 +1,363 @@
+use rustls::{ClientSession, ServerName, ServerSession, Session};
+
+use std::convert::TryFrom;
+use std::ffi::CStr;
+use std::io::{self, Read, Write};
+use std::os::raw::{c_char, c_void};
+use std::ptr;
+
+use crate::client::{clone_client_config, rustls_client_config};
+use crate::error::rustls_result::{self, *};
+use crate::error::{rustls_io_result, EIO};
+use crate::server::{clone_server_config, rustls_server_config};
+use crate::{set_out, try_slice};
+
+/// A TLS client or server connection.
+///
+/// A connection is not internally synchronised: use it from one
+/// thread at a time.  Free it with `rustls_connection_free`.
+pub struct rustls_connection {
+    session: Box<dyn Session>,
+}
+
+/// Called by `rustls_connection_read_tls` to read up to `len` bytes of
+/// TLS data into `buf`.  Write the number of bytes read to `out_n` (zero
+/// at EOF) and return zero, or return an `errno` value on failure, such as
+/// `EAGAIN` for a non-blocking socket with nothing to read.
+pub type rustls_read_callback = Option<
+    unsafe extern "C" fn(
+        userdata: *mut c_void,
+        buf: *mut u8,
+        len: usize,
+        out_n: *mut usize,
+    ) -> rustls_io_result,
+>;
+
+/// Called by `rustls_connection_write_tls` to write up to `len` bytes of
+/// TLS data from `buf`.  Write the number of bytes written to `out_n` and
+/// return zero, or return an `errno` value on failure.
+pub type rustls_write_callback = Option<
+    unsafe extern "C" fn(
+        userdata: *mut c_void,
+        buf: *const u8,
+        len: usize,
+        out_n: *mut usize,
+    ) -> rustls_io_result,
+>;
+
+struct CallbackReader {
+    callback: unsafe extern "C" fn(*mut c_void, *mut u8, usize, *mut usize) -> rustls_io_result,
+    userdata: *mut c_void,
+}
+
+impl Read for CallbackReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut n = 0;
+        match unsafe { (self.callback)(self.userdata, buf.as_mut_ptr(), buf.len(), &mut n) } {
+            0 => Ok(n.min(buf.len())),
+            errno => Err(io::Error::from_raw_os_error(errno)),
+        }
+    }
+}
+
+struct CallbackWriter {
+    callback: unsafe extern "C" fn(*mut c_void, *const u8, usize, *mut usize) -> rustls_io_result,
+    userdata: *mut c_void,
+}
+
+impl Write for CallbackWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut n = 0;
+        match unsafe { (self.callback)(self.userdata, buf.as_ptr(), buf.len(), &mut n) } {
+            0 => Ok(n.min(buf.len())),
+            errno => Err(io::Error::from_raw_os_error(errno)),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+fn io_result(err: io::Error) -> rustls_io_result {
+    err.raw_os_error().unwrap_or(EIO)
+}
+
+fn new_connection(session: Box<dyn Session>) -> *mut rustls_connection {
+    Box::into_raw(Box::new(rustls_connection { session }))
+}
+
+/// Start a connection to the server named `server_name`, a NUL-terminated
+/// DNS name or IP address, using `config`.  The connection holds its own
+/// reference to `config`.  On success the new connection is written to
+/// `conn_out`.
+#[no_mangle]
+pub unsafe extern "C" fn rustls_client_connection_new(
+    config: *const rustls_client_config,
+    server_name: *const c_char,
+    conn_out: *mut *mut rustls_connection,
+) -> rustls_result {
+    ffi_panic_boundary! { RUSTLS_RESULT_PANIC, {
+        let config = match clone_client_config(config) {
+            Some(config) if !server_name.is_null() && !conn_out.is_null() => config,
+            _ => return RUSTLS_RESULT_NULL_PARAMETER,
+        };
+
+        let server_name = match CStr::from_ptr(server_name)
+            .to_str()
+            .ok()
+            .and_then(|name| ServerName::try_from(name).ok())
+        {
+            Some(server_name) => server_name,
+            None => return RUSTLS_RESULT_INVALID_DNS_NAME,
+        };
+
+        match ClientSession::new(&config, server_name) {
+            Ok(session) => {
+                set_out(conn_out, new_connection(Box::new(session)));
+                RUSTLS_RESULT_OK
+            }
+            Err(err) => rustls_result::from(&err),
+        }
+    } }
+}
+
+/// Start a connection from a client, using `config`.  The connection
+/// holds its own reference to `config`.  On success the new connection
+/// is written to `conn_out`.
+#[no_mangle]
+pub unsafe extern "C" fn rustls_server_connection_new(
+    config: *const rustls_server_config,
+    conn_out: *mut *mut rustls_connection,
+) -> rustls_result {
+    ffi_panic_boundary! { RUSTLS_RESULT_PANIC, {
+        match clone_server_config(config) {
+            Some(config) if !conn_out.is_null() => {
+                set_out(conn_out, new_connection(Box::new(ServerSession::new(&config))));
+                RUSTLS_RESULT_OK
+            }
+            _ => RUSTLS_RESULT_NULL_PARAMETER,
+        }
+    } }
+}
+
+/// Read TLS data from the network by calling `callback` once, passing it
+/// `userdata`.  The number of bytes read is written to `out_n`; zero means
+/// the peer closed the connection.  Returns zero, or the `errno` value from
+/// `callback`.  Call `rustls_connection_process_new_packets` after each
+/// successful read.
+#[no_mangle]
+pub unsafe extern "C" fn rustls_connection_read_tls(
+    conn: *mut rustls_connection,
+    callback: rustls_read_callback,
+    userdata: *mut c_void,
+    out_n: *mut usize,
+) -> rustls_io_result {
+    ffi_panic_boundary! { EIO, {
+        let (conn, callback) = match (conn.as_mut(), callback) {
+            (Some(conn), Some(callback)) if !out_n.is_null() => (conn, callback),
+            _ => return EIO,
+        };
+
+        let mut reader = CallbackReader { callback, userdata };
+        match conn.session.read_tls(&mut reader) {
+            Ok(n) => {
+                *out_n = n;
+                0
+            }
+            Err(err) => io_result(err),
+        }
+    } }
+}
+
+/// Write pending TLS data to the network by calling `callback` one or
+/// more times, passing it `userdata`.  The number of bytes written is
+/// written to `out_n`.  Returns zero, or the `errno` value from `callback`.
+#[no_mangle]
+pub unsafe extern "C" fn rustls_connection_write_tls(
+    conn: *mut rustls_connection,
+    callback: rustls_write_callback,
+    userdata: *mut c_void,
+    out_n: *mut usize,
+) -> rustls_io_result {
+    ffi_panic_boundary! { EIO, {
+        let (conn, callback) = match (conn.as_mut(), callback) {
+            (Some(conn), Some(callback)) if !out_n.is_null() => (conn, callback),
+            _ => return EIO,
+        };
+
+        let mut writer = CallbackWriter { callback, userdata };
+        match conn.session.write_tls(&mut writer) {
+            Ok(n) => {
+                *out_n = n;
+                0
+            }
+            Err(err) => io_result(err),
+        }
+    } }
+}
+
+/// Process TLS data previously read by `rustls_connection_read_tls`.
+/// On error the connection is unusable, and any alert for the peer
+/// is waiting to be written with `rustls_connection_write_tls`.
+#[no_mangle]
+pub unsafe extern "C" fn rustls_connection_process_new_packets(
+    conn: *mut rustls_connection,
+) -> rustls_result {
+    ffi_panic_boundary! { RUSTLS_RESULT_PANIC, {
+        let conn = match conn.as_mut() {
+            Some(conn) => conn,
+            None => return RUSTLS_RESULT_NULL_PARAMETER,
+        };
+
+        match conn.session.process_new_packets() {
+            Ok(()) => RUSTLS_RESULT_OK,
+            Err(err) => rustls_result::from(&err),
+        }
+    } }
+}
+
+/// Whether the connection wants to read TLS data from the network.
+#[no_mangle]
+pub unsafe extern "C" fn rustls_connection_wants_read(conn: *const rustls_connection) -> bool {
+    ffi_panic_boundary! { false, {
+        match conn.as_ref() {
+            Some(conn) => conn.session.wants_read(),
+            None => false,
+        }
+    } }
+}
+
+/// Whether the connection has TLS data to write to the network.
+#[no_mangle]
+pub unsafe extern "C" fn rustls_connection_wants_write(conn: *const rustls_connection) -> bool {
+    ffi_panic_boundary! { false, {
+        match conn.as_ref() {
+            Some(conn) => conn.session.wants_write(),
+            None => false,
+        }
+    } }
+}
+
+/// Whether the handshake is still in progress.
+#[no_mangle]
+pub unsafe extern "C" fn rustls_connection_is_handshaking(conn: *const rustls_connection) -> bool {
+    ffi_panic_boundary! { false, {
+        match conn.as_ref() {
+            Some(conn) => conn.session.is_handshaking(),
+            None => false,
+        }
+    } }
+}
+
+/// Write the negotiated ALPN protocol to `protocol_out` and its length to
+/// `len_out`.  If none was negotiated, they are set to NULL and zero.
+/// The protocol is borrowed from `conn`, and is valid until it is freed.
+#[no_mangle]
+pub unsafe extern "C" fn rustls_connection_get_alpn_protocol(
+    conn: *const rustls_connection,
+    protocol_out: *mut *const u8,
+    len_out: *mut usize,
+) -> rustls_result {
+    ffi_panic_boundary! { RUSTLS_RESULT_PANIC, {
+        let conn = match conn.as_ref() {
+            Some(conn) if !protocol_out.is_null() && !len_out.is_null() => conn,
+            _ => return RUSTLS_RESULT_NULL_PARAMETER,
+        };
+
+        match conn.session.get_alpn_protocol() {
+            Some(protocol) => {
+                *protocol_out = protocol.as_ptr();
+                *len_out = protocol.len();
+            }
+            None => {
+                *protocol_out = ptr::null();
+                *len_out = 0;
+            }
+        }
+        RUSTLS_RESULT_OK
+    } }
+}
+
+/// Send the `len` bytes of plaintext at `buf` to the peer.  They are
+/// encrypted once the handshake allows, and then written with
+/// `rustls_connection_write_tls`.  The number of bytes accepted is
+/// written to `out_n`.
+#[no_mangle]
+pub unsafe extern "C" fn rustls_connection_write(
+    conn: *mut rustls_connection,
+    buf: *const u8,
+    len: usize,
+    out_n: *mut usize,
+) -> rustls_result {
+    ffi_panic_boundary! { RUSTLS_RESULT_PANIC, {
+        let (conn, buf) = match (conn.as_mut(), try_slice(buf, len)) {
+            (Some(conn), Some(buf)) if !out_n.is_null() => (conn, buf),
+            _ => return RUSTLS_RESULT_NULL_PARAMETER,
+        };
+
+        match conn.session.write(buf) {
+            Ok(n) => {
+                *out_n = n;
+                RUSTLS_RESULT_OK
+            }
+            Err(_) => RUSTLS_RESULT_IO,
+        }
+    } }
+}
+
+/// Read up to `len` bytes of plaintext received from the peer into `buf`.
+/// The number of bytes read is written to `out_n`; zero means none is
+/// available yet.  Returns `RUSTLS_RESULT_CLOSE_NOTIFY` once the peer has
+/// cleanly closed the connection and all its data has been read.
+#[no_mangle]
+pub unsafe extern "C" fn rustls_connection_read(
+    conn: *mut rustls_connection,
+    buf: *mut u8,
+    len: usize,
+    out_n: *mut usize,
+) -> rustls_result {
+    ffi_panic_boundary! { RUSTLS_RESULT_PANIC, {
+        if buf.is_null() || out_n.is_null() {
+            return RUSTLS_RESULT_NULL_PARAMETER;
+        }
+        let conn = match conn.as_mut() {
+            Some(conn) => conn,
+            None => return RUSTLS_RESULT_NULL_PARAMETER,
+        };
+
+        let buf = std::slice::from_raw_parts_mut(buf, len);
+        match conn.session.read(buf) {
+            Ok(n) => {
+                *out_n = n;
+                RUSTLS_RESULT_OK
+            }
+            Err(err) if err.kind() == io::ErrorKind::ConnectionAborted => {
+                *out_n = 0;
+                RUSTLS_RESULT_CLOSE_NOTIFY
+            }
+            Err(_) => RUSTLS_RESULT_IO,
+        }
+    } }
+}
+
+/// Queue a close_notify alert, telling the peer that no more data will
+/// be sent.  Write it with `rustls_connection_write_tls`.
+#[no_mangle]
+pub unsafe extern "C" fn rustls_connection_send_close_notify(conn: *mut rustls_connection) {
+    ffi_panic_boundary! { (), {
+        if let Some(conn) = conn.as_mut() {
+            conn.session.send_close_notify();
+        }
+    } }
+}
+
+/// Free a connection.  Null is ignored.
+#[no_mangle]
+pub unsafe extern "C" fn rustls_connection_free(conn: *mut rustls_connection) {
+    ffi_panic_boundary! { (), {
+        if !conn.is_null() {
+            drop(Box::from_raw(conn));
+        }
+    } }
+}
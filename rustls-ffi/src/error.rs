@@ -0,0 +1,136 @@
+use rustls::{CertificateError, TlsError};
+
+use std::os::raw::c_int;
+
+/// The outcome of a call into rustls.
+///
+/// Values are stable, so they may be logged or compared numerically.
+/// `rustls_error` describes each one in English.
+#[repr(u32)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum rustls_result {
+    RUSTLS_RESULT_OK = 7000,
+    RUSTLS_RESULT_IO = 7001,
+    RUSTLS_RESULT_NULL_PARAMETER = 7002,
+    RUSTLS_RESULT_INVALID_DNS_NAME = 7003,
+    RUSTLS_RESULT_PANIC = 7004,
+    RUSTLS_RESULT_CERTIFICATE_PARSE_ERROR = 7005,
+    RUSTLS_RESULT_PRIVATE_KEY_PARSE_ERROR = 7006,
+    RUSTLS_RESULT_CLOSE_NOTIFY = 7008,
+    RUSTLS_RESULT_NO_CERTIFICATE_OR_KEY = 7009,
+
+    // From rustls::TlsError.
+    RUSTLS_RESULT_CORRUPT_MESSAGE = 7100,
+    RUSTLS_RESULT_NO_CERTIFICATES_PRESENTED = 7101,
+    RUSTLS_RESULT_DECRYPT_ERROR = 7102,
+    RUSTLS_RESULT_PEER_INCOMPATIBLE_ERROR = 7103,
+    RUSTLS_RESULT_PEER_MISBEHAVED_ERROR = 7104,
+    RUSTLS_RESULT_INAPPROPRIATE_MESSAGE = 7105,
+    RUSTLS_RESULT_INAPPROPRIATE_HANDSHAKE_MESSAGE = 7106,
+    RUSTLS_RESULT_ALERT_RECEIVED = 7107,
+    RUSTLS_RESULT_CERT_INVALID = 7108,
+    RUSTLS_RESULT_CERT_SCT_INVALID = 7109,
+    RUSTLS_RESULT_CERT_REVOKED = 7110,
+    RUSTLS_RESULT_OCSP_RESPONSE_INVALID = 7111,
+    RUSTLS_RESULT_FAILED_TO_GET_CURRENT_TIME = 7112,
+    RUSTLS_RESULT_FAILED_TO_GET_RANDOM_BYTES = 7113,
+    RUSTLS_RESULT_GENERAL = 7114,
+    RUSTLS_RESULT_HANDSHAKE_NOT_COMPLETE = 7115,
+    RUSTLS_RESULT_PEER_SENT_OVERSIZED_RECORD = 7116,
+    RUSTLS_RESULT_NO_APPLICATION_PROTOCOL = 7117,
+}
+
+use self::rustls_result::*;
+
+/// An `errno` value from an IO callback, or returned to one.
+/// Zero means success.
+pub type rustls_io_result = c_int;
+
+/// Used when an IO error carries no OS error code.
+pub(crate) const EIO: rustls_io_result = 5;
+
+impl From<&TlsError> for rustls_result {
+    fn from(err: &TlsError) -> Self {
+        match err {
+            TlsError::CorruptMessage | TlsError::CorruptMessagePayload(_) => {
+                RUSTLS_RESULT_CORRUPT_MESSAGE
+            }
+            TlsError::NoCertificatesPresented => RUSTLS_RESULT_NO_CERTIFICATES_PRESENTED,
+            TlsError::DecryptError => RUSTLS_RESULT_DECRYPT_ERROR,
+            TlsError::PeerIncompatibleError(_) => RUSTLS_RESULT_PEER_INCOMPATIBLE_ERROR,
+            TlsError::PeerMisbehavedError(_) => RUSTLS_RESULT_PEER_MISBEHAVED_ERROR,
+            TlsError::InappropriateMessage { .. } => RUSTLS_RESULT_INAPPROPRIATE_MESSAGE,
+            TlsError::InappropriateHandshakeMessage { .. } => {
+                RUSTLS_RESULT_INAPPROPRIATE_HANDSHAKE_MESSAGE
+            }
+            TlsError::AlertReceived { .. } => RUSTLS_RESULT_ALERT_RECEIVED,
+            TlsError::InvalidCertificate(CertificateError::Revoked) => RUSTLS_RESULT_CERT_REVOKED,
+            TlsError::InvalidCertificate(_) => RUSTLS_RESULT_CERT_INVALID,
+            TlsError::InvalidSCT(_) => RUSTLS_RESULT_CERT_SCT_INVALID,
+            TlsError::CRLError(_) => RUSTLS_RESULT_CERT_REVOKED,
+            TlsError::InvalidOCSPResponse(_) => RUSTLS_RESULT_OCSP_RESPONSE_INVALID,
+            TlsError::FailedToGetCurrentTime => RUSTLS_RESULT_FAILED_TO_GET_CURRENT_TIME,
+            TlsError::FailedToGetRandomBytes => RUSTLS_RESULT_FAILED_TO_GET_RANDOM_BYTES,
+            TlsError::General(_) => RUSTLS_RESULT_GENERAL,
+            TlsError::HandshakeNotComplete => RUSTLS_RESULT_HANDSHAKE_NOT_COMPLETE,
+            TlsError::PeerSentOversizedRecord => RUSTLS_RESULT_PEER_SENT_OVERSIZED_RECORD,
+            TlsError::NoApplicationProtocol => RUSTLS_RESULT_NO_APPLICATION_PROTOCOL,
+        }
+    }
+}
+
+impl rustls_result {
+    fn description(self) -> &'static str {
+        match self {
+            RUSTLS_RESULT_OK => "OK",
+            RUSTLS_RESULT_IO => "IO error",
+            RUSTLS_RESULT_NULL_PARAMETER => "a parameter was NULL",
+            RUSTLS_RESULT_INVALID_DNS_NAME => "server name was not a valid DNS name or IP address",
+            RUSTLS_RESULT_PANIC => "a Rust component panicked",
+            RUSTLS_RESULT_CERTIFICATE_PARSE_ERROR => "error parsing certificate",
+            RUSTLS_RESULT_PRIVATE_KEY_PARSE_ERROR => "error parsing private key",
+            RUSTLS_RESULT_CLOSE_NOTIFY => "peer sent close_notify alert",
+            RUSTLS_RESULT_NO_CERTIFICATE_OR_KEY => "no certificate chain or private key was given",
+            RUSTLS_RESULT_CORRUPT_MESSAGE => "received corrupt message",
+            RUSTLS_RESULT_NO_CERTIFICATES_PRESENTED => "peer sent no certificates",
+            RUSTLS_RESULT_DECRYPT_ERROR => "cannot decrypt peer's message",
+            RUSTLS_RESULT_PEER_INCOMPATIBLE_ERROR => "peer is incompatible",
+            RUSTLS_RESULT_PEER_MISBEHAVED_ERROR => "peer misbehaved",
+            RUSTLS_RESULT_INAPPROPRIATE_MESSAGE => "received unexpected message",
+            RUSTLS_RESULT_INAPPROPRIATE_HANDSHAKE_MESSAGE => "received unexpected handshake message",
+            RUSTLS_RESULT_ALERT_RECEIVED => "received fatal alert",
+            RUSTLS_RESULT_CERT_INVALID => "invalid certificate",
+            RUSTLS_RESULT_CERT_SCT_INVALID => "invalid certificate timestamp",
+            RUSTLS_RESULT_CERT_REVOKED => "certificate revoked",
+            RUSTLS_RESULT_OCSP_RESPONSE_INVALID => "invalid OCSP response",
+            RUSTLS_RESULT_FAILED_TO_GET_CURRENT_TIME => "failed to get current time",
+            RUSTLS_RESULT_FAILED_TO_GET_RANDOM_BYTES => "failed to get random bytes",
+            RUSTLS_RESULT_GENERAL => "unexpected error",
+            RUSTLS_RESULT_HANDSHAKE_NOT_COMPLETE => "handshake not complete",
+            RUSTLS_RESULT_PEER_SENT_OVERSIZED_RECORD => "peer sent excess record size",
+            RUSTLS_RESULT_NO_APPLICATION_PROTOCOL => "peer doesn't support any known protocol",
+        }
+    }
+}
+
+/// Write an English description of `result` to `buf`, which has room
+/// for `len` bytes.  The description is not NUL-terminated; its length
+/// is written to `out_n`.  If `buf` is too small the description is
+/// truncated.
+#[no_mangle]
+pub unsafe extern "C" fn rustls_error(
+    result: rustls_result,
+    buf: *mut u8,
+    len: usize,
+    out_n: *mut usize,
+) {
+    ffi_panic_boundary! { (), {
+        if buf.is_null() || out_n.is_null() {
+            return;
+        }
+        let description = result.description().as_bytes();
+        let n = description.len().min(len);
+        std::ptr::copy_nonoverlapping(description.as_ptr(), buf, n);
+        *out_n = n;
+    } }
+}
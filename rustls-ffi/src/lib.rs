@@ -0,0 +1,79 @@
+//! A C API for rustls.
+//!
+//! This exposes client and server configurations and connections
+//! to C as opaque handles.  The application moves TLS data between
+//! a connection and the network through read and write callbacks,
+//! so it keeps control of its own sockets and event loop.
+//!
+//! The C declarations are in `rustls.h`, alongside this file, which
+//! also documents the ownership and threading rules for each handle.
+//!
+//! Every function here catches panics at the FFI boundary: a panic
+//! is reported as `RUSTLS_RESULT_PANIC` (or a null/false return)
+//! rather than unwinding into C.
+
+// Opaque types and functions are named to suit C, not Rust.
+#![allow(non_camel_case_types)]
+// The safety contract for each function is documented in rustls.h.
+#![allow(clippy::missing_safety_doc)]
+
+use std::ptr;
+use std::slice;
+
+macro_rules! ffi_panic_boundary {
+    ($on_panic:expr, $body:block) => {
+        match std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| $body)) {
+            Ok(ret) => ret,
+            Err(_) => $on_panic,
+        }
+    };
+}
+
+mod client;
+mod connection;
+mod error;
+mod server;
+
+pub use crate::client::*;
+pub use crate::connection::*;
+pub use crate::error::*;
+pub use crate::server::*;
+
+/// A read-only view of a byte buffer owned by the caller.
+#[repr(C)]
+pub struct rustls_slice_bytes {
+    pub data: *const u8,
+    pub len: usize,
+}
+
+/// Borrow `len` bytes at `data`, or `None` if `data` is null.
+unsafe fn try_slice<'a>(data: *const u8, len: usize) -> Option<&'a [u8]> {
+    if data.is_null() {
+        None
+    } else {
+        Some(slice::from_raw_parts(data, len))
+    }
+}
+
+/// Copy the `count` buffers at `protocols` into owned ALPN protocol
+/// names, or `None` if any pointer is null.
+unsafe fn try_alpn_protocols(
+    protocols: *const rustls_slice_bytes,
+    count: usize,
+) -> Option<Vec<Vec<u8>>> {
+    if protocols.is_null() {
+        return None;
+    }
+
+    slice::from_raw_parts(protocols, count)
+        .iter()
+        .map(|proto| try_slice(proto.data, proto.len).map(|bytes| bytes.to_vec()))
+        .collect()
+}
+
+/// Write `value` to `out`, if `out` is not null.
+unsafe fn set_out<T>(out: *mut T, value: T) {
+    if !out.is_null() {
+        ptr::write(out, value);
+    }
+}
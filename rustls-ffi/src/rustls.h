@@ -0,0 +1,189 @@
+/*
+ * C API for rustls.
+ *
+ * Build the rustls-ffi crate to get librustls_ffi.a (or the shared
+ * library), and link it along with the system libraries that Rust
+ * needs on your platform (for example -lpthread -ldl -lm on Linux).
+ *
+ * Ownership
+ * ---------
+ * Builders are created with *_builder_new, configured, then consumed
+ * by *_builder_build (even if it fails) or released with
+ * *_builder_free.
+ *
+ * Configurations are immutable and reference-counted.  They may be
+ * shared between threads.  Each connection keeps its own reference to
+ * its configuration, so *_config_free may be called as soon as the
+ * last connection has been made.
+ *
+ * A connection must only be used by one thread at a time.  Release it
+ * with rustls_connection_free.
+ *
+ * Unless noted, pointer parameters must not be NULL; passing NULL
+ * gives RUSTLS_RESULT_NULL_PARAMETER.  *_free functions accept NULL.
+ *
+ * IO
+ * --
+ * rustls never touches the network itself.  Pass TLS data between
+ * the connection and your socket with rustls_connection_read_tls and
+ * rustls_connection_write_tls, which call back into your code.
+ * Callbacks return 0 on success, or an errno value such as EAGAIN,
+ * which is returned to the caller unchanged.
+ */
+
+#ifndef RUSTLS_H
+#define RUSTLS_H
+
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+typedef enum rustls_result {
+  RUSTLS_RESULT_OK = 7000,
+  RUSTLS_RESULT_IO = 7001,
+  RUSTLS_RESULT_NULL_PARAMETER = 7002,
+  RUSTLS_RESULT_INVALID_DNS_NAME = 7003,
+  RUSTLS_RESULT_PANIC = 7004,
+  RUSTLS_RESULT_CERTIFICATE_PARSE_ERROR = 7005,
+  RUSTLS_RESULT_PRIVATE_KEY_PARSE_ERROR = 7006,
+  RUSTLS_RESULT_CLOSE_NOTIFY = 7008,
+  RUSTLS_RESULT_NO_CERTIFICATE_OR_KEY = 7009,
+
+  /* TLS errors */
+  RUSTLS_RESULT_CORRUPT_MESSAGE = 7100,
+  RUSTLS_RESULT_NO_CERTIFICATES_PRESENTED = 7101,
+  RUSTLS_RESULT_DECRYPT_ERROR = 7102,
+  RUSTLS_RESULT_PEER_INCOMPATIBLE_ERROR = 7103,
+  RUSTLS_RESULT_PEER_MISBEHAVED_ERROR = 7104,
+  RUSTLS_RESULT_INAPPROPRIATE_MESSAGE = 7105,
+  RUSTLS_RESULT_INAPPROPRIATE_HANDSHAKE_MESSAGE = 7106,
+  RUSTLS_RESULT_ALERT_RECEIVED = 7107,
+  RUSTLS_RESULT_CERT_INVALID = 7108,
+  RUSTLS_RESULT_CERT_SCT_INVALID = 7109,
+  RUSTLS_RESULT_CERT_REVOKED = 7110,
+  RUSTLS_RESULT_OCSP_RESPONSE_INVALID = 7111,
+  RUSTLS_RESULT_FAILED_TO_GET_CURRENT_TIME = 7112,
+  RUSTLS_RESULT_FAILED_TO_GET_RANDOM_BYTES = 7113,
+  RUSTLS_RESULT_GENERAL = 7114,
+  RUSTLS_RESULT_HANDSHAKE_NOT_COMPLETE = 7115,
+  RUSTLS_RESULT_PEER_SENT_OVERSIZED_RECORD = 7116,
+  RUSTLS_RESULT_NO_APPLICATION_PROTOCOL = 7117,
+} rustls_result;
+
+/* An errno value; zero means success. */
+typedef int rustls_io_result;
+
+typedef struct rustls_client_config_builder rustls_client_config_builder;
+typedef struct rustls_client_config rustls_client_config;
+typedef struct rustls_server_config_builder rustls_server_config_builder;
+typedef struct rustls_server_config rustls_server_config;
+typedef struct rustls_connection rustls_connection;
+
+/* A read-only view of a byte buffer owned by the caller. */
+typedef struct rustls_slice_bytes {
+  const uint8_t *data;
+  size_t len;
+} rustls_slice_bytes;
+
+/* Read up to len bytes into buf, storing the count in *out_n
+ * (0 at EOF).  Return 0, or an errno value. */
+typedef rustls_io_result (*rustls_read_callback)(void *userdata,
+                                                 uint8_t *buf,
+                                                 size_t len,
+                                                 size_t *out_n);
+
+/* Write up to len bytes from buf, storing the count in *out_n.
+ * Return 0, or an errno value. */
+typedef rustls_io_result (*rustls_write_callback)(void *userdata,
+                                                  const uint8_t *buf,
+                                                  size_t len,
+                                                  size_t *out_n);
+
+/* Write an English description of result (not NUL-terminated) to buf,
+ * truncated to len bytes.  Its length is stored in *out_n. */
+void rustls_error(rustls_result result, uint8_t *buf, size_t len, size_t *out_n);
+
+/* Client configuration.  The safe default cipher suites, key exchange
+ * groups and protocol versions are used. */
+rustls_client_config_builder *rustls_client_config_builder_new(void);
+rustls_result rustls_client_config_builder_add_root_der(rustls_client_config_builder *builder,
+                                                        const uint8_t *der,
+                                                        size_t len);
+rustls_result rustls_client_config_builder_set_alpn_protocols(rustls_client_config_builder *builder,
+                                                              const rustls_slice_bytes *protocols,
+                                                              size_t count);
+rustls_result rustls_client_config_builder_build(rustls_client_config_builder *builder,
+                                                 const rustls_client_config **config_out);
+void rustls_client_config_builder_free(rustls_client_config_builder *builder);
+void rustls_client_config_free(const rustls_client_config *config);
+
+/* Server configuration.  Add the end-entity certificate first, then
+ * any intermediates.  Client certificates are not requested. */
+rustls_server_config_builder *rustls_server_config_builder_new(void);
+rustls_result rustls_server_config_builder_add_cert_der(rustls_server_config_builder *builder,
+                                                        const uint8_t *der,
+                                                        size_t len);
+rustls_result rustls_server_config_builder_set_private_key_der(rustls_server_config_builder *builder,
+                                                               const uint8_t *der,
+                                                               size_t len);
+rustls_result rustls_server_config_builder_set_alpn_protocols(rustls_server_config_builder *builder,
+                                                              const rustls_slice_bytes *protocols,
+                                                              size_t count);
+rustls_result rustls_server_config_builder_build(rustls_server_config_builder *builder,
+                                                 const rustls_server_config **config_out);
+void rustls_server_config_builder_free(rustls_server_config_builder *builder);
+void rustls_server_config_free(const rustls_server_config *config);
+
+/* Connections.  server_name is a NUL-terminated DNS name or IP address. */
+rustls_result rustls_client_connection_new(const rustls_client_config *config,
+                                           const char *server_name,
+                                           rustls_connection **conn_out);
+rustls_result rustls_server_connection_new(const rustls_server_config *config,
+                                           rustls_connection **conn_out);
+
+/* Read TLS data by calling callback once; then call
+ * rustls_connection_process_new_packets. */
+rustls_io_result rustls_connection_read_tls(rustls_connection *conn,
+                                            rustls_read_callback callback,
+                                            void *userdata,
+                                            size_t *out_n);
+/* Write pending TLS data by calling callback one or more times. */
+rustls_io_result rustls_connection_write_tls(rustls_connection *conn,
+                                             rustls_write_callback callback,
+                                             void *userdata,
+                                             size_t *out_n);
+/* On error the connection is unusable; write out any pending alert. */
+rustls_result rustls_connection_process_new_packets(rustls_connection *conn);
+
+bool rustls_connection_wants_read(const rustls_connection *conn);
+bool rustls_connection_wants_write(const rustls_connection *conn);
+bool rustls_connection_is_handshaking(const rustls_connection *conn);
+
+/* The negotiated ALPN protocol, borrowed from conn; NULL and 0 if none. */
+rustls_result rustls_connection_get_alpn_protocol(const rustls_connection *conn,
+                                                  const uint8_t **protocol_out,
+                                                  size_t *len_out);
+
+/* Plaintext.  rustls_connection_read stores 0 in *out_n when nothing is
+ * available yet, and returns RUSTLS_RESULT_CLOSE_NOTIFY once the peer has
+ * cleanly closed the connection. */
+rustls_result rustls_connection_write(rustls_connection *conn,
+                                      const uint8_t *buf,
+                                      size_t len,
+                                      size_t *out_n);
+rustls_result rustls_connection_read(rustls_connection *conn,
+                                     uint8_t *buf,
+                                     size_t len,
+                                     size_t *out_n);
+void rustls_connection_send_close_notify(rustls_connection *conn);
+void rustls_connection_free(rustls_connection *conn);
+
+#ifdef __cplusplus
+} /* extern "C" */
+#endif
+
+#endif /* RUSTLS_H */
@@ -0,0 +1,184 @@
+use rustls::sign;
+use rustls::{Certificate, PrivateKey, ServerConfig};
+
+use std::mem::ManuallyDrop;
+use std::ptr;
+use std::sync::Arc;
+
+use crate::error::rustls_result::{self, *};
+use crate::{rustls_slice_bytes, set_out, try_alpn_protocols, try_slice};
+
+/// Collects the settings for a `rustls_server_config`.
+///
+/// Each builder is used by a single thread, and is consumed by
+/// `rustls_server_config_builder_build` or freed with
+/// `rustls_server_config_builder_free`.
+pub struct rustls_server_config_builder {
+    cert_chain: Vec<Certificate>,
+    private_key: Option<PrivateKey>,
+    alpn_protocols: Vec<Vec<u8>>,
+}
+
+/// An immutable, reference-counted server configuration.  It may be
+/// shared between threads and used for any number of connections.
+pub struct rustls_server_config {
+    _private: [u8; 0],
+}
+
+/// Borrow the `ServerConfig` behind `config`, taking a new reference
+/// to it.
+pub(crate) unsafe fn clone_server_config(
+    config: *const rustls_server_config,
+) -> Option<Arc<ServerConfig>> {
+    if config.is_null() {
+        return None;
+    }
+    let arc = ManuallyDrop::new(Arc::from_raw(config as *const ServerConfig));
+    Some(Arc::clone(&arc))
+}
+
+/// Make a new server configuration builder, with no certificate.
+#[no_mangle]
+pub extern "C" fn rustls_server_config_builder_new() -> *mut rustls_server_config_builder {
+    ffi_panic_boundary! { ptr::null_mut(), {
+        Box::into_raw(Box::new(rustls_server_config_builder {
+            cert_chain: Vec::new(),
+            private_key: None,
+            alpn_protocols: Vec::new(),
+        }))
+    } }
+}
+
+/// Append the DER-encoded certificate `der` (of `len` bytes) to the
+/// chain presented to clients.  Add the end-entity certificate first,
+/// followed by any intermediates.
+#[no_mangle]
+pub unsafe extern "C" fn rustls_server_config_builder_add_cert_der(
+    builder: *mut rustls_server_config_builder,
+    der: *const u8,
+    len: usize,
+) -> rustls_result {
+    ffi_panic_boundary! { RUSTLS_RESULT_PANIC, {
+        match (builder.as_mut(), try_slice(der, len)) {
+            (Some(builder), Some(der)) => {
+                builder.cert_chain.push(Certificate(der.to_vec()));
+                RUSTLS_RESULT_OK
+            }
+            _ => RUSTLS_RESULT_NULL_PARAMETER,
+        }
+    } }
+}
+
+/// Use the DER-encoded PKCS#8, PKCS#1 or SEC1 private key `der` (of
+/// `len` bytes), which must match the end-entity certificate.
+#[no_mangle]
+pub unsafe extern "C" fn rustls_server_config_builder_set_private_key_der(
+    builder: *mut rustls_server_config_builder,
+    der: *const u8,
+    len: usize,
+) -> rustls_result {
+    ffi_panic_boundary! { RUSTLS_RESULT_PANIC, {
+        let (builder, der) = match (builder.as_mut(), try_slice(der, len)) {
+            (Some(builder), Some(der)) => (builder, der),
+            _ => return RUSTLS_RESULT_NULL_PARAMETER,
+        };
+
+        let private_key = PrivateKey(der.to_vec());
+        if sign::any_supported_type(&private_key).is_err() {
+            return RUSTLS_RESULT_PRIVATE_KEY_PARSE_ERROR;
+        }
+        builder.private_key = Some(private_key);
+        RUSTLS_RESULT_OK
+    } }
+}
+
+/// Accept the `count` ALPN protocols at `protocols`, in preference
+/// order.  This replaces any previously set.
+#[no_mangle]
+pub unsafe extern "C" fn rustls_server_config_builder_set_alpn_protocols(
+    builder: *mut rustls_server_config_builder,
+    protocols: *const rustls_slice_bytes,
+    count: usize,
+) -> rustls_result {
+    ffi_panic_boundary! { RUSTLS_RESULT_PANIC, {
+        match (builder.as_mut(), try_alpn_protocols(protocols, count)) {
+            (Some(builder), Some(protocols)) => {
+                builder.alpn_protocols = protocols;
+                RUSTLS_RESULT_OK
+            }
+            _ => RUSTLS_RESULT_NULL_PARAMETER,
+        }
+    } }
+}
+
+/// Turn `builder` into a server configuration, written to `config_out`.
+/// This uses rustls's safe default cipher suites, key exchange groups
+/// and protocol versions, and does not ask for client certificates.
+/// `builder` is consumed, even on error.
+#[no_mangle]
+pub unsafe extern "C" fn rustls_server_config_builder_build(
+    builder: *mut rustls_server_config_builder,
+    config_out: *mut *const rustls_server_config,
+) -> rustls_result {
+    ffi_panic_boundary! { RUSTLS_RESULT_PANIC, {
+        if builder.is_null() || config_out.is_null() {
+            return RUSTLS_RESULT_NULL_PARAMETER;
+        }
+        let rustls_server_config_builder {
+            cert_chain,
+            private_key,
+            alpn_protocols,
+        } = *Box::from_raw(builder);
+
+        let private_key = match private_key {
+            Some(private_key) if !cert_chain.is_empty() => private_key,
+            _ => return RUSTLS_RESULT_NO_CERTIFICATE_OR_KEY,
+        };
+
+        let config = ServerConfig::builder()
+            .with_safe_default_cipher_suites()
+            .with_safe_default_kx_groups()
+            .with_safe_default_protocol_versions()
+            .and_then(|config| {
+                config
+                    .with_no_client_auth()
+                    .with_single_cert(cert_chain, private_key)
+            });
+        let mut config = match config {
+            Ok(config) => config,
+            Err(err) => return rustls_result::from(&err),
+        };
+        config.alpn_protocols = alpn_protocols;
+
+        set_out(
+            config_out,
+            Arc::into_raw(Arc::new(config)) as *const rustls_server_config,
+        );
+        RUSTLS_RESULT_OK
+    } }
+}
+
+/// Free a builder which was not passed to
+/// `rustls_server_config_builder_build`.  Null is ignored.
+#[no_mangle]
+pub unsafe extern "C" fn rustls_server_config_builder_free(
+    builder: *mut rustls_server_config_builder,
+) {
+    ffi_panic_boundary! { (), {
+        if !builder.is_null() {
+            drop(Box::from_raw(builder));
+        }
+    } }
+}
+
+/// Release this reference to `config`.  Connections made from it hold
+/// their own references, so this may be called while they are in use.
+/// Null is ignored.
+#[no_mangle]
+pub unsafe extern "C" fn rustls_server_config_free(config: *const rustls_server_config) {
+    ffi_panic_boundary! { (), {
+        if !config.is_null() {
+            drop(Arc::from_raw(config as *const ServerConfig));
+        }
+    } }
+}
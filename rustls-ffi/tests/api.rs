@@ -0,0 +1,349 @@
+//! Exercises the C API from Rust, in the way a C program would use it.
+
+use rustls_ffi::rustls_result::*;
+use rustls_ffi::*;
+
+use std::ffi::CString;
+use std::io::BufReader;
+use std::os::raw::c_void;
+use std::ptr;
+use std::slice;
+
+fn load_der(path: &str) -> Vec<Vec<u8>> {
+    let pem = std::fs::read(format!("../test-ca/rsa/{}", path)).unwrap();
+    let mut reader = BufReader::new(&pem[..]);
+    if path.ends_with(".key") {
+        rustls_pemfile::pkcs8_private_keys(&mut reader).unwrap()
+    } else {
+        rustls_pemfile::certs(&mut reader).unwrap()
+    }
+}
+
+const ALPN_H2: &[u8] = b"h2";
+
+fn alpn_h2() -> [rustls_slice_bytes; 1] {
+    [rustls_slice_bytes {
+        data: ALPN_H2.as_ptr(),
+        len: ALPN_H2.len(),
+    }]
+}
+
+fn make_client_config() -> *const rustls_client_config {
+    let ca = &load_der("ca.cert")[0];
+    let alpn = alpn_h2();
+    let mut config = ptr::null();
+    unsafe {
+        let builder = rustls_client_config_builder_new();
+        assert_eq!(
+            rustls_client_config_builder_add_root_der(builder, ca.as_ptr(), ca.len()),
+            RUSTLS_RESULT_OK
+        );
+        assert_eq!(
+            rustls_client_config_builder_set_alpn_protocols(builder, alpn.as_ptr(), alpn.len()),
+            RUSTLS_RESULT_OK
+        );
+        assert_eq!(
+            rustls_client_config_builder_build(builder, &mut config),
+            RUSTLS_RESULT_OK
+        );
+    }
+    config
+}
+
+fn make_server_config() -> *const rustls_server_config {
+    let chain = load_der("end.fullchain");
+    let key = &load_der("end.key")[0];
+    let alpn = alpn_h2();
+    let mut config = ptr::null();
+    unsafe {
+        let builder = rustls_server_config_builder_new();
+        for cert in &chain {
+            assert_eq!(
+                rustls_server_config_builder_add_cert_der(builder, cert.as_ptr(), cert.len()),
+                RUSTLS_RESULT_OK
+            );
+        }
+        assert_eq!(
+            rustls_server_config_builder_set_private_key_der(builder, key.as_ptr(), key.len()),
+            RUSTLS_RESULT_OK
+        );
+        assert_eq!(
+            rustls_server_config_builder_set_alpn_protocols(builder, alpn.as_ptr(), alpn.len()),
+            RUSTLS_RESULT_OK
+        );
+        assert_eq!(
+            rustls_server_config_builder_build(builder, &mut config),
+            RUSTLS_RESULT_OK
+        );
+    }
+    config
+}
+
+fn make_pair(server_name: &str) -> (*mut rustls_connection, *mut rustls_connection) {
+    let client_config = make_client_config();
+    let server_config = make_server_config();
+    let server_name = CString::new(server_name).unwrap();
+    let mut client = ptr::null_mut();
+    let mut server = ptr::null_mut();
+
+    unsafe {
+        assert_eq!(
+            rustls_client_connection_new(client_config, server_name.as_ptr(), &mut client),
+            RUSTLS_RESULT_OK
+        );
+        assert_eq!(
+            rustls_server_connection_new(server_config, &mut server),
+            RUSTLS_RESULT_OK
+        );
+
+        // the connections keep their configs alive
+        rustls_client_config_free(client_config);
+        rustls_server_config_free(server_config);
+    }
+
+    (client, server)
+}
+
+unsafe extern "C" fn write_to_vec(
+    userdata: *mut c_void,
+    buf: *const u8,
+    len: usize,
+    out_n: *mut usize,
+) -> rustls_io_result {
+    let vec = &mut *(userdata as *mut Vec<u8>);
+    vec.extend_from_slice(slice::from_raw_parts(buf, len));
+    *out_n = len;
+    0
+}
+
+unsafe extern "C" fn read_from_vec(
+    userdata: *mut c_void,
+    buf: *mut u8,
+    len: usize,
+    out_n: *mut usize,
+) -> rustls_io_result {
+    let vec = &mut *(userdata as *mut Vec<u8>);
+    let n = len.min(vec.len());
+    ptr::copy_nonoverlapping(vec.as_ptr(), buf, n);
+    vec.drain(..n);
+    *out_n = n;
+    0
+}
+
+unsafe extern "C" fn read_would_block(
+    _userdata: *mut c_void,
+    _buf: *mut u8,
+    _len: usize,
+    _out_n: *mut usize,
+) -> rustls_io_result {
+    11 // EAGAIN
+}
+
+/// Move all pending TLS data from `from` to `to`, and process it.
+fn transfer(from: *mut rustls_connection, to: *mut rustls_connection) -> rustls_result {
+    let mut buf = Vec::new();
+    let mut n = 0;
+
+    unsafe {
+        while rustls_connection_wants_write(from) {
+            let userdata = &mut buf as *mut Vec<u8> as *mut c_void;
+            assert_eq!(
+                rustls_connection_write_tls(from, Some(write_to_vec), userdata, &mut n),
+                0
+            );
+        }
+
+        while !buf.is_empty() {
+            let userdata = &mut buf as *mut Vec<u8> as *mut c_void;
+            assert_eq!(
+                rustls_connection_read_tls(to, Some(read_from_vec), userdata, &mut n),
+                0
+            );
+            let result = rustls_connection_process_new_packets(to);
+            if result != RUSTLS_RESULT_OK {
+                return result;
+            }
+        }
+    }
+
+    RUSTLS_RESULT_OK
+}
+
+fn do_handshake(client: *mut rustls_connection, server: *mut rustls_connection) -> rustls_result {
+    unsafe {
+        while rustls_connection_is_handshaking(client) || rustls_connection_is_handshaking(server) {
+            let result = transfer(client, server);
+            if result != RUSTLS_RESULT_OK {
+                return result;
+            }
+            let result = transfer(server, client);
+            if result != RUSTLS_RESULT_OK {
+                return result;
+            }
+        }
+    }
+    RUSTLS_RESULT_OK
+}
+
+fn alpn_protocol(conn: *const rustls_connection) -> Option<Vec<u8>> {
+    let mut protocol = ptr::null();
+    let mut len = 0;
+    unsafe {
+        assert_eq!(
+            rustls_connection_get_alpn_protocol(conn, &mut protocol, &mut len),
+            RUSTLS_RESULT_OK
+        );
+        if protocol.is_null() {
+            None
+        } else {
+            Some(slice::from_raw_parts(protocol, len).to_vec())
+        }
+    }
+}
+
+#[test]
+fn handshake_and_exchange_data() {
+    let (client, server) = make_pair("testserver.com");
+    assert_eq!(do_handshake(client, server), RUSTLS_RESULT_OK);
+    assert_eq!(alpn_protocol(client), Some(ALPN_H2.to_vec()));
+    assert_eq!(alpn_protocol(server), Some(ALPN_H2.to_vec()));
+
+    let mut n = 0;
+    let mut buf = [0u8; 16];
+    unsafe {
+        assert_eq!(
+            rustls_connection_write(client, b"hello".as_ptr(), 5, &mut n),
+            RUSTLS_RESULT_OK
+        );
+        assert_eq!(n, 5);
+        assert_eq!(transfer(client, server), RUSTLS_RESULT_OK);
+
+        assert_eq!(
+            rustls_connection_read(server, buf.as_mut_ptr(), buf.len(), &mut n),
+            RUSTLS_RESULT_OK
+        );
+        assert_eq!(&buf[..n], b"hello");
+        assert_eq!(
+            rustls_connection_read(server, buf.as_mut_ptr(), buf.len(), &mut n),
+            RUSTLS_RESULT_OK
+        );
+        assert_eq!(n, 0);
+
+        rustls_connection_send_close_notify(client);
+        assert_eq!(transfer(client, server), RUSTLS_RESULT_OK);
+        assert_eq!(
+            rustls_connection_read(server, buf.as_mut_ptr(), buf.len(), &mut n),
+            RUSTLS_RESULT_CLOSE_NOTIFY
+        );
+
+        rustls_connection_free(client);
+        rustls_connection_free(server);
+    }
+}
+
+#[test]
+fn client_rejects_wrong_server_name() {
+    let (client, server) = make_pair("not-the-right-hostname.com");
+    assert_eq!(do_handshake(client, server), RUSTLS_RESULT_CERT_INVALID);
+    unsafe {
+        rustls_connection_free(client);
+        rustls_connection_free(server);
+    }
+}
+
+#[test]
+fn read_tls_passes_errno_through() {
+    let (client, server) = make_pair("testserver.com");
+    let mut n = 0;
+    unsafe {
+        assert_eq!(
+            rustls_connection_read_tls(server, Some(read_would_block), ptr::null_mut(), &mut n),
+            11
+        );
+        rustls_connection_free(client);
+        rustls_connection_free(server);
+    }
+}
+
+#[test]
+fn invalid_server_name_is_rejected() {
+    let config = make_client_config();
+    let server_name = CString::new("not a name").unwrap();
+    let mut conn = ptr::null_mut();
+    unsafe {
+        assert_eq!(
+            rustls_client_connection_new(config, server_name.as_ptr(), &mut conn),
+            RUSTLS_RESULT_INVALID_DNS_NAME
+        );
+        assert!(conn.is_null());
+        rustls_client_config_free(config);
+    }
+}
+
+#[test]
+fn null_parameters_are_rejected() {
+    let mut config = ptr::null();
+    let mut conn = ptr::null_mut();
+    unsafe {
+        assert_eq!(
+            rustls_client_config_builder_build(ptr::null_mut(), &mut config),
+            RUSTLS_RESULT_NULL_PARAMETER
+        );
+        assert_eq!(
+            rustls_server_connection_new(ptr::null(), &mut conn),
+            RUSTLS_RESULT_NULL_PARAMETER
+        );
+        assert_eq!(
+            rustls_connection_process_new_packets(ptr::null_mut()),
+            RUSTLS_RESULT_NULL_PARAMETER
+        );
+        assert!(!rustls_connection_wants_read(ptr::null()));
+        rustls_connection_free(ptr::null_mut());
+        rustls_client_config_free(ptr::null());
+        rustls_server_config_builder_free(ptr::null_mut());
+    }
+}
+
+#[test]
+fn server_config_needs_certificate_and_key() {
+    let mut config = ptr::null();
+    unsafe {
+        let builder = rustls_server_config_builder_new();
+        assert_eq!(
+            rustls_server_config_builder_set_private_key_der(builder, b"junk".as_ptr(), 4),
+            RUSTLS_RESULT_PRIVATE_KEY_PARSE_ERROR
+        );
+        assert_eq!(
+            rustls_server_config_builder_build(builder, &mut config),
+            RUSTLS_RESULT_NO_CERTIFICATE_OR_KEY
+        );
+        assert!(config.is_null());
+    }
+}
+
+#[test]
+fn bad_root_certificate_is_rejected() {
+    unsafe {
+        let builder = rustls_client_config_builder_new();
+        assert_eq!(
+            rustls_client_config_builder_add_root_der(builder, b"junk".as_ptr(), 4),
+            RUSTLS_RESULT_CERTIFICATE_PARSE_ERROR
+        );
+        rustls_client_config_builder_free(builder);
+    }
+}
+
+#[test]
+fn errors_are_described() {
+    let mut buf = [0u8; 64];
+    let mut n = 0;
+    unsafe {
+        rustls_error(RUSTLS_RESULT_CERT_INVALID, buf.as_mut_ptr(), buf.len(), &mut n);
+    }
+    assert_eq!(&buf[..n], b"invalid certificate");
+
+    unsafe {
+        rustls_error(RUSTLS_RESULT_CERT_INVALID, buf.as_mut_ptr(), 7, &mut n);
+    }
+    assert_eq!(&buf[..n], b"invalid");
+}
@@ -1,4 +1,6 @@
+use std::convert::TryInto;
 use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::{Arc, Mutex};
 
 use mio;
@@ -19,7 +21,6 @@ extern crate serde_derive;
 use docopt::Docopt;
 
 use rustls;
-use webpki;
 use webpki_roots;
 
 use rustls::{Session, RootCertStore};
@@ -38,14 +39,14 @@ struct TlsClient {
 impl TlsClient {
     fn new(
         sock: TcpStream,
-        hostname: webpki::DNSNameRef<'_>,
+        server_name: rustls::ServerName,
         cfg: Arc<rustls::ClientConfig>,
     ) -> TlsClient {
         TlsClient {
             socket: sock,
             closing: false,
             clean_closure: false,
-            tls_session: rustls::ClientSession::new(&cfg, hostname).unwrap(),
+            tls_session: rustls::ClientSession::new(&cfg, server_name).unwrap(),
         }
     }
 
@@ -60,20 +61,42 @@ impl TlsClient {
         if ev.is_writable() {
             self.do_write();
         }
+    }
 
-        if self.is_closed() {
-            println!("Connection closed");
-            process::exit(if self.clean_closure { 0 } else { 1 });
+    /// Send `data` as early data, if this session can.  Returns
+    /// whether any was sent.
+    fn send_early_data(&mut self, data: &[u8]) -> bool {
+        match self.tls_session.early_data() {
+            Some(mut early_data) => {
+                let len = early_data.write(data).unwrap();
+                println!("Sending {} of {} bytes as early data", len, data.len());
+                len > 0
+            }
+            None => {
+                println!("Cannot send early data on this connection");
+                false
+            }
         }
     }
 
-    fn read_source_to_end(&mut self, rd: &mut dyn io::Read) -> io::Result<usize> {
-        let mut buf = Vec::new();
-        let len = rd.read_to_end(&mut buf)?;
-        self.tls_session
-            .write_all(&buf)
-            .unwrap();
-        Ok(len)
+    /// Do IO until the connection closes.  Returns whether it
+    /// closed cleanly.
+    fn run(&mut self) -> bool {
+        let mut poll = mio::Poll::new().unwrap();
+        let mut events = mio::Events::with_capacity(32);
+        self.register(poll.registry());
+
+        while !self.is_closed() {
+            poll.poll(&mut events, None).unwrap();
+
+            for ev in events.iter() {
+                self.ready(&ev);
+            }
+            self.reregister(poll.registry());
+        }
+
+        println!("Connection closed");
+        self.clean_closure
     }
 
     /// We're ready to do a read.
@@ -202,6 +225,7 @@ impl io::Read for TlsClient {
 struct PersistCache {
     cache: Mutex<collections::HashMap<Vec<u8>, Vec<u8>>>,
     filename: Option<String>,
+    tickets_received: AtomicUsize,
 }
 
 impl PersistCache {
@@ -211,6 +235,7 @@ impl PersistCache {
         let cache = PersistCache {
             cache: Mutex::new(collections::HashMap::new()),
             filename: filename.clone(),
+            tickets_received: AtomicUsize::new(0),
         };
         if cache.filename.is_some() {
             cache.load();
@@ -218,6 +243,31 @@ impl PersistCache {
         cache
     }
 
+    /// How many of the sessions stored so far came with a ticket.
+    fn tickets_received(&self) -> usize {
+        self.tickets_received
+            .load(Ordering::SeqCst)
+    }
+
+    /// Count `value` in `tickets_received` if it's a session with
+    /// a ticket.
+    fn count_ticket(&self, key: &[u8], value: &[u8]) {
+        use rustls::internal::msgs::codec::Reader;
+        use rustls::internal::msgs::persist::ClientSessionValue;
+
+        if !key.starts_with(b"session") {
+            return;
+        }
+
+        let session = ClientSessionValue::read(&mut Reader::init(value), rustls::ALL_CIPHERSUITES);
+        if let Some(session) = session {
+            if !session.ticket.0.is_empty() {
+                self.tickets_received
+                    .fetch_add(1, Ordering::SeqCst);
+            }
+        }
+    }
+
     /// If we have a filename, save the cache contents to it.
     fn save(&self) {
         use rustls::internal::msgs::base::PayloadU16;
@@ -267,6 +317,7 @@ impl PersistCache {
 impl rustls::StoresClientSessions for PersistCache {
     /// put: insert into in-memory cache, and perhaps persist to disk.
     fn put(&self, key: Vec<u8>, value: Vec<u8>) -> bool {
+        self.count_ticket(&key, &value);
         self.cache
             .lock()
             .unwrap()
@@ -313,6 +364,13 @@ Options:
     --proto PROTOCOL    Send ALPN extension containing PROTOCOL.
                         May be used multiple times to offer several protocols.
     --cache CACHE       Save session cache to file CACHE.
+    --resume N          After the first connection closes, reconnect N
+                        more times, resuming the cached session
+                        [default: 0].
+    --early-data FILE   Enable TLS1.3 early data, and send the contents
+                        of FILE as early data on connections which can.
+    --expect-ticket     Fail unless the server issues a ticket on each
+                        connection.
     --no-tickets        Disable session ticket support.
     --no-sni            Disable server name indication support.
     --insecure          Disable certificate verification.
@@ -333,6 +391,9 @@ struct Args {
     flag_mtu: Option<usize>,
     flag_cafile: Option<String>,
     flag_cache: Option<String>,
+    flag_resume: usize,
+    flag_early_data: Option<String>,
+    flag_expect_ticket: bool,
     flag_no_tickets: bool,
     flag_no_sni: bool,
     flag_insecure: bool,
@@ -386,13 +447,13 @@ fn lookup_suites(suites: &[String]) -> Vec<&'static rustls::SupportedCipherSuite
 }
 
 /// Make a vector of protocol versions named in `versions`
-fn lookup_versions(versions: &[String]) -> Vec<rustls::ProtocolVersion> {
+fn lookup_versions(versions: &[String]) -> Vec<&'static rustls::SupportedProtocolVersion> {
     let mut out = Vec::new();
 
     for vname in versions {
         let version = match vname.as_ref() {
-            "1.2" => rustls::ProtocolVersion::TLSv1_2,
-            "1.3" => rustls::ProtocolVersion::TLSv1_3,
+            "1.2" => &rustls::version::TLS12,
+            "1.3" => &rustls::version::TLS13,
             _ => panic!(
                 "cannot look up version '{}', valid are '1.2' and '1.3'",
                 vname
@@ -441,7 +502,6 @@ fn load_key_and_cert(config: &mut rustls::ClientConfig, keyfile: &str, certsfile
 #[cfg(feature = "dangerous_configuration")]
 mod danger {
     use super::rustls;
-    use webpki;
 
     pub struct NoCertificateVerification {}
 
@@ -450,7 +510,7 @@ mod danger {
             &self,
             _end_entity: &rustls::Certificate,
             _intermediates: &[rustls::Certificate],
-            _dns_name: webpki::DNSNameRef<'_>,
+            _server_name: &rustls::ServerName,
             _scts: &mut dyn Iterator<Item=&[u8]>,
             _ocsp: &[u8],
             _now: std::time::SystemTime,
@@ -475,8 +535,9 @@ fn apply_dangerous_options(args: &Args, _: &mut rustls::ClientConfig) {
     }
 }
 
-/// Build a `ClientConfig` from our arguments
-fn make_config(args: &Args) -> Arc<rustls::ClientConfig> {
+/// Build a `ClientConfig` from our arguments, storing sessions
+/// in `persist`.
+fn make_config(args: &Args, persist: Arc<PersistCache>) -> Arc<rustls::ClientConfig> {
     let mut root_store = RootCertStore::empty();
 
     if args.flag_cafile.is_some() {
@@ -499,18 +560,22 @@ fn make_config(args: &Args) -> Arc<rustls::ClientConfig> {
     }
 
     if !args.flag_protover.is_empty() {
-        config.versions = lookup_versions(&args.flag_protover);
+        let versions = lookup_versions(&args.flag_protover);
+        config.set_protocol_versions(&versions);
     }
 
     if args.flag_no_tickets {
         config.enable_tickets = false;
+        config.enable_tls13_resumption = false;
     }
 
     if args.flag_no_sni {
         config.enable_sni = false;
     }
 
-    let persist = Arc::new(PersistCache::new(&args.flag_cache));
+    if args.flag_early_data.is_some() {
+        config.enable_early_data = true;
+    }
 
     config.set_protocols(
         &args
@@ -559,38 +624,80 @@ fn main() {
     let port = args.flag_port.unwrap_or(443);
     let addr = lookup_ipv4(args.arg_hostname.as_str(), port);
 
-    let config = make_config(&args);
-
-    let sock = TcpStream::connect(addr).unwrap();
-    let dns_name = webpki::DNSNameRef::try_from_ascii_str(&args.arg_hostname).unwrap();
-    let mut tlsclient = TlsClient::new(sock, dns_name, config);
-
-    if args.flag_http {
-        let httpreq = format!(
+    let persist = Arc::new(PersistCache::new(&args.flag_cache));
+    let config = make_config(&args, persist.clone());
+    let server_name: rustls::ServerName = args
+        .arg_hostname
+        .as_str()
+        .try_into()
+        .expect("invalid DNS name");
+
+    let request = if args.flag_http {
+        format!(
             "GET / HTTP/1.0\r\nHost: {}\r\nConnection: \
                                close\r\nAccept-Encoding: identity\r\n\r\n",
             args.arg_hostname
-        );
-        tlsclient
-            .write_all(httpreq.as_bytes())
-            .unwrap();
+        )
+        .into_bytes()
     } else {
-        let mut stdin = io::stdin();
-        tlsclient
-            .read_source_to_end(&mut stdin)
+        let mut stdin = Vec::new();
+        io::stdin()
+            .read_to_end(&mut stdin)
             .unwrap();
-    }
+        stdin
+    };
 
-    let mut poll = mio::Poll::new().unwrap();
-    let mut events = mio::Events::with_capacity(32);
-    tlsclient.register(poll.registry());
+    let early_data = args
+        .flag_early_data
+        .as_ref()
+        .map(|filename| fs::read(filename).expect("cannot read early data file"));
 
-    loop {
-        poll.poll(&mut events, None).unwrap();
+    // Reporting on resumption is only interesting when we're testing it.
+    let report = args.flag_resume > 0 || early_data.is_some() || args.flag_expect_ticket;
+
+    for attempt in 0..=args.flag_resume {
+        if attempt > 0 {
+            println!("Reconnecting ({} of {})", attempt, args.flag_resume);
+        }
+
+        let sock = TcpStream::connect(addr).unwrap();
+        let mut tlsclient = TlsClient::new(sock, server_name.clone(), config.clone());
+        let tickets_before = persist.tickets_received();
+
+        let sent_early_data = match early_data {
+            Some(ref data) => tlsclient.send_early_data(data),
+            None => false,
+        };
+
+        tlsclient.write_all(&request).unwrap();
+        let clean_closure = tlsclient.run();
+
+        if report {
+            match tlsclient.tls_session.handshake_kind() {
+                Some(rustls::HandshakeKind::Resumed) => println!("Session resumed"),
+                Some(_) => println!("Session not resumed"),
+                None => println!("Handshake not completed"),
+            }
+        }
+
+        if sent_early_data {
+            if tlsclient
+                .tls_session
+                .is_early_data_accepted()
+            {
+                println!("Early data accepted");
+            } else {
+                println!("Early data rejected");
+            }
+        }
+
+        if args.flag_expect_ticket && persist.tickets_received() == tickets_before {
+            println!("No ticket received");
+            process::exit(1);
+        }
 
-        for ev in events.iter() {
-            tlsclient.ready(&ev);
-            tlsclient.reregister(poll.registry());
+        if !clean_closure {
+            process::exit(1);
         }
     }
 }
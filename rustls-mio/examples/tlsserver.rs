@@ -36,8 +36,8 @@ enum ServerMode {
     /// Write back received bytes
     Echo,
 
-    /// Do one read, then write a bodged HTTP response and
-    /// cleanly close the connection.
+    /// Answer each HTTP request with a fixed response, keeping
+    /// the connection open unless the client asks otherwise.
     Http,
 
     /// Forward traffic to/from given port on localhost.
@@ -52,16 +52,23 @@ struct TlsServer {
     next_id: usize,
     tls_config: Arc<rustls::ServerConfig>,
     mode: ServerMode,
+    proxy_protocol: bool,
 }
 
 impl TlsServer {
-    fn new(server: TcpListener, mode: ServerMode, cfg: Arc<rustls::ServerConfig>) -> TlsServer {
+    fn new(
+        server: TcpListener,
+        mode: ServerMode,
+        proxy_protocol: bool,
+        cfg: Arc<rustls::ServerConfig>,
+    ) -> TlsServer {
         TlsServer {
             server,
             connections: HashMap::new(),
             next_id: 2,
             tls_config: cfg,
             mode,
+            proxy_protocol,
         }
     }
 
@@ -77,7 +84,8 @@ impl TlsServer {
                     let token = mio::Token(self.next_id);
                     self.next_id += 1;
 
-                    let mut connection = Connection::new(socket, token, mode, tls_session);
+                    let mut connection =
+                        Connection::new(socket, token, mode, self.proxy_protocol, tls_session);
                     connection.register(registry);
                     self.connections
                         .insert(token, connection);
@@ -123,7 +131,9 @@ struct Connection {
     mode: ServerMode,
     tls_session: rustls::ServerSession,
     back: Option<TcpStream>,
-    sent_http_response: bool,
+    proxy_header: Option<Vec<u8>>,
+    http_request: Vec<u8>,
+    http_closed: bool,
 }
 
 /// Open a plaintext TCP-level connection for forwarded connections.
@@ -153,11 +163,151 @@ fn try_read(r: io::Result<usize>) -> io::Result<Option<usize>> {
     }
 }
 
+/// The longest HTTP request head we'll buffer.
+const MAX_HTTP_REQUEST_HEAD: usize = 8192;
+
+/// What we need to know about an HTTP request to answer it.
+struct HttpRequest {
+    /// Whether the client wants the connection kept open afterwards.
+    keep_alive: bool,
+
+    /// The length of the request body, which we ignore.
+    body_len: usize,
+}
+
+/// Parse the request line and headers of an HTTP/1.x request, which
+/// end with an empty line.  Returns None if it's malformed.
+fn parse_http_request(head: &[u8]) -> Option<HttpRequest> {
+    let head = std::str::from_utf8(head).ok()?;
+    let mut lines = head.split("\r\n");
+
+    let mut request_line = lines.next()?.split(' ');
+    let (_method, _target) = (request_line.next()?, request_line.next()?);
+    let mut keep_alive = match request_line.next()? {
+        "HTTP/1.1" => true,
+        "HTTP/1.0" => false,
+        _ => return None,
+    };
+
+    let mut body_len = 0;
+    for line in lines.filter(|line| !line.is_empty()) {
+        let mut header = line.splitn(2, ':');
+        let name = header.next()?.trim();
+        let value = header.next()?.trim();
+        if name.eq_ignore_ascii_case("connection") {
+            if value.eq_ignore_ascii_case("close") {
+                keep_alive = false;
+            } else if value.eq_ignore_ascii_case("keep-alive") {
+                keep_alive = true;
+            }
+        } else if name.eq_ignore_ascii_case("content-length") {
+            body_len = value.parse().ok()?;
+        }
+    }
+
+    Some(HttpRequest {
+        keep_alive,
+        body_len,
+    })
+}
+
+type ProxyHeaderResult = Result<Option<(usize, Option<net::SocketAddr>)>, &'static str>;
+
+const PROXY_V1_PREFIX: &[u8] = b"PROXY ";
+const PROXY_V2_SIGNATURE: &[u8] = b"\r\n\r\n\0\r\nQUIT\n";
+
+/// Parse the HAProxy PROXY protocol header, of version 1 or 2, from
+/// the start of `buf`.
+///
+/// This returns None if `buf` doesn't yet hold all of it.  Otherwise
+/// it returns the length of the header, and the address of the client
+/// it gives -- which is None if the proxy doesn't say.
+fn parse_proxy_header(buf: &[u8]) -> ProxyHeaderResult {
+    if buf.starts_with(PROXY_V1_PREFIX) {
+        parse_proxy_v1(buf)
+    } else if buf.starts_with(PROXY_V2_SIGNATURE) {
+        parse_proxy_v2(buf)
+    } else if PROXY_V1_PREFIX.starts_with(buf) || PROXY_V2_SIGNATURE.starts_with(buf) {
+        Ok(None)
+    } else {
+        Err("no PROXY protocol header")
+    }
+}
+
+fn parse_proxy_v1(buf: &[u8]) -> ProxyHeaderResult {
+    // The header is one line of at most 107 bytes.
+    let end = match buf
+        .windows(2)
+        .position(|crlf| crlf == b"\r\n")
+    {
+        Some(end) => end,
+        None if buf.len() < 107 => return Ok(None),
+        None => return Err("PROXY header too long"),
+    };
+
+    let line = std::str::from_utf8(&buf[..end]).map_err(|_| "PROXY header isn't text")?;
+    let fields = line.split(' ').collect::<Vec<_>>();
+    let source = match fields[..] {
+        ["PROXY", "UNKNOWN", ..] => None,
+        ["PROXY", "TCP4", addr, _, port, _] | ["PROXY", "TCP6", addr, _, port, _] => {
+            let addr = addr
+                .parse()
+                .map_err(|_| "bad PROXY source address")?;
+            let port = port
+                .parse()
+                .map_err(|_| "bad PROXY source port")?;
+            Some(net::SocketAddr::new(addr, port))
+        }
+        _ => return Err("bad PROXY header"),
+    };
+
+    Ok(Some((end + 2, source)))
+}
+
+fn parse_proxy_v2(buf: &[u8]) -> ProxyHeaderResult {
+    if buf.len() < 16 {
+        return Ok(None);
+    }
+
+    let len = 16 + u16::from_be_bytes([buf[14], buf[15]]) as usize;
+    if buf.len() < len {
+        return Ok(None);
+    }
+
+    if buf[12] >> 4 != 2 {
+        return Err("unsupported PROXY protocol version");
+    }
+
+    let addrs = &buf[16..len];
+    let source = match (buf[12] & 0x0f, buf[13] >> 4) {
+        // LOCAL: the proxy's own connection, such as a health check.
+        (0, _) => None,
+        (1, 1) if addrs.len() >= 12 => {
+            let addr = net::Ipv4Addr::new(addrs[0], addrs[1], addrs[2], addrs[3]);
+            let port = u16::from_be_bytes([addrs[8], addrs[9]]);
+            Some(net::SocketAddr::new(addr.into(), port))
+        }
+        (1, 2) if addrs.len() >= 36 => {
+            let mut octets = [0u8; 16];
+            octets.copy_from_slice(&addrs[..16]);
+            let addr = net::Ipv6Addr::from(octets);
+            let port = u16::from_be_bytes([addrs[32], addrs[33]]);
+            Some(net::SocketAddr::new(addr.into(), port))
+        }
+        (1, 1) | (1, 2) => return Err("PROXY addresses too short"),
+        (1, _) => None,
+        _ => return Err("unsupported PROXY command"),
+    };
+
+    Ok(Some((len, source)))
+}
+
 impl Connection {
     fn new(
         socket: TcpStream,
         token: mio::Token,
         mode: ServerMode,
+        proxy_protocol: bool,
         tls_session: rustls::ServerSession,
     ) -> Connection {
         let back = open_back(&mode);
@@ -169,7 +319,13 @@ impl Connection {
             mode,
             tls_session,
             back,
-            sent_http_response: false,
+            proxy_header: if proxy_protocol {
+                Some(Vec::new())
+            } else {
+                None
+            },
+            http_request: Vec::new(),
+            http_closed: false,
         }
     }
 
@@ -179,9 +335,14 @@ impl Connection {
         // see if that yielded new plaintext.  Then
         // see if the backend is readable too.
         if ev.is_readable() {
-            self.do_tls_read();
-            self.try_plain_read();
-            self.try_back_read();
+            if self.proxy_header.is_some() {
+                self.read_proxy_header();
+            }
+            if self.proxy_header.is_none() && !self.closing {
+                self.do_tls_read();
+                self.try_plain_read();
+                self.try_back_read();
+            }
         }
 
         if ev.is_writable() {
@@ -233,6 +394,60 @@ impl Connection {
             return;
         }
 
+        self.process_tls();
+    }
+
+    /// Read the PROXY protocol header which precedes the TLS data,
+    /// and log the client address it gives.
+    fn read_proxy_header(&mut self) {
+        let mut buf = [0u8; 256];
+        while let Some(header) = self.proxy_header.as_mut() {
+            let len = match try_read(self.socket.read(&mut buf)) {
+                Ok(Some(0)) => {
+                    debug!("eof");
+                    self.closing = true;
+                    return;
+                }
+                Ok(Some(len)) => len,
+                Ok(None) => return,
+                Err(err) => {
+                    error!("read error {:?}", err);
+                    self.closing = true;
+                    return;
+                }
+            };
+
+            header.extend_from_slice(&buf[..len]);
+            let (used, source) = match parse_proxy_header(header) {
+                Ok(Some(parsed)) => parsed,
+                Ok(None) => continue,
+                Err(err) => {
+                    error!("cannot parse PROXY header: {}", err);
+                    self.closing = true;
+                    return;
+                }
+            };
+
+            info!("PROXY header gives client address {:?}", source);
+            let rest = header.split_off(used);
+            self.proxy_header = None;
+
+            // Whatever followed the header is the start of the TLS data.
+            let mut rd = &rest[..];
+            while !rd.is_empty() {
+                if let Err(err) = self.tls_session.read_tls(&mut rd) {
+                    error!("read error {:?}", err);
+                    self.closing = true;
+                    return;
+                }
+            }
+            if !rest.is_empty() {
+                self.process_tls();
+            }
+        }
+    }
+
+    fn process_tls(&mut self) {
         // Process newly-received TLS messages.
         let processed = self.tls_session.process_new_packets();
         if processed.is_err() {
@@ -304,7 +519,8 @@ impl Connection {
                 self.tls_session.write_all(buf).unwrap();
             }
             ServerMode::Http => {
-                self.send_http_response_once();
+                self.http_request.extend_from_slice(buf);
+                self.answer_http_requests();
             }
             ServerMode::Forward(_) => {
                 self.back
@@ -316,14 +532,57 @@ impl Connection {
         }
     }
 
-    fn send_http_response_once(&mut self) {
-        let response =
-            b"HTTP/1.0 200 OK\r\nConnection: close\r\n\r\nHello world from rustls tlsserver\r\n";
-        if !self.sent_http_response {
-            self.tls_session
-                .write_all(response)
-                .unwrap();
-            self.sent_http_response = true;
+    /// Answer each complete HTTP request received so far.
+    fn answer_http_requests(&mut self) {
+        while !self.http_closed {
+            let head_len = match self
+                .http_request
+                .windows(4)
+                .position(|end| end == b"\r\n\r\n")
+            {
+                Some(pos) => pos + 4,
+                None if self.http_request.len() > MAX_HTTP_REQUEST_HEAD => {
+                    self.send_http_response("431 Request Header Fields Too Large", false);
+                    return;
+                }
+                None => return,
+            };
+
+            let request = match parse_http_request(&self.http_request[..head_len]) {
+                Some(request) => request,
+                None => {
+                    self.send_http_response("400 Bad Request", false);
+                    return;
+                }
+            };
+
+            if self.http_request.len() < head_len + request.body_len {
+                return;
+            }
+
+            self.http_request
+                .drain(..head_len + request.body_len);
+            self.send_http_response("200 OK", request.keep_alive);
+        }
+    }
+
+    /// Send an HTTP response with the given `status`, and close the
+    /// session afterwards unless `keep_alive`.
+    fn send_http_response(&mut self, status: &str, keep_alive: bool) {
+        let body = "Hello world from rustls tlsserver\r\n";
+        let response = format!(
+            "HTTP/1.1 {}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: {}\r\n\r\n{}",
+            status,
+            body.len(),
+            if keep_alive { "keep-alive" } else { "close" },
+            body
+        );
+        self.tls_session
+            .write_all(response.as_bytes())
+            .unwrap();
+
+        if !keep_alive {
+            self.http_closed = true;
             self.tls_session.send_close_notify();
         }
     }
@@ -403,8 +662,9 @@ Runs a TLS server on :PORT.  The default PORT is 443.
 
 `echo' mode means the server echoes received data on each connection.
 
-`http' mode means the server blindly sends a HTTP response on each
-connection.
+`http' mode means the server answers each HTTP request on a connection
+with a fixed response, keeping the connection open for more unless the
+client asks it not to.
 
 `forward' means the server forwards plaintext to a connection made to
 localhost:fport.
@@ -438,6 +698,9 @@ Options:
                         authentication.
     --resumption        Support session resumption.
     --tickets           Support tickets.
+    --proxy-protocol    Expect each connection to start with a HAProxy
+                        PROXY protocol header (version 1 or 2), and log
+                        the client address it gives.
     --protover VERSION  Disable default TLS version list, and use
                         VERSION instead.  May be used multiple times.
     --suite SUITE       Disable default cipher suite list, and use
@@ -466,6 +729,7 @@ struct Args {
     flag_require_auth: bool,
     flag_resumption: bool,
     flag_tickets: bool,
+    flag_proxy_protocol: bool,
     arg_fport: Option<u16>,
 }
 
@@ -496,13 +760,13 @@ fn lookup_suites(suites: &[String]) -> Vec<&'static rustls::SupportedCipherSuite
 }
 
 /// Make a vector of protocol versions named in `versions`
-fn lookup_versions(versions: &[String]) -> Vec<rustls::ProtocolVersion> {
+fn lookup_versions(versions: &[String]) -> Vec<&'static rustls::SupportedProtocolVersion> {
     let mut out = Vec::new();
 
     for vname in versions {
         let version = match vname.as_ref() {
-            "1.2" => rustls::ProtocolVersion::TLSv1_2,
-            "1.3" => rustls::ProtocolVersion::TLSv1_3,
+            "1.2" => &rustls::version::TLS12,
+            "1.3" => &rustls::version::TLS13,
             _ => panic!(
                 "cannot look up version '{}', valid are '1.2' and '1.3'",
                 vname
@@ -591,7 +855,8 @@ fn make_config(args: &Args) -> Arc<rustls::ServerConfig> {
     }
 
     if !args.flag_protover.is_empty() {
-        config.versions = lookup_versions(&args.flag_protover);
+        let versions = lookup_versions(&args.flag_protover);
+        config.set_protocol_versions(&versions);
     }
 
     if args.flag_resumption {
@@ -647,7 +912,7 @@ fn main() {
         ServerMode::Forward(args.arg_fport.expect("fport required"))
     };
 
-    let mut tlsserv = TlsServer::new(listener, mode, config);
+    let mut tlsserv = TlsServer::new(listener, mode, args.flag_proxy_protocol, config);
 
     let mut events = mio::Events::with_capacity(256);
     loop {
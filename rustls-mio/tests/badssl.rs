@@ -18,7 +18,7 @@ mod online {
         polite();
         connect("cbc.badssl.com")
             .fails()
-            .expect(r"TLS error: AlertReceived\(HandshakeFailure\)")
+            .expect(r"TLS error: AlertReceived \{ description: HandshakeFailure, fatal: true \}")
             .go()
             .unwrap();
     }
@@ -28,7 +28,7 @@ mod online {
         polite();
         connect("rc4.badssl.com")
             .fails()
-            .expect(r"TLS error: AlertReceived\(HandshakeFailure\)")
+            .expect(r"TLS error: AlertReceived \{ description: HandshakeFailure, fatal: true \}")
             .go()
             .unwrap();
     }
@@ -38,7 +38,7 @@ mod online {
         polite();
         connect("expired.badssl.com")
             .fails()
-            .expect(r"TLS error: WebPKIError\(CertExpired, ValidateServerCert\)")
+            .expect(r"TLS error: InvalidCertificate\(Expired\)")
             .go()
             .unwrap();
     }
@@ -48,7 +48,7 @@ mod online {
         polite();
         connect("wrong.host.badssl.com")
             .fails()
-            .expect(r"TLS error: WebPKIError\(CertNotValidForName, ValidateForDNSName\)")
+            .expect(r#"TLS error: InvalidCertificate\(NameMismatch \{ expected: "wrong.host.badssl.com""#)
             .go()
             .unwrap();
     }
@@ -58,7 +58,7 @@ mod online {
         polite();
         connect("self-signed.badssl.com")
             .fails()
-            .expect(r"TLS error: WebPKIError\((UnknownIssuer|CertExpired), ValidateServerCert\)")
+            .expect(r"TLS error: InvalidCertificate\((UnknownIssuer|Expired)\)")
             .go()
             .unwrap();
     }
@@ -68,7 +68,7 @@ mod online {
         polite();
         connect("dh2048.badssl.com")
             .fails()
-            .expect(r"TLS error: AlertReceived\(HandshakeFailure\)")
+            .expect(r"TLS error: AlertReceived \{ description: HandshakeFailure, fatal: true \}")
             .go()
             .unwrap();
     }
@@ -133,7 +133,7 @@ mod online {
         polite();
         connect("sha1-2016.badssl.com")
             .fails()
-            .expect(r"TLS error: WebPKIError\(CertExpired, ValidateServerCert\)")
+            .expect(r"TLS error: InvalidCertificate\(Expired\)")
             .go()
             .unwrap();
     }
@@ -450,7 +450,7 @@ impl TlsClient {
 
         let output = process::Command::new(tlsclient_find())
             .args(&args)
-            .env("SSLKEYLOGFILE", "./sslkeylogfile.txt")
+            .env("SSLKEYLOGFILE", env::temp_dir().join("rustls-mio-sslkeylogfile.txt"))
             .output()
             .unwrap_or_else(|e| panic!("failed to execute: {}", e));
 
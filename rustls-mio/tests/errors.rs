@@ -22,7 +22,7 @@ fn no_ecdhe() {
         .verbose()
         .fails()
         .expect_log("TLS alert received:")
-        .expect(r"TLS error: AlertReceived\(HandshakeFailure\)")
+        .expect(r"TLS error: AlertReceived \{ description: HandshakeFailure, fatal: true \}")
         .go();
 }
 
@@ -39,6 +39,6 @@ fn tls11_only() {
         .verbose()
         .fails()
         .expect_log("TLS alert received:")
-        .expect(r"TLS error: AlertReceived\((HandshakeFailure|ProtocolVersion|InternalError)\)")
+        .expect(r"TLS error: AlertReceived \{ description: (HandshakeFailure|ProtocolVersion|InternalError), fatal: true \}")
         .go();
 }
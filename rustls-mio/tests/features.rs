@@ -144,7 +144,7 @@ fn client_auth_by_client_with_ecdsa_suite() {
         )
         .expect_log("Got CertificateRequest")
         .expect_log("Attempting client auth")
-        .expect(r"AlertReceived\(UnknownCA\)")
+        .expect(r"AlertReceived \{ description: UnknownCA, fatal: true \}")
         .fails()
         .go();
 
@@ -176,7 +176,7 @@ fn client_auth_by_client_with_eddsa_suite() {
         )
         .expect_log("Got CertificateRequest")
         .expect_log("Attempting client auth")
-        .expect(r"AlertReceived\(UnknownCA\)")
+        .expect(r"AlertReceived \{ description: UnknownCA, fatal: true \}")
         .fails()
         .go();
 
@@ -220,7 +220,7 @@ fn client_auth_required_but_unsupported() {
         .client()
         .expect_log("Got CertificateRequest")
         .expect_log("Client auth requested but no cert/sigscheme available")
-        .expect(r"TLS error: AlertReceived\(HandshakeFailure\)")
+        .expect(r"TLS error: AlertReceived \{ description: HandshakeFailure, fatal: true \}")
         .fails()
         .go();
 
@@ -486,7 +486,7 @@ fn send_sni() {
     server
         .client()
         .fails()
-        .expect(r"TLS error: AlertReceived\(UnrecognisedName\)")
+        .expect(r"TLS error: AlertReceived \{ description: UnrecognisedName, fatal: true \}")
         .go();
 }
 
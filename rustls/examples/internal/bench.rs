@@ -3,6 +3,7 @@
 // Note: we don't use any of the standard 'cargo bench', 'test::Bencher',
 // etc. because it's unstable at the time of writing.
 
+use std::convert::TryInto;
 use std::env;
 use std::fs;
 use std::io::{self, Read, Write};
@@ -13,7 +14,6 @@ use rustls;
 use rustls::ClientSessionMemoryCache;
 use rustls::NoClientSessionStorage;
 use rustls::NoServerSessionStorage;
-use rustls::ProtocolVersion;
 use rustls::ServerSessionMemoryCache;
 use rustls::Session;
 use rustls::Ticketer;
@@ -22,8 +22,6 @@ use rustls::{ClientConfig, ClientSession};
 use rustls::{ServerConfig, ServerSession};
 use rustls_pemfile;
 
-use webpki;
-
 fn duration_nanos(d: Duration) -> f64 {
     (d.as_secs() as f64) + f64::from(d.subsec_nanos()) / 1e9
 }
@@ -140,14 +138,14 @@ enum KeyType {
 struct BenchmarkParam {
     key_type: KeyType,
     ciphersuite: &'static rustls::SupportedCipherSuite,
-    version: ProtocolVersion,
+    version: &'static rustls::SupportedProtocolVersion,
 }
 
 impl BenchmarkParam {
     const fn new(
         key_type: KeyType,
         ciphersuite: &'static rustls::SupportedCipherSuite,
-        version: ProtocolVersion,
+        version: &'static rustls::SupportedProtocolVersion,
     ) -> BenchmarkParam {
         BenchmarkParam {
             key_type,
@@ -161,62 +159,62 @@ static ALL_BENCHMARKS: &[BenchmarkParam] = &[
     BenchmarkParam::new(
         KeyType::RSA,
         &rustls::ciphersuite::TLS_ECDHE_RSA_WITH_CHACHA20_POLY1305_SHA256,
-        ProtocolVersion::TLSv1_2,
+        &rustls::version::TLS12,
     ),
     BenchmarkParam::new(
         KeyType::ECDSA,
         &rustls::ciphersuite::TLS_ECDHE_ECDSA_WITH_CHACHA20_POLY1305_SHA256,
-        ProtocolVersion::TLSv1_2,
+        &rustls::version::TLS12,
     ),
     BenchmarkParam::new(
         KeyType::RSA,
         &rustls::ciphersuite::TLS_ECDHE_RSA_WITH_CHACHA20_POLY1305_SHA256,
-        ProtocolVersion::TLSv1_2,
+        &rustls::version::TLS12,
     ),
     BenchmarkParam::new(
         KeyType::RSA,
         &rustls::ciphersuite::TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256,
-        ProtocolVersion::TLSv1_2,
+        &rustls::version::TLS12,
     ),
     BenchmarkParam::new(
         KeyType::RSA,
         &rustls::ciphersuite::TLS_ECDHE_RSA_WITH_AES_256_GCM_SHA384,
-        ProtocolVersion::TLSv1_2,
+        &rustls::version::TLS12,
     ),
     BenchmarkParam::new(
         KeyType::ECDSA,
         &rustls::ciphersuite::TLS_ECDHE_ECDSA_WITH_AES_128_GCM_SHA256,
-        ProtocolVersion::TLSv1_2,
+        &rustls::version::TLS12,
     ),
     BenchmarkParam::new(
         KeyType::ECDSA,
         &rustls::ciphersuite::TLS_ECDHE_ECDSA_WITH_AES_256_GCM_SHA384,
-        ProtocolVersion::TLSv1_2,
+        &rustls::version::TLS12,
     ),
     BenchmarkParam::new(
         KeyType::RSA,
         &rustls::ciphersuite::TLS13_CHACHA20_POLY1305_SHA256,
-        ProtocolVersion::TLSv1_3,
+        &rustls::version::TLS13,
     ),
     BenchmarkParam::new(
         KeyType::RSA,
         &rustls::ciphersuite::TLS13_AES_256_GCM_SHA384,
-        ProtocolVersion::TLSv1_3,
+        &rustls::version::TLS13,
     ),
     BenchmarkParam::new(
         KeyType::RSA,
         &rustls::ciphersuite::TLS13_AES_128_GCM_SHA256,
-        ProtocolVersion::TLSv1_3,
+        &rustls::version::TLS13,
     ),
     BenchmarkParam::new(
         KeyType::ECDSA,
         &rustls::ciphersuite::TLS13_AES_128_GCM_SHA256,
-        ProtocolVersion::TLSv1_3,
+        &rustls::version::TLS13,
     ),
     BenchmarkParam::new(
         KeyType::ED25519,
         &rustls::ciphersuite::TLS13_AES_128_GCM_SHA256,
-        ProtocolVersion::TLSv1_3,
+        &rustls::version::TLS13,
     ),
 ];
 
@@ -296,8 +294,7 @@ fn make_server_config(
         cfg.set_persistence(Arc::new(NoServerSessionStorage {}));
     }
 
-    cfg.versions.clear();
-    cfg.versions.push(params.version);
+    cfg.set_protocol_versions(&[params.version]);
 
     cfg.mtu = mtu;
 
@@ -316,8 +313,7 @@ fn make_client_config(
         .add_parsable_certificates(&rustls_pemfile::certs(&mut rootbuf).unwrap());
 
     let mut cfg = ClientConfig::new(root_store, &[], &[params.ciphersuite]);
-    cfg.versions.clear();
-    cfg.versions.push(params.version);
+    cfg.set_protocol_versions(&[params.version]);
 
     if clientauth == ClientAuth::Yes {
         cfg.set_single_client_cert(
@@ -354,7 +350,7 @@ fn bench_handshake(params: &BenchmarkParam, clientauth: ClientAuth, resume: Resu
     assert!(
         params
             .ciphersuite
-            .usable_for_version(params.version)
+            .usable_for_version(params.version.version)
     );
 
     let rounds = apply_work_multiplier(if resume == Resumption::No { 512 } else { 4096 });
@@ -362,8 +358,8 @@ fn bench_handshake(params: &BenchmarkParam, clientauth: ClientAuth, resume: Resu
     let mut server_time = 0f64;
 
     for _ in 0..rounds {
-        let dns_name = webpki::DNSNameRef::try_from_ascii_str("localhost").unwrap();
-        let mut client = ClientSession::new(&client_config, dns_name).unwrap();
+        let server_name = "localhost".try_into().unwrap();
+        let mut client = ClientSession::new(&client_config, server_name).unwrap();
         let mut server = ServerSession::new(&server_config);
 
         server_time += time(|| {
@@ -437,8 +433,8 @@ fn bench_bulk(params: &BenchmarkParam, plaintext_size: u64, mtu: Option<usize>)
         mtu,
     ));
 
-    let dns_name = webpki::DNSNameRef::try_from_ascii_str("localhost").unwrap();
-    let mut client = ClientSession::new(&client_config, dns_name).unwrap();
+    let server_name = "localhost".try_into().unwrap();
+    let mut client = ClientSession::new(&client_config, server_name).unwrap();
     let mut server = ServerSession::new(&server_config);
 
     do_handshake(&mut client, &mut server);
@@ -506,8 +502,8 @@ fn bench_memory(params: &BenchmarkParam, session_count: u64) {
 
     for _i in 0..session_count {
         servers.push(ServerSession::new(&server_config));
-        let dns_name = webpki::DNSNameRef::try_from_ascii_str("localhost").unwrap();
-        clients.push(ClientSession::new(&client_config, dns_name).unwrap());
+        let server_name = "localhost".try_into().unwrap();
+        clients.push(ClientSession::new(&client_config, server_name).unwrap());
     }
 
     for _step in 0..5 {
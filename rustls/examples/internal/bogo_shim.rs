@@ -7,13 +7,13 @@
 use base64;
 use env_logger;
 use rustls;
-use webpki;
 
 use rustls::internal::msgs::enums::ProtocolVersion;
 use rustls::quic;
 use rustls::quic::ClientQuicExt;
 use rustls::quic::ServerQuicExt;
 use rustls::ClientHello;
+use std::convert::TryInto;
 use std::env;
 use std::fs;
 use std::io;
@@ -180,13 +180,13 @@ impl rustls::ClientCertVerifier for DummyClientAuth {
         true
     }
 
-    fn client_auth_mandatory(&self, _sni: Option<&webpki::DNSName>) -> Option<bool> {
+    fn client_auth_mandatory(&self, _sni: Option<&rustls::DnsName>) -> Option<bool> {
         Some(self.mandatory)
     }
 
     fn client_auth_root_subjects(
         &self,
-        _sni: Option<&webpki::DNSName>,
+        _sni: Option<&rustls::DnsName>,
     ) -> Option<rustls::DistinguishedNames> {
         Some(rustls::DistinguishedNames::new())
     }
@@ -195,7 +195,7 @@ impl rustls::ClientCertVerifier for DummyClientAuth {
         &self,
         _end_entity: &rustls::Certificate,
         _intermediates: &[rustls::Certificate],
-        _sni: Option<&webpki::DNSName>,
+        _sni: Option<&rustls::DnsName>,
         _now: SystemTime,
     ) -> Result<rustls::ClientCertVerified, rustls::TlsError> {
         Ok(rustls::ClientCertVerified::assertion())
@@ -211,7 +211,7 @@ impl rustls::ServerCertVerifier for DummyServerAuth {
         &self,
         _end_entity: &rustls::Certificate,
         _certs: &[rustls::Certificate],
-        _hostname: webpki::DNSNameRef<'_>,
+        _server_name: &rustls::ServerName,
         _scts: &mut dyn Iterator<Item=&[u8]>,
         _ocsp: &[u8],
         _now: SystemTime,
@@ -303,6 +303,9 @@ fn lookup_scheme(scheme: u16) -> rustls::SignatureScheme {
         0x0807 => rustls::SignatureScheme::ED25519,
         // TODO: add support for Ed448
         // 0x0808 => rustls::SignatureScheme::ED448,
+        0x0809 => rustls::SignatureScheme::RSA_PSS_PSS_SHA256,
+        0x080a => rustls::SignatureScheme::RSA_PSS_PSS_SHA384,
+        0x080b => rustls::SignatureScheme::RSA_PSS_PSS_SHA512,
         _ => {
             println_err!("Unsupported signature scheme {:04x}", scheme);
             process::exit(BOGO_NACK);
@@ -371,18 +374,18 @@ fn make_server_cfg(opts: &Options) -> Arc<rustls::ServerConfig> {
         );
     }
 
-    cfg.versions.clear();
+    let mut versions = Vec::new();
 
     if opts.tls12_supported() {
-        cfg.versions
-            .push(ProtocolVersion::TLSv1_2);
+        versions.push(&rustls::version::TLS12);
     }
 
     if opts.tls13_supported() {
-        cfg.versions
-            .push(ProtocolVersion::TLSv1_3);
+        versions.push(&rustls::version::TLS13);
     }
 
+    cfg.set_protocol_versions(&versions);
+
     if let Some(curves) = &opts.curves {
         cfg.kx_groups = curves
             .iter()
@@ -453,18 +456,18 @@ fn make_client_cfg(opts: &Options) -> Arc<rustls::ClientConfig> {
         );
     }
 
-    cfg.versions.clear();
+    let mut versions = Vec::new();
 
     if opts.tls12_supported() {
-        cfg.versions
-            .push(ProtocolVersion::TLSv1_2);
+        versions.push(&rustls::version::TLS12);
     }
 
     if opts.tls13_supported() {
-        cfg.versions
-            .push(ProtocolVersion::TLSv1_3);
+        versions.push(&rustls::version::TLS13);
     }
 
+    cfg.set_protocol_versions(&versions);
+
     if opts.enable_early_data {
         cfg.enable_early_data = true;
     }
@@ -491,7 +494,7 @@ fn quit_err(why: &str) -> ! {
 
 fn handle_err(err: rustls::TlsError) -> ! {
     use rustls::internal::msgs::enums::{AlertDescription, ContentType};
-    use rustls::TlsError;
+    use rustls::{CertificateError, TlsError};
     use std::{thread, time};
 
     println!("TLS error: {:?}", err);
@@ -501,14 +504,22 @@ fn handle_err(err: rustls::TlsError) -> ! {
         TlsError::InappropriateHandshakeMessage { .. } | TlsError::InappropriateMessage { .. } => {
             quit(":UNEXPECTED_MESSAGE:")
         }
-        TlsError::AlertReceived(AlertDescription::RecordOverflow) => {
-            quit(":TLSV1_ALERT_RECORD_OVERFLOW:")
-        }
-        TlsError::AlertReceived(AlertDescription::HandshakeFailure) => quit(":HANDSHAKE_FAILURE:"),
-        TlsError::AlertReceived(AlertDescription::ProtocolVersion) => quit(":WRONG_VERSION:"),
-        TlsError::AlertReceived(AlertDescription::InternalError) => {
-            quit(":PEER_ALERT_INTERNAL_ERROR:")
-        }
+        TlsError::AlertReceived {
+            description: AlertDescription::RecordOverflow,
+            ..
+        } => quit(":TLSV1_ALERT_RECORD_OVERFLOW:"),
+        TlsError::AlertReceived {
+            description: AlertDescription::HandshakeFailure,
+            ..
+        } => quit(":HANDSHAKE_FAILURE:"),
+        TlsError::AlertReceived {
+            description: AlertDescription::ProtocolVersion,
+            ..
+        } => quit(":WRONG_VERSION:"),
+        TlsError::AlertReceived {
+            description: AlertDescription::InternalError,
+            ..
+        } => quit(":PEER_ALERT_INTERNAL_ERROR:"),
         TlsError::CorruptMessagePayload(ContentType::Alert) => quit(":BAD_ALERT:"),
         TlsError::CorruptMessagePayload(ContentType::ChangeCipherSpec) => {
             quit(":BAD_CHANGE_CIPHER_SPEC:")
@@ -520,15 +531,21 @@ fn handle_err(err: rustls::TlsError) -> ! {
         TlsError::PeerIncompatibleError(_) => quit(":INCOMPATIBLE:"),
         TlsError::PeerMisbehavedError(_) => quit(":PEER_MISBEHAVIOUR:"),
         TlsError::NoCertificatesPresented => quit(":NO_CERTS:"),
-        TlsError::AlertReceived(AlertDescription::UnexpectedMessage) => quit(":BAD_ALERT:"),
-        TlsError::AlertReceived(AlertDescription::DecompressionFailure) => {
+        TlsError::AlertReceived {
+            description: AlertDescription::UnexpectedMessage,
+            ..
+        } => quit(":BAD_ALERT:"),
+        TlsError::AlertReceived {
+            description: AlertDescription::DecompressionFailure,
+            ..
+        } => {
             quit_err(":SSLV3_ALERT_DECOMPRESSION_FAILURE:")
         }
-        TlsError::WebPKIError(webpki::Error::BadDER, ..) => quit(":CANNOT_PARSE_LEAF_CERT:"),
-        TlsError::WebPKIError(webpki::Error::InvalidSignatureForPublicKey, ..) => {
-            quit(":BAD_SIGNATURE:")
+        TlsError::InvalidCertificate(CertificateError::BadEncoding) => {
+            quit(":CANNOT_PARSE_LEAF_CERT:")
         }
-        TlsError::WebPKIError(webpki::Error::UnsupportedSignatureAlgorithmForPublicKey, ..) => {
+        TlsError::InvalidCertificate(CertificateError::BadSignature) => quit(":BAD_SIGNATURE:"),
+        TlsError::InvalidCertificate(CertificateError::UnsupportedSignatureAlgorithm) => {
             quit(":WRONG_SIGNATURE_TYPE:")
         }
         TlsError::PeerSentOversizedRecord => quit(":DATA_LENGTH_TOO_LONG:"),
@@ -1045,14 +1062,14 @@ fn main() {
             };
             ClientOrServer::Server(s)
         } else {
-            let dns_name = webpki::DNSNameRef::try_from_ascii_str(&opts.host_name).unwrap();
+            let server_name = opts.host_name.as_str().try_into().unwrap();
             let c = if opts.quic_transport_params.is_empty() {
-                rustls::ClientSession::new(ccfg.as_ref().unwrap(), dns_name)
+                rustls::ClientSession::new(ccfg.as_ref().unwrap(), server_name)
             } else {
                 rustls::ClientSession::new_quic(
                     ccfg.as_ref().unwrap(),
                     quic::Version::V1,
-                    dns_name,
+                    server_name,
                     opts.quic_transport_params.clone(),
                 )
             }.unwrap();
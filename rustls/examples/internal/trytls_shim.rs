@@ -4,10 +4,10 @@
 // See: https://github.com/HowNetWorks/trytls-rustls-stub
 //
 
-use webpki;
 use webpki_roots;
 
 use rustls::{ClientConfig, ClientSession, Session, TlsError, RootCertStore, DEFAULT_CIPHERSUITES};
+use std::convert::TryInto;
 use std::env;
 use std::error::Error;
 use std::fs::File;
@@ -48,9 +48,9 @@ fn parse_args(args: &[String]) -> Result<(String, u16, ClientConfig), Box<dyn Er
 }
 
 fn communicate(host: String, port: u16, config: ClientConfig) -> Result<Verdict, Box<dyn Error>> {
-    let dns_name = webpki::DNSNameRef::try_from_ascii_str(&host).unwrap();
+    let server_name = host.as_str().try_into().unwrap();
     let rc_config = Arc::new(config);
-    let mut client = ClientSession::new(&rc_config, dns_name).unwrap();
+    let mut client = ClientSession::new(&rc_config, server_name).unwrap();
     let mut stream = TcpStream::connect((&*host, port))?;
 
     client.write_all(b"GET / HTTP/1.0\r\nConnection: close\r\nContent-Length: 0\r\n\r\n")?;
@@ -66,7 +66,7 @@ fn communicate(host: String, port: u16, config: ClientConfig) -> Result<Verdict,
 
             if let Err(err) = client.process_new_packets() {
                 return match err {
-                    TlsError::WebPKIError(..) | TlsError::AlertReceived(_) => {
+                    TlsError::InvalidCertificate(_) | TlsError::AlertReceived { .. } => {
                         Ok(Verdict::Reject(err))
                     }
                     _ => Err(From::from(format!("{:?}", err))),
@@ -1,13 +1,13 @@
-/// limitedclient: This example demonstrates usage of only ClientConfig::new_custom
+/// limitedclient: This example demonstrates configuring only the cryptography that is used,
 /// so that unused cryptography in rustls can be discarded by the linker.  You can
 /// observe using `nm` that the binary of this program does not contain any AES code.
+use std::convert::TryInto;
 use std::sync::Arc;
 
 use std::io::{stdout, Read, Write};
 use std::net::TcpStream;
 
 use rustls;
-use webpki;
 use webpki_roots;
 
 use rustls::Session;
@@ -16,14 +16,16 @@ fn main() {
     let mut root_store = rustls::RootCertStore::empty();
     root_store
         .add_server_trust_anchors(&webpki_roots::TLS_SERVER_ROOTS);
-    let config = rustls::ClientConfig::new(
-        root_store,
-        &[],
-        &[&rustls::ciphersuite::TLS13_CHACHA20_POLY1305_SHA256]
-    );
+    let config = rustls::ClientConfig::builder()
+        .with_cipher_suites(&[&rustls::ciphersuite::TLS13_CHACHA20_POLY1305_SHA256])
+        .with_kx_groups(&[&rustls::kx_group::X25519])
+        .with_protocol_versions(&[&rustls::version::TLS13])
+        .unwrap()
+        .with_root_certificates(root_store, &[])
+        .with_no_client_auth();
 
-    let dns_name = webpki::DNSNameRef::try_from_ascii_str("google.com").unwrap();
-    let mut sess = rustls::ClientSession::new(&Arc::new(config), dns_name).unwrap();
+    let server_name = "google.com".try_into().unwrap();
+    let mut sess = rustls::ClientSession::new(&Arc::new(config), server_name).unwrap();
     let mut sock = TcpStream::connect("google.com:443").unwrap();
     let mut tls = rustls::Stream::new(&mut sess, &mut sock);
     tls.write(
@@ -1,3 +1,4 @@
+use std::convert::TryInto;
 use std::sync::Arc;
 
 use std::io::{stdout, Read, Write};
@@ -5,13 +6,12 @@ use std::net::TcpStream;
 
 use env_logger;
 use rustls;
-use webpki;
 use webpki_roots;
 use rustls::RootCertStore;
 
 fn start_session(config: &Arc<rustls::ClientConfig>, domain_name: &str) {
-    let dns_name = webpki::DNSNameRef::try_from_ascii_str(domain_name).unwrap();
-    let mut sess = rustls::ClientSession::new(config, dns_name).unwrap();
+    let server_name = domain_name.try_into().unwrap();
+    let mut sess = rustls::ClientSession::new(config, server_name).unwrap();
     let mut sock = TcpStream::connect(format!("{}:443", domain_name)).unwrap();
     sock.set_nodelay(true).unwrap();
     let request = format!(
@@ -7,13 +7,13 @@
 ///
 /// Note that `unwrap()` is used to deal with networking errors; this is not something
 /// that is sensible outside of example code.
+use std::convert::TryInto;
 use std::sync::Arc;
 
 use std::io::{stdout, Read, Write};
 use std::net::TcpStream;
 
 use rustls;
-use webpki;
 use webpki_roots;
 
 use rustls::{Session, RootCertStore};
@@ -22,13 +22,16 @@ fn main() {
     let mut root_store = RootCertStore::empty();
     root_store
         .add_server_trust_anchors(&webpki_roots::TLS_SERVER_ROOTS);
-    let config = rustls::ClientConfig::new(
-        root_store,
-        &[],
-        rustls::DEFAULT_CIPHERSUITES);
+    let config = rustls::ClientConfig::builder()
+        .with_safe_default_cipher_suites()
+        .with_safe_default_kx_groups()
+        .with_safe_default_protocol_versions()
+        .unwrap()
+        .with_root_certificates(root_store, &[])
+        .with_no_client_auth();
 
-    let dns_name = webpki::DNSNameRef::try_from_ascii_str("google.com").unwrap();
-    let mut sess = rustls::ClientSession::new(&Arc::new(config), dns_name).unwrap();
+    let server_name = "google.com".try_into().unwrap();
+    let mut sess = rustls::ClientSession::new(&Arc::new(config), server_name).unwrap();
     let mut sock = TcpStream::connect("google.com:443").unwrap();
     let mut tls = rustls::Stream::new(&mut sess, &mut sock);
     tls.write(
@@ -0,0 +1,137 @@
+use crate::msgs::message::Message;
+
+use std::fmt;
+use std::sync::{Arc, Mutex};
+
+/// Chunks of outgoing TLS data larger than this aren't kept, so one
+/// big write doesn't pin a big allocation in the pool.
+const MAX_CHUNK_CAPACITY: usize = 4 * Message::MAX_WIRE_SIZE;
+
+/// A store of buffers shared between sessions.
+///
+/// Each session needs a buffer of about 18KB for reassembling incoming
+/// TLS records, and allocates more as it queues outgoing TLS data.  A
+/// server handling many short-lived connections can share a `BufferPool`
+/// between them, by setting `ServerConfig::buffer_pool` (or
+/// `ClientConfig::buffer_pool`).  Sessions then take their buffers from
+/// the pool, and give them back once they're finished with them or
+/// dropped, instead of going to the allocator each time.
+///
+/// The pool keeps at most a fixed number of idle buffers of each kind;
+/// any more are freed.
+pub struct BufferPool {
+    max_idle: usize,
+    records: Mutex<Vec<Vec<u8>>>,
+    chunks: Mutex<Vec<Vec<u8>>>,
+}
+
+impl BufferPool {
+    /// Make a new pool, which keeps at most `max_idle` buffers of
+    /// each kind when they're not in use.
+    pub fn new(max_idle: usize) -> Arc<BufferPool> {
+        Arc::new(BufferPool {
+            max_idle,
+            records: Mutex::new(Vec::new()),
+            chunks: Mutex::new(Vec::new()),
+        })
+    }
+
+    /// How many buffers the pool holds that aren't in use.
+    pub fn idle_buffers(&self) -> usize {
+        self.records.lock().unwrap().len() + self.chunks.lock().unwrap().len()
+    }
+
+    /// Get a buffer for reassembling incoming records.  It is exactly
+    /// `Message::MAX_WIRE_SIZE` bytes long, with arbitrary contents.
+    pub(crate) fn take_record_buffer(&self) -> Vec<u8> {
+        self.records
+            .lock()
+            .unwrap()
+            .pop()
+            .unwrap_or_else(|| vec![0u8; Message::MAX_WIRE_SIZE])
+    }
+
+    /// Give back a buffer from `take_record_buffer`.
+    pub(crate) fn put_record_buffer(&self, buf: Vec<u8>) {
+        debug_assert_eq!(buf.len(), Message::MAX_WIRE_SIZE);
+        let mut records = self.records.lock().unwrap();
+        if records.len() < self.max_idle {
+            records.push(buf);
+        }
+    }
+
+    /// Get an empty buffer with room for at least `capacity` bytes.
+    pub(crate) fn take_chunk(&self, capacity: usize) -> Vec<u8> {
+        let mut chunk = self
+            .chunks
+            .lock()
+            .unwrap()
+            .pop()
+            .unwrap_or_default();
+        chunk.reserve(capacity);
+        chunk
+    }
+
+    /// Give back a buffer which is no longer needed.
+    pub(crate) fn put_chunk(&self, mut chunk: Vec<u8>) {
+        if chunk.capacity() == 0 || chunk.capacity() > MAX_CHUNK_CAPACITY {
+            return;
+        }
+
+        chunk.clear();
+        let mut chunks = self.chunks.lock().unwrap();
+        if chunks.len() < self.max_idle {
+            chunks.push(chunk);
+        }
+    }
+}
+
+impl fmt::Debug for BufferPool {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("BufferPool")
+            .field("max_idle", &self.max_idle)
+            .field("idle_buffers", &self.idle_buffers())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn record_buffers_are_reused() {
+        let pool = BufferPool::new(1);
+        let buf = pool.take_record_buffer();
+        let ptr = buf.as_ptr();
+        pool.put_record_buffer(buf);
+        assert_eq!(pool.idle_buffers(), 1);
+
+        let buf = pool.take_record_buffer();
+        assert_eq!(buf.as_ptr(), ptr);
+        assert_eq!(buf.len(), Message::MAX_WIRE_SIZE);
+        assert_eq!(pool.idle_buffers(), 0);
+    }
+
+    #[test]
+    fn chunks_are_cleared_and_reused() {
+        let pool = BufferPool::new(1);
+        let mut chunk = pool.take_chunk(100);
+        chunk.extend_from_slice(b"hello");
+        let ptr = chunk.as_ptr();
+        pool.put_chunk(chunk);
+
+        let chunk = pool.take_chunk(10);
+        assert!(chunk.is_empty());
+        assert_eq!(chunk.as_ptr(), ptr);
+    }
+
+    #[test]
+    fn idle_buffers_are_limited() {
+        let pool = BufferPool::new(1);
+        pool.put_chunk(Vec::with_capacity(10));
+        pool.put_chunk(Vec::with_capacity(10));
+        pool.put_chunk(Vec::with_capacity(MAX_CHUNK_CAPACITY + 1));
+        assert_eq!(pool.idle_buffers(), 1);
+    }
+}
@@ -0,0 +1,580 @@
+//! A builder for `ClientConfig` and `ServerConfig`.
+//!
+//! The builder asks for each decision in turn: cipher suites, key
+//! exchange groups, protocol versions, how to verify the peer, and
+//! then which certificate (if any) to present.  Each step returns a
+//! builder in a new state, so skipping a step is a compile error:
+//!
+//! ```no_run
+//! # let root_store = rustls::RootCertStore::empty();
+//! let config = rustls::ClientConfig::builder()
+//!     .with_safe_default_cipher_suites()
+//!     .with_safe_default_kx_groups()
+//!     .with_safe_default_protocol_versions()
+//!     .unwrap()
+//!     .with_root_certificates(root_store, &[])
+//!     .with_no_client_auth();
+//! ```
+//!
+//! Combinations which can't work, like TLS1.2-only cipher suites with
+//! only TLS1.3 enabled, are rejected by `with_protocol_versions`.
+//!
+//! Alternatively, `with_profile` makes the first three decisions (and
+//! restricts the signature schemes) according to a `Profile`:
+//!
+//! ```no_run
+//! # use std::sync::Arc;
+//! # let resolver: Arc<dyn rustls::ResolvesServerCert> = unimplemented!();
+//! let config = rustls::ServerConfig::builder()
+//!     .with_profile(rustls::Profile::Intermediate)
+//!     .with_no_client_auth()
+//!     .with_cert_resolver(resolver);
+//! ```
+//!
+//! Settings outside these steps keep the same defaults as
+//! `ClientConfig::new` and `ServerConfig::new`, and can be changed
+//! on the finished config.
+
+use crate::anchors::RootCertStore;
+use crate::client::{ClientConfig, ResolvesClientCert};
+use crate::crl::CrlSet;
+use crate::error::TlsError;
+use crate::key;
+use crate::kx::{SupportedKxGroup, ALL_KX_GROUPS};
+use crate::kx;
+use crate::msgs::enums::SignatureScheme;
+use crate::server::{ResolvesServerCert, ServerConfig};
+use crate::suites::{self, SupportedCipherSuite, ALL_CIPHERSUITES};
+use crate::verify;
+#[cfg(feature = "tls13")]
+use crate::versions;
+use crate::versions::{EnabledVersions, SupportedProtocolVersion, DEFAULT_VERSIONS};
+use crate::{client, server};
+
+use std::marker::PhantomData;
+use std::sync::Arc;
+
+/// A builder for a `ClientConfig` or `ServerConfig` (`Side`).
+///
+/// `State` is the decision the builder wants next.  Start with
+/// `ClientConfig::builder()` or `ServerConfig::builder()`.
+pub struct ConfigBuilder<Side, State> {
+    state: State,
+    side: PhantomData<Side>,
+}
+
+impl<Side, State> ConfigBuilder<Side, State> {
+    fn next<Next>(state: Next) -> ConfigBuilder<Side, Next> {
+        ConfigBuilder {
+            state,
+            side: PhantomData,
+        }
+    }
+}
+
+/// Builder state: wants the cipher suites.
+pub struct WantsCipherSuites(());
+
+/// Builder state: wants the key exchange groups.
+pub struct WantsKxGroups {
+    cipher_suites: Vec<&'static SupportedCipherSuite>,
+}
+
+/// Builder state: wants the protocol versions.
+pub struct WantsVersions {
+    cipher_suites: Vec<&'static SupportedCipherSuite>,
+    kx_groups: Vec<&'static SupportedKxGroup>,
+}
+
+/// Builder state: wants how to verify the peer's certificates.
+pub struct WantsVerifier {
+    cipher_suites: Vec<&'static SupportedCipherSuite>,
+    kx_groups: Vec<&'static SupportedKxGroup>,
+    versions: EnabledVersions,
+    signature_schemes: Option<Vec<SignatureScheme>>,
+}
+
+/// Builder state: wants the client's certificate, if any.
+pub struct WantsClientCert {
+    cipher_suites: Vec<&'static SupportedCipherSuite>,
+    kx_groups: Vec<&'static SupportedKxGroup>,
+    versions: EnabledVersions,
+    signature_schemes: Option<Vec<SignatureScheme>>,
+    verifier: Arc<dyn verify::ServerCertVerifier>,
+}
+
+/// Builder state: wants the server's certificate.
+pub struct WantsServerCert {
+    cipher_suites: Vec<&'static SupportedCipherSuite>,
+    kx_groups: Vec<&'static SupportedKxGroup>,
+    versions: EnabledVersions,
+    signature_schemes: Option<Vec<SignatureScheme>>,
+    verifier: Arc<dyn verify::ClientCertVerifier>,
+}
+
+/// A named set of cipher suites, key exchange groups, protocol versions
+/// and signature schemes, following Mozilla's "Server Side TLS"
+/// recommendations (version 5).
+///
+/// Use one with `ConfigBuilder::with_profile`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[non_exhaustive]
+pub enum Profile {
+    /// For services whose clients all support TLS1.3.
+    ///
+    /// Only TLS1.3 is enabled.  RSA PKCS#1 signature schemes are
+    /// not offered, so RSA keys sign using RSA-PSS.  Without the `tls13`
+    /// crate feature, this profile enables no protocol versions.
+    Modern,
+
+    /// For general-purpose services: TLS1.3 and TLS1.2, with
+    /// forward-secret AEAD cipher suites.  This is the recommended
+    /// profile for almost all services.
+    Intermediate,
+
+    /// For services with old clients that can't be upgraded.
+    ///
+    /// rustls doesn't implement the legacy algorithms that Mozilla's
+    /// "Old" configuration adds (CBC cipher suites, static RSA key
+    /// exchange, TLS1.1 and earlier), so this is everything rustls
+    /// supports: `ALL_CIPHERSUITES` and `ALL_KX_GROUPS`.
+    LegacyCompat,
+}
+
+static MODERN_CIPHERSUITES: &[&SupportedCipherSuite] = &[
+    #[cfg(feature = "tls13")]
+    &suites::TLS13_AES_128_GCM_SHA256,
+    #[cfg(feature = "tls13")]
+    &suites::TLS13_AES_256_GCM_SHA384,
+    #[cfg(feature = "tls13")]
+    &suites::TLS13_CHACHA20_POLY1305_SHA256,
+];
+
+static INTERMEDIATE_CIPHERSUITES: &[&SupportedCipherSuite] = &[
+    #[cfg(feature = "tls13")]
+    &suites::TLS13_AES_128_GCM_SHA256,
+    #[cfg(feature = "tls13")]
+    &suites::TLS13_AES_256_GCM_SHA384,
+    #[cfg(feature = "tls13")]
+    &suites::TLS13_CHACHA20_POLY1305_SHA256,
+    #[cfg(feature = "tls12")]
+    &suites::TLS_ECDHE_ECDSA_WITH_AES_128_GCM_SHA256,
+    #[cfg(feature = "tls12")]
+    &suites::TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256,
+    #[cfg(feature = "tls12")]
+    &suites::TLS_ECDHE_ECDSA_WITH_AES_256_GCM_SHA384,
+    #[cfg(feature = "tls12")]
+    &suites::TLS_ECDHE_RSA_WITH_AES_256_GCM_SHA384,
+    #[cfg(feature = "tls12")]
+    &suites::TLS_ECDHE_ECDSA_WITH_CHACHA20_POLY1305_SHA256,
+    #[cfg(feature = "tls12")]
+    &suites::TLS_ECDHE_RSA_WITH_CHACHA20_POLY1305_SHA256,
+];
+
+static RECOMMENDED_KX_GROUPS: &[&SupportedKxGroup] = &[&kx::X25519, &kx::SECP256R1, &kx::SECP384R1];
+
+static MODERN_VERSIONS: &[&SupportedProtocolVersion] = &[
+    #[cfg(feature = "tls13")]
+    &versions::TLS13,
+];
+
+static MODERN_SIGNATURE_SCHEMES: &[SignatureScheme] = &[
+    SignatureScheme::ECDSA_NISTP256_SHA256,
+    SignatureScheme::ECDSA_NISTP384_SHA384,
+    SignatureScheme::ED25519,
+    SignatureScheme::RSA_PSS_SHA256,
+    SignatureScheme::RSA_PSS_SHA384,
+    SignatureScheme::RSA_PSS_SHA512,
+    SignatureScheme::RSA_PSS_PSS_SHA256,
+    SignatureScheme::RSA_PSS_PSS_SHA384,
+    SignatureScheme::RSA_PSS_PSS_SHA512,
+];
+
+impl Profile {
+    /// The cipher suites enabled by this profile, in preference order.
+    pub fn cipher_suites(self) -> &'static [&'static SupportedCipherSuite] {
+        match self {
+            Profile::Modern => MODERN_CIPHERSUITES,
+            Profile::Intermediate => INTERMEDIATE_CIPHERSUITES,
+            Profile::LegacyCompat => ALL_CIPHERSUITES,
+        }
+    }
+
+    /// The key exchange groups enabled by this profile, in
+    /// preference order.
+    pub fn kx_groups(self) -> &'static [&'static SupportedKxGroup] {
+        match self {
+            Profile::Modern | Profile::Intermediate => RECOMMENDED_KX_GROUPS,
+            Profile::LegacyCompat => &ALL_KX_GROUPS,
+        }
+    }
+
+    /// The protocol versions enabled by this profile.
+    pub fn versions(self) -> &'static [&'static SupportedProtocolVersion] {
+        match self {
+            Profile::Modern => MODERN_VERSIONS,
+            Profile::Intermediate | Profile::LegacyCompat => DEFAULT_VERSIONS,
+        }
+    }
+
+    /// The signature schemes this profile is restricted to, or `None`
+    /// if it allows all those supported.
+    pub fn signature_schemes(self) -> Option<&'static [SignatureScheme]> {
+        match self {
+            Profile::Modern => Some(MODERN_SIGNATURE_SCHEMES),
+            Profile::Intermediate | Profile::LegacyCompat => None,
+        }
+    }
+}
+
+impl<Side> ConfigBuilder<Side, WantsCipherSuites> {
+    pub(crate) fn new() -> Self {
+        Self::next(WantsCipherSuites(()))
+    }
+
+    /// Use `cipher_suites`, in preference order.
+    pub fn with_cipher_suites(
+        self,
+        cipher_suites: &[&'static SupportedCipherSuite],
+    ) -> ConfigBuilder<Side, WantsKxGroups> {
+        Self::next(WantsKxGroups {
+            cipher_suites: cipher_suites.to_vec(),
+        })
+    }
+
+    /// Use `DEFAULT_CIPHERSUITES`, in the order `default_ciphersuites`
+    /// chooses for this machine.
+    pub fn with_safe_default_cipher_suites(self) -> ConfigBuilder<Side, WantsKxGroups> {
+        self.with_cipher_suites(suites::default_ciphersuites())
+    }
+
+    /// Use the cipher suites, key exchange groups, protocol versions
+    /// and signature schemes of `profile`.
+    pub fn with_profile(self, profile: Profile) -> ConfigBuilder<Side, WantsVerifier> {
+        Self::next(WantsVerifier {
+            cipher_suites: profile.cipher_suites().to_vec(),
+            kx_groups: profile.kx_groups().to_vec(),
+            versions: EnabledVersions::new(profile.versions()),
+            signature_schemes: profile
+                .signature_schemes()
+                .map(|schemes| schemes.to_vec()),
+        })
+    }
+}
+
+impl<Side> ConfigBuilder<Side, WantsKxGroups> {
+    /// Use `kx_groups`, in preference order.
+    pub fn with_kx_groups(
+        self,
+        kx_groups: &[&'static SupportedKxGroup],
+    ) -> ConfigBuilder<Side, WantsVersions> {
+        Self::next(WantsVersions {
+            cipher_suites: self.state.cipher_suites,
+            kx_groups: kx_groups.to_vec(),
+        })
+    }
+
+    /// Use `ALL_KX_GROUPS`.
+    pub fn with_safe_default_kx_groups(self) -> ConfigBuilder<Side, WantsVersions> {
+        self.with_kx_groups(&ALL_KX_GROUPS)
+    }
+}
+
+impl<Side> ConfigBuilder<Side, WantsVersions> {
+    /// Enable the protocol versions in `versions`.
+    ///
+    /// This fails if `versions` is empty, or if no key exchange groups
+    /// were given, or if none of the cipher suites can be used with any
+    /// of `versions`.
+    pub fn with_protocol_versions(
+        self,
+        versions: &[&'static SupportedProtocolVersion],
+    ) -> Result<ConfigBuilder<Side, WantsVerifier>, TlsError> {
+        if versions.is_empty() {
+            return Err(TlsError::General("no protocol versions configured".into()));
+        }
+
+        if self.state.kx_groups.is_empty() {
+            return Err(TlsError::General("no kx groups configured".into()));
+        }
+
+        let usable = self
+            .state
+            .cipher_suites
+            .iter()
+            .any(|cs| {
+                versions
+                    .iter()
+                    .any(|v| cs.usable_for_version(v.version))
+            });
+        if !usable {
+            return Err(TlsError::General(
+                "no usable cipher suites for the configured protocol versions".into(),
+            ));
+        }
+
+        Ok(Self::next(WantsVerifier {
+            cipher_suites: self.state.cipher_suites,
+            kx_groups: self.state.kx_groups,
+            versions: EnabledVersions::new(versions),
+            signature_schemes: None,
+        }))
+    }
+
+    /// Use `DEFAULT_VERSIONS`.
+    pub fn with_safe_default_protocol_versions(
+        self,
+    ) -> Result<ConfigBuilder<Side, WantsVerifier>, TlsError> {
+        self.with_protocol_versions(DEFAULT_VERSIONS)
+    }
+}
+
+impl ConfigBuilder<ClientConfig, WantsVerifier> {
+    /// Verify server certificates against the roots in `root_store`,
+    /// and the CT logs (if any) in `ct_logs`.
+    pub fn with_root_certificates(
+        self,
+        root_store: RootCertStore,
+        ct_logs: &'static [&'static sct::Log],
+    ) -> ConfigBuilder<ClientConfig, WantsClientCert> {
+        self.with_verifier(Arc::new(verify::WebPkiVerifier::new(root_store, ct_logs)))
+    }
+
+    /// Like `with_root_certificates`, but also check server
+    /// certificates against the certificate revocation lists in `crls`.
+    pub fn with_root_certificates_and_crls(
+        self,
+        root_store: RootCertStore,
+        ct_logs: &'static [&'static sct::Log],
+        crls: CrlSet,
+    ) -> ConfigBuilder<ClientConfig, WantsClientCert> {
+        self.with_verifier(Arc::new(verify::WebPkiVerifier::new_with_crls(
+            root_store, ct_logs, crls,
+        )))
+    }
+
+    /// Use `policy` to decide whether to trust server certificates,
+    /// instead of the usual webpki validation against a set of roots.
+    pub fn with_server_cert_policy(
+        self,
+        policy: Arc<dyn verify::ServerCertPolicy>,
+    ) -> ConfigBuilder<ClientConfig, WantsClientCert> {
+        self.with_verifier(Arc::new(verify::PolicyVerifier::new(policy)))
+    }
+
+    /// Use a custom certificate verifier.
+    #[cfg(feature = "dangerous_configuration")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "dangerous_configuration")))]
+    pub fn with_custom_certificate_verifier(
+        self,
+        verifier: Arc<dyn verify::ServerCertVerifier>,
+    ) -> ConfigBuilder<ClientConfig, WantsClientCert> {
+        self.with_verifier(verifier)
+    }
+
+    fn with_verifier(
+        self,
+        verifier: Arc<dyn verify::ServerCertVerifier>,
+    ) -> ConfigBuilder<ClientConfig, WantsClientCert> {
+        Self::next(WantsClientCert {
+            cipher_suites: self.state.cipher_suites,
+            kx_groups: self.state.kx_groups,
+            versions: self.state.versions,
+            signature_schemes: self.state.signature_schemes,
+            verifier,
+        })
+    }
+}
+
+impl ConfigBuilder<ClientConfig, WantsClientCert> {
+    /// Present `cert_chain` and `key_der` to any server that asks
+    /// for client authentication.
+    ///
+    /// This fails for the same reasons as `ClientConfig::set_single_client_cert`.
+    pub fn with_single_cert(
+        self,
+        cert_chain: Vec<key::Certificate>,
+        key_der: key::PrivateKey,
+    ) -> Result<ClientConfig, TlsError> {
+        let resolver = client::handy::AlwaysResolvesClientCert::new(cert_chain, &key_der)?;
+        Ok(self.with_client_cert_resolver(Arc::new(resolver)))
+    }
+
+    /// Don't do client authentication.
+    pub fn with_no_client_auth(self) -> ClientConfig {
+        self.with_client_cert_resolver(Arc::new(client::handy::FailResolveClientCert {}))
+    }
+
+    /// Use `resolver` to choose a client certificate when the server
+    /// asks for one.
+    pub fn with_client_cert_resolver(
+        self,
+        resolver: Arc<dyn ResolvesClientCert>,
+    ) -> ClientConfig {
+        let mut config = ClientConfig::new_(self.state.verifier, &self.state.cipher_suites);
+        config.kx_groups = self.state.kx_groups;
+        config.versions = self.state.versions;
+        config.signature_schemes = self.state.signature_schemes;
+        config.client_auth_cert_resolver = resolver;
+        config
+    }
+}
+
+impl ConfigBuilder<ServerConfig, WantsVerifier> {
+    /// Use `verifier` to decide whether to ask for, and then whether
+    /// to accept, client certificates.
+    pub fn with_client_cert_verifier(
+        self,
+        verifier: Arc<dyn verify::ClientCertVerifier>,
+    ) -> ConfigBuilder<ServerConfig, WantsServerCert> {
+        Self::next(WantsServerCert {
+            cipher_suites: self.state.cipher_suites,
+            kx_groups: self.state.kx_groups,
+            versions: self.state.versions,
+            signature_schemes: self.state.signature_schemes,
+            verifier,
+        })
+    }
+
+    /// Don't do client authentication.
+    pub fn with_no_client_auth(self) -> ConfigBuilder<ServerConfig, WantsServerCert> {
+        self.with_client_cert_verifier(verify::NoClientAuth::new())
+    }
+}
+
+impl ConfigBuilder<ServerConfig, WantsServerCert> {
+    /// Use a single certificate chain and matching private key for
+    /// all connections.  See `ServerConfig::set_single_cert`, which
+    /// fails for the same reasons.
+    pub fn with_single_cert(
+        self,
+        cert_chain: Vec<key::Certificate>,
+        key_der: key::PrivateKey,
+    ) -> Result<ServerConfig, TlsError> {
+        let resolver = server::handy::AlwaysResolvesChain::new(cert_chain, &key_der)?;
+        Ok(self.with_cert_resolver(Arc::new(resolver)))
+    }
+
+    /// Like `with_single_cert`, but also staple the OCSP response `ocsp`
+    /// and the `SignedCertificateTimestampList` `scts`.  Either is
+    /// ignored if empty.
+    pub fn with_single_cert_with_ocsp_and_sct(
+        self,
+        cert_chain: Vec<key::Certificate>,
+        key_der: key::PrivateKey,
+        ocsp: Vec<u8>,
+        scts: Vec<u8>,
+    ) -> Result<ServerConfig, TlsError> {
+        let resolver =
+            server::handy::AlwaysResolvesChain::new_with_extras(cert_chain, &key_der, ocsp, scts)?;
+        Ok(self.with_cert_resolver(Arc::new(resolver)))
+    }
+
+    /// Use `cert_resolver` to choose a certificate for each connection.
+    pub fn with_cert_resolver(self, cert_resolver: Arc<dyn ResolvesServerCert>) -> ServerConfig {
+        let mut config = ServerConfig::with_ciphersuites(self.state.verifier, &self.state.cipher_suites);
+        config.kx_groups = self.state.kx_groups;
+        config.versions = self.state.versions;
+        config.signature_schemes = self.state.signature_schemes;
+        config.cert_resolver = cert_resolver;
+        config
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    #[cfg(feature = "tls13")]
+    use crate::msgs::enums::ProtocolVersion;
+    #[cfg(feature = "tls13")]
+    use crate::suites::TLS13_AES_128_GCM_SHA256;
+    #[cfg(all(feature = "tls12", feature = "tls13"))]
+    use crate::suites::TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256;
+
+    #[cfg(all(feature = "tls12", feature = "tls13"))]
+    #[test]
+    fn test_rejects_incompatible_versions() {
+        let tls12_only = ServerConfig::builder()
+            .with_cipher_suites(&[&TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256])
+            .with_safe_default_kx_groups();
+        assert!(tls12_only
+            .with_protocol_versions(&[&versions::TLS13])
+            .is_err());
+
+        let tls13_only = ClientConfig::builder()
+            .with_cipher_suites(&[&TLS13_AES_128_GCM_SHA256])
+            .with_safe_default_kx_groups();
+        assert!(tls13_only
+            .with_protocol_versions(&[&versions::TLS13, &versions::TLS12])
+            .is_ok());
+    }
+
+    #[test]
+    fn test_rejects_empty_choices() {
+        assert!(ClientConfig::builder()
+            .with_cipher_suites(&[])
+            .with_safe_default_kx_groups()
+            .with_safe_default_protocol_versions()
+            .is_err());
+        assert!(ClientConfig::builder()
+            .with_safe_default_cipher_suites()
+            .with_kx_groups(&[])
+            .with_safe_default_protocol_versions()
+            .is_err());
+        assert!(ClientConfig::builder()
+            .with_safe_default_cipher_suites()
+            .with_safe_default_kx_groups()
+            .with_protocol_versions(&[])
+            .is_err());
+    }
+
+    #[test]
+    fn test_profiles_are_consistent() {
+        for profile in &[Profile::Modern, Profile::Intermediate, Profile::LegacyCompat] {
+            for version in profile.versions() {
+                assert!(profile
+                    .cipher_suites()
+                    .iter()
+                    .any(|cs| cs.usable_for_version(version.version)));
+            }
+
+            let config = ServerConfig::builder()
+                .with_profile(*profile)
+                .with_no_client_auth()
+                .with_cert_resolver(Arc::new(server::handy::FailResolveChain {}));
+            assert_eq!(config.versions, EnabledVersions::new(profile.versions()));
+            assert_eq!(
+                config.signature_schemes.as_deref(),
+                profile.signature_schemes()
+            );
+        }
+    }
+
+    #[cfg(feature = "tls13")]
+    #[test]
+    fn test_builds_tls13_only_config() {
+        let config = ClientConfig::builder()
+            .with_cipher_suites(&[&TLS13_AES_128_GCM_SHA256])
+            .with_safe_default_kx_groups()
+            .with_protocol_versions(&[&versions::TLS13])
+            .unwrap()
+            .with_root_certificates(RootCertStore::empty(), &[])
+            .with_no_client_auth();
+        assert_eq!(config.ciphersuites, vec![&TLS13_AES_128_GCM_SHA256]);
+        assert!(config.supports_version(ProtocolVersion::TLSv1_3));
+        assert!(!config.supports_version(ProtocolVersion::TLSv1_2));
+    }
+
+    #[test]
+    fn test_builds_configs() {
+        let config = ServerConfig::builder()
+            .with_safe_default_cipher_suites()
+            .with_kx_groups(&[&kx::X25519])
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_no_client_auth()
+            .with_cert_resolver(Arc::new(server::handy::FailResolveChain {}));
+        assert_eq!(config.kx_groups.len(), 1);
+        assert!(!config.get_verifier().offer_client_auth());
+    }
+}
@@ -25,6 +25,7 @@ macro_rules! require_handshake_msg(
 );
 
 /// Like require_handshake_msg, but moves the payload out of $m.
+#[cfg(feature = "tls12")]
 macro_rules! require_handshake_msg_mut(
   ( $m:expr, $handshake_type:path, $payload_type:path ) => (
     match $m.payload {
@@ -1,23 +1,78 @@
+#[cfg(feature = "tls13")]
+use crate::error::PeerMisbehaved;
 use crate::error::TlsError;
+#[cfg(feature = "tls13")]
 use crate::key_schedule::{derive_traffic_iv, derive_traffic_key};
 use crate::msgs::codec;
+#[cfg(feature = "tls13")]
 use crate::msgs::codec::Codec;
 use crate::msgs::enums::{ContentType, ProtocolVersion};
-use crate::msgs::fragmenter::MAX_FRAGMENT_LEN;
-use crate::msgs::message::{BorrowMessage, Message, MessagePayload};
+use crate::msgs::fragmenter::{MAX_FRAGMENT_LEN, PACKET_OVERHEAD};
+use crate::msgs::message::{BorrowMessage, BorrowMessageMut};
+#[cfg(feature = "tls12")]
 use crate::session::SessionSecrets;
 use crate::suites::SupportedCipherSuite;
-use ring::{aead, hkdf};
+use ring::aead;
+#[cfg(feature = "tls13")]
+use ring::hkdf;
+#[cfg(feature = "tls12")]
 use std::io::Write;
 
 /// Objects with this trait can decrypt TLS messages.
 pub trait MessageDecrypter: Send + Sync {
-    fn decrypt(&self, m: Message, seq: u64) -> Result<Message, TlsError>;
+    /// Decrypt `m`, which is record number `seq`, in place.  The
+    /// plaintext returned is always a prefix of `m.payload`.
+    fn decrypt<'a>(&self, m: BorrowMessageMut<'a>, seq: u64)
+        -> Result<BorrowMessage<'a>, TlsError>;
 }
 
 /// Objects with this trait can encrypt TLS messages.
 pub trait MessageEncrypter: Send + Sync {
-    fn encrypt(&self, m: BorrowMessage, seq: u64) -> Result<Message, TlsError>;
+    /// Encrypt `m` as record number `seq`, writing the whole record,
+    /// header included, to the start of `out` and returning its length.
+    /// The payload is encrypted in place there.
+    ///
+    /// `out` must be at least `encrypted_len(m.payload.len())` long.
+    fn encrypt(&self, m: BorrowMessage, seq: u64, out: &mut [u8]) -> Result<usize, TlsError>;
+
+    /// The length of the record `encrypt` makes from `len` bytes of
+    /// payload, header included.
+    fn encrypted_len(&self, len: usize) -> usize;
+}
+
+/// The most an encrypted record can add to its plaintext, including
+/// the header: TLS1.2 AES-GCM's explicit nonce and tag.
+pub const MAX_RECORD_OVERHEAD: usize = 5 + 8 + 16;
+
+/// Write a record header to the start of `out`, for a `len` byte
+/// payload.  Returns the part of `out` the payload goes in.
+fn start_record(
+    out: &mut [u8],
+    typ: ContentType,
+    version: ProtocolVersion,
+    len: usize,
+) -> &mut [u8] {
+    out[0] = typ.get_u8();
+    codec::put_u16(version.get_u16(), &mut out[1..]);
+    codec::put_u16(len as u16, &mut out[3..]);
+    &mut out[PACKET_OVERHEAD..PACKET_OVERHEAD + len]
+}
+
+/// Encrypt the first `len` bytes of `buf` in place, and write the tag
+/// after them.
+fn seal_in_place<A: AsRef<[u8]>>(
+    key: &aead::LessSafeKey,
+    nonce: aead::Nonce,
+    aad: aead::Aad<A>,
+    buf: &mut [u8],
+    len: usize,
+) -> Result<(), TlsError> {
+    let (payload, tag_out) = buf.split_at_mut(len);
+    let tag = key
+        .seal_in_place_separate_tag(nonce, aad, payload)
+        .map_err(|_| TlsError::General("encrypt failed".to_string()))?;
+    tag_out.copy_from_slice(tag.as_ref());
+    Ok(())
 }
 
 impl dyn MessageEncrypter {
@@ -32,9 +87,12 @@ impl dyn MessageDecrypter {
     }
 }
 
+#[cfg(feature = "tls12")]
 pub type MessageCipherPair = (Box<dyn MessageDecrypter>, Box<dyn MessageEncrypter>);
 
+#[cfg(feature = "tls12")]
 const TLS12_AAD_SIZE: usize = 8 + 1 + 2 + 2;
+#[cfg(feature = "tls12")]
 fn make_tls12_aad(
     seq: u64,
     typ: ContentType,
@@ -49,6 +107,7 @@ fn make_tls12_aad(
     ring::aead::Aad::from(out)
 }
 
+#[cfg(feature = "tls12")]
 fn make_tls12_gcm_nonce(write_iv: &[u8], explicit: &[u8]) -> Iv {
     debug_assert_eq!(write_iv.len(), 4);
     debug_assert_eq!(explicit.len(), 8);
@@ -66,13 +125,17 @@ fn make_tls12_gcm_nonce(write_iv: &[u8], explicit: &[u8]) -> Iv {
     iv
 }
 
+#[cfg(feature = "tls12")]
 pub type BuildTLS12Decrypter = fn(&[u8], &[u8]) -> Box<dyn MessageDecrypter>;
+#[cfg(feature = "tls12")]
 pub type BuildTLS12Encrypter = fn(&[u8], &[u8], &[u8]) -> Box<dyn MessageEncrypter>;
 
+#[cfg(feature = "tls12")]
 pub fn build_tls12_gcm_128_decrypter(key: &[u8], iv: &[u8]) -> Box<dyn MessageDecrypter> {
     Box::new(GCMMessageDecrypter::new(&aead::AES_128_GCM, key, iv))
 }
 
+#[cfg(feature = "tls12")]
 pub fn build_tls12_gcm_128_encrypter(
     key: &[u8],
     iv: &[u8],
@@ -82,10 +145,12 @@ pub fn build_tls12_gcm_128_encrypter(
     Box::new(GCMMessageEncrypter::new(&aead::AES_128_GCM, key, nonce))
 }
 
+#[cfg(feature = "tls12")]
 pub fn build_tls12_gcm_256_decrypter(key: &[u8], iv: &[u8]) -> Box<dyn MessageDecrypter> {
     Box::new(GCMMessageDecrypter::new(&aead::AES_256_GCM, key, iv))
 }
 
+#[cfg(feature = "tls12")]
 pub fn build_tls12_gcm_256_encrypter(
     key: &[u8],
     iv: &[u8],
@@ -95,6 +160,7 @@ pub fn build_tls12_gcm_256_encrypter(
     Box::new(GCMMessageEncrypter::new(&aead::AES_256_GCM, key, nonce))
 }
 
+#[cfg(feature = "tls12")]
 pub fn build_tls12_chacha_decrypter(key: &[u8], iv: &[u8]) -> Box<dyn MessageDecrypter> {
     Box::new(ChaCha20Poly1305MessageDecrypter::new(
         &aead::CHACHA20_POLY1305,
@@ -103,6 +169,7 @@ pub fn build_tls12_chacha_decrypter(key: &[u8], iv: &[u8]) -> Box<dyn MessageDec
     ))
 }
 
+#[cfg(feature = "tls12")]
 pub fn build_tls12_chacha_encrypter(key: &[u8], iv: &[u8], _: &[u8]) -> Box<dyn MessageEncrypter> {
     Box::new(ChaCha20Poly1305MessageEncrypter::new(
         &aead::CHACHA20_POLY1305,
@@ -113,6 +180,7 @@ pub fn build_tls12_chacha_encrypter(key: &[u8], iv: &[u8], _: &[u8]) -> Box<dyn
 
 /// Make a `MessageCipherPair` based on the given supported ciphersuite `scs`,
 /// and the session's `secrets`.
+#[cfg(feature = "tls12")]
 pub fn new_tls12(
     scs: &'static SupportedCipherSuite,
     secrets: &SessionSecrets,
@@ -149,6 +217,7 @@ pub fn new_tls12(
     )
 }
 
+#[cfg(feature = "tls13")]
 pub fn new_tls13_read(
     scs: &'static SupportedCipherSuite,
     secret: &hkdf::Prk,
@@ -159,6 +228,7 @@ pub fn new_tls13_read(
     Box::new(TLS13MessageDecrypter::new(key, iv))
 }
 
+#[cfg(feature = "tls13")]
 pub fn new_tls13_write(
     scs: &'static SupportedCipherSuite,
     secret: &hkdf::Prk,
@@ -170,26 +240,32 @@ pub fn new_tls13_write(
 }
 
 /// A `MessageEncrypter` for AES-GCM AEAD ciphersuites. TLS 1.2 only.
+#[cfg(feature = "tls12")]
 pub struct GCMMessageEncrypter {
     enc_key: aead::LessSafeKey,
     iv: Iv,
 }
 
 /// A `MessageDecrypter` for AES-GCM AEAD ciphersuites.  TLS1.2 only.
+#[cfg(feature = "tls12")]
 pub struct GCMMessageDecrypter {
     dec_key: aead::LessSafeKey,
     dec_salt: [u8; 4],
 }
 
+#[cfg(feature = "tls12")]
 const GCM_EXPLICIT_NONCE_LEN: usize = 8;
+#[cfg(feature = "tls12")]
 const GCM_OVERHEAD: usize = GCM_EXPLICIT_NONCE_LEN + 16;
 
+#[cfg(feature = "tls12")]
 impl MessageDecrypter for GCMMessageDecrypter {
-    fn decrypt(&self, mut msg: Message, seq: u64) -> Result<Message, TlsError> {
-        let payload = msg
-            .take_opaque_payload()
-            .ok_or(TlsError::DecryptError)?;
-        let mut buf = payload.0;
+    fn decrypt<'a>(
+        &self,
+        msg: BorrowMessageMut<'a>,
+        seq: u64,
+    ) -> Result<BorrowMessage<'a>, TlsError> {
+        let buf = msg.payload;
 
         if buf.len() < GCM_OVERHEAD {
             return Err(TlsError::DecryptError);
@@ -210,49 +286,45 @@ impl MessageDecrypter for GCMMessageDecrypter {
 
         let aad = make_tls12_aad(seq, msg.typ, msg.version, buf.len() - GCM_OVERHEAD);
 
-        let plain_len = self
+        let plain = self
             .dec_key
-            .open_within(nonce, aad, &mut buf, GCM_EXPLICIT_NONCE_LEN..)
-            .map_err(|_| TlsError::DecryptError)?
-            .len();
+            .open_within(nonce, aad, buf, GCM_EXPLICIT_NONCE_LEN..)
+            .map_err(|_| TlsError::DecryptError)?;
 
-        if plain_len > MAX_FRAGMENT_LEN {
+        if plain.len() > MAX_FRAGMENT_LEN {
             return Err(TlsError::PeerSentOversizedRecord);
         }
 
-        buf.truncate(plain_len);
-
-        Ok(Message {
+        Ok(BorrowMessage {
             typ: msg.typ,
             version: msg.version,
-            payload: MessagePayload::new_opaque(buf),
+            payload: plain,
         })
     }
 }
 
+#[cfg(feature = "tls12")]
 impl MessageEncrypter for GCMMessageEncrypter {
-    fn encrypt(&self, msg: BorrowMessage, seq: u64) -> Result<Message, TlsError> {
+    fn encrypt(&self, msg: BorrowMessage, seq: u64, out: &mut [u8]) -> Result<usize, TlsError> {
         let nonce = make_tls13_nonce(&self.iv, seq);
         let aad = make_tls12_aad(seq, msg.typ, msg.version, msg.payload.len());
 
-        let total_len = msg.payload.len() + self.enc_key.algorithm().tag_len();
-        let mut payload = Vec::with_capacity(GCM_EXPLICIT_NONCE_LEN + total_len);
-        payload.extend_from_slice(&nonce.as_ref()[4..]);
-        payload.extend_from_slice(&msg.payload);
+        let total_len = self.encrypted_len(msg.payload.len());
+        let payload = start_record(out, msg.typ, msg.version, total_len - PACKET_OVERHEAD);
+        let (explicit_nonce, payload) = payload.split_at_mut(GCM_EXPLICIT_NONCE_LEN);
+        explicit_nonce.copy_from_slice(&nonce.as_ref()[4..]);
+        payload[..msg.payload.len()].copy_from_slice(msg.payload);
 
-        self.enc_key
-            .seal_in_place_separate_tag(nonce, aad, &mut payload[GCM_EXPLICIT_NONCE_LEN..])
-            .map(|tag| payload.extend(tag.as_ref()))
-            .map_err(|_| TlsError::General("encrypt failed".to_string()))?;
+        seal_in_place(&self.enc_key, nonce, aad, payload, msg.payload.len())?;
+        Ok(total_len)
+    }
 
-        Ok(Message {
-            typ: msg.typ,
-            version: msg.version,
-            payload: MessagePayload::new_opaque(payload),
-        })
+    fn encrypted_len(&self, len: usize) -> usize {
+        PACKET_OVERHEAD + GCM_EXPLICIT_NONCE_LEN + len + self.enc_key.algorithm().tag_len()
     }
 }
 
+#[cfg(feature = "tls12")]
 impl GCMMessageEncrypter {
     fn new(alg: &'static aead::Algorithm, enc_key: &[u8], iv: Iv) -> GCMMessageEncrypter {
         let key = aead::UnboundKey::new(alg, enc_key).unwrap();
@@ -263,6 +335,7 @@ impl GCMMessageEncrypter {
     }
 }
 
+#[cfg(feature = "tls12")]
 impl GCMMessageDecrypter {
     fn new(alg: &'static aead::Algorithm, dec_key: &[u8], dec_iv: &[u8]) -> GCMMessageDecrypter {
         let key = aead::UnboundKey::new(alg, dec_key).unwrap();
@@ -284,10 +357,12 @@ impl GCMMessageDecrypter {
 pub(crate) struct Iv([u8; ring::aead::NONCE_LEN]);
 
 impl Iv {
+    #[cfg(feature = "tls12")]
     pub(crate) fn new(value: [u8; ring::aead::NONCE_LEN]) -> Self {
         Self(value)
     }
 
+    #[cfg(feature = "tls12")]
     fn copy(value: &[u8]) -> Self {
         debug_assert_eq!(value.len(), ring::aead::NONCE_LEN);
         let mut iv = Iv::new(Default::default());
@@ -295,20 +370,23 @@ impl Iv {
         iv
     }
 
-    #[cfg(test)]
+    #[cfg(any(test, feature = "secret_extraction"))]
     pub(crate) fn value(&self) -> &[u8; 12] {
         &self.0
     }
 }
 
+#[cfg(feature = "tls13")]
 pub(crate) struct IvLen;
 
+#[cfg(feature = "tls13")]
 impl hkdf::KeyType for IvLen {
     fn len(&self) -> usize {
         aead::NONCE_LEN
     }
 }
 
+#[cfg(feature = "tls13")]
 impl From<hkdf::Okm<'_, IvLen>> for Iv {
     fn from(okm: hkdf::Okm<IvLen>) -> Self {
         let mut r = Iv(Default::default());
@@ -317,25 +395,25 @@ impl From<hkdf::Okm<'_, IvLen>> for Iv {
     }
 }
 
+#[cfg(feature = "tls13")]
 struct TLS13MessageEncrypter {
     enc_key: aead::LessSafeKey,
     iv: Iv,
 }
 
+#[cfg(feature = "tls13")]
 struct TLS13MessageDecrypter {
     dec_key: aead::LessSafeKey,
     iv: Iv,
 }
 
-fn unpad_tls13(v: &mut Vec<u8>) -> ContentType {
-    loop {
-        match v.pop() {
-            Some(0) => {}
-
-            Some(content_type) => return ContentType::read_bytes(&[content_type]).unwrap(),
-
-            None => return ContentType::Unknown(0),
-        }
+/// Find the content type at the end of a TLSInnerPlaintext `v`,
+/// returning it with the length of the content before it.
+#[cfg(feature = "tls13")]
+fn unpad_tls13(v: &[u8]) -> (ContentType, usize) {
+    match v.iter().rposition(|&b| b != 0) {
+        Some(pos) => (ContentType::read_bytes(&v[pos..pos + 1]).unwrap(), pos),
+        None => (ContentType::Unknown(0), 0),
     }
 }
 
@@ -353,6 +431,7 @@ fn make_tls13_nonce(iv: &Iv, seq: u64) -> ring::aead::Nonce {
     aead::Nonce::assume_unique_for_key(nonce)
 }
 
+#[cfg(feature = "tls13")]
 fn make_tls13_aad(len: usize) -> ring::aead::Aad<[u8; 1 + 2 + 2]> {
     ring::aead::Aad::from([
         0x17, // ContentType::ApplicationData
@@ -363,34 +442,41 @@ fn make_tls13_aad(len: usize) -> ring::aead::Aad<[u8; 1 + 2 + 2]> {
     ])
 }
 
+#[cfg(feature = "tls13")]
 impl MessageEncrypter for TLS13MessageEncrypter {
-    fn encrypt(&self, msg: BorrowMessage, seq: u64) -> Result<Message, TlsError> {
-        let total_len = msg.payload.len() + 1 + self.enc_key.algorithm().tag_len();
-        let mut buf = Vec::with_capacity(total_len);
-        buf.extend_from_slice(&msg.payload);
-        msg.typ.encode(&mut buf);
+    fn encrypt(&self, msg: BorrowMessage, seq: u64, out: &mut [u8]) -> Result<usize, TlsError> {
+        let total_len = self.encrypted_len(msg.payload.len());
+        let payload_len = total_len - PACKET_OVERHEAD;
+        let payload = start_record(
+            out,
+            ContentType::ApplicationData,
+            ProtocolVersion::TLSv1_2,
+            payload_len,
+        );
+        let inner_len = msg.payload.len() + 1;
+        payload[..msg.payload.len()].copy_from_slice(msg.payload);
+        payload[msg.payload.len()] = msg.typ.get_u8();
 
         let nonce = make_tls13_nonce(&self.iv, seq);
-        let aad = make_tls13_aad(total_len);
+        let aad = make_tls13_aad(payload_len);
 
-        self.enc_key
-            .seal_in_place_append_tag(nonce, aad, &mut buf)
-            .map_err(|_| TlsError::General("encrypt failed".to_string()))?;
+        seal_in_place(&self.enc_key, nonce, aad, payload, inner_len)?;
+        Ok(total_len)
+    }
 
-        Ok(Message {
-            typ: ContentType::ApplicationData,
-            version: ProtocolVersion::TLSv1_2,
-            payload: MessagePayload::new_opaque(buf),
-        })
+    fn encrypted_len(&self, len: usize) -> usize {
+        PACKET_OVERHEAD + len + 1 + self.enc_key.algorithm().tag_len()
     }
 }
 
+#[cfg(feature = "tls13")]
 impl MessageDecrypter for TLS13MessageDecrypter {
-    fn decrypt(&self, mut msg: Message, seq: u64) -> Result<Message, TlsError> {
-        let payload = msg
-            .take_opaque_payload()
-            .ok_or(TlsError::DecryptError)?;
-        let mut buf = payload.0;
+    fn decrypt<'a>(
+        &self,
+        msg: BorrowMessageMut<'a>,
+        seq: u64,
+    ) -> Result<BorrowMessage<'a>, TlsError> {
+        let buf = msg.payload;
 
         if buf.len() < self.dec_key.algorithm().tag_len() {
             return Err(TlsError::DecryptError);
@@ -398,36 +484,33 @@ impl MessageDecrypter for TLS13MessageDecrypter {
 
         let nonce = make_tls13_nonce(&self.iv, seq);
         let aad = make_tls13_aad(buf.len());
-        let plain_len = self
+        let plain = self
             .dec_key
-            .open_in_place(nonce, aad, &mut buf)
-            .map_err(|_| TlsError::DecryptError)?
-            .len();
-
-        buf.truncate(plain_len);
+            .open_in_place(nonce, aad, buf)
+            .map_err(|_| TlsError::DecryptError)?;
 
-        if buf.len() > MAX_FRAGMENT_LEN + 1 {
+        if plain.len() > MAX_FRAGMENT_LEN + 1 {
             return Err(TlsError::PeerSentOversizedRecord);
         }
 
-        let content_type = unpad_tls13(&mut buf);
+        let (content_type, plain_len) = unpad_tls13(plain);
         if content_type == ContentType::Unknown(0) {
-            let msg = "peer sent bad TLSInnerPlaintext".to_string();
-            return Err(TlsError::PeerMisbehavedError(msg));
+            return Err(TlsError::PeerMisbehavedError(PeerMisbehaved::IllegalTlsInnerPlaintext));
         }
 
-        if buf.len() > MAX_FRAGMENT_LEN {
+        if plain_len > MAX_FRAGMENT_LEN {
             return Err(TlsError::PeerSentOversizedRecord);
         }
 
-        Ok(Message {
+        Ok(BorrowMessage {
             typ: content_type,
             version: ProtocolVersion::TLSv1_3,
-            payload: MessagePayload::new_opaque(buf),
+            payload: &plain[..plain_len],
         })
     }
 }
 
+#[cfg(feature = "tls13")]
 impl TLS13MessageEncrypter {
     fn new(key: aead::UnboundKey, enc_iv: Iv) -> TLS13MessageEncrypter {
         TLS13MessageEncrypter {
@@ -437,6 +520,7 @@ impl TLS13MessageEncrypter {
     }
 }
 
+#[cfg(feature = "tls13")]
 impl TLS13MessageDecrypter {
     fn new(key: aead::UnboundKey, dec_iv: Iv) -> TLS13MessageDecrypter {
         TLS13MessageDecrypter {
@@ -449,6 +533,7 @@ impl TLS13MessageDecrypter {
 /// The RFC7905/RFC7539 ChaCha20Poly1305 construction.
 /// This implementation does the AAD construction required in TLS1.2.
 /// TLS1.3 uses `TLS13MessageEncrypter`.
+#[cfg(feature = "tls12")]
 pub struct ChaCha20Poly1305MessageEncrypter {
     enc_key: aead::LessSafeKey,
     enc_offset: Iv,
@@ -457,11 +542,13 @@ pub struct ChaCha20Poly1305MessageEncrypter {
 /// The RFC7905/RFC7539 ChaCha20Poly1305 construction.
 /// This implementation does the AAD construction required in TLS1.2.
 /// TLS1.3 uses `TLS13MessageDecrypter`.
+#[cfg(feature = "tls12")]
 pub struct ChaCha20Poly1305MessageDecrypter {
     dec_key: aead::LessSafeKey,
     dec_offset: Iv,
 }
 
+#[cfg(feature = "tls12")]
 impl ChaCha20Poly1305MessageEncrypter {
     fn new(
         alg: &'static aead::Algorithm,
@@ -476,6 +563,7 @@ impl ChaCha20Poly1305MessageEncrypter {
     }
 }
 
+#[cfg(feature = "tls12")]
 impl ChaCha20Poly1305MessageDecrypter {
     fn new(
         alg: &'static aead::Algorithm,
@@ -490,14 +578,17 @@ impl ChaCha20Poly1305MessageDecrypter {
     }
 }
 
+#[cfg(feature = "tls12")]
 const CHACHAPOLY1305_OVERHEAD: usize = 16;
 
+#[cfg(feature = "tls12")]
 impl MessageDecrypter for ChaCha20Poly1305MessageDecrypter {
-    fn decrypt(&self, mut msg: Message, seq: u64) -> Result<Message, TlsError> {
-        let payload = msg
-            .take_opaque_payload()
-            .ok_or(TlsError::DecryptError)?;
-        let mut buf = payload.0;
+    fn decrypt<'a>(
+        &self,
+        msg: BorrowMessageMut<'a>,
+        seq: u64,
+    ) -> Result<BorrowMessage<'a>, TlsError> {
+        let buf = msg.payload;
 
         if buf.len() < CHACHAPOLY1305_OVERHEAD {
             return Err(TlsError::DecryptError);
@@ -511,44 +602,39 @@ impl MessageDecrypter for ChaCha20Poly1305MessageDecrypter {
             buf.len() - CHACHAPOLY1305_OVERHEAD,
         );
 
-        let plain_len = self
+        let plain = self
             .dec_key
-            .open_in_place(nonce, aad, &mut buf)
-            .map_err(|_| TlsError::DecryptError)?
-            .len();
+            .open_in_place(nonce, aad, buf)
+            .map_err(|_| TlsError::DecryptError)?;
 
-        if plain_len > MAX_FRAGMENT_LEN {
+        if plain.len() > MAX_FRAGMENT_LEN {
             return Err(TlsError::PeerSentOversizedRecord);
         }
 
-        buf.truncate(plain_len);
-
-        Ok(Message {
+        Ok(BorrowMessage {
             typ: msg.typ,
             version: msg.version,
-            payload: MessagePayload::new_opaque(buf),
+            payload: plain,
         })
     }
 }
 
+#[cfg(feature = "tls12")]
 impl MessageEncrypter for ChaCha20Poly1305MessageEncrypter {
-    fn encrypt(&self, msg: BorrowMessage, seq: u64) -> Result<Message, TlsError> {
+    fn encrypt(&self, msg: BorrowMessage, seq: u64, out: &mut [u8]) -> Result<usize, TlsError> {
         let nonce = make_tls13_nonce(&self.enc_offset, seq);
         let aad = make_tls12_aad(seq, msg.typ, msg.version, msg.payload.len());
 
-        let total_len = msg.payload.len() + self.enc_key.algorithm().tag_len();
-        let mut buf = Vec::with_capacity(total_len);
-        buf.extend_from_slice(&msg.payload);
+        let total_len = self.encrypted_len(msg.payload.len());
+        let payload = start_record(out, msg.typ, msg.version, total_len - PACKET_OVERHEAD);
+        payload[..msg.payload.len()].copy_from_slice(msg.payload);
 
-        self.enc_key
-            .seal_in_place_append_tag(nonce, aad, &mut buf)
-            .map_err(|_| TlsError::General("encrypt failed".to_string()))?;
+        seal_in_place(&self.enc_key, nonce, aad, payload, msg.payload.len())?;
+        Ok(total_len)
+    }
 
-        Ok(Message {
-            typ: msg.typ,
-            version: msg.version,
-            payload: MessagePayload::new_opaque(buf),
-        })
+    fn encrypted_len(&self, len: usize) -> usize {
+        PACKET_OVERHEAD + len + self.enc_key.algorithm().tag_len()
     }
 }
 
@@ -556,16 +642,24 @@ impl MessageEncrypter for ChaCha20Poly1305MessageEncrypter {
 pub struct InvalidMessageEncrypter {}
 
 impl MessageEncrypter for InvalidMessageEncrypter {
-    fn encrypt(&self, _m: BorrowMessage, _seq: u64) -> Result<Message, TlsError> {
+    fn encrypt(&self, _m: BorrowMessage, _seq: u64, _out: &mut [u8]) -> Result<usize, TlsError> {
         Err(TlsError::General("encrypt not yet available".to_string()))
     }
+
+    fn encrypted_len(&self, len: usize) -> usize {
+        PACKET_OVERHEAD + len
+    }
 }
 
 /// A `MessageDecrypter` which doesn't work.
 pub struct InvalidMessageDecrypter {}
 
 impl MessageDecrypter for InvalidMessageDecrypter {
-    fn decrypt(&self, _m: Message, _seq: u64) -> Result<Message, TlsError> {
+    fn decrypt<'a>(
+        &self,
+        _m: BorrowMessageMut<'a>,
+        _seq: u64,
+    ) -> Result<BorrowMessage<'a>, TlsError> {
         Err(TlsError::DecryptError)
     }
 }
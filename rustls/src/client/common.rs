@@ -2,22 +2,27 @@ use crate::hash_hs;
 #[cfg(feature = "logging")]
 use crate::log::trace;
 use crate::msgs::enums::ExtensionType;
+#[cfg(feature = "tls13")]
 use crate::msgs::enums::NamedGroup;
 use crate::msgs::handshake::CertificatePayload;
+#[cfg(feature = "tls12")]
 use crate::msgs::handshake::DigitallySignedStruct;
 use crate::msgs::handshake::SCTList;
 use crate::msgs::handshake::ServerExtension;
 use crate::msgs::handshake::SessionID;
 use crate::msgs::persist;
+use crate::server_name::ServerName;
 use crate::sign;
+#[cfg(feature = "tls13")]
 use crate::kx;
-use webpki;
 
 use std::mem;
 
 pub struct ServerCertDetails {
     pub cert_chain: CertificatePayload,
     pub ocsp_response: Vec<u8>,
+    #[cfg(feature = "tls13")]
+    pub intermediate_ocsp: Vec<Vec<u8>>,
     pub scts: Option<SCTList>,
 }
 
@@ -28,6 +33,8 @@ impl ServerCertDetails {
         ServerCertDetails {
             cert_chain,
             ocsp_response,
+            #[cfg(feature = "tls13")]
+            intermediate_ocsp: Vec::new(),
             scts,
         }
     }
@@ -36,6 +43,15 @@ impl ServerCertDetails {
         mem::replace(&mut self.cert_chain, Vec::new())
     }
 
+    pub fn take_ocsp_response(&mut self) -> Vec<u8> {
+        mem::take(&mut self.ocsp_response)
+    }
+
+    #[cfg(feature = "tls13")]
+    pub fn take_intermediate_ocsp(&mut self) -> Vec<Vec<u8>> {
+        mem::take(&mut self.intermediate_ocsp)
+    }
+
     pub fn scts(&self) -> impl Iterator<Item=&[u8]> {
         self.scts
             .as_ref()
@@ -46,11 +62,13 @@ impl ServerCertDetails {
     }
 }
 
+#[cfg(feature = "tls12")]
 pub struct ServerKXDetails {
     pub kx_params: Vec<u8>,
     pub kx_sig: DigitallySignedStruct,
 }
 
+#[cfg(feature = "tls12")]
 impl ServerKXDetails {
     pub fn new(params: Vec<u8>, sig: DigitallySignedStruct) -> ServerKXDetails {
         ServerKXDetails {
@@ -63,25 +81,28 @@ impl ServerKXDetails {
 pub struct HandshakeDetails {
     pub resuming_session: Option<persist::ClientSessionValue>,
     pub transcript: hash_hs::HandshakeHash,
+    #[cfg(feature = "tls12")]
     pub using_ems: bool,
     pub session_id: SessionID,
-    pub dns_name: webpki::DNSName,
+    pub server_name: ServerName,
 }
 
 impl HandshakeDetails {
-    pub fn new(host_name: webpki::DNSName) -> HandshakeDetails {
+    pub fn new(server_name: ServerName) -> HandshakeDetails {
         HandshakeDetails {
             resuming_session: None,
             transcript: hash_hs::HandshakeHash::new(),
+            #[cfg(feature = "tls12")]
             using_ems: false,
             session_id: SessionID::empty(),
-            dns_name: host_name,
+            server_name,
         }
     }
 }
 
 pub struct ClientHelloDetails {
     pub sent_extensions: Vec<ExtensionType>,
+    #[cfg(feature = "tls13")]
     pub offered_key_shares: Vec<kx::KeyExchange>,
 }
 
@@ -89,20 +110,24 @@ impl ClientHelloDetails {
     pub fn new() -> ClientHelloDetails {
         ClientHelloDetails {
             sent_extensions: Vec::new(),
+            #[cfg(feature = "tls13")]
             offered_key_shares: Vec::new(),
         }
     }
 
+    #[cfg(feature = "tls13")]
     pub fn server_may_send_sct_list(&self) -> bool {
         self.sent_extensions.contains(&ExtensionType::SCT)
     }
 
+    #[cfg(feature = "tls13")]
     pub fn has_key_share(&self, group: NamedGroup) -> bool {
         self.offered_key_shares
             .iter()
             .any(|share| share.group() == group)
     }
 
+    #[cfg(feature = "tls13")]
     pub fn find_key_share(&mut self, group: NamedGroup) -> Option<kx::KeyExchange> {
         self.offered_key_shares
             .iter()
@@ -110,6 +135,7 @@ impl ClientHelloDetails {
             .map(|idx| self.offered_key_shares.remove(idx))
     }
 
+    #[cfg(feature = "tls13")]
     pub fn find_key_share_and_discard_others(
         &mut self,
         group: NamedGroup,
@@ -141,11 +167,13 @@ impl ClientHelloDetails {
     }
 }
 
+#[cfg(feature = "tls12")]
 pub struct ReceivedTicketDetails {
     pub new_ticket: Vec<u8>,
     pub new_ticket_lifetime: u32,
 }
 
+#[cfg(feature = "tls12")]
 impl ReceivedTicketDetails {
     pub fn new() -> ReceivedTicketDetails {
         ReceivedTicketDetails::from(Vec::new(), 0)
@@ -162,6 +190,7 @@ impl ReceivedTicketDetails {
 pub struct ClientAuthDetails {
     pub cert: Option<CertificatePayload>,
     pub signer: Option<Box<dyn sign::Signer>>,
+    #[cfg(feature = "tls13")]
     pub auth_context: Option<Vec<u8>>,
 }
 
@@ -170,6 +199,7 @@ impl ClientAuthDetails {
         ClientAuthDetails {
             cert: None,
             signer: None,
+            #[cfg(feature = "tls13")]
             auth_context: None,
         }
     }
@@ -1,8 +1,12 @@
 use crate::client;
 use crate::error::TlsError;
 use crate::key;
+use crate::msgs::codec::Codec;
 use crate::msgs::enums::SignatureScheme;
+use crate::msgs::persist;
+use crate::server_name::ServerName;
 use crate::sign;
+use crate::time::{self, SystemTimeProvider, TimeProvider};
 
 use std::collections;
 use std::sync::{Arc, Mutex};
@@ -20,50 +24,126 @@ impl client::StoresClientSessions for NoClientSessionStorage {
     }
 }
 
+struct CachedValue {
+    value: Vec<u8>,
+    /// When `value` expires, in UNIX seconds, if it does.
+    expires: Option<u64>,
+    /// When `value` was last stored or retrieved, in `CacheState::clock`
+    /// ticks.
+    last_used: u64,
+}
+
+impl CachedValue {
+    fn has_expired(&self, now: Option<u64>) -> bool {
+        match (self.expires, now) {
+            (Some(expires), Some(now)) => expires < now,
+            _ => false,
+        }
+    }
+}
+
+struct CacheState {
+    values: collections::HashMap<Vec<u8>, CachedValue>,
+    /// Counts puts and gets, to order values by when they were last used.
+    clock: u64,
+}
+
 /// An implementer of `StoresClientSessions` that stores everything
 /// in memory.  It enforces a limit on the number of entries
 /// to bound memory usage.
+///
+/// Sessions are dropped once the lifetime the server gave their
+/// ticket has passed.  When the cache is full, the least recently
+/// used entry makes way for a new one.
 pub struct ClientSessionMemoryCache {
-    cache: Mutex<collections::HashMap<Vec<u8>, Vec<u8>>>,
+    cache: Mutex<CacheState>,
     max_entries: usize,
+    time_provider: Arc<dyn TimeProvider>,
 }
 
 impl ClientSessionMemoryCache {
     /// Make a new ClientSessionMemoryCache.  `size` is the
     /// maximum number of stored sessions.
     pub fn new(size: usize) -> Arc<ClientSessionMemoryCache> {
+        ClientSessionMemoryCache::new_with_time_provider(size, Arc::new(SystemTimeProvider))
+    }
+
+    /// Like `new`, but sessions' expiry is judged by the time
+    /// `time_provider` gives.
+    pub fn new_with_time_provider(
+        size: usize,
+        time_provider: Arc<dyn TimeProvider>,
+    ) -> Arc<ClientSessionMemoryCache> {
         debug_assert!(size > 0);
         Arc::new(ClientSessionMemoryCache {
-            cache: Mutex::new(collections::HashMap::new()),
+            cache: Mutex::new(CacheState {
+                values: collections::HashMap::new(),
+                clock: 0,
+            }),
             max_entries: size,
+            time_provider,
         })
     }
 
-    fn limit_size(&self) {
+    /// Forget everything stored for `server_name`, so the next
+    /// session with it starts afresh.
+    pub fn purge(&self, server_name: &ServerName) {
         let mut cache = self.cache.lock().unwrap();
-        while cache.len() > self.max_entries {
-            let k = cache.keys().next().unwrap().clone();
-            cache.remove(&k);
+        for key in &[
+            persist::ClientSessionKey::session_for_server_name(server_name),
+            persist::ClientSessionKey::hint_for_server_name(server_name),
+        ] {
+            cache.values.remove(&key.get_encoding());
         }
     }
+
+    fn now(&self) -> Option<u64> {
+        time::unix_time(self.time_provider.as_ref()).ok()
+    }
 }
 
 impl client::StoresClientSessions for ClientSessionMemoryCache {
     fn put(&self, key: Vec<u8>, value: Vec<u8>) -> bool {
-        self.cache
-            .lock()
-            .unwrap()
-            .insert(key, value);
-        self.limit_size();
+        let now = self.now();
+        let mut cache = self.cache.lock().unwrap();
+        cache.clock += 1;
+        let entry = CachedValue {
+            expires: persist::ClientSessionValue::read_expiry(&value),
+            value,
+            last_used: cache.clock,
+        };
+        cache.values.insert(key, entry);
+
+        if cache.values.len() > self.max_entries {
+            cache
+                .values
+                .retain(|_, entry| !entry.has_expired(now));
+        }
+        while cache.values.len() > self.max_entries {
+            let lru = cache
+                .values
+                .iter()
+                .min_by_key(|(_, entry)| entry.last_used)
+                .map(|(key, _)| key.clone())
+                .unwrap();
+            cache.values.remove(&lru);
+        }
         true
     }
 
     fn get(&self, key: &[u8]) -> Option<Vec<u8>> {
-        self.cache
-            .lock()
-            .unwrap()
-            .get(key)
-            .cloned()
+        let now = self.now();
+        let mut cache = self.cache.lock().unwrap();
+        cache.clock += 1;
+        let clock = cache.clock;
+
+        let entry = cache.values.get_mut(key)?;
+        if entry.has_expired(now) {
+            cache.values.remove(key);
+            return None;
+        }
+        entry.last_used = clock;
+        Some(entry.value.clone())
     }
 }
 
@@ -92,10 +172,9 @@ impl AlwaysResolvesClientCert {
     ) -> Result<AlwaysResolvesClientCert, TlsError> {
         let key = sign::any_supported_type(priv_key)
             .map_err(|_| TlsError::General("invalid private key".into()))?;
-        Ok(AlwaysResolvesClientCert(sign::CertifiedKey::new(
-            chain,
-            Arc::new(key),
-        )))
+        let ck = sign::CertifiedKey::new(chain, Arc::new(key));
+        ck.cross_check_end_entity_cert(None)?;
+        Ok(AlwaysResolvesClientCert(ck))
     }
 }
 
@@ -113,6 +192,56 @@ impl client::ResolvesClientCert for AlwaysResolvesClientCert {
     }
 }
 
+/// Something that chooses between several client certificates, by
+/// the issuers the server says it accepts.
+///
+/// The first certificate added which is one of, or chains to one of,
+/// the server's acceptable issuers, and whose key can make one of the
+/// server's signature schemes, is used.
+#[derive(Default)]
+pub struct ResolvesClientCertUsingIssuers {
+    keys: Vec<sign::CertifiedKey>,
+}
+
+impl ResolvesClientCertUsingIssuers {
+    /// Create a new and empty (i.e., knows no certificates) resolver.
+    pub fn new() -> ResolvesClientCertUsingIssuers {
+        ResolvesClientCertUsingIssuers { keys: Vec::new() }
+    }
+
+    /// Add a new `sign::CertifiedKey` to be chosen from.  Its chain
+    /// should include intermediates, so it can match a server which
+    /// names the root.
+    ///
+    /// This function fails if the certificate chain is syntactically
+    /// faulty.
+    pub fn add(&mut self, ck: sign::CertifiedKey) -> Result<(), TlsError> {
+        ck.cross_check_end_entity_cert(None)?;
+        self.keys.push(ck);
+        Ok(())
+    }
+}
+
+impl client::ResolvesClientCert for ResolvesClientCertUsingIssuers {
+    fn resolve(
+        &self,
+        acceptable_issuers: &[&[u8]],
+        sigschemes: &[SignatureScheme],
+    ) -> Option<sign::CertifiedKey> {
+        self.keys
+            .iter()
+            .find(|ck| {
+                ck.is_issued_by_any(acceptable_issuers)
+                    && ck.key.choose_scheme(sigschemes).is_some()
+            })
+            .cloned()
+    }
+
+    fn has_certs(&self) -> bool {
+        !self.keys.is_empty()
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -183,4 +312,95 @@ mod test {
 
         assert_eq!(count, 4);
     }
+
+    #[test]
+    fn test_clientsessionmemorycache_drops_least_recently_used() {
+        let c = ClientSessionMemoryCache::new(2);
+        c.put(vec![0x01], vec![0x02]);
+        c.put(vec![0x03], vec![0x04]);
+        assert_eq!(c.get(&[0x01]), Some(vec![0x02]));
+        c.put(vec![0x05], vec![0x06]);
+
+        assert_eq!(c.get(&[0x01]), Some(vec![0x02]));
+        assert_eq!(c.get(&[0x03]), None);
+        assert_eq!(c.get(&[0x05]), Some(vec![0x06]));
+    }
+
+    struct FixedTime(Mutex<u64>);
+
+    impl TimeProvider for FixedTime {
+        fn current_time(&self) -> Option<std::time::SystemTime> {
+            let secs = *self.0.lock().unwrap();
+            Some(std::time::UNIX_EPOCH + std::time::Duration::from_secs(secs))
+        }
+    }
+
+    fn session_value(epoch: u64, lifetime: u32) -> Vec<u8> {
+        let mut value = persist::ClientSessionValue::new(
+            crate::ProtocolVersion::TLSv1_3,
+            crate::ALL_CIPHERSUITES[0],
+            &crate::msgs::handshake::SessionID::empty(),
+            vec![0x01],
+            vec![0x02],
+            &vec![],
+        );
+        value.set_times(epoch, lifetime, 0);
+        value.get_encoding()
+    }
+
+    #[test]
+    fn test_clientsessionmemorycache_drops_expired_sessions() {
+        let time = Arc::new(FixedTime(Mutex::new(1000)));
+        let c = ClientSessionMemoryCache::new_with_time_provider(4, time.clone());
+        let value = session_value(1000, 100);
+        c.put(vec![0x01], value.clone());
+        c.put(vec![0x02], session_value(1000, 0));
+
+        *time.0.lock().unwrap() = 1100;
+        assert_eq!(c.get(&[0x01]), Some(value));
+
+        *time.0.lock().unwrap() = 1101;
+        assert_eq!(c.get(&[0x01]), None);
+        assert!(c.get(&[0x02]).is_some());
+    }
+
+    #[test]
+    fn test_clientsessionmemorycache_prefers_dropping_expired_sessions() {
+        let time = Arc::new(FixedTime(Mutex::new(1000)));
+        let c = ClientSessionMemoryCache::new_with_time_provider(2, time.clone());
+        c.put(vec![0x01], vec![0x02]);
+        c.put(vec![0x03], session_value(1000, 10));
+        *time.0.lock().unwrap() = 2000;
+        c.put(vec![0x05], vec![0x06]);
+
+        assert_eq!(c.get(&[0x01]), Some(vec![0x02]));
+        assert_eq!(c.get(&[0x05]), Some(vec![0x06]));
+    }
+
+    #[test]
+    fn test_clientsessionmemorycache_purges_server() {
+        use std::convert::TryFrom;
+
+        let c = ClientSessionMemoryCache::new(4);
+        let name = ServerName::try_from("example.com").unwrap();
+        let other = ServerName::try_from("example.org").unwrap();
+        for server_name in &[&name, &other] {
+            c.put(
+                persist::ClientSessionKey::session_for_server_name(server_name).get_encoding(),
+                vec![0x01],
+            );
+            c.put(
+                persist::ClientSessionKey::hint_for_server_name(server_name).get_encoding(),
+                vec![0x02],
+            );
+        }
+
+        c.purge(&name);
+        let session_key = persist::ClientSessionKey::session_for_server_name(&name);
+        let hint_key = persist::ClientSessionKey::hint_for_server_name(&name);
+        assert_eq!(c.get(&session_key.get_encoding()), None);
+        assert_eq!(c.get(&hint_key.get_encoding()), None);
+        let other_key = persist::ClientSessionKey::session_for_server_name(&other);
+        assert_eq!(c.get(&other_key.get_encoding()), Some(vec![0x01]));
+    }
 }
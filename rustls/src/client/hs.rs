@@ -1,13 +1,21 @@
 #[cfg(feature = "logging")]
 use crate::bs_debug;
+#[cfg(feature = "tls13")]
 use crate::check::check_message;
-use crate::{cipher, SupportedCipherSuite};
+#[cfg(feature = "tls13")]
+use crate::cipher;
+use crate::SupportedCipherSuite;
+#[cfg(feature = "secret_extraction")]
+use crate::suites::ConnectionTrafficSecrets;
 use crate::client::ClientSessionImpl;
-use crate::error::TlsError;
+use crate::error::{PeerMisbehaved, TlsError};
+#[cfg(feature = "tls12")]
+use crate::error::{CertificateError, CrlError, OcspError};
+#[cfg(feature = "tls13")]
 use crate::key_schedule::{KeyScheduleEarly, KeyScheduleHandshake};
 #[cfg(feature = "logging")]
 use crate::log::{debug, trace};
-use crate::msgs::base::Payload;
+use crate::msgs::base::{Payload, PayloadU8};
 #[cfg(feature = "quic")]
 use crate::msgs::base::PayloadU16;
 use crate::msgs::codec::{Codec, Reader};
@@ -20,19 +28,34 @@ use crate::msgs::handshake::{ClientExtension, HasServerExtensions};
 use crate::msgs::handshake::{ClientHelloPayload, HandshakeMessagePayload, HandshakePayload};
 use crate::msgs::handshake::{ConvertProtocolNameList, ProtocolNameList};
 use crate::msgs::handshake::{ECPointFormatList, SupportedPointFormats};
-use crate::msgs::handshake::{Random, SessionID};
+use crate::msgs::handshake::{Random, SessionID, UseSRTPData};
+use crate::msgs::handshake::{ServerExtension, UnknownExtension};
+#[cfg(feature = "tls12")]
+use crate::msgs::handshake::ServerHelloPayload;
 use crate::msgs::message::{Message, MessagePayload};
 use crate::msgs::persist;
+#[cfg(feature = "quic")]
+use crate::quic;
 use crate::rand;
-use crate::session::{SessionRandoms, SessionSecrets};
-use crate::ticketer;
+use crate::server_name::ServerName;
+use crate::session::HandshakeKind;
+use crate::session::SessionRandoms;
+#[cfg(feature = "tls12")]
+use crate::session::SessionSecrets;
+use crate::time;
+#[cfg(feature = "tls12")]
 use crate::verify;
 
-use crate::client::common::{ClientHelloDetails, ReceivedTicketDetails};
+use crate::client::common::ClientHelloDetails;
+#[cfg(feature = "tls12")]
+use crate::client::common::ReceivedTicketDetails;
 use crate::client::common::HandshakeDetails;
-use crate::client::{tls12, tls13};
+#[cfg(feature = "tls13")]
+use crate::client::tls13;
+#[cfg(feature = "tls12")]
+use crate::client::tls12;
 
-use webpki;
+#[cfg(feature = "tls13")]
 use ring::digest::Digest;
 
 pub type NextState = Box<dyn State + Send + Sync>;
@@ -53,21 +76,28 @@ pub trait State {
     }
 
     fn perhaps_write_key_update(&mut self, _sess: &mut ClientSessionImpl) {}
+
+    /// Returns our traffic secrets for the (tx, rx) directions.
+    #[cfg(feature = "secret_extraction")]
+    fn extract_secrets(
+        &self,
+        _sess: &ClientSessionImpl,
+    ) -> Result<(ConnectionTrafficSecrets, ConnectionTrafficSecrets), TlsError> {
+        Err(TlsError::HandshakeNotComplete)
+    }
 }
 
-pub fn illegal_param(sess: &mut ClientSessionImpl, why: &str) -> TlsError {
+pub fn illegal_param(sess: &mut ClientSessionImpl, why: PeerMisbehaved) -> TlsError {
     sess.common
         .send_fatal_alert(AlertDescription::IllegalParameter);
-    TlsError::PeerMisbehavedError(why.to_string())
+    TlsError::PeerMisbehavedError(why)
 }
 
 pub fn check_aligned_handshake(sess: &mut ClientSessionImpl) -> Result<(), TlsError> {
     if !sess.common.handshake_joiner.is_empty() {
         sess.common
             .send_fatal_alert(AlertDescription::UnexpectedMessage);
-        Err(TlsError::PeerMisbehavedError(
-            "key epoch or handshake flight with pending fragment".to_string(),
-        ))
+        Err(TlsError::PeerMisbehavedError(PeerMisbehaved::KeyEpochWithPendingFragment))
     } else {
         Ok(())
     }
@@ -75,9 +105,9 @@ pub fn check_aligned_handshake(sess: &mut ClientSessionImpl) -> Result<(), TlsEr
 
 fn find_session(
     sess: &mut ClientSessionImpl,
-    dns_name: webpki::DNSNameRef,
+    server_name: &ServerName,
 ) -> Option<persist::ClientSessionValue> {
-    let key = persist::ClientSessionKey::session_for_dns_name(dns_name);
+    let key = persist::ClientSessionKey::session_for_server_name(server_name);
     let key_buf = key.get_encoding();
 
     let value = sess
@@ -85,7 +115,7 @@ fn find_session(
         .session_persistence
         .get(&key_buf)
         .or_else(|| {
-            debug!("No cached session for {:?}", dns_name);
+            debug!("No cached session for {:?}", server_name);
             None
         })?;
 
@@ -93,7 +123,11 @@ fn find_session(
     let result = persist::ClientSessionValue::read(
         &mut reader, &sess.config.ciphersuites);
     if let Some(result) = result {
-        if result.has_expired(ticketer::timebase()) {
+        let now = time::unix_time(sess.config.time_provider.as_ref()).ok()?;
+        if result.has_expired(now) {
+            None
+        } else if !sess.config.may_resume(&result) {
+            debug!("Not resuming: that kind of resumption is disabled");
             None
         } else {
             #[cfg(feature = "quic")]
@@ -110,9 +144,9 @@ fn find_session(
     }
 }
 
-fn random_sessionid() -> Result<SessionID, rand::GetRandomFailed> {
+fn random_sessionid(rng: &dyn rand::ProvidesRandom) -> Result<SessionID, rand::GetRandomFailed> {
     let mut random_id = [0u8; 32];
-    rand::fill_random(&mut random_id)?;
+    rng.fill(&mut random_id)?;
     Ok(SessionID::new(&random_id))
 }
 
@@ -122,9 +156,9 @@ struct InitialState {
 }
 
 impl InitialState {
-    fn new(host_name: webpki::DNSName, extra_exts: Vec<ClientExtension>) -> InitialState {
+    fn new(server_name: ServerName, extra_exts: Vec<ClientExtension>) -> InitialState {
         InitialState {
-            handshake: HandshakeDetails::new(host_name),
+            handshake: HandshakeDetails::new(server_name),
             extra_exts,
         }
     }
@@ -146,15 +180,16 @@ impl InitialState {
                 .set_client_auth_enabled();
         }
 
-        self.handshake.resuming_session = find_session(sess, self.handshake.dns_name.as_ref());
+        self.handshake.resuming_session = find_session(sess, &self.handshake.server_name);
 
         if let Some(resuming) = &mut self.handshake.resuming_session {
+            sess.common.resumption_offered = true;
             if resuming.version == ProtocolVersion::TLSv1_2 {
                 // If we have a ticket, we use the sessionid as a signal that
                 // we're  doing an abbreviated handshake.  See section 3.4 in
                 // RFC5077.
                 if !resuming.ticket.0.is_empty() {
-                    resuming.session_id = random_sessionid()?;
+                    resuming.session_id = random_sessionid(sess.config.random_provider.as_ref())?;
                 }
                 self.handshake.session_id = resuming.session_id;
             }
@@ -164,14 +199,20 @@ impl InitialState {
         }
         // https://tools.ietf.org/html/rfc8446#appendix-D.4
         // https://tools.ietf.org/html/draft-ietf-quic-tls-34#ref-TLS13
-        if self.handshake.session_id.is_empty() && !sess.common.is_quic() {
-            self.handshake.session_id = random_sessionid()?;
+        // https://www.rfc-editor.org/rfc/rfc9147#section-5.3
+        if self.handshake.session_id.is_empty()
+            && !sess.common.is_quic()
+            && !sess.common.is_dtls()
+        {
+            self.handshake.session_id = random_sessionid(sess.config.random_provider.as_ref())?;
         }
 
-        let randoms = SessionRandoms::for_client()?;
+        let randoms = SessionRandoms::for_client(sess.config.random_provider.as_ref())?;
         let hello_details = ClientHelloDetails::new();
         let sent_tls13_fake_ccs = false;
         let may_send_sct_list = sess.config.verifier.request_scts();
+        sess.common
+            .emit(|ev| ev.handshake_started());
         emit_client_hello_for_retry(
             sess,
             self.handshake,
@@ -186,25 +227,29 @@ impl InitialState {
 
 pub fn start_handshake(
     sess: &mut ClientSessionImpl,
-    host_name: webpki::DNSName,
+    server_name: ServerName,
     extra_exts: Vec<ClientExtension>,
 ) -> NextStateOrError {
-    InitialState::new(host_name, extra_exts).emit_initial_client_hello(sess)
+    InitialState::new(server_name, extra_exts).emit_initial_client_hello(sess)
 }
 
 struct ExpectServerHello {
     handshake: HandshakeDetails,
     randoms: SessionRandoms,
+    #[cfg(feature = "tls13")]
     early_key_schedule: Option<KeyScheduleEarly>,
     hello: ClientHelloDetails,
+    #[cfg_attr(not(feature = "tls13"), allow(dead_code))]
     sent_tls13_fake_ccs: bool,
 }
 
+#[cfg(feature = "tls13")]
 struct ExpectServerHelloOrHelloRetryRequest {
     next: ExpectServerHello,
     extra_exts: Vec<ClientExtension>,
 }
 
+#[cfg(feature = "tls13")]
 pub fn compatible_suite(
     sess: &ClientSessionImpl,
     resuming_suite: &SupportedCipherSuite,
@@ -219,24 +264,25 @@ fn emit_client_hello_for_retry(
     sess: &mut ClientSessionImpl,
     mut handshake: HandshakeDetails,
     randoms: SessionRandoms,
-    mut sent_tls13_fake_ccs: bool,
+    sent_tls13_fake_ccs: bool,
     mut hello: ClientHelloDetails,
     retryreq: Option<&HelloRetryRequest>,
     extra_exts: Vec<ClientExtension>,
     may_send_sct_list: bool,
 ) -> NextStateOrError {
     // Do we have a SessionID or ticket cached for this host?
-    let (ticket, resume_version) = if let Some(resuming) = &handshake.resuming_session {
-        (
-            resuming.ticket.0.clone(),
-            resuming.version,
-        )
-    } else {
-        (
-            Vec::new(),
-            ProtocolVersion::Unknown(0),
-        )
-    };
+    let ticket = handshake
+        .resuming_session
+        .as_ref()
+        .map(|resuming| resuming.ticket.0.clone())
+        .unwrap_or_default();
+    #[cfg(feature = "tls13")]
+    let resuming_tls13 = handshake
+        .resuming_session
+        .as_ref()
+        .map_or(false, |resuming| resuming.version == ProtocolVersion::TLSv1_3);
+    #[cfg(feature = "tls13")]
+    let mut sent_tls13_fake_ccs = sent_tls13_fake_ccs;
 
     let support_tls12 = sess
         .config
@@ -256,10 +302,17 @@ fn emit_client_hello_for_retry(
 
     let mut exts = Vec::new();
     if !supported_versions.is_empty() {
+        for version in supported_versions.iter_mut() {
+            *version = sess.common.wire_version(*version);
+        }
         exts.push(ClientExtension::SupportedVersions(supported_versions));
     }
     if sess.config.enable_sni {
-        exts.push(ClientExtension::make_sni(handshake.dns_name.as_ref()));
+        // RFC6066: "Literal IPv4 and IPv6 addresses are not permitted
+        // in "HostName"."
+        if let Some(dns_name) = handshake.server_name.dns_name() {
+            exts.push(ClientExtension::make_sni(dns_name));
+        }
     }
     exts.push(ClientExtension::ECPointFormats(
         ECPointFormatList::supported(),
@@ -270,11 +323,12 @@ fn emit_client_hello_for_retry(
             .map(|skxg| skxg.name)
             .collect()
     ));
-    exts.push(ClientExtension::SignatureAlgorithms(
-        sess.config
-            .get_verifier()
-            .supported_verify_schemes(),
-    ));
+    let mut sigschemes = sess
+        .config
+        .get_verifier()
+        .supported_verify_schemes();
+    sigschemes.retain(|scheme| sess.config.permits_signature_scheme(*scheme));
+    exts.push(ClientExtension::SignatureAlgorithms(sigschemes));
     exts.push(ClientExtension::ExtendedMasterSecretRequest);
     exts.push(ClientExtension::CertificateStatusRequest(
         CertificateStatusRequest::build_ocsp(),
@@ -284,6 +338,7 @@ fn emit_client_hello_for_retry(
         exts.push(ClientExtension::SignedCertificateTimestampRequest);
     }
 
+    #[cfg(feature = "tls13")]
     if support_tls13 {
         tls13::choose_kx_groups(sess, &mut exts, &mut hello, &mut handshake, retryreq);
     }
@@ -292,7 +347,7 @@ fn emit_client_hello_for_retry(
         exts.push(ClientExtension::Cookie(cookie.clone()));
     }
 
-    if support_tls13 && sess.config.enable_tickets {
+    if support_tls13 && sess.config.enable_tls13_resumption {
         // We could support PSK_KE here too. Such connections don't
         // have forward secrecy, and are similar to TLS1.2 resumption.
         let psk_modes = vec![PSKKeyExchangeMode::PSK_DHE_KE];
@@ -310,37 +365,61 @@ fn emit_client_hello_for_retry(
         )));
     }
 
+    if sess.common.is_dtls() && !sess.config.srtp_profiles.is_empty() {
+        exts.push(ClientExtension::UseSRTP(UseSRTPData {
+            profiles: sess.config.srtp_profiles.clone(),
+            mki: PayloadU8::empty(),
+        }));
+    }
+
+    if support_tls13 && !sess.config.certificate_authorities.is_empty() {
+        exts.push(ClientExtension::CertificateAuthorities(
+            sess.config.certificate_authorities.clone(),
+        ));
+    }
+
+    for (typ, payload) in &sess.config.custom_extensions {
+        let typ = ExtensionType::read_bytes(&typ.to_be_bytes()).unwrap();
+        if exts.iter().any(|ext| ext.get_type() == typ) {
+            debug!("Not sending custom extension {:?}: we send it ourselves", typ);
+            continue;
+        }
+        exts.push(ClientExtension::Unknown(UnknownExtension {
+            typ,
+            payload: Payload::new(payload.clone()),
+        }));
+    }
+
     // Extra extensions must be placed before the PSK extension
     exts.extend(extra_exts.iter().cloned());
 
+    #[cfg(feature = "tls13")]
     let fill_in_binder = if support_tls13
-        && sess.config.enable_tickets
-        && resume_version == ProtocolVersion::TLSv1_3
+        && sess.config.enable_tls13_resumption
+        && resuming_tls13
         && !ticket.is_empty()
     {
         tls13::prepare_resumption(sess, ticket, &handshake, &mut exts, retryreq.is_some())
-    } else if sess.config.enable_tickets {
-        // If we have a ticket, include it.  Otherwise, request one.
-        if ticket.is_empty() {
-            exts.push(ClientExtension::SessionTicketRequest);
-        } else {
-            exts.push(ClientExtension::SessionTicketOffer(Payload::new(ticket)));
-        }
-        false
     } else {
+        push_ticket_extension(sess, ticket, &mut exts);
         false
     };
 
+    #[cfg(not(feature = "tls13"))]
+    push_ticket_extension(sess, ticket, &mut exts);
+
     // Note what extensions we sent.
     hello.sent_extensions = exts
         .iter()
         .map(ClientExtension::get_type)
         .collect();
 
-    let mut chp = HandshakeMessagePayload {
+    let chp = HandshakeMessagePayload {
         typ: HandshakeType::ClientHello,
         payload: HandshakePayload::ClientHello(ClientHelloPayload {
-            client_version: ProtocolVersion::TLSv1_2,
+            client_version: sess
+                .common
+                .wire_version(ProtocolVersion::TLSv1_2),
             random: Random::from_slice(&randoms.client),
             session_id: handshake.session_id,
             cipher_suites: sess.get_cipher_suites(),
@@ -349,8 +428,11 @@ fn emit_client_hello_for_retry(
         }),
     };
 
+    #[cfg(feature = "tls13")]
+    let mut chp = chp;
+    #[cfg(feature = "tls13")]
     let early_key_schedule = if fill_in_binder {
-        Some(tls13::fill_in_psk_binder(&mut handshake, &mut chp))
+        Some(tls13::fill_in_psk_binder(sess, &handshake, &mut chp))
     } else {
         None
     };
@@ -368,6 +450,7 @@ fn emit_client_hello_for_retry(
         payload: MessagePayload::Handshake(chp),
     };
 
+    #[cfg(feature = "tls13")]
     if retryreq.is_some() {
         // send dummy CCS to fool middleboxes prior
         // to second client hello
@@ -380,6 +463,7 @@ fn emit_client_hello_for_retry(
     sess.common.send_msg(ch, false);
 
     // Calculate the hash of ClientHello and use it to derive EarlyTrafficSecret
+    #[cfg(feature = "tls13")]
     if sess.early_data.is_enabled() {
         // For middlebox compatibility
         tls13::emit_fake_ccs(&mut sent_tls13_fake_ccs, sess);
@@ -399,7 +483,7 @@ fn emit_client_hello_for_retry(
             .unwrap()
             .client_early_traffic_secret(
                 &client_hello_hash,
-                &*sess.config.key_log,
+                &*sess.key_log,
                 &randoms.client,
             );
         // Set early data encryption key
@@ -413,6 +497,7 @@ fn emit_client_hello_for_retry(
         #[cfg(feature = "quic")]
         {
             sess.common.quic.early_secret = Some(client_early_traffic_secret);
+            sess.common.quic.zero_rtt = quic::ZeroRttStatus::Pending;
         }
 
         // Now the client can send encrypted early data
@@ -424,15 +509,32 @@ fn emit_client_hello_for_retry(
         handshake,
         randoms,
         hello,
+        #[cfg(feature = "tls13")]
         early_key_schedule,
         sent_tls13_fake_ccs,
     };
 
-    Ok(if support_tls13 && retryreq.is_none() {
-        Box::new(ExpectServerHelloOrHelloRetryRequest { next, extra_exts })
-    } else {
-        Box::new(next)
-    })
+    #[cfg(feature = "tls13")]
+    if support_tls13 && retryreq.is_none() {
+        return Ok(Box::new(ExpectServerHelloOrHelloRetryRequest { next, extra_exts }));
+    }
+
+    Ok(Box::new(next))
+}
+
+fn push_ticket_extension(
+    sess: &ClientSessionImpl,
+    ticket: Vec<u8>,
+    exts: &mut Vec<ClientExtension>,
+) {
+    if sess.config.enable_tickets {
+        // If we have a ticket, include it.  Otherwise, request one.
+        if ticket.is_empty() {
+            exts.push(ClientExtension::SessionTicketRequest);
+        } else {
+            exts.push(ClientExtension::SessionTicketOffer(Payload::new(ticket)));
+        }
+    }
 }
 
 pub fn process_alpn_protocol(
@@ -446,7 +548,10 @@ pub fn process_alpn_protocol(
             .config
             .alpn_protocols
             .contains(alpn_protocol) {
-                return Err(illegal_param(sess, "server sent non-offered ALPN protocol"));
+                return Err(illegal_param(
+                    sess,
+                    PeerMisbehaved::SelectedUnofferedApplicationProtocol,
+                ));
             }
     }
 
@@ -459,11 +564,30 @@ pub fn process_alpn_protocol(
     Ok(())
 }
 
+/// Keep the server's replies to our custom extensions.
+pub fn save_custom_extensions(sess: &mut ClientSessionImpl, exts: &[ServerExtension]) {
+    for ext in exts {
+        let typ = ext.get_type().get_u16();
+        if sess
+            .config
+            .custom_extensions
+            .iter()
+            .any(|(t, _)| *t == typ)
+        {
+            // Skip the type and length.
+            let payload = ext.get_encoding()[4..].to_vec();
+            sess.custom_extensions_received
+                .push((typ, payload));
+        }
+    }
+}
+
 pub fn sct_list_is_invalid(scts: &SCTList) -> bool {
     scts.is_empty() || scts.iter().any(|sct| sct.0.is_empty())
 }
 
 impl ExpectServerHello {
+    #[cfg(feature = "tls13")]
     fn into_expect_tls13_encrypted_extensions(
         self,
         key_schedule: KeyScheduleHandshake,
@@ -478,6 +602,7 @@ impl ExpectServerHello {
         })
     }
 
+    #[cfg(feature = "tls12")]
     fn into_expect_tls12_new_ticket_resume(
         self,
         secrets: SessionSecrets,
@@ -493,6 +618,7 @@ impl ExpectServerHello {
         })
     }
 
+    #[cfg(feature = "tls12")]
     fn into_expect_tls12_ccs_resume(
         self,
         secrets: SessionSecrets,
@@ -509,6 +635,7 @@ impl ExpectServerHello {
         })
     }
 
+    #[cfg(feature = "tls12")]
     fn into_expect_tls12_certificate(
         self,
         suite: &'static SupportedCipherSuite,
@@ -526,6 +653,120 @@ impl ExpectServerHello {
             server_cert_sct_list,
         })
     }
+
+    #[cfg(feature = "tls12")]
+    fn handle_tls12_server_hello(
+        mut self: Box<Self>,
+        sess: &mut ClientSessionImpl,
+        server_hello: &ServerHelloPayload,
+        scs: &'static SupportedCipherSuite,
+        tls13_supported: bool,
+    ) -> NextStateOrError {
+        // Save ServerRandom and SessionID
+        server_hello
+            .random
+            .write_slice(&mut self.randoms.server);
+        self.handshake.session_id = server_hello.session_id;
+
+        // Look for TLS1.3 downgrade signal in server random
+        if tls13_supported
+            && self
+                .randoms
+                .has_tls12_downgrade_marker()
+        {
+            return Err(illegal_param(
+                sess,
+                PeerMisbehaved::AttemptedDowngradeToTls12WhenTls13IsSupported,
+            ));
+        }
+
+        // Doing EMS?
+        if server_hello.ems_support_acked() {
+            self.handshake.using_ems = true;
+        }
+
+        // Might the server send a ticket?
+        let must_issue_new_ticket = if server_hello
+            .find_extension(ExtensionType::SessionTicket)
+            .is_some()
+        {
+            debug!("Server supports tickets");
+            true
+        } else {
+            false
+        };
+
+        // Might the server send a CertificateStatus between Certificate and
+        // ServerKeyExchange?
+        let may_send_cert_status = server_hello
+            .find_extension(ExtensionType::StatusRequest)
+            .is_some();
+        if may_send_cert_status {
+            debug!("Server may staple OCSP response");
+        }
+
+        // Save any sent SCTs for verification against the certificate.
+        let server_cert_list_list =
+            if let Some(sct_list) = server_hello.get_sct_list() {
+            debug!("Server sent {:?} SCTs", sct_list.len());
+
+            if sct_list_is_invalid(sct_list) {
+                return Err(TlsError::PeerMisbehavedError(PeerMisbehaved::InvalidSctList));
+            }
+            Some(sct_list.clone())
+        } else {
+            None
+        };
+
+        // See if we're successfully resuming.
+        if let Some(ref resuming) = self.handshake.resuming_session {
+            if resuming.session_id == self.handshake.session_id {
+                debug!("Server agreed to resume");
+                sess.common.handshake_kind = Some(HandshakeKind::Resumed);
+
+                // Is the server telling lies about the ciphersuite?
+                if resuming.suite != scs {
+                    return Err(TlsError::PeerMisbehavedError(
+                        PeerMisbehaved::ResumptionOfferedWithVariedCipherSuite,
+                    ));
+                }
+
+                // And about EMS support?
+                if resuming.extended_ms != self.handshake.using_ems {
+                    return Err(TlsError::PeerMisbehavedError(
+                        PeerMisbehaved::ResumptionOfferedWithVariedEms,
+                    ));
+                }
+
+                let secrets = SessionSecrets::new_resume(
+                    &self.randoms,
+                    scs,
+                    &resuming.master_secret.0,
+                );
+                sess.key_log.log(
+                    "CLIENT_RANDOM",
+                    &secrets.randoms.client,
+                    &secrets.master_secret,
+                );
+                sess.common
+                    .start_encryption_tls12(&secrets);
+
+                // Since we're resuming, we verified the certificate and
+                // proof of possession in the prior session.
+                sess.server_cert_chain = resuming.server_cert_chain.clone();
+                let certv = verify::ServerCertVerified::assertion();
+                let sigv = verify::HandshakeSignatureValid::assertion();
+
+                return if must_issue_new_ticket {
+                    Ok(self.into_expect_tls12_new_ticket_resume(secrets, certv, sigv))
+                } else {
+                    Ok(self.into_expect_tls12_ccs_resume(secrets, certv, sigv))
+                };
+            }
+        }
+
+        Ok(self.into_expect_tls12_certificate(scs, may_send_cert_status, must_issue_new_ticket, server_cert_list_list))
+    }
 }
 
 impl State for ExpectServerHello {
@@ -537,12 +778,15 @@ impl State for ExpectServerHello {
         use crate::ProtocolVersion::{TLSv1_2, TLSv1_3};
         let tls13_supported = sess.config.supports_version(TLSv1_3);
 
-        let server_version = if server_hello.legacy_version == TLSv1_2 {
+        let legacy_version = sess
+            .common
+            .version_from_wire(server_hello.legacy_version);
+        let server_version = if legacy_version == TLSv1_2 {
             server_hello
                 .get_supported_versions()
-                .unwrap_or(server_hello.legacy_version)
+                .map_or(legacy_version, |v| sess.common.version_from_wire(v))
         } else {
-            server_hello.legacy_version
+            legacy_version
         };
 
         let version = match server_version {
@@ -554,7 +798,7 @@ impl State for ExpectServerHello {
                     // The client must fail with a dedicated error code if the server
                     // responds with TLS 1.2 when offering 0-RTT.
                     return Err(TlsError::PeerMisbehavedError(
-                        "server chose v1.2 when offering 0-rtt".to_string(),
+                        PeerMisbehaved::OfferedEarlyDataWithOldProtocolVersion,
                     ));
                 }
 
@@ -564,7 +808,7 @@ impl State for ExpectServerHello {
                 {
                     return Err(illegal_param(
                         sess,
-                        "server chose v1.2 using v1.3 extension",
+                        PeerMisbehaved::SelectedTls12UsingTls13VersionExtension,
                     ));
                 }
 
@@ -580,14 +824,14 @@ impl State for ExpectServerHello {
         };
 
         if server_hello.compression_method != Compression::Null {
-            return Err(illegal_param(sess, "server chose non-Null compression"));
+            return Err(illegal_param(sess, PeerMisbehaved::SelectedUnofferedCompression));
         }
 
         if server_hello.has_duplicate_extension() {
             sess.common
                 .send_fatal_alert(AlertDescription::DecodeError);
             return Err(TlsError::PeerMisbehavedError(
-                "server sent duplicate extensions".to_string(),
+                PeerMisbehaved::DuplicateServerHelloExtensions,
             ));
         }
 
@@ -599,11 +843,12 @@ impl State for ExpectServerHello {
             sess.common
                 .send_fatal_alert(AlertDescription::UnsupportedExtension);
             return Err(TlsError::PeerMisbehavedError(
-                "server sent unsolicited extension".to_string(),
+                PeerMisbehaved::UnsolicitedServerHelloExtension,
             ));
         }
 
         sess.common.negotiated_version = Some(version);
+        save_custom_extensions(sess, &server_hello.extensions);
 
         // Extract ALPN protocol
         if !sess.common.is_tls13() {
@@ -617,7 +862,7 @@ impl State for ExpectServerHello {
                 sess.common
                     .send_fatal_alert(AlertDescription::HandshakeFailure);
                 return Err(TlsError::PeerMisbehavedError(
-                    "server does not support uncompressed points".to_string(),
+                    PeerMisbehaved::ServerHelloMustOfferUncompressedEcPoints,
                 ));
             }
         }
@@ -626,21 +871,17 @@ impl State for ExpectServerHello {
             .ok_or_else(|| {
                 sess.common
                     .send_fatal_alert(AlertDescription::HandshakeFailure);
-                TlsError::PeerMisbehavedError(
-                    "server chose non-offered ciphersuite".to_string())
+                TlsError::PeerMisbehavedError(PeerMisbehaved::SelectedUnofferedCipherSuite)
             })?;
 
         debug!("Using ciphersuite {:?}", server_hello.cipher_suite);
         if !sess.common.set_suite(scs) {
-            return Err(illegal_param(sess, "server varied selected ciphersuite"));
+            return Err(illegal_param(sess, PeerMisbehaved::SelectedDifferentCipherSuiteAfterRetry));
         }
 
         if !scs.usable_for_version(version)
         {
-            return Err(illegal_param(
-                sess,
-                "server chose unusable ciphersuite for version",
-            ));
+            return Err(illegal_param(sess, PeerMisbehaved::SelectedCipherSuiteUnusableForVersion));
         }
 
         // Start our handshake hash, and input the server-hello.
@@ -653,6 +894,7 @@ impl State for ExpectServerHello {
 
         // For TLS1.3, start message encryption using
         // handshake_traffic_secret.
+        #[cfg(feature = "tls13")]
         if sess.common.is_tls13() {
             tls13::validate_server_hello(sess, &server_hello)?;
             let (key_schedule, hash_at_client_recvd_server_hello) = tls13::start_handshake_traffic(
@@ -669,112 +911,19 @@ impl State for ExpectServerHello {
         }
 
         // TLS1.2 only from here-on
-
-        // Save ServerRandom and SessionID
-        server_hello
-            .random
-            .write_slice(&mut self.randoms.server);
-        self.handshake.session_id = server_hello.session_id;
-
-        // Look for TLS1.3 downgrade signal in server random
-        if tls13_supported
-            && self
-                .randoms
-                .has_tls12_downgrade_marker()
+        #[cfg(feature = "tls12")]
         {
-            return Err(illegal_param(
-                sess,
-                "downgrade to TLS1.2 when TLS1.3 is supported",
-            ));
-        }
-
-        // Doing EMS?
-        if server_hello.ems_support_acked() {
-            self.handshake.using_ems = true;
+            self.handle_tls12_server_hello(sess, server_hello, scs, tls13_supported)
         }
 
-        // Might the server send a ticket?
-        let must_issue_new_ticket = if server_hello
-            .find_extension(ExtensionType::SessionTicket)
-            .is_some()
+        #[cfg(not(feature = "tls12"))]
         {
-            debug!("Server supports tickets");
-            true
-        } else {
-            false
-        };
-
-        // Might the server send a CertificateStatus between Certificate and
-        // ServerKeyExchange?
-        let may_send_cert_status = server_hello
-            .find_extension(ExtensionType::StatusRequest)
-            .is_some();
-        if may_send_cert_status {
-            debug!("Server may staple OCSP response");
+            Err(illegal_param(sess, PeerMisbehaved::SelectedUnofferedProtocolVersion))
         }
-
-        // Save any sent SCTs for verification against the certificate.
-        let server_cert_list_list =
-            if let Some(sct_list) = server_hello.get_sct_list() {
-            debug!("Server sent {:?} SCTs", sct_list.len());
-
-            if sct_list_is_invalid(sct_list) {
-                let error_msg = "server sent invalid SCT list".to_string();
-                return Err(TlsError::PeerMisbehavedError(error_msg));
-            }
-            Some(sct_list.clone())
-        } else {
-            None
-        };
-
-        // See if we're successfully resuming.
-        if let Some(ref resuming) = self.handshake.resuming_session {
-            if resuming.session_id == self.handshake.session_id {
-                debug!("Server agreed to resume");
-
-                // Is the server telling lies about the ciphersuite?
-                if resuming.suite != scs {
-                    let error_msg = "abbreviated handshake offered, but with varied cs".to_string();
-                    return Err(TlsError::PeerMisbehavedError(error_msg));
-                }
-
-                // And about EMS support?
-                if resuming.extended_ms != self.handshake.using_ems {
-                    let error_msg = "server varied ems support over resume".to_string();
-                    return Err(TlsError::PeerMisbehavedError(error_msg));
-                }
-
-                let secrets = SessionSecrets::new_resume(
-                    &self.randoms,
-                    scs,
-                    &resuming.master_secret.0,
-                );
-                sess.config.key_log.log(
-                    "CLIENT_RANDOM",
-                    &secrets.randoms.client,
-                    &secrets.master_secret,
-                );
-                sess.common
-                    .start_encryption_tls12(&secrets);
-
-                // Since we're resuming, we verified the certificate and
-                // proof of possession in the prior session.
-                sess.server_cert_chain = resuming.server_cert_chain.clone();
-                let certv = verify::ServerCertVerified::assertion();
-                let sigv = verify::HandshakeSignatureValid::assertion();
-
-                return if must_issue_new_ticket {
-                    Ok(self.into_expect_tls12_new_ticket_resume(secrets, certv, sigv))
-                } else {
-                    Ok(self.into_expect_tls12_ccs_resume(secrets, certv, sigv))
-                };
-            }
-        }
-
-        Ok(self.into_expect_tls12_certificate(scs, may_send_cert_status, must_issue_new_ticket, server_cert_list_list))
     }
 }
 
+#[cfg(feature = "tls13")]
 impl ExpectServerHelloOrHelloRetryRequest {
     fn into_expect_server_hello(self) -> NextState {
         Box::new(self.next)
@@ -793,6 +942,7 @@ impl ExpectServerHelloOrHelloRetryRequest {
         trace!("Got HRR {:?}", hrr);
 
         check_aligned_handshake(sess)?;
+        sess.common.handshake_kind = Some(HandshakeKind::FullWithHelloRetryRequest);
 
         let cookie = hrr.get_cookie();
         let req_group = hrr.get_requested_key_share_group();
@@ -804,13 +954,19 @@ impl ExpectServerHelloOrHelloRetryRequest {
                 .map(|g| self.next.hello.has_key_share(g))
                 .unwrap_or(false)
         {
-            return Err(illegal_param(sess, "server requested hrr with our group"));
+            return Err(illegal_param(
+                sess,
+                PeerMisbehaved::IllegalHelloRetryRequestWithOfferedGroup,
+            ));
         }
 
         // Or asks for us to retry on an unsupported group.
         if let Some(group) = req_group {
             if sess.config.kx_groups.iter().find(|skxg| skxg.name == group).is_none() {
-                return Err(illegal_param(sess, "server requested hrr with bad group"));
+                return Err(illegal_param(
+                    sess,
+                    PeerMisbehaved::IllegalHelloRetryRequestWithUnsupportedGroup,
+                ));
             }
         }
 
@@ -819,7 +975,7 @@ impl ExpectServerHelloOrHelloRetryRequest {
             if cookie.0.is_empty() {
                 return Err(illegal_param(
                     sess,
-                    "server requested hrr with empty cookie",
+                    PeerMisbehaved::IllegalHelloRetryRequestWithEmptyCookie,
                 ));
             }
         }
@@ -835,23 +991,26 @@ impl ExpectServerHelloOrHelloRetryRequest {
 
         // Or has the same extensions more than once
         if hrr.has_duplicate_extension() {
-            return Err(illegal_param(sess, "server send duplicate hrr extensions"));
+            return Err(illegal_param(sess, PeerMisbehaved::DuplicateHelloRetryRequestExtensions));
         }
 
         // Or asks us to change nothing.
         if cookie.is_none() && req_group.is_none() {
-            return Err(illegal_param(sess, "server requested hrr with no changes"));
+            return Err(illegal_param(sess, PeerMisbehaved::IllegalHelloRetryRequestWithNoChanges));
         }
 
         // Or asks us to talk a protocol we didn't offer, or doesn't support HRR at all.
-        match hrr.get_supported_versions() {
+        match hrr
+            .get_supported_versions()
+            .map(|v| sess.common.version_from_wire(v))
+        {
             Some(ProtocolVersion::TLSv1_3) => {
                 sess.common.negotiated_version = Some(ProtocolVersion::TLSv1_3);
             }
             _ => {
                 return Err(illegal_param(
                     sess,
-                    "server requested unsupported version in hrr",
+                    PeerMisbehaved::IllegalHelloRetryRequestWithUnsupportedVersion,
                 ));
             }
         }
@@ -863,7 +1022,7 @@ impl ExpectServerHelloOrHelloRetryRequest {
             None => {
                 return Err(illegal_param(
                     sess,
-                    "server requested unsupported cs in hrr",
+                    PeerMisbehaved::IllegalHelloRetryRequestWithUnofferedCipherSuite,
                 ));
             }
         };
@@ -888,7 +1047,11 @@ impl ExpectServerHelloOrHelloRetryRequest {
         // Early data is not allowed after HelloRetryrequest
         if sess.early_data.is_enabled() {
             sess.early_data.rejected();
+            #[cfg(feature = "metrics")]
+            sess.config.metrics.early_data_rejected.incr();
         }
+        #[cfg(feature = "quic")]
+        sess.common.quic.reject_0rtt();
 
         let may_send_sct_list = self.next.hello.server_may_send_sct_list();
         emit_client_hello_for_retry(
@@ -904,6 +1067,7 @@ impl ExpectServerHelloOrHelloRetryRequest {
     }
 }
 
+#[cfg(feature = "tls13")]
 impl State for ExpectServerHelloOrHelloRetryRequest {
     fn handle(self: Box<Self>, sess: &mut ClientSessionImpl, m: Message) -> NextStateOrError {
         check_message(
@@ -920,9 +1084,10 @@ impl State for ExpectServerHelloOrHelloRetryRequest {
     }
 }
 
+#[cfg(feature = "tls12")]
 pub fn send_cert_error_alert(sess: &mut ClientSessionImpl, err: TlsError) -> TlsError {
     match err {
-        TlsError::WebPKIError(webpki::Error::BadDER, _) => {
+        TlsError::InvalidCertificate(CertificateError::BadEncoding) => {
             sess.common
                 .send_fatal_alert(AlertDescription::DecodeError);
         }
@@ -930,6 +1095,12 @@ pub fn send_cert_error_alert(sess: &mut ClientSessionImpl, err: TlsError) -> Tls
             sess.common
                 .send_fatal_alert(AlertDescription::IllegalParameter);
         }
+        TlsError::InvalidCertificate(CertificateError::Revoked)
+        | TlsError::CRLError(CrlError::CertRevoked)
+        | TlsError::InvalidOCSPResponse(OcspError::CertRevoked) => {
+            sess.common
+                .send_fatal_alert(AlertDescription::CertificateRevoked);
+        }
         _ => {
             sess.common
                 .send_fatal_alert(AlertDescription::BadCertificate);
@@ -1,33 +1,50 @@
+use crate::bufpool::BufferPool;
+use crate::events::ConnectionEvents;
+#[cfg(feature = "metrics")]
+use crate::metrics::Metrics;
+use crate::builder::{ConfigBuilder, WantsCipherSuites};
 use crate::error::TlsError;
-use crate::{key, RootCertStore};
+use crate::{key, CrlSet, DistinguishedNames, RootCertStore};
 use crate::keylog::{KeyLog, NoKeyLog};
 #[cfg(feature = "logging")]
 use crate::log::trace;
 use crate::msgs::enums::CipherSuite;
-use crate::msgs::enums::SignatureScheme;
+use crate::msgs::enums::{SRTPProtectionProfile, SignatureScheme};
 use crate::msgs::enums::{AlertDescription, HandshakeType};
 use crate::msgs::enums::{ContentType, ProtocolVersion};
 use crate::msgs::handshake::CertificatePayload;
 use crate::msgs::handshake::ClientExtension;
 use crate::msgs::message::Message;
-use crate::session::{MiddleboxCCS, Session, SessionCommon};
+use crate::msgs::persist;
+use crate::server_name::ServerName;
+#[cfg(feature = "secret_extraction")]
+use crate::suites::ExtractedSecrets;
+use crate::session::{plaintext_written, HandshakeKind, Incoming, MiddleboxCCS};
+use crate::session::{RenegotiationPolicy, Session, SessionCommon};
 use crate::sign;
 use crate::suites::SupportedCipherSuite;
-use crate::kx::{SupportedKxGroup, ALL_KX_GROUPS};
+use crate::kx::{ProvidesKeyExchange, RingKeyExchange, SupportedKxGroup, ALL_KX_GROUPS};
+use crate::rand::{ProvidesRandom, RingRandom};
+use crate::time::{SystemTimeProvider, TimeProvider};
 use crate::verify;
+use crate::versions::{EnabledVersions, SupportedProtocolVersion, DEFAULT_VERSIONS};
+use crate::x509;
+
+use ring::digest;
 
 use std::fmt;
 use std::io::{self, IoSlice};
 use std::mem;
 use std::sync::Arc;
 
-use webpki;
 
 #[macro_use]
 mod hs;
 mod common;
 pub mod handy;
+#[cfg(feature = "tls12")]
 mod tls12;
+#[cfg(feature = "tls13")]
 mod tls13;
 
 /// A trait for the ability to store client session data.
@@ -37,6 +54,13 @@ mod tls13;
 /// **highly sensitive data**, containing enough key material
 /// to break all security of the corresponding session.
 ///
+/// The keys and values are self-contained byte strings, so they
+/// can be kept in a file or a shared cache like Redis, to resume
+/// sessions from another process or after a restart.  Values
+/// start with a format version: a value stored by a version of
+/// rustls using a different format is ignored, and the handshake
+/// is a full one.
+///
 /// `put` is a mutating operation; this isn't expressed
 /// in the type system to allow implementations freedom in
 /// how to achieve interior mutability.  `Mutex` is a common
@@ -58,9 +82,14 @@ pub trait ResolvesClientCert: Send + Sync {
     /// the server's supported signature schemes in `sigschemes`,
     /// return a certificate chain and signing key to authenticate.
     ///
-    /// `acceptable_issuers` is undecoded and unverified by the rustls
-    /// library, but it should be expected to contain a DER encodings
-    /// of X501 NAMEs.
+    /// `acceptable_issuers` are the distinguished names from the
+    /// server's CertificateRequest (or, in TLS1.3, its
+    /// certificate_authorities extension).  They are unverified by the
+    /// rustls library, but should be DER encodings of X501 NAMEs, in
+    /// the same form as a certificate's issuer.  It is empty if the
+    /// server didn't say which issuers it accepts.  Use
+    /// `CertifiedKey::is_issued_by_any` to choose between identities,
+    /// or `ResolvesClientCertUsingIssuers` to have that done for you.
     ///
     /// Return None to continue the handshake without any client
     /// authentication.  The server may reject the handshake later
@@ -92,29 +121,96 @@ pub struct ClientConfig {
     /// and in TLS1.3 a key share for it is sent in the client hello.
     pub kx_groups: Vec<&'static SupportedKxGroup>,
 
+    /// How ephemeral key exchanges are performed.  The default
+    /// does them in software.
+    pub kx_provider: Arc<dyn ProvidesKeyExchange>,
+
+    /// Where the random material rustls generates itself (handshake
+    /// randoms, session IDs, tickets) comes from.  The default uses
+    /// the operating system RNG.
+    pub random_provider: Arc<dyn ProvidesRandom>,
+
+    /// Where the current time comes from, for certificate
+    /// validation and ticket lifetimes.  The default asks the
+    /// operating system.
+    pub time_provider: Arc<dyn TimeProvider>,
+
     /// Which ALPN protocols we include in our client hello.
     /// If empty, no ALPN extension is sent.
     pub alpn_protocols: Vec<Vec<u8>>,
 
+    /// Which SRTP protection profiles we offer for DTLS-SRTP, most
+    /// preferred first.  If empty, no use_srtp extension is sent.
+    ///
+    /// This only affects DTLS connections.
+    pub srtp_profiles: Vec<SRTPProtectionProfile>,
+
+    /// The names of the CAs whose certificates we accept, sent to
+    /// the server in the TLS1.3 certificate_authorities extension
+    /// to help it choose its certificate.  `RootCertStore::get_subjects`
+    /// gives these for a set of roots.
+    ///
+    /// If empty (the default), the extension isn't sent.
+    pub certificate_authorities: DistinguishedNames,
+
+    /// Extra extensions to send in our client hello, as pairs of
+    /// extension type and payload.  This is for prototyping
+    /// extensions rustls doesn't implement; the server's replies
+    /// are available from `ClientSession::get_custom_extension`.
+    ///
+    /// Any with the type of an extension rustls sent itself are
+    /// left out.  The default is empty.
+    pub custom_extensions: Vec<(u16, Vec<u8>)>,
+
     /// How we store session data or tickets.
     pub session_persistence: Arc<dyn StoresClientSessions>,
 
     /// Our MTU.  If None, we don't limit TLS message sizes.
     pub mtu: Option<usize>,
 
+    /// Where sessions get their TLS buffers from.  If None, each
+    /// session allocates its own.
+    pub buffer_pool: Option<Arc<BufferPool>>,
+
+    /// Where sessions report handshakes, alerts and other events.
+    /// If None, they aren't reported.
+    pub connection_events: Option<Arc<dyn ConnectionEvents>>,
+
+    /// What sessions count their handshakes, failures and so on into.
+    #[cfg(feature = "metrics")]
+    pub metrics: Arc<Metrics>,
+
     /// How to decide what client auth certificate/keys to use.
     pub client_auth_cert_resolver: Arc<dyn ResolvesClientCert>,
 
-    /// Whether to support RFC5077 tickets.  You must provide a working
-    /// `session_persistence` member for this to have any meaningful
-    /// effect.
+    /// Whether to support RFC5077 tickets in TLS1.2.  You must provide
+    /// a working `session_persistence` member for this to have any
+    /// meaningful effect.
     ///
     /// The default is true.
     pub enable_tickets: bool,
 
-    /// Supported versions, in no particular order.  The default
-    /// is all supported versions.
-    pub versions: Vec<ProtocolVersion>,
+    /// Whether to resume TLS1.2 sessions by session ID, when the
+    /// server didn't issue a ticket.
+    ///
+    /// The default is true.
+    pub enable_session_id_resumption: bool,
+
+    /// Whether to resume TLS1.3 sessions, using tickets the server
+    /// issued as pre-shared keys.
+    ///
+    /// The default is true.
+    pub enable_tls13_resumption: bool,
+
+    /// Supported versions.  Change this with `set_protocol_versions`.
+    pub(crate) versions: EnabledVersions,
+
+    /// Which signature schemes to offer and accept, if not all those
+    /// supported.  This restricts our own signatures, and those we
+    /// accept from the peer.
+    ///
+    /// The default is `None`: no restriction.
+    pub signature_schemes: Option<Vec<SignatureScheme>>,
 
     /// Whether to send the Server Name Indication (SNI) extension
     /// during the client handshake.
@@ -122,9 +218,19 @@ pub struct ClientConfig {
     /// The default is true.
     pub enable_sni: bool,
 
+    /// What to do when a TLS1.2 server asks to renegotiate.
+    ///
+    /// The default is `RenegotiationPolicy::Decline`.
+    pub renegotiation_policy: RenegotiationPolicy,
+
     /// How to verify the server certificate chain.
     verifier: Arc<dyn verify::ServerCertVerifier>,
 
+    /// How to check whether the server's certificates have been
+    /// revoked, after `verifier` accepts them.  The default does
+    /// nothing.
+    pub revocation_checker: Arc<dyn verify::RevocationChecker>,
+
     /// How to output key material for debugging.  The default
     /// does nothing.
     pub key_log: Arc<dyn KeyLog>,
@@ -137,13 +243,19 @@ pub struct ClientConfig {
 }
 
 impl ClientConfig {
+    /// Start building a `ClientConfig`.  See the `ConfigBuilder`
+    /// documentation for the steps.
+    pub fn builder() -> ConfigBuilder<ClientConfig, WantsCipherSuites> {
+        ConfigBuilder::new()
+    }
+
     /// Make a `ClientConfig`.
     ///
     /// The verifier will use the roots in `root_store` and CT logs (if any) in
     /// `ct_logs`.
     ///
     /// `ciphersuites` contains the list of cipher suites to enable. It should
-    /// generally be `DEFAULT_CIPHERSUITES`.
+    /// generally be `default_ciphersuites()`.
     ///
     /// No ALPN protocols will be enabled, and client auth will be supported
     /// by default. The default session persistence provider stores up to 32
@@ -154,12 +266,37 @@ impl ClientConfig {
         Self::new_(Arc::new(verifier), ciphersuites)
     }
 
+    /// Make a `ClientConfig` which checks server certificates against
+    /// the certificate revocation lists in `crls`.
+    ///
+    /// Otherwise this is the same as `ClientConfig::new`.
+    pub fn new_with_crls(root_store: RootCertStore, ct_logs: &'static [&'static sct::Log],
+                         crls: CrlSet,
+                         ciphersuites: &[&'static SupportedCipherSuite]) -> Self {
+        let verifier = verify::WebPkiVerifier::new_with_crls(root_store, ct_logs, crls);
+        Self::new_(Arc::new(verifier), ciphersuites)
+    }
+
+    /// Make a `ClientConfig` which uses `policy` to decide whether
+    /// to trust server certificates, instead of the usual webpki
+    /// validation against a set of roots.
+    ///
+    /// `ciphersuites` contains the list of cipher suites to enable. It should
+    /// generally be `default_ciphersuites()`.
+    ///
+    /// Otherwise this is the same as `ClientConfig::new`.
+    pub fn new_with_policy(policy: Arc<dyn verify::ServerCertPolicy>,
+                           ciphersuites: &[&'static SupportedCipherSuite]) -> Self {
+        let verifier = verify::PolicyVerifier::new(policy);
+        Self::new_(Arc::new(verifier), ciphersuites)
+    }
+
     /// Make a `ClientConfig` with a custom certificate verifier.
     ///
     /// `verifier` is the certificate verifier to use.
     ///
     /// `ciphersuites` contains the list of cipher suites to enable. It should
-    /// generally be `DEFAULT_CIPHERSUITES`.
+    /// generally be `default_ciphersuites()`.
     ///
     /// No ALPN protocols will be enabled, and client auth will be supported
     /// by default. The default session persistence provider stores up to 32
@@ -172,30 +309,56 @@ impl ClientConfig {
         Self::new_(verifier, ciphersuites)
     }
 
-    fn new_(verifier: Arc<dyn verify::ServerCertVerifier>,
+    pub(crate) fn new_(verifier: Arc<dyn verify::ServerCertVerifier>,
             ciphersuites: &[&'static SupportedCipherSuite]) -> Self {
         Self {
             ciphersuites: ciphersuites.to_vec(),
             kx_groups: ALL_KX_GROUPS.to_vec(),
+            kx_provider: Arc::new(RingKeyExchange),
+            random_provider: Arc::new(RingRandom),
+            time_provider: Arc::new(SystemTimeProvider),
             alpn_protocols: Vec::new(),
+            srtp_profiles: Vec::new(),
+            certificate_authorities: Vec::new(),
+            custom_extensions: Vec::new(),
             session_persistence: handy::ClientSessionMemoryCache::new(32),
             mtu: None,
+            buffer_pool: None,
+            connection_events: None,
+            #[cfg(feature = "metrics")]
+            metrics: Metrics::new(),
             client_auth_cert_resolver: Arc::new(handy::FailResolveClientCert {}),
             enable_tickets: true,
-            versions: vec![ProtocolVersion::TLSv1_3, ProtocolVersion::TLSv1_2],
+            enable_session_id_resumption: true,
+            enable_tls13_resumption: true,
+            versions: EnabledVersions::new(DEFAULT_VERSIONS),
+            signature_schemes: None,
             enable_sni: true,
+            renegotiation_policy: RenegotiationPolicy::Decline,
             verifier,
+            revocation_checker: Arc::new(verify::NoRevocationChecking {}),
             key_log: Arc::new(NoKeyLog {}),
             enable_early_data: false,
         }
     }
 
+    /// Whether the kind of resumption `value` allows is enabled.
+    pub(crate) fn may_resume(&self, value: &persist::ClientSessionValue) -> bool {
+        if value.version == ProtocolVersion::TLSv1_3 {
+            self.enable_tls13_resumption
+        } else if value.ticket.0.is_empty() {
+            self.enable_session_id_resumption
+        } else {
+            self.enable_tickets
+        }
+    }
+
     #[doc(hidden)]
     /// We support a given TLS version if it's quoted in the configured
     /// versions *and* at least one ciphersuite for this version is
     /// also configured.
     pub fn supports_version(&self, v: ProtocolVersion) -> bool {
-        self.versions.contains(&v)
+        self.versions.contains(v)
             && self
                 .ciphersuites
                 .iter()
@@ -207,6 +370,14 @@ impl ClientConfig {
         self.verifier.as_ref()
     }
 
+    /// Whether `signature_schemes` allows `scheme`.
+    pub(crate) fn permits_signature_scheme(&self, scheme: SignatureScheme) -> bool {
+        match &self.signature_schemes {
+            Some(schemes) => schemes.contains(&scheme),
+            None => true,
+        }
+    }
+
     /// Set the ALPN protocol list to the given protocol names.
     /// Overwrites any existing configured protocols.
     /// The first element in the `protocols` list is the most
@@ -217,6 +388,21 @@ impl ClientConfig {
             .extend_from_slice(protocols);
     }
 
+    /// Enable only the protocol versions in `versions`.  The default
+    /// is `DEFAULT_VERSIONS`.
+    ///
+    /// A version is only used if at least one of the configured
+    /// cipher suites can be used with it.
+    pub fn set_protocol_versions(&mut self, versions: &[&'static SupportedProtocolVersion]) {
+        self.versions = EnabledVersions::new(versions);
+    }
+
+    /// Sets how server certificates are checked for revocation.
+    /// See `RevocationChecker`.
+    pub fn set_revocation_checker(&mut self, checker: Arc<dyn verify::RevocationChecker>) {
+        self.revocation_checker = checker;
+    }
+
     /// Sets persistence layer to `persist`.
     pub fn set_persistence(&mut self, persist: Arc<dyn StoresClientSessions>) {
         self.session_persistence = persist;
@@ -244,6 +430,10 @@ impl ClientConfig {
     ///
     /// `cert_chain` is a vector of DER-encoded certificates,
     /// `key_der` is a DER-encoded RSA or ECDSA private key.
+    ///
+    /// This function fails if `key_der` is invalid, if it doesn't match
+    /// the end-entity certificate, or if `cert_chain` is empty or out
+    /// of order.
     pub fn set_single_client_cert(
         &mut self,
         cert_chain: Vec<key::Certificate>,
@@ -285,6 +475,7 @@ pub mod danger {
 }
 
 #[derive(Debug, PartialEq)]
+#[cfg_attr(not(feature = "tls13"), allow(dead_code))]
 enum EarlyDataState {
     Disabled,
     Ready,
@@ -320,23 +511,27 @@ impl EarlyData {
         }
     }
 
+    #[cfg(feature = "tls13")]
     fn enable(&mut self, max_data: usize) {
         assert_eq!(self.state, EarlyDataState::Disabled);
         self.state = EarlyDataState::Ready;
         self.left = max_data;
     }
 
+    #[cfg(feature = "tls13")]
     fn rejected(&mut self) {
         trace!("EarlyData rejected");
         self.state = EarlyDataState::Rejected;
     }
 
+    #[cfg(feature = "tls13")]
     fn accepted(&mut self) {
         trace!("EarlyData accepted");
         assert_eq!(self.state, EarlyDataState::Ready);
         self.state = EarlyDataState::Accepted;
     }
 
+    #[cfg(feature = "tls13")]
     fn finished(&mut self) {
         trace!("EarlyData finished");
         self.state = match self.state {
@@ -399,12 +594,19 @@ impl<'a> io::Write for WriteEarlyData<'a> {
 pub struct ClientSessionImpl {
     pub config: Arc<ClientConfig>,
     pub alpn_protocol: Option<Vec<u8>>,
+    /// The server's replies to `config.custom_extensions`.
+    pub custom_extensions_received: Vec<(u16, Vec<u8>)>,
     pub common: SessionCommon,
     pub error: Option<TlsError>,
     pub state: Option<hs::NextState>,
     pub server_cert_chain: CertificatePayload,
+    pub server_ocsp_response: Vec<u8>,
+    pub server_intermediate_ocsp: Vec<Vec<u8>>,
+    pub validated_scts: Vec<verify::ValidatedSct>,
     pub early_data: EarlyData,
     pub resumption_ciphersuite: Option<&'static SupportedCipherSuite>,
+    /// Where secrets go: `config.key_log` unless overridden.
+    pub key_log: Arc<dyn KeyLog>,
 }
 
 impl fmt::Debug for ClientSessionImpl {
@@ -416,20 +618,37 @@ impl fmt::Debug for ClientSessionImpl {
 
 impl ClientSessionImpl {
     pub fn new(config: &Arc<ClientConfig>) -> ClientSessionImpl {
+        #[allow(unused_mut)]
+        let mut common = SessionCommon::new(
+            config.mtu,
+            config.buffer_pool.as_ref(),
+            config.connection_events.clone(),
+            true,
+        );
+        #[cfg(feature = "metrics")]
+        {
+            common.metrics = Some(config.metrics.clone());
+        }
+
         ClientSessionImpl {
             config: config.clone(),
             alpn_protocol: None,
-            common: SessionCommon::new(config.mtu, true),
+            custom_extensions_received: Vec::new(),
+            common,
             error: None,
             state: None,
             server_cert_chain: Vec::new(),
+            server_ocsp_response: Vec::new(),
+            server_intermediate_ocsp: Vec::new(),
+            validated_scts: Vec::new(),
             early_data: EarlyData::new(),
             resumption_ciphersuite: None,
+            key_log: config.key_log.clone(),
         }
     }
 
-    pub fn start_handshake(&mut self, hostname: webpki::DNSName, extra_exts: Vec<ClientExtension>) -> Result<(), TlsError> {
-        self.state = Some(hs::start_handshake(self, hostname, extra_exts)?);
+    pub fn start_handshake(&mut self, server_name: ServerName, extra_exts: Vec<ClientExtension>) -> Result<(), TlsError> {
+        self.state = Some(hs::start_handshake(self, server_name, extra_exts)?);
         Ok(())
     }
 
@@ -472,6 +691,38 @@ impl ClientSessionImpl {
         self.common.set_buffer_limit(len)
     }
 
+    fn process_incoming(&mut self, incoming: Incoming) -> Result<(), TlsError> {
+        match incoming {
+            Incoming::Record(msg) => self.process_msg(msg),
+            Incoming::Decrypted(msg) => self.process_plaintext_msg(msg),
+        }
+    }
+
+    /// Process a received record, or the error from receiving it.
+    /// Errors are remembered, so every later call fails the same way.
+    pub fn process_received(
+        &mut self,
+        incoming: Result<Incoming, TlsError>,
+    ) -> Result<(), TlsError> {
+        if let Some(ref err) = self.error {
+            return Err(err.clone());
+        }
+
+        let rc = incoming.and_then(|incoming| self.process_incoming(incoming));
+        if let Err(err) = rc {
+            #[cfg(feature = "metrics")]
+            {
+                if !self.common.traffic {
+                    self.config.metrics.handshake_failed(&err);
+                }
+            }
+            self.error = Some(err.clone());
+            return Err(err);
+        }
+
+        Ok(())
+    }
+
     pub fn process_msg(&mut self, mut msg: Message) -> Result<(), TlsError> {
         // TLS1.3: drop CCS at any time during handshaking
         if let MiddleboxCCS::Drop = self.common.filter_tls13_ccs(&msg)? {
@@ -485,6 +736,11 @@ impl ClientSessionImpl {
             msg = dm;
         }
 
+        self.process_plaintext_msg(msg)
+    }
+
+    /// Process `msg`, which is already decrypted if need be.
+    fn process_plaintext_msg(&mut self, mut msg: Message) -> Result<(), TlsError> {
         // For handshake messages, we need to join them before parsing
         // and processing.
         if self
@@ -531,8 +787,7 @@ impl ClientSessionImpl {
 
     fn reject_renegotiation_attempt(&mut self) -> Result<(), TlsError> {
         self.common
-            .send_warning_alert(AlertDescription::NoRenegotiation);
-        Ok(())
+            .refuse_renegotiation(self.config.renegotiation_policy)
     }
 
     fn queue_unexpected_alert(&mut self) {
@@ -581,19 +836,8 @@ impl ClientSessionImpl {
             return Err(TlsError::CorruptMessage);
         }
 
-        while let Some(msg) = self
-            .common
-            .message_deframer
-            .frames
-            .pop_front()
-        {
-            match self.process_msg(msg) {
-                Ok(_) => {}
-                Err(err) => {
-                    self.error = Some(err.clone());
-                    return Err(err);
-                }
-            }
+        while let Some(incoming) = self.common.next_incoming().transpose() {
+            self.process_received(incoming)?;
         }
 
         Ok(())
@@ -626,6 +870,18 @@ impl ClientSessionImpl {
         self.common.get_suite()
     }
 
+    pub fn get_negotiated_kx_group(&self) -> Option<&'static SupportedKxGroup> {
+        self.common.kx_group
+    }
+
+    pub fn get_peer_signature_scheme(&self) -> Option<SignatureScheme> {
+        self.common.peer_signature_scheme
+    }
+
+    pub fn handshake_kind(&self) -> Option<HandshakeKind> {
+        self.common.handshake_kind()
+    }
+
     pub fn write_early_data(&mut self, data: &[u8]) -> io::Result<usize> {
         self.early_data
             .check_write(data.len())
@@ -648,13 +904,26 @@ impl ClientSessionImpl {
             .and_then(|st| st.export_keying_material(output, label, context))
     }
 
+    #[cfg(feature = "secret_extraction")]
+    fn extract_secrets(&self) -> Result<ExtractedSecrets, TlsError> {
+        let secrets = self
+            .state
+            .as_ref()
+            .ok_or(TlsError::HandshakeNotComplete)
+            .and_then(|st| st.extract_secrets(self));
+        self.common.extract_secrets(secrets)
+    }
+
     fn send_some_plaintext(&mut self, buf: &[u8]) -> usize {
+        self.perhaps_write_key_update();
+        self.common.send_some_plaintext(buf)
+    }
+
+    pub(crate) fn perhaps_write_key_update(&mut self) {
         let mut st = self.state.take();
         st.as_mut()
             .map(|st| st.perhaps_write_key_update(self));
         self.state = st;
-
-        self.common.send_some_plaintext(buf)
     }
 }
 
@@ -667,14 +936,40 @@ pub struct ClientSession {
 
 impl ClientSession {
     /// Make a new ClientSession.  `config` controls how
-    /// we behave in the TLS protocol, `hostname` is the
-    /// hostname of who we want to talk to.
-    pub fn new(config: &Arc<ClientConfig>, hostname: webpki::DNSNameRef) -> Result<ClientSession, TlsError> {
+    /// we behave in the TLS protocol, `name` is the
+    /// name or IP address of who we want to talk to.
+    pub fn new(config: &Arc<ClientConfig>, name: ServerName) -> Result<ClientSession, TlsError> {
         let mut imp = ClientSessionImpl::new(config);
-        imp.start_handshake(hostname.into(), vec![])?;
+        imp.start_handshake(name, vec![])?;
         Ok(ClientSession { imp })
     }
 
+    /// Make a new ClientSession like `new`, but give this session's
+    /// secrets to `key_log` instead of `config.key_log`.
+    ///
+    /// This allows logging secrets for some sessions but not others,
+    /// without making a separate `ClientConfig` for each.
+    pub fn new_with_key_log(
+        config: &Arc<ClientConfig>,
+        name: ServerName,
+        key_log: Arc<dyn KeyLog>,
+    ) -> Result<ClientSession, TlsError> {
+        let mut imp = ClientSessionImpl::new(config);
+        imp.key_log = key_log;
+        imp.start_handshake(name, vec![])?;
+        Ok(ClientSession { imp })
+    }
+
+    /// Give secrets derived from now on to `key_log` instead of
+    /// `config.key_log`.  Pass `Arc::new(NoKeyLog)` to stop logging
+    /// this session's secrets.
+    ///
+    /// The early traffic secret is derived when the session is made,
+    /// so use `new_with_key_log` if that must be covered too.
+    pub fn set_key_log(&mut self, key_log: Arc<dyn KeyLog>) {
+        self.imp.key_log = key_log;
+    }
+
     /// Returns an `io::Write` implementer you can write bytes to
     /// to send TLS1.3 early data (a.k.a. "0-RTT data") to the server.
     ///
@@ -709,6 +1004,109 @@ impl ClientSession {
     pub fn is_early_data_accepted(&self) -> bool {
         self.imp.early_data.is_accepted()
     }
+
+    /// Returns the payload of the server's extension of type `typ`,
+    /// if it sent one in reply to one of `ClientConfig::custom_extensions`.
+    ///
+    /// For TLS1.2 this is from the ServerHello; for TLS1.3, from the
+    /// ServerHello or EncryptedExtensions.
+    pub fn get_custom_extension(&self, typ: u16) -> Option<&[u8]> {
+        self.imp
+            .custom_extensions_received
+            .iter()
+            .find(|(t, _)| *t == typ)
+            .map(|(_, payload)| payload.as_ref())
+    }
+
+    /// Extract the negotiated traffic keys and sequence numbers, so that
+    /// record protection can be handed over to something else -- typically
+    /// the kernel, via kTLS.
+    ///
+    /// This consumes the session: once the secrets are in use elsewhere,
+    /// this session's sequence numbers are stale and it must not be used
+    /// to send or receive any more data.
+    ///
+    /// This fails if the handshake is not complete, if there is TLS data
+    /// waiting to be written or plaintext waiting to be read, or for QUIC
+    /// and DTLS sessions.
+    ///
+    /// These are secrets: mishandling them breaks the security of the
+    /// connection, so take care over where they end up.
+    #[cfg(feature = "secret_extraction")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "secret_extraction")))]
+    pub fn dangerous_extract_secrets(self) -> Result<ExtractedSecrets, TlsError> {
+        self.imp.extract_secrets()
+    }
+
+    /// Returns the "tls-server-end-point" channel binding for this
+    /// connection, as defined in RFC5929: a hash of the server's
+    /// end-entity certificate.
+    ///
+    /// The hash function is the one used in the certificate's signature,
+    /// except that MD5 and SHA-1 are replaced by SHA-256.  This fails if
+    /// the handshake is not complete, if the server didn't present a
+    /// certificate, or if the certificate's signature algorithm doesn't
+    /// name a single hash function (Ed25519, for example).
+    pub fn get_tls_server_end_point_channel_binding(&self) -> Result<Vec<u8>, TlsError> {
+        if self.imp.is_handshaking() {
+            return Err(TlsError::HandshakeNotComplete);
+        }
+
+        let cert = self
+            .imp
+            .server_cert_chain
+            .first()
+            .ok_or(TlsError::NoCertificatesPresented)?;
+        let alg = x509::end_point_hash(&cert.0).map_err(|_| {
+            TlsError::General("no tls-server-end-point hash for certificate".to_string())
+        })?;
+        Ok(digest::digest(alg, &cert.0)
+            .as_ref()
+            .to_vec())
+    }
+
+    /// Returns the DER-encoded OCSP response stapled by the server,
+    /// if any.
+    ///
+    /// This is available once the server's certificate has been
+    /// verified, and is `None` if the server didn't staple a response
+    /// or if the session was resumed.
+    pub fn get_ocsp_response(&self) -> Option<&[u8]> {
+        if self.imp.server_ocsp_response.is_empty() {
+            None
+        } else {
+            Some(&self.imp.server_ocsp_response)
+        }
+    }
+
+    /// Returns the DER-encoded OCSP responses stapled by the server
+    /// for its intermediate certificates.
+    ///
+    /// There is one item for each intermediate certificate, in the
+    /// same order as the certificates returned by `get_peer_certificates`
+    /// (after the end-entity certificate).  An item is `None` if the
+    /// server didn't staple a response for that certificate.  Only
+    /// TLS1.3 supports this, so all items are `None` for TLS1.2.
+    pub fn get_intermediate_ocsp_responses(&self) -> Vec<Option<&[u8]>> {
+        (0..self.imp.server_cert_chain.len().saturating_sub(1))
+            .map(|i| {
+                self.imp
+                    .server_intermediate_ocsp
+                    .get(i)
+                    .filter(|ocsp| !ocsp.is_empty())
+                    .map(Vec::as_slice)
+            })
+            .collect()
+    }
+
+    /// Returns the signed certificate timestamps which were validated
+    /// along with the server's certificate.
+    ///
+    /// This is empty if no CT logs were configured, the server didn't
+    /// send any SCTs, or the session was resumed.
+    pub fn get_validated_scts(&self) -> &[verify::ValidatedSct] {
+        &self.imp.validated_scts
+    }
 }
 
 impl Session for ClientSession {
@@ -716,6 +1114,10 @@ impl Session for ClientSession {
         self.imp.common.read_tls(rd)
     }
 
+    fn read_tls_buf(&mut self, rd: &mut dyn io::BufRead) -> io::Result<usize> {
+        self.imp.common.read_tls_buf(rd)
+    }
+
     /// Writes TLS messages to `wr`.
     fn write_tls(&mut self, wr: &mut dyn io::Write) -> io::Result<usize> {
         self.imp.common.write_tls(wr)
@@ -772,6 +1174,22 @@ impl Session for ClientSession {
             .get_negotiated_ciphersuite()
             .or(self.imp.resumption_ciphersuite)
     }
+
+    fn get_negotiated_kx_group(&self) -> Option<&'static SupportedKxGroup> {
+        self.imp.get_negotiated_kx_group()
+    }
+
+    fn get_peer_signature_scheme(&self) -> Option<SignatureScheme> {
+        self.imp.get_peer_signature_scheme()
+    }
+
+    fn handshake_kind(&self) -> Option<HandshakeKind> {
+        self.imp.handshake_kind()
+    }
+
+    fn get_sent_fatal_alert(&self) -> Option<AlertDescription> {
+        self.imp.common.get_sent_fatal_alert()
+    }
 }
 
 impl io::Read for ClientSession {
@@ -792,6 +1210,20 @@ impl io::Read for ClientSession {
     }
 }
 
+impl io::BufRead for ClientSession {
+    /// Obtain plaintext data received from the peer, without copying
+    /// it out of the session's buffer.  This returns an empty slice if
+    /// there is none yet, and fails like `read` once the peer has
+    /// closed the session cleanly.
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        self.imp.common.fill_buf()
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.imp.common.consume(amt)
+    }
+}
+
 impl io::Write for ClientSession {
     /// Send the plaintext `buf` to the peer, encrypting
     /// and authenticating it.  Once this function succeeds
@@ -800,19 +1232,25 @@ impl io::Write for ClientSession {
     ///
     /// This function buffers plaintext sent before the
     /// TLS handshake completes, and sends it as soon
-    /// as it can.  This buffer is of *unlimited size* so
-    /// writing much data before it can be sent will
-    /// cause excess memory usage.
+    /// as it can.  This buffer is of unlimited size unless
+    /// `set_buffer_limit` is used, so writing much data before
+    /// it can be sent will cause excess memory usage.
+    ///
+    /// If the buffer limit has been reached this fails with
+    /// `io::ErrorKind::WouldBlock`.
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
-        Ok(self.imp.send_some_plaintext(buf))
+        let len = self.imp.send_some_plaintext(buf);
+        plaintext_written(len, buf.len())
     }
 
     fn write_vectored(&mut self, bufs: &[IoSlice<'_>]) -> io::Result<usize> {
         let mut sz = 0;
+        let mut requested = 0;
         for buf in bufs {
             sz += self.imp.send_some_plaintext(buf);
+            requested += buf.len();
         }
-        Ok(sz)
+        plaintext_written(sz, requested)
     }
 
     fn flush(&mut self) -> io::Result<()> {
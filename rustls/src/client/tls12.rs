@@ -1,12 +1,14 @@
 use crate::check::check_message;
 use crate::client::ClientSessionImpl;
-use crate::error::TlsError;
+use crate::error::{PeerMisbehaved, TlsError};
+#[cfg(feature = "secret_extraction")]
+use crate::suites::ConnectionTrafficSecrets;
 #[cfg(feature = "logging")]
-use crate::log::{debug, trace};
+use crate::log::{debug, trace, warn};
 use crate::msgs::base::{Payload, PayloadU8};
 use crate::msgs::ccs::ChangeCipherSpecPayload;
 use crate::msgs::codec::Codec;
-use crate::msgs::enums::{AlertDescription, ProtocolVersion};
+use crate::msgs::enums::{AlertDescription, ProtocolVersion, SignatureScheme};
 use crate::msgs::enums::{ContentType, HandshakeType};
 use crate::msgs::handshake::{DecomposedSignatureScheme, SCTList, CertificatePayload};
 use crate::msgs::handshake::DigitallySignedStruct;
@@ -17,7 +19,7 @@ use crate::msgs::persist;
 use crate::session::{SessionRandoms, SessionSecrets};
 use crate::SupportedCipherSuite;
 use crate::kx;
-use crate::ticketer;
+use crate::time;
 use crate::verify;
 
 use crate::client::common::{ClientAuthDetails, ReceivedTicketDetails};
@@ -272,7 +274,7 @@ fn emit_clientkx(
     kxd: &kx::KeyExchangeResult,
 ) {
     let mut buf = Vec::new();
-    let ecpoint = PayloadU8::new(Vec::from(kxd.pubkey.as_ref()));
+    let ecpoint = PayloadU8::new(kxd.pubkey.clone());
     ecpoint.encode(&mut buf);
     let pubkey = Payload::new(buf);
 
@@ -415,15 +417,21 @@ impl hs::State for ExpectCertificateRequest {
             .iter()
             .map(|p| p.0.as_slice())
             .collect::<Vec<&[u8]>>();
+        let sigschemes = certreq
+            .sigschemes
+            .iter()
+            .cloned()
+            .filter(|scheme| sess.config.permits_signature_scheme(*scheme))
+            .collect::<Vec<SignatureScheme>>();
         let maybe_certkey = sess
             .config
             .client_auth_cert_resolver
-            .resolve(&canames, &certreq.sigschemes);
+            .resolve(&canames, &sigschemes);
 
         if let Some(mut certkey) = maybe_certkey {
             let maybe_signer = certkey
                 .key
-                .choose_scheme(&certreq.sigschemes);
+                .choose_scheme(&sigschemes);
 
             if let Some(_) = &maybe_signer {
                 debug!("Attempting client auth");
@@ -553,7 +561,7 @@ impl hs::State for ExpectServerDone {
         hs::check_aligned_handshake(sess)?;
 
         trace!("Server cert is {:?}", st.server_cert.cert_chain);
-        debug!("Server DNS name is {:?}", st.handshake.dns_name);
+        debug!("Server name is {:?}", st.handshake.server_name);
 
         let suite = st.suite;
 
@@ -575,20 +583,28 @@ impl hs::State for ExpectServerDone {
             .cert_chain
             .split_first()
             .ok_or(TlsError::NoCertificatesPresented)?;
-        let now = std::time::SystemTime::now();
+        let now = time::now(sess.config.time_provider.as_ref())?;
         let certv = sess
             .config
             .get_verifier()
             .verify_server_cert(
                 end_entity,
                 intermediates,
-                st.handshake.dns_name.as_ref(),
+                &st.handshake.server_name,
                 &mut st.server_cert.scts(),
                 &st.server_cert.ocsp_response,
                 now,
             )
             .map_err(|err| hs::send_cert_error_alert(sess, err))?;
 
+        let verified_intermediates = certv
+            .intermediates()
+            .unwrap_or(intermediates);
+        sess.config
+            .revocation_checker
+            .check_revocation(end_entity, verified_intermediates, now)
+            .map_err(|err| hs::send_cert_error_alert(sess, err))?;
+
         // 3.
         // Build up the contents of the signed message.
         // It's ClientHello.random || ServerHello.random || ServerKeyExchange.params
@@ -601,12 +617,21 @@ impl hs::State for ExpectServerDone {
             // Check the signature is compatible with the ciphersuite.
             let sig = &st.server_kx.kx_sig;
             if !suite.usable_for_sigalg(sig.scheme.sign()) {
-                let error_message = format!(
+                warn!(
                     "peer signed kx with wrong algorithm (got {:?} expect {:?})",
                     sig.scheme.sign(),
                     suite.sign
                 );
-                return Err(TlsError::PeerMisbehavedError(error_message));
+                return Err(TlsError::PeerMisbehavedError(
+                    PeerMisbehaved::SignedKxWithWrongAlgorithm,
+                ));
+            }
+
+            if !sess.config.permits_signature_scheme(sig.scheme) {
+                return Err(hs::illegal_param(
+                    sess,
+                    PeerMisbehaved::SignedHandshakeWithUnofferedSigScheme,
+                ));
             }
 
             sess.config
@@ -614,7 +639,10 @@ impl hs::State for ExpectServerDone {
                 .verify_tls12_signature(&message, &st.server_cert.cert_chain[0], sig)
                 .map_err(|err| hs::send_cert_error_alert(sess, err))?
         };
+        sess.common.peer_signature_scheme = Some(st.server_kx.kx_sig.scheme);
         sess.server_cert_chain = st.server_cert.take_chain();
+        sess.server_ocsp_response = st.server_cert.take_ocsp_response();
+        sess.validated_scts = certv.scts().to_vec();
 
         // 4.
         if let Some(client_auth) = &mut st.client_auth {
@@ -622,8 +650,10 @@ impl hs::State for ExpectServerDone {
         }
 
         // 5a.
-        let kxd = kx::KeyExchange::client_ecdhe(&st.server_kx.kx_params, &sess.config.kx_groups)
-            .ok_or_else(|| TlsError::PeerMisbehavedError("key exchange failed".to_string()))?;
+        let kxd = kx::KeyExchange::client_ecdhe(&st.server_kx.kx_params, &sess.config.kx_groups,
+                                                 sess.config.kx_provider.as_ref())
+            .ok_or(TlsError::PeerMisbehavedError(PeerMisbehaved::InvalidKeyShare))?;
+        sess.common.kx_group = Some(kxd.group);
 
         // 5b.
         emit_clientkx(&mut st.handshake, sess, &kxd);
@@ -652,7 +682,7 @@ impl hs::State for ExpectServerDone {
         } else {
             SessionSecrets::new(&st.randoms, suite, &kxd.shared_secret)
         };
-        sess.config.key_log.log(
+        sess.key_log.log(
             "CLIENT_RANDOM",
             &secrets.randoms.client,
             &secrets.master_secret,
@@ -776,7 +806,15 @@ fn save_session(
         return;
     }
 
-    let key = persist::ClientSessionKey::session_for_dns_name(handshake.dns_name.as_ref());
+    let now = match time::unix_time(sess.config.time_provider.as_ref()) {
+        Ok(now) => now,
+        Err(_) => {
+            debug!("Session not saved: current time unknown");
+            return;
+        }
+    };
+
+    let key = persist::ClientSessionKey::session_for_server_name(&handshake.server_name);
 
     let master_secret = secrets.get_master_secret();
     let mut value = persist::ClientSessionValue::new(
@@ -787,11 +825,16 @@ fn save_session(
         master_secret,
         &sess.server_cert_chain,
     );
-    value.set_times(ticketer::timebase(), recvd_ticket.new_ticket_lifetime, 0);
+    value.set_times(now, recvd_ticket.new_ticket_lifetime, 0);
     if handshake.using_ems {
         value.set_extended_ms_used();
     }
 
+    if !sess.config.may_resume(&value) {
+        debug!("Session not saved: that kind of resumption is disabled");
+        return;
+    }
+
     let worked = sess
         .config
         .session_persistence
@@ -894,7 +937,14 @@ impl hs::State for ExpectTraffic {
         context: Option<&[u8]>,
     ) -> Result<(), TlsError> {
         self.secrets
-            .export_keying_material(output, label, context);
-        Ok(())
+            .export_keying_material(output, label, context)
+    }
+
+    #[cfg(feature = "secret_extraction")]
+    fn extract_secrets(
+        &self,
+        _sess: &ClientSessionImpl,
+    ) -> Result<(ConnectionTrafficSecrets, ConnectionTrafficSecrets), TlsError> {
+        Ok(self.secrets.extract_secrets())
     }
 }
@@ -1,7 +1,9 @@
 use crate::check::check_message;
 use crate::{cipher, SupportedCipherSuite};
 use crate::client::ClientSessionImpl;
-use crate::error::TlsError;
+use crate::error::{CertificateError, CrlError, OcspError, PeerMisbehaved, TlsError};
+#[cfg(feature = "secret_extraction")]
+use crate::suites::ConnectionTrafficSecrets;
 use crate::key_schedule::{
     KeyScheduleEarly, KeyScheduleHandshake, KeyScheduleNonSecret, KeyScheduleTraffic,
     KeyScheduleTrafficWithClientFinishedPending,
@@ -24,10 +26,12 @@ use crate::msgs::handshake::{HasServerExtensions, ServerHelloPayload, SessionID}
 use crate::msgs::handshake::{PresharedKeyIdentity, PresharedKeyOffer};
 use crate::msgs::message::{Message, MessagePayload};
 use crate::msgs::persist;
+use crate::server_name::ServerName;
+use crate::session::HandshakeKind;
 use crate::session::SessionRandoms;
 use crate::sign;
 use crate::kx;
-use crate::ticketer;
+use crate::time;
 use crate::verify;
 #[cfg(feature = "quic")]
 use crate::{msgs::base::PayloadU16, quic, session::Protocol};
@@ -37,7 +41,6 @@ use crate::client::common::{HandshakeDetails, ServerCertDetails};
 use crate::client::hs;
 
 use ring::constant_time;
-use webpki;
 use ring::digest::Digest;
 
 // Extensions we expect in plaintext in the ServerHello.
@@ -64,17 +67,15 @@ pub fn validate_server_hello(
         if !ALLOWED_PLAINTEXT_EXTS.contains(&ext.get_type()) {
             sess.common
                 .send_fatal_alert(AlertDescription::UnsupportedExtension);
-            return Err(TlsError::PeerMisbehavedError(
-                "server sent unexpected cleartext ext".to_string(),
-            ));
+            return Err(TlsError::PeerMisbehavedError(PeerMisbehaved::UnexpectedCleartextExtension));
         }
     }
 
     Ok(())
 }
 
-fn find_kx_hint(sess: &ClientSessionImpl, dns_name: webpki::DNSNameRef) -> Option<NamedGroup> {
-    let key = persist::ClientSessionKey::hint_for_dns_name(dns_name);
+fn find_kx_hint(sess: &ClientSessionImpl, server_name: &ServerName) -> Option<NamedGroup> {
+    let key = persist::ClientSessionKey::hint_for_server_name(server_name);
     let key_buf = key.get_encoding();
 
     let maybe_value = sess
@@ -84,8 +85,8 @@ fn find_kx_hint(sess: &ClientSessionImpl, dns_name: webpki::DNSNameRef) -> Optio
     maybe_value.and_then(|enc| NamedGroup::read_bytes(&enc))
 }
 
-fn save_kx_hint(sess: &mut ClientSessionImpl, dns_name: webpki::DNSNameRef, group: NamedGroup) {
-    let key = persist::ClientSessionKey::hint_for_dns_name(dns_name);
+fn save_kx_hint(sess: &mut ClientSessionImpl, server_name: &ServerName, group: NamedGroup) {
+    let key = persist::ClientSessionKey::hint_for_server_name(server_name);
 
     sess.config
         .session_persistence
@@ -109,7 +110,7 @@ pub fn choose_kx_groups(
         .and_then(|req| {
             HelloRetryRequest::get_requested_key_share_group(req)
         })
-        .or_else(|| find_kx_hint(sess, handshake.dns_name.as_ref()))
+        .or_else(|| find_kx_hint(sess, &handshake.server_name))
         .unwrap_or_else(|| {
                 sess.config
                     .kx_groups
@@ -130,7 +131,8 @@ pub fn choose_kx_groups(
         hello
             .offered_key_shares
             .push(already_offered_share);
-    } else if let Some(key_share) = kx::KeyExchange::choose(group, &sess.config.kx_groups).and_then(kx::KeyExchange::start) {
+    } else if let Some(key_share) = kx::KeyExchange::choose(group, &sess.config.kx_groups)
+        .and_then(|skxg| kx::KeyExchange::start(skxg, sess.config.kx_provider.as_ref())) {
         key_shares.push(KeyShareEntry::new(group, key_share.pubkey.as_ref()));
         hello.offered_key_shares.push(key_share);
     }
@@ -141,6 +143,7 @@ pub fn choose_kx_groups(
 /// This implements the horrifying TLS1.3 hack where PSK binders have a
 /// data dependency on the message they are contained within.
 pub fn fill_in_psk_binder(
+    sess: &ClientSessionImpl,
     handshake: &HandshakeDetails,
     hmp: &mut HandshakeMessagePayload,
 ) -> KeyScheduleEarly {
@@ -162,7 +165,7 @@ pub fn fill_in_psk_binder(
 
     // Run a fake key_schedule to simulate what the server will do if it chooses
     // to resume.
-    let key_schedule = KeyScheduleEarly::new(hkdf_alg, &resuming.master_secret.0);
+    let key_schedule = KeyScheduleEarly::new(sess.common.protocol, hkdf_alg, &resuming.master_secret.0);
     let real_binder = key_schedule.resumption_psk_binder_key_and_sign_verify_data(&handshake_hash);
 
     if let HandshakePayload::ClientHello(ref mut ch) = hmp.payload {
@@ -186,22 +189,23 @@ pub fn start_handshake_traffic(
         .ok_or_else(|| {
             sess.common
                 .send_fatal_alert(AlertDescription::MissingExtension);
-            TlsError::PeerMisbehavedError("missing key share".to_string())
+            TlsError::PeerMisbehavedError(PeerMisbehaved::MissingKeyShare)
         })?;
 
     let our_key_share = hello
         .find_key_share_and_discard_others(their_key_share.group)
-        .ok_or_else(|| hs::illegal_param(sess, "wrong group for key share"))?;
+        .ok_or_else(|| hs::illegal_param(sess, PeerMisbehaved::WrongGroupForKeyShare))?;
     let shared = our_key_share
         .complete(&their_key_share.payload.0)
-        .ok_or_else(|| TlsError::PeerMisbehavedError("key exchange failed".to_string()))?;
+        .ok_or(TlsError::PeerMisbehavedError(PeerMisbehaved::InvalidKeyShare))?;
+    sess.common.kx_group = Some(shared.group);
 
     let mut key_schedule = if let Some(selected_psk) = server_hello.get_psk_index() {
         if let Some(ref resuming) = handshake.resuming_session {
             if !resuming.suite.can_resume_to(suite) {
                 return Err(hs::illegal_param(
                     sess,
-                    "server resuming incompatible suite",
+                    PeerMisbehaved::ResumptionOfferedWithIncompatibleCipherSuite,
                 ));
             }
 
@@ -210,20 +214,19 @@ pub fn start_handshake_traffic(
             if sess.early_data.is_enabled() && resuming.suite != suite {
                 return Err(hs::illegal_param(
                     sess,
-                    "server varied suite with early data",
+                    PeerMisbehaved::EarlyDataOfferedWithVariedCipherSuite,
                 ));
             }
 
             if selected_psk != 0 {
-                return Err(hs::illegal_param(sess, "server selected invalid psk"));
+                return Err(hs::illegal_param(sess, PeerMisbehaved::SelectedInvalidPsk));
             }
 
             debug!("Resuming using PSK");
+            sess.common.handshake_kind = Some(HandshakeKind::Resumed);
             // The key schedule has been initialized and set in fill_in_psk_binder()
         } else {
-            return Err(TlsError::PeerMisbehavedError(
-                "server selected unoffered psk".to_string(),
-            ));
+            return Err(TlsError::PeerMisbehavedError(PeerMisbehaved::SelectedUnofferedPsk));
         }
         early_key_schedule
             .unwrap()
@@ -231,14 +234,22 @@ pub fn start_handshake_traffic(
     } else {
         debug!("Not resuming");
         // Discard the early data key schedule.
+        #[cfg(feature = "metrics")]
+        {
+            if sess.early_data.is_enabled() {
+                sess.config.metrics.early_data_rejected.incr();
+            }
+        }
         sess.early_data.rejected();
         sess.common.early_traffic = false;
+        #[cfg(feature = "quic")]
+        sess.common.quic.reject_0rtt();
         handshake.resuming_session.take();
-        KeyScheduleNonSecret::new(suite.hkdf_algorithm).into_handshake(&shared.shared_secret)
+        KeyScheduleNonSecret::new(sess.common.protocol, suite.hkdf_algorithm).into_handshake(&shared.shared_secret)
     };
 
     // Remember what KX group the server liked for next time.
-    save_kx_hint(sess, handshake.dns_name.as_ref(), their_key_share.group);
+    save_kx_hint(sess, &handshake.server_name, their_key_share.group);
 
     // If we change keying when a subsequent handshake message is being joined,
     // the two halves will have different record layer protections.  Disallow this.
@@ -250,7 +261,7 @@ pub fn start_handshake_traffic(
         // Set the client encryption key for handshakes if early data is not used
         let write_key = key_schedule.client_handshake_traffic_secret(
             &hash_at_client_recvd_server_hello,
-            &*sess.config.key_log,
+            &*sess.key_log,
             &randoms.client,
         );
         sess.common
@@ -263,7 +274,7 @@ pub fn start_handshake_traffic(
 
     let read_key = key_schedule.server_handshake_traffic_secret(
         &hash_at_client_recvd_server_hello,
-        &*sess.config.key_log,
+        &*sess.key_log,
         &randoms.client,
     );
     sess.common
@@ -276,7 +287,7 @@ pub fn start_handshake_traffic(
             // Traffic secret wasn't computed and stored above, so do it here.
             key_schedule.client_handshake_traffic_secret(
                 &hash_at_client_recvd_server_hello,
-                &*sess.config.key_log,
+                &*sess.key_log,
                 &randoms.client,
             )
         } else {
@@ -313,7 +324,12 @@ pub fn prepare_resumption(
     // The EarlyData extension MUST be supplied together with the
     // PreSharedKey extension.
     let max_early_data_size = resuming_session.max_early_data_size;
-    if sess.config.enable_early_data && max_early_data_size > 0 && !doing_retry {
+    // DTLS sessions don't send early data.
+    if sess.config.enable_early_data
+        && max_early_data_size > 0
+        && !doing_retry
+        && !sess.common.is_dtls()
+    {
         sess.early_data
             .enable(max_early_data_size as usize);
         exts.push(ClientExtension::EarlyData);
@@ -324,7 +340,8 @@ pub fn prepare_resumption(
     //
     // Include an empty binder. It gets filled in below because it depends on
     // the message it's contained in (!!!).
-    let obfuscated_ticket_age= resuming_session.get_obfuscated_ticket_age(ticketer::timebase());
+    let now = time::unix_time(sess.config.time_provider.as_ref()).unwrap_or(0);
+    let obfuscated_ticket_age = resuming_session.get_obfuscated_ticket_age(now);
 
     let binder_len = resuming_suite.get_hash().output_len;
     let binder = vec![0u8; binder_len];
@@ -337,7 +354,7 @@ pub fn prepare_resumption(
 }
 
 pub fn emit_fake_ccs(sent_tls13_fake_ccs: &mut bool, sess: &mut ClientSessionImpl) {
-    if sess.common.is_quic() {
+    if sess.common.is_quic() || sess.common.is_dtls() {
         return;
     }
 
@@ -361,16 +378,13 @@ fn validate_encrypted_extensions(
     if exts.has_duplicate_extension() {
         sess.common
             .send_fatal_alert(AlertDescription::DecodeError);
-        return Err(TlsError::PeerMisbehavedError(
-            "server sent duplicate encrypted extensions".to_string(),
-        ));
+        return Err(TlsError::PeerMisbehavedError(PeerMisbehaved::DuplicateEncryptedExtensions));
     }
 
     if hello.server_sent_unsolicited_extensions(exts, &[]) {
         sess.common
             .send_fatal_alert(AlertDescription::UnsupportedExtension);
-        let msg = "server sent unsolicited encrypted extension".to_string();
-        return Err(TlsError::PeerMisbehavedError(msg));
+        return Err(TlsError::PeerMisbehavedError(PeerMisbehaved::UnsolicitedEncryptedExtension));
     }
 
     for ext in exts {
@@ -379,8 +393,7 @@ fn validate_encrypted_extensions(
         {
             sess.common
                 .send_fatal_alert(AlertDescription::UnsupportedExtension);
-            let msg = "server sent inappropriate encrypted extension".to_string();
-            return Err(TlsError::PeerMisbehavedError(msg));
+            return Err(TlsError::PeerMisbehavedError(PeerMisbehaved::DisallowedEncryptedExtension));
         }
     }
 
@@ -440,6 +453,7 @@ impl hs::State for ExpectEncryptedExtensions {
             .add_message(&m);
 
         validate_encrypted_extensions(sess, &self.hello, &exts)?;
+        hs::save_custom_extensions(sess, exts);
         hs::process_alpn_protocol(sess, exts.get_alpn_protocol())?;
 
         #[cfg(feature = "quic")]
@@ -448,6 +462,28 @@ impl hs::State for ExpectEncryptedExtensions {
             if let Some(params) = exts.get_quic_params_extension() {
                 sess.common.quic.params = Some(params);
             }
+
+            // DTLS connection ID (RFC9146)
+            if let Some(cid) = exts.get_connection_id_extension() {
+                sess.common.quic.connection_id = Some(cid.to_vec());
+            }
+
+            // DTLS-SRTP (RFC5764)
+            if let Some(srtp) = exts.get_srtp_extension() {
+                let profile = match srtp.profiles.as_slice() {
+                    [profile] if sess.config.srtp_profiles.contains(profile) => *profile,
+                    _ => {
+                        return Err(hs::illegal_param(
+                            sess,
+                            PeerMisbehaved::SelectedUnofferedSrtpProfile,
+                        ))
+                    }
+                };
+                if !srtp.mki.0.is_empty() {
+                    return Err(hs::illegal_param(sess, PeerMisbehaved::SelectedUnofferedSrtpMki));
+                }
+                sess.common.quic.srtp_profile = Some(profile);
+            }
         }
 
         if let Some(resuming_session) = &self.handshake.resuming_session {
@@ -455,9 +491,21 @@ impl hs::State for ExpectEncryptedExtensions {
             if was_early_traffic {
                 if exts.early_data_extension_offered() {
                     sess.early_data.accepted();
+                    #[cfg(feature = "metrics")]
+                    sess.config.metrics.early_data_accepted.incr();
+                    #[cfg(feature = "quic")]
+                    {
+                        if sess.common.quic.early_secret.is_some() {
+                            sess.common.quic.zero_rtt = quic::ZeroRttStatus::Accepted;
+                        }
+                    }
                 } else {
                     sess.early_data.rejected();
+                    #[cfg(feature = "metrics")]
+                    sess.config.metrics.early_data_rejected.incr();
                     sess.common.early_traffic = false;
+                    #[cfg(feature = "quic")]
+                    sess.common.quic.reject_0rtt();
                 }
             }
 
@@ -468,7 +516,7 @@ impl hs::State for ExpectEncryptedExtensions {
                     .key_schedule
                     .client_handshake_traffic_secret(
                         &self.hash_at_client_recvd_server_hello,
-                        &*sess.config.key_log,
+                        &*sess.key_log,
                         &self.randoms.client,
                     );
                 sess.common
@@ -487,8 +535,9 @@ impl hs::State for ExpectEncryptedExtensions {
             Ok(self.into_expect_finished_resume(certv, sigv))
         } else {
             if exts.early_data_extension_offered() {
-                let msg = "server sent early data extension without resumption".to_string();
-                return Err(TlsError::PeerMisbehavedError(msg));
+                return Err(TlsError::PeerMisbehavedError(
+                    PeerMisbehaved::EarlyDataExtensionWithoutResumption,
+                ));
             }
             Ok(self.into_expect_certificate_or_certreq())
         }
@@ -546,25 +595,22 @@ impl hs::State for ExpectCertificate {
             warn!("certificate chain contains unsolicited/unknown extension");
             sess.common
                 .send_fatal_alert(AlertDescription::UnsupportedExtension);
-            return Err(TlsError::PeerMisbehavedError(
-                "bad cert chain extensions".to_string(),
-            ));
+            return Err(TlsError::PeerMisbehavedError(PeerMisbehaved::BadCertChainExtensions));
         }
 
-        let server_cert = ServerCertDetails::new(
+        let mut server_cert = ServerCertDetails::new(
             cert_chain.convert(),
             cert_chain.get_end_entity_ocsp(),
             cert_chain.get_end_entity_scts());
+        server_cert.intermediate_ocsp = cert_chain.get_intermediate_ocsp();
 
         if let Some(sct_list) = server_cert.scts.as_ref() {
             if hs::sct_list_is_invalid(sct_list) {
-                let error_msg = "server sent invalid SCT list".to_string();
-                return Err(TlsError::PeerMisbehavedError(error_msg));
+                return Err(TlsError::PeerMisbehavedError(PeerMisbehaved::InvalidSctList));
             }
 
             if !self.may_send_sct_list {
-                let error_msg = "server sent unsolicited SCT list".to_string();
-                return Err(TlsError::PeerMisbehavedError(error_msg));
+                return Err(TlsError::PeerMisbehavedError(PeerMisbehaved::UnsolicitedSctList));
             }
         }
 
@@ -653,7 +699,7 @@ impl ExpectCertificateVerify {
 
 fn send_cert_error_alert(sess: &mut ClientSessionImpl, err: TlsError) -> TlsError {
     match err {
-        TlsError::WebPKIError(webpki::Error::BadDER, _) => {
+        TlsError::InvalidCertificate(CertificateError::BadEncoding) => {
             sess.common
                 .send_fatal_alert(AlertDescription::DecodeError);
         }
@@ -661,6 +707,12 @@ fn send_cert_error_alert(sess: &mut ClientSessionImpl, err: TlsError) -> TlsErro
             sess.common
                 .send_fatal_alert(AlertDescription::IllegalParameter);
         }
+        TlsError::InvalidCertificate(CertificateError::Revoked)
+        | TlsError::CRLError(CrlError::CertRevoked)
+        | TlsError::InvalidOCSPResponse(OcspError::CertRevoked) => {
+            sess.common
+                .send_fatal_alert(AlertDescription::CertificateRevoked);
+        }
         _ => {
             sess.common
                 .send_fatal_alert(AlertDescription::BadCertificate);
@@ -690,21 +742,36 @@ impl hs::State for ExpectCertificateVerify {
             .cert_chain
             .split_first()
             .ok_or(TlsError::NoCertificatesPresented)?;
-        let now = std::time::SystemTime::now();
+        let now = time::now(sess.config.time_provider.as_ref())?;
         let certv = sess
             .config
             .get_verifier()
             .verify_server_cert(
                 end_entity,
                 intermediates,
-                self.handshake.dns_name.as_ref(),
+                &self.handshake.server_name,
                 &mut self.server_cert.scts(),
                 &self.server_cert.ocsp_response,
                 now,
             )
             .map_err(|err| send_cert_error_alert(sess, err))?;
 
+        let verified_intermediates = certv
+            .intermediates()
+            .unwrap_or(intermediates);
+        sess.config
+            .revocation_checker
+            .check_revocation(end_entity, verified_intermediates, now)
+            .map_err(|err| send_cert_error_alert(sess, err))?;
+
         // 2. Verify their signature on the handshake.
+        if !sess.config.permits_signature_scheme(cert_verify.scheme) {
+            return Err(hs::illegal_param(
+                sess,
+                PeerMisbehaved::SignedHandshakeWithUnofferedSigScheme,
+            ));
+        }
+
         let handshake_hash = self
             .handshake
             .transcript
@@ -718,8 +785,12 @@ impl hs::State for ExpectCertificateVerify {
                 &cert_verify,
             )
             .map_err(|err| send_cert_error_alert(sess, err))?;
+        sess.common.peer_signature_scheme = Some(cert_verify.scheme);
 
         sess.server_cert_chain = self.server_cert.take_chain();
+        sess.server_ocsp_response = self.server_cert.take_ocsp_response();
+        sess.server_intermediate_ocsp = self.server_cert.take_intermediate_ocsp();
+        sess.validated_scts = certv.scts().to_vec();
         self.handshake
             .transcript
             .add_message(&m);
@@ -786,7 +857,9 @@ impl hs::State for ExpectCertificateRequest {
             .unwrap_or(&no_sigschemes)
             .iter()
             .cloned()
-            .filter(|scheme| tls13_sign_schemes.contains(scheme))
+            .filter(|scheme| {
+                tls13_sign_schemes.contains(scheme) && sess.config.permits_signature_scheme(*scheme)
+            })
             .collect::<Vec<SignatureScheme>>();
 
         if compat_sigschemes.is_empty() {
@@ -953,8 +1026,11 @@ impl ExpectFinished {
         sig_verified: verify::HandshakeSignatureValid,
         fin_verified: verify::FinishedMessageVerified,
     ) -> ExpectTraffic {
+        // Nothing more goes into the transcript, so keep only its final
+        // hash (for deriving ticket PSKs) and let the rest go.
         ExpectTraffic {
-            handshake,
+            server_name: handshake.server_name,
+            handshake_hash: handshake.transcript.get_current_hash(),
             key_schedule,
             want_write_key_update: false,
             _cert_verified: cert_verified,
@@ -993,7 +1069,7 @@ impl hs::State for ExpectFinished {
                 .key_schedule
                 .client_handshake_traffic_secret(
                     &st.hash_at_client_recvd_server_hello,
-                    &*sess.config.key_log,
+                    &*sess.key_log,
                     &st.randoms.client,
                 );
             Some(key)
@@ -1037,7 +1113,7 @@ impl hs::State for ExpectFinished {
         /* Traffic from server is now decrypted with application data keys. */
         let read_key = key_schedule_finished.server_application_traffic_secret(
             &hash_after_handshake,
-            &*sess.config.key_log,
+            &*sess.key_log,
             &st.randoms.client,
         );
         sess.common
@@ -1046,13 +1122,13 @@ impl hs::State for ExpectFinished {
 
         key_schedule_finished.exporter_master_secret(
             &hash_after_handshake,
-            &*sess.config.key_log,
+            &*sess.key_log,
             &st.randoms.client,
         );
 
         let write_key = key_schedule_finished.client_application_traffic_secret(
             &hash_after_handshake,
-            &*sess.config.key_log,
+            &*sess.key_log,
             &st.randoms.client,
         );
         sess.common
@@ -1071,7 +1147,7 @@ impl hs::State for ExpectFinished {
         );
         #[cfg(feature = "quic")]
         {
-            if sess.common.protocol == Protocol::Quic {
+            if sess.common.protocol != Protocol::Tls13 {
                 sess.common.quic.traffic_secrets = Some(quic::Secrets {
                     client: write_key,
                     server: read_key,
@@ -1088,7 +1164,8 @@ impl hs::State for ExpectFinished {
 // In this state we can be sent tickets, keyupdates,
 // and application data.
 struct ExpectTraffic {
-    handshake: HandshakeDetails,
+    server_name: ServerName,
+    handshake_hash: Digest,
     key_schedule: KeyScheduleTraffic,
     want_write_key_update: bool,
     _cert_verified: verify::ServerCertVerified,
@@ -1102,13 +1179,22 @@ impl ExpectTraffic {
         sess: &mut ClientSessionImpl,
         nst: &NewSessionTicketPayloadTLS13,
     ) -> Result<(), TlsError> {
-        let handshake_hash = self
-            .handshake
-            .transcript
-            .get_current_hash();
+        if !sess.config.enable_tls13_resumption {
+            debug!("Ticket not saved: TLS1.3 resumption is disabled");
+            return Ok(());
+        }
+
+        let now = match time::unix_time(sess.config.time_provider.as_ref()) {
+            Ok(now) => now,
+            Err(_) => {
+                debug!("Ticket not saved: current time unknown");
+                return Ok(());
+            }
+        };
+
         let secret = self
             .key_schedule
-            .resumption_master_secret_and_derive_ticket_psk(&handshake_hash, &nst.nonce.0);
+            .resumption_master_secret_and_derive_ticket_psk(&self.handshake_hash, &nst.nonce.0);
 
         let mut value = persist::ClientSessionValue::new(
             ProtocolVersion::TLSv1_3,
@@ -1118,7 +1204,7 @@ impl ExpectTraffic {
             secret,
             &sess.server_cert_chain,
         );
-        value.set_times(ticketer::timebase(), nst.lifetime, nst.age_add);
+        value.set_times(now, nst.lifetime, nst.age_add);
 
         if let Some(sz) = nst.get_max_early_data_size() {
             value.set_max_early_data_size(sz);
@@ -1127,14 +1213,14 @@ impl ExpectTraffic {
                 if sess.common.protocol == Protocol::Quic {
                     if sz != 0 && sz != 0xffff_ffff {
                         return Err(TlsError::PeerMisbehavedError(
-                            "invalid max_early_data_size".into(),
+                            PeerMisbehaved::InvalidMaxEarlyDataSize,
                         ));
                     }
                 }
             }
         }
 
-        let key = persist::ClientSessionKey::session_for_dns_name(self.handshake.dns_name.as_ref());
+        let key = persist::ClientSessionKey::session_for_server_name(&self.server_name);
         #[allow(unused_mut)]
         let mut ticket = value.get_encoding();
 
@@ -1172,12 +1258,13 @@ impl ExpectTraffic {
     ) -> Result<(), TlsError> {
         #[cfg(feature = "quic")]
         {
-            if let Protocol::Quic = sess.common.protocol {
+            if sess.common.protocol != Protocol::Tls13 {
                 sess.common
                     .send_fatal_alert(AlertDescription::UnexpectedMessage);
-                let msg = "KeyUpdate received in QUIC connection".to_string();
-                warn!("{}", msg);
-                return Err(TlsError::PeerMisbehavedError(msg));
+                warn!("KeyUpdate received in {:?} connection", sess.common.protocol);
+                return Err(TlsError::PeerMisbehavedError(
+                    PeerMisbehaved::KeyUpdateReceivedInQuicOrDtls,
+                ));
             }
         }
 
@@ -1204,6 +1291,8 @@ impl ExpectTraffic {
         sess.common
             .record_layer
             .set_message_decrypter(cipher::new_tls13_read(suite, &new_read_key));
+        sess.common
+            .emit(|ev| ev.key_update_received());
 
         Ok(())
     }
@@ -1249,6 +1338,19 @@ impl hs::State for ExpectTraffic {
             .export_keying_material(output, label, context)
     }
 
+    #[cfg(feature = "secret_extraction")]
+    fn extract_secrets(
+        &self,
+        sess: &ClientSessionImpl,
+    ) -> Result<(ConnectionTrafficSecrets, ConnectionTrafficSecrets), TlsError> {
+        if self.want_write_key_update {
+            return Err(TlsError::General("a key update is pending".into()));
+        }
+        Ok(self
+            .key_schedule
+            .extract_secrets(sess.common.get_suite_assert()))
+    }
+
     fn perhaps_write_key_update(&mut self, sess: &mut ClientSessionImpl) {
         if self.want_write_key_update {
             self.want_write_key_update = false;
@@ -1262,6 +1364,8 @@ impl hs::State for ExpectTraffic {
             sess.common
                 .record_layer
                 .set_message_encrypter(cipher::new_tls13_write(scs, &write_key));
+            sess.common
+                .emit(|ev| ev.key_update_sent());
         }
     }
 }
@@ -0,0 +1,433 @@
+// Checking certificates against certificate revocation lists (RFC5280).
+//
+// webpki doesn't deal with CRLs, so after it has built a chain we
+// walk it again here using the DER helpers in `x509`.
+
+use crate::error::CrlError;
+#[cfg(feature = "logging")]
+use crate::log::{debug, trace};
+use crate::x509::{self, ChainLink, Reader};
+use crate::x509::{TAG_BOOLEAN, TAG_CONTEXT_0, TAG_GENERALIZED_TIME, TAG_UTC_TIME};
+
+use ring::io::der;
+
+impl From<x509::Error> for CrlError {
+    fn from(err: x509::Error) -> Self {
+        match err {
+            x509::Error::BadDer => CrlError::Malformed,
+            x509::Error::UnsupportedSignatureAlgorithm => CrlError::UnsupportedSignatureAlgorithm,
+            x509::Error::BadSignature => CrlError::BadSignature,
+            x509::Error::UnknownIssuer => CrlError::UnknownIssuer,
+        }
+    }
+}
+
+/// What to do with a certificate whose issuer has no CRL in a `CrlSet`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum UnknownStatusPolicy {
+    /// Accept the certificate.
+    Allow,
+    /// Reject the certificate with `CrlError::UnknownStatus`.
+    Deny,
+}
+
+/// What to do with a CRL whose nextUpdate time has passed.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ExpiredCrlPolicy {
+    /// Keep using the CRL: it is still the best information we have.
+    Ignore,
+    /// Reject certificates it covers with `CrlError::Expired`.
+    Enforce,
+}
+
+/// A parsed CRL.  We keep owned copies of just the parts
+/// needed to check its signature and look up serial numbers.
+#[derive(Debug, Clone)]
+struct Crl {
+    issuer: Vec<u8>,
+    this_update: u64,
+    next_update: Option<u64>,
+    revoked_serials: Vec<Vec<u8>>,
+    tbs: Vec<u8>,
+    signature_alg: Vec<u8>,
+    signature: Vec<u8>,
+}
+
+impl Crl {
+    fn parse(der: &[u8]) -> Result<Crl, CrlError> {
+        let mut outer = Reader::new(der);
+        let mut crl = outer.read_sequence()?;
+        outer.finish()?;
+
+        let (tbs_value, tbs) = crl.read_tlv(der::Tag::Sequence as u8)?;
+        let (signature_alg, _) = crl.read_algorithm()?;
+        let signature = crl.read_bit_string()?;
+        crl.finish()?;
+
+        // TBSCertList
+        let mut tbs_reader = Reader::new(tbs_value);
+        tbs_reader.read_optional(der::Tag::Integer as u8)?;
+        tbs_reader.skip()?;
+        let (_, issuer) = tbs_reader.read_tlv(der::Tag::Sequence as u8)?;
+        let this_update = tbs_reader.read_time()?;
+        let next_update = if tbs_reader.peek(TAG_UTC_TIME) || tbs_reader.peek(TAG_GENERALIZED_TIME) {
+            Some(tbs_reader.read_time()?)
+        } else {
+            None
+        };
+
+        let mut revoked_serials = Vec::new();
+        if let Some(entries) = tbs_reader.read_optional(der::Tag::Sequence as u8)? {
+            let mut entries = Reader::new(entries);
+            while !entries.at_end() {
+                let mut entry = entries.read_sequence()?;
+                let serial = entry.read(der::Tag::Integer as u8)?;
+                entry.read_time()?;
+                if let Some(extensions) = entry.read_optional(der::Tag::Sequence as u8)? {
+                    check_extensions(extensions)?;
+                }
+                entry.finish()?;
+                revoked_serials.push(serial.to_vec());
+            }
+        }
+
+        if let Some(explicit) = tbs_reader.read_optional(TAG_CONTEXT_0)? {
+            let mut explicit = Reader::new(explicit);
+            check_extensions(explicit.read(der::Tag::Sequence as u8)?)?;
+            explicit.finish()?;
+        }
+        tbs_reader.finish()?;
+
+        Ok(Crl {
+            issuer: issuer.to_vec(),
+            this_update,
+            next_update,
+            revoked_serials,
+            tbs: tbs.to_vec(),
+            signature_alg: signature_alg.to_vec(),
+            signature: signature.to_vec(),
+        })
+    }
+
+    fn revokes(&self, serial: &[u8]) -> bool {
+        self.revoked_serials
+            .iter()
+            .any(|revoked| revoked.as_slice() == serial)
+    }
+
+    fn verify_signature(&self, issuer: &x509::Issuer) -> Result<(), CrlError> {
+        issuer
+            .public_key
+            .verify(&self.signature_alg, &self.tbs, &self.signature)
+            .map_err(CrlError::from)
+    }
+}
+
+/// We don't implement any CRL or CRL entry extensions, so refuse
+/// any marked critical.  This rejects delta CRLs, indirect CRLs
+/// and partitioned CRLs, which we would otherwise misinterpret.
+fn check_extensions(extensions: &[u8]) -> Result<(), CrlError> {
+    let mut exts = Reader::new(extensions);
+    while !exts.at_end() {
+        let mut ext = exts.read_sequence()?;
+        ext.read(der::Tag::OID as u8)?;
+        let critical = ext.read_optional(TAG_BOOLEAN)?;
+        ext.read(der::Tag::OctetString as u8)?;
+        ext.finish()?;
+
+        if critical == Some(&[0xff]) {
+            return Err(CrlError::UnsupportedCriticalExtension);
+        }
+    }
+
+    Ok(())
+}
+
+/// A set of certificate revocation lists, and the policy for
+/// applying them.
+///
+/// These are checked after the usual certificate chain validation:
+/// the end-entity certificate and (unless `end_entity_only` is set)
+/// each intermediate certificate on the validated chain are looked up
+/// in a CRL from their issuer.  Where there are several, the one with
+/// the latest thisUpdate is used, ignoring any whose thisUpdate has
+/// not yet come.
+#[derive(Debug, Clone)]
+pub struct CrlSet {
+    crls: Vec<Crl>,
+
+    /// What to do with certificates whose issuer has no CRL in
+    /// this set.  The default is `UnknownStatusPolicy::Deny`.
+    pub unknown_status: UnknownStatusPolicy,
+
+    /// What to do with CRLs which are past their nextUpdate time.
+    /// The default is `ExpiredCrlPolicy::Ignore`.
+    pub expired_crls: ExpiredCrlPolicy,
+
+    /// Only check the end-entity certificate, and not intermediates.
+    /// The default is false.
+    pub end_entity_only: bool,
+}
+
+impl CrlSet {
+    /// Make a new, empty `CrlSet` with the default policy.
+    pub fn new() -> CrlSet {
+        CrlSet {
+            crls: Vec::new(),
+            unknown_status: UnknownStatusPolicy::Deny,
+            expired_crls: ExpiredCrlPolicy::Ignore,
+            end_entity_only: false,
+        }
+    }
+
+    /// Return true if there are no CRLs in the set.
+    pub fn is_empty(&self) -> bool {
+        self.crls.is_empty()
+    }
+
+    /// Say how many CRLs are in the set.
+    pub fn len(&self) -> usize {
+        self.crls.len()
+    }
+
+    /// Add a single DER-encoded CRL to the set.
+    ///
+    /// The CRL's signature is not checked until it is used.
+    pub fn add(&mut self, der: &[u8]) -> Result<(), CrlError> {
+        self.crls.push(Crl::parse(der)?);
+        Ok(())
+    }
+
+    /// Check the certificates on `chain`, as built by
+    /// `x509::build_chain`, against these CRLs at time `now`
+    /// (in seconds since the unix epoch).
+    ///
+    /// The chain must already have been validated.
+    pub(crate) fn check(&self, chain: &[ChainLink], now: u64) -> Result<(), CrlError> {
+        let checked = if self.end_entity_only { 1 } else { chain.len() };
+
+        for link in chain.iter().take(checked) {
+            self.check_one(link, now)?;
+        }
+
+        Ok(())
+    }
+
+    fn check_one(&self, link: &ChainLink, now: u64) -> Result<(), CrlError> {
+        let cert = &link.cert;
+        let mut newest: Option<&Crl> = None;
+        let mut unusable = None;
+
+        for crl in self
+            .crls
+            .iter()
+            .filter(|crl| crl.issuer == cert.issuer)
+        {
+            // This names the right issuer but wasn't signed by it: it
+            // is from another CA with the same name, or forged.
+            if let Err(err) = crl.verify_signature(&link.issuer) {
+                unusable = Some(err);
+                continue;
+            }
+
+            // A CRL issued after now can't be right: either it or
+            // our clock is wrong.
+            if crl.this_update > now {
+                unusable = Some(CrlError::NotYetValid);
+                continue;
+            }
+
+            // Of two CRLs issued at the same time, believe the one
+            // which revokes the certificate.
+            let newer = match newest {
+                Some(n) if n.this_update == crl.this_update => crl.revokes(cert.serial),
+                Some(n) => n.this_update < crl.this_update,
+                None => true,
+            };
+            if newer {
+                newest = Some(crl);
+            }
+        }
+
+        let crl = match newest {
+            Some(crl) => crl,
+            None if self.unknown_status == UnknownStatusPolicy::Allow => {
+                debug!("No CRL for certificate, allowing by policy");
+                return Ok(());
+            }
+            None => return Err(unusable.unwrap_or(CrlError::UnknownStatus)),
+        };
+
+        if let Some(next_update) = crl.next_update {
+            if next_update < now && self.expired_crls == ExpiredCrlPolicy::Enforce {
+                return Err(CrlError::Expired);
+            }
+        }
+
+        if crl.revokes(cert.serial) {
+            return Err(CrlError::CertRevoked);
+        }
+
+        trace!("Certificate not revoked by CRL");
+        Ok(())
+    }
+}
+
+impl Default for CrlSet {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::anchors::RootCertStore;
+    use crate::key::Certificate;
+    use crate::x509::parse_time;
+
+    fn time(s: &str) -> u64 {
+        parse_time(s.as_bytes(), true).unwrap()
+    }
+
+    fn roots() -> RootCertStore {
+        let mut roots = RootCertStore::empty();
+        roots
+            .add(&Certificate(include_bytes!("testdata/crl-ca.der").to_vec()))
+            .unwrap();
+        roots
+    }
+
+    fn check(crls: &CrlSet, now: &str) -> Result<(), CrlError> {
+        let ee = include_bytes!("testdata/crl-end.der");
+        let intermediates = [Certificate(
+            include_bytes!("testdata/crl-inter.der").to_vec(),
+        )];
+        let roots = roots();
+        let chain = x509::build_chain(ee, &intermediates, &roots)?;
+        crls.check(&chain, time(now))
+    }
+
+    fn crl_set(crls: &[&[u8]]) -> CrlSet {
+        let mut set = CrlSet::new();
+        for crl in crls {
+            set.add(crl).unwrap();
+        }
+        set
+    }
+
+    // The intermediate CRLs were issued on the 12th (good), 16th
+    // (revoked) and 20th of October 2026 (reinstated and
+    // revoked-again), the CA CRLs on the 1st.
+    static INTER_GOOD: &[u8] = include_bytes!("testdata/crl-inter-good.der");
+    static INTER_REVOKED: &[u8] = include_bytes!("testdata/crl-inter-revoked.der");
+    static INTER_REINSTATED: &[u8] = include_bytes!("testdata/crl-inter-reinstated.der");
+    static INTER_REVOKED_AGAIN: &[u8] = include_bytes!("testdata/crl-inter-revoked-again.der");
+    static CA_GOOD: &[u8] = include_bytes!("testdata/crl-ca-good.der");
+    static CA_REVOKED: &[u8] = include_bytes!("testdata/crl-ca-revoked.der");
+
+    #[test]
+    fn test_not_revoked() {
+        let crls = crl_set(&[INTER_GOOD, CA_GOOD]);
+        assert_eq!(crls.len(), 2);
+        assert_eq!(check(&crls, "20261101000000Z"), Ok(()));
+    }
+
+    #[test]
+    fn test_end_entity_revoked() {
+        let crls = crl_set(&[INTER_REVOKED, CA_GOOD]);
+        assert_eq!(check(&crls, "20261101000000Z"), Err(CrlError::CertRevoked));
+    }
+
+    #[test]
+    fn test_intermediate_revoked() {
+        let mut crls = crl_set(&[INTER_GOOD, CA_REVOKED]);
+        assert_eq!(check(&crls, "20261101000000Z"), Err(CrlError::CertRevoked));
+
+        crls.end_entity_only = true;
+        assert_eq!(check(&crls, "20261101000000Z"), Ok(()));
+    }
+
+    #[test]
+    fn test_newest_crl_is_used() {
+        let crls = crl_set(&[INTER_GOOD, INTER_REVOKED, CA_GOOD]);
+        assert_eq!(check(&crls, "20261101000000Z"), Err(CrlError::CertRevoked));
+        let crls = crl_set(&[INTER_REVOKED, INTER_GOOD, CA_GOOD]);
+        assert_eq!(check(&crls, "20261101000000Z"), Err(CrlError::CertRevoked));
+
+        let crls = crl_set(&[INTER_REVOKED, INTER_REINSTATED, CA_GOOD]);
+        assert_eq!(check(&crls, "20261101000000Z"), Ok(()));
+        let crls = crl_set(&[INTER_REINSTATED, INTER_REVOKED, CA_GOOD]);
+        assert_eq!(check(&crls, "20261101000000Z"), Ok(()));
+    }
+
+    #[test]
+    fn test_revoking_crl_wins_tie() {
+        let crls = crl_set(&[INTER_REINSTATED, INTER_REVOKED_AGAIN, CA_GOOD]);
+        assert_eq!(check(&crls, "20261101000000Z"), Err(CrlError::CertRevoked));
+        let crls = crl_set(&[INTER_REVOKED_AGAIN, INTER_REINSTATED, CA_GOOD]);
+        assert_eq!(check(&crls, "20261101000000Z"), Err(CrlError::CertRevoked));
+    }
+
+    #[test]
+    fn test_future_crl_is_ignored() {
+        let crls = crl_set(&[INTER_GOOD, INTER_REVOKED, CA_GOOD]);
+        assert_eq!(check(&crls, "20261014000000Z"), Ok(()));
+        assert_eq!(check(&crls, "20261017000000Z"), Err(CrlError::CertRevoked));
+
+        let crls = crl_set(&[INTER_REVOKED, CA_GOOD]);
+        assert_eq!(check(&crls, "20261014000000Z"), Err(CrlError::NotYetValid));
+    }
+
+    #[test]
+    fn test_unknown_status() {
+        let mut crls = crl_set(&[INTER_GOOD]);
+        assert_eq!(check(&crls, "20261101000000Z"), Err(CrlError::UnknownStatus));
+
+        crls.unknown_status = UnknownStatusPolicy::Allow;
+        assert_eq!(check(&crls, "20261101000000Z"), Ok(()));
+
+        let mut crls = CrlSet::new();
+        assert!(crls.is_empty());
+        assert_eq!(check(&crls, "20261101000000Z"), Err(CrlError::UnknownStatus));
+        crls.unknown_status = UnknownStatusPolicy::Allow;
+        assert_eq!(check(&crls, "20261101000000Z"), Ok(()));
+    }
+
+    #[test]
+    fn test_expired_crl() {
+        let mut crls = crl_set(&[INTER_GOOD, CA_GOOD]);
+        assert_eq!(check(&crls, "20270101000000Z"), Ok(()));
+
+        crls.expired_crls = ExpiredCrlPolicy::Enforce;
+        assert_eq!(check(&crls, "20261101000000Z"), Ok(()));
+        assert_eq!(check(&crls, "20270101000000Z"), Err(CrlError::Expired));
+    }
+
+    #[test]
+    fn test_tampered_crl() {
+        let mut crl = INTER_GOOD.to_vec();
+        let last = crl.len() - 1;
+        crl[last] ^= 0x01;
+        let mut crls = crl_set(&[&crl, CA_GOOD]);
+        assert_eq!(check(&crls, "20261101000000Z"), Err(CrlError::BadSignature));
+
+        crls.unknown_status = UnknownStatusPolicy::Allow;
+        assert_eq!(check(&crls, "20261101000000Z"), Ok(()));
+
+        // CRLs which fail to verify are skipped.
+        let crls = crl_set(&[&crl, INTER_REVOKED, CA_GOOD]);
+        assert_eq!(check(&crls, "20261101000000Z"), Err(CrlError::CertRevoked));
+    }
+
+    #[test]
+    fn test_unsupported_crls() {
+        let mut crls = CrlSet::new();
+        assert_eq!(
+            crls.add(include_bytes!("testdata/crl-inter-idp.der")),
+            Err(CrlError::UnsupportedCriticalExtension)
+        );
+        assert_eq!(crls.add(b"hello world"), Err(CrlError::Malformed));
+        assert!(crls.is_empty());
+    }
+}
@@ -0,0 +1,161 @@
+//! Reproducible handshakes, for tests.
+//!
+//! A handshake normally differs every time: the handshake randoms,
+//! session IDs, key shares, ticket nonces and RSA-PSS/ECDSA signatures
+//! are all fresh random material, and tickets record the time they
+//! were issued.  `configure_client` and `configure_server` replace each
+//! of these sources with one derived from a seed, and pin the clock.
+//! A client and server configured this way, with `signing_key` for the
+//! server's (and any client's) private key, exchange exactly the same
+//! bytes every time they are run with the same seeds and keys -- useful for
+//! regression tests against recorded transcripts, and for differential
+//! fuzzing.
+//!
+//! Everything here is insecure by design: anyone who knows the seed
+//! can recover the session keys.  Never enable the `deterministic`
+//! feature outside tests.
+
+use crate::client::ClientConfig;
+use crate::key;
+use crate::kx::{ActiveKeyExchange, ProvidesKeyExchange, RingKeyExchange, SupportedKxGroup};
+use crate::msgs::enums::NamedGroup;
+use crate::rand::{GetRandomFailed, ProvidesRandom};
+use crate::server::{ProducesTickets, ServerConfig};
+use crate::sign;
+use crate::ticketer::AEADTicketer;
+use crate::time::TimeProvider;
+
+use ring::digest;
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::SystemTime;
+
+/// A `ProvidesRandom` which produces a fixed stream of bytes for
+/// each seed.
+///
+/// The stream is SHA-256 of the seed and a block counter.  Each
+/// `fill` takes whole blocks, so the bytes a given call returns
+/// depend only on the seed and on how many blocks earlier calls took.
+pub struct SeededRandom {
+    seed: Vec<u8>,
+    block: AtomicU64,
+}
+
+impl SeededRandom {
+    /// Make a new stream from `seed`.
+    pub fn new(seed: &[u8]) -> SeededRandom {
+        SeededRandom {
+            seed: seed.to_vec(),
+            block: AtomicU64::new(0),
+        }
+    }
+
+    /// Make a new stream from `seed`, independent of those from the
+    /// same seed with a different `label`.
+    fn derived(seed: &[u8], label: &[u8]) -> SeededRandom {
+        let mut ctx = digest::Context::new(&digest::SHA256);
+        ctx.update(label);
+        ctx.update(seed);
+        SeededRandom::new(ctx.finish().as_ref())
+    }
+}
+
+impl ProvidesRandom for SeededRandom {
+    fn fill(&self, bytes: &mut [u8]) -> Result<(), GetRandomFailed> {
+        for chunk in bytes.chunks_mut(digest::SHA256_OUTPUT_LEN) {
+            let block = self
+                .block
+                .fetch_add(1, Ordering::Relaxed);
+            let mut ctx = digest::Context::new(&digest::SHA256);
+            ctx.update(&self.seed);
+            ctx.update(&block.to_be_bytes());
+            chunk.copy_from_slice(&ctx.finish().as_ref()[..chunk.len()]);
+        }
+        Ok(())
+    }
+}
+
+/// A `ProvidesKeyExchange` whose private keys come from a
+/// `SeededRandom`.
+pub struct SeededKeyExchange {
+    random: SeededRandom,
+}
+
+impl SeededKeyExchange {
+    /// Make a new key exchange provider, with keys drawn from `seed`.
+    pub fn new(seed: &[u8]) -> SeededKeyExchange {
+        SeededKeyExchange {
+            random: SeededRandom::new(seed),
+        }
+    }
+}
+
+impl ProvidesKeyExchange for SeededKeyExchange {
+    fn start(&self, group: &'static SupportedKxGroup) -> Option<Box<dyn ActiveKeyExchange>> {
+        // *ring* asks for exactly one scalar's worth of random material.
+        let key_len = match group.name {
+            NamedGroup::X25519 | NamedGroup::secp256r1 => 32,
+            NamedGroup::secp384r1 => 48,
+            _ => return None,
+        };
+
+        let mut key = vec![0u8; key_len];
+        self.random.fill(&mut key).ok()?;
+        let rng = ring::test::rand::FixedSliceRandom { bytes: &key };
+        RingKeyExchange::start_with_rng(group, &rng)
+    }
+}
+
+/// A `TimeProvider` which always reports the same time.
+pub struct FixedTime(pub SystemTime);
+
+impl TimeProvider for FixedTime {
+    fn current_time(&self) -> Option<SystemTime> {
+        Some(self.0)
+    }
+}
+
+/// Make a ticketer whose key and nonces are drawn from `seed`.
+pub fn ticketer(seed: &[u8]) -> Arc<dyn ProducesTickets> {
+    let random = Arc::new(SeededRandom::derived(seed, b"ticketer"));
+    Arc::new(AEADTicketer::new_with_random(random).unwrap())
+}
+
+/// Parse `der` like `sign::any_supported_type`, but RSA-PSS and ECDSA
+/// signatures made with the resulting key depend only on the key and
+/// the message signed.
+///
+/// *ring* also mixes a secret it draws when an ECDSA key is loaded
+/// into that key's signatures.  So ECDSA signatures only repeat for
+/// the same returned key: share it, rather than calling this again.
+pub fn signing_key(der: &key::PrivateKey) -> Result<Box<dyn sign::SigningKey>, ()> {
+    sign::any_supported_type_deterministic(der)
+}
+
+/// Make `config`'s sessions reproducible: their random material
+/// and key shares are drawn from `seed`, and the time is always
+/// `now`.
+///
+/// Client certificate keys must be made with `signing_key`.
+pub fn configure_client(config: &mut ClientConfig, seed: &[u8], now: SystemTime) {
+    config.random_provider = Arc::new(SeededRandom::derived(seed, b"client random"));
+    config.kx_provider = Arc::new(SeededKeyExchange {
+        random: SeededRandom::derived(seed, b"client kx"),
+    });
+    config.time_provider = Arc::new(FixedTime(now));
+}
+
+/// Make `config`'s sessions reproducible: their random material,
+/// key shares and tickets are drawn from `seed`, and the time is
+/// always `now`.
+///
+/// Certificate keys must be made with `signing_key`.
+pub fn configure_server(config: &mut ServerConfig, seed: &[u8], now: SystemTime) {
+    config.random_provider = Arc::new(SeededRandom::derived(seed, b"server random"));
+    config.kx_provider = Arc::new(SeededKeyExchange {
+        random: SeededRandom::derived(seed, b"server kx"),
+    });
+    config.time_provider = Arc::new(FixedTime(now));
+    config.ticketer = ticketer(seed);
+}
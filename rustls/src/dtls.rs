@@ -0,0 +1,1407 @@
+//! This module contains an implementation of DTLS1.3 (RFC9147).
+//!
+//! DTLS runs the TLS1.3 handshake over an unreliable datagram transport,
+//! such as UDP.  Like QUIC, it uses the TLS state machine for the
+//! cryptographic handshake only: this module supplies the record layer,
+//! handshake message fragmentation and reassembly, and the ACK-based
+//! retransmission scheme.
+//!
+//! This API is sans-IO and doesn't read any clock: feed received datagrams
+//! to `read_datagram`, send whatever `write_datagram` returns, and call
+//! `handle_timeout` once the time returned by `poll_timeout` has passed.
+//!
+//! Connection IDs (RFC9146) are supported: see `DtlsClientConnection::with_connection_id`,
+//! `DtlsServerConnection::with_connection_id` and `peek_connection_id`.
+//!
+//! Early data and post-handshake key updates are not supported.  Resumption tickets are stored under the server name in
+//! the configured `StoresClientSessions`; since they are not interchangeable
+//! with TLS tickets, don't share a `ClientConfig` between TLS and DTLS
+//! connections if you want resumption to work.
+
+use crate::client::{ClientConfig, ClientSession, ClientSessionImpl};
+use crate::error::{PeerMisbehaved, TlsError};
+use crate::key;
+use crate::key_schedule::hkdf_expand_label;
+use crate::kx::SupportedKxGroup;
+#[cfg(feature = "logging")]
+use crate::log::{debug, trace, warn};
+use crate::msgs::codec::{self, Codec, Reader};
+use crate::msgs::base::PayloadU8;
+use crate::msgs::enums::{AlertDescription, AlertLevel, ContentType, HandshakeType, ProtocolVersion};
+use crate::msgs::enums::{SRTPProtectionProfile, SignatureScheme};
+use crate::msgs::handshake::{ClientExtension, ServerExtension};
+use crate::msgs::message::{Message, MessagePayload};
+use crate::quic;
+use crate::server::{ServerConfig, ServerSession, ServerSessionImpl};
+use crate::server_name::ServerName;
+use crate::session::{HandshakeKind, Protocol, Session, SessionCommon};
+use crate::suites::{BulkAlgorithm, SupportedCipherSuite};
+
+use std::collections::{BTreeMap, VecDeque};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use ring::{aead, hkdf};
+
+/// The default maximum datagram size, in bytes.
+pub const DEFAULT_MTU: usize = 1200;
+
+/// How long to wait for a flight to be acknowledged before first retransmitting it.
+pub const INITIAL_RETRANSMIT_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// The retransmission timeout doubles on each retransmission, up to this limit.
+pub const MAX_RETRANSMIT_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Handshake messages this far ahead of the next expected one are dropped.
+const MAX_BUFFERED_MESSAGES: u16 = 8;
+
+/// Type, length, message_seq, fragment_offset and fragment_length.
+const HANDSHAKE_HEADER_LEN: usize = 12;
+
+/// Content type, version, epoch, sequence number and length.
+const PLAINTEXT_HEADER_LEN: usize = 13;
+
+/// Flags, 16-bit sequence number and length.
+const CIPHERTEXT_HEADER_LEN: usize = 5;
+
+/// The largest handshake message we'll reassemble.
+const MAX_HANDSHAKE_SIZE: usize = 0xffff;
+
+/// The sequence numbers of a record in each epoch.
+const EPOCH_PLAINTEXT: u64 = 0;
+const EPOCH_HANDSHAKE: u64 = 2;
+const EPOCH_APPLICATION: u64 = 3;
+
+/// Methods common to `DtlsClientConnection` and `DtlsServerConnection`.
+pub trait DtlsConnection {
+    /// Process one datagram received from the peer.
+    ///
+    /// Records which can't be authenticated are silently dropped, as
+    /// RFC9147 requires.  Errors are fatal to the connection: any alert
+    /// to be sent is queued for `write_datagram`, and future calls return
+    /// the same error.
+    fn read_datagram(&mut self, datagram: &[u8]) -> Result<(), TlsError>;
+
+    /// Return the next datagram to send to the peer, if any.
+    ///
+    /// `now` is the current time, from which the retransmission timer
+    /// for the handshake flight in the datagram runs.
+    fn write_datagram(&mut self, now: Instant) -> Option<Vec<u8>>;
+
+    /// Returns true if there are datagrams waiting to be sent.
+    fn wants_write(&self) -> bool;
+
+    /// Returns when `handle_timeout` should next be called, if at all.
+    ///
+    /// This is the time by which our outstanding handshake flight should
+    /// have been acknowledged.
+    fn poll_timeout(&self) -> Option<Instant>;
+
+    /// Retransmit unacknowledged handshake messages, if the retransmission
+    /// timer has expired by `now`.
+    ///
+    /// The retransmission timeout doubles each time this happens, up to
+    /// `MAX_RETRANSMIT_TIMEOUT`.
+    fn handle_timeout(&mut self, now: Instant);
+
+    /// Encrypt `data` into a single application data record.
+    ///
+    /// This fails if the handshake is not complete, or if the record would
+    /// not fit in a datagram.
+    fn send_application_data(&mut self, data: &[u8]) -> Result<(), TlsError>;
+
+    /// Return the next application data record received from the peer.
+    fn recv_application_data(&mut self) -> Option<Vec<u8>>;
+
+    /// Queue a close_notify alert to be sent in the next datagram.
+    fn send_close_notify(&mut self);
+
+    /// Returns true if the peer has sent a close_notify alert.
+    fn received_close_notify(&self) -> bool;
+
+    /// Set the largest datagram this connection will produce.
+    ///
+    /// The default is `DEFAULT_MTU`.
+    fn set_mtu(&mut self, mtu: usize);
+
+    /// Returns true if the handshake is still in progress.
+    fn is_handshaking(&self) -> bool;
+
+    /// Returns the connection ID the peer asked us to include in the
+    /// records we send, if connection IDs were negotiated.
+    ///
+    /// This may be empty: the peer is then prepared to send connection IDs
+    /// but doesn't want to receive them.
+    fn get_peer_connection_id(&self) -> Option<&[u8]>;
+
+    /// Retrieves the certificate chain used by the peer to authenticate.
+    ///
+    /// See `Session::get_peer_certificates`.
+    fn get_peer_certificates(&self) -> Option<Vec<key::Certificate>>;
+
+    /// Retrieves the protocol agreed with the peer via ALPN.
+    fn get_alpn_protocol(&self) -> Option<&[u8]>;
+
+    /// Retrieves the ciphersuite agreed with the peer.
+    fn get_negotiated_ciphersuite(&self) -> Option<&'static SupportedCipherSuite>;
+
+    /// Retrieves the key exchange group used with the peer.
+    ///
+    /// See `Session::get_negotiated_kx_group`.
+    fn get_negotiated_kx_group(&self) -> Option<&'static SupportedKxGroup>;
+
+    /// Retrieves the signature scheme the peer authenticated with.
+    ///
+    /// See `Session::get_peer_signature_scheme`.
+    fn get_peer_signature_scheme(&self) -> Option<SignatureScheme>;
+
+    /// Says what kind of handshake this connection did.
+    ///
+    /// See `Session::handshake_kind`.
+    fn handshake_kind(&self) -> Option<HandshakeKind>;
+
+    /// Retrieves the fatal alert this connection sent to the peer, if
+    /// it has sent one.
+    ///
+    /// See `Session::get_sent_fatal_alert`.
+    fn get_sent_fatal_alert(&self) -> Option<AlertDescription>;
+
+    /// Derives key material from the agreed connection secrets.
+    ///
+    /// See `Session::export_keying_material`.
+    fn export_keying_material(
+        &self,
+        output: &mut [u8],
+        label: &[u8],
+        context: Option<&[u8]>,
+    ) -> Result<(), TlsError>;
+
+    /// Retrieves the SRTP protection profile agreed with the peer via
+    /// the use_srtp extension.
+    fn get_srtp_profile(&self) -> Option<SRTPProtectionProfile>;
+
+    /// Derives the SRTP master keys and salts for the agreed protection
+    /// profile, as described in RFC5764 section 4.2.
+    ///
+    /// This fails if the handshake is not complete or SRTP was not
+    /// negotiated.
+    fn export_srtp_keys(&self) -> Result<SrtpKeys, TlsError>;
+}
+
+/// SRTP master keys and salts exported from a DTLS connection.
+///
+/// The client keys protect SRTP packets sent by the client, and the
+/// server keys protect those sent by the server.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SrtpKeys {
+    /// The protection profile these keys are for.
+    pub profile: SRTPProtectionProfile,
+    /// The client's SRTP master key.
+    pub client_key: Vec<u8>,
+    /// The client's SRTP master salt.
+    pub client_salt: Vec<u8>,
+    /// The server's SRTP master key.
+    pub server_key: Vec<u8>,
+    /// The server's SRTP master salt.
+    pub server_salt: Vec<u8>,
+}
+
+impl SrtpKeys {
+    fn export(session: &dyn Session, profile: SRTPProtectionProfile) -> Result<Self, TlsError> {
+        let (key_len, salt_len) = match profile {
+            SRTPProtectionProfile::SRTP_AES128_CM_HMAC_SHA1_80
+            | SRTPProtectionProfile::SRTP_AES128_CM_HMAC_SHA1_32
+            | SRTPProtectionProfile::SRTP_NULL_HMAC_SHA1_80
+            | SRTPProtectionProfile::SRTP_NULL_HMAC_SHA1_32 => (16, 14),
+            SRTPProtectionProfile::SRTP_AEAD_AES_128_GCM => (16, 12),
+            SRTPProtectionProfile::SRTP_AEAD_AES_256_GCM => (32, 12),
+            SRTPProtectionProfile::Unknown(_) => {
+                return Err(TlsError::General("unknown SRTP protection profile".into()))
+            }
+        };
+
+        let mut material = vec![0u8; 2 * (key_len + salt_len)];
+        session.export_keying_material(&mut material, b"EXTRACTOR-dtls_srtp", None)?;
+
+        // client_write_SRTP_master_key | server_write_SRTP_master_key |
+        // client_write_SRTP_master_salt | server_write_SRTP_master_salt
+        let (keys, salts) = material.split_at(2 * key_len);
+        Ok(Self {
+            profile,
+            client_key: keys[..key_len].to_vec(),
+            server_key: keys[key_len..].to_vec(),
+            client_salt: salts[..salt_len].to_vec(),
+            server_salt: salts[salt_len..].to_vec(),
+        })
+    }
+}
+
+/// A DTLS1.3 client connection.
+pub struct DtlsClientConnection {
+    session: ClientSession,
+    state: DtlsState,
+}
+
+impl DtlsClientConnection {
+    /// Make a new DTLS client connection, and queue its ClientHello.
+    ///
+    /// `config` must only enable TLS1.3.
+    pub fn new(config: &Arc<ClientConfig>, name: ServerName) -> Result<Self, TlsError> {
+        Self::new_inner(config, name, None)
+    }
+
+    /// Make a new DTLS client connection which offers to use connection IDs.
+    ///
+    /// `connection_id` is the ID the server should put in records it sends
+    /// us; it may be empty.
+    pub fn with_connection_id(
+        config: &Arc<ClientConfig>,
+        name: ServerName,
+        connection_id: Vec<u8>,
+    ) -> Result<Self, TlsError> {
+        Self::new_inner(config, name, Some(connection_id))
+    }
+
+    fn new_inner(
+        config: &Arc<ClientConfig>,
+        name: ServerName,
+        connection_id: Option<Vec<u8>>,
+    ) -> Result<Self, TlsError> {
+        assert!(
+            !config.supports_version(ProtocolVersion::TLSv1_2),
+            "DTLS requires TLS version >= 1.3"
+        );
+        let exts = match &connection_id {
+            Some(cid) => vec![ClientExtension::ConnectionId(PayloadU8::new(cid.clone()))],
+            None => vec![],
+        };
+        let mut imp = ClientSessionImpl::new(config);
+        imp.common.protocol = Protocol::Dtls;
+        imp.start_handshake(name, exts)?;
+
+        let mut state = DtlsState::new(connection_id);
+        state.flush_handshake(&mut imp);
+        Ok(Self {
+            session: ClientSession { imp },
+            state,
+        })
+    }
+}
+
+/// A DTLS1.3 server connection.
+pub struct DtlsServerConnection {
+    session: ServerSession,
+    state: DtlsState,
+}
+
+impl DtlsServerConnection {
+    /// Make a new DTLS server connection.
+    ///
+    /// `config` must only enable TLS1.3.
+    pub fn new(config: &Arc<ServerConfig>) -> Self {
+        Self::new_inner(config, None)
+    }
+
+    /// Make a new DTLS server connection which uses connection IDs, if
+    /// the client offers to.
+    ///
+    /// `connection_id` is the ID the client should put in records it sends
+    /// us; it may be empty.  Use `peek_connection_id` to find which
+    /// connection an incoming datagram belongs to.
+    pub fn with_connection_id(config: &Arc<ServerConfig>, connection_id: Vec<u8>) -> Self {
+        Self::new_inner(config, Some(connection_id))
+    }
+
+    fn new_inner(config: &Arc<ServerConfig>, connection_id: Option<Vec<u8>>) -> Self {
+        assert!(
+            !config.supports_version(ProtocolVersion::TLSv1_2),
+            "DTLS requires TLS version >= 1.3"
+        );
+        let exts = match &connection_id {
+            Some(cid) => vec![ServerExtension::ConnectionId(PayloadU8::new(cid.clone()))],
+            None => vec![],
+        };
+        let mut imp = ServerSessionImpl::new(config, exts);
+        imp.common.protocol = Protocol::Dtls;
+        Self {
+            session: ServerSession { imp },
+            state: DtlsState::new(connection_id),
+        }
+    }
+
+    /// Retrieves the SNI hostname, if any, used to select the certificate and
+    /// private key.
+    pub fn get_sni_hostname(&self) -> Option<&str> {
+        self.session.get_sni_hostname()
+    }
+}
+
+macro_rules! impl_dtls_connection {
+    ($name:ident) => {
+        impl DtlsConnection for $name {
+            fn read_datagram(&mut self, datagram: &[u8]) -> Result<(), TlsError> {
+                self.state
+                    .read_datagram(&mut self.session.imp, datagram)
+            }
+
+            fn write_datagram(&mut self, now: Instant) -> Option<Vec<u8>> {
+                self.state.write_datagram(now)
+            }
+
+            fn wants_write(&self) -> bool {
+                !self.state.sendable.is_empty()
+            }
+
+            fn poll_timeout(&self) -> Option<Instant> {
+                self.state.retransmit_at
+            }
+
+            fn handle_timeout(&mut self, now: Instant) {
+                self.state.handle_timeout(now);
+            }
+
+            fn send_application_data(&mut self, data: &[u8]) -> Result<(), TlsError> {
+                if self.session.is_handshaking() {
+                    return Err(TlsError::HandshakeNotComplete);
+                }
+                self.state
+                    .send_application_data(data)
+            }
+
+            fn recv_application_data(&mut self) -> Option<Vec<u8>> {
+                self.state.received.pop_front()
+            }
+
+            fn send_close_notify(&mut self) {
+                self.state
+                    .send_alert(AlertLevel::Warning, AlertDescription::CloseNotify);
+                self.session
+                    .imp
+                    .common
+                    .emit(|ev| ev.alert_sent(AlertDescription::CloseNotify, false));
+            }
+
+            fn received_close_notify(&self) -> bool {
+                self.state.peer_closed
+            }
+
+            fn set_mtu(&mut self, mtu: usize) {
+                self.state.mtu = mtu;
+            }
+
+            fn is_handshaking(&self) -> bool {
+                self.session.is_handshaking()
+            }
+
+            fn get_peer_connection_id(&self) -> Option<&[u8]> {
+                self.state.peer_cid.as_deref()
+            }
+
+            fn get_peer_certificates(&self) -> Option<Vec<key::Certificate>> {
+                self.session.get_peer_certificates()
+            }
+
+            fn get_alpn_protocol(&self) -> Option<&[u8]> {
+                self.session.get_alpn_protocol()
+            }
+
+            fn get_negotiated_ciphersuite(&self) -> Option<&'static SupportedCipherSuite> {
+                self.session
+                    .get_negotiated_ciphersuite()
+            }
+
+            fn get_negotiated_kx_group(&self) -> Option<&'static SupportedKxGroup> {
+                self.session
+                    .get_negotiated_kx_group()
+            }
+
+            fn get_peer_signature_scheme(&self) -> Option<SignatureScheme> {
+                self.session
+                    .get_peer_signature_scheme()
+            }
+
+            fn handshake_kind(&self) -> Option<HandshakeKind> {
+                self.session.handshake_kind()
+            }
+
+            fn get_sent_fatal_alert(&self) -> Option<AlertDescription> {
+                self.session.get_sent_fatal_alert()
+            }
+
+            fn export_keying_material(
+                &self,
+                output: &mut [u8],
+                label: &[u8],
+                context: Option<&[u8]>,
+            ) -> Result<(), TlsError> {
+                self.session
+                    .export_keying_material(output, label, context)
+            }
+
+            fn get_srtp_profile(&self) -> Option<SRTPProtectionProfile> {
+                self.session.imp.common.quic.srtp_profile
+            }
+
+            fn export_srtp_keys(&self) -> Result<SrtpKeys, TlsError> {
+                if self.session.is_handshaking() {
+                    return Err(TlsError::HandshakeNotComplete);
+                }
+                match self.get_srtp_profile() {
+                    Some(profile) => SrtpKeys::export(&self.session, profile),
+                    None => Err(TlsError::General("SRTP was not negotiated".into())),
+                }
+            }
+        }
+    };
+}
+
+impl_dtls_connection!(DtlsClientConnection);
+impl_dtls_connection!(DtlsServerConnection);
+
+/// Returns the connection ID in the first record of `datagram`, if it has one.
+///
+/// `cid_len` is the length of the connection IDs this endpoint issues:
+/// they are not self-delimiting.  Servers can use this to route datagrams
+/// to connections independently of the peer's address.
+pub fn peek_connection_id(datagram: &[u8], cid_len: usize) -> Option<&[u8]> {
+    let first = *datagram.first()?;
+    if first & 0xe0 != 0x20 || first & 0x10 == 0 {
+        return None;
+    }
+    datagram.get(1..1 + cid_len)
+}
+
+/// Access to the TLS handshake state machine of either endpoint.
+trait HandshakeDriver {
+    fn common(&mut self) -> &mut SessionCommon;
+    fn process_new_handshake_messages(&mut self) -> Result<(), TlsError>;
+}
+
+impl HandshakeDriver for ClientSessionImpl {
+    fn common(&mut self) -> &mut SessionCommon {
+        &mut self.common
+    }
+
+    fn process_new_handshake_messages(&mut self) -> Result<(), TlsError> {
+        ClientSessionImpl::process_new_handshake_messages(self)
+    }
+}
+
+impl HandshakeDriver for ServerSessionImpl {
+    fn common(&mut self) -> &mut SessionCommon {
+        &mut self.common
+    }
+
+    fn process_new_handshake_messages(&mut self) -> Result<(), TlsError> {
+        ServerSessionImpl::process_new_handshake_messages(self)
+    }
+}
+
+/// Record protection keys for one direction of one epoch.
+struct RecordKeys {
+    key: aead::LessSafeKey,
+    iv: Iv,
+    sn: aead::quic::HeaderProtectionKey,
+}
+
+impl RecordKeys {
+    fn new(suite: &'static SupportedCipherSuite, secret: &hkdf::Prk) -> Self {
+        let sn_alg = match suite.bulk {
+            BulkAlgorithm::AES_128_GCM => &aead::quic::AES_128,
+            BulkAlgorithm::AES_256_GCM => &aead::quic::AES_256,
+            BulkAlgorithm::CHACHA20_POLY1305 => &aead::quic::CHACHA20,
+        };
+        let prefix = Protocol::Dtls.label_prefix();
+
+        Self {
+            key: aead::LessSafeKey::new(hkdf_expand_label(
+                secret,
+                suite.aead_algorithm,
+                prefix,
+                b"key",
+                &[],
+            )),
+            iv: hkdf_expand_label(secret, IvLen, prefix, b"iv", &[]),
+            sn: hkdf_expand_label(secret, sn_alg, prefix, b"sn", &[]),
+        }
+    }
+
+    /// The mask for the sequence number of a record with this `ciphertext`.
+    fn sn_mask(&self, ciphertext: &[u8]) -> Option<[u8; 5]> {
+        if ciphertext.len() < 16 {
+            return None;
+        }
+        self.sn.new_mask(&ciphertext[..16]).ok()
+    }
+}
+
+struct Iv([u8; aead::NONCE_LEN]);
+
+impl Iv {
+    fn nonce_for(&self, seq: u64) -> aead::Nonce {
+        let mut out = [0; aead::NONCE_LEN];
+        out[4..].copy_from_slice(&seq.to_be_bytes());
+        for (out, inp) in out.iter_mut().zip(self.0.iter()) {
+            *out ^= inp;
+        }
+        aead::Nonce::assume_unique_for_key(out)
+    }
+}
+
+impl From<hkdf::Okm<'_, IvLen>> for Iv {
+    fn from(okm: hkdf::Okm<IvLen>) -> Self {
+        let mut iv = [0; aead::NONCE_LEN];
+        okm.fill(&mut iv[..]).unwrap();
+        Iv(iv)
+    }
+}
+
+struct IvLen;
+
+impl hkdf::KeyType for IvLen {
+    fn len(&self) -> usize {
+        aead::NONCE_LEN
+    }
+}
+
+/// Sliding window of received record sequence numbers, for replay protection.
+struct ReplayWindow {
+    /// One more than the highest sequence number received.
+    next: u64,
+    /// Bit `i` is set if `next - 1 - i` has been received.
+    bitmap: u64,
+}
+
+impl ReplayWindow {
+    fn new() -> Self {
+        Self { next: 0, bitmap: 0 }
+    }
+
+    fn is_fresh(&self, seq: u64) -> bool {
+        if seq >= self.next {
+            return true;
+        }
+        let age = self.next - 1 - seq;
+        age < 64 && self.bitmap & (1 << age) == 0
+    }
+
+    fn mark(&mut self, seq: u64) {
+        if seq >= self.next {
+            let shift = seq + 1 - self.next;
+            self.bitmap = if shift >= 64 {
+                0
+            } else {
+                self.bitmap << shift
+            };
+            self.bitmap |= 1;
+            self.next = seq + 1;
+        } else {
+            self.bitmap |= 1 << (self.next - 1 - seq);
+        }
+    }
+
+    /// Recover a full sequence number from its low `bits` bits, choosing the
+    /// candidate closest to the next expected one (RFC9000 appendix A.3).
+    fn expand(&self, truncated: u64, bits: u32) -> u64 {
+        let expected = self.next;
+        let window = 1u64 << bits;
+        let half = window / 2;
+        let candidate = (expected & !(window - 1)) | truncated;
+
+        if candidate + half <= expected {
+            candidate + window
+        } else if candidate > expected + half && candidate >= window {
+            candidate - window
+        } else {
+            candidate
+        }
+    }
+}
+
+struct ReadEpoch {
+    epoch: u64,
+    keys: Option<RecordKeys>,
+    window: ReplayWindow,
+}
+
+struct WriteEpoch {
+    epoch: u64,
+    keys: Option<RecordKeys>,
+    next_seq: u64,
+}
+
+/// A partially-received handshake message.
+struct IncomingMessage {
+    typ: u8,
+    epoch: u64,
+    body: Vec<u8>,
+    received: Vec<bool>,
+    missing: usize,
+}
+
+/// A handshake message fragment in our current flight.
+struct FlightFragment {
+    epoch: u64,
+    /// The fragment, with its DTLS handshake header.
+    fragment: Vec<u8>,
+    /// The sequence numbers of the records this fragment was sent in.
+    records: Vec<u64>,
+    acked: bool,
+}
+
+struct DtlsState {
+    mtu: usize,
+    read_epochs: Vec<ReadEpoch>,
+    write_epochs: Vec<WriteEpoch>,
+    next_send_message_seq: u16,
+    next_receive_message_seq: u16,
+    incoming: BTreeMap<u16, IncomingMessage>,
+    flight: Vec<FlightFragment>,
+    /// Whether the peer has sent a new handshake message since our last flight.
+    peer_progressed: bool,
+    retransmit_timeout: Duration,
+    /// When our flight should have been acknowledged by; `None` until it's sent.
+    retransmit_at: Option<Instant>,
+    /// The connection ID we asked the peer to use, if any.
+    local_cid: Option<Vec<u8>>,
+    /// The connection ID the peer asked us to use, once negotiated.
+    peer_cid: Option<Vec<u8>>,
+    /// Records ready to be packed into datagrams.
+    sendable: VecDeque<Vec<u8>>,
+    received: VecDeque<Vec<u8>>,
+    peer_closed: bool,
+    error: Option<TlsError>,
+}
+
+impl DtlsState {
+    fn new(local_cid: Option<Vec<u8>>) -> Self {
+        Self {
+            mtu: DEFAULT_MTU,
+            read_epochs: vec![ReadEpoch {
+                epoch: EPOCH_PLAINTEXT,
+                keys: None,
+                window: ReplayWindow::new(),
+            }],
+            write_epochs: vec![WriteEpoch {
+                epoch: EPOCH_PLAINTEXT,
+                keys: None,
+                next_seq: 0,
+            }],
+            next_send_message_seq: 0,
+            next_receive_message_seq: 0,
+            incoming: BTreeMap::new(),
+            flight: Vec::new(),
+            peer_progressed: false,
+            retransmit_timeout: INITIAL_RETRANSMIT_TIMEOUT,
+            retransmit_at: None,
+            local_cid,
+            peer_cid: None,
+            sendable: VecDeque::new(),
+            received: VecDeque::new(),
+            peer_closed: false,
+            error: None,
+        }
+    }
+
+    fn read_datagram(
+        &mut self,
+        sess: &mut dyn HandshakeDriver,
+        datagram: &[u8],
+    ) -> Result<(), TlsError> {
+        if let Some(err) = &self.error {
+            return Err(err.clone());
+        }
+
+        match self.read_records(sess, datagram) {
+            Ok(()) => Ok(()),
+            Err(err) => {
+                if let Some(alert) = sess.common().quic.alert.take() {
+                    self.send_alert(AlertLevel::Fatal, alert);
+                }
+                self.error = Some(err.clone());
+                Err(err)
+            }
+        }
+    }
+
+    fn read_records(
+        &mut self,
+        sess: &mut dyn HandshakeDriver,
+        datagram: &[u8],
+    ) -> Result<(), TlsError> {
+        let mut to_ack = Vec::new();
+        let mut peer_retransmitted = false;
+        let mut sent_flight = false;
+        let mut rest = datagram;
+
+        while !rest.is_empty() {
+            let (record, remaining) = match self.open_record(rest) {
+                Some(x) => x,
+                None => break,
+            };
+            rest = remaining;
+            let (epoch, seq, typ, payload) = match record {
+                Some(record) => record,
+                None => continue,
+            };
+
+            match typ {
+                ContentType::Handshake => {
+                    let (retransmit, sent) = self.read_handshake(sess, epoch, &payload)?;
+                    peer_retransmitted |= retransmit;
+                    sent_flight |= sent;
+                    to_ack.push((epoch, seq));
+                }
+                ContentType::Ack => self.read_ack(&payload)?,
+                ContentType::Alert => self.read_alert(sess.common(), &payload)?,
+                ContentType::ApplicationData
+                    if epoch >= EPOCH_APPLICATION && sess.common().traffic =>
+                {
+                    self.received.push_back(payload);
+                }
+                _ => {
+                    sess.common()
+                        .send_fatal_alert(AlertDescription::UnexpectedMessage);
+                    return Err(TlsError::InappropriateMessage {
+                        expect_types: vec![ContentType::Handshake, ContentType::Ack],
+                        got_type: typ,
+                    });
+                }
+            }
+        }
+
+        let unacked = self.flight.iter().any(|f| !f.acked);
+        let common = sess.common();
+        if peer_retransmitted && unacked {
+            // Our flight was probably lost.
+            self.retransmit();
+        } else if !to_ack.is_empty() && (!sent_flight || (!common.is_client && common.traffic)) {
+            // A new flight implicitly acknowledges the peer's, except for
+            // the client's final flight.
+            self.send_ack(&to_ack);
+        }
+
+        Ok(())
+    }
+
+    /// Parse and deprotect the first record in `buf`.
+    ///
+    /// Returns `None` if the rest of the datagram can't be parsed, and
+    /// `Some((None, rest))` if this record should be dropped.
+    #[allow(clippy::type_complexity)]
+    fn open_record<'a>(
+        &mut self,
+        buf: &'a [u8],
+    ) -> Option<(Option<(u64, u64, ContentType, Vec<u8>)>, &'a [u8])> {
+        let first = buf[0];
+        if first & 0xe0 != 0x20 {
+            return self.open_plaintext_record(buf);
+        }
+
+        // 0 0 1 C S L E E
+        let cid_len = if first & 0x10 != 0 {
+            match &self.local_cid {
+                Some(cid) if buf.get(1..1 + cid.len()) == Some(cid) => cid.len(),
+                // Not for this connection.
+                _ => return None,
+            }
+        } else {
+            0
+        };
+        let seq_len = if first & 0x08 != 0 { 2 } else { 1 };
+        let header_len = 1 + cid_len + seq_len + if first & 0x04 != 0 { 2 } else { 0 };
+        if buf.len() < header_len {
+            return None;
+        }
+        let (len, rest) = if first & 0x04 != 0 {
+            let len = u16::from_be_bytes([buf[header_len - 2], buf[header_len - 1]]) as usize;
+            if buf.len() < header_len + len {
+                return None;
+            }
+            (len, &buf[header_len + len..])
+        } else {
+            (buf.len() - header_len, &[][..])
+        };
+
+        let mut header = buf[..header_len].to_vec();
+        let mut ciphertext = buf[header_len..header_len + len].to_vec();
+
+        let low_bits = u64::from(first & 0x03);
+        let epoch = match self
+            .read_epochs
+            .iter_mut()
+            .rev()
+            .find(|e| e.keys.is_some() && e.epoch & 0x03 == low_bits)
+        {
+            Some(epoch) => epoch,
+            None => return Some((None, rest)),
+        };
+        let keys = epoch.keys.as_ref().unwrap();
+
+        let mask = match keys.sn_mask(&ciphertext) {
+            Some(mask) => mask,
+            None => return Some((None, rest)),
+        };
+        let mut truncated = 0u64;
+        for i in 0..seq_len {
+            header[1 + cid_len + i] ^= mask[i];
+            truncated = (truncated << 8) | u64::from(header[1 + cid_len + i]);
+        }
+        let seq = epoch
+            .window
+            .expand(truncated, 8 * seq_len as u32);
+        if !epoch.window.is_fresh(seq) {
+            trace!("Dropping replayed record {}/{}", epoch.epoch, seq);
+            return Some((None, rest));
+        }
+
+        let plaintext = match keys.key.open_in_place(
+            keys.iv.nonce_for(seq),
+            aead::Aad::from(&header),
+            &mut ciphertext,
+        ) {
+            Ok(plaintext) => plaintext,
+            Err(_) => {
+                trace!("Dropping undecryptable record");
+                return Some((None, rest));
+            }
+        };
+        epoch.window.mark(seq);
+
+        // Strip padding; the content type is the last non-zero byte.
+        let typ_pos = match plaintext.iter().rposition(|b| *b != 0) {
+            Some(pos) => pos,
+            None => return Some((None, rest)),
+        };
+        let typ = plaintext[typ_pos];
+        let payload = plaintext[..typ_pos].to_vec();
+
+        Some((
+            Some((epoch.epoch, seq, ContentType::read_bytes(&[typ])?, payload)),
+            rest,
+        ))
+    }
+
+    #[allow(clippy::type_complexity)]
+    fn open_plaintext_record<'a>(
+        &mut self,
+        buf: &'a [u8],
+    ) -> Option<(Option<(u64, u64, ContentType, Vec<u8>)>, &'a [u8])> {
+        if buf.len() < PLAINTEXT_HEADER_LEN {
+            return None;
+        }
+        let mut r = Reader::init(&buf[..PLAINTEXT_HEADER_LEN]);
+        let typ = ContentType::read(&mut r)?;
+        let _version = ProtocolVersion::read(&mut r)?;
+        let epoch = u16::read(&mut r)?;
+        let seq = r
+            .take(6)?
+            .iter()
+            .fold(0u64, |acc, b| (acc << 8) | u64::from(*b));
+        let len = u16::read(&mut r)? as usize;
+        if buf.len() < PLAINTEXT_HEADER_LEN + len {
+            return None;
+        }
+        let payload = buf[PLAINTEXT_HEADER_LEN..PLAINTEXT_HEADER_LEN + len].to_vec();
+        let rest = &buf[PLAINTEXT_HEADER_LEN + len..];
+
+        match typ {
+            ContentType::Handshake | ContentType::Alert | ContentType::Ack if epoch == 0 => {}
+            _ => return Some((None, rest)),
+        }
+
+        let window = &mut self.read_epochs[0].window;
+        if !window.is_fresh(seq) {
+            return Some((None, rest));
+        }
+        window.mark(seq);
+        Some((Some((EPOCH_PLAINTEXT, seq, typ, payload)), rest))
+    }
+
+    /// Process the handshake fragments in one record.
+    ///
+    /// Returns whether the peer retransmitted a message we already had,
+    /// and whether we started a new flight in response.
+    fn read_handshake(
+        &mut self,
+        sess: &mut dyn HandshakeDriver,
+        epoch: u64,
+        payload: &[u8],
+    ) -> Result<(bool, bool), TlsError> {
+        let mut retransmitted = false;
+        let mut sent_flight = false;
+        let mut r = Reader::init(payload);
+
+        while r.any_left() {
+            let (typ, length, message_seq, offset, fragment) =
+                match Self::read_fragment_header(&mut r) {
+                    Some(x) => x,
+                    None => {
+                        sess.common()
+                            .send_fatal_alert(AlertDescription::DecodeError);
+                        return Err(TlsError::CorruptMessagePayload(ContentType::Handshake));
+                    }
+                };
+
+            if message_seq < self.next_receive_message_seq {
+                retransmitted = true;
+                continue;
+            }
+            if message_seq - self.next_receive_message_seq >= MAX_BUFFERED_MESSAGES {
+                continue;
+            }
+            if length > MAX_HANDSHAKE_SIZE {
+                sess.common()
+                    .send_fatal_alert(AlertDescription::DecodeError);
+                return Err(TlsError::PeerSentOversizedRecord);
+            }
+
+            let msg = self
+                .incoming
+                .entry(message_seq)
+                .or_insert_with(|| IncomingMessage {
+                    typ,
+                    epoch,
+                    body: vec![0; length],
+                    received: vec![false; length],
+                    missing: length,
+                });
+            if msg.typ != typ || msg.body.len() != length || msg.epoch != epoch {
+                sess.common()
+                    .send_fatal_alert(AlertDescription::IllegalParameter);
+                return Err(TlsError::PeerMisbehavedError(
+                    PeerMisbehaved::InconsistentHandshakeFragments,
+                ));
+            }
+            for (i, byte) in fragment.iter().enumerate() {
+                if !msg.received[offset + i] {
+                    msg.received[offset + i] = true;
+                    msg.body[offset + i] = *byte;
+                    msg.missing -= 1;
+                }
+            }
+        }
+
+        while let Some(msg) = self
+            .incoming
+            .get(&self.next_receive_message_seq)
+        {
+            if msg.missing != 0 {
+                break;
+            }
+            let msg = self
+                .incoming
+                .remove(&self.next_receive_message_seq)
+                .unwrap();
+            self.next_receive_message_seq += 1;
+
+            // Our flight is implicitly acknowledged by the peer's next one.
+            if !self.peer_progressed {
+                self.clear_flight();
+                self.peer_progressed = true;
+            }
+            self.deliver_message(sess, msg)?;
+            sent_flight |= self.flush_handshake(sess);
+        }
+
+        Ok((retransmitted, sent_flight))
+    }
+
+    #[allow(clippy::type_complexity)]
+    fn read_fragment_header<'r>(
+        r: &'r mut Reader,
+    ) -> Option<(u8, usize, u16, usize, &'r [u8])> {
+        let typ = u8::read(r)?;
+        let length = codec::u24::read(r)?.0 as usize;
+        let message_seq = u16::read(r)?;
+        let offset = codec::u24::read(r)?.0 as usize;
+        let fragment_len = codec::u24::read(r)?.0 as usize;
+        let fragment = r.take(fragment_len)?;
+        if offset + fragment_len > length {
+            return None;
+        }
+        Some((typ, length, message_seq, offset, fragment))
+    }
+
+    /// Give a complete handshake message to the TLS state machine.
+    fn deliver_message(
+        &mut self,
+        sess: &mut dyn HandshakeDriver,
+        msg: IncomingMessage,
+    ) -> Result<(), TlsError> {
+        let typ = HandshakeType::read_bytes(&[msg.typ]).unwrap();
+        let hello = typ == HandshakeType::ClientHello || typ == HandshakeType::ServerHello;
+        if hello != (msg.epoch == EPOCH_PLAINTEXT) {
+            sess.common()
+                .send_fatal_alert(AlertDescription::UnexpectedMessage);
+            warn!("{:?} received in epoch {}", typ, msg.epoch);
+            return Err(TlsError::PeerMisbehavedError(
+                PeerMisbehaved::HandshakeMessageInWrongEpoch,
+            ));
+        }
+
+        // TLS1.3 framing, which is also what goes into the transcript.
+        let mut tls = Vec::with_capacity(4 + msg.body.len());
+        tls.push(msg.typ);
+        codec::u24(msg.body.len() as u32).encode(&mut tls);
+        tls.extend_from_slice(&msg.body);
+
+        let common = sess.common();
+        if common
+            .handshake_joiner
+            .take_message(Message {
+                typ: ContentType::Handshake,
+                version: ProtocolVersion::TLSv1_3,
+                payload: MessagePayload::new_opaque(tls),
+            })
+            .is_none()
+        {
+            common.send_fatal_alert(AlertDescription::DecodeError);
+            return Err(TlsError::CorruptMessagePayload(ContentType::Handshake));
+        }
+        sess.process_new_handshake_messages()
+    }
+
+    fn read_ack(&mut self, payload: &[u8]) -> Result<(), TlsError> {
+        let mut r = Reader::init(payload);
+        let len = u16::read(&mut r).ok_or(TlsError::CorruptMessagePayload(ContentType::Ack))?;
+        let mut sub = r
+            .sub(len as usize)
+            .ok_or(TlsError::CorruptMessagePayload(ContentType::Ack))?;
+
+        while sub.any_left() {
+            let epoch = u64::read(&mut sub).ok_or(TlsError::CorruptMessagePayload(ContentType::Ack))?;
+            let seq = u64::read(&mut sub).ok_or(TlsError::CorruptMessagePayload(ContentType::Ack))?;
+            for fragment in self.flight.iter_mut() {
+                if fragment.epoch == epoch && fragment.records.contains(&seq) {
+                    fragment.acked = true;
+                }
+            }
+        }
+
+        if self.flight.iter().all(|f| f.acked) {
+            self.clear_flight();
+        }
+        Ok(())
+    }
+
+    fn read_alert(&mut self, common: &SessionCommon, payload: &[u8]) -> Result<(), TlsError> {
+        if payload.len() != 2 {
+            return Err(TlsError::CorruptMessagePayload(ContentType::Alert));
+        }
+        let level = AlertLevel::read_bytes(&payload[..1]).unwrap();
+        let desc = AlertDescription::read_bytes(&payload[1..]).unwrap();
+        common.emit(|ev| ev.alert_received(desc, level == AlertLevel::Fatal));
+
+        if desc == AlertDescription::CloseNotify {
+            debug!("Received close_notify");
+            self.peer_closed = true;
+            return Ok(());
+        }
+        if level == AlertLevel::Warning {
+            debug!("Ignoring warning alert {:?}", desc);
+            return Ok(());
+        }
+        Err(TlsError::AlertReceived {
+            description: desc,
+            fatal: level == AlertLevel::Fatal,
+        })
+    }
+
+    /// Take new handshake messages and keys from the TLS state machine,
+    /// and add the messages to our flight.  Returns whether there were any.
+    fn flush_handshake(&mut self, sess: &mut dyn HandshakeDriver) -> bool {
+        let common = sess.common();
+        let suite = common.get_suite();
+
+        if self.peer_cid.is_none() {
+            self.peer_cid = common.quic.connection_id.clone();
+        }
+
+        if let Some(secrets) = common.quic.hs_secrets.take() {
+            self.install_keys(suite.unwrap(), common.is_client, EPOCH_HANDSHAKE, &secrets);
+        }
+        if let Some(secrets) = common.quic.traffic_secrets.as_ref() {
+            if !common.quic.returned_traffic_keys {
+                common.quic.returned_traffic_keys = true;
+                let secrets = secrets.clone();
+                self.install_keys(suite.unwrap(), common.is_client, EPOCH_APPLICATION, &secrets);
+            }
+        }
+
+        let sent_flight = !common.quic.hs_queue.is_empty();
+        if sent_flight {
+            self.peer_progressed = false;
+            self.retransmit_timeout = INITIAL_RETRANSMIT_TIMEOUT;
+        }
+
+        while let Some((must_encrypt, msg)) = common.quic.hs_queue.pop_front() {
+            let epoch = if !must_encrypt {
+                EPOCH_PLAINTEXT
+            } else if msg[0] == HandshakeType::NewSessionTicket.get_u8()
+                || msg[0] == HandshakeType::KeyUpdate.get_u8()
+            {
+                EPOCH_APPLICATION
+            } else {
+                EPOCH_HANDSHAKE
+            };
+            self.queue_handshake_message(epoch, &msg);
+        }
+
+        if let Some(alert) = common.quic.alert.take() {
+            self.send_alert(AlertLevel::Fatal, alert);
+        }
+        sent_flight
+    }
+
+    fn install_keys(
+        &mut self,
+        suite: &'static SupportedCipherSuite,
+        is_client: bool,
+        epoch: u64,
+        secrets: &quic::Secrets,
+    ) {
+        let (local, remote) = if is_client {
+            (&secrets.client, &secrets.server)
+        } else {
+            (&secrets.server, &secrets.client)
+        };
+        self.write_epochs.push(WriteEpoch {
+            epoch,
+            keys: Some(RecordKeys::new(suite, local)),
+            next_seq: 0,
+        });
+        self.read_epochs.push(ReadEpoch {
+            epoch,
+            keys: Some(RecordKeys::new(suite, remote)),
+            window: ReplayWindow::new(),
+        });
+    }
+
+    /// Fragment a TLS-framed handshake message into our current flight.
+    fn queue_handshake_message(&mut self, epoch: u64, msg: &[u8]) {
+        let message_seq = self.next_send_message_seq;
+        self.next_send_message_seq += 1;
+
+        let typ = msg[0];
+        let body = &msg[4..];
+        let max_fragment = self
+            .max_record_payload(epoch)
+            .saturating_sub(HANDSHAKE_HEADER_LEN)
+            .max(1);
+
+        let mut offset = 0;
+        loop {
+            let len = (body.len() - offset).min(max_fragment);
+            let mut fragment = Vec::with_capacity(HANDSHAKE_HEADER_LEN + len);
+            fragment.push(typ);
+            codec::u24(body.len() as u32).encode(&mut fragment);
+            message_seq.encode(&mut fragment);
+            codec::u24(offset as u32).encode(&mut fragment);
+            codec::u24(len as u32).encode(&mut fragment);
+            fragment.extend_from_slice(&body[offset..offset + len]);
+
+            let seq = self.seal_record(epoch, ContentType::Handshake, &fragment);
+            self.flight.push(FlightFragment {
+                epoch,
+                fragment,
+                records: vec![seq],
+                acked: false,
+            });
+
+            offset += len;
+            if offset >= body.len() {
+                break;
+            }
+        }
+    }
+
+    fn clear_flight(&mut self) {
+        self.flight.clear();
+        self.retransmit_at = None;
+    }
+
+    fn handle_timeout(&mut self, now: Instant) {
+        match self.retransmit_at {
+            Some(at) if now >= at => {
+                self.retransmit_timeout = (self.retransmit_timeout * 2).min(MAX_RETRANSMIT_TIMEOUT);
+                self.retransmit();
+            }
+            _ => {}
+        }
+    }
+
+    /// Send unacknowledged fragments of our current flight again.
+    ///
+    /// The retransmission timer restarts when they're written.
+    fn retransmit(&mut self) {
+        self.retransmit_at = None;
+
+        let unacked = self
+            .flight
+            .iter()
+            .enumerate()
+            .filter(|(_, f)| !f.acked)
+            .map(|(i, _)| i)
+            .collect::<Vec<_>>();
+        if !unacked.is_empty() {
+            debug!("Retransmitting {} handshake fragments", unacked.len());
+        }
+
+        for i in unacked {
+            let epoch = self.flight[i].epoch;
+            let fragment = self.flight[i].fragment.clone();
+            let seq = self.seal_record(epoch, ContentType::Handshake, &fragment);
+            self.flight[i].records.push(seq);
+        }
+    }
+
+    fn send_ack(&mut self, records: &[(u64, u64)]) {
+        let mut payload = Vec::with_capacity(2 + 16 * records.len());
+        ((records.len() * 16) as u16).encode(&mut payload);
+        for (epoch, seq) in records {
+            epoch.encode(&mut payload);
+            seq.encode(&mut payload);
+        }
+        let epoch = self.current_write_epoch();
+        self.seal_record(epoch, ContentType::Ack, &payload);
+    }
+
+    fn send_alert(&mut self, level: AlertLevel, desc: AlertDescription) {
+        let epoch = self.current_write_epoch();
+        self.seal_record(epoch, ContentType::Alert, &[level.get_u8(), desc.get_u8()]);
+    }
+
+    fn send_application_data(&mut self, data: &[u8]) -> Result<(), TlsError> {
+        if data.len() > self.max_record_payload(EPOCH_APPLICATION) {
+            return Err(TlsError::General(
+                "application data does not fit in a datagram".into(),
+            ));
+        }
+        self.seal_record(EPOCH_APPLICATION, ContentType::ApplicationData, data);
+        Ok(())
+    }
+
+    fn current_write_epoch(&self) -> u64 {
+        self.write_epochs.last().unwrap().epoch
+    }
+
+    fn max_record_payload(&self, epoch: u64) -> usize {
+        let overhead = if epoch == EPOCH_PLAINTEXT {
+            PLAINTEXT_HEADER_LEN
+        } else {
+            // Inner content type and AEAD tag.
+            let cid_len = self.peer_cid.as_ref().map_or(0, Vec::len);
+            CIPHERTEXT_HEADER_LEN + cid_len + 1 + 16
+        };
+        self.mtu.saturating_sub(overhead)
+    }
+
+    /// Protect a record and queue it for sending, returning its sequence number.
+    fn seal_record(&mut self, epoch: u64, typ: ContentType, payload: &[u8]) -> u64 {
+        let write = self
+            .write_epochs
+            .iter_mut()
+            .find(|e| e.epoch == epoch)
+            .expect("no keys for epoch");
+        let seq = write.next_seq;
+        write.next_seq += 1;
+
+        let mut record = Vec::with_capacity(payload.len() + PLAINTEXT_HEADER_LEN + 17);
+        let keys = match &write.keys {
+            Some(keys) => keys,
+            None => {
+                typ.encode(&mut record);
+                ProtocolVersion::DTLSv1_2.encode(&mut record);
+                (epoch as u16).encode(&mut record);
+                record.extend_from_slice(&seq.to_be_bytes()[2..]);
+                (payload.len() as u16).encode(&mut record);
+                record.extend_from_slice(payload);
+                self.sendable.push_back(record);
+                return seq;
+            }
+        };
+
+        let mut ciphertext = Vec::with_capacity(payload.len() + 1 + 16);
+        ciphertext.extend_from_slice(payload);
+        ciphertext.push(typ.get_u8());
+        let len = ciphertext.len() + keys.key.algorithm().tag_len();
+
+        // 0 0 1 C S=1 L=1 E E
+        let cid = self
+            .peer_cid
+            .as_deref()
+            .unwrap_or(&[]);
+        let cid_flag = if cid.is_empty() { 0 } else { 0x10 };
+        record.push(0x2c | cid_flag | (epoch & 0x03) as u8);
+        record.extend_from_slice(cid);
+        (seq as u16).encode(&mut record);
+        (len as u16).encode(&mut record);
+
+        keys.key
+            .seal_in_place_append_tag(
+                keys.iv.nonce_for(seq),
+                aead::Aad::from(&record),
+                &mut ciphertext,
+            )
+            .unwrap();
+        let mask = keys.sn_mask(&ciphertext).unwrap();
+        record[1 + cid.len()] ^= mask[0];
+        record[2 + cid.len()] ^= mask[1];
+        record.extend_from_slice(&ciphertext);
+
+        self.sendable.push_back(record);
+        seq
+    }
+
+    /// Pack as many queued records as fit into one datagram.
+    fn write_datagram(&mut self, now: Instant) -> Option<Vec<u8>> {
+        let mut datagram = self.sendable.pop_front()?;
+        while let Some(next) = self.sendable.front() {
+            if datagram.len() + next.len() > self.mtu {
+                break;
+            }
+            datagram.extend_from_slice(next);
+            self.sendable.pop_front();
+        }
+
+        if self.retransmit_at.is_none() && self.flight.iter().any(|f| !f.acked) {
+            self.retransmit_at = Some(now + self.retransmit_timeout);
+        }
+        Some(datagram)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::ReplayWindow;
+
+    #[test]
+    fn replay_window() {
+        let mut window = ReplayWindow::new();
+        assert!(window.is_fresh(0));
+        window.mark(0);
+        assert!(!window.is_fresh(0));
+        window.mark(5);
+        assert!(window.is_fresh(3));
+        assert!(!window.is_fresh(5));
+        window.mark(3);
+        assert!(!window.is_fresh(3));
+        window.mark(100);
+        assert!(!window.is_fresh(5));
+        assert!(window.is_fresh(99));
+        assert!(!window.is_fresh(36));
+    }
+
+    #[test]
+    fn sequence_number_expansion() {
+        let mut window = ReplayWindow::new();
+        assert_eq!(window.expand(0x0003, 16), 3);
+        window.mark(0xfffe);
+        assert_eq!(window.expand(0x0001, 16), 0x1_0001);
+        assert_eq!(window.expand(0xfff0, 16), 0xfff0);
+        window.mark(0x1_0005);
+        assert_eq!(window.expand(0xfff0, 16), 0xfff0);
+        assert_eq!(window.expand(0x0010, 16), 0x1_0010);
+    }
+}
@@ -5,30 +5,241 @@ use std::error::Error;
 use std::fmt;
 use webpki;
 
-/// The reason WebPKI operation was performed, used in [`TLSError`].
+/// Why a certificate was rejected, used in [`TlsError`].
 #[derive(Debug, PartialEq, Clone)]
 #[non_exhaustive]
-pub enum WebPKIOp {
-    /// Validate server certificate.
-    ValidateServerCert,
-    /// Validate client certificate.
-    ValidateClientCert,
-    /// Validate certificate for DNS name
-    ValidateForDNSName,
-    /// Parse end entity certificate.
-    ParseEndEntity,
-    /// Verify message signature using the certificate.
-    VerifySignature,
+pub enum CertificateError {
+    /// The certificate couldn't be parsed.
+    BadEncoding,
+    /// The certificate's notAfter time is in the past.
+    Expired,
+    /// The certificate's notBefore time is in the future.
+    NotYetValid,
+    /// The chain doesn't lead to any of the trusted roots.
+    UnknownIssuer,
+    /// The certificate isn't valid for the name we expected.
+    NameMismatch {
+        /// The DNS name or IP address we were checking for
+        expected: String,
+        /// The DNS names and IP addresses the certificate's subject
+        /// alternative names contain
+        found: Vec<String>,
+    },
+    /// The configured `RevocationChecker` says a certificate in the
+    /// chain has been revoked.
+    Revoked,
+    /// A signature by the certificate's key, or on the certificate,
+    /// is invalid.
+    BadSignature,
+    /// A signature used an algorithm we don't support, or one which
+    /// doesn't suit the key.
+    UnsupportedSignatureAlgorithm,
+    /// The certificate isn't allowed to be used for this purpose, for
+    /// example a server certificate was presented by a client.
+    InvalidPurpose,
+    /// Some other problem which webpki found with the certificate.
+    Other(webpki::Error),
+}
+
+impl From<webpki::Error> for CertificateError {
+    fn from(err: webpki::Error) -> Self {
+        match err {
+            webpki::Error::BadDER | webpki::Error::BadDERTime => CertificateError::BadEncoding,
+            webpki::Error::CertExpired => CertificateError::Expired,
+            webpki::Error::CertNotValidYet => CertificateError::NotYetValid,
+            webpki::Error::UnknownIssuer => CertificateError::UnknownIssuer,
+            webpki::Error::InvalidSignatureForPublicKey => CertificateError::BadSignature,
+            webpki::Error::UnsupportedSignatureAlgorithm
+            | webpki::Error::UnsupportedSignatureAlgorithmForPublicKey => {
+                CertificateError::UnsupportedSignatureAlgorithm
+            }
+            webpki::Error::RequiredEKUNotFound => CertificateError::InvalidPurpose,
+            _ => CertificateError::Other(err),
+        }
+    }
 }
 
-impl fmt::Display for WebPKIOp {
+impl fmt::Display for CertificateError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
-            WebPKIOp::ValidateServerCert => write!(f, "validate server certificate"),
-            WebPKIOp::ValidateClientCert => write!(f, "validate client certificate"),
-            WebPKIOp::ValidateForDNSName => write!(f, "validate certificate for DNS name"),
-            WebPKIOp::ParseEndEntity => write!(f, "parse end entity certificate"),
-            WebPKIOp::VerifySignature => write!(f, "verify signature"),
+            CertificateError::BadEncoding => write!(f, "bad encoding"),
+            CertificateError::Expired => write!(f, "certificate expired"),
+            CertificateError::NotYetValid => write!(f, "certificate not valid yet"),
+            CertificateError::UnknownIssuer => write!(f, "unknown certificate issuer"),
+            CertificateError::NameMismatch { expected, found } => write!(
+                f,
+                "certificate not valid for name {:?}; certificate is only valid for {:?}",
+                expected, found
+            ),
+            CertificateError::Revoked => write!(f, "certificate revoked"),
+            CertificateError::BadSignature => write!(f, "bad signature"),
+            CertificateError::UnsupportedSignatureAlgorithm => write!(f, "unsupported signature algorithm"),
+            CertificateError::InvalidPurpose => write!(f, "certificate not valid for this purpose"),
+            CertificateError::Other(err) => write!(f, "{:?}", err),
+        }
+    }
+}
+
+/// The protocol violation which made us give up on a peer, used in
+/// [`TlsError`].
+///
+/// These are for diagnosing interoperability problems and writing bug
+/// reports; the names are intended to be self-describing.  We may add
+/// variants, or stop using some, in any release.
+#[allow(missing_docs)]
+#[derive(Debug, PartialEq, Clone)]
+#[non_exhaustive]
+pub enum PeerMisbehaved {
+    AttemptedDowngradeToTls12WhenTls13IsSupported,
+    BadCertChainExtensions,
+    DisallowedEncryptedExtension,
+    DuplicateClientHelloExtensions,
+    DuplicateEncryptedExtensions,
+    DuplicateHelloRetryRequestExtensions,
+    DuplicateServerHelloExtensions,
+    DuplicateServerNameTypes,
+    EarlyDataExtensionWithoutResumption,
+    EarlyDataOfferedWithVariedCipherSuite,
+    HandshakeMessageInWrongEpoch,
+    IllegalHelloRetryRequestWithEmptyCookie,
+    IllegalHelloRetryRequestWithNoChanges,
+    IllegalHelloRetryRequestWithOfferedGroup,
+    IllegalHelloRetryRequestWithUnofferedCipherSuite,
+    IllegalHelloRetryRequestWithUnsupportedGroup,
+    IllegalHelloRetryRequestWithUnsupportedVersion,
+    IllegalMiddleboxChangeCipherSpec,
+    IllegalTlsInnerPlaintext,
+    InconsistentHandshakeFragments,
+    IncorrectBinder,
+    InvalidKeyShare,
+    InvalidMaxEarlyDataSize,
+    InvalidSctList,
+    KeyEpochWithPendingFragment,
+    KeyUpdateReceivedInQuicOrDtls,
+    MissingBinderInPskExtension,
+    MissingKeyShare,
+    OfferedDuplicateKeyShares,
+    OfferedEarlyDataWithOldProtocolVersion,
+    OfferedEmptyApplicationProtocol,
+    OfferedIncorrectCompressions,
+    PskExtensionMustBeLast,
+    PskExtensionWithMismatchedIdsAndBinders,
+    RefusedToFollowHelloRetryRequest,
+    ResumptionAttemptedWithVariedEms,
+    ResumptionOfferedWithIncompatibleCipherSuite,
+    ResumptionOfferedWithVariedCipherSuite,
+    ResumptionOfferedWithVariedEms,
+    SelectedCipherSuiteUnusableForVersion,
+    SelectedDifferentCipherSuiteAfterRetry,
+    SelectedInvalidPsk,
+    SelectedTls12UsingTls13VersionExtension,
+    SelectedUnofferedApplicationProtocol,
+    SelectedUnofferedCipherSuite,
+    SelectedUnofferedCompression,
+    SelectedUnofferedProtocolVersion,
+    SelectedUnofferedPsk,
+    SelectedUnofferedSrtpMki,
+    SelectedUnofferedSrtpProfile,
+    ServerHelloMustOfferUncompressedEcPoints,
+    ServerNameMustContainOneHostName,
+    SignedHandshakeWithUnadvertisedSigScheme,
+    SignedHandshakeWithUnofferedSigScheme,
+    SignedKxWithWrongAlgorithm,
+    UnexpectedCleartextExtension,
+    UnsolicitedCertExtension,
+    UnsolicitedEncryptedExtension,
+    UnsolicitedSctList,
+    UnsolicitedServerHelloExtension,
+    WrongGroupForKeyShare,
+}
+
+/// The reason a stapled OCSP response was rejected, used in [`TlsError`].
+#[derive(Debug, PartialEq, Clone)]
+#[non_exhaustive]
+pub enum OcspError {
+    /// The response couldn't be parsed.
+    Malformed,
+    /// The responder didn't return a successful response.
+    UnsuccessfulResponse,
+    /// The response wasn't a BasicOCSPResponse.
+    UnsupportedResponseType,
+    /// The response was signed using an algorithm we don't support.
+    UnsupportedSignatureAlgorithm,
+    /// The response signature was invalid.
+    BadSignature,
+    /// We couldn't find the issuer of the certificate being checked.
+    UnknownIssuer,
+    /// The response wasn't signed by the issuer or an authorized responder.
+    UnknownResponder,
+    /// The response didn't contain a status for the certificate being checked.
+    CertNotInResponse,
+    /// The response's thisUpdate is in the future.
+    NotYetValid,
+    /// The response's nextUpdate is in the past.
+    Expired,
+    /// The certificate has been revoked.
+    CertRevoked,
+    /// The responder doesn't know about the certificate.
+    CertStatusUnknown,
+}
+
+impl fmt::Display for OcspError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            OcspError::Malformed => write!(f, "malformed response"),
+            OcspError::UnsuccessfulResponse => write!(f, "unsuccessful response"),
+            OcspError::UnsupportedResponseType => write!(f, "unsupported response type"),
+            OcspError::UnsupportedSignatureAlgorithm => write!(f, "unsupported signature algorithm"),
+            OcspError::BadSignature => write!(f, "bad signature"),
+            OcspError::UnknownIssuer => write!(f, "unknown certificate issuer"),
+            OcspError::UnknownResponder => write!(f, "unknown responder"),
+            OcspError::CertNotInResponse => write!(f, "certificate not covered by response"),
+            OcspError::NotYetValid => write!(f, "response not yet valid"),
+            OcspError::Expired => write!(f, "response expired"),
+            OcspError::CertRevoked => write!(f, "certificate revoked"),
+            OcspError::CertStatusUnknown => write!(f, "certificate status unknown"),
+        }
+    }
+}
+
+/// The reason a certificate failed a revocation check against the
+/// configured CRLs, used in [`TlsError`].
+#[derive(Debug, PartialEq, Clone)]
+#[non_exhaustive]
+pub enum CrlError {
+    /// The CRL couldn't be parsed.
+    Malformed,
+    /// The CRL contains a critical extension we don't understand, for
+    /// example it is a delta CRL or only covers some certificates.
+    UnsupportedCriticalExtension,
+    /// The CRL was signed using an algorithm we don't support.
+    UnsupportedSignatureAlgorithm,
+    /// The CRL signature was invalid.
+    BadSignature,
+    /// We couldn't find the issuer of the certificate being checked.
+    UnknownIssuer,
+    /// The CRL's nextUpdate is in the past.
+    Expired,
+    /// The CRL's thisUpdate is in the future.
+    NotYetValid,
+    /// The certificate has been revoked.
+    CertRevoked,
+    /// There is no CRL covering the certificate.
+    UnknownStatus,
+}
+
+impl fmt::Display for CrlError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CrlError::Malformed => write!(f, "malformed CRL"),
+            CrlError::UnsupportedCriticalExtension => write!(f, "unsupported critical extension"),
+            CrlError::UnsupportedSignatureAlgorithm => write!(f, "unsupported signature algorithm"),
+            CrlError::BadSignature => write!(f, "bad signature"),
+            CrlError::UnknownIssuer => write!(f, "unknown certificate issuer"),
+            CrlError::Expired => write!(f, "CRL expired"),
+            CrlError::NotYetValid => write!(f, "CRL not yet valid"),
+            CrlError::CertRevoked => write!(f, "certificate revoked"),
+            CrlError::UnknownStatus => write!(f, "no CRL covers certificate"),
         }
     }
 }
@@ -75,18 +286,35 @@ pub enum TlsError {
     PeerIncompatibleError(String),
 
     /// The peer deviated from the standard TLS protocol.
-    /// The parameter gives a hint where.
-    PeerMisbehavedError(String),
+    /// The parameter says how.
+    PeerMisbehavedError(PeerMisbehaved),
 
-    /// We received a fatal alert.  This means the peer is unhappy.
-    AlertReceived(AlertDescription),
+    /// We received an alert which ended the session.  This means the
+    /// peer is unhappy.
+    ///
+    /// The alert is usually fatal, but a warning alert also ends a
+    /// TLS1.3 session (except `user_canceled`).
+    AlertReceived {
+        /// What the peer said was wrong
+        description: AlertDescription,
+        /// Whether the peer sent the alert with the fatal level
+        fatal: bool,
+    },
 
     /// The presented certificate chain is invalid.
-    WebPKIError(webpki::Error, WebPKIOp),
+    InvalidCertificate(CertificateError),
 
     /// The presented SCT(s) were invalid.
     InvalidSCT(sct::Error),
 
+    /// The stapled OCSP response was invalid, or said the
+    /// certificate is revoked.
+    InvalidOCSPResponse(OcspError),
+
+    /// A certificate in the chain failed a check against the
+    /// configured certificate revocation lists.
+    CRLError(CrlError),
+
     /// A catch-all error for unlikely errors.
     General(String),
 
@@ -140,9 +368,16 @@ impl fmt::Display for TlsError {
                 write!(f, "received corrupt message of type {:?}", typ)
             }
             TlsError::PeerIncompatibleError(ref why) => write!(f, "peer is incompatible: {}", why),
-            TlsError::PeerMisbehavedError(ref why) => write!(f, "peer misbehaved: {}", why),
-            TlsError::AlertReceived(ref alert) => write!(f, "received fatal alert: {:?}", alert),
-            TlsError::WebPKIError(ref err, ref reason) => write!(f, "certificate error in operation: {}: {:?}", reason, err),
+            TlsError::PeerMisbehavedError(ref why) => write!(f, "peer misbehaved: {:?}", why),
+            TlsError::AlertReceived {
+                ref description,
+                fatal: true,
+            } => write!(f, "received fatal alert: {:?}", description),
+            TlsError::AlertReceived {
+                ref description,
+                fatal: false,
+            } => write!(f, "received warning alert: {:?}", description),
+            TlsError::InvalidCertificate(ref err) => write!(f, "invalid peer certificate: {}", err),
             TlsError::CorruptMessage => write!(f, "received corrupt message"),
             TlsError::NoCertificatesPresented => write!(f, "peer sent no certificates"),
             TlsError::DecryptError => write!(f, "cannot decrypt peer's message"),
@@ -150,6 +385,8 @@ impl fmt::Display for TlsError {
             TlsError::HandshakeNotComplete => write!(f, "handshake not complete"),
             TlsError::NoApplicationProtocol => write!(f, "peer doesn't support any known protocol"),
             TlsError::InvalidSCT(ref err) => write!(f, "invalid certificate timestamp: {:?}", err),
+            TlsError::InvalidOCSPResponse(ref err) => write!(f, "invalid OCSP response: {}", err),
+            TlsError::CRLError(ref err) => write!(f, "certificate revocation check failed: {}", err),
             TlsError::FailedToGetCurrentTime => write!(f, "failed to get current time"),
             TlsError::FailedToGetRandomBytes => write!(f, "failed to get random bytes"),
             TlsError::General(ref err) => write!(f, "unexpected error: {}", err), // (please file a bug)
@@ -170,7 +407,7 @@ mod tests {
     #[test]
     fn smoke() {
         use super::TlsError;
-        use super::WebPKIOp;
+        use super::{CertificateError, CrlError, OcspError, PeerMisbehaved};
         use crate::msgs::enums::{AlertDescription, ContentType, HandshakeType};
         use sct;
         use webpki;
@@ -189,10 +426,25 @@ mod tests {
             TlsError::NoCertificatesPresented,
             TlsError::DecryptError,
             TlsError::PeerIncompatibleError("no tls1.2".to_string()),
-            TlsError::PeerMisbehavedError("inconsistent something".to_string()),
-            TlsError::AlertReceived(AlertDescription::ExportRestriction),
-            TlsError::WebPKIError(webpki::Error::ExtensionValueInvalid, WebPKIOp::ParseEndEntity),
+            TlsError::PeerMisbehavedError(PeerMisbehaved::InconsistentHandshakeFragments),
+            TlsError::AlertReceived {
+                description: AlertDescription::ExportRestriction,
+                fatal: true,
+            },
+            TlsError::AlertReceived {
+                description: AlertDescription::UnrecognisedName,
+                fatal: false,
+            },
+            TlsError::InvalidCertificate(CertificateError::Expired),
+            TlsError::InvalidCertificate(CertificateError::NameMismatch {
+                expected: "example.com".to_string(),
+                found: vec!["example.org".to_string(), "127.0.0.1".to_string()],
+            }),
+            TlsError::InvalidCertificate(CertificateError::Other(webpki::Error::ExtensionValueInvalid)),
             TlsError::InvalidSCT(sct::Error::MalformedSCT),
+            TlsError::InvalidOCSPResponse(OcspError::CertRevoked),
+            TlsError::CRLError(CrlError::CertRevoked),
+            TlsError::InvalidCertificate(CertificateError::Revoked),
             TlsError::General("undocumented error".to_string()),
             TlsError::FailedToGetCurrentTime,
             TlsError::FailedToGetRandomBytes,
@@ -0,0 +1,56 @@
+use crate::msgs::enums::{AlertDescription, CipherSuite, ProtocolVersion};
+use crate::session::HandshakeKind;
+
+/// Receives notice of significant events in a session's life.
+///
+/// Set `ClientConfig::connection_events` or
+/// `ServerConfig::connection_events` to an implementation of this
+/// trait to collect structured telemetry -- counts of resumptions,
+/// alerts and so on -- without parsing debug logs.  Every method
+/// has an empty default, so implement only the ones you need.
+///
+/// The callbacks are made synchronously from inside rustls, while
+/// it is processing the session's data, so they should be quick.
+/// They don't say which session they concern; use a separate
+/// config per source if you need to tell them apart.
+pub trait ConnectionEvents: Send + Sync {
+    /// A handshake has begun: a client has sent its first
+    /// ClientHello, or a server has received one.
+    fn handshake_started(&self) {}
+
+    /// The protocol `version` and cipher `suite` for the session
+    /// have been chosen.
+    fn parameters_chosen(&self, _version: ProtocolVersion, _suite: CipherSuite) {}
+
+    /// The handshake has completed, as a handshake of `kind`.
+    fn handshake_finished(&self, _kind: HandshakeKind) {}
+
+    /// The handshake resumed a previous session.
+    fn resumption_used(&self) {}
+
+    /// The client offered to resume a previous session, but the
+    /// handshake was a full one.
+    ///
+    /// Clients often send a random TLS1.2 session ID, which a server
+    /// can't tell from one it has forgotten.  So servers only report
+    /// this for tickets and TLS1.3 PSKs they couldn't use.
+    fn resumption_declined(&self) {}
+
+    /// A server has issued the client a ticket, valid for
+    /// `lifetime_secs` seconds.
+    fn ticket_issued(&self, _lifetime_secs: u32) {}
+
+    /// We sent the peer an alert.
+    fn alert_sent(&self, _description: AlertDescription, _fatal: bool) {}
+
+    /// The peer sent us an alert.
+    fn alert_received(&self, _description: AlertDescription, _fatal: bool) {}
+
+    /// We sent a TLS1.3 KeyUpdate, and now use new keys for
+    /// sending.
+    fn key_update_sent(&self) {}
+
+    /// The peer sent a TLS1.3 KeyUpdate, and now uses new keys
+    /// for sending to us.
+    fn key_update_received(&self) {}
+}
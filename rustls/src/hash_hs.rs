@@ -1,6 +1,7 @@
 #[cfg(feature = "logging")]
 use crate::log::warn;
 use crate::msgs::codec::Codec;
+#[cfg(feature = "tls13")]
 use crate::msgs::handshake::HandshakeMessagePayload;
 use crate::msgs::message::{Message, MessagePayload};
 use ring::digest;
@@ -100,6 +101,7 @@ impl HandshakeHash {
 
     /// Get the hash value if we were to hash `extra` too,
     /// using hash function `hash`.
+    #[cfg(feature = "tls13")]
     pub fn get_hash_given(&self, hash: &'static digest::Algorithm, extra: &[u8]) -> digest::Digest {
         let mut ctx = match &self.ctx {
             None => {
@@ -119,6 +121,7 @@ impl HandshakeHash {
     /// Take the current hash value, and encapsulate it in a
     /// 'handshake_hash' handshake message.  Start this hash
     /// again, with that message at the front.
+    #[cfg(feature = "tls13")]
     pub fn rollup_for_hrr(&mut self) {
         let ctx = self.ctx.as_mut().unwrap();
 
@@ -143,6 +146,7 @@ impl HandshakeHash {
     /// Takes this object's buffer containing all handshake messages
     /// so far.  This method only works once; it resets the buffer
     /// to empty.
+    #[cfg(feature = "tls12")]
     pub fn take_handshake_buf(&mut self) -> Vec<u8> {
         debug_assert!(self.client_auth_enabled);
         mem::replace(&mut self.buffer, Vec::new())
@@ -186,8 +190,11 @@ mod test {
         assert_eq!(h[1], 0x6a);
         assert_eq!(h[2], 0x18);
         assert_eq!(h[3], 0x5c);
-        let buf = hh.take_handshake_buf();
-        assert_eq!(b"helloworld".to_vec(), buf);
+        #[cfg(feature = "tls12")]
+        {
+            let buf = hh.take_handshake_buf();
+            assert_eq!(b"helloworld".to_vec(), buf);
+        }
     }
 
     #[test]
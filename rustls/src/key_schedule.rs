@@ -1,7 +1,11 @@
 use crate::cipher::{Iv, IvLen};
 use crate::error::TlsError;
 use crate::msgs::base::PayloadU8;
+use crate::session::Protocol;
+#[cfg(feature = "secret_extraction")]
+use crate::suites::{ConnectionTrafficSecrets, SupportedCipherSuite};
 use crate::KeyLog;
+use std::fmt;
 /// Key schedule maintenance for TLS1.3
 use ring::{
     aead, digest::{self, Digest},
@@ -59,7 +63,12 @@ impl SecretKind {
 /// typestates.
 struct KeySchedule {
     current: hkdf::Prk,
+    /// The bytes of `current`, so the session can be exported, and so
+    /// tests can check it.
+    #[cfg(any(test, feature = "session_export"))]
+    current_bytes: Vec<u8>,
     algorithm: ring::hkdf::Algorithm,
+    label_prefix: &'static [u8],
 }
 
 // We express the state of a contained KeySchedule using these
@@ -74,9 +83,9 @@ pub struct KeyScheduleEarly {
 }
 
 impl KeyScheduleEarly {
-    pub fn new(algorithm: hkdf::Algorithm, secret: &[u8]) -> KeyScheduleEarly {
+    pub fn new(protocol: Protocol, algorithm: hkdf::Algorithm, secret: &[u8]) -> KeyScheduleEarly {
         KeyScheduleEarly {
-            ks: KeySchedule::new(algorithm, secret),
+            ks: KeySchedule::new(protocol, algorithm, secret),
         }
     }
 
@@ -99,7 +108,7 @@ impl KeyScheduleEarly {
             .ks
             .derive_for_empty_hash(SecretKind::ResumptionPSKBinderKey);
         self.ks
-            .sign_verify_data(&resumption_psk_binder_key, hs_hash)
+            .sign_verify_data(&resumption_psk_binder_key, hs_hash.as_ref())
     }
 
     pub fn into_handshake(mut self, secret: &[u8]) -> KeyScheduleHandshake {
@@ -119,9 +128,9 @@ pub struct KeyScheduleNonSecret {
 }
 
 impl KeyScheduleNonSecret {
-    pub fn new(algorithm: hkdf::Algorithm) -> KeyScheduleNonSecret {
+    pub fn new(protocol: Protocol, algorithm: hkdf::Algorithm) -> KeyScheduleNonSecret {
         KeyScheduleNonSecret {
-            ks: KeySchedule::new_with_empty_secret(algorithm),
+            ks: KeySchedule::new_with_empty_secret(protocol, algorithm),
         }
     }
 
@@ -181,7 +190,7 @@ impl KeyScheduleHandshake {
             self.current_server_traffic_secret
                 .as_ref()
                 .unwrap(),
-            hs_hash,
+            hs_hash.as_ref(),
         )
     }
 
@@ -190,6 +199,11 @@ impl KeyScheduleHandshake {
     ) -> KeyScheduleTrafficWithClientFinishedPending {
         self.ks.input_empty();
         KeyScheduleTrafficWithClientFinishedPending {
+            #[cfg(feature = "session_export")]
+            secrets: TrafficSecrets {
+                master: self.ks.current_bytes.clone(),
+                ..TrafficSecrets::default()
+            },
             ks: self.ks,
             handshake_client_traffic_secret: self
                 .current_client_traffic_secret
@@ -209,12 +223,14 @@ pub struct KeyScheduleTrafficWithClientFinishedPending {
     current_client_traffic_secret: Option<hkdf::Prk>,
     current_server_traffic_secret: Option<hkdf::Prk>,
     current_exporter_secret: Option<hkdf::Prk>,
+    #[cfg(feature = "session_export")]
+    secrets: TrafficSecrets,
 }
 
 impl KeyScheduleTrafficWithClientFinishedPending {
     pub fn sign_client_finish(&self, hs_hash: &Digest) -> hmac::Tag {
         self.ks
-            .sign_finish(&self.handshake_client_traffic_secret, hs_hash)
+            .sign_finish(&self.handshake_client_traffic_secret, hs_hash.as_ref())
     }
 
     pub fn server_application_traffic_secret(
@@ -229,6 +245,12 @@ impl KeyScheduleTrafficWithClientFinishedPending {
             key_log,
             client_random,
         );
+        #[cfg(feature = "session_export")]
+        {
+            self.secrets.server = self
+                .ks
+                .derive_bytes(SecretKind::ServerApplicationTrafficSecret, hs_hash.as_ref());
+        }
         self.current_server_traffic_secret = Some(secret.clone());
         secret
     }
@@ -245,6 +267,12 @@ impl KeyScheduleTrafficWithClientFinishedPending {
             key_log,
             client_random,
         );
+        #[cfg(feature = "session_export")]
+        {
+            self.secrets.client = self
+                .ks
+                .derive_bytes(SecretKind::ClientApplicationTrafficSecret, hs_hash.as_ref());
+        }
         self.current_client_traffic_secret = Some(secret.clone());
         secret
     }
@@ -261,6 +289,12 @@ impl KeyScheduleTrafficWithClientFinishedPending {
             key_log,
             client_random,
         );
+        #[cfg(feature = "session_export")]
+        {
+            self.secrets.exporter = self
+                .ks
+                .derive_bytes(SecretKind::ExporterMasterSecret, hs_hash.as_ref());
+        }
         self.current_exporter_secret = Some(secret);
     }
 
@@ -274,6 +308,8 @@ impl KeyScheduleTrafficWithClientFinishedPending {
                 .current_server_traffic_secret
                 .unwrap(),
             current_exporter_secret: self.current_exporter_secret.unwrap(),
+            #[cfg(feature = "session_export")]
+            secrets: self.secrets,
         }
     }
 }
@@ -285,13 +321,84 @@ pub struct KeyScheduleTraffic {
     current_client_traffic_secret: hkdf::Prk,
     current_server_traffic_secret: hkdf::Prk,
     current_exporter_secret: hkdf::Prk,
+    #[cfg(feature = "session_export")]
+    secrets: TrafficSecrets,
+}
+
+/// The bytes of a `KeyScheduleTraffic`'s secrets, from which it
+/// can be rebuilt in another process.
+#[cfg(feature = "session_export")]
+#[derive(Default)]
+pub(crate) struct TrafficSecrets {
+    pub(crate) master: Vec<u8>,
+    pub(crate) client: Vec<u8>,
+    pub(crate) server: Vec<u8>,
+    pub(crate) exporter: Vec<u8>,
 }
 
 impl KeyScheduleTraffic {
+    /// Rebuild a key schedule from the `secrets` of an exported one.
+    ///
+    /// Returns `None` if they aren't the right length for `algorithm`.
+    #[cfg(feature = "session_export")]
+    pub(crate) fn import(
+        protocol: Protocol,
+        algorithm: hkdf::Algorithm,
+        secrets: TrafficSecrets,
+    ) -> Option<KeyScheduleTraffic> {
+        let all = [
+            &secrets.master,
+            &secrets.client,
+            &secrets.server,
+            &secrets.exporter,
+        ];
+        if all
+            .iter()
+            .any(|secret| secret.len() != algorithm.len())
+        {
+            return None;
+        }
+
+        let prk = |secret: &[u8]| hkdf::Prk::new_less_safe(algorithm, secret);
+        Some(KeyScheduleTraffic {
+            ks: KeySchedule {
+                current: prk(&secrets.master),
+                current_bytes: secrets.master.clone(),
+                algorithm,
+                label_prefix: protocol.label_prefix(),
+            },
+            current_client_traffic_secret: prk(&secrets.client),
+            current_server_traffic_secret: prk(&secrets.server),
+            current_exporter_secret: prk(&secrets.exporter),
+            secrets,
+        })
+    }
+
+    /// The current client and server traffic secrets, in that order.
+    #[cfg(feature = "session_export")]
+    pub(crate) fn current_traffic_secrets(&self) -> (&hkdf::Prk, &hkdf::Prk) {
+        (
+            &self.current_client_traffic_secret,
+            &self.current_server_traffic_secret,
+        )
+    }
+
+    /// The bytes of our secrets, for exporting the session.
+    #[cfg(feature = "session_export")]
+    pub(crate) fn export(&self) -> &TrafficSecrets {
+        &self.secrets
+    }
+
     pub fn next_server_application_traffic_secret(&mut self) -> hkdf::Prk {
         let secret = self
             .ks
             .derive_next(&self.current_server_traffic_secret);
+        #[cfg(feature = "session_export")]
+        {
+            self.secrets.server = self
+                .ks
+                .derive_next_bytes(&self.current_server_traffic_secret);
+        }
         self.current_server_traffic_secret = secret.clone();
         secret
     }
@@ -300,6 +407,12 @@ impl KeyScheduleTraffic {
         let secret = self
             .ks
             .derive_next(&self.current_client_traffic_secret);
+        #[cfg(feature = "session_export")]
+        {
+            self.secrets.client = self
+                .ks
+                .derive_next_bytes(&self.current_client_traffic_secret);
+        }
         self.current_client_traffic_secret = secret.clone();
         secret
     }
@@ -327,16 +440,42 @@ impl KeyScheduleTraffic {
         self.ks
             .export_keying_material(&self.current_exporter_secret, out, label, context)
     }
+
+    /// Returns the current client and server traffic secrets, in that order.
+    #[cfg(feature = "secret_extraction")]
+    pub(crate) fn extract_secrets(
+        &self,
+        suite: &SupportedCipherSuite,
+    ) -> (ConnectionTrafficSecrets, ConnectionTrafficSecrets) {
+        let expand = |secret: &hkdf::Prk| {
+            let key: PayloadU8 = hkdf_expand(
+                secret,
+                PayloadU8Len(suite.aead_algorithm.key_len()),
+                b"key",
+                &[],
+            );
+            let iv = derive_traffic_iv(secret);
+            ConnectionTrafficSecrets::new(&suite.bulk, &key.0, iv.value())
+        };
+
+        (
+            expand(&self.current_client_traffic_secret),
+            expand(&self.current_server_traffic_secret),
+        )
+    }
 }
 
 impl KeySchedule {
-    fn new(algorithm: hkdf::Algorithm, secret: &[u8]) -> KeySchedule {
+    fn new(protocol: Protocol, algorithm: hkdf::Algorithm, secret: &[u8]) -> KeySchedule {
         let zeroes = [0u8; digest::MAX_OUTPUT_LEN];
         let zeroes = &zeroes[..algorithm.len()];
         let salt = hkdf::Salt::new(algorithm, &zeroes);
         KeySchedule {
             current: salt.extract(secret),
+            #[cfg(any(test, feature = "session_export"))]
+            current_bytes: extract_bytes(algorithm, zeroes, secret),
             algorithm,
+            label_prefix: protocol.label_prefix(),
         }
     }
 
@@ -345,9 +484,9 @@ impl KeySchedule {
         self.algorithm
     }
 
-    fn new_with_empty_secret(algorithm: hkdf::Algorithm) -> KeySchedule {
+    fn new_with_empty_secret(protocol: Protocol, algorithm: hkdf::Algorithm) -> KeySchedule {
         let zeroes = [0u8; digest::MAX_OUTPUT_LEN];
-        Self::new(algorithm, &zeroes[..algorithm.len()])
+        Self::new(protocol, algorithm, &zeroes[..algorithm.len()])
     }
 
     /// Input the empty secret.
@@ -358,17 +497,58 @@ impl KeySchedule {
 
     /// Input the given secret.
     fn input_secret(&mut self, secret: &[u8]) {
+        #[cfg(any(test, feature = "session_export"))]
+        {
+            let salt = self.derive_bytes(SecretKind::DerivedSecret, self.empty_hash().as_ref());
+            self.current_bytes = extract_bytes(self.algorithm, &salt, secret);
+        }
         let salt: hkdf::Salt = self.derive_for_empty_hash(SecretKind::DerivedSecret);
         self.current = salt.extract(secret);
     }
 
+    /// Like `derive`, but giving the secret's bytes.
+    #[cfg(any(test, feature = "session_export"))]
+    fn derive_bytes(&self, kind: SecretKind, hs_hash: &[u8]) -> Vec<u8> {
+        self.derive::<PayloadU8, _>(PayloadU8Len(self.algorithm.len()), kind, hs_hash)
+            .into_inner()
+    }
+
+    /// Like `derive_next`, but giving the secret's bytes.
+    #[cfg(feature = "session_export")]
+    fn derive_next_bytes(&self, base_key: &hkdf::Prk) -> Vec<u8> {
+        let secret: PayloadU8 = hkdf_expand_label(
+            base_key,
+            PayloadU8Len(self.algorithm.len()),
+            self.label_prefix,
+            b"traffic upd",
+            &[],
+        );
+        secret.into_inner()
+    }
+
+    /// The hash of the empty string.
+    #[cfg(any(test, feature = "session_export"))]
+    fn empty_hash(&self) -> Digest {
+        let digest_alg = self
+            .algorithm
+            .hmac_algorithm()
+            .digest_algorithm();
+        digest::digest(digest_alg, &[])
+    }
+
     /// Derive a secret of given `kind`, using current handshake hash `hs_hash`.
     fn derive<T, L>(&self, key_type: L, kind: SecretKind, hs_hash: &[u8]) -> T
     where
         T: for<'a> From<hkdf::Okm<'a, L>>,
         L: hkdf::KeyType,
     {
-        hkdf_expand(&self.current, key_type, kind.to_bytes(), hs_hash.as_ref())
+        hkdf_expand_label(
+            &self.current,
+            key_type,
+            self.label_prefix,
+            kind.to_bytes(),
+            hs_hash,
+        )
     }
 
     fn derive_logged_secret(
@@ -408,29 +588,36 @@ impl KeySchedule {
 
     /// Sign the finished message consisting of `hs_hash` using a current
     /// traffic secret.
-    fn sign_finish(&self, base_key: &hkdf::Prk, hs_hash: &Digest) -> hmac::Tag {
+    fn sign_finish(&self, base_key: &hkdf::Prk, hs_hash: &[u8]) -> hmac::Tag {
         self.sign_verify_data(base_key, hs_hash)
     }
 
     /// Sign the finished message consisting of `hs_hash` using the key material
     /// `base_key`.
-    fn sign_verify_data(&self, base_key: &hkdf::Prk, hs_hash: &Digest) -> hmac::Tag {
+    fn sign_verify_data(&self, base_key: &hkdf::Prk, hs_hash: &[u8]) -> hmac::Tag {
         let hmac_alg = self.algorithm.hmac_algorithm();
-        let hmac_key = hkdf_expand(base_key, hmac_alg, b"finished", &[]);
-        hmac::sign(&hmac_key, hs_hash.as_ref())
+        let hmac_key = hkdf_expand_label(base_key, hmac_alg, self.label_prefix, b"finished", &[]);
+        hmac::sign(&hmac_key, hs_hash)
     }
 
     /// Derive the next application traffic secret, returning it.
     fn derive_next(&self, base_key: &hkdf::Prk) -> hkdf::Prk {
-        hkdf_expand(&base_key, self.algorithm, b"traffic upd", &[])
+        hkdf_expand_label(
+            base_key,
+            self.algorithm,
+            self.label_prefix,
+            b"traffic upd",
+            &[],
+        )
     }
 
     /// Derive the PSK to use given a resumption_master_secret and
     /// ticket_nonce.
     fn derive_ticket_psk(&self, rms: &hkdf::Prk, nonce: &[u8]) -> Vec<u8> {
-        let payload: PayloadU8 = hkdf_expand(
+        let payload: PayloadU8 = hkdf_expand_label(
             rms,
             PayloadU8Len(self.algorithm.len()),
+            self.label_prefix,
             b"resumption",
             nonce,
         );
@@ -449,20 +636,27 @@ impl KeySchedule {
             .hmac_algorithm()
             .digest_algorithm();
 
+        // HKDF-Expand produces at most 255 blocks, and `hkdf_expand_info`
+        // treats exceeding that as a bug.
+        if out.len() > 255 * digest_alg.output_len {
+            return Err(TlsError::General("exporting too much".to_string()));
+        }
+
         let h_empty = digest::digest(digest_alg, &[]);
-        let secret: hkdf::Prk = hkdf_expand(
+        let secret: hkdf::Prk = hkdf_expand_label(
             current_exporter_secret,
             self.algorithm,
+            self.label_prefix,
             label,
             h_empty.as_ref(),
         );
 
         let h_context = digest::digest(digest_alg, context.unwrap_or(&[]));
 
-        // TODO: Test what happens when this fails
         hkdf_expand_info(
             &secret,
             PayloadU8Len(out.len()),
+            self.label_prefix,
             b"exporter",
             h_context.as_ref(),
             |okm| okm.fill(out),
@@ -476,12 +670,34 @@ where
     T: for<'a> From<hkdf::Okm<'a, L>>,
     L: hkdf::KeyType,
 {
-    hkdf_expand_info(secret, key_type, label, context, |okm| okm.into())
+    hkdf_expand_label(
+        secret,
+        key_type,
+        Protocol::Tls13.label_prefix(),
+        label,
+        context,
+    )
+}
+
+/// HKDF-Expand-Label, for a protocol whose labels start with `label_prefix`.
+pub(crate) fn hkdf_expand_label<T, L>(
+    secret: &hkdf::Prk,
+    key_type: L,
+    label_prefix: &[u8],
+    label: &[u8],
+    context: &[u8],
+) -> T
+where
+    T: for<'a> From<hkdf::Okm<'a, L>>,
+    L: hkdf::KeyType,
+{
+    hkdf_expand_info(secret, key_type, label_prefix, label, context, |okm| okm.into())
 }
 
 fn hkdf_expand_info<F, T, L>(
     secret: &hkdf::Prk,
     key_type: L,
+    label_prefix: &[u8],
     label: &[u8],
     context: &[u8],
     f: F,
@@ -490,16 +706,14 @@ where
     F: for<'b> FnOnce(hkdf::Okm<'b, L>) -> T,
     L: hkdf::KeyType,
 {
-    const LABEL_PREFIX: &[u8] = b"tls13 ";
-
     let output_len = u16::to_be_bytes(key_type.len() as u16);
-    let label_len = u8::to_be_bytes((LABEL_PREFIX.len() + label.len()) as u8);
+    let label_len = u8::to_be_bytes((label_prefix.len() + label.len()) as u8);
     let context_len = u8::to_be_bytes(context.len() as u8);
 
     let info = &[
         &output_len[..],
         &label_len[..],
-        LABEL_PREFIX,
+        label_prefix,
         label,
         &context_len[..],
         context,
@@ -509,6 +723,91 @@ where
     f(okm)
 }
 
+/// HKDF-Extract, giving the bytes of the result rather than a `Prk`.
+#[cfg(any(test, feature = "session_export"))]
+fn extract_bytes(algorithm: hkdf::Algorithm, salt: &[u8], secret: &[u8]) -> Vec<u8> {
+    let key = hmac::Key::new(algorithm.hmac_algorithm(), salt);
+    hmac::sign(&key, secret)
+        .as_ref()
+        .to_vec()
+}
+
+/// A secret derived from a session's exporter, from which a protocol
+/// layered on TLS can derive its own keys and secrets.
+///
+/// Get one with `Session::exporter_secret`.  Its methods use
+/// HKDF-Expand-Label, as defined in RFC8446 section 7.1, with the hash
+/// of the session's ciphersuite and the `tls13 ` label prefix, so
+/// protocols in the style of QUIC needn't implement it themselves.
+pub struct ExporterSecret {
+    secret: hkdf::Prk,
+    algorithm: hkdf::Algorithm,
+}
+
+impl ExporterSecret {
+    pub(crate) fn new(algorithm: hkdf::Algorithm, secret: &[u8]) -> Self {
+        Self {
+            secret: hkdf::Prk::new_less_safe(algorithm, secret),
+            algorithm,
+        }
+    }
+
+    /// The length of the hash, and so of the secrets `derive_secret`
+    /// makes.
+    pub fn hash_len(&self) -> usize {
+        self.algorithm.len()
+    }
+
+    /// Fills `output` with HKDF-Expand-Label(secret, `label`,
+    /// `context`, `output.len()`).
+    ///
+    /// This fails if `output` is longer than 255 times `hash_len()`,
+    /// if `label` is longer than 249 bytes, or if `context` is longer
+    /// than 255 bytes.
+    pub fn expand_label(
+        &self,
+        output: &mut [u8],
+        label: &[u8],
+        context: &[u8],
+    ) -> Result<(), TlsError> {
+        let label_prefix = Protocol::Tls13.label_prefix();
+        if output.len() > 255 * self.hash_len()
+            || label_prefix.len() + label.len() > 255
+            || context.len() > 255
+        {
+            return Err(TlsError::General("invalid HKDF-Expand-Label input".to_string()));
+        }
+
+        hkdf_expand_info(
+            &self.secret,
+            PayloadU8Len(output.len()),
+            label_prefix,
+            label,
+            context,
+            |okm| okm.fill(output),
+        )
+        .map_err(|_| TlsError::General("invalid HKDF-Expand-Label input".to_string()))
+    }
+
+    /// Derives another secret, `hash_len()` bytes long, with
+    /// HKDF-Expand-Label(secret, `label`, `context`, `hash_len()`).
+    ///
+    /// This fails in the same cases as `expand_label`.
+    pub fn derive_secret(&self, label: &[u8], context: &[u8]) -> Result<Self, TlsError> {
+        let mut secret = vec![0u8; self.hash_len()];
+        self.expand_label(&mut secret, label, context)?;
+        Ok(Self::new(self.algorithm, &secret))
+    }
+}
+
+impl fmt::Debug for ExporterSecret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ExporterSecret")
+            .field("hash_len", &self.hash_len())
+            .finish()
+    }
+}
+
 pub(crate) struct PayloadU8Len(pub(crate) usize);
 impl hkdf::KeyType for PayloadU8Len {
     fn len(&self) -> usize {
@@ -537,9 +836,25 @@ pub(crate) fn derive_traffic_iv(secret: &hkdf::Prk) -> Iv {
 
 #[cfg(test)]
 mod test {
-    use super::{derive_traffic_iv, derive_traffic_key, KeySchedule, SecretKind};
-    use crate::KeyLog;
-    use ring::{aead, hkdf};
+    use super::{derive_traffic_iv, derive_traffic_key, KeySchedule, PayloadU8Len, SecretKind};
+    use crate::hash_hs::HandshakeHash;
+    use crate::kx::{
+        ActiveKeyExchange, KeyExchange, ProvidesKeyExchange, RingKeyExchange, SupportedKxGroup,
+        X25519,
+    };
+    use crate::msgs::base::PayloadU8;
+    use crate::msgs::codec::{Codec, Reader};
+    use crate::msgs::enums::{ContentType, ProtocolVersion};
+    use crate::msgs::handshake::{HandshakeMessagePayload, HandshakePayload};
+    use crate::msgs::message::{Message, MessagePayload};
+    use crate::rand::{GetRandomFailed, ProvidesRandom};
+    use crate::session::Protocol;
+    use crate::{
+        ClientConfig, ClientSession, KeyLog, RootCertStore, Session, DEFAULT_CIPHERSUITES,
+    };
+    use ring::{aead, digest, hkdf};
+    use std::convert::TryInto;
+    use std::sync::Arc;
 
     #[test]
     fn test_vectors() {
@@ -623,7 +938,7 @@ mod test {
         ];
 
         let hkdf = hkdf::HKDF_SHA256;
-        let mut ks = KeySchedule::new_with_empty_secret(hkdf);
+        let mut ks = KeySchedule::new_with_empty_secret(Protocol::Tls13, hkdf);
         ks.input_secret(&ecdhe_secret);
 
         assert_traffic_secret(
@@ -663,6 +978,423 @@ mod test {
             &server_ats_key,
             &server_ats_iv,
         );
+
+        // An exported session rebuilds the key schedule from its bytes.
+        #[cfg(feature = "session_export")]
+        {
+            assert_eq!(
+                ks.derive_bytes(SecretKind::ClientApplicationTrafficSecret, &hs_full_hash),
+                client_ats.to_vec()
+            );
+
+            let rebuilt = KeySchedule {
+                current: hkdf::Prk::new_less_safe(hkdf, &ks.current_bytes),
+                current_bytes: ks.current_bytes.clone(),
+                algorithm: hkdf,
+                label_prefix: ks.label_prefix,
+            };
+            assert_traffic_secret(
+                &rebuilt,
+                SecretKind::ServerApplicationTrafficSecret,
+                &hs_full_hash,
+                &server_ats,
+                &server_ats_key,
+                &server_ats_iv,
+            );
+        }
+    }
+
+    // From the simple 1-RTT handshake in RFC 8448 section 3.
+
+    /// The client's X25519 private key.
+    const CLIENT_PRIVATE_KEY: [u8; 32] = [
+        0x49, 0xaf, 0x42, 0xba, 0x7f, 0x79, 0x94, 0x85, 0x2d, 0x71, 0x3e, 0xf2, 0x78, 0x4b, 0xcb,
+        0xca, 0xa7, 0x91, 0x1d, 0xe2, 0x6a, 0xdc, 0x56, 0x42, 0xcb, 0x63, 0x45, 0x40, 0xe7, 0xea,
+        0x50, 0x05,
+    ];
+
+    /// The server's X25519 private key.
+    const SERVER_PRIVATE_KEY: [u8; 32] = [
+        0xb1, 0x58, 0x0e, 0xea, 0xdf, 0x6d, 0xd5, 0x89, 0xb8, 0xef, 0x4f, 0x2d, 0x56, 0x52, 0x57,
+        0x8c, 0xc8, 0x10, 0xe9, 0x98, 0x01, 0x91, 0xec, 0x8d, 0x05, 0x83, 0x08, 0xce, 0xa2, 0x16,
+        0xa2, 0x1e,
+    ];
+
+    /// The (EC)DHE shared secret.
+    const SHARED_SECRET: [u8; 32] = [
+        0x8b, 0xd4, 0x05, 0x4f, 0xb5, 0x5b, 0x9d, 0x63, 0xfd, 0xfb, 0xac, 0xf9, 0xf0, 0x4b, 0x9f,
+        0x0d, 0x35, 0xe6, 0xd6, 0x3f, 0x53, 0x75, 0x63, 0xef, 0xd4, 0x62, 0x72, 0x90, 0x0f, 0x89,
+        0x49, 0x2d,
+    ];
+
+    /// The ClientHello handshake message.
+    const CLIENT_HELLO: [u8; 196] = [
+        0x01, 0x00, 0x00, 0xc0, 0x03, 0x03, 0xcb, 0x34, 0xec, 0xb1, 0xe7, 0x81, 0x63, 0xba, 0x1c,
+        0x38, 0xc6, 0xda, 0xcb, 0x19, 0x6a, 0x6d, 0xff, 0xa2, 0x1a, 0x8d, 0x99, 0x12, 0xec, 0x18,
+        0xa2, 0xef, 0x62, 0x83, 0x02, 0x4d, 0xec, 0xe7, 0x00, 0x00, 0x06, 0x13, 0x01, 0x13, 0x03,
+        0x13, 0x02, 0x01, 0x00, 0x00, 0x91, 0x00, 0x00, 0x00, 0x0b, 0x00, 0x09, 0x00, 0x00, 0x06,
+        0x73, 0x65, 0x72, 0x76, 0x65, 0x72, 0xff, 0x01, 0x00, 0x01, 0x00, 0x00, 0x0a, 0x00, 0x14,
+        0x00, 0x12, 0x00, 0x1d, 0x00, 0x17, 0x00, 0x18, 0x00, 0x19, 0x01, 0x00, 0x01, 0x01, 0x01,
+        0x02, 0x01, 0x03, 0x01, 0x04, 0x00, 0x23, 0x00, 0x00, 0x00, 0x33, 0x00, 0x26, 0x00, 0x24,
+        0x00, 0x1d, 0x00, 0x20, 0x99, 0x38, 0x1d, 0xe5, 0x60, 0xe4, 0xbd, 0x43, 0xd2, 0x3d, 0x8e,
+        0x43, 0x5a, 0x7d, 0xba, 0xfe, 0xb3, 0xc0, 0x6e, 0x51, 0xc1, 0x3c, 0xae, 0x4d, 0x54, 0x13,
+        0x69, 0x1e, 0x52, 0x9a, 0xaf, 0x2c, 0x00, 0x2b, 0x00, 0x03, 0x02, 0x03, 0x04, 0x00, 0x0d,
+        0x00, 0x20, 0x00, 0x1e, 0x04, 0x03, 0x05, 0x03, 0x06, 0x03, 0x02, 0x03, 0x08, 0x04, 0x08,
+        0x05, 0x08, 0x06, 0x04, 0x01, 0x05, 0x01, 0x06, 0x01, 0x02, 0x01, 0x04, 0x02, 0x05, 0x02,
+        0x06, 0x02, 0x02, 0x02, 0x00, 0x2d, 0x00, 0x02, 0x01, 0x01, 0x00, 0x1c, 0x00, 0x02, 0x40,
+        0x01,
+    ];
+
+    /// The ServerHello handshake message.
+    const SERVER_HELLO: [u8; 90] = [
+        0x02, 0x00, 0x00, 0x56, 0x03, 0x03, 0xa6, 0xaf, 0x06, 0xa4, 0x12, 0x18, 0x60, 0xdc, 0x5e,
+        0x6e, 0x60, 0x24, 0x9c, 0xd3, 0x4c, 0x95, 0x93, 0x0c, 0x8a, 0xc5, 0xcb, 0x14, 0x34, 0xda,
+        0xc1, 0x55, 0x77, 0x2e, 0xd3, 0xe2, 0x69, 0x28, 0x00, 0x13, 0x01, 0x00, 0x00, 0x2e, 0x00,
+        0x33, 0x00, 0x24, 0x00, 0x1d, 0x00, 0x20, 0xc9, 0x82, 0x88, 0x76, 0x11, 0x20, 0x95, 0xfe,
+        0x66, 0x76, 0x2b, 0xdb, 0xf7, 0xc6, 0x72, 0xe1, 0x56, 0xd6, 0xcc, 0x25, 0x3b, 0x83, 0x3d,
+        0xf1, 0xdd, 0x69, 0xb1, 0xb0, 0x4e, 0x75, 0x1f, 0x0f, 0x00, 0x2b, 0x00, 0x02, 0x03, 0x04,
+    ];
+
+    /// The transcript hash after the ServerHello.
+    const HELLO_HASH: [u8; 32] = [
+        0x86, 0x0c, 0x06, 0xed, 0xc0, 0x78, 0x58, 0xee, 0x8e, 0x78, 0xf0, 0xe7, 0x42, 0x8c, 0x58,
+        0xed, 0xd6, 0xb4, 0x3f, 0x2c, 0xa3, 0xe6, 0xe9, 0x5f, 0x02, 0xed, 0x06, 0x3c, 0xf0, 0xe1,
+        0xca, 0xd8,
+    ];
+
+    /// The early secret, with no PSK.
+    const EARLY_SECRET: [u8; 32] = [
+        0x33, 0xad, 0x0a, 0x1c, 0x60, 0x7e, 0xc0, 0x3b, 0x09, 0xe6, 0xcd, 0x98, 0x93, 0x68, 0x0c,
+        0xe2, 0x10, 0xad, 0xf3, 0x00, 0xaa, 0x1f, 0x26, 0x60, 0xe1, 0xb2, 0x2e, 0x10, 0xf1, 0x70,
+        0xf9, 0x2a,
+    ];
+
+    /// The handshake secret.
+    const HANDSHAKE_SECRET: [u8; 32] = [
+        0x1d, 0xc8, 0x26, 0xe9, 0x36, 0x06, 0xaa, 0x6f, 0xdc, 0x0a, 0xad, 0xc1, 0x2f, 0x74, 0x1b,
+        0x01, 0x04, 0x6a, 0xa6, 0xb9, 0x9f, 0x69, 0x1e, 0xd2, 0x21, 0xa9, 0xf0, 0xca, 0x04, 0x3f,
+        0xbe, 0xac,
+    ];
+
+    /// client_handshake_traffic_secret, and its key and IV.
+    const CLIENT_HS_TRAFFIC: [u8; 32] = [
+        0xb3, 0xed, 0xdb, 0x12, 0x6e, 0x06, 0x7f, 0x35, 0xa7, 0x80, 0xb3, 0xab, 0xf4, 0x5e, 0x2d,
+        0x8f, 0x3b, 0x1a, 0x95, 0x07, 0x38, 0xf5, 0x2e, 0x96, 0x00, 0x74, 0x6a, 0x0e, 0x27, 0xa5,
+        0x5a, 0x21,
+    ];
+    const CLIENT_HS_KEY: [u8; 16] = [
+        0xdb, 0xfa, 0xa6, 0x93, 0xd1, 0x76, 0x2c, 0x5b, 0x66, 0x6a, 0xf5, 0xd9, 0x50, 0x25, 0x8d,
+        0x01,
+    ];
+    const CLIENT_HS_IV: [u8; 12] = [
+        0x5b, 0xd3, 0xc7, 0x1b, 0x83, 0x6e, 0x0b, 0x76, 0xbb, 0x73, 0x26, 0x5f,
+    ];
+
+    /// server_handshake_traffic_secret, and its key and IV.
+    const SERVER_HS_TRAFFIC: [u8; 32] = [
+        0xb6, 0x7b, 0x7d, 0x69, 0x0c, 0xc1, 0x6c, 0x4e, 0x75, 0xe5, 0x42, 0x13, 0xcb, 0x2d, 0x37,
+        0xb4, 0xe9, 0xc9, 0x12, 0xbc, 0xde, 0xd9, 0x10, 0x5d, 0x42, 0xbe, 0xfd, 0x59, 0xd3, 0x91,
+        0xad, 0x38,
+    ];
+    const SERVER_HS_KEY: [u8; 16] = [
+        0x3f, 0xce, 0x51, 0x60, 0x09, 0xc2, 0x17, 0x27, 0xd0, 0xf2, 0xe4, 0xe8, 0x6e, 0xe4, 0x03,
+        0xbc,
+    ];
+    const SERVER_HS_IV: [u8; 12] = [
+        0x5d, 0x31, 0x3e, 0xb2, 0x67, 0x12, 0x76, 0xee, 0x13, 0x00, 0x0b, 0x30,
+    ];
+
+    /// The transcript hash after the server's CertificateVerify.
+    const CERTIFICATE_VERIFY_HASH: [u8; 32] = [
+        0xed, 0xb7, 0x72, 0x5f, 0xa7, 0xa3, 0x47, 0x3b, 0x03, 0x1e, 0xc8, 0xef, 0x65, 0xa2, 0x48,
+        0x54, 0x93, 0x90, 0x01, 0x38, 0xa2, 0xb9, 0x12, 0x91, 0x40, 0x7d, 0x79, 0x51, 0xa0, 0x61,
+        0x10, 0xed,
+    ];
+
+    /// The server's Finished verify_data.
+    const SERVER_VERIFY_DATA: [u8; 32] = [
+        0x9b, 0x9b, 0x14, 0x1d, 0x90, 0x63, 0x37, 0xfb, 0xd2, 0xcb, 0xdc, 0xe7, 0x1d, 0xf4, 0xde,
+        0xda, 0x4a, 0xb4, 0x2c, 0x30, 0x95, 0x72, 0xcb, 0x7f, 0xff, 0xee, 0x54, 0x54, 0xb7, 0x8f,
+        0x07, 0x18,
+    ];
+
+    /// The master secret.
+    const MASTER_SECRET: [u8; 32] = [
+        0x18, 0xdf, 0x06, 0x84, 0x3d, 0x13, 0xa0, 0x8b, 0xf2, 0xa4, 0x49, 0x84, 0x4c, 0x5f, 0x8a,
+        0x47, 0x80, 0x01, 0xbc, 0x4d, 0x4c, 0x62, 0x79, 0x84, 0xd5, 0xa4, 0x1d, 0xa8, 0xd0, 0x40,
+        0x29, 0x19,
+    ];
+
+    /// The transcript hash after the server's Finished.
+    const SERVER_FINISHED_HASH: [u8; 32] = [
+        0x96, 0x08, 0x10, 0x2a, 0x0f, 0x1c, 0xcc, 0x6d, 0xb6, 0x25, 0x0b, 0x7b, 0x7e, 0x41, 0x7b,
+        0x1a, 0x00, 0x0e, 0xaa, 0xda, 0x3d, 0xaa, 0xe4, 0x77, 0x7a, 0x76, 0x86, 0xc9, 0xff, 0x83,
+        0xdf, 0x13,
+    ];
+
+    /// client_application_traffic_secret_0, and its key and IV.
+    const CLIENT_AP_TRAFFIC: [u8; 32] = [
+        0x9e, 0x40, 0x64, 0x6c, 0xe7, 0x9a, 0x7f, 0x9d, 0xc0, 0x5a, 0xf8, 0x88, 0x9b, 0xce, 0x65,
+        0x52, 0x87, 0x5a, 0xfa, 0x0b, 0x06, 0xdf, 0x00, 0x87, 0xf7, 0x92, 0xeb, 0xb7, 0xc1, 0x75,
+        0x04, 0xa5,
+    ];
+    const CLIENT_AP_KEY: [u8; 16] = [
+        0x17, 0x42, 0x2d, 0xda, 0x59, 0x6e, 0xd5, 0xd9, 0xac, 0xd8, 0x90, 0xe3, 0xc6, 0x3f, 0x50,
+        0x51,
+    ];
+    const CLIENT_AP_IV: [u8; 12] = [
+        0x5b, 0x78, 0x92, 0x3d, 0xee, 0x08, 0x57, 0x90, 0x33, 0xe5, 0x23, 0xd9,
+    ];
+
+    /// server_application_traffic_secret_0, and its key and IV.
+    const SERVER_AP_TRAFFIC: [u8; 32] = [
+        0xa1, 0x1a, 0xf9, 0xf0, 0x55, 0x31, 0xf8, 0x56, 0xad, 0x47, 0x11, 0x6b, 0x45, 0xa9, 0x50,
+        0x32, 0x82, 0x04, 0xb4, 0xf4, 0x4b, 0xfb, 0x6b, 0x3a, 0x4b, 0x4f, 0x1f, 0x3f, 0xcb, 0x63,
+        0x16, 0x43,
+    ];
+    const SERVER_AP_KEY: [u8; 16] = [
+        0x9f, 0x02, 0x28, 0x3b, 0x6c, 0x9c, 0x07, 0xef, 0xc2, 0x6b, 0xb9, 0xf2, 0xac, 0x92, 0xe3,
+        0x56,
+    ];
+    const SERVER_AP_IV: [u8; 12] = [
+        0xcf, 0x78, 0x2b, 0x88, 0xdd, 0x83, 0x54, 0x9a, 0xad, 0xf1, 0xe9, 0x84,
+    ];
+
+    /// exporter_master_secret.
+    const EXPORTER_MASTER_SECRET: [u8; 32] = [
+        0xfe, 0x22, 0xf8, 0x81, 0x17, 0x6e, 0xda, 0x18, 0xeb, 0x8f, 0x44, 0x52, 0x9e, 0x67, 0x92,
+        0xc5, 0x0c, 0x9a, 0x3f, 0x89, 0x45, 0x2f, 0x68, 0xd8, 0xae, 0x31, 0x1b, 0x43, 0x09, 0xd3,
+        0xcf, 0x50,
+    ];
+
+    /// The client's Finished verify_data.
+    const CLIENT_VERIFY_DATA: [u8; 32] = [
+        0xa8, 0xec, 0x43, 0x6d, 0x67, 0x76, 0x34, 0xae, 0x52, 0x5a, 0xc1, 0xfc, 0xeb, 0xe1, 0x1a,
+        0x03, 0x9e, 0xc1, 0x76, 0x94, 0xfa, 0xc6, 0xe9, 0x85, 0x27, 0xb6, 0x42, 0xf2, 0xed, 0xd5,
+        0xce, 0x61,
+    ];
+
+    /// The transcript hash after the client's Finished.
+    const CLIENT_FINISHED_HASH: [u8; 32] = [
+        0x20, 0x91, 0x45, 0xa9, 0x6e, 0xe8, 0xe2, 0xa1, 0x22, 0xff, 0x81, 0x00, 0x47, 0xcc, 0x95,
+        0x26, 0x84, 0x65, 0x8d, 0x60, 0x49, 0xe8, 0x64, 0x29, 0x42, 0x6d, 0xb8, 0x7c, 0x54, 0xad,
+        0x14, 0x3d,
+    ];
+
+    /// resumption_master_secret.
+    const RESUMPTION_MASTER_SECRET: [u8; 32] = [
+        0x7d, 0xf2, 0x35, 0xf2, 0x03, 0x1d, 0x2a, 0x05, 0x12, 0x87, 0xd0, 0x2b, 0x02, 0x41, 0xb0,
+        0xbf, 0xda, 0xf8, 0x6c, 0xc8, 0x56, 0x23, 0x1f, 0x2d, 0x5a, 0xba, 0x46, 0xc4, 0x34, 0xec,
+        0x19, 0x6c,
+    ];
+
+    /// The PSK for the server's ticket, whose nonce is 0x0000.
+    const TICKET_PSK: [u8; 32] = [
+        0x4e, 0xcd, 0x0e, 0xb6, 0xec, 0x3b, 0x4d, 0x87, 0xf5, 0xd6, 0x02, 0x8f, 0x92, 0x2c, 0xa4,
+        0xc5, 0x85, 0x1a, 0x27, 0x7f, 0xd4, 0x13, 0x11, 0xc9, 0xe6, 0x2d, 0x2c, 0x94, 0x92, 0xe1,
+        0xc4, 0xf3,
+    ];
+
+    /// Makes key shares from a fixed private key.
+    struct FixedKeyShare(&'static [u8]);
+
+    impl ProvidesKeyExchange for FixedKeyShare {
+        fn start(&self, group: &'static SupportedKxGroup) -> Option<Box<dyn ActiveKeyExchange>> {
+            let rng = ring::test::rand::FixedSliceRandom { bytes: self.0 };
+            RingKeyExchange::start_with_rng(group, &rng)
+        }
+    }
+
+    /// Gives out the same 32 bytes, over and over.
+    struct FixedRandom(&'static [u8]);
+
+    impl ProvidesRandom for FixedRandom {
+        fn fill(&self, bytes: &mut [u8]) -> Result<(), GetRandomFailed> {
+            for (i, b) in bytes.iter_mut().enumerate() {
+                *b = self.0[i % self.0.len()];
+            }
+            Ok(())
+        }
+    }
+
+    fn read_handshake(bytes: &[u8]) -> HandshakeMessagePayload {
+        let mut rd = Reader::init(bytes);
+        let hmp = HandshakeMessagePayload::read_version(&mut rd, ProtocolVersion::TLSv1_3).unwrap();
+        assert!(!rd.any_left());
+        assert_eq!(hmp.get_encoding(), bytes.to_vec());
+        hmp
+    }
+
+    fn handshake_message(hmp: HandshakeMessagePayload) -> Message {
+        Message {
+            typ: ContentType::Handshake,
+            version: ProtocolVersion::TLSv1_3,
+            payload: MessagePayload::Handshake(hmp),
+        }
+    }
+
+    fn client_hello_random_and_share(hmp: &HandshakeMessagePayload) -> (Vec<u8>, Vec<u8>) {
+        match hmp.payload {
+            HandshakePayload::ClientHello(ref ch) => {
+                let share = &ch.get_keyshare_extension().unwrap()[0];
+                assert_eq!(share.group, X25519.name);
+                (ch.random.get_encoding(), share.payload.0.clone())
+            }
+            _ => panic!("not a ClientHello"),
+        }
+    }
+
+    #[test]
+    fn test_rfc8448_simple_1rtt() {
+        let client_hello = read_handshake(&CLIENT_HELLO);
+        let server_hello = read_handshake(&SERVER_HELLO);
+
+        let (_, client_share) = client_hello_random_and_share(&client_hello);
+        let server_share = match server_hello.payload {
+            HandshakePayload::ServerHello(ref sh) => sh
+                .get_key_share()
+                .unwrap()
+                .payload
+                .0
+                .clone(),
+            _ => panic!("not a ServerHello"),
+        };
+
+        // Our key exchange, given the RFC's private keys, makes the same
+        // key shares and agrees the same secret.
+        let client_kx = KeyExchange::start(&X25519, &FixedKeyShare(&CLIENT_PRIVATE_KEY)).unwrap();
+        let server_kx = KeyExchange::start(&X25519, &FixedKeyShare(&SERVER_PRIVATE_KEY)).unwrap();
+        assert_eq!(client_kx.pubkey, client_share);
+        assert_eq!(server_kx.pubkey, server_share);
+        let shared_secret = client_kx
+            .complete(&server_share)
+            .unwrap()
+            .shared_secret;
+        assert_eq!(shared_secret, SHARED_SECRET.to_vec());
+        assert_eq!(
+            server_kx
+                .complete(&client_share)
+                .unwrap()
+                .shared_secret,
+            shared_secret
+        );
+
+        let mut transcript = HandshakeHash::new();
+        transcript.start_hash(&digest::SHA256);
+        transcript.add_message(&handshake_message(client_hello));
+        transcript.add_message(&handshake_message(server_hello));
+        let hello_hash = transcript.get_current_hash();
+        assert_eq!(hello_hash.as_ref(), &HELLO_HASH[..]);
+
+        let mut ks = KeySchedule::new_with_empty_secret(Protocol::Tls13, hkdf::HKDF_SHA256);
+        assert_eq!(ks.current_bytes, EARLY_SECRET.to_vec());
+
+        ks.input_secret(&shared_secret);
+        assert_eq!(ks.current_bytes, HANDSHAKE_SECRET.to_vec());
+
+        let client_hts = assert_traffic_secret(
+            &ks,
+            SecretKind::ClientHandshakeTrafficSecret,
+            hello_hash.as_ref(),
+            &CLIENT_HS_TRAFFIC,
+            &CLIENT_HS_KEY,
+            &CLIENT_HS_IV,
+        );
+        let server_hts = assert_traffic_secret(
+            &ks,
+            SecretKind::ServerHandshakeTrafficSecret,
+            hello_hash.as_ref(),
+            &SERVER_HS_TRAFFIC,
+            &SERVER_HS_KEY,
+            &SERVER_HS_IV,
+        );
+
+        // We can't reproduce the server's certificate messages (its
+        // CertificateVerify signature is randomised), so from here the
+        // transcript hashes are the published ones.
+        assert_eq!(
+            ks.sign_finish(&server_hts, &CERTIFICATE_VERIFY_HASH)
+                .as_ref(),
+            &SERVER_VERIFY_DATA[..]
+        );
+
+        ks.input_empty();
+        assert_eq!(ks.current_bytes, MASTER_SECRET.to_vec());
+
+        assert_traffic_secret(
+            &ks,
+            SecretKind::ClientApplicationTrafficSecret,
+            &SERVER_FINISHED_HASH,
+            &CLIENT_AP_TRAFFIC,
+            &CLIENT_AP_KEY,
+            &CLIENT_AP_IV,
+        );
+        assert_traffic_secret(
+            &ks,
+            SecretKind::ServerApplicationTrafficSecret,
+            &SERVER_FINISHED_HASH,
+            &SERVER_AP_TRAFFIC,
+            &SERVER_AP_KEY,
+            &SERVER_AP_IV,
+        );
+        assert_derived_secret(
+            &ks,
+            SecretKind::ExporterMasterSecret,
+            &SERVER_FINISHED_HASH,
+            &EXPORTER_MASTER_SECRET,
+        );
+        assert_eq!(
+            ks.sign_finish(&client_hts, &SERVER_FINISHED_HASH)
+                .as_ref(),
+            &CLIENT_VERIFY_DATA[..]
+        );
+
+        let resumption_master_secret = assert_derived_secret(
+            &ks,
+            SecretKind::ResumptionMasterSecret,
+            &CLIENT_FINISHED_HASH,
+            &RESUMPTION_MASTER_SECRET,
+        );
+        assert_eq!(
+            ks.derive_ticket_psk(&resumption_master_secret, &[0, 0]),
+            TICKET_PSK.to_vec()
+        );
+    }
+
+    #[test]
+    fn test_rfc8448_client_hello() {
+        // Given the RFC's random and key share, our ClientHello carries them.
+        let (client_random, client_share) =
+            client_hello_random_and_share(&read_handshake(&CLIENT_HELLO));
+        let mut config = ClientConfig::new(RootCertStore::empty(), &[], DEFAULT_CIPHERSUITES);
+        config.random_provider = Arc::new(FixedRandom(&CLIENT_HELLO[6..38]));
+        config.kx_provider = Arc::new(FixedKeyShare(&CLIENT_PRIVATE_KEY));
+
+        let mut client =
+            ClientSession::new(&Arc::new(config), "server".try_into().unwrap()).unwrap();
+        let mut tls = Vec::new();
+        client.write_tls(&mut tls).unwrap();
+
+        let mut msg = Message::read(&mut Reader::init(&tls)).unwrap();
+        assert!(msg.decode_payload());
+        let (random, share) = match msg.payload {
+            MessagePayload::Handshake(ref hmp) => client_hello_random_and_share(hmp),
+            _ => panic!("not a handshake message"),
+        };
+        assert_eq!(random, client_random);
+        assert_eq!(share, client_share);
+    }
+
+    /// Check a secret of `kind`, derived from `hash`, is `expected`.
+    fn assert_derived_secret(
+        ks: &KeySchedule,
+        kind: SecretKind,
+        hash: &[u8],
+        expected: &[u8],
+    ) -> hkdf::Prk {
+        let secret: PayloadU8 = ks.derive(PayloadU8Len(expected.len()), kind, hash);
+        assert_eq!(secret.into_inner(), expected.to_vec());
+        ks.derive(ks.algorithm(), kind, hash)
     }
 
     fn assert_traffic_secret(
@@ -672,7 +1404,7 @@ mod test {
         expected_traffic_secret: &[u8],
         expected_key: &[u8],
         expected_iv: &[u8],
-    ) {
+    ) -> hkdf::Prk {
         struct Log<'a>(&'a [u8]);
         impl KeyLog for Log<'_> {
             fn log(&self, _label: &str, _client_random: &[u8], secret: &[u8]) {
@@ -693,6 +1425,7 @@ mod test {
 
         let iv = derive_traffic_iv(&traffic_secret);
         assert_eq!(iv.value(), expected_iv);
+        traffic_secret
     }
 
     fn seal_zeroes(key: aead::UnboundKey) -> Vec<u8> {
@@ -205,6 +205,28 @@ mod test {
         );
     }
 
+    #[test]
+    fn test_writes_nss_key_log_format() {
+        init();
+        let path = env::temp_dir().join(format!("keylogfileformattest-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        let mut inner = KeyLogFileInner::new(Ok(path.to_str().unwrap().into()));
+        inner
+            .try_write("CLIENT_RANDOM", &[0x01, 0xab], &[0xff, 0x00, 0x10])
+            .unwrap();
+        inner
+            .try_write("EXPORTER_SECRET", &[0x02], &[0x20])
+            .unwrap();
+
+        let written = std::fs::read_to_string(&path).unwrap();
+        let _ = std::fs::remove_file(&path);
+        assert_eq!(
+            written,
+            "CLIENT_RANDOM 01ab ff0010\nEXPORTER_SECRET 02 20\n"
+        );
+    }
+
     #[test]
     fn test_env_var_cannot_be_written() {
         init();
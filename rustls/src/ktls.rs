@@ -0,0 +1,242 @@
+//! Linux kernel TLS (kTLS) offload.
+//!
+//! Once a handshake is complete, the traffic secrets extracted with
+//! `dangerous_extract_secrets` can be handed to the kernel, which then does
+//! record protection for the socket itself.  After that, plain `read`,
+//! `write` and `sendfile` on the socket send and receive TLS records.
+//!
+//! ```no_run
+//! # use std::net::TcpStream;
+//! # fn offload(session: rustls::ServerSession, socket: &TcpStream) -> std::io::Result<()> {
+//! use rustls::Session;
+//!
+//! let version = session.get_protocol_version().unwrap();
+//! let secrets = session.dangerous_extract_secrets().unwrap();
+//! rustls::ktls::configure_ktls(socket, version, secrets)?;
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! The kernel only protects application data.  Any other record -- an
+//! alert, or a TLS1.3 `NewSessionTicket` or `KeyUpdate` -- makes receives
+//! fail, and has to be dealt with by the application using the kernel's
+//! control message interface.
+
+use crate::msgs::enums::ProtocolVersion;
+use crate::suites::{ConnectionTrafficSecrets, ExtractedSecrets};
+
+use std::io;
+use std::mem;
+use std::os::unix::io::AsRawFd;
+
+/// `SOL_TLS`, from `linux/socket.h`.
+const SOL_TLS: libc::c_int = 282;
+
+/// `TCP_ULP`, from `linux/tcp.h`.
+const TCP_ULP: libc::c_int = 31;
+
+/// Socket options at `SOL_TLS` level, from `linux/tls.h`.
+const TLS_TX: libc::c_int = 1;
+const TLS_RX: libc::c_int = 2;
+
+/// Cipher identifiers, from `linux/tls.h`.
+const TLS_CIPHER_AES_GCM_128: u16 = 51;
+const TLS_CIPHER_AES_GCM_256: u16 = 52;
+const TLS_CIPHER_CHACHA20_POLY1305: u16 = 54;
+
+/// `struct tls_crypto_info`.
+#[repr(C)]
+struct CryptoInfoHeader {
+    version: u16,
+    cipher_type: u16,
+}
+
+/// `struct tls12_crypto_info_aes_gcm_128`.
+#[repr(C)]
+struct AesGcm128 {
+    info: CryptoInfoHeader,
+    iv: [u8; 8],
+    key: [u8; 16],
+    salt: [u8; 4],
+    rec_seq: [u8; 8],
+}
+
+/// `struct tls12_crypto_info_aes_gcm_256`.
+#[repr(C)]
+struct AesGcm256 {
+    info: CryptoInfoHeader,
+    iv: [u8; 8],
+    key: [u8; 32],
+    salt: [u8; 4],
+    rec_seq: [u8; 8],
+}
+
+/// `struct tls12_crypto_info_chacha20_poly1305`.  Its salt is empty.
+#[repr(C)]
+struct Chacha20Poly1305 {
+    info: CryptoInfoHeader,
+    iv: [u8; 12],
+    key: [u8; 32],
+    rec_seq: [u8; 8],
+}
+
+/// The argument to the `TLS_TX` and `TLS_RX` socket options.
+enum CryptoInfo {
+    AesGcm128(AesGcm128),
+    AesGcm256(AesGcm256),
+    Chacha20Poly1305(Chacha20Poly1305),
+}
+
+impl CryptoInfo {
+    fn new(
+        version: ProtocolVersion,
+        (seq, secrets): (u64, ConnectionTrafficSecrets),
+    ) -> io::Result<CryptoInfo> {
+        let version = match version {
+            ProtocolVersion::TLSv1_2 | ProtocolVersion::TLSv1_3 => version.get_u16(),
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "kTLS only supports TLS1.2 and TLS1.3",
+                ))
+            }
+        };
+        let rec_seq = seq.to_be_bytes();
+
+        Ok(match secrets {
+            ConnectionTrafficSecrets::Aes128Gcm { key, salt, iv } => {
+                CryptoInfo::AesGcm128(AesGcm128 {
+                    info: CryptoInfoHeader {
+                        version,
+                        cipher_type: TLS_CIPHER_AES_GCM_128,
+                    },
+                    iv,
+                    key,
+                    salt,
+                    rec_seq,
+                })
+            }
+            ConnectionTrafficSecrets::Aes256Gcm { key, salt, iv } => {
+                CryptoInfo::AesGcm256(AesGcm256 {
+                    info: CryptoInfoHeader {
+                        version,
+                        cipher_type: TLS_CIPHER_AES_GCM_256,
+                    },
+                    iv,
+                    key,
+                    salt,
+                    rec_seq,
+                })
+            }
+            ConnectionTrafficSecrets::Chacha20Poly1305 { key, iv } => {
+                CryptoInfo::Chacha20Poly1305(Chacha20Poly1305 {
+                    info: CryptoInfoHeader {
+                        version,
+                        cipher_type: TLS_CIPHER_CHACHA20_POLY1305,
+                    },
+                    iv,
+                    key,
+                    rec_seq,
+                })
+            }
+        })
+    }
+
+    fn set(&self, fd: libc::c_int, direction: libc::c_int) -> io::Result<()> {
+        match self {
+            CryptoInfo::AesGcm128(info) => setsockopt(fd, SOL_TLS, direction, info),
+            CryptoInfo::AesGcm256(info) => setsockopt(fd, SOL_TLS, direction, info),
+            CryptoInfo::Chacha20Poly1305(info) => setsockopt(fd, SOL_TLS, direction, info),
+        }
+    }
+}
+
+#[allow(unsafe_code)]
+fn setsockopt<T: ?Sized>(
+    fd: libc::c_int,
+    level: libc::c_int,
+    name: libc::c_int,
+    value: &T,
+) -> io::Result<()> {
+    let len = mem::size_of_val(value) as libc::socklen_t;
+    let value: *const T = value;
+
+    // Safety: `value` points to `len` readable bytes, which the kernel
+    // copies before returning.
+    let ret = unsafe { libc::setsockopt(fd, level, name, value.cast(), len) };
+
+    if ret == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+/// Hand record protection for `socket` over to the kernel, using `secrets`
+/// extracted from a session which negotiated protocol `version`.
+///
+/// `socket` must be the TCP socket the session ran over, and every byte
+/// the session wrote must already have been written to it.
+///
+/// This fails if the kernel doesn't support kTLS, or the negotiated
+/// ciphersuite.  The socket can't be used for TLS after a failure: the
+/// session is gone, and the kernel may have been given the keys for one
+/// direction but not the other.
+pub fn configure_ktls(
+    socket: &impl AsRawFd,
+    version: ProtocolVersion,
+    secrets: ExtractedSecrets,
+) -> io::Result<()> {
+    let tx = CryptoInfo::new(version, secrets.tx)?;
+    let rx = CryptoInfo::new(version, secrets.rx)?;
+
+    let fd = socket.as_raw_fd();
+    setsockopt(fd, libc::SOL_TCP, TCP_ULP, &b"tls"[..])?;
+    tx.set(fd, TLS_TX)?;
+    rx.set(fd, TLS_RX)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn crypto_info_layout() {
+        // Sizes of the structs in linux/tls.h.
+        assert_eq!(mem::size_of::<AesGcm128>(), 40);
+        assert_eq!(mem::size_of::<AesGcm256>(), 56);
+        assert_eq!(mem::size_of::<Chacha20Poly1305>(), 56);
+    }
+
+    #[test]
+    fn crypto_info_from_secrets() {
+        let secrets = ConnectionTrafficSecrets::Aes128Gcm {
+            key: [1; 16],
+            salt: [2; 4],
+            iv: [3; 8],
+        };
+        match CryptoInfo::new(ProtocolVersion::TLSv1_3, (0x0102, secrets)).unwrap() {
+            CryptoInfo::AesGcm128(info) => {
+                assert_eq!(info.info.version, 0x0304);
+                assert_eq!(info.info.cipher_type, TLS_CIPHER_AES_GCM_128);
+                assert_eq!(info.key, [1; 16]);
+                assert_eq!(info.salt, [2; 4]);
+                assert_eq!(info.iv, [3; 8]);
+                assert_eq!(info.rec_seq, [0, 0, 0, 0, 0, 0, 1, 2]);
+            }
+            _ => panic!("wrong crypto info"),
+        }
+    }
+
+    #[test]
+    fn crypto_info_rejects_old_versions() {
+        let secrets = ConnectionTrafficSecrets::Chacha20Poly1305 {
+            key: [0; 32],
+            iv: [0; 12],
+        };
+        let err = CryptoInfo::new(ProtocolVersion::TLSv1_1, (0, secrets))
+            .err()
+            .unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+}
@@ -1,5 +1,7 @@
 use crate::msgs::enums::NamedGroup;
+#[cfg(feature = "tls12")]
 use crate::msgs::codec::{Codec, Reader};
+#[cfg(feature = "tls12")]
 use crate::msgs::handshake::{ClientECDHParams, ServerECDHParams};
 
 use ring;
@@ -8,16 +10,89 @@ use ring;
 /// and the agreed shared secret (also known as the "premaster secret"
 /// in TLS1.0-era protocols, and "Z" in TLS1.3).
 pub struct KeyExchangeResult {
-    pub pubkey: ring::agreement::PublicKey,
+    pub group: &'static SupportedKxGroup,
+    pub pubkey: Vec<u8>,
     pub shared_secret: Vec<u8>,
 }
 
+/// Starts ephemeral key exchanges on behalf of rustls.
+///
+/// The default, `RingKeyExchange`, does this in software.  Implement
+/// this trait to have the private scalar generated and used elsewhere,
+/// such as in a TPM or secure element.
+pub trait ProvidesKeyExchange: Send + Sync {
+    /// Generate an ephemeral key pair in `group`.
+    ///
+    /// Return None if `group` is not supported, or if key
+    /// generation failed: the handshake is then aborted.
+    fn start(&self, group: &'static SupportedKxGroup) -> Option<Box<dyn ActiveKeyExchange>>;
+}
+
+/// An ephemeral key pair, waiting for the peer's public key.
+pub trait ActiveKeyExchange: Send + Sync {
+    /// Our public key, in the encoding used on the wire.  For
+    /// the NIST curves this is an uncompressed point; for X25519
+    /// it is the 32-byte u-coordinate.
+    fn pub_key(&self) -> &[u8];
+
+    /// Complete the key exchange with the peer's public key
+    /// `peer_pub_key`, consuming our private key.  Returns the
+    /// shared secret, or None if `peer_pub_key` was invalid.
+    fn complete(self: Box<Self>, peer_pub_key: &[u8]) -> Option<Vec<u8>>;
+}
+
+/// The default `ProvidesKeyExchange` implementation, using *ring*.
+pub struct RingKeyExchange;
+
+impl ProvidesKeyExchange for RingKeyExchange {
+    fn start(&self, group: &'static SupportedKxGroup) -> Option<Box<dyn ActiveKeyExchange>> {
+        RingKeyExchange::start_with_rng(group, &ring::rand::SystemRandom::new())
+    }
+}
+
+impl RingKeyExchange {
+    /// Like `start`, but drawing the private key from `rng`.  Tests use
+    /// this with one of *ring*'s fixed sources, to reproduce published
+    /// key shares.
+    pub(crate) fn start_with_rng(
+        group: &'static SupportedKxGroup,
+        rng: &dyn ring::rand::SecureRandom,
+    ) -> Option<Box<dyn ActiveKeyExchange>> {
+        let privkey = ring::agreement::EphemeralPrivateKey::generate(group.agreement_algorithm, rng).ok()?;
+        let pubkey = privkey.compute_public_key().ok()?;
+
+        Some(Box::new(RingActiveKeyExchange {
+            alg: group.agreement_algorithm,
+            privkey,
+            pubkey,
+        }))
+    }
+}
+
+struct RingActiveKeyExchange {
+    alg: &'static ring::agreement::Algorithm,
+    privkey: ring::agreement::EphemeralPrivateKey,
+    pubkey: ring::agreement::PublicKey,
+}
+
+impl ActiveKeyExchange for RingActiveKeyExchange {
+    fn pub_key(&self) -> &[u8] {
+        self.pubkey.as_ref()
+    }
+
+    fn complete(self: Box<Self>, peer_pub_key: &[u8]) -> Option<Vec<u8>> {
+        let peer_key = ring::agreement::UnparsedPublicKey::new(self.alg, peer_pub_key);
+        ring::agreement::agree_ephemeral(self.privkey, &peer_key, (), |v| Ok(Vec::from(v)))
+            .ok()
+    }
+}
+
 /// An in-progress key exchange.  This has the algorithm,
 /// our private key, and our public key.
 pub struct KeyExchange {
     skxg: &'static SupportedKxGroup,
-    privkey: ring::agreement::EphemeralPrivateKey,
-    pub pubkey: ring::agreement::PublicKey,
+    active: Box<dyn ActiveKeyExchange>,
+    pub pubkey: Vec<u8>,
 }
 
 impl KeyExchange {
@@ -25,12 +100,14 @@ impl KeyExchange {
     /// From a TLS1.2 client's point of view, start a key exchange: `kx_params` is the server's ServerECDHParams
     /// saying which group to use and the server's public key.  `supported` is the list of
     /// supported key exchange groups.
-    pub fn client_ecdhe(kx_params: &[u8], supported: &[&'static SupportedKxGroup]) -> Option<KeyExchangeResult> {
+    #[cfg(feature = "tls12")]
+    pub fn client_ecdhe(kx_params: &[u8], supported: &[&'static SupportedKxGroup],
+                        provider: &dyn ProvidesKeyExchange) -> Option<KeyExchangeResult> {
         let mut rd = Reader::init(kx_params);
         let ecdh_params = ServerECDHParams::read(&mut rd)?;
 
         KeyExchange::choose(ecdh_params.curve_params.named_group, supported)
-            .and_then(KeyExchange::start)
+            .and_then(|skxg| KeyExchange::start(skxg, provider))
             .and_then(|kx| kx.complete(&ecdh_params.public.0))
     }
 
@@ -44,25 +121,26 @@ impl KeyExchange {
 
     /// Start a key exchange, using the given SupportedKxGroup.
     ///
-    /// This generates an ephemeral key pair and stores it in the returned KeyExchange object.
-    pub fn start(skxg: &'static SupportedKxGroup) -> Option<KeyExchange> {
-        let rng = ring::rand::SystemRandom::new();
-        let ours = ring::agreement::EphemeralPrivateKey::generate(skxg.agreement_algorithm, &rng).unwrap();
-
-        let pubkey = ours.compute_public_key().unwrap();
+    /// `provider` generates an ephemeral key pair, which is stored in the
+    /// returned KeyExchange object.
+    pub fn start(skxg: &'static SupportedKxGroup, provider: &dyn ProvidesKeyExchange) -> Option<KeyExchange> {
+        let active = provider.start(skxg)?;
+        let pubkey = Vec::from(active.pub_key());
 
         Some(KeyExchange {
             skxg,
-            privkey: ours,
+            active,
             pubkey,
         })
     }
 
     /// Return the group being used.
+    #[cfg(feature = "tls13")]
     pub fn group(&self) -> NamedGroup {
         self.skxg.name
     }
 
+    #[cfg(feature = "tls12")]
     fn decode_client_params(&self, kx_params: &[u8]) -> Option<ClientECDHParams> {
         let mut rd = Reader::init(kx_params);
         let ecdh_params = ClientECDHParams::read(&mut rd).unwrap();
@@ -75,6 +153,7 @@ impl KeyExchange {
 
     /// Complete the server-side computation, by decoding the client's ClientECDHParams
     /// and then using the contained public key to invoke complete().
+    #[cfg(feature = "tls12")]
     pub fn server_complete(self, kx_params: &[u8]) -> Option<KeyExchangeResult> {
         self.decode_client_params(kx_params)
             .and_then(|ecdh| self.complete(&ecdh.public.0))
@@ -83,14 +162,15 @@ impl KeyExchange {
     /// Completes the key exchange, given the peer's public key.  The shared
     /// secret is returned as a KeyExchangeResult.
     pub fn complete(self, peer: &[u8]) -> Option<KeyExchangeResult> {
-        let peer_key = ring::agreement::UnparsedPublicKey::new(self.skxg.agreement_algorithm, peer);
+        let group = self.skxg;
         let pubkey = self.pubkey;
-        ring::agreement::agree_ephemeral(self.privkey, &peer_key, (), move |v| {
-            Ok(KeyExchangeResult {
+        self.active
+            .complete(peer)
+            .map(|shared_secret| KeyExchangeResult {
+                group,
                 pubkey,
-                shared_secret: Vec::from(v),
+                shared_secret,
             })
-        }).ok()
     }
 }
 
@@ -26,11 +26,13 @@
 //! * OCSP stapling by servers.
 //! * SCT stapling by servers.
 //! * SCT verification by clients.
+//! * OCSP verification by clients.
+//! * Certificate revocation list (CRL) checking by clients and servers.
+//! * Connecting to servers by IP address, validating iPAddress SANs.
 //!
 //! ## Possible future features
 //!
 //! * PSK support.
-//! * OCSP verification by clients.
 //! * Certificate pinning.
 //!
 //! ## Non-features
@@ -106,10 +108,13 @@
 //! and use it for all connections made by that process.
 //!
 //! ```rust,ignore
-//! let config = rustls::ClientConfig::new(
-//!     root_store,
-//!     trusted_ct_logs,
-//!     rustls::DEFAULT_CIPHERSUITES);
+//! let config = rustls::ClientConfig::builder()
+//!     .with_safe_default_cipher_suites()
+//!     .with_safe_default_kx_groups()
+//!     .with_safe_default_protocol_versions()
+//!     .unwrap()
+//!     .with_root_certificates(root_store, trusted_ct_logs)
+//!     .with_no_client_auth();
 //! ```
 //!
 //! Now we can make a session.  You need to provide the server's hostname so we
@@ -117,17 +122,20 @@
 //!
 //! ```no_run
 //! # use rustls;
-//! # use webpki;
+//! # use std::convert::TryInto;
 //! # use std::sync::Arc;
 //! # let mut root_store = rustls::RootCertStore::empty();
 //! # root_store.add_server_trust_anchors(&webpki_roots::TLS_SERVER_ROOTS);
 //! # let trusted_ct_logs = &[];
-//! # let config = rustls::ClientConfig::new(
-//! #     root_store,
-//! #     trusted_ct_logs,
-//! #     rustls::DEFAULT_CIPHERSUITES);
+//! # let config = rustls::ClientConfig::builder()
+//! #     .with_safe_default_cipher_suites()
+//! #     .with_safe_default_kx_groups()
+//! #     .with_safe_default_protocol_versions()
+//! #     .unwrap()
+//! #     .with_root_certificates(root_store, trusted_ct_logs)
+//! #     .with_no_client_auth();
 //! let rc_config = Arc::new(config);
-//! let example_com = webpki::DNSNameRef::try_from_ascii_str("example.com").unwrap();
+//! let example_com = "example.com".try_into().unwrap();
 //! let mut client = rustls::ClientSession::new(&rc_config, example_com);
 //! ```
 //!
@@ -142,7 +150,7 @@
 //! therefore call `client.process_new_packets()` which parses and processes the messages.
 //! Any error returned from `process_new_packets` is fatal to the session, and will tell you
 //! why.  For example, if the server's certificate is expired `process_new_packets` will
-//! return `Err(WebPKIError(CertExpired))`.  From this point on, `process_new_packets` will
+//! return `Err(InvalidCertificate(Expired))`.  From this point on, `process_new_packets` will
 //! not do any new work and will return that error continually.
 //!
 //! You can extract newly received data by calling `client.read()` (via the `io::Read`
@@ -190,19 +198,80 @@
 //!   messages do not contain secret key data, and so are safe to archive without
 //!   affecting session security.  This feature is in the default set.
 //!
+//!   Without this feature, every log statement is compiled out, along with
+//!   its format string and arguments, and rustls otherwise behaves exactly
+//!   the same.  Where binary size matters, for example on embedded targets,
+//!   turn off the default features and enable just `tls12` and/or `tls13`.
+//!
 //! - `dangerous_configuration`: this feature enables a `dangerous()` method on
 //!   `ClientConfig` and `ServerConfig` that allows setting inadvisable options,
 //!   such as replacing the certificate verification process.  Applications
 //!   requesting this feature should be reviewed carefully.
 //!
+//! - `secret_extraction`: this feature enables a `dangerous_extract_secrets()`
+//!   method on `ClientSession` and `ServerSession`, which hands over the
+//!   negotiated traffic keys after the handshake -- for example, to offload
+//!   record protection to the kernel with kTLS.  Applications requesting this
+//!   feature should be reviewed carefully.
+//!
+//! - `ktls`: this feature exposes the `ktls` module on Linux, which
+//!   configures a socket for kernel TLS offload using secrets from
+//!   `dangerous_extract_secrets()`.  It implies `secret_extraction`.
+//!
+//! - `session_export`: this feature enables `ServerSession::dangerous_export()`
+//!   and `ServerSession::dangerous_import()`, which move an established
+//!   session into another process -- for example, across a zero-downtime
+//!   restart.  Only server sessions can be exported: there is no
+//!   equivalent for `ClientSession`.  The exported state includes the
+//!   session's secrets, so applications requesting this feature should
+//!   be reviewed carefully.  It implies `secret_extraction`.
+//!
 //! - `quic`: this feature exposes additional constructors and functions
 //!   for using rustls as a TLS library for QUIC.  See the `quic` module for
 //!   details of these.  You will only need this if you're writing a QUIC
-//!   implementation.
+//!   implementation.  It implies `tls13`.
+//!
+//! - `dtls`: this feature exposes the `dtls` module, for running DTLS1.3
+//!   over a datagram transport.  It implies `quic`, whose handshake-only
+//!   mode it builds on.
+//!
+//! - `pkcs11`: this feature exposes the `pkcs11` module, for signing with
+//!   private keys held in an HSM or other PKCS#11 token.
+//!
+//! - `encrypted_keys`: this feature exposes the `encrypted_keys` module,
+//!   for decrypting password-protected PKCS#8 private keys.  It makes
+//!   the rustls crate depend on the `aes` and `cbc` crates.
+//!
+//! - `pkcs12`: this feature exposes the `pkcs12` module, for loading a
+//!   certificate chain and private key from a `.p12`/`.pfx` archive.
+//!   It implies `encrypted_keys`.
+//!
+//! - `metrics`: this feature exposes the `metrics` module, and gives
+//!   `ClientConfig` and `ServerConfig` a `metrics` member counting
+//!   handshakes, failures, resumptions and bytes encrypted by their
+//!   sessions.
+//!
+//! - `deterministic`: this feature exposes the `deterministic` module,
+//!   which makes handshakes reproducible byte-for-byte for regression
+//!   tests and differential fuzzing.  It is insecure by design: never
+//!   enable it outside tests.
+//!
+//! - `tls12`: this feature enables support for TLS1.2.  Without it, the
+//!   TLS1.2 handshake state machines, record protection and cipher suites
+//!   are left out of the build, and only TLS1.3 can be negotiated.  This
+//!   feature is in the default set.
+//!
+//! - `tls13`: this feature enables support for TLS1.3.  Without it, the
+//!   TLS1.3 handshake state machines, key schedule and cipher suites are
+//!   left out of the build, and only TLS1.2 can be negotiated.  This
+//!   feature is in the default set.
 //!
 
 // Require docs for public APIs, deny unsafe code, etc.
-#![forbid(unsafe_code, unused_must_use, unstable_features)]
+#![forbid(unused_must_use, unstable_features)]
+// The `ktls` feature needs one `setsockopt` call; nothing else may use unsafe.
+#![cfg_attr(not(feature = "ktls"), forbid(unsafe_code))]
+#![cfg_attr(feature = "ktls", deny(unsafe_code))]
 #![deny(
     trivial_casts,
     trivial_numeric_casts,
@@ -238,7 +307,9 @@ mod anchors;
 mod cipher;
 mod error;
 mod hash_hs;
+#[cfg(feature = "tls13")]
 mod key_schedule;
+#[cfg(feature = "tls12")]
 mod prf;
 mod rand;
 mod record_layer;
@@ -252,18 +323,37 @@ mod x509;
 #[macro_use]
 mod check;
 mod bs_debug;
+mod bufpool;
+mod builder;
 mod client;
 mod key;
 mod keylog;
+mod ocsp;
+mod crl;
+mod events;
+mod server_name;
 mod server;
 mod suites;
 mod kx;
 mod ticketer;
+mod time;
+mod versions;
 
 /// Internal classes which may be useful outside the library.
 /// The contents of this section DO NOT form part of the stable interface.
 pub mod internal {
     /// Low-level TLS message parsing and encoding functions.
+    ///
+    /// These are the codecs rustls itself uses, for protocol analyzers,
+    /// test harnesses and fuzzers.  Decode a record with
+    /// `message::Message::read` and `codec::Reader`, then
+    /// `Message::decode_payload` to parse its handshake, alert or other
+    /// payload; build messages from the types in `handshake` and friends,
+    /// and write them out with `Codec::encode`.
+    ///
+    /// This needs no cargo feature.  Our own tests, fuzzers and the
+    /// bogo shim use it, as do tools outside this repository, so it
+    /// isn't put behind one.
     pub mod msgs {
         pub use crate::msgs::*;
     }
@@ -271,43 +361,81 @@ pub mod internal {
 
 // The public interface is:
 pub use crate::anchors::{DistinguishedNames, OwnedTrustAnchor, RootCertStore};
+pub use crate::bufpool::BufferPool;
+pub use crate::builder::{
+    ConfigBuilder, Profile, WantsCipherSuites, WantsClientCert, WantsKxGroups, WantsServerCert,
+    WantsVerifier, WantsVersions,
+};
 pub use crate::client::handy::{ClientSessionMemoryCache, NoClientSessionStorage};
+pub use crate::client::handy::ResolvesClientCertUsingIssuers;
 pub use crate::client::ResolvesClientCert;
 pub use crate::client::StoresClientSessions;
 pub use crate::client::{ClientConfig, ClientSession, WriteEarlyData};
-pub use crate::error::TlsError;
-pub use crate::error::WebPKIOp;
+pub use crate::crl::{CrlSet, ExpiredCrlPolicy, UnknownStatusPolicy};
+pub use crate::events::ConnectionEvents;
+pub use crate::error::{CertificateError, CrlError, OcspError, PeerMisbehaved, TlsError};
 pub use crate::key::{Certificate, PrivateKey};
+#[cfg(feature = "tls13")]
+pub use crate::key_schedule::ExporterSecret;
 pub use crate::keylog::{KeyLog, KeyLogFile, NoKeyLog};
+pub use crate::msgs::enums::AlertDescription;
 pub use crate::msgs::enums::CipherSuite;
+pub use crate::msgs::enums::NamedGroup;
 pub use crate::msgs::enums::ProtocolVersion;
 pub use crate::msgs::enums::SignatureScheme;
+pub use crate::msgs::enums::SRTPProtectionProfile;
+pub use crate::server::handy::OcspResponseCache;
+pub use crate::server::handy::ReloadableResolvesChain;
 pub use crate::server::handy::ResolvesServerCertUsingSni;
 pub use crate::server::handy::{NoServerSessionStorage, ServerSessionMemoryCache};
-pub use crate::server::StoresServerSessions;
-pub use crate::server::{ClientHello, ProducesTickets, ResolvesServerCert};
-pub use crate::server::{ServerConfig, ServerSession};
-pub use crate::session::Session;
-pub use crate::stream::{Stream, StreamOwned};
-pub use crate::suites::{BulkAlgorithm, SupportedCipherSuite, ALL_CIPHERSUITES, DEFAULT_CIPHERSUITES};
-pub use crate::kx::{SupportedKxGroup, ALL_KX_GROUPS};
-    pub use crate::ticketer::Ticketer;
+pub use crate::server::{SessionLookup, StoresServerSessions};
+pub use crate::server::{CertResolution, ClientHello, ProducesOcspResponses, ProducesTickets, ResolvesServerCert};
+pub use crate::server::{HandlesCustomExtensions, SelectsAlpnProtocol};
+pub use crate::server::{Acceptor, Accepted, ServerConfig, ServerSession, VirtualHost};
+pub use crate::server::{peek_client_hello, PeekedClientHello};
+pub use crate::server::ACME_TLS_ALPN_PROTOCOL;
+pub use crate::server_name::{DnsName, InvalidDnsNameError, ServerName};
+pub use crate::session::{HandshakeKind, RenegotiationPolicy, Session};
+pub use crate::stream::{split, ReadHalf, SharedSocket, Stream, StreamOwned, WriteHalf};
+pub use crate::suites::{
+    default_ciphersuites, BulkAlgorithm, SupportedCipherSuite, ALL_CIPHERSUITES, DEFAULT_CIPHERSUITES,
+};
+#[cfg(feature = "secret_extraction")]
+#[cfg_attr(docsrs, doc(cfg(feature = "secret_extraction")))]
+pub use crate::suites::{ConnectionTrafficSecrets, ExtractedSecrets};
+pub use crate::kx::{ActiveKeyExchange, ProvidesKeyExchange, RingKeyExchange, SupportedKxGroup, ALL_KX_GROUPS};
+pub use crate::rand::{GetRandomFailed, ProvidesRandom, RingRandom};
+pub use crate::time::{SystemTimeProvider, TimeProvider};
+    pub use crate::ticketer::{ProducesTicketKeys, TicketCipher, TicketKey, TicketSwitcher, Ticketer};
 pub use crate::verify::{
-    AllowAnyAnonymousOrAuthenticatedClient, AllowAnyAuthenticatedClient, NoClientAuth,
+    AllowAnyAnonymousOrAuthenticatedClient, AllowAnyAuthenticatedClient, NoClientAuth, ValidatedSct,
 };
+pub use crate::verify::ClientCertVerifierUsingSni;
+pub use crate::verify::{NoRevocationChecking, RevocationChecker};
+pub use crate::verify::{ServerCertInfo, ServerCertPolicy};
+pub use crate::versions::{SupportedProtocolVersion, ALL_VERSIONS, DEFAULT_VERSIONS};
 
 /// All defined ciphersuites appear in this module.
 ///
 /// ALL_CIPHERSUITES is provided as an array of all of these values.
 pub mod ciphersuite {
+    #[cfg(feature = "tls13")]
     pub use crate::suites::TLS13_AES_128_GCM_SHA256;
+    #[cfg(feature = "tls13")]
     pub use crate::suites::TLS13_AES_256_GCM_SHA384;
+    #[cfg(feature = "tls13")]
     pub use crate::suites::TLS13_CHACHA20_POLY1305_SHA256;
+    #[cfg(feature = "tls12")]
     pub use crate::suites::TLS_ECDHE_ECDSA_WITH_AES_128_GCM_SHA256;
+    #[cfg(feature = "tls12")]
     pub use crate::suites::TLS_ECDHE_ECDSA_WITH_AES_256_GCM_SHA384;
+    #[cfg(feature = "tls12")]
     pub use crate::suites::TLS_ECDHE_ECDSA_WITH_CHACHA20_POLY1305_SHA256;
+    #[cfg(feature = "tls12")]
     pub use crate::suites::TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256;
+    #[cfg(feature = "tls12")]
     pub use crate::suites::TLS_ECDHE_RSA_WITH_AES_256_GCM_SHA384;
+    #[cfg(feature = "tls12")]
     pub use crate::suites::TLS_ECDHE_RSA_WITH_CHACHA20_POLY1305_SHA256;
 }
 
@@ -320,14 +448,54 @@ pub mod kx_group {
     pub use crate::kx::X25519;
 }
 
+/// All defined protocol versions appear in this module.
+///
+/// ALL_VERSIONS is provided as an array of all of these values.
+pub mod version {
+    #[cfg(feature = "tls12")]
+    pub use crate::versions::TLS12;
+    #[cfg(feature = "tls13")]
+    pub use crate::versions::TLS13;
+}
+
 /// Message signing interfaces and implementations.
 pub mod sign;
 
+#[cfg(feature = "pkcs11")]
+#[cfg_attr(docsrs, doc(cfg(feature = "pkcs11")))]
+pub mod pkcs11;
+
+#[cfg(feature = "encrypted_keys")]
+#[cfg_attr(docsrs, doc(cfg(feature = "encrypted_keys")))]
+pub mod encrypted_keys;
+
+#[cfg(feature = "pkcs12")]
+#[cfg_attr(docsrs, doc(cfg(feature = "pkcs12")))]
+pub mod pkcs12;
+
+#[cfg(feature = "metrics")]
+#[cfg_attr(docsrs, doc(cfg(feature = "metrics")))]
+pub mod metrics;
+
+pub mod unbuffered;
+
 #[cfg(feature = "quic")]
 #[cfg_attr(docsrs, doc(cfg(feature = "quic")))]
 /// APIs for implementing QUIC TLS
 pub mod quic;
 
+#[cfg(all(feature = "ktls", target_os = "linux"))]
+#[cfg_attr(docsrs, doc(cfg(all(feature = "ktls", target_os = "linux"))))]
+pub mod ktls;
+
+#[cfg(feature = "dtls")]
+#[cfg_attr(docsrs, doc(cfg(feature = "dtls")))]
+pub mod dtls;
+
+#[cfg(feature = "deterministic")]
+#[cfg_attr(docsrs, doc(cfg(feature = "deterministic")))]
+pub mod deterministic;
+
 #[cfg(not(feature = "quic"))]
 // If QUIC support is disabled, just define a private module with an empty
 // trait to allow Session having QuicExt as a trait bound.
@@ -118,8 +118,9 @@ rustls naturally does not support SSLv2, but most importantly does not support R
 is an attack against CBC mode ciphersuites in SSLv3.  This was possible in most cases because some clients willingly
 downgraded to SSLv3 after failed handshakes for later versions.
 
-rustls does not support CBC mode ciphersuites, or SSLv3.  Note that rustls does not need to implement `TLS_FALLBACK_SCSV`
-introduced as a countermeasure because it contains no ability to downgrade to earlier protocol versions.
+rustls does not support CBC mode ciphersuites, or SSLv3.  Note that the rustls client does not need to send `TLS_FALLBACK_SCSV`,
+introduced as a countermeasure, because it contains no ability to downgrade to earlier protocol versions.  The rustls
+server honours it, refusing clients which fall back to TLSv1.2 when TLSv1.3 is enabled, to protect clients that do.
 
 ## GCM nonces
 
@@ -0,0 +1,152 @@
+//! Counters of what sessions have done, for exporting to a
+//! monitoring system.
+//!
+//! Each `ClientConfig` and `ServerConfig` has a `metrics` member,
+//! which all sessions made from that config count into.  Call
+//! `Metrics::snapshot` to read the counters, for example when a
+//! Prometheus exporter is scraped.  To count several configs
+//! together, point their `metrics` at the same `Metrics`.
+//!
+//! The counters only ever increase, and wrap on overflow.
+
+use crate::error::TlsError;
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+#[derive(Debug, Default)]
+pub(crate) struct Counter(AtomicU64);
+
+impl Counter {
+    pub(crate) fn incr(&self) {
+        self.add(1);
+    }
+
+    pub(crate) fn add(&self, n: usize) {
+        self.0.fetch_add(n as u64, Ordering::Relaxed);
+    }
+
+    fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// Counters shared by the sessions made from a config.
+#[derive(Debug, Default)]
+pub struct Metrics {
+    pub(crate) handshakes_completed: Counter,
+    failed_alert_received: Counter,
+    failed_invalid_certificate: Counter,
+    failed_peer_incompatible: Counter,
+    failed_peer_misbehaved: Counter,
+    failed_corrupt_message: Counter,
+    failed_other: Counter,
+    pub(crate) resumptions: Counter,
+    pub(crate) early_data_accepted: Counter,
+    pub(crate) early_data_rejected: Counter,
+    pub(crate) bytes_encrypted: Counter,
+}
+
+impl Metrics {
+    /// Make a new set of counters, all zero.
+    pub fn new() -> Arc<Metrics> {
+        Arc::new(Metrics::default())
+    }
+
+    /// Read the counters.  Sessions may be counting at the same
+    /// time, so the values needn't be consistent with each other.
+    pub fn snapshot(&self) -> MetricsSnapshot {
+        MetricsSnapshot {
+            handshakes_completed: self.handshakes_completed.get(),
+            handshakes_failed: HandshakeFailures {
+                alert_received: self.failed_alert_received.get(),
+                invalid_certificate: self.failed_invalid_certificate.get(),
+                peer_incompatible: self.failed_peer_incompatible.get(),
+                peer_misbehaved: self.failed_peer_misbehaved.get(),
+                corrupt_message: self.failed_corrupt_message.get(),
+                other: self.failed_other.get(),
+            },
+            resumptions: self.resumptions.get(),
+            early_data_accepted: self.early_data_accepted.get(),
+            early_data_rejected: self.early_data_rejected.get(),
+            bytes_encrypted: self.bytes_encrypted.get(),
+        }
+    }
+
+    /// Count a handshake that failed with `err`.
+    pub(crate) fn handshake_failed(&self, err: &TlsError) {
+        let counter = match err {
+            TlsError::AlertReceived { .. } => &self.failed_alert_received,
+            TlsError::InvalidCertificate(_)
+            | TlsError::NoCertificatesPresented
+            | TlsError::InvalidSCT(_)
+            | TlsError::InvalidOCSPResponse(_)
+            | TlsError::CRLError(_) => &self.failed_invalid_certificate,
+            TlsError::PeerIncompatibleError(_) | TlsError::NoApplicationProtocol => {
+                &self.failed_peer_incompatible
+            }
+            TlsError::PeerMisbehavedError(_)
+            | TlsError::InappropriateMessage { .. }
+            | TlsError::InappropriateHandshakeMessage { .. } => &self.failed_peer_misbehaved,
+            TlsError::CorruptMessage
+            | TlsError::CorruptMessagePayload(_)
+            | TlsError::DecryptError
+            | TlsError::PeerSentOversizedRecord => &self.failed_corrupt_message,
+            _ => &self.failed_other,
+        };
+        counter.incr();
+    }
+}
+
+/// The values of a `Metrics`' counters at one time.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct MetricsSnapshot {
+    /// Handshakes which completed, including resumptions.
+    pub handshakes_completed: u64,
+
+    /// Handshakes which failed, by cause.
+    pub handshakes_failed: HandshakeFailures,
+
+    /// Handshakes which resumed a previous session.
+    pub resumptions: u64,
+
+    /// Handshakes in which the server accepted early data.
+    pub early_data_accepted: u64,
+
+    /// Handshakes in which the client sent early data, but the
+    /// server rejected it.
+    pub early_data_rejected: u64,
+
+    /// Bytes of plaintext encrypted into TLS records: application
+    /// data, plus encrypted handshake messages and alerts.
+    pub bytes_encrypted: u64,
+}
+
+/// How many handshakes failed for each cause.
+///
+/// A handshake counts as failed when `process_new_packets` returns
+/// an error before it completes.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct HandshakeFailures {
+    /// The peer sent a fatal alert: `TlsError::AlertReceived`.
+    pub alert_received: u64,
+
+    /// The peer's certificate wasn't acceptable, or it didn't send
+    /// one: `TlsError::InvalidCertificate` and similar.
+    pub invalid_certificate: u64,
+
+    /// The peer doesn't support anything we're configured to
+    /// use: `TlsError::PeerIncompatibleError` and
+    /// `TlsError::NoApplicationProtocol`.
+    pub peer_incompatible: u64,
+
+    /// The peer broke the protocol: `TlsError::PeerMisbehavedError`
+    /// and the unexpected-message errors.
+    pub peer_misbehaved: u64,
+
+    /// The peer sent something we couldn't decode or decrypt.
+    pub corrupt_message: u64,
+
+    /// Any other error.
+    pub other: u64,
+}
@@ -1,14 +1,25 @@
+use std::cmp;
 use std::collections::VecDeque;
 use std::io;
+use std::mem;
+use std::sync::Arc;
 
+use crate::bufpool::BufferPool;
 use crate::msgs::codec;
-use crate::msgs::message::{Message, MessageError};
+use crate::msgs::enums::ContentType;
+use crate::msgs::message::{BorrowMessageMut, Message, MessageError};
 
 /// This deframer works to reconstruct TLS messages
 /// from arbitrary-sized reads, buffering as necessary.
-/// The input is `read()`, the output is the `frames` deque.
+///
+/// Whole messages stay in one fixed-size buffer until they are
+/// taken with `pop` or `pop_borrowed`, so a session's incoming
+/// data costs that buffer and nothing more.  The buffer is only
+/// taken when data arrives, and can be given up with
+/// `release_buffer` while it's empty.
 pub struct MessageDeframer {
-    /// Completed frames for output.
+    /// Messages which come before any in our buffer: those taken
+    /// straight from a `read_buf` reader, or given back.
     pub frames: VecDeque<Message>,
 
     /// Set to true if the peer is not talking TLS, but some other
@@ -16,24 +27,20 @@ pub struct MessageDeframer {
     /// the deframer cannot recover.
     pub desynced: bool,
 
-    /// A fixed-size buffer containing the currently-accumulating
-    /// TLS message.
-    buf: Box<[u8; Message::MAX_WIRE_SIZE]>,
+    /// A fixed-size buffer containing whole messages not yet
+    /// taken, followed perhaps by a partial one.  It is
+    /// `Message::MAX_WIRE_SIZE` bytes long, or empty if we
+    /// don't have one at the moment.
+    buf: Vec<u8>,
+
+    /// Where in `buf` the first message not yet taken starts.
+    start: usize,
 
     /// What size prefix of `buf` is used.
     used: usize,
-}
-
-enum BufferContents {
-    /// Contains an invalid message as a header.
-    Invalid,
-
-    /// Might contain a valid message if we receive more.
-    /// Perhaps totally empty!
-    Partial,
 
-    /// Contains a valid frame as a prefix.
-    Valid,
+    /// Where `buf` came from, and goes back to on drop.
+    pool: Option<Arc<BufferPool>>,
 }
 
 impl Default for MessageDeframer {
@@ -47,86 +54,261 @@ impl MessageDeframer {
         MessageDeframer {
             frames: VecDeque::new(),
             desynced: false,
-            buf: Box::new([0u8; Message::MAX_WIRE_SIZE]),
+            buf: Vec::new(),
+            start: 0,
             used: 0,
+            pool: None,
+        }
+    }
+
+    /// Like `new`, but take the buffer from `pool`, returning
+    /// it there when released or on drop.
+    pub fn new_pooled(pool: Arc<BufferPool>) -> MessageDeframer {
+        MessageDeframer {
+            frames: VecDeque::new(),
+            desynced: false,
+            buf: Vec::new(),
+            start: 0,
+            used: 0,
+            pool: Some(pool),
         }
     }
 
     /// Read some bytes from `rd`, and add them to our internal
-    /// buffer.  If this means our internal buffer contains
-    /// full messages, decode them all.
+    /// buffer.
     pub fn read(&mut self, rd: &mut dyn io::Read) -> io::Result<usize> {
         // Try to do the largest reads possible.  Note that if
         // we get a message with a length field out of range here,
         // we do a zero length read.  That looks like an EOF to
         // the next layer up, which is fine.
+        self.take_buffer();
+        self.make_room();
         debug_assert!(self.used <= Message::MAX_WIRE_SIZE);
         let new_bytes = rd.read(&mut self.buf[self.used..])?;
 
         self.used += new_bytes;
+        self.check_buffered();
+        Ok(new_bytes)
+    }
+
+    /// Like `read`, but parse whole messages straight out of `rd`'s
+    /// buffer.  Only a trailing partial message is copied into ours.
+    pub fn read_buf(&mut self, rd: &mut dyn io::BufRead) -> io::Result<usize> {
+        if self.used > self.start {
+            // Keep everything after what we already have.
+            self.make_room();
+            let available = rd.fill_buf()?;
+            let len = cmp::min(available.len(), self.buf.len() - self.used);
+            self.buf[self.used..self.used + len].copy_from_slice(&available[..len]);
+            rd.consume(len);
+
+            self.used += len;
+            self.check_buffered();
+            return Ok(len);
+        }
 
+        let available = rd.fill_buf()?;
+        let mut taken = 0;
         loop {
-            match self.try_deframe_one() {
-                BufferContents::Invalid => {
+            let mut msg_rd = codec::Reader::init(&available[taken..]);
+            match Message::read_with_detailed_error(&mut msg_rd) {
+                Ok(m) => {
+                    taken += msg_rd.used();
+                    self.frames.push_back(m);
+                }
+                Err(MessageError::TooShortForHeader) | Err(MessageError::TooShortForLength) => {
+                    // Keep the partial message.  It's shorter than
+                    // MAX_WIRE_SIZE, or its length would be invalid.
+                    self.take_buffer();
+                    let rest = &available[taken..];
+                    self.buf[..rest.len()].copy_from_slice(rest);
+                    self.start = 0;
+                    self.used = rest.len();
+                    taken = available.len();
+                    break;
+                }
+                Err(_) => {
                     self.desynced = true;
                     break;
                 }
-                BufferContents::Valid => continue,
-                BufferContents::Partial => break,
             }
         }
 
-        Ok(new_bytes)
+        rd.consume(taken);
+        Ok(taken)
     }
 
     /// Returns true if we have messages for the caller
     /// to process, either whole messages in our output
     /// queue or partial messages in our buffer.
     pub fn has_pending(&self) -> bool {
-        !self.frames.is_empty() || self.used > 0
+        !self.frames.is_empty() || self.used > self.start
+    }
+
+    /// Returns true if there's a whole message to take.
+    pub fn has_message(&self) -> bool {
+        !self.frames.is_empty() || self.buffered_len(self.start).is_some()
+    }
+
+    /// Take the next whole message, if there is one.
+    pub fn pop(&mut self) -> Option<Message> {
+        match self.frames.pop_front() {
+            Some(m) => Some(m),
+            None => self.pop_buffered(),
+        }
+    }
+
+    /// The content type of the next message, if it is whole and
+    /// can be taken with `pop_borrowed`.
+    pub fn peek_borrowable(&self) -> Option<ContentType> {
+        if !self.frames.is_empty() {
+            return None;
+        }
+
+        self.buffered_len(self.start)?;
+        let mut rd = codec::Reader::init(&self.buf[self.start..self.used]);
+        Message::read_header(&mut rd)
+            .ok()
+            .map(|(typ, _, _)| typ)
+    }
+
+    /// Like `pop`, but return a view of the message in our buffer
+    /// rather than copying it out.  This fails if `peek_borrowable`
+    /// does.
+    pub fn pop_borrowed(&mut self) -> Option<BorrowMessageMut<'_>> {
+        self.peek_borrowable()?;
+
+        let mut rd = codec::Reader::init(&self.buf[self.start..self.used]);
+        let (typ, version, len) = Message::read_header(&mut rd).ok()?;
+        let payload_start = self.start + rd.used();
+        let payload_end = payload_start + len as usize;
+        self.take_buffered(payload_end - self.start);
+
+        Some(BorrowMessageMut {
+            typ,
+            version,
+            payload: &mut self.buf[payload_start..payload_end],
+        })
     }
 
-    /// Does our `buf` contain a full message?  It does if it is big enough to
-    /// contain a header, and that header has a length which falls within `buf`.
-    /// If so, deframe it and place the message onto the frames output queue.
-    fn try_deframe_one(&mut self) -> BufferContents {
-        // Try to decode a message off the front of buf.
-        let mut rd = codec::Reader::init(&self.buf[..self.used]);
+    /// How many bytes are held in our buffer, including any
+    /// partial message.
+    pub fn buffered(&self) -> usize {
+        self.used - self.start
+    }
+
+    /// The length of the whole message starting at `offset` in our
+    /// buffer, or `None` if it hasn't all arrived or can't be valid.
+    fn buffered_len(&self, offset: usize) -> Option<usize> {
+        let mut rd = codec::Reader::init(&self.buf[offset..self.used]);
+        let (_, _, len) = Message::read_header(&mut rd).ok()?;
+        let total = rd.used() + len as usize;
+        if offset + total <= self.used {
+            Some(total)
+        } else {
+            None
+        }
+    }
+
+    /// Give up our buffer if nothing is in it, so a session that
+    /// isn't receiving anything doesn't hold one.  Another is taken
+    /// when more data arrives.
+    pub fn release_buffer(&mut self) {
+        if self.used > 0 || self.buf.is_empty() {
+            return;
+        }
+
+        let buf = mem::take(&mut self.buf);
+        if let Some(pool) = &self.pool {
+            pool.put_record_buffer(buf);
+        }
+    }
+
+    /// Get a buffer, if we don't have one.
+    fn take_buffer(&mut self) {
+        if !self.buf.is_empty() {
+            return;
+        }
+
+        self.buf = match &self.pool {
+            Some(pool) => pool.take_record_buffer(),
+            None => vec![0u8; Message::MAX_WIRE_SIZE],
+        };
+    }
+
+    /// Take the message at the front of our buffer, if it is whole.
+    fn pop_buffered(&mut self) -> Option<Message> {
+        let mut rd = codec::Reader::init(&self.buf[self.start..self.used]);
+        let m = Message::read_with_detailed_error(&mut rd).ok()?;
+        let used = rd.used();
+        self.take_buffered(used);
+        Some(m)
+    }
+
+    /// Mark `len` bytes from the front of our buffer as taken.
+    fn take_buffered(&mut self, len: usize) {
+        self.start += len;
+        if self.start == self.used {
+            self.start = 0;
+            self.used = 0;
+        }
+    }
 
-        match Message::read_with_detailed_error(&mut rd) {
-            Ok(m) => {
-                let used = rd.used();
+    /// Move what's left in our buffer to its front, so the rest is
+    /// free for reading into.  If it is full of whole messages the
+    /// caller hasn't taken yet, they are copied out to `frames`.
+    fn make_room(&mut self) {
+        if self.used == self.buf.len() && !self.desynced {
+            while let Some(m) = self.pop_buffered() {
                 self.frames.push_back(m);
-                self.buf_consume(used);
-                BufferContents::Valid
-            }
-            Err(MessageError::TooShortForHeader) | Err(MessageError::TooShortForLength) => {
-                BufferContents::Partial
             }
-            Err(_) => BufferContents::Invalid,
         }
-    }
 
-    fn buf_consume(&mut self, taken: usize) {
-        if taken < self.used {
+        if self.start > 0 {
             /* Before:
              * +----------+----------+----------+
              * | taken    | pending  |xxxxxxxxxx|
              * +----------+----------+----------+
-             * 0          ^ taken    ^ self.used
+             * 0          ^ start    ^ used
              *
              * After:
              * +----------+----------+----------+
              * | pending  |xxxxxxxxxxxxxxxxxxxxx|
              * +----------+----------+----------+
-             * 0          ^ self.used
+             * 0          ^ used
              */
 
             self.buf
-                .copy_within(taken..self.used, 0);
-            self.used = self.used - taken;
-        } else if taken == self.used {
-            self.used = 0;
+                .copy_within(self.start..self.used, 0);
+            self.used -= self.start;
+            self.start = 0;
+        }
+    }
+
+    /// Check the headers of the messages in our buffer, noting if
+    /// any are invalid.
+    fn check_buffered(&mut self) {
+        let mut offset = self.start;
+        while offset < self.used {
+            let mut rd = codec::Reader::init(&self.buf[offset..self.used]);
+            match Message::read_header(&mut rd) {
+                Ok((_, _, len)) => offset += rd.used() + len as usize,
+                Err(MessageError::TooShortForHeader) => break,
+                Err(_) => {
+                    self.desynced = true;
+                    break;
+                }
+            }
+        }
+    }
+}
+
+impl Drop for MessageDeframer {
+    fn drop(&mut self) {
+        if let Some(pool) = &self.pool {
+            if !self.buf.is_empty() {
+                pool.put_record_buffer(mem::take(&mut self.buf));
+            }
         }
     }
 }
@@ -134,6 +316,7 @@ impl MessageDeframer {
 #[cfg(test)]
 mod tests {
     use super::MessageDeframer;
+    use crate::bufpool::BufferPool;
     use crate::msgs;
     use std::io;
 
@@ -214,19 +397,30 @@ mod tests {
             .expect_err("error not propagated");
     }
 
+    /// How many whole messages `d` has for the taking.
+    fn whole_messages(d: &MessageDeframer) -> usize {
+        let mut count = d.frames.len();
+        let mut offset = d.start;
+        while let Some(len) = d.buffered_len(offset) {
+            offset += len;
+            count += 1;
+        }
+        count
+    }
+
     fn input_whole_incremental(d: &mut MessageDeframer, bytes: &[u8]) {
-        let frames_before = d.frames.len();
+        let frames_before = whole_messages(d);
 
         for i in 0..bytes.len() {
             assert_len(1, input_bytes(d, &bytes[i..i + 1]));
             assert_eq!(d.has_pending(), true);
 
             if i < bytes.len() - 1 {
-                assert_eq!(frames_before, d.frames.len());
+                assert_eq!(frames_before, whole_messages(d));
             }
         }
 
-        assert_eq!(frames_before + 1, d.frames.len());
+        assert_eq!(frames_before + 1, whole_messages(d));
     }
 
     fn assert_len(want: usize, got: io::Result<usize>) {
@@ -238,13 +432,13 @@ mod tests {
     }
 
     fn pop_first(d: &mut MessageDeframer) {
-        let mut m = d.frames.pop_front().unwrap();
+        let mut m = d.pop().unwrap();
         m.decode_payload();
         assert_eq!(m.typ, msgs::enums::ContentType::Handshake);
     }
 
     fn pop_second(d: &mut MessageDeframer) {
-        let mut m = d.frames.pop_front().unwrap();
+        let mut m = d.pop().unwrap();
         m.decode_payload();
         assert_eq!(m.typ, msgs::enums::ContentType::Alert);
     }
@@ -255,7 +449,7 @@ mod tests {
         assert_eq!(d.has_pending(), false);
         input_whole_incremental(&mut d, FIRST_MESSAGE);
         assert_eq!(d.has_pending(), true);
-        assert_eq!(1, d.frames.len());
+        assert_eq!(1, whole_messages(&d));
         pop_first(&mut d);
         assert_eq!(d.has_pending(), false);
     }
@@ -268,7 +462,7 @@ mod tests {
         assert_eq!(d.has_pending(), true);
         input_whole_incremental(&mut d, SECOND_MESSAGE);
         assert_eq!(d.has_pending(), true);
-        assert_eq!(2, d.frames.len());
+        assert_eq!(2, whole_messages(&d));
         pop_first(&mut d);
         assert_eq!(d.has_pending(), true);
         pop_second(&mut d);
@@ -281,7 +475,7 @@ mod tests {
         assert_eq!(d.has_pending(), false);
         assert_len(FIRST_MESSAGE.len(), input_bytes(&mut d, FIRST_MESSAGE));
         assert_eq!(d.has_pending(), true);
-        assert_eq!(d.frames.len(), 1);
+        assert_eq!(whole_messages(&d), 1);
         pop_first(&mut d);
         assert_eq!(d.has_pending(), false);
     }
@@ -292,7 +486,7 @@ mod tests {
         assert_eq!(d.has_pending(), false);
         assert_len(FIRST_MESSAGE.len(), input_bytes(&mut d, FIRST_MESSAGE));
         assert_len(SECOND_MESSAGE.len(), input_bytes(&mut d, SECOND_MESSAGE));
-        assert_eq!(d.frames.len(), 2);
+        assert_eq!(whole_messages(&d), 2);
         pop_first(&mut d);
         pop_second(&mut d);
         assert_eq!(d.has_pending(), false);
@@ -306,12 +500,53 @@ mod tests {
             FIRST_MESSAGE.len() + SECOND_MESSAGE.len(),
             input_bytes_concat(&mut d, FIRST_MESSAGE, SECOND_MESSAGE),
         );
-        assert_eq!(d.frames.len(), 2);
+        assert_eq!(whole_messages(&d), 2);
+        pop_first(&mut d);
+        pop_second(&mut d);
+        assert_eq!(d.has_pending(), false);
+    }
+
+    #[test]
+    fn test_two_in_one_read_buf() {
+        let mut d = MessageDeframer::new();
+        let mut bytes = FIRST_MESSAGE.to_vec();
+        bytes.extend_from_slice(SECOND_MESSAGE);
+
+        let mut rd = &bytes[..];
+        assert_len(bytes.len(), d.read_buf(&mut rd));
+        assert!(rd.is_empty());
+        assert_eq!(whole_messages(&d), 2);
         pop_first(&mut d);
         pop_second(&mut d);
         assert_eq!(d.has_pending(), false);
     }
 
+    #[test]
+    fn test_read_buf_keeps_partial_message() {
+        let mut d = MessageDeframer::new();
+        let mut bytes = FIRST_MESSAGE.to_vec();
+        bytes.extend_from_slice(&SECOND_MESSAGE[..3]);
+
+        assert_len(bytes.len(), d.read_buf(&mut &bytes[..]));
+        assert_eq!(whole_messages(&d), 1);
+        assert_eq!(d.has_pending(), true);
+
+        let mut rest = &SECOND_MESSAGE[3..];
+        assert_len(rest.len(), d.read_buf(&mut rest));
+        assert_eq!(whole_messages(&d), 2);
+        pop_first(&mut d);
+        pop_second(&mut d);
+        assert_eq!(d.has_pending(), false);
+    }
+
+    #[test]
+    fn test_read_buf_invalid() {
+        let mut d = MessageDeframer::new();
+        let bytes = [0xff, 0x03, 0x03, 0x00, 0x01, 0x00];
+        d.read_buf(&mut &bytes[..]).unwrap();
+        assert_eq!(d.desynced, true);
+    }
+
     #[test]
     fn test_two_in_one_read_shortest_first() {
         let mut d = MessageDeframer::new();
@@ -320,12 +555,76 @@ mod tests {
             FIRST_MESSAGE.len() + SECOND_MESSAGE.len(),
             input_bytes_concat(&mut d, SECOND_MESSAGE, FIRST_MESSAGE),
         );
-        assert_eq!(d.frames.len(), 2);
+        assert_eq!(whole_messages(&d), 2);
         pop_second(&mut d);
         pop_first(&mut d);
         assert_eq!(d.has_pending(), false);
     }
 
+    #[test]
+    fn test_pop_borrowed() {
+        let mut d = MessageDeframer::new();
+        assert_eq!(d.peek_borrowable(), None);
+        input_bytes_concat(&mut d, FIRST_MESSAGE, SECOND_MESSAGE).unwrap();
+        assert_eq!(
+            d.peek_borrowable(),
+            Some(msgs::enums::ContentType::Handshake)
+        );
+
+        let m = d.pop_borrowed().unwrap();
+        assert_eq!(m.typ, msgs::enums::ContentType::Handshake);
+        assert_eq!(m.payload, &FIRST_MESSAGE[5..]);
+        assert!(d.has_pending());
+        pop_second(&mut d);
+        assert!(!d.has_pending());
+    }
+
+    #[test]
+    fn test_full_buffer_moves_messages_to_frames() {
+        let mut d = MessageDeframer::new();
+        let count = msgs::message::Message::MAX_WIRE_SIZE / SECOND_MESSAGE.len() + 1;
+        let bytes = SECOND_MESSAGE.repeat(count);
+
+        let mut rd = ByteRead::new(&bytes);
+        while d.read(&mut rd).unwrap() > 0 {}
+        assert!(!d.desynced);
+        assert!(!d.frames.is_empty());
+        assert_eq!(d.peek_borrowable(), None);
+        assert_eq!(whole_messages(&d), count);
+
+        for _ in 0..count {
+            pop_second(&mut d);
+        }
+        assert!(!d.has_pending());
+    }
+
+    #[test]
+    fn test_release_buffer() {
+        let pool = BufferPool::new(1);
+        let mut d = MessageDeframer::new_pooled(pool.clone());
+        assert_eq!(pool.idle_buffers(), 0);
+
+        // Not while a partial message is buffered.
+        assert_len(3, input_bytes(&mut d, &FIRST_MESSAGE[..3]));
+        d.release_buffer();
+        assert_eq!(pool.idle_buffers(), 0);
+
+        assert_len(
+            FIRST_MESSAGE.len() - 3,
+            input_bytes(&mut d, &FIRST_MESSAGE[3..]),
+        );
+        pop_first(&mut d);
+        d.release_buffer();
+        assert_eq!(pool.idle_buffers(), 1);
+
+        // A buffer is taken again when needed.
+        assert_len(SECOND_MESSAGE.len(), input_bytes(&mut d, SECOND_MESSAGE));
+        assert_eq!(pool.idle_buffers(), 0);
+        pop_second(&mut d);
+        drop(d);
+        assert_eq!(pool.idle_buffers(), 1);
+    }
+
     #[test]
     fn test_incremental_with_nonfatal_read_error() {
         let mut d = MessageDeframer::new();
@@ -335,7 +634,7 @@ mod tests {
             FIRST_MESSAGE.len() - 3,
             input_bytes(&mut d, &FIRST_MESSAGE[3..]),
         );
-        assert_eq!(d.frames.len(), 1);
+        assert_eq!(whole_messages(&d), 1);
         pop_first(&mut d);
         assert_eq!(d.has_pending(), false);
     }
@@ -13,7 +13,10 @@ enum_builder! {
         TLSv1_0 => 0x0301,
         TLSv1_1 => 0x0302,
         TLSv1_2 => 0x0303,
-        TLSv1_3 => 0x0304
+        TLSv1_3 => 0x0304,
+        DTLSv1_0 => 0xfeff,
+        DTLSv1_2 => 0xfefd,
+        DTLSv1_3 => 0xfefc
     }
 }
 
@@ -94,7 +97,8 @@ enum_builder! {
         Alert => 0x15,
         Handshake => 0x16,
         ApplicationData => 0x17,
-        Heartbeat => 0x18
+        Heartbeat => 0x18,
+        Ack => 0x1a
     }
 }
 
@@ -233,6 +237,7 @@ enum_builder! {
         PostHandshakeAuth => 0x0031,
         SignatureAlgorithmsCert => 0x0032,
         KeyShare => 0x0033,
+        ConnectionId => 0x0036,
         TransportParameters => 0x0039,
         NextProtocolNegotiation => 0x3374,
         ChannelId => 0x754f,
@@ -513,6 +518,7 @@ enum_builder! {
         TLS13_CHACHA20_POLY1305_SHA256 => 0x1303,
         TLS13_AES_128_CCM_SHA256 => 0x1304,
         TLS13_AES_128_CCM_8_SHA256 => 0x1305,
+        TLS_FALLBACK_SCSV => 0x5600,
         TLS_ECDH_ECDSA_WITH_NULL_SHA => 0xc001,
         TLS_ECDH_ECDSA_WITH_RC4_128_SHA => 0xc002,
         TLS_ECDH_ECDSA_WITH_3DES_EDE_CBC_SHA => 0xc003,
@@ -753,7 +759,10 @@ enum_builder! {
         RSA_PSS_SHA384 => 0x0805,
         RSA_PSS_SHA512 => 0x0806,
         ED25519 => 0x0807,
-        ED448 => 0x0808
+        ED448 => 0x0808,
+        RSA_PSS_PSS_SHA256 => 0x0809,
+        RSA_PSS_PSS_SHA384 => 0x080a,
+        RSA_PSS_PSS_SHA512 => 0x080b
     }
 }
 
@@ -791,3 +800,19 @@ enum_builder! {
         OCSP => 0x01
     }
 }
+
+enum_builder! {
+    /// The `SRTPProtectionProfile` DTLS-SRTP protocol enum.  Values in this enum are taken
+    /// from RFC5764 and RFC7714, and are listed by IANA.
+    /// The `Unknown` item is used when processing unrecognised ordinals.
+    @U16
+    EnumName: SRTPProtectionProfile;
+    EnumVal{
+        SRTP_AES128_CM_HMAC_SHA1_80 => 0x0001,
+        SRTP_AES128_CM_HMAC_SHA1_32 => 0x0002,
+        SRTP_NULL_HMAC_SHA1_80 => 0x0005,
+        SRTP_NULL_HMAC_SHA1_32 => 0x0006,
+        SRTP_AEAD_AES_128_GCM => 0x0007,
+        SRTP_AEAD_AES_256_GCM => 0x0008
+    }
+}
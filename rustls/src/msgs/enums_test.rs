@@ -84,11 +84,15 @@ fn test_enums() {
         HeartbeatMode::PeerNotAllowedToSend,
     );
     test_enum8::<ECCurveType>(ECCurveType::ExplicitPrime, ECCurveType::NamedCurve);
-    test_enum16::<SignatureScheme>(SignatureScheme::RSA_PKCS1_SHA1, SignatureScheme::ED448);
+    test_enum16::<SignatureScheme>(SignatureScheme::RSA_PKCS1_SHA1, SignatureScheme::RSA_PSS_PSS_SHA512);
     test_enum8::<PSKKeyExchangeMode>(PSKKeyExchangeMode::PSK_KE, PSKKeyExchangeMode::PSK_DHE_KE);
     test_enum8::<KeyUpdateRequest>(
         KeyUpdateRequest::UpdateNotRequested,
         KeyUpdateRequest::UpdateRequested,
     );
     test_enum8::<CertificateStatusType>(CertificateStatusType::OCSP, CertificateStatusType::OCSP);
+    test_enum16::<SRTPProtectionProfile>(
+        SRTPProtectionProfile::SRTP_AES128_CM_HMAC_SHA1_80,
+        SRTPProtectionProfile::SRTP_AEAD_AES_256_GCM,
+    );
 }
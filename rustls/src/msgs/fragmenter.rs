@@ -64,6 +64,23 @@ impl MessageFragmenter {
             out.push_back(cm);
         }
     }
+
+    /// Like `fragment_borrow`, but return the fragments as they're
+    /// needed rather than collecting them.
+    pub fn iter_borrow<'a>(
+        &self,
+        typ: ContentType,
+        version: ProtocolVersion,
+        payload: &'a [u8],
+    ) -> impl Iterator<Item = BorrowMessage<'a>> + 'a {
+        payload
+            .chunks(self.max_frag)
+            .map(move |chunk| BorrowMessage {
+                typ,
+                version,
+                payload: chunk,
+            })
+    }
 }
 
 #[cfg(test)]
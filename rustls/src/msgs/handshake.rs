@@ -9,6 +9,7 @@ use crate::msgs::enums::{CipherSuite, Compression, ECPointFormat, ExtensionType}
 use crate::msgs::enums::{HandshakeType, ProtocolVersion};
 use crate::msgs::enums::{HashAlgorithm, ServerNameType, SignatureAlgorithm};
 use crate::msgs::enums::{KeyUpdateRequest, NamedGroup, SignatureScheme};
+use crate::msgs::enums::SRTPProtectionProfile;
 
 #[cfg(feature = "logging")]
 use crate::log::warn;
@@ -217,7 +218,10 @@ impl DecomposedSignatureScheme for SignatureScheme {
             | SignatureScheme::RSA_PKCS1_SHA512
             | SignatureScheme::RSA_PSS_SHA256
             | SignatureScheme::RSA_PSS_SHA384
-            | SignatureScheme::RSA_PSS_SHA512 => SignatureAlgorithm::RSA,
+            | SignatureScheme::RSA_PSS_SHA512
+            | SignatureScheme::RSA_PSS_PSS_SHA256
+            | SignatureScheme::RSA_PSS_PSS_SHA384
+            | SignatureScheme::RSA_PSS_PSS_SHA512 => SignatureAlgorithm::RSA,
             SignatureScheme::ECDSA_NISTP256_SHA256
             | SignatureScheme::ECDSA_NISTP384_SHA384
             | SignatureScheme::ECDSA_NISTP521_SHA512 => SignatureAlgorithm::ECDSA,
@@ -466,6 +470,29 @@ impl Codec for PresharedKeyOffer {
     }
 }
 
+// --- RFC5764 use_srtp ---
+declare_u16_vec!(SRTPProtectionProfiles, SRTPProtectionProfile);
+
+#[derive(Clone, Debug)]
+pub struct UseSRTPData {
+    pub profiles: SRTPProtectionProfiles,
+    pub mki: PayloadU8,
+}
+
+impl Codec for UseSRTPData {
+    fn encode(&self, bytes: &mut Vec<u8>) {
+        self.profiles.encode(bytes);
+        self.mki.encode(bytes);
+    }
+
+    fn read(r: &mut Reader) -> Option<UseSRTPData> {
+        Some(UseSRTPData {
+            profiles: SRTPProtectionProfiles::read(r)?,
+            mki: PayloadU8::read(r)?,
+        })
+    }
+}
+
 // --- RFC6066 certificate status request ---
 type ResponderIDs = VecU16OfPayloadU16;
 
@@ -564,6 +591,9 @@ pub enum ClientExtension {
     TransportParameters(Vec<u8>),
     TransportParametersDraft(Vec<u8>),
     EarlyData,
+    ConnectionId(PayloadU8),
+    UseSRTP(UseSRTPData),
+    CertificateAuthorities(DistinguishedNames),
     Unknown(UnknownExtension),
 }
 
@@ -589,6 +619,9 @@ impl ClientExtension {
             ClientExtension::TransportParameters(_) => ExtensionType::TransportParameters,
             ClientExtension::TransportParametersDraft(_) => ExtensionType::TransportParametersDraft,
             ClientExtension::EarlyData => ExtensionType::EarlyData,
+            ClientExtension::ConnectionId(_) => ExtensionType::ConnectionId,
+            ClientExtension::UseSRTP(_) => ExtensionType::UseSRTP,
+            ClientExtension::CertificateAuthorities(_) => ExtensionType::CertificateAuthorities,
             ClientExtension::Unknown(ref r) => r.typ,
         }
     }
@@ -618,6 +651,9 @@ impl Codec for ClientExtension {
             ClientExtension::CertificateStatusRequest(ref r) => r.encode(&mut sub),
             ClientExtension::TransportParameters(ref r)
             | ClientExtension::TransportParametersDraft(ref r) => sub.extend_from_slice(r),
+            ClientExtension::ConnectionId(ref r) => r.encode(&mut sub),
+            ClientExtension::UseSRTP(ref r) => r.encode(&mut sub),
+            ClientExtension::CertificateAuthorities(ref r) => r.encode(&mut sub),
             ClientExtension::Unknown(ref r) => r.encode(&mut sub),
         }
 
@@ -683,6 +719,11 @@ impl Codec for ClientExtension {
                 ClientExtension::TransportParametersDraft(sub.rest().to_vec())
             }
             ExtensionType::EarlyData if !sub.any_left() => ClientExtension::EarlyData,
+            ExtensionType::ConnectionId => ClientExtension::ConnectionId(PayloadU8::read(&mut sub)?),
+            ExtensionType::UseSRTP => ClientExtension::UseSRTP(UseSRTPData::read(&mut sub)?),
+            ExtensionType::CertificateAuthorities => {
+                ClientExtension::CertificateAuthorities(DistinguishedNames::read(&mut sub)?)
+            }
             _ => ClientExtension::Unknown(UnknownExtension::read(typ, &mut sub)?),
         })
     }
@@ -731,6 +772,8 @@ pub enum ServerExtension {
     TransportParameters(Vec<u8>),
     TransportParametersDraft(Vec<u8>),
     EarlyData,
+    ConnectionId(PayloadU8),
+    UseSRTP(UseSRTPData),
     Unknown(UnknownExtension),
 }
 
@@ -751,6 +794,8 @@ impl ServerExtension {
             ServerExtension::TransportParameters(_) => ExtensionType::TransportParameters,
             ServerExtension::TransportParametersDraft(_) => ExtensionType::TransportParametersDraft,
             ServerExtension::EarlyData => ExtensionType::EarlyData,
+            ServerExtension::ConnectionId(_) => ExtensionType::ConnectionId,
+            ServerExtension::UseSRTP(_) => ExtensionType::UseSRTP,
             ServerExtension::Unknown(ref r) => r.typ,
         }
     }
@@ -776,6 +821,8 @@ impl Codec for ServerExtension {
             ServerExtension::SupportedVersions(ref r) => r.encode(&mut sub),
             ServerExtension::TransportParameters(ref r)
             | ServerExtension::TransportParametersDraft(ref r) => sub.extend_from_slice(r),
+            ServerExtension::ConnectionId(ref r) => r.encode(&mut sub),
+            ServerExtension::UseSRTP(ref r) => r.encode(&mut sub),
             ServerExtension::Unknown(ref r) => r.encode(&mut sub),
         }
 
@@ -818,6 +865,8 @@ impl Codec for ServerExtension {
                 ServerExtension::TransportParametersDraft(sub.rest().to_vec())
             }
             ExtensionType::EarlyData => ServerExtension::EarlyData,
+            ExtensionType::ConnectionId => ServerExtension::ConnectionId(PayloadU8::read(&mut sub)?),
+            ExtensionType::UseSRTP => ServerExtension::UseSRTP(UseSRTPData::read(&mut sub)?),
             _ => ServerExtension::Unknown(UnknownExtension::read(typ, &mut sub)?),
         })
     }
@@ -854,6 +903,10 @@ impl Codec for ClientHelloPayload {
         self.client_version.encode(bytes);
         self.random.encode(bytes);
         self.session_id.encode(bytes);
+        if self.is_dtls() {
+            // legacy_cookie: always empty in DTLS1.3.
+            bytes.push(0);
+        }
         codec::encode_vec_u16(bytes, &self.cipher_suites);
         codec::encode_vec_u8(bytes, &self.compression_methods);
 
@@ -863,10 +916,23 @@ impl Codec for ClientHelloPayload {
     }
 
     fn read(r: &mut Reader) -> Option<ClientHelloPayload> {
+        let client_version = ProtocolVersion::read(r)?;
+        let random = Random::read(r)?;
+        let session_id = SessionID::read(r)?;
+
+        if client_version == ProtocolVersion::DTLSv1_0
+            || client_version == ProtocolVersion::DTLSv1_2
+        {
+            let cookie = PayloadU8::read(r)?;
+            if !cookie.0.is_empty() {
+                return None;
+            }
+        }
+
         let mut ret = ClientHelloPayload {
-            client_version: ProtocolVersion::read(r)?,
-            random: Random::read(r)?,
-            session_id: SessionID::read(r)?,
+            client_version,
+            random,
+            session_id,
             cipher_suites: codec::read_vec_u16::<CipherSuite>(r)?,
             compression_methods: codec::read_vec_u8::<Compression>(r)?,
             extensions: Vec::new(),
@@ -880,7 +946,20 @@ impl Codec for ClientHelloPayload {
     }
 }
 
+pub fn unknown_extensions(exts: &[ClientExtension]) -> impl Iterator<Item = (u16, &[u8])> {
+    exts.iter()
+        .filter_map(|ext| match ext {
+            ClientExtension::Unknown(unknown) => Some((unknown.typ.get_u16(), &unknown.payload.0[..])),
+            _ => None,
+        })
+}
+
 impl ClientHelloPayload {
+    fn is_dtls(&self) -> bool {
+        self.client_version == ProtocolVersion::DTLSv1_0
+            || self.client_version == ProtocolVersion::DTLSv1_2
+    }
+
     /// Returns true if there is more than one extension of a given
     /// type.
     pub fn has_duplicate_extension(&self) -> bool {
@@ -955,6 +1034,30 @@ impl ClientHelloPayload {
         }
     }
 
+    pub fn get_connection_id_extension(&self) -> Option<&[u8]> {
+        let ext = self.find_extension(ExtensionType::ConnectionId)?;
+        match *ext {
+            ClientExtension::ConnectionId(ref cid) => Some(&cid.0),
+            _ => None,
+        }
+    }
+
+    pub fn get_srtp_extension(&self) -> Option<&UseSRTPData> {
+        let ext = self.find_extension(ExtensionType::UseSRTP)?;
+        match *ext {
+            ClientExtension::UseSRTP(ref data) => Some(data),
+            _ => None,
+        }
+    }
+
+    pub fn get_certificate_authorities_extension(&self) -> Option<&DistinguishedNames> {
+        let ext = self.find_extension(ExtensionType::CertificateAuthorities)?;
+        match *ext {
+            ClientExtension::CertificateAuthorities(ref names) => Some(names),
+            _ => None,
+        }
+    }
+
     pub fn get_ticket_extension(&self) -> Option<&ClientExtension> {
         self.find_extension(ExtensionType::SessionTicket)
     }
@@ -967,6 +1070,11 @@ impl ClientHelloPayload {
         }
     }
 
+    /// The extensions we don't understand, as pairs of type and payload.
+    pub fn get_unknown_extensions(&self) -> impl Iterator<Item = (u16, &[u8])> {
+        unknown_extensions(&self.extensions)
+    }
+
     pub fn get_keyshare_extension(&self) -> Option<&KeyShareEntries> {
         let ext = self.find_extension(ExtensionType::KeyShare)?;
         match *ext {
@@ -1502,6 +1610,19 @@ impl CertificatePayloadTLS13 {
             .unwrap_or_else(Vec::new)
     }
 
+    pub fn get_intermediate_ocsp(&self) -> Vec<Vec<u8>> {
+        self.entries
+            .iter()
+            .skip(1)
+            .map(|entry| {
+                entry
+                    .get_ocsp_response()
+                    .cloned()
+                    .unwrap_or_else(Vec::new)
+            })
+            .collect()
+    }
+
     pub fn get_end_entity_scts(&self) -> Option<SCTList> {
         self.entries
             .first()
@@ -1768,6 +1889,22 @@ pub trait HasServerExtensions {
         self.find_extension(ExtensionType::EarlyData)
             .is_some()
     }
+
+    fn get_connection_id_extension(&self) -> Option<&[u8]> {
+        let ext = self.find_extension(ExtensionType::ConnectionId)?;
+        match *ext {
+            ServerExtension::ConnectionId(ref cid) => Some(&cid.0),
+            _ => None,
+        }
+    }
+
+    fn get_srtp_extension(&self) -> Option<&UseSRTPData> {
+        let ext = self.find_extension(ExtensionType::UseSRTP)?;
+        match *ext {
+            ServerExtension::UseSRTP(ref data) => Some(data),
+            _ => None,
+        }
+    }
 }
 
 impl HasServerExtensions for EncryptedExtensions {
@@ -339,6 +339,12 @@ fn get_sample_clienthellopayload() -> ClientHelloPayload {
             ClientExtension::CertificateStatusRequest(CertificateStatusRequest::build_ocsp()),
             ClientExtension::SignedCertificateTimestampRequest,
             ClientExtension::TransportParameters(vec![1, 2, 3]),
+            ClientExtension::ConnectionId(PayloadU8(vec![4, 5, 6])),
+            ClientExtension::UseSRTP(UseSRTPData {
+                profiles: vec![SRTPProtectionProfile::SRTP_AEAD_AES_128_GCM],
+                mki: PayloadU8(vec![7]),
+            }),
+            ClientExtension::CertificateAuthorities(vec![PayloadU16(vec![1, 2, 3])]),
             ClientExtension::Unknown(UnknownExtension {
                 typ: ExtensionType::Unknown(12345),
                 payload: Payload(vec![1, 2, 3]),
@@ -490,6 +496,21 @@ fn client_get_alpn_extension() {
     });
 }
 
+#[test]
+fn client_get_connection_id_extension() {
+    test_client_extension_getter(ExtensionType::ConnectionId, |chp| {
+        chp.get_connection_id_extension()
+            .is_some()
+    });
+}
+
+#[test]
+fn client_get_srtp_extension() {
+    test_client_extension_getter(ExtensionType::UseSRTP, |chp| {
+        chp.get_srtp_extension().is_some()
+    });
+}
+
 #[test]
 fn client_get_quic_params_extension() {
     test_client_extension_getter(ExtensionType::TransportParameters, |chp| {
@@ -666,6 +687,21 @@ fn server_get_sct_list() {
     test_server_extension_getter(ExtensionType::SCT, |shp| shp.get_sct_list().is_some());
 }
 
+#[test]
+fn server_get_connection_id_extension() {
+    test_server_extension_getter(ExtensionType::ConnectionId, |shp| {
+        shp.get_connection_id_extension()
+            .is_some()
+    });
+}
+
+#[test]
+fn server_get_srtp_extension() {
+    test_server_extension_getter(ExtensionType::UseSRTP, |shp| {
+        shp.get_srtp_extension().is_some()
+    });
+}
+
 #[test]
 fn server_get_supported_versions() {
     test_server_extension_getter(ExtensionType::SupportedVersions, |shp| {
@@ -724,6 +760,11 @@ fn get_sample_serverhellopayload() -> ServerHelloPayload {
             ServerExtension::SignedCertificateTimestamp(vec![PayloadU16(vec![0])]),
             ServerExtension::SupportedVersions(ProtocolVersion::TLSv1_2),
             ServerExtension::TransportParameters(vec![1, 2, 3]),
+            ServerExtension::ConnectionId(PayloadU8(vec![4, 5, 6])),
+            ServerExtension::UseSRTP(UseSRTPData {
+                profiles: vec![SRTPProtectionProfile::SRTP_AEAD_AES_128_GCM],
+                mki: PayloadU8(vec![7]),
+            }),
             ServerExtension::Unknown(UnknownExtension {
                 typ: ExtensionType::Unknown(12345),
                 payload: Payload(vec![1, 2, 3]),
@@ -44,6 +44,12 @@ impl HandshakeJoiner {
         self.buf.is_empty()
     }
 
+    /// Free any memory we're not using to hold messages.
+    pub fn shrink_to_fit(&mut self) {
+        self.frames.shrink_to_fit();
+        self.buf.shrink_to_fit();
+    }
+
     /// Take the message, and join/split it as needed.
     /// Return the number of new messages added to the
     /// output deque as a result of this message.
@@ -180,6 +186,9 @@ mod tests {
 
         pop_eq(&expect, &mut hj);
         pop_eq(&expect, &mut hj);
+
+        hj.shrink_to_fit();
+        assert_eq!(hj.frames.capacity(), 0);
     }
 
     #[test]
@@ -117,6 +117,25 @@ impl Message {
     /// this message might be valid if we read more data; and this message will
     /// never be valid.
     pub fn read_with_detailed_error(r: &mut Reader) -> Result<Message, MessageError> {
+        let (typ, version, len) = Message::read_header(r)?;
+
+        let mut sub = r
+            .sub(len as usize)
+            .ok_or(MessageError::TooShortForLength)?;
+        let payload = Payload::read(&mut sub).unwrap();
+
+        Ok(Message {
+            typ,
+            version,
+            payload: MessagePayload::Opaque(payload),
+        })
+    }
+
+    /// Read and check a record header, returning the record's content
+    /// type, version and payload length.
+    pub fn read_header(
+        r: &mut Reader,
+    ) -> Result<(ContentType, ProtocolVersion, u16), MessageError> {
         let typ = ContentType::read(r).ok_or(MessageError::TooShortForHeader)?;
         let version = ProtocolVersion::read(r).ok_or(MessageError::TooShortForHeader)?;
         let len = u16::read(r).ok_or(MessageError::TooShortForHeader)?;
@@ -139,16 +158,7 @@ impl Message {
             _ => {}
         };
 
-        let mut sub = r
-            .sub(len as usize)
-            .ok_or(MessageError::TooShortForLength)?;
-        let payload = Payload::read(&mut sub).unwrap();
-
-        Ok(Message {
-            typ,
-            version,
-            payload: MessagePayload::Opaque(payload),
-        })
+        Ok((typ, version, len))
     }
 
     pub fn is_content_type(&self, typ: ContentType) -> bool {
@@ -258,3 +268,11 @@ pub struct BorrowMessage<'a> {
     pub version: ProtocolVersion,
     pub payload: &'a [u8],
 }
+
+/// Like `BorrowMessage`, but the payload is borrowed mutably,
+/// so a received record can be decrypted where it lies.
+pub struct BorrowMessageMut<'a> {
+    pub typ: ContentType,
+    pub version: ProtocolVersion,
+    pub payload: &'a mut [u8],
+}
@@ -3,6 +3,7 @@ use crate::msgs::codec::{Codec, Reader};
 use crate::msgs::enums::{CipherSuite, ProtocolVersion};
 use crate::msgs::handshake::CertificatePayload;
 use crate::msgs::handshake::SessionID;
+use crate::server_name::{DnsName, ServerName};
 
 use webpki;
 
@@ -18,13 +19,13 @@ use crate::SupportedCipherSuite;
 #[derive(Debug)]
 pub struct ClientSessionKey {
     kind: &'static [u8],
-    dns_name: PayloadU8,
+    server_name: PayloadU8,
 }
 
 impl Codec for ClientSessionKey {
     fn encode(&self, bytes: &mut Vec<u8>) {
         bytes.extend_from_slice(self.kind);
-        self.dns_name.encode(bytes);
+        self.server_name.encode(bytes);
     }
 
     // Don't need to read these.
@@ -34,23 +35,26 @@ impl Codec for ClientSessionKey {
 }
 
 impl ClientSessionKey {
-    pub fn session_for_dns_name(dns_name: webpki::DNSNameRef) -> ClientSessionKey {
-        let dns_name_str: &str = dns_name.into();
+    pub fn session_for_server_name(server_name: &ServerName) -> ClientSessionKey {
         ClientSessionKey {
             kind: b"session",
-            dns_name: PayloadU8::new(dns_name_str.as_bytes().to_vec()),
+            server_name: PayloadU8::new(server_name.to_key_string().into_bytes()),
         }
     }
 
-    pub fn hint_for_dns_name(dns_name: webpki::DNSNameRef) -> ClientSessionKey {
-        let dns_name_str: &str = dns_name.into();
+    pub fn hint_for_server_name(server_name: &ServerName) -> ClientSessionKey {
         ClientSessionKey {
             kind: b"kx-hint",
-            dns_name: PayloadU8::new(dns_name_str.as_bytes().to_vec()),
+            server_name: PayloadU8::new(server_name.to_key_string().into_bytes()),
         }
     }
 }
 
+/// The first byte of every encoded `ClientSessionValue`.  Change
+/// this whenever the encoding changes: values stored by other
+/// versions of rustls are then ignored, rather than misread.
+const CLIENT_SESSION_VALUE_FORMAT: u8 = 1;
+
 #[derive(Debug)]
 pub struct ClientSessionValue {
     pub version: ProtocolVersion,
@@ -68,6 +72,7 @@ pub struct ClientSessionValue {
 
 impl ClientSessionValue {
     pub fn encode(&self, bytes: &mut Vec<u8>) {
+        CLIENT_SESSION_VALUE_FORMAT.encode(bytes);
         self.version.encode(bytes);
         self.suite.suite.encode(bytes);
         self.session_id.encode(bytes);
@@ -82,6 +87,9 @@ impl ClientSessionValue {
     }
 
     pub fn read(r: &mut Reader, enabled_cipher_suites: &[&'static SupportedCipherSuite]) -> Option<ClientSessionValue> {
+        if u8::read(r)? != CLIENT_SESSION_VALUE_FORMAT {
+            return None;
+        }
         let v = ProtocolVersion::read(r)?;
         let cs = CipherSuite::read(r)?;
         let cipher_suite =
@@ -116,6 +124,28 @@ impl ClientSessionValue {
         self.encode(&mut result);
         result
     }
+
+    /// When the value encoded in `bytes` expires, in UNIX seconds.
+    /// None if it never does, or `bytes` isn't an encoded value.
+    pub fn read_expiry(bytes: &[u8]) -> Option<u64> {
+        let mut r = Reader::init(bytes);
+        if u8::read(&mut r)? != CLIENT_SESSION_VALUE_FORMAT {
+            return None;
+        }
+        ProtocolVersion::read(&mut r)?;
+        CipherSuite::read(&mut r)?;
+        SessionID::read(&mut r)?;
+        PayloadU16::read(&mut r)?;
+        PayloadU8::read(&mut r)?;
+        let epoch = u64::read(&mut r)?;
+        let lifetime = u32::read(&mut r)?;
+
+        if lifetime == 0 {
+            None
+        } else {
+            Some(epoch.saturating_add(u64::from(lifetime)))
+        }
+    }
 }
 
 static MAX_TICKET_LIFETIME: u32 = 7 * 24 * 60 * 60;
@@ -180,7 +210,7 @@ pub type ServerSessionKey = SessionID;
 
 #[derive(Debug)]
 pub struct ServerSessionValue {
-    pub sni: Option<webpki::DNSName>,
+    pub sni: Option<DnsName>,
     pub version: ProtocolVersion,
     pub cipher_suite: CipherSuite,
     pub master_secret: PayloadU8,
@@ -194,7 +224,7 @@ impl Codec for ServerSessionValue {
     fn encode(&self, bytes: &mut Vec<u8>) {
         if let Some(ref sni) = self.sni {
             1u8.encode(bytes);
-            let sni_bytes: &str = sni.as_ref().into();
+            let sni_bytes: &str = sni.as_ref();
             PayloadU8::new(Vec::from(sni_bytes)).encode(bytes);
         } else {
             0u8.encode(bytes);
@@ -223,7 +253,7 @@ impl Codec for ServerSessionValue {
         let sni = if has_sni == 1 {
             let dns_name = PayloadU8::read(r)?;
             let dns_name = webpki::DNSNameRef::try_from_ascii(&dns_name.0).ok()?;
-            Some(dns_name.into())
+            Some(DnsName(dns_name.into()))
         } else {
             None
         };
@@ -260,7 +290,7 @@ impl Codec for ServerSessionValue {
 
 impl ServerSessionValue {
     pub fn new(
-        sni: Option<&webpki::DNSName>,
+        sni: Option<&DnsName>,
         v: ProtocolVersion,
         cs: CipherSuite,
         ms: Vec<u8>,
@@ -284,3 +314,172 @@ impl ServerSessionValue {
         self.extended_ms = true;
     }
 }
+
+// --- Exported sessions ---
+
+/// The format of `ExportedServerSession`'s encoding.  Change this
+/// whenever the encoding changes, so older exports are refused
+/// rather than misread.
+#[cfg(feature = "session_export")]
+pub const EXPORTED_SESSION_FORMAT: u8 = 1;
+
+/// The secrets of an exported session, which depend on its
+/// protocol version.
+#[cfg(feature = "session_export")]
+#[derive(Debug)]
+pub enum ExportedKeys {
+    Tls12 {
+        client_random: [u8; 32],
+        server_random: [u8; 32],
+        master_secret: PayloadU8,
+    },
+    Tls13 {
+        master_secret: PayloadU8,
+        client_traffic_secret: PayloadU8,
+        server_traffic_secret: PayloadU8,
+        exporter_secret: PayloadU8,
+    },
+}
+
+#[cfg(feature = "session_export")]
+impl ExportedKeys {
+    pub fn version(&self) -> ProtocolVersion {
+        match *self {
+            ExportedKeys::Tls12 { .. } => ProtocolVersion::TLSv1_2,
+            ExportedKeys::Tls13 { .. } => ProtocolVersion::TLSv1_3,
+        }
+    }
+}
+
+#[cfg(feature = "session_export")]
+impl Codec for ExportedKeys {
+    fn encode(&self, bytes: &mut Vec<u8>) {
+        self.version().encode(bytes);
+        match *self {
+            ExportedKeys::Tls12 {
+                ref client_random,
+                ref server_random,
+                ref master_secret,
+            } => {
+                bytes.extend_from_slice(client_random);
+                bytes.extend_from_slice(server_random);
+                master_secret.encode(bytes);
+            }
+            ExportedKeys::Tls13 {
+                ref master_secret,
+                ref client_traffic_secret,
+                ref server_traffic_secret,
+                ref exporter_secret,
+            } => {
+                master_secret.encode(bytes);
+                client_traffic_secret.encode(bytes);
+                server_traffic_secret.encode(bytes);
+                exporter_secret.encode(bytes);
+            }
+        }
+    }
+
+    fn read(r: &mut Reader) -> Option<ExportedKeys> {
+        let random = |r: &mut Reader| {
+            let mut random = [0u8; 32];
+            random.copy_from_slice(r.take(32)?);
+            Some(random)
+        };
+
+        match ProtocolVersion::read(r)? {
+            ProtocolVersion::TLSv1_2 => Some(ExportedKeys::Tls12 {
+                client_random: random(r)?,
+                server_random: random(r)?,
+                master_secret: PayloadU8::read(r)?,
+            }),
+            ProtocolVersion::TLSv1_3 => Some(ExportedKeys::Tls13 {
+                master_secret: PayloadU8::read(r)?,
+                client_traffic_secret: PayloadU8::read(r)?,
+                server_traffic_secret: PayloadU8::read(r)?,
+                exporter_secret: PayloadU8::read(r)?,
+            }),
+            _ => None,
+        }
+    }
+}
+
+/// An established server session, as made by
+/// `ServerSession::dangerous_export`.
+#[cfg(feature = "session_export")]
+#[derive(Debug)]
+pub struct ExportedServerSession {
+    pub cipher_suite: CipherSuite,
+    pub write_seq: u64,
+    pub read_seq: u64,
+    pub keys: ExportedKeys,
+    pub sni: Option<DnsName>,
+    pub alpn: Option<PayloadU8>,
+    pub client_cert_chain: Option<CertificatePayload>,
+}
+
+#[cfg(feature = "session_export")]
+impl Codec for ExportedServerSession {
+    fn encode(&self, bytes: &mut Vec<u8>) {
+        EXPORTED_SESSION_FORMAT.encode(bytes);
+        self.cipher_suite.encode(bytes);
+        self.write_seq.encode(bytes);
+        self.read_seq.encode(bytes);
+        self.keys.encode(bytes);
+        if let Some(ref sni) = self.sni {
+            1u8.encode(bytes);
+            let sni_bytes: &str = sni.as_ref();
+            PayloadU8::new(Vec::from(sni_bytes)).encode(bytes);
+        } else {
+            0u8.encode(bytes);
+        }
+        if let Some(ref alpn) = self.alpn {
+            1u8.encode(bytes);
+            alpn.encode(bytes);
+        } else {
+            0u8.encode(bytes);
+        }
+        if let Some(ref chain) = self.client_cert_chain {
+            1u8.encode(bytes);
+            chain.encode(bytes);
+        } else {
+            0u8.encode(bytes);
+        }
+    }
+
+    fn read(r: &mut Reader) -> Option<ExportedServerSession> {
+        if u8::read(r)? != EXPORTED_SESSION_FORMAT {
+            return None;
+        }
+        let cipher_suite = CipherSuite::read(r)?;
+        let write_seq = u64::read(r)?;
+        let read_seq = u64::read(r)?;
+        let keys = ExportedKeys::read(r)?;
+        let sni = if u8::read(r)? == 1 {
+            let dns_name = PayloadU8::read(r)?;
+            let dns_name = webpki::DNSNameRef::try_from_ascii(&dns_name.0).ok()?;
+            Some(DnsName(dns_name.into()))
+        } else {
+            None
+        };
+        let alpn = if u8::read(r)? == 1 {
+            Some(PayloadU8::read(r)?)
+        } else {
+            None
+        };
+        let client_cert_chain = if u8::read(r)? == 1 {
+            Some(CertificatePayload::read(r)?)
+        } else {
+            None
+        };
+
+        Some(ExportedServerSession {
+            cipher_suite,
+            write_seq,
+            read_seq,
+            keys,
+            sni,
+            alpn,
+            client_cert_chain,
+        })
+    }
+}
@@ -3,16 +3,24 @@ use super::enums::*;
 use super::handshake::*;
 use super::persist::*;
 use crate::key::Certificate;
-use webpki::DNSNameRef;
-use crate::suites::TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256;
+use crate::server_name::ServerName;
+use std::convert::TryFrom;
+use crate::suites::ALL_CIPHERSUITES;
 
 #[test]
 fn clientsessionkey_is_debug() {
-    let name = DNSNameRef::try_from_ascii_str("hello").unwrap();
-    let csk = ClientSessionKey::session_for_dns_name(name);
+    let name = ServerName::try_from("hello").unwrap();
+    let csk = ClientSessionKey::session_for_server_name(&name);
     println!("{:?}", csk);
 }
 
+#[test]
+fn clientsessionkey_for_ip_address() {
+    let ip: std::net::IpAddr = "2001:db8::1".parse().unwrap();
+    let csk = ClientSessionKey::session_for_server_name(&ip.into());
+    assert_eq!(csk.get_encoding(), b"session\x0b2001:db8::1".to_vec());
+}
+
 #[test]
 fn clientsessionkey_cannot_be_read() {
     let bytes = [0; 1];
@@ -23,8 +31,8 @@ fn clientsessionkey_cannot_be_read() {
 #[test]
 fn clientsessionvalue_is_debug() {
     let csv = ClientSessionValue::new(
-        ProtocolVersion::TLSv1_2,
-        &TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256,
+        ProtocolVersion::TLSv1_3,
+        ALL_CIPHERSUITES[0],
         &SessionID::new(&[1u8]),
         vec![],
         vec![1, 2, 3],
@@ -33,6 +41,65 @@ fn clientsessionvalue_is_debug() {
     println!("{:?}", csv);
 }
 
+#[test]
+fn clientsessionvalue_roundtrip() {
+    let mut csv = ClientSessionValue::new(
+        ProtocolVersion::TLSv1_3,
+        ALL_CIPHERSUITES[0],
+        &SessionID::new(&[1u8]),
+        vec![4, 5],
+        vec![1, 2, 3],
+        &vec![Certificate(b"abc".to_vec())],
+    );
+    csv.set_times(1_600_000_000, 3600, 1234);
+    let bytes = csv.get_encoding();
+
+    let mut rd = Reader::init(&bytes);
+    let read = ClientSessionValue::read(&mut rd, ALL_CIPHERSUITES).unwrap();
+    assert!(!rd.any_left());
+    assert_eq!(read.get_encoding(), bytes);
+    assert_eq!(read.epoch, 1_600_000_000);
+    assert_eq!(read.ticket.0, vec![4, 5]);
+}
+
+#[test]
+fn clientsessionvalue_of_other_format_is_ignored() {
+    let csv = ClientSessionValue::new(
+        ProtocolVersion::TLSv1_2,
+        ALL_CIPHERSUITES[0],
+        &SessionID::new(&[1u8]),
+        vec![],
+        vec![1, 2, 3],
+        &vec![],
+    );
+    let mut bytes = csv.get_encoding();
+    bytes[0] = bytes[0].wrapping_add(1);
+
+    let mut rd = Reader::init(&bytes);
+    assert!(ClientSessionValue::read(&mut rd, ALL_CIPHERSUITES).is_none());
+}
+
+#[test]
+fn clientsessionvalue_expiry_can_be_read() {
+    let mut csv = ClientSessionValue::new(
+        ProtocolVersion::TLSv1_3,
+        ALL_CIPHERSUITES[0],
+        &SessionID::new(&[1u8]),
+        vec![4, 5],
+        vec![1, 2, 3],
+        &vec![Certificate(b"abc".to_vec())],
+    );
+    assert_eq!(ClientSessionValue::read_expiry(&csv.get_encoding()), None);
+
+    csv.set_times(1_600_000_000, 3600, 1234);
+    assert_eq!(
+        ClientSessionValue::read_expiry(&csv.get_encoding()),
+        Some(1_600_003_600)
+    );
+    assert_eq!(ClientSessionValue::read_expiry(&[]), None);
+    assert_eq!(ClientSessionValue::read_expiry(&[0x1d, 0x00]), None);
+}
+
 #[test]
 fn serversessionvalue_is_debug() {
     let ssv = ServerSessionValue::new(
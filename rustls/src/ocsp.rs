@@ -0,0 +1,422 @@
+// Validation of stapled OCSP responses (RFC6960).
+//
+// webpki doesn't deal with OCSP, so this picks apart a
+// BasicOCSPResponse using the DER helpers in `x509`.
+
+use crate::error::OcspError;
+#[cfg(feature = "logging")]
+use crate::log::{debug, trace};
+use crate::x509::{self, parse_time, CertInfo, ChainLink, Issuer, Reader};
+use crate::x509::{TAG_BOOLEAN, TAG_CONTEXT_0, TAG_CONTEXT_1, TAG_GENERALIZED_TIME};
+
+use ring::digest;
+use ring::io::der;
+
+const OID_PKIX_OCSP_BASIC: &[u8] = &[0x2b, 0x06, 0x01, 0x05, 0x05, 0x07, 0x30, 0x01, 0x01];
+const OID_KP_OCSP_SIGNING: &[u8] = &[0x2b, 0x06, 0x01, 0x05, 0x05, 0x07, 0x03, 0x09];
+const OID_CE_EXT_KEY_USAGE: &[u8] = &[0x55, 0x1d, 0x25];
+
+const OID_SHA1: &[u8] = &[0x2b, 0x0e, 0x03, 0x02, 0x1a];
+const OID_SHA256: &[u8] = &[0x60, 0x86, 0x48, 0x01, 0x65, 0x03, 0x04, 0x02, 0x01];
+const OID_SHA384: &[u8] = &[0x60, 0x86, 0x48, 0x01, 0x65, 0x03, 0x04, 0x02, 0x02];
+const OID_SHA512: &[u8] = &[0x60, 0x86, 0x48, 0x01, 0x65, 0x03, 0x04, 0x02, 0x03];
+
+const TAG_ENUMERATED: u8 = 0x0a;
+const TAG_CERT_STATUS_GOOD: u8 = 0x80;
+const TAG_CERT_STATUS_REVOKED: u8 = 0xa1;
+
+impl From<x509::Error> for OcspError {
+    fn from(err: x509::Error) -> Self {
+        match err {
+            x509::Error::BadDer => OcspError::Malformed,
+            x509::Error::UnsupportedSignatureAlgorithm => OcspError::UnsupportedSignatureAlgorithm,
+            x509::Error::BadSignature => OcspError::BadSignature,
+            x509::Error::UnknownIssuer => OcspError::UnknownIssuer,
+        }
+    }
+}
+
+fn has_ocsp_signing_eku(extensions: &[u8]) -> Result<bool, OcspError> {
+    let mut outer = Reader::new(extensions);
+    let mut exts = outer.read_sequence()?;
+    outer.finish()?;
+
+    while !exts.at_end() {
+        let mut ext = exts.read_sequence()?;
+        let oid = ext.read(der::Tag::OID as u8)?;
+        ext.read_optional(TAG_BOOLEAN)?;
+        let value = ext.read(der::Tag::OctetString as u8)?;
+        ext.finish()?;
+
+        if oid != OID_CE_EXT_KEY_USAGE {
+            continue;
+        }
+
+        let mut outer = Reader::new(value);
+        let mut purposes = outer.read_sequence()?;
+        outer.finish()?;
+        while !purposes.at_end() {
+            if purposes.read(der::Tag::OID as u8)? == OID_KP_OCSP_SIGNING {
+                return Ok(true);
+            }
+        }
+    }
+
+    Ok(false)
+}
+
+/// One entry in `ResponseData.responses`.
+struct SingleResponse<'a> {
+    hash_alg: &'a [u8],
+    issuer_name_hash: &'a [u8],
+    issuer_key_hash: &'a [u8],
+    serial: &'a [u8],
+    status_tag: u8,
+    this_update: u64,
+    next_update: Option<u64>,
+}
+
+impl<'a> SingleResponse<'a> {
+    fn parse(r: &mut Reader<'a>) -> Result<SingleResponse<'a>, OcspError> {
+        let mut cert_id = r.read_sequence()?;
+        let (hash_alg, _) = cert_id.read_algorithm()?;
+        let issuer_name_hash = cert_id.read(der::Tag::OctetString as u8)?;
+        let issuer_key_hash = cert_id.read(der::Tag::OctetString as u8)?;
+        let serial = cert_id.read(der::Tag::Integer as u8)?;
+        cert_id.finish()?;
+
+        let (status_tag, _, _) = r.read_any()?;
+        let this_update = parse_time(r.read(TAG_GENERALIZED_TIME)?, true)?;
+        let next_update = match r.read_optional(TAG_CONTEXT_0)? {
+            Some(explicit) => {
+                let mut explicit = Reader::new(explicit);
+                let time = parse_time(explicit.read(TAG_GENERALIZED_TIME)?, true)?;
+                explicit.finish()?;
+                Some(time)
+            }
+            None => None,
+        };
+        r.read_optional(TAG_CONTEXT_1)?;
+        r.finish()?;
+
+        Ok(SingleResponse {
+            hash_alg,
+            issuer_name_hash,
+            issuer_key_hash,
+            serial,
+            status_tag,
+            this_update,
+            next_update,
+        })
+    }
+
+    fn matches(&self, end_entity: &CertInfo, issuer: &Issuer) -> bool {
+        let alg = match self.hash_alg {
+            OID_SHA1 => &digest::SHA1_FOR_LEGACY_USE_ONLY,
+            OID_SHA256 => &digest::SHA256,
+            OID_SHA384 => &digest::SHA384,
+            OID_SHA512 => &digest::SHA512,
+            _ => return false,
+        };
+
+        self.serial == end_entity.serial
+            && self.issuer_name_hash == digest::digest(alg, end_entity.issuer).as_ref()
+            && self.issuer_key_hash == digest::digest(alg, issuer.public_key.key).as_ref()
+    }
+}
+
+/// The parts of a successful OCSPResponse's BasicOCSPResponse.
+struct BasicResponse<'a> {
+    tbs_value: &'a [u8],
+    tbs: &'a [u8],
+    signature_alg: &'a [u8],
+    signature: &'a [u8],
+    responder_certs: Option<&'a [u8]>,
+}
+
+impl<'a> BasicResponse<'a> {
+    fn parse(response: &'a [u8]) -> Result<BasicResponse<'a>, OcspError> {
+        // OCSPResponse
+        let mut outer = Reader::new(response);
+        let mut ocsp_response = outer.read_sequence()?;
+        outer.finish()?;
+
+        if ocsp_response.read(TAG_ENUMERATED)? != [0] {
+            return Err(OcspError::UnsuccessfulResponse);
+        }
+
+        let mut response_bytes = Reader::new(ocsp_response.read(TAG_CONTEXT_0)?);
+        ocsp_response.finish()?;
+        let mut response_bytes_seq = response_bytes.read_sequence()?;
+        response_bytes.finish()?;
+        if response_bytes_seq.read(der::Tag::OID as u8)? != OID_PKIX_OCSP_BASIC {
+            return Err(OcspError::UnsupportedResponseType);
+        }
+        let basic = response_bytes_seq.read(der::Tag::OctetString as u8)?;
+        response_bytes_seq.finish()?;
+
+        // BasicOCSPResponse
+        let mut outer = Reader::new(basic);
+        let mut basic = outer.read_sequence()?;
+        outer.finish()?;
+        let (tbs_value, tbs) = basic.read_tlv(der::Tag::Sequence as u8)?;
+        let (signature_alg, _) = basic.read_algorithm()?;
+        let signature = basic.read_bit_string()?;
+        let responder_certs = basic.read_optional(TAG_CONTEXT_0)?;
+        basic.finish()?;
+
+        Ok(BasicResponse {
+            tbs_value,
+            tbs,
+            signature_alg,
+            signature,
+            responder_certs,
+        })
+    }
+
+    /// The contents of `ResponseData.responses`.
+    fn responses(&self) -> Result<Reader<'a>, OcspError> {
+        let mut tbs = Reader::new(self.tbs_value);
+        tbs.read_optional(TAG_CONTEXT_0)?;
+        tbs.skip()?; // responderID: the signer is established separately
+        tbs.read(TAG_GENERALIZED_TIME)?;
+        let responses = tbs.read_sequence()?;
+        tbs.read_optional(TAG_CONTEXT_1)?;
+        tbs.finish()?;
+        Ok(responses)
+    }
+}
+
+/// The earliest nextUpdate in `response`, in seconds since the epoch.
+/// This doesn't check the response is signed or names any particular
+/// certificate; it's for deciding when a response we produced must
+/// no longer be stapled.
+///
+/// Returns None if `response` is malformed or has no nextUpdate.
+pub(crate) fn next_update(response: &[u8]) -> Option<u64> {
+    let mut responses = BasicResponse::parse(response)
+        .and_then(|basic| basic.responses())
+        .ok()?;
+
+    let mut earliest = None;
+    while !responses.at_end() {
+        let single = SingleResponse::parse(&mut responses.read_sequence().ok()?).ok()?;
+        earliest = match (earliest, single.next_update) {
+            (Some(a), Some(b)) => Some(std::cmp::min(a, b)),
+            (a, b) => a.or(b),
+        };
+    }
+    earliest
+}
+
+/// Check `response` is a well-formed, correctly signed and current
+/// OCSP response saying `end_entity` is not revoked.
+///
+/// The response must be signed by the issuer of `end_entity`, or by a
+/// responder certificate included in the response, issued by that
+/// issuer and carrying the id-kp-OCSPSigning extended key usage.
+///
+/// `end_entity` is the first link of the path built by
+/// `x509::build_chain`, so its issuer is the one whose signature on the
+/// certificate has been checked, not just one with a matching name.
+pub(crate) fn verify_ocsp_response(
+    response: &[u8],
+    end_entity: &ChainLink,
+    now: u64,
+) -> Result<(), OcspError> {
+    let issuer = &end_entity.issuer;
+    let end_entity = &end_entity.cert;
+
+    let basic = BasicResponse::parse(response)?;
+    verify_response_signature(
+        issuer,
+        basic.responder_certs,
+        basic.signature_alg,
+        basic.tbs,
+        basic.signature,
+        now,
+    )?;
+
+    let mut responses = basic.responses()?;
+    while !responses.at_end() {
+        let single = SingleResponse::parse(&mut responses.read_sequence()?)?;
+        if !single.matches(end_entity, issuer) {
+            continue;
+        }
+
+        if single.this_update > now {
+            return Err(OcspError::NotYetValid);
+        }
+
+        if let Some(next_update) = single.next_update {
+            if next_update < now {
+                return Err(OcspError::Expired);
+            }
+        }
+
+        return match single.status_tag {
+            TAG_CERT_STATUS_GOOD => {
+                trace!("OCSP response valid, certificate status good");
+                Ok(())
+            }
+            TAG_CERT_STATUS_REVOKED => Err(OcspError::CertRevoked),
+            _ => Err(OcspError::CertStatusUnknown),
+        };
+    }
+
+    Err(OcspError::CertNotInResponse)
+}
+
+fn verify_response_signature(
+    issuer: &Issuer,
+    responder_certs: Option<&[u8]>,
+    signature_alg: &[u8],
+    tbs: &[u8],
+    signature: &[u8],
+    now: u64,
+) -> Result<(), OcspError> {
+    let err = match issuer
+        .public_key
+        .verify(signature_alg, tbs, signature)
+    {
+        Ok(()) => return Ok(()),
+        Err(err) => err,
+    };
+
+    let responder_certs = match responder_certs {
+        Some(certs) => certs,
+        None => return Err(err.into()),
+    };
+
+    let mut outer = Reader::new(responder_certs);
+    let mut certs = outer.read_sequence()?;
+    outer.finish()?;
+
+    while !certs.at_end() {
+        let (_, cert) = certs.read_tlv(der::Tag::Sequence as u8)?;
+        let responder = CertInfo::parse(cert)?;
+
+        let ocsp_signing = match responder.extensions {
+            Some(extensions) => has_ocsp_signing_eku(extensions)?,
+            None => false,
+        };
+
+        if responder.issuer != issuer.subject.as_slice()
+            || !ocsp_signing
+            || responder.not_before > now
+            || responder.not_after < now
+        {
+            debug!("OCSP responder certificate unsuitable");
+            continue;
+        }
+
+        issuer
+            .public_key
+            .verify(responder.signature_alg, responder.tbs, responder.signature)?;
+        return responder
+            .public_key
+            .verify(signature_alg, tbs, signature)
+            .map_err(OcspError::from);
+    }
+
+    Err(OcspError::UnknownResponder)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::anchors::RootCertStore;
+    use crate::key::Certificate;
+
+    fn time(s: &str) -> u64 {
+        parse_time(s.as_bytes(), true).unwrap()
+    }
+
+    fn roots() -> RootCertStore {
+        let mut roots = RootCertStore::empty();
+        roots
+            .add(&Certificate(include_bytes!("testdata/ocsp-ca.der").to_vec()))
+            .unwrap();
+        roots
+    }
+
+    fn verify_with(
+        response: &[u8],
+        intermediates: &[Certificate],
+        now: &str,
+    ) -> Result<(), OcspError> {
+        let ee = include_bytes!("testdata/ocsp-end.der");
+        let roots = roots();
+        let path = x509::build_chain(ee, intermediates, &roots).unwrap();
+        verify_ocsp_response(response, &path[0], time(now))
+    }
+
+    fn verify(response: &[u8], now: &str) -> Result<(), OcspError> {
+        let inter = Certificate(include_bytes!("testdata/ocsp-inter.der").to_vec());
+        verify_with(response, &[inter], now)
+    }
+
+    #[test]
+    fn test_good_response() {
+        let resp = include_bytes!("testdata/ocsp-good.der");
+        assert_eq!(verify(resp, "20261101000000Z"), Ok(()));
+    }
+
+    #[test]
+    fn test_good_response_by_delegated_responder() {
+        let resp = include_bytes!("testdata/ocsp-good-delegated.der");
+        assert_eq!(verify(resp, "20261101000000Z"), Ok(()));
+    }
+
+    #[test]
+    fn test_revoked_response() {
+        let resp = include_bytes!("testdata/ocsp-revoked.der");
+        assert_eq!(verify(resp, "20261101000000Z"), Err(OcspError::CertRevoked));
+    }
+
+    #[test]
+    fn test_stale_response() {
+        let resp = include_bytes!("testdata/ocsp-good.der");
+        assert_eq!(verify(resp, "20270101000000Z"), Err(OcspError::Expired));
+        assert_eq!(verify(resp, "20260101000000Z"), Err(OcspError::NotYetValid));
+    }
+
+    #[test]
+    fn test_tampered_response() {
+        let mut resp = include_bytes!("testdata/ocsp-good.der").to_vec();
+        let last = resp.len() - 1;
+        resp[last] ^= 0x01;
+        assert_eq!(verify(&resp, "20261101000000Z"), Err(OcspError::BadSignature));
+    }
+
+    #[test]
+    fn test_next_update() {
+        let resp = include_bytes!("testdata/ocsp-good.der");
+        let expires = next_update(resp).unwrap();
+        assert!(expires > time("20261101000000Z"));
+        assert!(expires < time("20270101000000Z"));
+        assert_eq!(next_update(b"hello world"), None);
+    }
+
+    #[test]
+    fn test_same_name_issuer_rejected() {
+        // signed by a certificate with the intermediate's name but its
+        // own key, which the peer presents ahead of the real intermediate.
+        let resp = include_bytes!("testdata/ocsp-good-forged.der");
+        let intermediates = [
+            Certificate(include_bytes!("testdata/ocsp-forged-inter.der").to_vec()),
+            Certificate(include_bytes!("testdata/ocsp-inter.der").to_vec()),
+        ];
+        assert_eq!(
+            verify_with(resp, &intermediates, "20261101000000Z"),
+            Err(OcspError::BadSignature)
+        );
+    }
+
+    #[test]
+    fn test_garbage_response() {
+        assert_eq!(verify(b"hello world", "20261101000000Z"), Err(OcspError::Malformed));
+        assert_eq!(verify(&[0x30, 0x03, 0x0a, 0x01, 0x01], "20261101000000Z"),
+                   Err(OcspError::UnsuccessfulResponse));
+    }
+}
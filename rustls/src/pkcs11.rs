@@ -0,0 +1,410 @@
+//! Signing with private keys held in a PKCS#11 token, such as an HSM.
+//!
+//! rustls doesn't link against a PKCS#11 library itself.  Instead,
+//! implement `Pkcs11Token` using whichever PKCS#11 binding you prefer;
+//! it only needs to look up a key's type, list the token's mechanisms
+//! and call `C_SignInit`/`C_Sign`.  Then make a `Pkcs11SigningKey`
+//! for the private key object, which works out the TLS signature schemes
+//! the key and token support between them.
+//!
+//! The private key never leaves the token.
+
+use crate::error::TlsError;
+use crate::key;
+use crate::msgs::enums::{SignatureAlgorithm, SignatureScheme};
+use crate::sign;
+
+use std::sync::Arc;
+
+/// A PKCS#11 object handle (`CK_OBJECT_HANDLE`) for a private key.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ObjectHandle(pub u64);
+
+/// The type of a private key, from its `CKA_KEY_TYPE` and,
+/// for EC keys, `CKA_EC_PARAMS` attributes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum KeyType {
+    /// `CKK_RSA`.
+    Rsa,
+
+    /// `CKK_EC` on the NIST P-256 curve.
+    EcP256,
+
+    /// `CKK_EC` on the NIST P-384 curve.
+    EcP384,
+}
+
+/// A PKCS#11 signing mechanism.  Each of these hashes the data
+/// inside the token.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Mechanism {
+    /// `CKM_SHA256_RSA_PKCS`
+    Sha256RsaPkcs,
+    /// `CKM_SHA384_RSA_PKCS`
+    Sha384RsaPkcs,
+    /// `CKM_SHA512_RSA_PKCS`
+    Sha512RsaPkcs,
+    /// `CKM_SHA256_RSA_PKCS_PSS`, with the parameters from `pss_params`.
+    Sha256RsaPkcsPss,
+    /// `CKM_SHA384_RSA_PKCS_PSS`, with the parameters from `pss_params`.
+    Sha384RsaPkcsPss,
+    /// `CKM_SHA512_RSA_PKCS_PSS`, with the parameters from `pss_params`.
+    Sha512RsaPkcsPss,
+    /// `CKM_ECDSA_SHA256`
+    EcdsaSha256,
+    /// `CKM_ECDSA_SHA384`
+    EcdsaSha384,
+}
+
+/// The fields of a `CK_RSA_PKCS_PSS_PARAMS` structure.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PssParams {
+    /// `hashAlg`: a `CKM_SHA*` mechanism type.
+    pub hash_alg: u64,
+    /// `mgf`: a `CKG_MGF1_SHA*` value.
+    pub mgf: u64,
+    /// `sLen`: the salt length in bytes.
+    pub salt_len: u64,
+}
+
+impl Mechanism {
+    /// The `CK_MECHANISM_TYPE` value for this mechanism.
+    pub fn mechanism_type(self) -> u64 {
+        match self {
+            Mechanism::Sha256RsaPkcs => 0x40,
+            Mechanism::Sha384RsaPkcs => 0x41,
+            Mechanism::Sha512RsaPkcs => 0x42,
+            Mechanism::Sha256RsaPkcsPss => 0x43,
+            Mechanism::Sha384RsaPkcsPss => 0x44,
+            Mechanism::Sha512RsaPkcsPss => 0x45,
+            Mechanism::EcdsaSha256 => 0x1044,
+            Mechanism::EcdsaSha384 => 0x1045,
+        }
+    }
+
+    /// The mechanism parameters TLS requires for RSA-PSS: the
+    /// same hash for the message and MGF1, and a salt as long as
+    /// the hash.  `None` for other mechanisms.
+    pub fn pss_params(self) -> Option<PssParams> {
+        match self {
+            Mechanism::Sha256RsaPkcsPss => Some(PssParams {
+                hash_alg: 0x250,
+                mgf: 0x02,
+                salt_len: 32,
+            }),
+            Mechanism::Sha384RsaPkcsPss => Some(PssParams {
+                hash_alg: 0x260,
+                mgf: 0x03,
+                salt_len: 48,
+            }),
+            Mechanism::Sha512RsaPkcsPss => Some(PssParams {
+                hash_alg: 0x270,
+                mgf: 0x04,
+                salt_len: 64,
+            }),
+            _ => None,
+        }
+    }
+}
+
+/// Our signature schemes in preference order, with the key type
+/// and mechanism each needs.
+static SCHEMES: &[(SignatureScheme, KeyType, Mechanism)] = &[
+    (SignatureScheme::RSA_PSS_SHA512, KeyType::Rsa, Mechanism::Sha512RsaPkcsPss),
+    (SignatureScheme::RSA_PSS_SHA384, KeyType::Rsa, Mechanism::Sha384RsaPkcsPss),
+    (SignatureScheme::RSA_PSS_SHA256, KeyType::Rsa, Mechanism::Sha256RsaPkcsPss),
+    (SignatureScheme::RSA_PKCS1_SHA512, KeyType::Rsa, Mechanism::Sha512RsaPkcs),
+    (SignatureScheme::RSA_PKCS1_SHA384, KeyType::Rsa, Mechanism::Sha384RsaPkcs),
+    (SignatureScheme::RSA_PKCS1_SHA256, KeyType::Rsa, Mechanism::Sha256RsaPkcs),
+    (SignatureScheme::ECDSA_NISTP256_SHA256, KeyType::EcP256, Mechanism::EcdsaSha256),
+    (SignatureScheme::ECDSA_NISTP384_SHA384, KeyType::EcP384, Mechanism::EcdsaSha384),
+];
+
+/// The PKCS#11 operations rustls needs from a logged-in session.
+pub trait Pkcs11Token: Send + Sync {
+    /// Return the type of the private key object `key`.
+    fn key_type(&self, key: ObjectHandle) -> Result<KeyType, TlsError>;
+
+    /// Return the mechanism types the token supports, as from
+    /// `C_GetMechanismList`.
+    fn mechanisms(&self) -> Result<Vec<u64>, TlsError>;
+
+    /// Sign `data` with `key` using `mechanism`, as with `C_SignInit`
+    /// and `C_Sign`.
+    ///
+    /// ECDSA signatures should be returned as the token produces them:
+    /// `r` and `s` concatenated.  rustls converts them to the DER
+    /// encoding TLS uses.
+    fn sign(
+        &self,
+        key: ObjectHandle,
+        mechanism: Mechanism,
+        data: &[u8],
+    ) -> Result<Vec<u8>, TlsError>;
+}
+
+/// A `sign::SigningKey` for a private key held in a PKCS#11 token.
+pub struct Pkcs11SigningKey {
+    token: Arc<dyn Pkcs11Token>,
+    key: ObjectHandle,
+    key_type: KeyType,
+    schemes: Vec<(SignatureScheme, Mechanism)>,
+}
+
+impl Pkcs11SigningKey {
+    /// Make a `Pkcs11SigningKey` for the private key object `key`.
+    ///
+    /// This asks the token for the key's type and the mechanisms it
+    /// supports, and fails if there are no usable signature schemes.
+    pub fn new(token: Arc<dyn Pkcs11Token>, key: ObjectHandle) -> Result<Self, TlsError> {
+        let key_type = token.key_type(key)?;
+        let mechanisms = token.mechanisms()?;
+
+        let schemes: Vec<_> = SCHEMES
+            .iter()
+            .filter(|(_, kt, mech)| *kt == key_type && mechanisms.contains(&mech.mechanism_type()))
+            .map(|(scheme, _, mech)| (*scheme, *mech))
+            .collect();
+
+        if schemes.is_empty() {
+            return Err(TlsError::General(
+                "PKCS#11 token supports no signature schemes for this key".to_string(),
+            ));
+        }
+
+        Ok(Pkcs11SigningKey {
+            token,
+            key,
+            key_type,
+            schemes,
+        })
+    }
+
+    /// The signature schemes this key can be used with, most
+    /// preferred first.
+    pub fn supported_schemes(&self) -> Vec<SignatureScheme> {
+        self.schemes
+            .iter()
+            .map(|(scheme, _)| *scheme)
+            .collect()
+    }
+
+    /// Pair this key with its certificate chain, for use
+    /// in a `ResolvesServerCert` or `ResolvesClientCert`.
+    pub fn into_certified_key(self, cert_chain: Vec<key::Certificate>) -> sign::CertifiedKey {
+        let key: Box<dyn sign::SigningKey> = Box::new(self);
+        sign::CertifiedKey::new(cert_chain, Arc::new(key))
+    }
+}
+
+impl sign::SigningKey for Pkcs11SigningKey {
+    fn choose_scheme(&self, offered: &[SignatureScheme]) -> Option<Box<dyn sign::Signer>> {
+        self.schemes
+            .iter()
+            .find(|(scheme, _)| offered.contains(scheme))
+            .map(|(scheme, mechanism)| {
+                let signer: Box<dyn sign::Signer> = Box::new(Pkcs11Signer {
+                    token: self.token.clone(),
+                    key: self.key,
+                    scheme: *scheme,
+                    mechanism: *mechanism,
+                });
+                signer
+            })
+    }
+
+    fn algorithm(&self) -> SignatureAlgorithm {
+        match self.key_type {
+            KeyType::Rsa => SignatureAlgorithm::RSA,
+            KeyType::EcP256 | KeyType::EcP384 => SignatureAlgorithm::ECDSA,
+        }
+    }
+}
+
+struct Pkcs11Signer {
+    token: Arc<dyn Pkcs11Token>,
+    key: ObjectHandle,
+    scheme: SignatureScheme,
+    mechanism: Mechanism,
+}
+
+impl sign::Signer for Pkcs11Signer {
+    fn sign(&self, message: &[u8]) -> Result<Vec<u8>, TlsError> {
+        let sig = self
+            .token
+            .sign(self.key, self.mechanism, message)?;
+
+        match self.mechanism {
+            Mechanism::EcdsaSha256 | Mechanism::EcdsaSha384 => ecdsa_sig_to_der(&sig),
+            _ => Ok(sig),
+        }
+    }
+
+    fn get_scheme(&self) -> SignatureScheme {
+        self.scheme
+    }
+}
+
+/// Convert a PKCS#11 ECDSA signature (`r || s`) to a DER
+/// `ECDSA-Sig-Value`.
+fn ecdsa_sig_to_der(raw: &[u8]) -> Result<Vec<u8>, TlsError> {
+    // P-384 is the largest curve we support, so every length
+    // fits in a single byte.
+    let half = raw.len() / 2;
+    if half == 0 || half * 2 != raw.len() || half > 48 {
+        return Err(TlsError::General(
+            "PKCS#11 token returned a malformed ECDSA signature".to_string(),
+        ));
+    }
+
+    let (r, s) = raw.split_at(half);
+    let mut body = Vec::new();
+    der_unsigned_integer(r, &mut body);
+    der_unsigned_integer(s, &mut body);
+
+    let mut der = vec![0x30, body.len() as u8];
+    der.extend(body);
+    Ok(der)
+}
+
+fn der_unsigned_integer(mut value: &[u8], out: &mut Vec<u8>) {
+    while value.len() > 1 && value[0] == 0 {
+        value = &value[1..];
+    }
+
+    let pad = value[0] & 0x80 != 0;
+    out.push(0x02);
+    out.push((value.len() + pad as usize) as u8);
+    if pad {
+        out.push(0);
+    }
+    out.extend_from_slice(value);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::sign::SigningKey;
+    use ring::rand::SystemRandom;
+    use ring::signature::{self, EcdsaKeyPair, KeyPair};
+
+    /// A token holding at most one P-256 key, which signs like
+    /// a real token: producing `r || s`.
+    struct SoftToken {
+        key_type: KeyType,
+        mechanisms: Vec<u64>,
+        ecdsa: Option<EcdsaKeyPair>,
+    }
+
+    impl SoftToken {
+        fn p256() -> (SoftToken, Vec<u8>) {
+            let rng = SystemRandom::new();
+            let alg = &signature::ECDSA_P256_SHA256_FIXED_SIGNING;
+            let pkcs8 = EcdsaKeyPair::generate_pkcs8(alg, &rng).unwrap();
+            let key = EcdsaKeyPair::from_pkcs8(alg, pkcs8.as_ref()).unwrap();
+            let public = key.public_key().as_ref().to_vec();
+            let token = SoftToken {
+                key_type: KeyType::EcP256,
+                mechanisms: vec![0x1041, 0x1044, 0x1045],
+                ecdsa: Some(key),
+            };
+            (token, public)
+        }
+    }
+
+    impl Pkcs11Token for SoftToken {
+        fn key_type(&self, key: ObjectHandle) -> Result<KeyType, TlsError> {
+            assert_eq!(key, ObjectHandle(7));
+            Ok(self.key_type)
+        }
+
+        fn mechanisms(&self) -> Result<Vec<u64>, TlsError> {
+            Ok(self.mechanisms.clone())
+        }
+
+        fn sign(
+            &self,
+            key: ObjectHandle,
+            mechanism: Mechanism,
+            data: &[u8],
+        ) -> Result<Vec<u8>, TlsError> {
+            assert_eq!(key, ObjectHandle(7));
+            assert_eq!(mechanism, Mechanism::EcdsaSha256);
+            let sig = self
+                .ecdsa
+                .as_ref()
+                .unwrap()
+                .sign(&SystemRandom::new(), data)
+                .unwrap();
+            Ok(sig.as_ref().to_vec())
+        }
+    }
+
+    #[test]
+    fn test_ecdsa_signing() {
+        let (token, public) = SoftToken::p256();
+        let key = Pkcs11SigningKey::new(Arc::new(token), ObjectHandle(7)).unwrap();
+        assert_eq!(
+            key.supported_schemes(),
+            vec![SignatureScheme::ECDSA_NISTP256_SHA256]
+        );
+        assert_eq!(key.algorithm(), SignatureAlgorithm::ECDSA);
+        assert!(key
+            .choose_scheme(&[SignatureScheme::ECDSA_NISTP384_SHA384])
+            .is_none());
+
+        let signer = key
+            .choose_scheme(&[
+                SignatureScheme::RSA_PSS_SHA256,
+                SignatureScheme::ECDSA_NISTP256_SHA256,
+            ])
+            .unwrap();
+        assert_eq!(signer.get_scheme(), SignatureScheme::ECDSA_NISTP256_SHA256);
+
+        let sig = signer.sign(b"hello").unwrap();
+        signature::UnparsedPublicKey::new(&signature::ECDSA_P256_SHA256_ASN1, public)
+            .verify(b"hello", &sig)
+            .unwrap();
+    }
+
+    #[test]
+    fn test_scheme_discovery() {
+        let token = SoftToken {
+            key_type: KeyType::Rsa,
+            mechanisms: vec![0x1, 0x40, 0x43, 0x45, 0x1044],
+            ecdsa: None,
+        };
+        let key = Pkcs11SigningKey::new(Arc::new(token), ObjectHandle(7)).unwrap();
+        assert_eq!(
+            key.supported_schemes(),
+            vec![
+                SignatureScheme::RSA_PSS_SHA512,
+                SignatureScheme::RSA_PSS_SHA256,
+                SignatureScheme::RSA_PKCS1_SHA256,
+            ]
+        );
+        assert_eq!(key.algorithm(), SignatureAlgorithm::RSA);
+
+        let token = SoftToken {
+            key_type: KeyType::EcP384,
+            mechanisms: vec![0x40, 0x1044],
+            ecdsa: None,
+        };
+        assert!(Pkcs11SigningKey::new(Arc::new(token), ObjectHandle(7)).is_err());
+    }
+
+    #[test]
+    fn test_ecdsa_sig_to_der() {
+        assert_eq!(
+            ecdsa_sig_to_der(&[0x00, 0x01, 0x80, 0x02]).unwrap(),
+            vec![0x30, 0x08, 0x02, 0x01, 0x01, 0x02, 0x03, 0x00, 0x80, 0x02]
+        );
+        assert_eq!(
+            ecdsa_sig_to_der(&[0x00, 0x00]).unwrap(),
+            vec![0x30, 0x06, 0x02, 0x01, 0x00, 0x02, 0x01, 0x00]
+        );
+        assert!(ecdsa_sig_to_der(&[]).is_err());
+        assert!(ecdsa_sig_to_der(&[1, 2, 3]).is_err());
+        assert!(ecdsa_sig_to_der(&[0; 98]).is_err());
+    }
+}
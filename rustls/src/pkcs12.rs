@@ -0,0 +1,535 @@
+//! Loading a certificate chain and private key from a PKCS#12 archive.
+//!
+//! These are the `.p12` and `.pfx` files exported by Windows, macOS
+//! Keychain, Java's keytool and `openssl pkcs12 -export`.
+//!
+//! Encrypted contents must use PBES2 with PBKDF2 and AES-CBC.  That is
+//! the default for OpenSSL 3 and the "AES256-SHA256" export option on
+//! Windows; archives made with the older RC2 and 3DES schemes are
+//! rejected with `Pkcs12Error::UnsupportedAlgorithm`, and need re-exporting.
+
+use crate::encrypted_keys::{decrypt_pbes2, read_u32, DecryptError};
+use crate::key;
+use crate::sign;
+use crate::x509::{self, CertInfo, Reader, TAG_CONTEXT_0};
+
+use ring::io::der;
+use ring::{digest, hmac};
+use std::error::Error;
+use std::fmt;
+use std::sync::Arc;
+
+const OID_DATA: &[u8] = &[0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x07, 0x01];
+const OID_ENCRYPTED_DATA: &[u8] = &[0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x07, 0x06];
+
+const OID_KEY_BAG: &[u8] = &[
+    0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x0c, 0x0a, 0x01, 0x01,
+];
+const OID_SHROUDED_KEY_BAG: &[u8] = &[
+    0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x0c, 0x0a, 0x01, 0x02,
+];
+const OID_CERT_BAG: &[u8] = &[
+    0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x0c, 0x0a, 0x01, 0x03,
+];
+
+const OID_X509_CERTIFICATE: &[u8] = &[0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x09, 0x16, 0x01];
+const OID_LOCAL_KEY_ID: &[u8] = &[0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x09, 0x15];
+
+const TAG_SET: u8 = 0x31;
+
+/// `encryptedContent [0] IMPLICIT OCTET STRING`.
+const TAG_IMPLICIT_0: u8 = 0x80;
+
+/// The PKCS#12 key derivation ID for MAC keys (RFC7292 appendix B.3).
+const KDF_ID_MAC: u8 = 3;
+
+/// Why a PKCS#12 archive couldn't be loaded.
+#[derive(Debug, PartialEq, Clone)]
+#[non_exhaustive]
+pub enum Pkcs12Error {
+    /// The input isn't a DER-encoded PKCS#12 archive.
+    Malformed,
+    /// The archive uses an integrity or encryption scheme we
+    /// don't support.
+    UnsupportedAlgorithm,
+    /// The archive's MAC didn't verify, or its contents didn't
+    /// decrypt.  This is almost always because the password was wrong.
+    WrongPassword,
+    /// The archive doesn't contain a private key.
+    MissingKey,
+    /// The archive doesn't contain a certificate.
+    MissingCertificate,
+    /// The private key isn't of a type rustls can sign with.
+    UnsupportedKey,
+}
+
+impl fmt::Display for Pkcs12Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Pkcs12Error::Malformed => write!(f, "malformed PKCS#12 archive"),
+            Pkcs12Error::UnsupportedAlgorithm => write!(f, "unsupported PKCS#12 algorithm"),
+            Pkcs12Error::WrongPassword => {
+                write!(f, "PKCS#12 integrity check failed (wrong password?)")
+            }
+            Pkcs12Error::MissingKey => write!(f, "no private key in PKCS#12 archive"),
+            Pkcs12Error::MissingCertificate => write!(f, "no certificate in PKCS#12 archive"),
+            Pkcs12Error::UnsupportedKey => write!(f, "unsupported private key type"),
+        }
+    }
+}
+
+impl Error for Pkcs12Error {}
+
+impl From<x509::Error> for Pkcs12Error {
+    fn from(_: x509::Error) -> Self {
+        Pkcs12Error::Malformed
+    }
+}
+
+impl From<DecryptError> for Pkcs12Error {
+    fn from(err: DecryptError) -> Self {
+        match err {
+            DecryptError::UnsupportedAlgorithm => Pkcs12Error::UnsupportedAlgorithm,
+            DecryptError::DecryptionFailed => Pkcs12Error::WrongPassword,
+            _ => Pkcs12Error::Malformed,
+        }
+    }
+}
+
+/// Load the DER-encoded PKCS#12 archive `der`, protected by `password`.
+///
+/// The result has the end-entity certificate for the archive's private
+/// key first, followed by any certificates in the archive which issued
+/// it, in order.
+pub fn load_pkcs12(der: &[u8], password: &str) -> Result<sign::CertifiedKey, Pkcs12Error> {
+    let mut outer = Reader::new(der);
+    let mut pfx = outer.read_sequence()?;
+    outer.finish()?;
+
+    if pfx.read(der::Tag::Integer as u8)? != [3] {
+        return Err(Pkcs12Error::Malformed);
+    }
+
+    // only password integrity mode is supported, not public-key
+    // integrity mode (where the contents are signed).
+    let mut auth_safe = pfx.read_sequence()?;
+    if auth_safe.read(der::Tag::OID as u8)? != OID_DATA {
+        return Err(Pkcs12Error::UnsupportedAlgorithm);
+    }
+    let contents = read_explicit_octets(&mut auth_safe)?;
+    auth_safe.finish()?;
+
+    if let Some(mac_data) = pfx.read_optional(der::Tag::Sequence as u8)? {
+        verify_mac(mac_data, contents, password)?;
+    }
+    pfx.finish()?;
+
+    let mut bags = Bags::default();
+    let mut outer = Reader::new(contents);
+    let mut content_infos = outer.read_sequence()?;
+    outer.finish()?;
+
+    while !content_infos.at_end() {
+        let mut content_info = content_infos.read_sequence()?;
+        let content_type = content_info.read(der::Tag::OID as u8)?;
+        if content_type == OID_DATA {
+            bags.read(read_explicit_octets(&mut content_info)?, password)?;
+        } else if content_type == OID_ENCRYPTED_DATA {
+            let plaintext = decrypt_content(&mut content_info, password)?;
+            bags.read(&plaintext, password)?;
+        } else {
+            return Err(Pkcs12Error::UnsupportedAlgorithm);
+        }
+        content_info.finish()?;
+    }
+
+    bags.into_certified_key()
+}
+
+/// A bag's contents and its localKeyId attribute, if any.
+struct Bag {
+    value: Vec<u8>,
+    local_key_id: Option<Vec<u8>>,
+}
+
+#[derive(Default)]
+struct Bags {
+    keys: Vec<Bag>,
+    certs: Vec<Bag>,
+}
+
+impl Bags {
+    /// Collect the bags in the SafeContents `der`.
+    fn read(&mut self, der: &[u8], password: &str) -> Result<(), Pkcs12Error> {
+        let mut outer = Reader::new(der);
+        let mut safe_contents = outer.read_sequence()?;
+        outer.finish()?;
+
+        while !safe_contents.at_end() {
+            let mut safe_bag = safe_contents.read_sequence()?;
+            let bag_id = safe_bag.read(der::Tag::OID as u8)?;
+            let mut value = Reader::new(safe_bag.read(TAG_CONTEXT_0)?);
+            let local_key_id = match safe_bag.read_optional(TAG_SET)? {
+                Some(attributes) => find_local_key_id(attributes)?,
+                None => None,
+            };
+            safe_bag.finish()?;
+
+            if bag_id == OID_KEY_BAG {
+                let (_, key) = value.read_tlv(der::Tag::Sequence as u8)?;
+                value.finish()?;
+                self.keys.push(Bag {
+                    value: key.to_vec(),
+                    local_key_id,
+                });
+            } else if bag_id == OID_SHROUDED_KEY_BAG {
+                let mut info = value.read_sequence()?;
+                let mut algorithm = info.read_sequence()?;
+                let ciphertext = info.read(der::Tag::OctetString as u8)?;
+                info.finish()?;
+                value.finish()?;
+                self.keys.push(Bag {
+                    value: decrypt_pbes2(&mut algorithm, ciphertext, password.as_bytes())?,
+                    local_key_id,
+                });
+            } else if bag_id == OID_CERT_BAG {
+                let mut cert_bag = value.read_sequence()?;
+                let cert_type = cert_bag.read(der::Tag::OID as u8)?;
+                let cert = read_explicit_octets(&mut cert_bag)?;
+                cert_bag.finish()?;
+                value.finish()?;
+
+                // other certificate types (eg. SDSI) are ignored
+                if cert_type == OID_X509_CERTIFICATE {
+                    self.certs.push(Bag {
+                        value: cert.to_vec(),
+                        local_key_id,
+                    });
+                }
+            }
+
+            // CRL bags, secret bags, and nested SafeContents are ignored
+        }
+
+        Ok(())
+    }
+
+    fn into_certified_key(mut self) -> Result<sign::CertifiedKey, Pkcs12Error> {
+        if self.keys.is_empty() {
+            return Err(Pkcs12Error::MissingKey);
+        }
+        let key = self.keys.swap_remove(0);
+
+        let leaf = self
+            .find_leaf(key.local_key_id.as_ref())
+            .ok_or(Pkcs12Error::MissingCertificate)?;
+        let mut chain = vec![self.certs.swap_remove(leaf).value];
+
+        // follow issuers through the remaining certificates
+        loop {
+            let last = CertInfo::parse(chain.last().unwrap())?;
+            if last.issuer == last.subject {
+                break;
+            }
+
+            let issuer = self.certs.iter().position(|cert| {
+                CertInfo::parse(&cert.value)
+                    .map(|info| info.subject == last.issuer)
+                    .unwrap_or(false)
+            });
+            match issuer {
+                Some(idx) => chain.push(self.certs.swap_remove(idx).value),
+                None => break,
+            }
+        }
+
+        let signing_key = sign::any_supported_type(&key::PrivateKey(key.value))
+            .map_err(|_| Pkcs12Error::UnsupportedKey)?;
+
+        Ok(sign::CertifiedKey::new(
+            chain
+                .into_iter()
+                .map(key::Certificate)
+                .collect(),
+            Arc::new(signing_key),
+        ))
+    }
+
+    /// Find the end-entity certificate: the one with the key's
+    /// localKeyId, or otherwise the one which issued no others.
+    fn find_leaf(&self, local_key_id: Option<&Vec<u8>>) -> Option<usize> {
+        if let Some(id) = local_key_id {
+            let found = self
+                .certs
+                .iter()
+                .position(|cert| cert.local_key_id.as_ref() == Some(id));
+            if found.is_some() {
+                return found;
+            }
+        }
+
+        let infos = self
+            .certs
+            .iter()
+            .map(|cert| CertInfo::parse(&cert.value))
+            .collect::<Result<Vec<_>, _>>()
+            .ok()?;
+
+        infos.iter().position(|candidate| {
+            !infos.iter().any(|other| {
+                other.issuer == candidate.subject && other.subject != candidate.subject
+            })
+        })
+    }
+}
+
+/// Read `[0] EXPLICIT OCTET STRING`, returning the octets.
+fn read_explicit_octets<'a>(reader: &mut Reader<'a>) -> Result<&'a [u8], Pkcs12Error> {
+    let mut explicit = Reader::new(reader.read(TAG_CONTEXT_0)?);
+    let octets = explicit.read(der::Tag::OctetString as u8)?;
+    explicit.finish()?;
+    Ok(octets)
+}
+
+/// Decrypt the `[0] EXPLICIT EncryptedData` in a ContentInfo.
+fn decrypt_content(content_info: &mut Reader, password: &str) -> Result<Vec<u8>, Pkcs12Error> {
+    let mut explicit = Reader::new(content_info.read(TAG_CONTEXT_0)?);
+    let mut encrypted_data = explicit.read_sequence()?;
+    explicit.finish()?;
+
+    encrypted_data.read(der::Tag::Integer as u8)?;
+    let mut encrypted_content_info = encrypted_data.read_sequence()?;
+    encrypted_data.finish()?;
+
+    if encrypted_content_info.read(der::Tag::OID as u8)? != OID_DATA {
+        return Err(Pkcs12Error::Malformed);
+    }
+    let mut algorithm = encrypted_content_info.read_sequence()?;
+    let ciphertext = encrypted_content_info.read(TAG_IMPLICIT_0)?;
+    encrypted_content_info.finish()?;
+
+    Ok(decrypt_pbes2(
+        &mut algorithm,
+        ciphertext,
+        password.as_bytes(),
+    )?)
+}
+
+/// Look for a localKeyId in the bag's attributes.
+fn find_local_key_id(attributes: &[u8]) -> Result<Option<Vec<u8>>, Pkcs12Error> {
+    let mut attributes = Reader::new(attributes);
+    while !attributes.at_end() {
+        let mut attribute = attributes.read_sequence()?;
+        let oid = attribute.read(der::Tag::OID as u8)?;
+        let mut values = Reader::new(attribute.read(TAG_SET)?);
+        attribute.finish()?;
+
+        if oid == OID_LOCAL_KEY_ID {
+            return Ok(Some(
+                values
+                    .read(der::Tag::OctetString as u8)?
+                    .to_vec(),
+            ));
+        }
+    }
+
+    Ok(None)
+}
+
+/// Check the MacData over the AuthenticatedSafe `contents`.
+fn verify_mac(mac_data: &[u8], contents: &[u8], password: &str) -> Result<(), Pkcs12Error> {
+    let mut mac_data = Reader::new(mac_data);
+    let mut digest_info = mac_data.read_sequence()?;
+    let (hash_oid, _) = digest_info.read_algorithm()?;
+    let expected = digest_info.read(der::Tag::OctetString as u8)?;
+    digest_info.finish()?;
+
+    let salt = mac_data.read(der::Tag::OctetString as u8)?;
+    let iterations = match mac_data.read_optional(der::Tag::Integer as u8)? {
+        Some(iterations) => read_u32(iterations).ok_or(Pkcs12Error::Malformed)?,
+        None => 1,
+    };
+    mac_data.finish()?;
+
+    let (hash, hmac_alg) = match hash_oid {
+        x509::OID_SHA1 => (
+            &digest::SHA1_FOR_LEGACY_USE_ONLY,
+            hmac::HMAC_SHA1_FOR_LEGACY_USE_ONLY,
+        ),
+        x509::OID_SHA256 => (&digest::SHA256, hmac::HMAC_SHA256),
+        x509::OID_SHA384 => (&digest::SHA384, hmac::HMAC_SHA384),
+        x509::OID_SHA512 => (&digest::SHA512, hmac::HMAC_SHA512),
+        _ => return Err(Pkcs12Error::UnsupportedAlgorithm),
+    };
+
+    let mac_key = pkcs12_kdf(
+        hash,
+        KDF_ID_MAC,
+        &bmp_password(password),
+        salt,
+        iterations,
+        hash.output_len,
+    );
+    hmac::verify(&hmac::Key::new(hmac_alg, &mac_key), contents, expected)
+        .map_err(|_| Pkcs12Error::WrongPassword)
+}
+
+/// The password as a NUL-terminated, big-endian UTF-16 BMPString.
+fn bmp_password(password: &str) -> Vec<u8> {
+    let mut bmp = Vec::new();
+    for unit in password.encode_utf16() {
+        bmp.extend_from_slice(&unit.to_be_bytes());
+    }
+    bmp.extend_from_slice(&[0, 0]);
+    bmp
+}
+
+/// The PKCS#12 key derivation function (RFC7292 appendix B.2).
+fn pkcs12_kdf(
+    hash: &'static digest::Algorithm,
+    id: u8,
+    password: &[u8],
+    salt: &[u8],
+    iterations: u32,
+    out_len: usize,
+) -> Vec<u8> {
+    let v = hash.block_len;
+
+    // repeat `input` to fill a whole number of `v`-byte blocks
+    let fill = |input: &[u8]| -> Vec<u8> {
+        let blocks = match input.len() % v {
+            0 => input.len() / v,
+            _ => input.len() / v + 1,
+        };
+        input
+            .iter()
+            .cycle()
+            .take(blocks * v)
+            .cloned()
+            .collect()
+    };
+
+    let diversifier = vec![id; v];
+    let mut input = fill(salt);
+    input.extend(fill(password));
+
+    let mut out = Vec::new();
+    while out.len() < out_len {
+        let mut ctx = digest::Context::new(hash);
+        ctx.update(&diversifier);
+        ctx.update(&input);
+        let mut a = ctx.finish();
+        for _ in 1..iterations {
+            a = digest::digest(hash, a.as_ref());
+        }
+        out.extend_from_slice(a.as_ref());
+
+        // treating each block of `input` as a big-endian integer,
+        // add `a` (repeated to fill a block) plus one.
+        let b = fill(a.as_ref());
+        for block in input.chunks_mut(v) {
+            let mut carry = 1u16;
+            for (x, y) in block.iter_mut().zip(&b).rev() {
+                let sum = u16::from(*x) + u16::from(*y) + carry;
+                *x = sum as u8;
+                carry = sum >> 8;
+            }
+        }
+    }
+
+    out.truncate(out_len);
+    out
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn chain_der(path: &str) -> Vec<Vec<u8>> {
+        // the test-ca `end.fullchain` files, but in DER
+        let pem = match path {
+            "ecdsa" => &include_bytes!("../../test-ca/ecdsa/end.fullchain")[..],
+            "rsa" => &include_bytes!("../../test-ca/rsa/end.fullchain")[..],
+            _ => unreachable!(),
+        };
+
+        let mut certs = Vec::new();
+        let mut body = String::new();
+        for line in std::str::from_utf8(pem)
+            .unwrap()
+            .lines()
+        {
+            if line.starts_with("-----END") {
+                certs.push(base64_decode(&body));
+                body.clear();
+            } else if !line.starts_with("-----") {
+                body.push_str(line.trim());
+            }
+        }
+        certs
+    }
+
+    fn base64_decode(text: &str) -> Vec<u8> {
+        const ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+        let mut out = Vec::new();
+        let (mut acc, mut bits) = (0u32, 0);
+        for c in text.bytes().filter(|c| *c != b'=') {
+            let value = ALPHABET
+                .iter()
+                .position(|a| *a == c)
+                .unwrap() as u32;
+            acc = (acc << 6) | value;
+            bits += 6;
+            if bits >= 8 {
+                bits -= 8;
+                out.push((acc >> bits) as u8);
+            }
+        }
+        out
+    }
+
+    fn certs(key: &sign::CertifiedKey) -> Vec<Vec<u8>> {
+        key.cert
+            .iter()
+            .map(|c| c.0.clone())
+            .collect()
+    }
+
+    #[test]
+    fn test_load_pbes2_archive() {
+        let p12 = include_bytes!("testdata/pkcs12-ecdsa.p12");
+        let key = load_pkcs12(p12, "hunter2").unwrap();
+        assert_eq!(certs(&key), chain_der("ecdsa"));
+
+        assert_eq!(
+            load_pkcs12(p12, "hunter3").err(),
+            Some(Pkcs12Error::WrongPassword)
+        );
+        assert_eq!(
+            load_pkcs12(&p12[..100], "hunter2").err(),
+            Some(Pkcs12Error::Malformed)
+        );
+    }
+
+    #[test]
+    fn test_load_unencrypted_archive() {
+        let p12 = include_bytes!("testdata/pkcs12-rsa-unencrypted.p12");
+        let key = load_pkcs12(p12, "hunter2").unwrap();
+        assert_eq!(certs(&key), chain_der("rsa"));
+    }
+
+    #[test]
+    fn test_rejects_legacy_encryption() {
+        let p12 = include_bytes!("testdata/pkcs12-legacy.p12");
+        assert_eq!(
+            load_pkcs12(p12, "hunter2").err(),
+            Some(Pkcs12Error::UnsupportedAlgorithm)
+        );
+    }
+
+    #[test]
+    fn test_bmp_password() {
+        assert_eq!(bmp_password(""), vec![0, 0]);
+        assert_eq!(bmp_password("a\u{e9}"), vec![0, b'a', 0, 0xe9, 0, 0]);
+    }
+}
@@ -6,13 +6,13 @@ use crate::msgs::enums::{AlertDescription, ContentType, ProtocolVersion};
 use crate::msgs::handshake::{ClientExtension, ServerExtension};
 use crate::msgs::message::{Message, MessagePayload};
 use crate::server::{ServerConfig, ServerSession, ServerSessionImpl};
+use crate::server_name::ServerName;
 use crate::session::{Protocol, SessionCommon};
 use crate::suites::{BulkAlgorithm, SupportedCipherSuite, TLS13_AES_128_GCM_SHA256};
 
 use std::sync::Arc;
 
 use ring::{aead, hkdf};
-use webpki;
 
 /// Secrets used to encrypt/decrypt traffic
 #[derive(Clone, Debug)]
@@ -39,8 +39,16 @@ pub trait QuicExt {
     fn get_quic_transport_parameters(&self) -> Option<&[u8]>;
 
     /// Compute the keys for encrypting/decrypting 0-RTT packets, if available
+    ///
+    /// These are derived from the client early traffic secret. A client has them as soon as it
+    /// has offered 0-RTT, and loses them if the server rejects it.
     fn get_0rtt_keys(&self) -> Option<DirectionalKeys>;
 
+    /// Whether 0-RTT was accepted by the server
+    ///
+    /// Check after `read_hs` to learn whether 0-RTT packets have to be discarded.
+    fn get_0rtt_status(&self) -> ZeroRttStatus;
+
     /// Consume unencrypted TLS handshake data.
     ///
     /// Handshake data obtained from separate encryption levels should be supplied in separate calls.
@@ -75,6 +83,7 @@ impl QuicExt for ClientSession {
     fn get_0rtt_keys(&self) -> Option<DirectionalKeys> {
         Some(DirectionalKeys::new(
             self.imp.resumption_ciphersuite?,
+            self.imp.common.quic.version,
             self.imp
                 .common
                 .quic
@@ -83,6 +92,10 @@ impl QuicExt for ClientSession {
         ))
     }
 
+    fn get_0rtt_status(&self) -> ZeroRttStatus {
+        self.imp.common.quic.zero_rtt
+    }
+
     fn read_hs(&mut self, plaintext: &[u8]) -> Result<(), TlsError> {
         read_hs(&mut self.imp.common, plaintext)?;
         self.imp
@@ -115,6 +128,7 @@ impl QuicExt for ServerSession {
     fn get_0rtt_keys(&self) -> Option<DirectionalKeys> {
         Some(DirectionalKeys::new(
             self.imp.common.get_suite()?,
+            self.imp.common.quic.version,
             self.imp
                 .common
                 .quic
@@ -123,6 +137,10 @@ impl QuicExt for ServerSession {
         ))
     }
 
+    fn get_0rtt_status(&self) -> ZeroRttStatus {
+        self.imp.common.quic.zero_rtt
+    }
+
     fn read_hs(&mut self, plaintext: &[u8]) -> Result<(), TlsError> {
         read_hs(&mut self.imp.common, plaintext)?;
         self.imp
@@ -141,6 +159,22 @@ impl QuicExt for ServerSession {
     }
 }
 
+/// The fate of 0-RTT data on a QUIC session
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ZeroRttStatus {
+    /// The client did not offer 0-RTT
+    NotAttempted,
+    /// The client offered 0-RTT and is waiting to hear whether the server accepted it
+    Pending,
+    /// The server accepted 0-RTT, so 0-RTT packets will be processed
+    Accepted,
+    /// The server rejected 0-RTT
+    ///
+    /// A client must treat any 0-RTT packets it sent as lost, and a server must
+    /// discard any it receives.
+    Rejected,
+}
+
 /// Keys used to communicate in a single direction
 pub struct DirectionalKeys {
     /// Encrypts or decrypts a packet's headers
@@ -150,7 +184,7 @@ pub struct DirectionalKeys {
 }
 
 impl DirectionalKeys {
-    fn new(suite: &'static SupportedCipherSuite, secret: &hkdf::Prk) -> Self {
+    fn new(suite: &'static SupportedCipherSuite, version: Version, secret: &hkdf::Prk) -> Self {
         let hp_alg = match suite.bulk {
             BulkAlgorithm::AES_128_GCM => &aead::quic::AES_128,
             BulkAlgorithm::AES_256_GCM => &aead::quic::AES_256,
@@ -158,8 +192,8 @@ impl DirectionalKeys {
         };
 
         Self {
-            header: hkdf_expand(secret, hp_alg, b"quic hp", &[]),
-            packet: PacketKey::new(suite, secret),
+            header: hkdf_expand(secret, hp_alg, version.labels().hp, &[]),
+            packet: PacketKey::new(suite, version, secret),
         }
     }
 }
@@ -173,15 +207,16 @@ pub struct PacketKey {
 }
 
 impl PacketKey {
-    fn new(suite: &'static SupportedCipherSuite, secret: &hkdf::Prk) -> Self {
+    fn new(suite: &'static SupportedCipherSuite, version: Version, secret: &hkdf::Prk) -> Self {
+        let labels = version.labels();
         Self {
             key: aead::LessSafeKey::new(hkdf_expand(
                 secret,
                 suite.aead_algorithm,
-                b"quic key",
+                labels.key,
                 &[],
             )),
-            iv: hkdf_expand(secret, IvLen, b"quic iv", &[]),
+            iv: hkdf_expand(secret, IvLen, labels.iv, &[]),
         }
     }
 }
@@ -235,8 +270,14 @@ pub struct Keys {
 
 impl Keys {
     /// Construct keys for use with initial packets
-    pub fn initial(
+    pub fn initial(version: Version, client_dst_connection_id: &[u8], is_client: bool) -> Self {
+        let initial_salt = hkdf::Salt::new(hkdf::HKDF_SHA256, version.initial_salt());
+        Self::initial_with_salt(&initial_salt, version, client_dst_connection_id, is_client)
+    }
+
+    fn initial_with_salt(
         initial_salt: &hkdf::Salt,
+        version: Version,
         client_dst_connection_id: &[u8],
         is_client: bool,
     ) -> Self {
@@ -248,14 +289,19 @@ impl Keys {
             client: hkdf_expand(&hs_secret, hkdf::HKDF_SHA256, CLIENT_LABEL, &[]),
             server: hkdf_expand(&hs_secret, hkdf::HKDF_SHA256, SERVER_LABEL, &[]),
         };
-        Self::new(&TLS13_AES_128_GCM_SHA256, is_client, &secrets)
+        Self::new(&TLS13_AES_128_GCM_SHA256, version, is_client, &secrets)
     }
 
-    fn new(suite: &'static SupportedCipherSuite, is_client: bool, secrets: &Secrets) -> Self {
+    fn new(
+        suite: &'static SupportedCipherSuite,
+        version: Version,
+        is_client: bool,
+        secrets: &Secrets,
+    ) -> Self {
         let (local, remote) = secrets.local_remote(is_client);
         Keys {
-            local: DirectionalKeys::new(suite, local),
-            remote: DirectionalKeys::new(suite, remote),
+            local: DirectionalKeys::new(suite, version, local),
+            remote: DirectionalKeys::new(suite, version, remote),
         }
     }
 }
@@ -287,12 +333,22 @@ fn write_hs(this: &mut SessionCommon, buf: &mut Vec<u8>) -> Option<Keys> {
         }
     }
     if let Some(secrets) = this.quic.hs_secrets.take() {
-        return Some(Keys::new(this.get_suite_assert(), this.is_client, &secrets));
+        return Some(Keys::new(
+            this.get_suite_assert(),
+            this.quic.version,
+            this.is_client,
+            &secrets,
+        ));
     }
     if let Some(secrets) = this.quic.traffic_secrets.as_ref() {
         if !this.quic.returned_traffic_keys {
             this.quic.returned_traffic_keys = true;
-            return Some(Keys::new(this.get_suite_assert(), this.is_client, &secrets));
+            return Some(Keys::new(
+                this.get_suite_assert(),
+                this.quic.version,
+                this.is_client,
+                secrets,
+            ));
         }
     }
     None
@@ -306,22 +362,24 @@ fn next_1rtt_keys(this: &mut SessionCommon) -> PacketKeySet {
         .as_ref()
         .expect("traffic keys not yet available");
 
-    let next = next_1rtt_secrets(hkdf_alg, secrets);
+    let version = this.quic.version;
+    let next = next_1rtt_secrets(hkdf_alg, version, secrets);
 
     let (local, remote) = next.local_remote(this.is_client);
     let keys = PacketKeySet {
-        local: PacketKey::new(this.get_suite_assert(), local),
-        remote: PacketKey::new(this.get_suite_assert(), remote),
+        local: PacketKey::new(this.get_suite_assert(), version, local),
+        remote: PacketKey::new(this.get_suite_assert(), version, remote),
     };
 
     this.quic.traffic_secrets = Some(next);
     keys
 }
 
-fn next_1rtt_secrets(hkdf_alg: hkdf::Algorithm, prev: &Secrets) -> Secrets {
+fn next_1rtt_secrets(hkdf_alg: hkdf::Algorithm, version: Version, prev: &Secrets) -> Secrets {
+    let label = version.labels().ku;
     Secrets {
-        client: hkdf_expand(&prev.client, hkdf_alg, b"quic ku", &[]),
-        server: hkdf_expand(&prev.server, hkdf_alg, b"quic ku", &[]),
+        client: hkdf_expand(&prev.client, hkdf_alg, label, &[]),
+        server: hkdf_expand(&prev.server, hkdf_alg, label, &[]),
     }
 }
 
@@ -333,23 +391,21 @@ pub trait ClientQuicExt {
     fn new_quic(
         config: &Arc<ClientConfig>,
         quic_version: Version,
-        hostname: webpki::DNSNameRef,
+        name: ServerName,
         params: Vec<u8>,
     ) -> Result<ClientSession, TlsError> {
         assert!(
-            config
-                .versions
-                .iter()
-                .all(|x| x.get_u16() >= ProtocolVersion::TLSv1_3.get_u16()),
+            !config.supports_version(ProtocolVersion::TLSv1_2),
             "QUIC requires TLS version >= 1.3"
         );
         let ext = match quic_version {
             Version::V1Draft => ClientExtension::TransportParametersDraft(params),
-            Version::V1 => ClientExtension::TransportParameters(params),
+            Version::V1 | Version::V2 => ClientExtension::TransportParameters(params),
         };
         let mut imp = ClientSessionImpl::new(config);
         imp.common.protocol = Protocol::Quic;
-        imp.start_handshake(hostname.into(), vec![ext])?;
+        imp.common.quic.version = quic_version;
+        imp.start_handshake(name, vec![ext])?;
         Ok(ClientSession { imp })
     }
 }
@@ -363,10 +419,7 @@ pub trait ServerQuicExt {
     /// TLS-encoded transport parameters to send.
     fn new_quic(config: &Arc<ServerConfig>, quic_version: Version, params: Vec<u8>) -> ServerSession {
         assert!(
-            config
-                .versions
-                .iter()
-                .all(|x| x.get_u16() >= ProtocolVersion::TLSv1_3.get_u16()),
+            !config.supports_version(ProtocolVersion::TLSv1_2),
             "QUIC requires TLS version >= 1.3"
         );
         assert!(
@@ -375,10 +428,11 @@ pub trait ServerQuicExt {
         );
         let ext = match quic_version {
             Version::V1Draft => ServerExtension::TransportParametersDraft(params),
-            Version::V1 => ServerExtension::TransportParameters(params),
+            Version::V1 | Version::V2 => ServerExtension::TransportParameters(params),
         };
         let mut imp = ServerSessionImpl::new(config, vec![ext]);
         imp.common.protocol = Protocol::Quic;
+        imp.common.quic.version = quic_version;
         ServerSession { imp }
     }
 }
@@ -389,11 +443,144 @@ impl ServerQuicExt for ServerSession {}
 ///
 /// Governs version-specific behavior in the TLS layer
 #[non_exhaustive]
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum Version {
     /// Draft versions prior to V1
     V1Draft,
     /// First stable RFC
     V1,
+    /// QUIC version 2, as specified in RFC 9369
+    V2,
+}
+
+impl Version {
+    fn initial_salt(self) -> &'static [u8; 20] {
+        match self {
+            Version::V1Draft => &[
+                // https://datatracker.ietf.org/doc/html/draft-ietf-quic-tls-32#section-5.2
+                0xaf, 0xbf, 0xec, 0x28, 0x99, 0x93, 0xd2, 0x4c, 0x9e, 0x97, 0x86, 0xf1, 0x9c, 0x61,
+                0x11, 0xe0, 0x43, 0x90, 0xa8, 0x99,
+            ],
+            Version::V1 => &[
+                // https://www.rfc-editor.org/rfc/rfc9001.html#name-initial-secrets
+                0x38, 0x76, 0x2c, 0xf7, 0xf5, 0x59, 0x34, 0xb3, 0x4d, 0x17, 0x9a, 0xe6, 0xa4, 0xc8,
+                0x0c, 0xad, 0xcc, 0xbb, 0x7f, 0x0a,
+            ],
+            Version::V2 => &[
+                // https://www.rfc-editor.org/rfc/rfc9369.html#name-initial-salt
+                0x0d, 0xed, 0xe3, 0xde, 0xf7, 0x00, 0xa6, 0xdb, 0x81, 0x93, 0x81, 0xbe, 0x6e, 0x26,
+                0x9d, 0xcb, 0xf9, 0xbd, 0x2e, 0xd9,
+            ],
+        }
+    }
+
+    fn labels(self) -> Labels {
+        match self {
+            Version::V1Draft | Version::V1 => Labels {
+                key: b"quic key",
+                iv: b"quic iv",
+                hp: b"quic hp",
+                ku: b"quic ku",
+            },
+            Version::V2 => Labels {
+                key: b"quicv2 key",
+                iv: b"quicv2 iv",
+                hp: b"quicv2 hp",
+                ku: b"quicv2 ku",
+            },
+        }
+    }
+
+    fn retry_key(self) -> (&'static [u8; 16], &'static [u8; aead::NONCE_LEN]) {
+        match self {
+            Version::V1Draft => (
+                // https://datatracker.ietf.org/doc/html/draft-ietf-quic-tls-32#section-5.8
+                &[
+                    0xcc, 0xce, 0x18, 0x7e, 0xd0, 0x9a, 0x09, 0xd0, 0x57, 0x28, 0x15, 0x5a, 0x6c,
+                    0xb9, 0x6b, 0xe1,
+                ],
+                &[
+                    0xe5, 0x49, 0x30, 0xf9, 0x7f, 0x21, 0x36, 0xf0, 0x53, 0x0a, 0x8c, 0x1c,
+                ],
+            ),
+            Version::V1 => (
+                // https://www.rfc-editor.org/rfc/rfc9001.html#name-retry-packet-integrity
+                &[
+                    0xbe, 0x0c, 0x69, 0x0b, 0x9f, 0x66, 0x57, 0x5a, 0x1d, 0x76, 0x6b, 0x54, 0xe3,
+                    0x68, 0xc8, 0x4e,
+                ],
+                &[
+                    0x46, 0x15, 0x99, 0xd3, 0x5d, 0x63, 0x2b, 0xf2, 0x23, 0x98, 0x25, 0xbb,
+                ],
+            ),
+            Version::V2 => (
+                // https://www.rfc-editor.org/rfc/rfc9369.html#name-retry-integrity-tag
+                &[
+                    0x8f, 0xb4, 0xb0, 0x1b, 0x56, 0xac, 0x48, 0xe2, 0x60, 0xfb, 0xcb, 0xce, 0xad,
+                    0x7c, 0xcc, 0x92,
+                ],
+                &[
+                    0xd8, 0x69, 0x69, 0xbc, 0x2d, 0x7c, 0x6d, 0x99, 0x90, 0xef, 0xb0, 0x4a,
+                ],
+            ),
+        }
+    }
+}
+
+/// HKDF labels used to derive packet protection keys
+struct Labels {
+    key: &'static [u8],
+    iv: &'static [u8],
+    hp: &'static [u8],
+    ku: &'static [u8],
+}
+
+/// Length of the integrity tag at the end of a Retry packet
+pub const RETRY_INTEGRITY_TAG_LEN: usize = 16;
+
+/// Compute the Retry Integrity Tag for a Retry packet
+///
+/// `retry_packet` is the Retry packet without its tag, and `orig_dst_cid` is the destination
+/// connection ID of the client's first Initial packet.  The result is appended to the packet
+/// before sending it.
+pub fn retry_integrity_tag(
+    version: Version,
+    orig_dst_cid: &[u8],
+    retry_packet: &[u8],
+) -> [u8; RETRY_INTEGRITY_TAG_LEN] {
+    let (key, nonce) = version.retry_key();
+    let key = aead::LessSafeKey::new(aead::UnboundKey::new(&aead::AES_128_GCM, key).unwrap());
+
+    // The Retry Pseudo-Packet prefixes the packet with the original destination connection ID.
+    let mut pseudo_packet = Vec::with_capacity(1 + orig_dst_cid.len() + retry_packet.len());
+    pseudo_packet.push(orig_dst_cid.len() as u8);
+    pseudo_packet.extend_from_slice(orig_dst_cid);
+    pseudo_packet.extend_from_slice(retry_packet);
+
+    let tag = key
+        .seal_in_place_separate_tag(
+            aead::Nonce::assume_unique_for_key(*nonce),
+            aead::Aad::from(&pseudo_packet),
+            &mut [],
+        )
+        .unwrap();
+    let mut out = [0; RETRY_INTEGRITY_TAG_LEN];
+    out.copy_from_slice(tag.as_ref());
+    out
+}
+
+/// Check the Retry Integrity Tag on a received Retry packet
+///
+/// `retry_packet` is the whole packet, including its tag, and `orig_dst_cid` is the
+/// destination connection ID the client used in its first Initial packet.
+pub fn is_valid_retry(version: Version, orig_dst_cid: &[u8], retry_packet: &[u8]) -> bool {
+    if retry_packet.len() < RETRY_INTEGRITY_TAG_LEN {
+        return false;
+    }
+
+    let (packet, tag) = retry_packet.split_at(retry_packet.len() - RETRY_INTEGRITY_TAG_LEN);
+    let expected = retry_integrity_tag(version, orig_dst_cid, packet);
+    ring::constant_time::verify_slices_are_equal(&expected, tag).is_ok()
 }
 
 #[cfg(test)]
@@ -403,7 +590,7 @@ mod test {
     #[test]
     fn initial_keys_test_vectors() {
         // Test vectors based on draft 27
-        const INITIAL_SALT: [u8; 20] = [
+        const INITIAL_SALT: &[u8] = &[
             0xc3, 0xee, 0xf7, 0x12, 0xc7, 0x2e, 0xbb, 0x5a, 0x11, 0xa7, 0xd2, 0x43, 0x2b, 0xb4,
             0x63, 0x65, 0xbe, 0xf9, 0xf5, 0x02,
         ];
@@ -411,9 +598,11 @@ mod test {
         const CONNECTION_ID: &[u8] = &[0x83, 0x94, 0xc8, 0xf0, 0x3e, 0x51, 0x57, 0x08];
         const PACKET_NUMBER: u64 = 42;
 
-        let initial_salt = hkdf::Salt::new(hkdf::HKDF_SHA256, &INITIAL_SALT);
-        let server_keys = Keys::initial(&initial_salt, &CONNECTION_ID, false);
-        let client_keys = Keys::initial(&initial_salt, &CONNECTION_ID, true);
+        let initial_salt = hkdf::Salt::new(hkdf::HKDF_SHA256, INITIAL_SALT);
+        let server_keys =
+            Keys::initial_with_salt(&initial_salt, Version::V1Draft, CONNECTION_ID, false);
+        let client_keys =
+            Keys::initial_with_salt(&initial_salt, Version::V1Draft, CONNECTION_ID, true);
 
         // Nonces
         const SERVER_NONCE: [u8; 12] = [
@@ -562,6 +751,122 @@ mod test {
         );
     }
 
+    fn check_initial_keys(
+        version: Version,
+        client_nonce: [u8; 12],
+        client_mask: [u8; 5],
+        server_nonce: [u8; 12],
+        server_mask: [u8; 5],
+    ) {
+        // Packets and samples from the appendices of RFC 9001 and RFC 9369
+        const CONNECTION_ID: &[u8] = &[0x83, 0x94, 0xc8, 0xf0, 0x3e, 0x51, 0x57, 0x08];
+        const CLIENT_PACKET_NUMBER: u64 = 2;
+        const CLIENT_SAMPLE: &[u8] = &[
+            0xd1, 0xb1, 0xc9, 0x8d, 0xd7, 0x68, 0x9f, 0xb8, 0xec, 0x11, 0xd2, 0x42, 0xb1, 0x23,
+            0xdc, 0x9b,
+        ];
+        const SERVER_PACKET_NUMBER: u64 = 1;
+        const SERVER_SAMPLE: &[u8] = &[
+            0x2c, 0xd0, 0x99, 0x1c, 0xd2, 0x5b, 0x0a, 0xac, 0x40, 0x6a, 0x58, 0x16, 0xb6, 0x39,
+            0x41, 0x00,
+        ];
+
+        let client_keys = Keys::initial(version, CONNECTION_ID, true);
+        let server_keys = Keys::initial(version, CONNECTION_ID, false);
+
+        for keys in &[&client_keys.local, &server_keys.remote] {
+            assert_eq!(
+                keys.packet
+                    .iv
+                    .nonce_for(CLIENT_PACKET_NUMBER)
+                    .as_ref(),
+                &client_nonce
+            );
+            assert_eq!(keys.header.new_mask(CLIENT_SAMPLE).unwrap(), client_mask);
+        }
+        for keys in &[&server_keys.local, &client_keys.remote] {
+            assert_eq!(
+                keys.packet
+                    .iv
+                    .nonce_for(SERVER_PACKET_NUMBER)
+                    .as_ref(),
+                &server_nonce
+            );
+            assert_eq!(keys.header.new_mask(SERVER_SAMPLE).unwrap(), server_mask);
+        }
+    }
+
+    #[test]
+    fn initial_keys_v1() {
+        check_initial_keys(
+            Version::V1,
+            [
+                0xfa, 0x04, 0x4b, 0x2f, 0x42, 0xa3, 0xfd, 0x3b, 0x46, 0xfb, 0x25, 0x5e,
+            ],
+            [0x43, 0x7b, 0x9a, 0xec, 0x36],
+            [
+                0x0a, 0xc1, 0x49, 0x3c, 0xa1, 0x90, 0x58, 0x53, 0xb0, 0xbb, 0xa0, 0x3f,
+            ],
+            [0x2e, 0xc0, 0xd8, 0x35, 0x6a],
+        );
+    }
+
+    #[test]
+    fn initial_keys_v2() {
+        check_initial_keys(
+            Version::V2,
+            [
+                0x91, 0xf7, 0x3e, 0x23, 0x51, 0xd8, 0xfa, 0x91, 0x66, 0x0e, 0x90, 0x9d,
+            ],
+            [0x0b, 0xa9, 0x16, 0xbd, 0x32],
+            [
+                0xdd, 0x13, 0xc2, 0x76, 0x49, 0x9c, 0x02, 0x49, 0xd3, 0x31, 0x06, 0x53,
+            ],
+            [0xab, 0xac, 0xcb, 0x74, 0xe4],
+        );
+    }
+
+    #[test]
+    fn retry_integrity_test_vectors() {
+        const ORIG_DST_CID: &[u8] = &[0x83, 0x94, 0xc8, 0xf0, 0x3e, 0x51, 0x57, 0x08];
+        let vectors: &[(Version, &[u8])] = &[
+            (
+                Version::V1Draft,
+                &[
+                    0xff, 0xff, 0x00, 0x00, 0x1d, 0x00, 0x08, 0xf0, 0x67, 0xa5, 0x50, 0x2a, 0x42,
+                    0x62, 0xb5, 0x74, 0x6f, 0x6b, 0x65, 0x6e, 0xd1, 0x69, 0x26, 0xd8, 0x1f, 0x6f,
+                    0x9c, 0xa2, 0x95, 0x3a, 0x8a, 0xa4, 0x57, 0x5e, 0x1e, 0x49,
+                ],
+            ),
+            (
+                Version::V1,
+                &[
+                    0xff, 0x00, 0x00, 0x00, 0x01, 0x00, 0x08, 0xf0, 0x67, 0xa5, 0x50, 0x2a, 0x42,
+                    0x62, 0xb5, 0x74, 0x6f, 0x6b, 0x65, 0x6e, 0x04, 0xa2, 0x65, 0xba, 0x2e, 0xff,
+                    0x4d, 0x82, 0x90, 0x58, 0xfb, 0x3f, 0x0f, 0x24, 0x96, 0xba,
+                ],
+            ),
+            (
+                Version::V2,
+                &[
+                    0xcf, 0x6b, 0x33, 0x43, 0xcf, 0x00, 0x08, 0xf0, 0x67, 0xa5, 0x50, 0x2a, 0x42,
+                    0x62, 0xb5, 0x74, 0x6f, 0x6b, 0x65, 0x6e, 0xc8, 0x64, 0x6c, 0xe8, 0xbf, 0xe3,
+                    0x39, 0x52, 0xd9, 0x55, 0x54, 0x36, 0x65, 0xdc, 0xc7, 0xb6,
+                ],
+            ),
+        ];
+
+        for &(version, packet) in vectors {
+            let (body, tag) = packet.split_at(packet.len() - RETRY_INTEGRITY_TAG_LEN);
+            assert_eq!(&retry_integrity_tag(version, ORIG_DST_CID, body)[..], tag);
+            assert!(is_valid_retry(version, ORIG_DST_CID, packet));
+            assert!(!is_valid_retry(version, &ORIG_DST_CID[1..], packet));
+            assert!(!is_valid_retry(version, ORIG_DST_CID, &packet[1..]));
+        }
+        assert!(!is_valid_retry(Version::V2, ORIG_DST_CID, vectors[1].1));
+        assert!(!is_valid_retry(Version::V1, ORIG_DST_CID, &[0; 4]));
+    }
+
     #[test]
     fn key_update_test_vector() {
         fn equal_prk(x: &hkdf::Prk, y: &hkdf::Prk) -> bool {
@@ -597,7 +902,7 @@ mod test {
                 ],
             ),
         };
-        let updated = next_1rtt_secrets(hkdf::HKDF_SHA256, &initial);
+        let updated = next_1rtt_secrets(hkdf::HKDF_SHA256, Version::V1, &initial);
 
         assert!(equal_prk(
             &updated.client,
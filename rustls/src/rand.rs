@@ -1,28 +1,55 @@
+#[cfg(feature = "tls13")]
 use crate::msgs::codec;
 /// The single place where we generate random material
-/// for our own use.  These functions never fail,
-/// they panic on error.
+/// for our own use.
 use ring::rand::{SecureRandom, SystemRandom};
 
-/// Fill the whole slice with random material.
+/// Supplies the random material rustls generates itself: handshake
+/// randoms, session IDs, ticket nonces and ticket age obfuscation.
+///
+/// The default, `RingRandom`, uses the operating system RNG via *ring*
+/// (on wasm32-unknown-unknown, that is the browser's
+/// `crypto.getRandomValues`).  Implement this trait to draw from
+/// elsewhere, such as a hardware RNG, or a seeded generator in
+/// deterministic tests.  Randomness for ephemeral key exchange is
+/// controlled separately, by `ProvidesKeyExchange`.
+pub trait ProvidesRandom: Send + Sync {
+    /// Fill the whole of `bytes` with random material.
+    fn fill(&self, bytes: &mut [u8]) -> Result<(), GetRandomFailed>;
+}
+
+/// The default `ProvidesRandom` implementation, using *ring*.
+pub struct RingRandom;
+
+impl ProvidesRandom for RingRandom {
+    fn fill(&self, bytes: &mut [u8]) -> Result<(), GetRandomFailed> {
+        SystemRandom::new().fill(bytes).map_err(|_| GetRandomFailed)
+    }
+}
+
+/// Fill the whole slice with random material from the
+/// default source.
 pub fn fill_random(bytes: &mut [u8]) -> Result<(), GetRandomFailed> {
-    SystemRandom::new().fill(bytes).map_err(|_| GetRandomFailed)
+    RingRandom.fill(bytes)
 }
 
 /// Make a Vec<u8> of the given size
 /// containing random material.
-pub fn random_vec(len: usize) -> Result<Vec<u8>, GetRandomFailed> {
+#[cfg(feature = "tls13")]
+pub fn random_vec(rng: &dyn ProvidesRandom, len: usize) -> Result<Vec<u8>, GetRandomFailed> {
     let mut v = vec![0; len];
-    fill_random(&mut v)?;
+    rng.fill(&mut v)?;
     Ok(v)
 }
 
 /// Return a uniformly random u32.
-pub fn random_u32() -> Result<u32, GetRandomFailed> {
+#[cfg(feature = "tls13")]
+pub fn random_u32(rng: &dyn ProvidesRandom) -> Result<u32, GetRandomFailed> {
     let mut buf = [0u8; 4];
-    fill_random(&mut buf)?;
+    rng.fill(&mut buf)?;
     codec::decode_u32(&buf).ok_or(GetRandomFailed)
 }
 
+/// Random material could not be obtained.
 #[derive(Debug)]
 pub struct GetRandomFailed;
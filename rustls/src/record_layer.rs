@@ -1,6 +1,6 @@
 use crate::cipher::{MessageDecrypter, MessageEncrypter};
 use crate::error::TlsError;
-use crate::msgs::message::{BorrowMessage, Message};
+use crate::msgs::message::{BorrowMessage, BorrowMessageMut, Message, MessagePayload};
 
 static SEQ_SOFT_LIMIT: u64 = 0xffff_ffff_ffff_0000u64;
 static SEQ_HARD_LIMIT: u64 = 0xffff_ffff_ffff_fffeu64;
@@ -46,6 +46,32 @@ impl RecordLayer {
         self.decrypt_state == DirectionState::Active
     }
 
+    /// The sequence number of the next record we'll encrypt.
+    #[cfg(feature = "secret_extraction")]
+    pub fn write_seq(&self) -> u64 {
+        self.write_seq
+    }
+
+    /// The sequence number of the next record we'll decrypt.
+    #[cfg(feature = "secret_extraction")]
+    pub fn read_seq(&self) -> u64 {
+        self.read_seq
+    }
+
+    /// Carry on from `seq` as the sequence number of the next record
+    /// we'll encrypt.
+    #[cfg(feature = "session_export")]
+    pub fn set_write_seq(&mut self, seq: u64) {
+        self.write_seq = seq;
+    }
+
+    /// Carry on from `seq` as the sequence number of the next record
+    /// we'll decrypt.
+    #[cfg(feature = "session_export")]
+    pub fn set_read_seq(&mut self, seq: u64) {
+        self.read_seq = seq;
+    }
+
     /// Prepare to use the given `MessageEncrypter` for future message encryption.
     /// It is not used until you call `start_encrypting`.
     pub fn prepare_message_encrypter(&mut self, cipher: Box<dyn MessageEncrypter>) {
@@ -78,6 +104,7 @@ impl RecordLayer {
 
     /// Set and start using the given `MessageEncrypter` for future outgoing
     /// message encryption.
+    #[cfg(feature = "tls13")]
     pub fn set_message_encrypter(&mut self, cipher: Box<dyn MessageEncrypter>) {
         self.prepare_message_encrypter(cipher);
         self.start_encrypting();
@@ -85,6 +112,7 @@ impl RecordLayer {
 
     /// Set and start using the given `MessageDecrypter` for future incoming
     /// message decryption.
+    #[cfg(feature = "tls13")]
     pub fn set_message_decrypter(&mut self, cipher: Box<dyn MessageDecrypter>) {
         self.prepare_message_decrypter(cipher);
         self.start_decrypting();
@@ -114,12 +142,48 @@ impl RecordLayer {
         self.write_seq >= SEQ_HARD_LIMIT
     }
 
+    /// Return true if encrypting `records` more messages would take us
+    /// past the point where we want to close.
+    pub fn wants_close_within(&self, records: usize) -> bool {
+        self.write_seq
+            .saturating_add(records as u64)
+            > SEQ_SOFT_LIMIT
+    }
+
     /// Decrypt a TLS message.
     ///
     /// `encr` is a decoded message allegedly received from the peer.
     /// If it can be decrypted, its decryption is returned.  Otherwise,
     /// an error is returned.
-    pub fn decrypt_incoming(&mut self, encr: Message) -> Result<Message, TlsError> {
+    pub fn decrypt_incoming(&mut self, mut encr: Message) -> Result<Message, TlsError> {
+        let mut payload = encr
+            .take_opaque_payload()
+            .ok_or(TlsError::DecryptError)?
+            .0;
+        let (typ, version, len) = {
+            let plain = self.decrypt_in_place(BorrowMessageMut {
+                typ: encr.typ,
+                version: encr.version,
+                payload: &mut payload,
+            })?;
+            (plain.typ, plain.version, plain.payload.len())
+        };
+
+        // The plaintext is a prefix of the ciphertext.
+        payload.truncate(len);
+        Ok(Message {
+            typ,
+            version,
+            payload: MessagePayload::new_opaque(payload),
+        })
+    }
+
+    /// Like `decrypt_incoming`, but decrypt `encr` where it lies.
+    /// The plaintext borrows from `encr`'s payload.
+    pub fn decrypt_in_place<'a>(
+        &mut self,
+        encr: BorrowMessageMut<'a>,
+    ) -> Result<BorrowMessage<'a>, TlsError> {
         debug_assert!(self.decrypt_state == DirectionState::Active);
         let seq = self.read_seq;
         self.read_seq += 1;
@@ -129,15 +193,32 @@ impl RecordLayer {
 
     /// Encrypt a TLS message.
     ///
-    /// `plain` is a TLS message we'd like to send.  This function
-    /// panics if the requisite keying material hasn't been established yet.
-    pub fn encrypt_outgoing(&mut self, plain: BorrowMessage) -> Message {
+    /// `plain` is a TLS message we'd like to send.  Its encrypted
+    /// record is appended to `out`.  This function panics if the
+    /// requisite keying material hasn't been established yet.
+    pub fn encrypt_outgoing(&mut self, plain: BorrowMessage, out: &mut Vec<u8>) {
+        let start = out.len();
+        out.resize(start + self.encrypted_len(plain.payload.len()), 0);
+        self.encrypt_outgoing_into(plain, &mut out[start..]);
+    }
+
+    /// Like `encrypt_outgoing`, but write the record to the start of
+    /// `out`, returning its length.  `out` must be at least
+    /// `encrypted_len(plain.payload.len())` long.
+    pub fn encrypt_outgoing_into(&mut self, plain: BorrowMessage, out: &mut [u8]) -> usize {
         debug_assert!(self.encrypt_state == DirectionState::Active);
         assert!(!self.encrypt_exhausted());
         let seq = self.write_seq;
         self.write_seq += 1;
         self.message_encrypter
-            .encrypt(plain, seq)
+            .encrypt(plain, seq, out)
             .unwrap()
     }
+
+    /// The length of the record `encrypt_outgoing` makes from `len`
+    /// bytes of payload, header included.
+    pub fn encrypted_len(&self, len: usize) -> usize {
+        self.message_encrypter
+            .encrypted_len(len)
+    }
 }
@@ -1,14 +1,20 @@
 use crate::hash_hs;
 use crate::key;
+#[cfg(feature = "tls12")]
 use crate::kx;
-use crate::msgs::handshake::{ServerExtension, SessionID};
+use crate::msgs::handshake::ServerExtension;
+#[cfg(feature = "tls12")]
+use crate::msgs::handshake::SessionID;
 
+#[cfg(feature = "tls13")]
 use ring::digest;
 use std::mem;
 
 pub struct HandshakeDetails {
     pub transcript: hash_hs::HandshakeHash,
+    #[cfg(feature = "tls13")]
     pub hash_at_server_fin: Option<digest::Digest>,
+    #[cfg(feature = "tls12")]
     pub session_id: SessionID,
     pub extra_exts: Vec<ServerExtension>,
 }
@@ -17,17 +23,21 @@ impl HandshakeDetails {
     pub fn new(extra_exts: Vec<ServerExtension>) -> HandshakeDetails {
         HandshakeDetails {
             transcript: hash_hs::HandshakeHash::new(),
+            #[cfg(feature = "tls13")]
             hash_at_server_fin: None,
+            #[cfg(feature = "tls12")]
             session_id: SessionID::empty(),
             extra_exts,
         }
     }
 }
 
+#[cfg(feature = "tls12")]
 pub struct ServerKXDetails {
     pub kx: kx::KeyExchange,
 }
 
+#[cfg(feature = "tls12")]
 impl ServerKXDetails {
     pub fn new(kx: kx::KeyExchange) -> ServerKXDetails {
         ServerKXDetails { kx }
@@ -36,11 +46,17 @@ impl ServerKXDetails {
 
 pub struct ClientCertDetails {
     pub cert_chain: Vec<key::Certificate>,
+    /// False if the chain was rejected, but the verifier let the
+    /// handshake continue without client authentication.
+    pub verified: bool,
 }
 
 impl ClientCertDetails {
-    pub fn new(chain: Vec<key::Certificate>) -> ClientCertDetails {
-        ClientCertDetails { cert_chain: chain }
+    pub fn new(chain: Vec<key::Certificate>, verified: bool) -> ClientCertDetails {
+        ClientCertDetails {
+            cert_chain: chain,
+            verified,
+        }
     }
 
     pub fn take_chain(&mut self) -> Vec<key::Certificate> {
@@ -1,12 +1,16 @@
 use crate::error::TlsError;
 use crate::key;
+use crate::ocsp;
 use crate::server;
 use crate::server::ClientHello;
+use crate::server_name::DnsName;
 use crate::sign;
+use crate::x509;
 use webpki;
 
 use std::collections;
-use std::sync::{Arc, Mutex};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 /// Something which never stores sessions.
 pub struct NoServerSessionStorage {}
@@ -25,10 +29,12 @@ impl server::StoresServerSessions for NoServerSessionStorage {
 
 /// An implementer of `StoresServerSessions` that stores everything
 /// in memory.  If enforces a limit on the number of stored sessions
-/// to bound memory usage.
+/// to bound memory usage, and optionally a limit on how long each
+/// is kept.
 pub struct ServerSessionMemoryCache {
-    cache: Mutex<collections::HashMap<Vec<u8>, Vec<u8>>>,
+    cache: Mutex<collections::HashMap<Vec<u8>, (Instant, Vec<u8>)>>,
     max_entries: usize,
+    lifetime: Option<Duration>,
 }
 
 impl ServerSessionMemoryCache {
@@ -39,11 +45,33 @@ impl ServerSessionMemoryCache {
         Arc::new(ServerSessionMemoryCache {
             cache: Mutex::new(collections::HashMap::new()),
             max_entries: size,
+            lifetime: None,
         })
     }
 
+    /// Like `new`, but sessions are forgotten once they were stored
+    /// more than `lifetime` ago, so they can't be resumed after that.
+    pub fn with_lifetime(size: usize, lifetime: Duration) -> Arc<ServerSessionMemoryCache> {
+        debug_assert!(size > 0);
+        Arc::new(ServerSessionMemoryCache {
+            cache: Mutex::new(collections::HashMap::new()),
+            max_entries: size,
+            lifetime: Some(lifetime),
+        })
+    }
+
+    fn is_expired(&self, stored: Instant) -> bool {
+        match self.lifetime {
+            Some(lifetime) => stored.elapsed() >= lifetime,
+            None => false,
+        }
+    }
+
     fn limit_size(&self) {
         let mut cache = self.cache.lock().unwrap();
+        if cache.len() > self.max_entries && self.lifetime.is_some() {
+            cache.retain(|_, (stored, _)| !self.is_expired(*stored));
+        }
         while cache.len() > self.max_entries {
             let k = cache.keys().next().unwrap().clone();
             cache.remove(&k);
@@ -56,21 +84,29 @@ impl server::StoresServerSessions for ServerSessionMemoryCache {
         self.cache
             .lock()
             .unwrap()
-            .insert(key, value);
+            .insert(key, (Instant::now(), value));
         self.limit_size();
         true
     }
 
     fn get(&self, key: &[u8]) -> Option<Vec<u8>> {
-        self.cache
-            .lock()
-            .unwrap()
-            .get(key)
-            .cloned()
+        let mut cache = self.cache.lock().unwrap();
+        match cache.get(key) {
+            Some((stored, _)) if self.is_expired(*stored) => {
+                cache.remove(key);
+                None
+            }
+            Some((_, value)) => Some(value.clone()),
+            None => None,
+        }
     }
 
     fn take(&self, key: &[u8]) -> Option<Vec<u8>> {
-        self.cache.lock().unwrap().remove(key)
+        match self.cache.lock().unwrap().remove(key) {
+            Some((stored, _)) if self.is_expired(stored) => None,
+            Some((_, value)) => Some(value),
+            None => None,
+        }
     }
 }
 
@@ -92,6 +128,141 @@ impl server::ProducesTickets for NeverProducesTickets {
     }
 }
 
+/// Something which never produces OCSP responses.
+pub struct NeverProducesOcspResponses {}
+
+impl server::ProducesOcspResponses for NeverProducesOcspResponses {
+    fn get_ocsp_response(&self, _cert_chain: &[key::Certificate]) -> Option<Vec<u8>> {
+        None
+    }
+}
+
+/// An implementer of `ProducesOcspResponses` that remembers the
+/// responses produced by another implementation, and only asks it
+/// again once a response is older than a given age.
+///
+/// The refresh happens during whichever handshake first finds the
+/// response too old, and only one refresh per certificate runs at a
+/// time: other handshakes meanwhile use the previous response.
+///
+/// If the inner implementation fails to produce a new response,
+/// the previous one continues to be used until its nextUpdate time.
+/// After that no response is stapled, even if the `CertifiedKey`
+/// carries one.  Responses for certificates which haven't been asked
+/// about for `max_age` are forgotten.
+pub struct OcspResponseCache {
+    inner: Arc<dyn server::ProducesOcspResponses>,
+    max_age: Duration,
+    cache: Mutex<collections::HashMap<Vec<u8>, CachedOcspResponse>>,
+}
+
+struct CachedOcspResponse {
+    /// When `response` was produced, or None if nothing has been yet.
+    fetched: Option<Instant>,
+    used: Instant,
+    response: Vec<u8>,
+
+    /// The nextUpdate of `response`, in seconds since the epoch.
+    next_update: Option<u64>,
+
+    /// Whether some handshake is asking `inner` for a new response.
+    refreshing: bool,
+}
+
+impl CachedOcspResponse {
+    fn new(now: Instant) -> CachedOcspResponse {
+        CachedOcspResponse {
+            fetched: None,
+            used: now,
+            response: Vec::new(),
+            next_update: None,
+            refreshing: false,
+        }
+    }
+
+    /// The response to staple at `now`, in seconds since the epoch.
+    fn current(&self, now: u64) -> Option<Vec<u8>> {
+        self.fetched?;
+        match self.next_update {
+            Some(next_update) if next_update < now => Some(Vec::new()),
+            _ => Some(self.response.clone()),
+        }
+    }
+}
+
+impl OcspResponseCache {
+    /// Make a new OcspResponseCache.  Responses from `inner` are
+    /// reused for `max_age` before being refreshed.
+    pub fn new(
+        inner: Arc<dyn server::ProducesOcspResponses>,
+        max_age: Duration,
+    ) -> Arc<OcspResponseCache> {
+        Arc::new(OcspResponseCache {
+            inner,
+            max_age,
+            cache: Mutex::new(collections::HashMap::new()),
+        })
+    }
+
+    fn get_ocsp_response_at(
+        &self,
+        cert_chain: &[key::Certificate],
+        unix_time: u64,
+    ) -> Option<Vec<u8>> {
+        let end_entity = cert_chain.first()?;
+
+        {
+            let mut cache = self.cache.lock().unwrap();
+            let now = Instant::now();
+            let max_age = self.max_age;
+            cache.retain(|cert, cached| {
+                *cert == end_entity.0
+                    || cached.refreshing
+                    || now.duration_since(cached.used) < max_age
+            });
+
+            let cached = cache
+                .entry(end_entity.0.clone())
+                .or_insert_with(|| CachedOcspResponse::new(now));
+            cached.used = now;
+            let fresh = match cached.fetched {
+                Some(fetched) => now.duration_since(fetched) < max_age,
+                None => false,
+            };
+            if fresh || cached.refreshing {
+                return cached.current(unix_time);
+            }
+            cached.refreshing = true;
+        }
+
+        // Fetching may be slow, so don't hold the lock meanwhile.
+        let fetched = self.inner.get_ocsp_response(cert_chain);
+
+        let mut cache = self.cache.lock().unwrap();
+        let now = Instant::now();
+        let cached = cache
+            .entry(end_entity.0.clone())
+            .or_insert_with(|| CachedOcspResponse::new(now));
+        cached.refreshing = false;
+        if let Some(response) = fetched {
+            cached.fetched = Some(now);
+            cached.next_update = ocsp::next_update(&response);
+            cached.response = response;
+        }
+        cached.current(unix_time)
+    }
+}
+
+impl server::ProducesOcspResponses for OcspResponseCache {
+    fn get_ocsp_response(&self, cert_chain: &[key::Certificate]) -> Option<Vec<u8>> {
+        let unix_time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .ok()?
+            .as_secs();
+        self.get_ocsp_response_at(cert_chain, unix_time)
+    }
+}
+
 /// Something which never resolves a certificate.
 pub struct FailResolveChain {}
 
@@ -113,10 +284,9 @@ impl AlwaysResolvesChain {
     ) -> Result<AlwaysResolvesChain, TlsError> {
         let key = sign::any_supported_type(priv_key)
             .map_err(|_| TlsError::General("invalid private key".into()))?;
-        Ok(AlwaysResolvesChain(sign::CertifiedKey::new(
-            chain,
-            Arc::new(key),
-        )))
+        let ck = sign::CertifiedKey::new(chain, Arc::new(key));
+        ck.cross_check_end_entity_cert(None)?;
+        Ok(AlwaysResolvesChain(ck))
     }
 
     /// Creates an `AlwaysResolvesChain`, auto-detecting the underlying private
@@ -146,10 +316,78 @@ impl server::ResolvesServerCert for AlwaysResolvesChain {
     }
 }
 
+/// Something which resolves to one cert chain, which can be
+/// replaced while the server is running.
+///
+/// Keep a clone of the `Arc` returned by `new`, and call `reload`
+/// when the certificate is renewed.  Handshakes that have already
+/// resolved a certificate carry on with the old one; later ones
+/// get the new one.  There's no need to rebuild the `ServerConfig`.
+pub struct ReloadableResolvesChain {
+    current: RwLock<sign::CertifiedKey>,
+}
+
+impl ReloadableResolvesChain {
+    /// Creates a `ReloadableResolvesChain`, initially resolving to
+    /// `chain` and `priv_key`.
+    ///
+    /// This fails if the private key type is unsupported, or if the
+    /// certificate chain is syntactically faulty.
+    pub fn new(
+        chain: Vec<key::Certificate>,
+        priv_key: &key::PrivateKey,
+    ) -> Result<Arc<ReloadableResolvesChain>, TlsError> {
+        Ok(Arc::new(ReloadableResolvesChain {
+            current: RwLock::new(Self::certified_key(chain, priv_key)?),
+        }))
+    }
+
+    /// Replace the cert chain and key used for future handshakes.
+    ///
+    /// If this fails (for the same reasons as `new`), the previous
+    /// cert chain and key continue to be used.
+    pub fn reload(
+        &self,
+        chain: Vec<key::Certificate>,
+        priv_key: &key::PrivateKey,
+    ) -> Result<(), TlsError> {
+        let ck = Self::certified_key(chain, priv_key)?;
+        *self.current.write().unwrap() = ck;
+        Ok(())
+    }
+
+    fn certified_key(
+        chain: Vec<key::Certificate>,
+        priv_key: &key::PrivateKey,
+    ) -> Result<sign::CertifiedKey, TlsError> {
+        let key = sign::any_supported_type(priv_key)
+            .map_err(|_| TlsError::General("invalid private key".into()))?;
+        let ck = sign::CertifiedKey::new(chain, Arc::new(key));
+        ck.cross_check_end_entity_cert(None)?;
+        Ok(ck)
+    }
+}
+
+impl server::ResolvesServerCert for ReloadableResolvesChain {
+    fn resolve(&self, _client_hello: ClientHello) -> Option<sign::CertifiedKey> {
+        Some(self.current.read().unwrap().clone())
+    }
+}
+
 /// Something that resolves do different cert chains/keys based
 /// on client-supplied server name (via SNI).
+///
+/// Names are matched in this order:
+///
+/// 1. an exact name added with `add`,
+/// 2. a wildcard name like `*.example.com` added with `add`, which
+///    matches exactly one extra label (`www.example.com`, but not
+///    `example.com` or `a.b.example.com`),
+/// 3. the longest matching suffix added with `add_suffix`.
 pub struct ResolvesServerCertUsingSni {
     by_name: collections::HashMap<String, sign::CertifiedKey>,
+    by_wildcard: collections::HashMap<String, sign::CertifiedKey>,
+    by_suffix: collections::HashMap<String, sign::CertifiedKey>,
 }
 
 impl ResolvesServerCertUsingSni {
@@ -157,28 +395,118 @@ impl ResolvesServerCertUsingSni {
     pub fn new() -> ResolvesServerCertUsingSni {
         ResolvesServerCertUsingSni {
             by_name: collections::HashMap::new(),
+            by_wildcard: collections::HashMap::new(),
+            by_suffix: collections::HashMap::new(),
         }
     }
 
     /// Add a new `sign::CertifiedKey` to be used for the given SNI `name`.
     ///
+    /// `name` may be a wildcard name like `*.example.com`.
+    ///
     /// This function fails if `name` is not a valid DNS name, or if
     /// it's not valid for the supplied certificate, or if the certificate
-    /// chain is syntactically faulty.
+    /// chain is syntactically faulty.  For a wildcard name, the
+    /// certificate must be valid for at least one name it matches.
     pub fn add(&mut self, name: &str, ck: sign::CertifiedKey) -> Result<(), TlsError> {
-        let checked_name = webpki::DNSNameRef::try_from_ascii_str(name)
-            .map_err(|_| TlsError::General("Bad DNS name".into()))?;
+        if let Some(parent) = name.strip_prefix("*.") {
+            let parent = checked_name(parent)?;
+            let lower = parent.as_ref().to_ascii_lowercase();
+            cross_check_some_name(&ck, |san| parent_name(san) == Some(&lower))?;
+            self.by_wildcard
+                .insert(parent.as_ref().to_string(), ck);
+            return Ok(());
+        }
+
+        let checked_name = checked_name(name)?;
+        ck.cross_check_end_entity_cert(Some(&checked_name))?;
+        self.by_name
+            .insert(checked_name.as_ref().to_string(), ck);
+        Ok(())
+    }
 
-        ck.cross_check_end_entity_cert(Some(checked_name))?;
-        self.by_name.insert(name.into(), ck);
+    /// Add a new `sign::CertifiedKey` to be used for `suffix`, and
+    /// any name below it at any depth.  If several suffixes match
+    /// a name, the longest is used.
+    ///
+    /// This function fails if `suffix` is not a valid DNS name, or if
+    /// the certificate is not valid for `suffix` or any name below it,
+    /// or if the certificate chain is syntactically faulty.
+    pub fn add_suffix(&mut self, suffix: &str, ck: sign::CertifiedKey) -> Result<(), TlsError> {
+        let suffix = checked_name(suffix)?;
+        let lower = suffix.as_ref().to_ascii_lowercase();
+        let below = format!(".{}", lower);
+        cross_check_some_name(&ck, |san| {
+            san == lower
+                || san.ends_with(&below)
+                || (san.starts_with("*.") && parent_name(&lower) == Some(&san[2..]))
+        })?;
+        self.by_suffix
+            .insert(suffix.as_ref().to_string(), ck);
         Ok(())
     }
+
+    fn lookup(&self, name: &str) -> Option<&sign::CertifiedKey> {
+        if let Some(ck) = self.by_name.get(name) {
+            return Some(ck);
+        }
+
+        if let Some(ck) = parent_name(name).and_then(|parent| self.by_wildcard.get(parent)) {
+            return Some(ck);
+        }
+
+        // Try `name` and then each parent in turn, so the longest
+        // suffix wins.
+        let mut suffix = Some(name);
+        while let Some(s) = suffix {
+            if let Some(ck) = self.by_suffix.get(s) {
+                return Some(ck);
+            }
+            suffix = s.find('.').map(|dot| &s[dot + 1..]);
+        }
+
+        None
+    }
+}
+
+fn checked_name(name: &str) -> Result<DnsName, TlsError> {
+    webpki::DNSNameRef::try_from_ascii_str(name)
+        .map(|name| DnsName(name.into()))
+        .map_err(|_| TlsError::General("Bad DNS name".into()))
+}
+
+/// `name` without its first label.
+fn parent_name(name: &str) -> Option<&str> {
+    name.find('.')
+        .map(|dot| &name[dot + 1..])
+}
+
+/// Like `CertifiedKey::cross_check_end_entity_cert` with a name, but
+/// for a wildcard or suffix: `ck`'s certificate must have a DNS name
+/// (in lower case) for which `matches` is true.
+fn cross_check_some_name(
+    ck: &sign::CertifiedKey,
+    matches: impl Fn(&str) -> bool,
+) -> Result<(), TlsError> {
+    ck.cross_check_end_entity_cert(None)?;
+
+    let names = match ck.end_entity_cert() {
+        Ok(cert) => x509::dns_name_sans(&cert.0).unwrap_or_default(),
+        Err(()) => Vec::new(),
+    };
+    if names.iter().any(|name| matches(name)) {
+        Ok(())
+    } else {
+        Err(TlsError::General(
+            "The server certificate is not valid for the given name".into(),
+        ))
+    }
 }
 
 impl server::ResolvesServerCert for ResolvesServerCertUsingSni {
     fn resolve(&self, client_hello: ClientHello) -> Option<sign::CertifiedKey> {
         if let Some(name) = client_hello.server_name() {
-            self.by_name.get(name.into()).cloned()
+            self.lookup(name).cloned()
         } else {
             // This kind of resolver requires SNI
             None
@@ -189,9 +517,11 @@ impl server::ResolvesServerCert for ResolvesServerCertUsingSni {
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::server::ProducesOcspResponses;
     use crate::server::ProducesTickets;
     use crate::server::ResolvesServerCert;
     use crate::StoresServerSessions;
+    use std::sync::Weak;
 
     #[test]
     fn test_noserversessionstorage_drops_put() {
@@ -267,6 +597,20 @@ mod test {
         assert_eq!(count, 4);
     }
 
+    #[test]
+    fn test_serversessionmemorycache_forgets_expired_sessions() {
+        let c = ServerSessionMemoryCache::with_lifetime(4, Duration::from_secs(0));
+        assert!(c.put(vec![0x01], vec![0x02]));
+        assert_eq!(c.get(&[0x01]), None);
+        assert!(c.put(vec![0x03], vec![0x04]));
+        assert_eq!(c.take(&[0x03]), None);
+
+        let c = ServerSessionMemoryCache::with_lifetime(4, Duration::from_secs(3600));
+        assert!(c.put(vec![0x01], vec![0x02]));
+        assert_eq!(c.get(&[0x01]), Some(vec![0x02]));
+        assert_eq!(c.take(&[0x01]), Some(vec![0x02]));
+    }
+
     #[test]
     fn test_neverproducestickets_does_nothing() {
         let npt = NeverProducesTickets {};
@@ -276,11 +620,166 @@ mod test {
         assert_eq!(None, npt.decrypt(&[]));
     }
 
+    #[test]
+    fn test_neverproducesocspresponses_does_nothing() {
+        let npo = NeverProducesOcspResponses {};
+        assert_eq!(None, npo.get_ocsp_response(&[]));
+        assert_eq!(None, npo.get_ocsp_response(&[key::Certificate(vec![0x01])]));
+    }
+
+    struct CountingOcspResponses {
+        calls: Mutex<u8>,
+        fail: bool,
+    }
+
+    impl ProducesOcspResponses for CountingOcspResponses {
+        fn get_ocsp_response(&self, _cert_chain: &[key::Certificate]) -> Option<Vec<u8>> {
+            let mut calls = self.calls.lock().unwrap();
+            *calls += 1;
+            if self.fail && *calls > 1 {
+                None
+            } else {
+                Some(vec![*calls])
+            }
+        }
+    }
+
+    #[test]
+    fn test_ocspresponsecache_reuses_fresh_responses() {
+        let inner = Arc::new(CountingOcspResponses {
+            calls: Mutex::new(0),
+            fail: false,
+        });
+        let c = OcspResponseCache::new(inner.clone(), Duration::from_secs(3600));
+        let chain = [key::Certificate(vec![0x01])];
+        assert_eq!(c.get_ocsp_response(&chain), Some(vec![1]));
+        assert_eq!(c.get_ocsp_response(&chain), Some(vec![1]));
+        assert_eq!(*inner.calls.lock().unwrap(), 1);
+
+        let other_chain = [key::Certificate(vec![0x02])];
+        assert_eq!(c.get_ocsp_response(&other_chain), Some(vec![2]));
+        assert_eq!(c.get_ocsp_response(&[]), None);
+    }
+
+    #[test]
+    fn test_ocspresponsecache_refreshes_stale_responses() {
+        let inner = Arc::new(CountingOcspResponses {
+            calls: Mutex::new(0),
+            fail: false,
+        });
+        let c = OcspResponseCache::new(inner.clone(), Duration::from_secs(0));
+        let chain = [key::Certificate(vec![0x01])];
+        assert_eq!(c.get_ocsp_response(&chain), Some(vec![1]));
+        assert_eq!(c.get_ocsp_response(&chain), Some(vec![2]));
+    }
+
+    #[test]
+    fn test_ocspresponsecache_keeps_response_on_failure() {
+        let inner = Arc::new(CountingOcspResponses {
+            calls: Mutex::new(0),
+            fail: true,
+        });
+        let c = OcspResponseCache::new(inner.clone(), Duration::from_secs(0));
+        let chain = [key::Certificate(vec![0x01])];
+        assert_eq!(c.get_ocsp_response(&chain), Some(vec![1]));
+        assert_eq!(c.get_ocsp_response(&chain), Some(vec![1]));
+        assert_eq!(*inner.calls.lock().unwrap(), 2);
+    }
+
+    #[test]
+    fn test_ocspresponsecache_forgets_unused_certificates() {
+        let inner = Arc::new(CountingOcspResponses {
+            calls: Mutex::new(0),
+            fail: false,
+        });
+        let c = OcspResponseCache::new(inner, Duration::from_secs(0));
+        assert_eq!(
+            c.get_ocsp_response(&[key::Certificate(vec![0x01])]),
+            Some(vec![1])
+        );
+        assert_eq!(
+            c.get_ocsp_response(&[key::Certificate(vec![0x02])]),
+            Some(vec![2])
+        );
+        assert_eq!(c.cache.lock().unwrap().len(), 1);
+    }
+
+    struct OnceOcspResponses(Mutex<Option<Vec<u8>>>);
+
+    impl ProducesOcspResponses for OnceOcspResponses {
+        fn get_ocsp_response(&self, _cert_chain: &[key::Certificate]) -> Option<Vec<u8>> {
+            self.0.lock().unwrap().take()
+        }
+    }
+
+    #[test]
+    fn test_ocspresponsecache_drops_expired_responses() {
+        let response = include_bytes!("../testdata/ocsp-good.der").to_vec();
+        let next_update = ocsp::next_update(&response).unwrap();
+        let inner = Arc::new(OnceOcspResponses(Mutex::new(Some(response.clone()))));
+        let c = OcspResponseCache::new(inner, Duration::from_secs(0));
+        let chain = [key::Certificate(vec![0x01])];
+        assert_eq!(
+            c.get_ocsp_response_at(&chain, next_update - 1),
+            Some(response)
+        );
+        assert_eq!(
+            c.get_ocsp_response_at(&chain, next_update + 1),
+            Some(vec![])
+        );
+    }
+
+    struct ReentrantOcspResponses {
+        cache: Mutex<Weak<OcspResponseCache>>,
+        seen: Mutex<Vec<Option<Vec<u8>>>>,
+    }
+
+    impl ProducesOcspResponses for ReentrantOcspResponses {
+        fn get_ocsp_response(&self, cert_chain: &[key::Certificate]) -> Option<Vec<u8>> {
+            let cache = self.cache.lock().unwrap().upgrade()?;
+            assert!(cache.cache.try_lock().is_ok());
+
+            // like a handshake happening during the fetch
+            let seen = cache.get_ocsp_response(cert_chain);
+            let mut all_seen = self.seen.lock().unwrap();
+            all_seen.push(seen);
+            Some(vec![all_seen.len() as u8])
+        }
+    }
+
+    #[test]
+    fn test_ocspresponsecache_unlocked_while_fetching() {
+        let inner = Arc::new(ReentrantOcspResponses {
+            cache: Mutex::new(Weak::new()),
+            seen: Mutex::new(Vec::new()),
+        });
+        let c = OcspResponseCache::new(inner.clone(), Duration::from_secs(3600));
+        *inner.cache.lock().unwrap() = Arc::downgrade(&c);
+        assert_eq!(
+            c.get_ocsp_response(&[key::Certificate(vec![0x01])]),
+            Some(vec![1])
+        );
+    }
+
+    #[test]
+    fn test_ocspresponsecache_refreshes_one_at_a_time() {
+        let inner = Arc::new(ReentrantOcspResponses {
+            cache: Mutex::new(Weak::new()),
+            seen: Mutex::new(Vec::new()),
+        });
+        let c = OcspResponseCache::new(inner.clone(), Duration::from_secs(0));
+        *inner.cache.lock().unwrap() = Arc::downgrade(&c);
+        let chain = [key::Certificate(vec![0x01])];
+        assert_eq!(c.get_ocsp_response(&chain), Some(vec![1]));
+        assert_eq!(c.get_ocsp_response(&chain), Some(vec![2]));
+        assert_eq!(*inner.seen.lock().unwrap(), vec![None, Some(vec![1])]);
+    }
+
     #[test]
     fn test_failresolvechain_does_nothing() {
         let frc = FailResolveChain {};
         assert!(
-            frc.resolve(ClientHello::new(None, &[], None))
+            frc.resolve(ClientHello::new(None, &[], None, &[], None, None, &[]))
                 .is_none()
         );
     }
@@ -290,7 +789,7 @@ mod test {
         let rscsni = ResolvesServerCertUsingSni::new();
         assert!(
             rscsni
-                .resolve(ClientHello::new(None, &[], None))
+                .resolve(ClientHello::new(None, &[], None, &[], None, None, &[]))
                 .is_none()
         );
     }
@@ -301,8 +800,93 @@ mod test {
         let name = webpki::DNSNameRef::try_from_ascii_str("hello.com").unwrap();
         assert!(
             rscsni
-                .resolve(ClientHello::new(Some(name), &[], None))
+                .resolve(ClientHello::new(Some(name), &[], None, &[], None, None, &[]))
                 .is_none()
         );
     }
+
+    struct NoSigningKey;
+
+    impl sign::SigningKey for NoSigningKey {
+        fn choose_scheme(
+            &self,
+            _offered: &[crate::SignatureScheme],
+        ) -> Option<Box<dyn sign::Signer>> {
+            None
+        }
+
+        fn algorithm(&self) -> crate::msgs::enums::SignatureAlgorithm {
+            crate::msgs::enums::SignatureAlgorithm::Anonymous
+        }
+    }
+
+    /// A `CertifiedKey` for google.com, told apart from others
+    /// by its (otherwise unused) OCSP response.
+    fn google_key(tag: u8) -> sign::CertifiedKey {
+        let ee = key::Certificate(include_bytes!("../testdata/cert-google.0.der").to_vec());
+        let mut ck = sign::CertifiedKey::new(vec![ee], Arc::new(Box::new(NoSigningKey)));
+        ck.ocsp = Some(vec![tag]);
+        ck
+    }
+
+    fn resolve_tag(rscsni: &ResolvesServerCertUsingSni, name: &str) -> Option<u8> {
+        let name = webpki::DNSNameRef::try_from_ascii_str(name).unwrap();
+        rscsni
+            .resolve(ClientHello::new(Some(name), &[], None, &[], None, None, &[]))
+            .map(|ck| ck.ocsp.unwrap()[0])
+    }
+
+    #[test]
+    fn test_resolvesservercertusingsni_precedence() {
+        let mut rscsni = ResolvesServerCertUsingSni::new();
+        rscsni
+            .add("www.google.com", google_key(1))
+            .unwrap();
+        rscsni
+            .add("*.google.com", google_key(2))
+            .unwrap();
+        rscsni
+            .add_suffix("google.com", google_key(3))
+            .unwrap();
+        rscsni
+            .add_suffix("maps.google.com", google_key(4))
+            .unwrap();
+
+        assert_eq!(resolve_tag(&rscsni, "www.google.com"), Some(1));
+        assert_eq!(resolve_tag(&rscsni, "mail.google.com"), Some(2));
+        assert_eq!(resolve_tag(&rscsni, "maps.google.com"), Some(2));
+        assert_eq!(resolve_tag(&rscsni, "google.com"), Some(3));
+        assert_eq!(resolve_tag(&rscsni, "a.b.google.com"), Some(3));
+        assert_eq!(resolve_tag(&rscsni, "a.maps.google.com"), Some(4));
+        assert_eq!(resolve_tag(&rscsni, "notgoogle.com"), None);
+        assert_eq!(resolve_tag(&rscsni, "com"), None);
+    }
+
+    #[test]
+    fn test_resolvesservercertusingsni_rejects_bad_names() {
+        let mut rscsni = ResolvesServerCertUsingSni::new();
+        assert!(rscsni.add("*.*.google.com", google_key(1)).is_err());
+        assert!(rscsni.add("www.*.com", google_key(1)).is_err());
+        assert!(rscsni.add("*", google_key(1)).is_err());
+        assert!(rscsni.add_suffix("*.google.com", google_key(1)).is_err());
+        assert!(rscsni.add("youtube.com", google_key(1)).is_ok());
+        assert!(rscsni.add("www.example.com", google_key(1)).is_err());
+    }
+
+    #[test]
+    fn test_resolvesservercertusingsni_checks_wildcards_and_suffixes() {
+        let mut rscsni = ResolvesServerCertUsingSni::new();
+        // the certificate has *.google.com, android.clients.google.com
+        // and developer.android.google.cn, among others.
+        assert!(rscsni.add("*.youtube.com", google_key(1)).is_ok());
+        assert!(rscsni.add("*.clients.google.com", google_key(1)).is_ok());
+        assert!(rscsni.add("*.example.com", google_key(1)).is_err());
+        assert!(rscsni.add("*.maps.google.com", google_key(1)).is_err());
+
+        assert!(rscsni.add_suffix("youtube.com", google_key(1)).is_ok());
+        assert!(rscsni.add_suffix("maps.google.com", google_key(1)).is_ok());
+        assert!(rscsni.add_suffix("google.cn", google_key(1)).is_ok());
+        assert!(rscsni.add_suffix("example.com", google_key(1)).is_err());
+        assert!(rscsni.add_suffix("a.maps.google.com", google_key(1)).is_err());
+    }
 }
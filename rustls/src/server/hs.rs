@@ -1,35 +1,61 @@
-use crate::error::TlsError;
+use crate::error::{CertificateError, PeerMisbehaved, TlsError};
+use crate::key;
+#[cfg(feature = "tls12")]
 use crate::kx;
 #[cfg(feature = "logging")]
 use crate::log::{debug, trace};
+use crate::msgs::base::Payload;
 use crate::msgs::codec::Codec;
-use crate::msgs::enums::{AlertDescription, ExtensionType};
-use crate::msgs::enums::{CipherSuite, Compression, ECPointFormat};
-use crate::msgs::enums::{ClientCertificateType, SignatureScheme};
+use crate::msgs::enums::{AlertDescription, CipherSuite, ExtensionType};
+use crate::msgs::enums::{Compression, SignatureScheme};
 use crate::msgs::enums::{ContentType, HandshakeType, ProtocolVersion};
-use crate::msgs::handshake::CertificateRequestPayload;
-use crate::msgs::handshake::CertificateStatus;
-use crate::msgs::handshake::ClientExtension;
-use crate::msgs::handshake::{ClientHelloPayload, ServerExtension, SessionID};
+use crate::msgs::handshake::{ClientHelloPayload, ServerExtension};
+use crate::msgs::handshake::UnknownExtension;
 use crate::msgs::handshake::{ConvertProtocolNameList, ConvertServerNameList};
-use crate::msgs::handshake::{DigitallySignedStruct, ServerECDHParams};
-use crate::msgs::handshake::{ECDHEServerKeyExchange, ServerKeyExchangePayload};
-use crate::msgs::handshake::{ECPointFormatList, SupportedPointFormats};
-use crate::msgs::handshake::{HandshakeMessagePayload, Random, ServerHelloPayload};
 use crate::msgs::handshake::{HandshakePayload, SupportedSignatureSchemes};
+#[cfg(feature = "quic")]
+use crate::msgs::handshake::UseSRTPData;
 use crate::msgs::message::{Message, MessagePayload};
 use crate::msgs::persist;
-use crate::rand;
-use crate::server::{ClientHello, ServerConfig, ServerSessionImpl};
+#[cfg(feature = "quic")]
+use crate::quic;
+#[cfg(feature = "tls12")]
+use crate::msgs::enums::{ClientCertificateType, ECPointFormat};
+#[cfg(feature = "tls12")]
+use crate::msgs::handshake::{CertificateRequestPayload, CertificateStatus, ClientExtension};
+#[cfg(feature = "tls12")]
+use crate::msgs::handshake::{DigitallySignedStruct, ServerECDHParams, SessionID};
+#[cfg(feature = "tls12")]
+use crate::msgs::handshake::{ECDHEServerKeyExchange, ServerKeyExchangePayload};
+#[cfg(feature = "tls12")]
+use crate::msgs::handshake::{ECPointFormatList, SupportedPointFormats};
+#[cfg(feature = "tls12")]
+use crate::msgs::handshake::{HandshakeMessagePayload, Random, ServerHelloPayload};
+use crate::server::{CertResolution, ClientHello, ServerConfig, ServerSessionImpl};
+use crate::server::ACME_TLS_ALPN_PROTOCOL;
+#[cfg(feature = "tls12")]
+use crate::server::SessionLookup;
+use crate::server_name::DnsName;
+#[cfg(feature = "tls12")]
+use crate::session::HandshakeKind;
 #[cfg(feature = "quic")]
 use crate::session::Protocol;
-use crate::session::{SessionRandoms, SessionSecrets};
+use crate::session::SessionRandoms;
+#[cfg(feature = "tls12")]
+use crate::session::SessionSecrets;
 use crate::sign;
 use crate::suites;
-use webpki;
-
-use crate::server::common::{HandshakeDetails, ServerKXDetails};
-use crate::server::{tls12, tls13};
+use crate::time;
+#[cfg(feature = "secret_extraction")]
+use crate::suites::ConnectionTrafficSecrets;
+
+use crate::server::common::HandshakeDetails;
+#[cfg(feature = "tls12")]
+use crate::server::common::ServerKXDetails;
+#[cfg(feature = "tls13")]
+use crate::server::tls13;
+#[cfg(feature = "tls12")]
+use crate::server::tls12;
 
 pub type NextState = Box<dyn State + Send + Sync>;
 pub type NextStateOrError = Result<NextState, TlsError>;
@@ -47,6 +73,72 @@ pub trait State {
     }
 
     fn perhaps_write_key_update(&mut self, _sess: &mut ServerSessionImpl) {}
+
+    /// Returns our traffic secrets for the (tx, rx) directions.
+    #[cfg(feature = "secret_extraction")]
+    fn extract_secrets(
+        &self,
+        _sess: &ServerSessionImpl,
+    ) -> Result<(ConnectionTrafficSecrets, ConnectionTrafficSecrets), TlsError> {
+        Err(TlsError::HandshakeNotComplete)
+    }
+
+    /// Returns the secrets from which `ServerSession::dangerous_import`
+    /// can rebuild this state.
+    #[cfg(feature = "session_export")]
+    fn export_keys(&self) -> Result<persist::ExportedKeys, TlsError> {
+        Err(TlsError::HandshakeNotComplete)
+    }
+
+    /// Whether the handshake is paused waiting for the application
+    /// to supply a certificate.
+    fn wants_certified_key(&self) -> bool {
+        false
+    }
+
+    /// Continue the handshake with a certificate supplied by the
+    /// application.  Only called if `wants_certified_key` is true.
+    fn supply_certified_key(
+        self: Box<Self>,
+        _sess: &mut ServerSessionImpl,
+        _key: Option<sign::CertifiedKey>,
+    ) -> NextStateOrError {
+        Err(TlsError::General(
+            "no certificate resolution is pending".to_string(),
+        ))
+    }
+
+    /// The scheme and message the application should sign, if the
+    /// handshake is paused waiting for a deferred signature.
+    fn signature_request(&self) -> Option<(SignatureScheme, &[u8])> {
+        None
+    }
+
+    /// Continue the handshake with a signature made by the
+    /// application.  Only called if `signature_request` is `Some`.
+    fn supply_signature(
+        self: Box<Self>,
+        _sess: &mut ServerSessionImpl,
+        _sig: Option<Vec<u8>>,
+    ) -> NextStateOrError {
+        Err(TlsError::General("no signature is pending".to_string()))
+    }
+
+    /// The key of the session the application should look up, if
+    /// the handshake is paused waiting for a deferred lookup.
+    fn session_lookup_request(&self) -> Option<&[u8]> {
+        None
+    }
+
+    /// Continue the handshake with a session value found by the
+    /// application.  Only called if `session_lookup_request` is `Some`.
+    fn supply_session_value(
+        self: Box<Self>,
+        _sess: &mut ServerSessionImpl,
+        _value: Option<Vec<u8>>,
+    ) -> NextStateOrError {
+        Err(TlsError::General("no session lookup is pending".to_string()))
+    }
 }
 
 pub fn incompatible(sess: &mut ServerSessionImpl, why: &str) -> TlsError {
@@ -55,22 +147,85 @@ pub fn incompatible(sess: &mut ServerSessionImpl, why: &str) -> TlsError {
     TlsError::PeerIncompatibleError(why.to_string())
 }
 
+pub fn send_revocation_alert(sess: &mut ServerSessionImpl, err: TlsError) -> TlsError {
+    let desc = match err {
+        TlsError::InvalidCertificate(CertificateError::Revoked) => AlertDescription::CertificateRevoked,
+        _ => AlertDescription::CertificateUnknown,
+    };
+    sess.common.send_fatal_alert(desc);
+    err
+}
+
+/// Check the client's certificate chain with the verifier and the
+/// revocation checker.  Returns whether the chain is good; if it isn't,
+/// but the verifier says that isn't fatal, the result is `Ok(false)`.
+pub fn check_client_cert(
+    sess: &mut ServerSessionImpl,
+    end_entity: &key::Certificate,
+    intermediates: &[key::Certificate],
+) -> Result<bool, TlsError> {
+    let config = sess.config.clone();
+    let verifier = config.get_verifier_for_sni(sess.get_sni());
+    let now = time::now(config.time_provider.as_ref())?;
+
+    let result = verifier.verify_client_cert(end_entity, intermediates, sess.get_sni(), now);
+    let verified = match result {
+        Ok(verified) => verified,
+        Err(err) => {
+            if verifier.client_cert_failure_is_fatal(&err, sess.get_sni()) {
+                incompatible(sess, "certificate invalid");
+                return Err(err);
+            }
+            debug!("continuing without client auth: {:?}", err);
+            return Ok(false);
+        }
+    };
+
+    let verified_intermediates = verified
+        .intermediates()
+        .unwrap_or(intermediates);
+    if let Err(err) = config
+        .revocation_checker
+        .check_revocation(end_entity, verified_intermediates, now)
+    {
+        if verifier.client_cert_failure_is_fatal(&err, sess.get_sni()) {
+            return Err(send_revocation_alert(sess, err));
+        }
+        debug!("continuing without client auth: {:?}", err);
+        return Ok(false);
+    }
+
+    Ok(true)
+}
+
 fn bad_version(sess: &mut ServerSessionImpl, why: &str) -> TlsError {
     sess.common
         .send_fatal_alert(AlertDescription::ProtocolVersion);
     TlsError::PeerIncompatibleError(why.to_string())
 }
 
-pub fn illegal_param(sess: &mut ServerSessionImpl, why: &str) -> TlsError {
+pub fn illegal_param(sess: &mut ServerSessionImpl, why: PeerMisbehaved) -> TlsError {
     sess.common
         .send_fatal_alert(AlertDescription::IllegalParameter);
-    TlsError::PeerMisbehavedError(why.to_string())
+    TlsError::PeerMisbehavedError(why)
+}
+
+pub fn no_signature(sess: &mut ServerSessionImpl) -> TlsError {
+    sess.common
+        .send_fatal_alert(AlertDescription::InternalError);
+    TlsError::General("no signature supplied".to_string())
+}
+
+fn no_cert_resolved(sess: &mut ServerSessionImpl) -> TlsError {
+    sess.common
+        .send_fatal_alert(AlertDescription::AccessDenied);
+    TlsError::General("no server certificate chain resolved".to_string())
 }
 
-pub fn decode_error(sess: &mut ServerSessionImpl, why: &str) -> TlsError {
+pub fn decode_error(sess: &mut ServerSessionImpl, why: PeerMisbehaved) -> TlsError {
     sess.common
         .send_fatal_alert(AlertDescription::DecodeError);
-    TlsError::PeerMisbehavedError(why.to_string())
+    TlsError::PeerMisbehavedError(why)
 }
 
 pub fn can_resume(
@@ -86,7 +241,8 @@ pub fn can_resume(
     // a different name. Instead, it proceeds with a full handshake to
     // establish a new session."
 
-    if resumedata.cipher_suite == sess.common.get_suite_assert().suite
+    if !sess.acme_challenge
+        && resumedata.cipher_suite == sess.common.get_suite_assert().suite
         && (resumedata.extended_ms == using_ems
             || (resumedata.extended_ms && !using_ems))
         && same_dns_name_or_both_none(resumedata.sni.as_ref(), sess.sni.as_ref())
@@ -99,11 +255,11 @@ pub fn can_resume(
 
 // Require an exact match for the purpose of comparing SNI DNS Names from two
 // client hellos, even though a case-insensitive comparison might also be OK.
-fn same_dns_name_or_both_none(a: Option<&webpki::DNSName>, b: Option<&webpki::DNSName>) -> bool {
+fn same_dns_name_or_both_none(a: Option<&DnsName>, b: Option<&DnsName>) -> bool {
     match (a, b) {
         (Some(a), Some(b)) => {
-            let a: &str = a.as_ref().into();
-            let b: &str = b.as_ref().into();
+            let a: &str = a.as_ref();
+            let b: &str = b.as_ref();
             a == b
         }
         (None, None) => true,
@@ -119,15 +275,13 @@ pub fn check_aligned_handshake(sess: &mut ServerSessionImpl) -> Result<(), TlsEr
     if !sess.common.handshake_joiner.is_empty() {
         sess.common
             .send_fatal_alert(AlertDescription::UnexpectedMessage);
-        Err(TlsError::PeerMisbehavedError(
-            "key epoch or handshake flight with pending fragment".to_string(),
-        ))
+        Err(TlsError::PeerMisbehavedError(PeerMisbehaved::KeyEpochWithPendingFragment))
     } else {
         Ok(())
     }
 }
 
-pub fn save_sni(sess: &mut ServerSessionImpl, sni: Option<webpki::DNSName>) {
+pub fn save_sni(sess: &mut ServerSessionImpl, sni: Option<DnsName>) {
     if let Some(sni) = sni {
         // Save the SNI into the session.
         sess.set_sni(sni);
@@ -139,6 +293,7 @@ pub struct ExtensionProcessing {
     // extensions to reply with
     pub exts: Vec<ServerExtension>,
 
+    #[cfg(feature = "tls12")]
     pub send_ticket: bool,
 }
 
@@ -155,9 +310,26 @@ impl ExtensionProcessing {
         resumedata: Option<&persist::ServerSessionValue>,
         handshake: &HandshakeDetails,
     ) -> Result<(), TlsError> {
-        // ALPN
-        let our_protocols = &sess.config.alpn_protocols;
-        let maybe_their_protocols = hello.get_alpn_extension();
+        // ALPN.  An ACME challenge always uses acme-tls/1, which
+        // the client must have offered.
+        if sess.acme_challenge {
+            sess.alpn_protocol = Some(ACME_TLS_ALPN_PROTOCOL.to_vec());
+            self.exts
+                .push(ServerExtension::make_alpn(&[ACME_TLS_ALPN_PROTOCOL]));
+        }
+
+        let vhost_protocols = sess
+            .config
+            .get_virtual_host(sess.get_sni())
+            .and_then(|vhost| vhost.alpn_protocols.as_ref());
+        let alpn_selector = match vhost_protocols {
+            Some(_) => None,
+            None => sess.config.alpn_selector.as_ref(),
+        };
+        let our_protocols = vhost_protocols.unwrap_or(&sess.config.alpn_protocols);
+        let maybe_their_protocols = hello
+            .get_alpn_extension()
+            .filter(|_| !sess.acme_challenge);
         if let Some(their_protocols) = maybe_their_protocols {
             let their_protocols = their_protocols.to_slices();
 
@@ -166,14 +338,19 @@ impl ExtensionProcessing {
                 .any(|protocol| protocol.is_empty())
             {
                 return Err(TlsError::PeerMisbehavedError(
-                    "client offered empty ALPN protocol".to_string(),
+                    PeerMisbehaved::OfferedEmptyApplicationProtocol,
                 ));
             }
 
-            sess.alpn_protocol = our_protocols
-                .iter()
-                .find(|protocol| their_protocols.contains(&protocol.as_slice()))
-                .cloned();
+            sess.alpn_protocol = match alpn_selector {
+                Some(selector) => selector
+                    .select_protocol(sess.get_sni(), &their_protocols)
+                    .filter(|protocol| their_protocols.contains(&protocol.as_slice())),
+                None => our_protocols
+                    .iter()
+                    .find(|protocol| their_protocols.contains(&protocol.as_slice()))
+                    .cloned(),
+            };
             if let Some(ref selected_protocol) = sess.alpn_protocol {
                 debug!("Chosen ALPN protocol {:?}", selected_protocol);
                 self.exts
@@ -182,7 +359,9 @@ impl ExtensionProcessing {
                 // For compatibility, strict ALPN validation is not employed unless targeting QUIC
                 #[cfg(feature = "quic")]
                 {
-                    if sess.common.protocol == Protocol::Quic && !our_protocols.is_empty() {
+                    let alpn_configured =
+                        !our_protocols.is_empty() || alpn_selector.is_some();
+                    if sess.common.protocol == Protocol::Quic && alpn_configured {
                         sess.common
                             .send_fatal_alert(AlertDescription::NoApplicationProtocol);
                         return Err(TlsError::NoApplicationProtocol);
@@ -213,6 +392,59 @@ impl ExtensionProcessing {
                         sess.common.quic.early_secret = None;
                     }
                 }
+
+                if hello.early_data_extension_offered() {
+                    sess.common.quic.zero_rtt = if sess.common.quic.early_secret.is_some() {
+                        quic::ZeroRttStatus::Accepted
+                    } else {
+                        quic::ZeroRttStatus::Rejected
+                    };
+                }
+            }
+
+            if sess.common.is_dtls() {
+                let we_use_cid = handshake
+                    .extra_exts
+                    .iter()
+                    .any(|ext| ext.get_type() == ExtensionType::ConnectionId);
+                if let Some(cid) = hello.get_connection_id_extension() {
+                    if we_use_cid {
+                        sess.common.quic.connection_id = Some(cid.to_vec());
+                    }
+                }
+
+                // DTLS-SRTP: choose our most preferred profile the client offered.
+                if let Some(srtp) = hello.get_srtp_extension() {
+                    let profile = sess
+                        .config
+                        .srtp_profiles
+                        .iter()
+                        .find(|profile| srtp.profiles.contains(profile))
+                        .cloned();
+                    if let Some(profile) = profile {
+                        self.exts
+                            .push(ServerExtension::UseSRTP(UseSRTPData {
+                                profiles: vec![profile],
+                                mki: srtp.mki.clone(),
+                            }));
+                        sess.common.quic.srtp_profile = Some(profile);
+                    }
+                }
+            }
+        }
+
+        #[cfg(feature = "metrics")]
+        {
+            if hello.early_data_extension_offered() {
+                if self
+                    .exts
+                    .iter()
+                    .any(|ext| ext.get_type() == ExtensionType::EarlyData)
+                {
+                    sess.config.metrics.early_data_accepted.incr();
+                } else {
+                    sess.config.metrics.early_data_rejected.incr();
+                }
             }
         }
 
@@ -232,14 +464,23 @@ impl ExtensionProcessing {
                     .find_extension(ExtensionType::StatusRequest)
                     .is_some()
             {
+                if let Some(ocsp) = sess
+                    .config
+                    .ocsp_responses
+                    .get_ocsp_response(&server_key.cert)
+                {
+                    server_key.ocsp = Some(ocsp).filter(|ocsp| !ocsp.is_empty());
+                }
+
                 if server_key.has_ocsp() && !sess.common.is_tls13() {
                     // Only TLS1.2 sends confirmation in ServerHello
                     self.exts
                         .push(ServerExtension::CertificateStatusAck);
                 }
             } else {
-                // Throw away any OCSP response so we don't try to send it later.
+                // Throw away any OCSP responses so we don't try to send them later.
                 drop(server_key.take_ocsp());
+                drop(server_key.take_intermediate_ocsp());
             }
 
             if !for_resume
@@ -263,12 +504,52 @@ impl ExtensionProcessing {
             }
         }
 
-        self.exts
-            .extend(handshake.extra_exts.iter().cloned());
+        // Extensions we don't handle ourselves go to the application.
+        if let Some(handler) = &sess.config.custom_extension_handler {
+            let offered = hello
+                .get_unknown_extensions()
+                .collect::<Vec<_>>();
+
+            if !offered.is_empty() {
+                for (typ, payload) in handler.handle_extensions(sess.get_sni(), &offered) {
+                    let typ = ExtensionType::read_bytes(&typ.to_be_bytes()).unwrap();
+                    if !offered
+                        .iter()
+                        .any(|(t, _)| *t == typ.get_u16())
+                        || self
+                            .exts
+                            .iter()
+                            .any(|ext| ext.get_type() == typ)
+                    {
+                        debug!("Dropping custom extension reply {:?}", typ);
+                        continue;
+                    }
+
+                    self.exts
+                        .push(ServerExtension::Unknown(UnknownExtension {
+                            typ,
+                            payload: Payload::new(payload),
+                        }));
+                }
+            }
+        }
+
+        // Only confirm a DTLS connection ID if the client asked for one.
+        let offered_cid = hello
+            .get_connection_id_extension()
+            .is_some();
+        self.exts.extend(
+            handshake
+                .extra_exts
+                .iter()
+                .filter(|ext| offered_cid || ext.get_type() != ExtensionType::ConnectionId)
+                .cloned(),
+        );
 
         Ok(())
     }
 
+    #[cfg(feature = "tls12")]
     fn process_tls12(
         &mut self,
         sess: &ServerSessionImpl,
@@ -295,7 +576,12 @@ impl ExtensionProcessing {
         if hello
             .find_extension(ExtensionType::SessionTicket)
             .is_some()
-            && sess.config.ticketer.enabled()
+            && sess.config.enable_tickets
+            && !sess.acme_challenge
+            && sess
+                .config
+                .get_ticketer_for_sni(sess.get_sni())
+                .enabled()
         {
             self.send_ticket = true;
             self.exts
@@ -312,9 +598,11 @@ impl ExtensionProcessing {
 
 pub struct ExpectClientHello {
     pub handshake: HandshakeDetails,
+    #[cfg(feature = "tls12")]
     pub using_ems: bool,
     pub done_retry: bool,
     pub send_ticket: bool,
+    pub supplied_key: Option<sign::CertifiedKey>,
 }
 
 impl ExpectClientHello {
@@ -324,15 +612,14 @@ impl ExpectClientHello {
     ) -> ExpectClientHello {
         let mut ech = ExpectClientHello {
             handshake: HandshakeDetails::new(extra_exts),
+            #[cfg(feature = "tls12")]
             using_ems: false,
             done_retry: false,
             send_ticket: false,
+            supplied_key: None,
         };
 
-        if server_config
-            .verifier
-            .offer_client_auth()
-        {
+        if server_config.may_offer_client_auth() {
             ech.handshake
                 .transcript
                 .set_client_auth_enabled();
@@ -341,6 +628,7 @@ impl ExpectClientHello {
         ech
     }
 
+    #[cfg(feature = "tls12")]
     fn into_expect_tls12_ccs(self, secrets: SessionSecrets) -> NextState {
         Box::new(tls12::ExpectCCS {
             secrets,
@@ -351,6 +639,7 @@ impl ExpectClientHello {
         })
     }
 
+    #[cfg(feature = "tls13")]
     fn into_complete_tls13_client_hello_handling(
         self,
         randoms: SessionRandoms,
@@ -363,6 +652,7 @@ impl ExpectClientHello {
         }
     }
 
+    #[cfg(feature = "tls12")]
     fn into_expect_tls12_certificate(
         self,
         randoms: SessionRandoms,
@@ -377,6 +667,7 @@ impl ExpectClientHello {
         })
     }
 
+    #[cfg(feature = "tls12")]
     fn into_expect_tls12_client_kx(
         self,
         randoms: SessionRandoms,
@@ -392,6 +683,7 @@ impl ExpectClientHello {
         })
     }
 
+    #[cfg(feature = "tls12")]
     fn emit_server_hello(
         &mut self,
         sess: &mut ServerSessionImpl,
@@ -430,6 +722,7 @@ impl ExpectClientHello {
         Ok(())
     }
 
+    #[cfg(feature = "tls12")]
     fn emit_certificate(
         &mut self,
         sess: &mut ServerSessionImpl,
@@ -452,6 +745,7 @@ impl ExpectClientHello {
         sess.common.send_msg(c, false);
     }
 
+    #[cfg(feature = "tls12")]
     fn emit_cert_status(
         &mut self,
         sess: &mut ServerSessionImpl,
@@ -479,34 +773,14 @@ impl ExpectClientHello {
         sess.common.send_msg(c, false);
     }
 
+    #[cfg(feature = "tls12")]
     fn emit_server_kx(
         &mut self,
         sess: &mut ServerSessionImpl,
-        sigschemes: Vec<SignatureScheme>,
-        skxg: &'static kx::SupportedKxGroup,
-        server_certkey: &mut sign::CertifiedKey,
-        randoms: &SessionRandoms,
-    ) -> Result<kx::KeyExchange, TlsError> {
-        let kx = kx::KeyExchange::start(skxg)
-            .ok_or_else(|| TlsError::PeerMisbehavedError("key exchange failed".to_string()))?;
-        let secdh = ServerECDHParams::new(skxg.name, kx.pubkey.as_ref());
-
-        let mut msg = Vec::new();
-        msg.extend(&randoms.client);
-        msg.extend(&randoms.server);
-        secdh.encode(&mut msg);
-
-        let signing_key = &server_certkey.key;
-        let signer = signing_key
-            .choose_scheme(&sigschemes)
-            .ok_or_else(|| TlsError::General("incompatible signing key".to_string()))?;
-        let sigscheme = signer.get_scheme();
-        let sig = signer.sign(&msg)?;
-
-        let skx = ServerKeyExchangePayload::ECDHE(ECDHEServerKeyExchange {
-            params: secdh,
-            dss: DigitallySignedStruct::new(sigscheme, sig),
-        });
+        params: ServerECDHParams,
+        dss: DigitallySignedStruct,
+    ) {
+        let skx = ServerKeyExchangePayload::ECDHE(ECDHEServerKeyExchange { params, dss });
 
         let m = Message {
             typ: ContentType::Handshake,
@@ -521,17 +795,45 @@ impl ExpectClientHello {
             .transcript
             .add_message(&m);
         sess.common.send_msg(m, false);
-        Ok(kx)
     }
 
+    /// Send the rest of our TLS1.2 flight, once the ServerKeyExchange
+    /// is signed.
+    #[cfg(feature = "tls12")]
+    fn complete_tls12_server_flight(
+        mut self: Box<Self>,
+        sess: &mut ServerSessionImpl,
+        randoms: SessionRandoms,
+        kx: kx::KeyExchange,
+        params: ServerECDHParams,
+        dss: DigitallySignedStruct,
+    ) -> NextStateOrError {
+        self.emit_server_kx(sess, params, dss);
+        let doing_client_auth = self.emit_certificate_req(sess)?;
+        self.emit_server_hello_done(sess);
+
+        if doing_client_auth {
+            Ok(self.into_expect_tls12_certificate(randoms, kx))
+        } else {
+            Ok(self.into_expect_tls12_client_kx(randoms, kx))
+        }
+    }
+
+    #[cfg(feature = "tls12")]
     fn emit_certificate_req(&mut self, sess: &mut ServerSessionImpl) -> Result<bool, TlsError> {
-        let client_auth = sess.config.get_verifier();
+        let client_auth = sess.config.get_verifier_for_sni(sess.get_sni());
 
-        if !client_auth.offer_client_auth() {
+        if !client_auth.offer_client_auth()
+            || !client_auth.offer_client_auth_for_sni(sess.get_sni())
+        {
+            self.handshake
+                .transcript
+                .abandon_client_auth();
             return Ok(false);
         }
 
-        let verify_schemes = client_auth.supported_verify_schemes();
+        let mut verify_schemes = client_auth.supported_verify_schemes();
+        verify_schemes.retain(|scheme| sess.config.permits_signature_scheme(*scheme));
 
         let names = client_auth
             .client_auth_root_subjects(sess.get_sni())
@@ -541,6 +843,7 @@ impl ExpectClientHello {
                     .send_fatal_alert(AlertDescription::AccessDenied);
                 TlsError::General("client rejected by client_auth_root_subjects".into())
             })?;
+        let names = sess.config.limit_ca_names(names);
 
         let cr = CertificateRequestPayload {
             certtypes: vec![
@@ -568,6 +871,7 @@ impl ExpectClientHello {
         Ok(true)
     }
 
+    #[cfg(feature = "tls12")]
     fn emit_server_hello_done(&mut self, sess: &mut ServerSessionImpl) {
         let m = Message {
             typ: ContentType::Handshake,
@@ -584,19 +888,21 @@ impl ExpectClientHello {
         sess.common.send_msg(m, false);
     }
 
+    #[cfg(feature = "tls12")]
     fn start_resumption(
         mut self,
         sess: &mut ServerSessionImpl,
         client_hello: &ClientHelloPayload,
-        sni: Option<&webpki::DNSName>,
+        sni: Option<&DnsName>,
         id: &SessionID,
         resumedata: persist::ServerSessionValue,
         randoms: &SessionRandoms,
     ) -> NextStateOrError {
         debug!("Resuming session");
+        sess.common.handshake_kind = Some(HandshakeKind::Resumed);
 
         if resumedata.extended_ms && !self.using_ems {
-            return Err(illegal_param(sess, "refusing to resume without ems"));
+            return Err(illegal_param(sess, PeerMisbehaved::ResumptionAttemptedWithVariedEms));
         }
 
         self.handshake.session_id = *id;
@@ -604,7 +910,7 @@ impl ExpectClientHello {
 
         let suite = sess.common.get_suite_assert();
         let secrets = SessionSecrets::new_resume(&randoms, suite, &resumedata.master_secret.0);
-        sess.config.key_log.log(
+        sess.key_log.log(
             "CLIENT_RANDOM",
             &secrets.randoms.client,
             &secrets.master_secret,
@@ -626,6 +932,234 @@ impl ExpectClientHello {
 
         Ok(self.into_expect_tls12_ccs(secrets))
     }
+
+    #[cfg(feature = "tls12")]
+    fn handle_tls12_client_hello(
+        mut self: Box<Self>,
+        sess: &mut ServerSessionImpl,
+        m: Message,
+        sni: Option<DnsName>,
+        sigschemes_ext: SupportedSignatureSchemes,
+        certkey: sign::CertifiedKey,
+        mut randoms: SessionRandoms,
+    ) -> NextStateOrError {
+        let client_hello =
+            require_handshake_msg!(m, HandshakeType::ClientHello, HandshakePayload::ClientHello)?;
+        if client_hello.ems_support_offered() {
+            self.using_ems = true;
+        }
+
+        // The groups are only needed for a full handshake, but the
+        // client must describe them either way.
+        client_hello
+            .get_namedgroups_extension()
+            .ok_or_else(|| incompatible(sess, "client didn't describe groups"))?;
+        let ecpoints_ext = client_hello
+            .get_ecpoints_extension()
+            .ok_or_else(|| incompatible(sess, "client didn't describe ec points"))?;
+
+        trace!("ecpoints {:?}", ecpoints_ext);
+
+        if !ecpoints_ext.contains(&ECPointFormat::Uncompressed) {
+            sess.common
+                .send_fatal_alert(AlertDescription::IllegalParameter);
+            return Err(TlsError::PeerIncompatibleError(
+                "client didn't support uncompressed ec points".to_string(),
+            ));
+        }
+
+        // -- If TLS1.3 is enabled, signal the downgrade in the server random
+        if sess
+            .config
+            .supports_version(ProtocolVersion::TLSv1_3)
+        {
+            randoms.set_tls12_downgrade_marker();
+        }
+
+        // -- Check for resumption --
+        // We can do this either by (in order of preference):
+        // 1. receiving a ticket that decrypts
+        // 2. receiving a sessionid that is in our cache
+        //
+        // If we receive a ticket, the sessionid won't be in our
+        // cache, so don't check.
+        //
+        // If either works, we end up with a ServerSessionValue
+        // which is passed to start_resumption and concludes
+        // our handling of the ClientHello.
+        //
+        let mut ticket_received = false;
+
+        if let Some(ticket_ext) = client_hello
+            .get_ticket_extension()
+            .filter(|_| sess.config.enable_tickets)
+        {
+            if let ClientExtension::SessionTicketOffer(ref ticket) = *ticket_ext {
+                ticket_received = true;
+                sess.common.resumption_offered |= !ticket.0.is_empty();
+                debug!("Ticket received");
+
+                if let Some(resume) = sess
+                    .config
+                    .get_ticketer_for_sni(sess.get_sni())
+                    .decrypt(&ticket.0)
+                    .and_then(|plain| persist::ServerSessionValue::read_bytes(&plain))
+                    .and_then(|resumedata| can_resume(sess, self.using_ems, resumedata))
+                {
+                    return self.start_resumption(
+                        sess,
+                        client_hello,
+                        sni.as_ref(),
+                        &client_hello.session_id,
+                        resume,
+                        &randoms,
+                    );
+                } else {
+                    debug!("Ticket didn't decrypt");
+                }
+            }
+        }
+
+        // If we're not offered a ticket or a potential session ID,
+        // allocate a session ID.
+        if self.handshake.session_id.is_empty()
+            && !ticket_received
+            && sess.config.enable_session_id_resumption
+        {
+            let mut bytes = [0u8; 32];
+            sess.config.random_provider.fill(&mut bytes)?;
+            self.handshake.session_id = SessionID::new(&bytes);
+        }
+
+        // Perhaps resume?  If we received a ticket, the sessionid
+        // does not correspond to a real session.
+        if !client_hello.session_id.is_empty()
+            && !ticket_received
+            && sess.config.enable_session_id_resumption
+        {
+            let key = client_hello.session_id.get_encoding();
+            let value = match sess
+                .config
+                .session_storage
+                .get_or_defer(&key)
+            {
+                SessionLookup::Found(value) => Some(value),
+                SessionLookup::NotFound => None,
+                SessionLookup::Pending => {
+                    debug!("session lookup deferred");
+                    return Ok(Box::new(ExpectSessionValue {
+                        next: self,
+                        client_hello: m,
+                        key,
+                        sni,
+                        sigschemes_ext,
+                        certkey,
+                        randoms,
+                    }));
+                }
+            };
+
+            if let Some(resume) = value
+                .and_then(|x| persist::ServerSessionValue::read_bytes(&x))
+                .and_then(|resumedata| can_resume(sess, self.using_ems, resumedata))
+            {
+                return self.start_resumption(
+                    sess,
+                    client_hello,
+                    sni.as_ref(),
+                    &client_hello.session_id,
+                    resume,
+                    &randoms,
+                );
+            }
+        }
+
+        self.complete_tls12_client_hello(sess, client_hello, sigschemes_ext, certkey, randoms)
+    }
+
+    /// Continue with a full TLS1.2 handshake, having decided
+    /// not to resume.
+    #[cfg(feature = "tls12")]
+    fn complete_tls12_client_hello(
+        mut self: Box<Self>,
+        sess: &mut ServerSessionImpl,
+        client_hello: &ClientHelloPayload,
+        sigschemes_ext: SupportedSignatureSchemes,
+        mut certkey: sign::CertifiedKey,
+        randoms: SessionRandoms,
+    ) -> NextStateOrError {
+        let groups_ext = client_hello
+            .get_namedgroups_extension()
+            .ok_or_else(|| incompatible(sess, "client didn't describe groups"))?;
+        let ecpoints_ext = client_hello
+            .get_ecpoints_extension()
+            .ok_or_else(|| incompatible(sess, "client didn't describe ec points"))?;
+
+        trace!("namedgroups {:?}", groups_ext);
+
+        // Now we have chosen a ciphersuite, we can make kx decisions.
+        let sigschemes = sess
+            .common
+            .get_suite_assert()
+            .resolve_sig_schemes(&sigschemes_ext);
+
+        if sigschemes.is_empty() {
+            return Err(incompatible(sess, "no supported sig scheme"));
+        }
+
+        let group = sess
+            .config
+            .kx_groups
+            .iter()
+            .find(|skxg| groups_ext.contains(&skxg.name))
+            .cloned()
+            .ok_or_else(|| incompatible(sess, "no supported group"))?;
+
+        let ecpoint = ECPointFormatList::supported()
+            .iter()
+            .find(|format| ecpoints_ext.contains(format))
+            .cloned()
+            .ok_or_else(|| incompatible(sess, "no supported point format"))?;
+
+        debug_assert_eq!(ecpoint, ECPointFormat::Uncompressed);
+
+        self.emit_server_hello(sess, Some(&mut certkey), client_hello, None, &randoms)?;
+        self.emit_certificate(sess, &mut certkey);
+        self.emit_cert_status(sess, &mut certkey);
+
+        let kx = kx::KeyExchange::start(group, sess.config.kx_provider.as_ref())
+            .ok_or_else(|| TlsError::General("key exchange failed".to_string()))?;
+        let params = ServerECDHParams::new(group.name, kx.pubkey.as_ref());
+
+        let mut message = Vec::new();
+        message.extend(&randoms.client);
+        message.extend(&randoms.server);
+        params.encode(&mut message);
+
+        let signer = certkey
+            .key
+            .choose_scheme(&sigschemes)
+            .ok_or_else(|| TlsError::General("incompatible signing key".to_string()))?;
+        let scheme = signer.get_scheme();
+
+        match signer.sign_or_defer(&message)? {
+            Some(sig) => {
+                let dss = DigitallySignedStruct::new(scheme, sig);
+                self.complete_tls12_server_flight(sess, randoms, kx, params, dss)
+            }
+            None => {
+                debug!("ServerKeyExchange signature deferred");
+                Ok(Box::new(ExpectServerKxSignature {
+                    next: self,
+                    randoms,
+                    kx,
+                    params,
+                    scheme,
+                    message,
+                }))
+            }
+        }
+    }
 }
 
 impl State for ExpectClientHello {
@@ -640,6 +1174,17 @@ impl State for ExpectClientHello {
             .supports_version(ProtocolVersion::TLSv1_2);
         trace!("we got a clienthello {:?}", client_hello);
 
+        // As with the SNI, keep what the first ClientHello offered.
+        if !self.done_retry && self.supplied_key.is_none() {
+            sess.client_cipher_suites = client_hello.cipher_suites.clone();
+            sess.client_unknown_extensions = client_hello
+                .get_unknown_extensions()
+                .map(|(typ, payload)| (typ, payload.to_vec()))
+                .collect();
+            sess.common
+                .emit(|ev| ev.handshake_started());
+        }
+
         if !client_hello
             .compression_methods
             .contains(&Compression::Null)
@@ -652,14 +1197,21 @@ impl State for ExpectClientHello {
         }
 
         if client_hello.has_duplicate_extension() {
-            return Err(decode_error(sess, "client sent duplicate extensions"));
+            return Err(decode_error(sess, PeerMisbehaved::DuplicateClientHelloExtensions));
         }
 
         // No handshake messages should follow this one in this flight.
         check_aligned_handshake(sess)?;
 
         // Are we doing TLS1.3?
-        let maybe_versions_ext = client_hello.get_versions_extension();
+        let maybe_versions_ext = client_hello
+            .get_versions_extension()
+            .map(|versions| {
+                versions
+                    .iter()
+                    .map(|v| sess.common.version_from_wire(*v))
+                    .collect::<Vec<_>>()
+            });
         let version = if let Some(versions) = maybe_versions_ext {
             if versions.contains(&ProtocolVersion::TLSv1_3) && tls13_enabled {
                 ProtocolVersion::TLSv1_3
@@ -679,6 +1231,23 @@ impl State for ExpectClientHello {
             ProtocolVersion::TLSv1_2
         };
 
+        // A client retrying with a lower version than it first tried
+        // says so, in case an attacker broke the first attempt to
+        // force a downgrade.  We'd have used TLS1.3 if it had offered it.
+        // https://tools.ietf.org/html/rfc7507
+        if version == ProtocolVersion::TLSv1_2
+            && tls13_enabled
+            && client_hello
+                .cipher_suites
+                .contains(&CipherSuite::TLS_FALLBACK_SCSV)
+        {
+            sess.common
+                .send_fatal_alert(AlertDescription::InappropriateFallback);
+            return Err(TlsError::PeerIncompatibleError(
+                "client fell back to TLS1.2 when TLS1.3 is supported".to_string(),
+            ));
+        }
+
         sess.common.negotiated_version = Some(version);
 
         // --- Common to TLS1.2 and TLS1.3: ciphersuite and certificate selection.
@@ -688,28 +1257,27 @@ impl State for ExpectClientHello {
         // send an Illegal Parameter alert instead of the Internal Error alert
         // (or whatever) that we'd send if this were checked later or in a
         // different way.
-        let sni: Option<webpki::DNSName> = match client_hello.get_sni_extension() {
+        let sni: Option<DnsName> = match client_hello.get_sni_extension() {
             Some(sni) => {
                 if sni.has_duplicate_names_for_type() {
-                    return Err(decode_error(
-                        sess,
-                        "ClientHello SNI contains duplicate name types",
-                    ));
+                    return Err(decode_error(sess, PeerMisbehaved::DuplicateServerNameTypes));
                 }
 
                 if let Some(hostname) = sni.get_single_hostname() {
-                    Some(hostname.into())
+                    Some(DnsName(hostname.into()))
                 } else {
                     return Err(illegal_param(
                         sess,
-                        "ClientHello SNI did not contain a hostname",
+                        PeerMisbehaved::ServerNameMustContainOneHostName,
                     ));
                 }
             }
             None => None,
         };
 
-        if !self.done_retry {
+        // If we're continuing after a deferred certificate resolution
+        // the SNI was saved the first time around.
+        if !self.done_retry && self.supplied_key.is_none() {
             // save only the first SNI
             save_sni(sess, sni.clone());
         }
@@ -719,68 +1287,94 @@ impl State for ExpectClientHello {
         // orthogonally to offered ciphersuites (even though, in TLS1.2 it is not).
         // So: reduce the offered sigschemes to those compatible with the
         // intersection of ciphersuites.
-        let mut common_suites = sess.config.ciphersuites.clone();
-        common_suites.retain(|scs| {
-            client_hello
-                .cipher_suites
-                .contains(&scs.suite)
-        });
+        let suite_table = sess.config.suite_table();
+        let common_suites = suite_table
+            .offered(&client_hello.cipher_suites)
+            .collect::<Vec<_>>();
 
         let mut sigschemes_ext = client_hello
             .get_sigalgs_extension()
             .cloned()
             .unwrap_or_else(SupportedSignatureSchemes::default);
-        sigschemes_ext
-            .retain(|scheme| suites::compatible_sigscheme_for_suites(*scheme, &common_suites));
+        sigschemes_ext.retain(|scheme| {
+            suites::compatible_sigscheme_for_suites(*scheme, &common_suites)
+                && sess.config.permits_signature_scheme(*scheme)
+        });
 
-        let alpn_protocols = client_hello
-            .get_alpn_extension()
-            .map(|protos| protos.to_slices());
+        // RFC8737: a client offering acme-tls/1 is validating a
+        // challenge, and gets the challenge certificate.
+        let offers_acme = match client_hello.get_alpn_extension() {
+            Some(protocols) => protocols
+                .to_slices()
+                .contains(&ACME_TLS_ALPN_PROTOCOL),
+            None => false,
+        };
+        let acme_resolver = sess
+            .config
+            .acme_challenge_resolver
+            .as_ref()
+            .filter(|_| offers_acme);
+        sess.acme_challenge = acme_resolver.is_some();
 
         // Choose a certificate.
-        let mut certkey = {
+        let certkey = {
             let sni_ref = sni
                 .as_ref()
-                .map(webpki::DNSName::as_ref);
+                .map(DnsName::as_webpki);
             trace!("sni {:?}", sni_ref);
             trace!("sig schemes {:?}", sigschemes_ext);
-            trace!("alpn protocols {:?}", alpn_protocols);
+            trace!("alpn protocols {:?}", client_hello.get_alpn_extension());
 
-            let alpn_slices = alpn_protocols
-                .as_ref()
-                .map(|vec| vec.as_slice());
-
-            let client_hello = ClientHello::new(sni_ref, &sigschemes_ext, alpn_slices);
-
-            let certkey = sess
+            let vhost_key = sess
                 .config
-                .cert_resolver
-                .resolve(client_hello);
-            certkey.ok_or_else(|| {
-                sess.common
-                    .send_fatal_alert(AlertDescription::AccessDenied);
-                TlsError::General("no server certificate chain resolved".to_string())
-            })?
+                .get_virtual_host(sess.get_sni())
+                .and_then(|vhost| vhost.certified_key.clone())
+                .filter(|_| acme_resolver.is_none());
+            let resolution = match self.supplied_key.take().or(vhost_key) {
+                Some(key) => CertResolution::Resolved(key),
+                None => acme_resolver
+                    .unwrap_or(&sess.config.cert_resolver)
+                    .resolve_or_defer(ClientHello::new(
+                        sni_ref,
+                        &sigschemes_ext,
+                        client_hello.get_alpn_extension(),
+                        &client_hello.cipher_suites,
+                        client_hello
+                            .get_namedgroups_extension()
+                            .map(Vec::as_slice),
+                        client_hello
+                            .get_versions_extension()
+                            .map(Vec::as_slice),
+                        &client_hello.extensions,
+                    )),
+            };
+
+            match resolution {
+                CertResolution::Resolved(key) => key,
+                CertResolution::Pending => {
+                    debug!("certificate resolution deferred");
+                    return Ok(Box::new(ExpectCertifiedKey {
+                        next: self,
+                        client_hello: m,
+                    }));
+                }
+                CertResolution::Failed => {
+                    return Err(no_cert_resolved(sess));
+                }
+            }
         };
 
-        // Reduce our supported ciphersuites by the certificate.
-        // (no-op for TLS1.3)
-        let suitable_suites =
-            suites::reduce_given_sigalg(&sess.config.ciphersuites, certkey.key.algorithm());
-
-        // And version
-        let suitable_suites = suites::reduce_given_version(&suitable_suites, version);
+        // Only suites usable with the certificate (any of them, for
+        // TLS1.3) and the version will do.
+        let sigalg = certkey.key.algorithm();
+        let usable = |scs: &suites::SupportedCipherSuite| {
+            scs.usable_for_sigalg(sigalg) && scs.usable_for_version(version)
+        };
 
         let ciphersuite = if sess.config.ignore_client_order {
-            suites::choose_ciphersuite_preferring_server(
-                &client_hello.cipher_suites,
-                &suitable_suites,
-            )
+            suite_table.choose_preferring_server(&client_hello.cipher_suites, usable)
         } else {
-            suites::choose_ciphersuite_preferring_client(
-                &client_hello.cipher_suites,
-                &suitable_suites,
-            )
+            suite_table.choose_preferring_client(&client_hello.cipher_suites, usable)
         }
         .ok_or_else(|| incompatible(sess, "no ciphersuites in common"))?;
 
@@ -805,11 +1399,12 @@ impl State for ExpectClientHello {
         }
 
         // Save their Random.
-        let mut randoms = SessionRandoms::for_server()?;
+        let mut randoms = SessionRandoms::for_server(sess.config.random_provider.as_ref())?;
         client_hello
             .random
             .write_slice(&mut randoms.client);
 
+        #[cfg(feature = "tls13")]
         if sess.common.is_tls13() {
             return self
                 .into_complete_tls13_client_hello_handling(randoms)
@@ -817,143 +1412,160 @@ impl State for ExpectClientHello {
         }
 
         // -- TLS1.2 only from hereon in --
-        self.handshake
-            .transcript
-            .add_message(&m);
-
-        if client_hello.ems_support_offered() {
-            self.using_ems = true;
+        #[cfg(feature = "tls12")]
+        {
+            self.handshake
+                .transcript
+                .add_message(&m);
+            self.handle_tls12_client_hello(sess, m, sni, sigschemes_ext, certkey, randoms)
         }
 
-        let groups_ext = client_hello
-            .get_namedgroups_extension()
-            .ok_or_else(|| incompatible(sess, "client didn't describe groups"))?;
-        let ecpoints_ext = client_hello
-            .get_ecpoints_extension()
-            .ok_or_else(|| incompatible(sess, "client didn't describe ec points"))?;
-
-        trace!("namedgroups {:?}", groups_ext);
-        trace!("ecpoints {:?}", ecpoints_ext);
-
-        if !ecpoints_ext.contains(&ECPointFormat::Uncompressed) {
-            sess.common
-                .send_fatal_alert(AlertDescription::IllegalParameter);
-            return Err(TlsError::PeerIncompatibleError(
-                "client didn't support uncompressed ec points".to_string(),
-            ));
+        #[cfg(not(feature = "tls12"))]
+        {
+            Err(bad_version(sess, "TLS1.2 not offered/enabled"))
         }
+    }
+}
 
-        // -- If TLS1.3 is enabled, signal the downgrade in the server random
-        if tls13_enabled {
-            randoms.set_tls12_downgrade_marker();
-        }
+/// The handshake is paused after a ClientHello, until the
+/// application supplies the certificate the resolver deferred.
+pub struct ExpectCertifiedKey {
+    next: Box<ExpectClientHello>,
+    client_hello: Message,
+}
 
-        // -- Check for resumption --
-        // We can do this either by (in order of preference):
-        // 1. receiving a ticket that decrypts
-        // 2. receiving a sessionid that is in our cache
-        //
-        // If we receive a ticket, the sessionid won't be in our
-        // cache, so don't check.
-        //
-        // If either works, we end up with a ServerSessionValue
-        // which is passed to start_resumption and concludes
-        // our handling of the ClientHello.
-        //
-        let mut ticket_received = false;
+impl State for ExpectCertifiedKey {
+    fn handle(self: Box<Self>, _sess: &mut ServerSessionImpl, m: Message) -> NextStateOrError {
+        // The client has nothing to send until it gets our reply.
+        Err(TlsError::InappropriateMessage {
+            expect_types: vec![],
+            got_type: m.typ,
+        })
+    }
 
-        if let Some(ticket_ext) = client_hello.get_ticket_extension() {
-            if let ClientExtension::SessionTicketOffer(ref ticket) = *ticket_ext {
-                ticket_received = true;
-                debug!("Ticket received");
+    fn wants_certified_key(&self) -> bool {
+        true
+    }
 
-                if let Some(resume) = sess
-                    .config
-                    .ticketer
-                    .decrypt(&ticket.0)
-                    .and_then(|plain| persist::ServerSessionValue::read_bytes(&plain))
-                    .and_then(|resumedata| can_resume(sess, self.using_ems, resumedata))
-                {
-                    return self.start_resumption(
-                        sess,
-                        client_hello,
-                        sni.as_ref(),
-                        &client_hello.session_id,
-                        resume,
-                        &randoms,
-                    );
-                } else {
-                    debug!("Ticket didn't decrypt");
-                }
+    fn supply_certified_key(
+        self: Box<Self>,
+        sess: &mut ServerSessionImpl,
+        key: Option<sign::CertifiedKey>,
+    ) -> NextStateOrError {
+        let mut next = self.next;
+        match key {
+            Some(key) => {
+                next.supplied_key = Some(key);
+                next.handle(sess, self.client_hello)
             }
+            None => Err(no_cert_resolved(sess)),
         }
+    }
+}
 
-        // If we're not offered a ticket or a potential session ID,
-        // allocate a session ID.
-        if self.handshake.session_id.is_empty() && !ticket_received {
-            let mut bytes = [0u8; 32];
-            rand::fill_random(&mut bytes)?;
-            self.handshake.session_id = SessionID::new(&bytes);
-        }
+/// The handshake is paused after a TLS1.2 ClientHello, until the
+/// application supplies the session the `StoresServerSessions`
+/// deferred looking up.
+#[cfg(feature = "tls12")]
+pub struct ExpectSessionValue {
+    next: Box<ExpectClientHello>,
+    client_hello: Message,
+    key: Vec<u8>,
+    sni: Option<DnsName>,
+    sigschemes_ext: SupportedSignatureSchemes,
+    certkey: sign::CertifiedKey,
+    randoms: SessionRandoms,
+}
 
-        // Perhaps resume?  If we received a ticket, the sessionid
-        // does not correspond to a real session.
-        if !client_hello.session_id.is_empty() && !ticket_received {
-            if let Some(resume) = sess
-                .config
-                .session_storage
-                .get(&client_hello.session_id.get_encoding())
-                .and_then(|x| persist::ServerSessionValue::read_bytes(&x))
-                .and_then(|resumedata| can_resume(sess, self.using_ems, resumedata))
-            {
-                return self.start_resumption(
-                    sess,
-                    client_hello,
-                    sni.as_ref(),
-                    &client_hello.session_id,
-                    resume,
-                    &randoms,
-                );
-            }
-        }
+#[cfg(feature = "tls12")]
+impl State for ExpectSessionValue {
+    fn handle(self: Box<Self>, _sess: &mut ServerSessionImpl, m: Message) -> NextStateOrError {
+        // The client has nothing to send until it gets our reply.
+        Err(TlsError::InappropriateMessage {
+            expect_types: vec![],
+            got_type: m.typ,
+        })
+    }
 
-        // Now we have chosen a ciphersuite, we can make kx decisions.
-        let sigschemes = sess
-            .common
-            .get_suite_assert()
-            .resolve_sig_schemes(&sigschemes_ext);
+    fn session_lookup_request(&self) -> Option<&[u8]> {
+        Some(&self.key)
+    }
 
-        if sigschemes.is_empty() {
-            return Err(incompatible(sess, "no supported sig scheme"));
+    fn supply_session_value(
+        self: Box<Self>,
+        sess: &mut ServerSessionImpl,
+        value: Option<Vec<u8>>,
+    ) -> NextStateOrError {
+        let st = *self;
+        let client_hello = require_handshake_msg!(
+            st.client_hello,
+            HandshakeType::ClientHello,
+            HandshakePayload::ClientHello
+        )?;
+
+        if let Some(resume) = value
+            .and_then(|x| persist::ServerSessionValue::read_bytes(&x))
+            .and_then(|resumedata| can_resume(sess, st.next.using_ems, resumedata))
+        {
+            return st.next.start_resumption(
+                sess,
+                client_hello,
+                st.sni.as_ref(),
+                &client_hello.session_id,
+                resume,
+                &st.randoms,
+            );
         }
 
-        let group = sess
-            .config
-            .kx_groups
-            .iter()
-            .find(|skxg| groups_ext.contains(&skxg.name))
-            .cloned()
-            .ok_or_else(|| incompatible(sess, "no supported group"))?;
+        st.next.complete_tls12_client_hello(
+            sess,
+            client_hello,
+            st.sigschemes_ext,
+            st.certkey,
+            st.randoms,
+        )
+    }
+}
 
-        let ecpoint = ECPointFormatList::supported()
-            .iter()
-            .find(|format| ecpoints_ext.contains(format))
-            .cloned()
-            .ok_or_else(|| incompatible(sess, "no supported point format"))?;
+/// The handshake is paused until the application supplies the
+/// deferred signature for our TLS1.2 ServerKeyExchange.
+#[cfg(feature = "tls12")]
+pub struct ExpectServerKxSignature {
+    next: Box<ExpectClientHello>,
+    randoms: SessionRandoms,
+    kx: kx::KeyExchange,
+    params: ServerECDHParams,
+    scheme: SignatureScheme,
+    message: Vec<u8>,
+}
 
-        debug_assert_eq!(ecpoint, ECPointFormat::Uncompressed);
+#[cfg(feature = "tls12")]
+impl State for ExpectServerKxSignature {
+    fn handle(self: Box<Self>, _sess: &mut ServerSessionImpl, m: Message) -> NextStateOrError {
+        // The client has nothing to send until it gets our reply.
+        Err(TlsError::InappropriateMessage {
+            expect_types: vec![],
+            got_type: m.typ,
+        })
+    }
 
-        self.emit_server_hello(sess, Some(&mut certkey), client_hello, None, &randoms)?;
-        self.emit_certificate(sess, &mut certkey);
-        self.emit_cert_status(sess, &mut certkey);
-        let kx = self.emit_server_kx(sess, sigschemes, group, &mut certkey, &randoms)?;
-        let doing_client_auth = self.emit_certificate_req(sess)?;
-        self.emit_server_hello_done(sess);
+    fn signature_request(&self) -> Option<(SignatureScheme, &[u8])> {
+        Some((self.scheme, &self.message))
+    }
 
-        if doing_client_auth {
-            Ok(self.into_expect_tls12_certificate(randoms, kx))
-        } else {
-            Ok(self.into_expect_tls12_client_kx(randoms, kx))
+    fn supply_signature(
+        self: Box<Self>,
+        sess: &mut ServerSessionImpl,
+        sig: Option<Vec<u8>>,
+    ) -> NextStateOrError {
+        let st = *self;
+        match sig {
+            Some(sig) => {
+                let dss = DigitallySignedStruct::new(st.scheme, sig);
+                st.next
+                    .complete_tls12_server_flight(sess, st.randoms, st.kx, st.params, dss)
+            }
+            None => Err(no_signature(sess)),
         }
     }
 }
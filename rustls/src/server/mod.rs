@@ -1,30 +1,62 @@
+use crate::builder::{ConfigBuilder, WantsCipherSuites};
+use crate::bufpool::BufferPool;
+use crate::events::ConnectionEvents;
+#[cfg(feature = "metrics")]
+use crate::metrics::Metrics;
 use crate::error::TlsError;
 use crate::key;
+use crate::DistinguishedNames;
 use crate::keylog::{KeyLog, NoKeyLog};
 #[cfg(feature = "logging")]
-use crate::log::trace;
-use crate::msgs::enums::ContentType;
-use crate::msgs::enums::SignatureScheme;
+use crate::log::{debug, trace};
+use crate::msgs::enums::{CipherSuite, ContentType, NamedGroup};
+use crate::msgs::enums::{SRTPProtectionProfile, SignatureScheme};
 use crate::msgs::enums::{AlertDescription, HandshakeType, ProtocolVersion};
-use crate::msgs::handshake::ServerExtension;
-use crate::msgs::message::Message;
-use crate::session::{MiddleboxCCS, Session, SessionCommon};
+use crate::msgs::deframer::MessageDeframer;
+use crate::msgs::handshake::{self, ClientExtension, ClientHelloPayload};
+use crate::msgs::handshake::{ConvertServerNameList, ServerExtension};
+use crate::msgs::handshake::{HandshakeMessagePayload, HandshakePayload, ProtocolNameList};
+use crate::msgs::hsjoiner::HandshakeJoiner;
+use crate::msgs::message::{Message, MessagePayload};
+use crate::server_name::DnsName;
+#[cfg(feature = "secret_extraction")]
+use crate::suites::ExtractedSecrets;
+#[cfg(all(feature = "session_export", feature = "tls13"))]
+use crate::key_schedule::TrafficSecrets;
+#[cfg(feature = "session_export")]
+use crate::msgs::base::PayloadU8;
+#[cfg(feature = "session_export")]
+use crate::msgs::codec::Codec;
+#[cfg(feature = "session_export")]
+use crate::msgs::persist;
+#[cfg(feature = "session_export")]
+use crate::session::Protocol;
+use crate::session::{plaintext_written, HandshakeKind, Incoming, MiddleboxCCS};
+use crate::session::{RenegotiationPolicy, Session, SessionCommon};
 use crate::sign;
-use crate::suites::{SupportedCipherSuite, DEFAULT_CIPHERSUITES};
-use crate::kx::{SupportedKxGroup, ALL_KX_GROUPS};
+use crate::suites::{default_ciphersuites, SuiteTable, SupportedCipherSuite};
+use crate::kx::{ProvidesKeyExchange, RingKeyExchange, SupportedKxGroup, ALL_KX_GROUPS};
+use crate::rand::{ProvidesRandom, RingRandom};
+use crate::time::{SystemTimeProvider, TimeProvider};
 use crate::verify;
+use crate::versions::{EnabledVersions, SupportedProtocolVersion, DEFAULT_VERSIONS};
 
 use webpki;
 
+use std::borrow::Cow;
+use std::collections;
 use std::fmt;
 use std::io::{self, IoSlice};
+use std::mem;
 use std::sync::Arc;
 
 #[macro_use]
 mod hs;
 mod common;
 pub mod handy;
+#[cfg(feature = "tls12")]
 mod tls12;
+#[cfg(feature = "tls13")]
 mod tls13;
 
 /// A trait for the ability to store server session data.
@@ -58,6 +90,37 @@ pub trait StoresServerSessions: Send + Sync {
     /// Find a value with the given `key`.  Return it and delete it;
     /// or None if it doesn't exist.
     fn take(&self, key: &[u8]) -> Option<Vec<u8>>;
+
+    /// Find a value with the given `key`, or defer the lookup
+    /// until later.
+    ///
+    /// This is used to resume TLS1.2 sessions by session ID.  Return
+    /// `SessionLookup::Pending` to pause the handshake after the
+    /// ClientHello; for example, while the value is fetched from
+    /// memcached or Redis.  `ServerSession::session_lookup_request`
+    /// then returns `key`, and the handshake continues once the
+    /// application calls `ServerSession::supply_session_value`.
+    ///
+    /// The default calls `get`.
+    fn get_or_defer(&self, key: &[u8]) -> SessionLookup {
+        match self.get(key) {
+            Some(value) => SessionLookup::Found(value),
+            None => SessionLookup::NotFound,
+        }
+    }
+}
+
+/// The result of `StoresServerSessions::get_or_defer`.
+pub enum SessionLookup {
+    /// This is the stored value.
+    Found(Vec<u8>),
+
+    /// Pause the handshake until the application supplies
+    /// the value.
+    Pending,
+
+    /// There is no such value: do a full handshake.
+    NotFound,
 }
 
 /// A trait for the ability to encrypt and decrypt tickets.
@@ -93,6 +156,62 @@ pub trait ProducesTickets: Send + Sync {
     fn decrypt(&self, cipher: &[u8]) -> Option<Vec<u8>>;
 }
 
+/// A trait for the ability to fetch fresh OCSP responses for stapling.
+///
+/// This is called during handshakes where the client asked for
+/// certificate status, and the handshake waits for it.  Wrapping a
+/// slow implementation in an `OcspResponseCache` means only the
+/// handshakes which trigger a refresh wait for the fetch; to avoid
+/// that too, do the fetching in the background.
+pub trait ProducesOcspResponses: Send + Sync {
+    /// Return a DER-encoded OCSP response for the end-entity
+    /// certificate of `cert_chain`.  This replaces any response
+    /// already attached to the resolved `CertifiedKey`.
+    ///
+    /// Return None to keep using whatever response (if any) the
+    /// `CertifiedKey` already carries, or an empty response to
+    /// staple nothing.
+    fn get_ocsp_response(&self, cert_chain: &[key::Certificate]) -> Option<Vec<u8>>;
+}
+
+/// A trait for choosing the ALPN protocol for a connection.
+///
+/// Use this instead of a fixed `ServerConfig::alpn_protocols` list
+/// when the choice depends on the connection, such as only
+/// offering `h2` for some virtual hosts.
+pub trait SelectsAlpnProtocol: Send + Sync {
+    /// Choose one of the `offered` protocols, in the client's order
+    /// of preference.  `sni` is the server name the client asked for,
+    /// if any.
+    ///
+    /// Return `None` to not negotiate a protocol.  Returning a protocol
+    /// the client didn't offer is treated the same as `None`.
+    fn select_protocol(&self, sni: Option<&DnsName>, offered: &[&[u8]]) -> Option<Vec<u8>>;
+}
+
+/// A trait for answering ClientHello extensions that rustls doesn't
+/// implement itself, such as private extensions between endpoints
+/// you control.  `ClientConfig::custom_extensions` sends them.
+pub trait HandlesCustomExtensions: Send + Sync {
+    /// `offered` are the client's extensions that rustls didn't
+    /// handle, as pairs of extension type and payload.  `sni` is the
+    /// server name the client asked for, if any.
+    ///
+    /// Return the extensions to reply with, as pairs of type and
+    /// payload.  They are sent in EncryptedExtensions in TLS1.3, and
+    /// in the ServerHello in TLS1.2.  Replies of a type the client
+    /// didn't offer are dropped.
+    fn handle_extensions(
+        &self,
+        sni: Option<&DnsName>,
+        offered: &[(u16, &[u8])],
+    ) -> Vec<(u16, Vec<u8>)>;
+}
+
+/// The ALPN protocol name ACME servers offer when validating a
+/// TLS-ALPN-01 challenge, as defined in RFC8737.
+pub const ACME_TLS_ALPN_PROTOCOL: &[u8] = b"acme-tls/1";
+
 /// How to choose a certificate chain and signing key for use
 /// in server authentication.
 pub trait ResolvesServerCert: Send + Sync {
@@ -101,13 +220,47 @@ pub trait ResolvesServerCert: Send + Sync {
     ///
     /// Return `None` to abort the handshake.
     fn resolve(&self, client_hello: ClientHello) -> Option<sign::CertifiedKey>;
+
+    /// Choose a certificate chain and matching key, or defer the
+    /// choice until later.
+    ///
+    /// Return `CertResolution::Pending` to pause the handshake after
+    /// the ClientHello; for example, while the certificate is fetched
+    /// from elsewhere.  `ServerSession::wants_certified_key` is then true,
+    /// and the handshake continues once the application calls
+    /// `ServerSession::supply_certified_key`.
+    ///
+    /// The default calls `resolve`.
+    fn resolve_or_defer(&self, client_hello: ClientHello) -> CertResolution {
+        match self.resolve(client_hello) {
+            Some(key) => CertResolution::Resolved(key),
+            None => CertResolution::Failed,
+        }
+    }
+}
+
+/// The result of `ResolvesServerCert::resolve_or_defer`.
+pub enum CertResolution {
+    /// Use this certificate chain and key.
+    Resolved(sign::CertifiedKey),
+
+    /// Pause the handshake until the application supplies
+    /// a certificate chain and key.
+    Pending,
+
+    /// Abort the handshake.
+    Failed,
 }
 
 /// A struct representing the received Client Hello
 pub struct ClientHello<'a> {
     server_name: Option<webpki::DNSNameRef<'a>>,
     sigschemes: &'a [SignatureScheme],
-    alpn: Option<&'a [&'a [u8]]>,
+    alpn: Option<&'a ProtocolNameList>,
+    cipher_suites: &'a [CipherSuite],
+    named_groups: Option<&'a [NamedGroup]>,
+    supported_versions: Option<&'a [ProtocolVersion]>,
+    extensions: &'a [ClientExtension],
 }
 
 impl<'a> ClientHello<'a> {
@@ -115,20 +268,28 @@ impl<'a> ClientHello<'a> {
     fn new(
         server_name: Option<webpki::DNSNameRef<'a>>,
         sigschemes: &'a [SignatureScheme],
-        alpn: Option<&'a [&'a [u8]]>,
+        alpn: Option<&'a ProtocolNameList>,
+        cipher_suites: &'a [CipherSuite],
+        named_groups: Option<&'a [NamedGroup]>,
+        supported_versions: Option<&'a [ProtocolVersion]>,
+        extensions: &'a [ClientExtension],
     ) -> Self {
         ClientHello {
             server_name,
             sigschemes,
             alpn,
+            cipher_suites,
+            named_groups,
+            supported_versions,
+            extensions,
         }
     }
 
     /// Get the server name indicator.
     ///
     /// Returns `None` if the client did not supply a SNI.
-    pub fn server_name(&self) -> Option<webpki::DNSNameRef> {
-        self.server_name
+    pub fn server_name(&self) -> Option<&str> {
+        self.server_name.map(|name| name.into())
     }
 
     /// Get the compatible signature schemes.
@@ -141,11 +302,78 @@ impl<'a> ClientHello<'a> {
     /// Get the alpn.
     ///
     /// Returns `None` if the client did not include an ALPN extension
-    pub fn alpn(&self) -> Option<&'a [&'a [u8]]> {
+    pub fn alpn(&self) -> Option<impl Iterator<Item = &'a [u8]>> {
         self.alpn
+            .map(|protocols| protocols.iter().map(|proto| proto.0.as_slice()))
+    }
+
+    /// Get the cipher suites offered by the client, in its order
+    /// of preference.
+    pub fn cipher_suites(&self) -> &[CipherSuite] {
+        self.cipher_suites
+    }
+
+    /// Get the key exchange groups offered by the client, in its
+    /// order of preference.
+    ///
+    /// Returns `None` if the client did not include a supported_groups
+    /// extension.
+    pub fn named_groups(&self) -> Option<&[NamedGroup]> {
+        self.named_groups
+    }
+
+    /// Get the protocol versions offered by the client in its
+    /// supported_versions extension.
+    ///
+    /// Returns `None` if the client did not include this extension,
+    /// meaning it doesn't support TLS1.3.
+    pub fn supported_versions(&self) -> Option<&[ProtocolVersion]> {
+        self.supported_versions
+    }
+
+    /// Get the extensions rustls doesn't understand, as pairs of
+    /// extension type and payload, in the order the client sent them.
+    pub fn unknown_extensions(&self) -> impl Iterator<Item = (u16, &'a [u8])> {
+        handshake::unknown_extensions(self.extensions)
+    }
+
+    /// Get the names of the CAs the client accepts certificates
+    /// from, if it sent the TLS1.3 certificate_authorities extension.
+    /// Each is a DER-encoded X.501 Name.
+    pub fn certificate_authorities(&self) -> Option<impl Iterator<Item = &'a [u8]>> {
+        self.extensions
+            .iter()
+            .find_map(|ext| match ext {
+                ClientExtension::CertificateAuthorities(names) => Some(names),
+                _ => None,
+            })
+            .map(|names| names.iter().map(|name| &name.0[..]))
     }
 }
 
+/// Settings for connections whose SNI matches a virtual host,
+/// overriding those in the `ServerConfig`.  Fields left as `None`
+/// use the `ServerConfig`'s setting.
+///
+/// Add these with `ServerConfig::add_virtual_host`.
+#[derive(Clone, Default)]
+pub struct VirtualHost {
+    /// The certificate chain and key to use.  If `None`, the
+    /// `ServerConfig`'s `cert_resolver` chooses one.
+    pub certified_key: Option<sign::CertifiedKey>,
+
+    /// Protocol names we support for this host, most preferred first.
+    /// This takes precedence over the `ServerConfig`'s `alpn_protocols`
+    /// and `alpn_selector`.
+    pub alpn_protocols: Option<Vec<Vec<u8>>>,
+
+    /// How to verify client certificates for this host.
+    pub client_cert_verifier: Option<Arc<dyn verify::ClientCertVerifier>>,
+
+    /// How to produce tickets for this host.
+    pub ticketer: Option<Arc<dyn ProducesTickets>>,
+}
+
 /// Common configuration for a set of server sessions.
 ///
 /// Making one of these can be expensive, and should be
@@ -155,44 +383,156 @@ pub struct ServerConfig {
     /// List of ciphersuites, in preference order.
     pub ciphersuites: Vec<&'static SupportedCipherSuite>,
 
+    /// `ciphersuites`, sorted for choosing from.  See `suite_table`.
+    suite_table: SuiteTable,
+
     /// List of supported key exchange groups.
     ///
     /// The first is the highest priority: they will be
     /// offered to the client in this order.
     pub kx_groups: Vec<&'static SupportedKxGroup>,
 
+    /// How ephemeral key exchanges are performed.  The default
+    /// does them in software.
+    pub kx_provider: Arc<dyn ProvidesKeyExchange>,
+
+    /// Where the random material rustls generates itself (handshake
+    /// randoms, session IDs, tickets) comes from.  The default uses
+    /// the operating system RNG.
+    pub random_provider: Arc<dyn ProvidesRandom>,
+
+    /// Where the current time comes from, for certificate
+    /// validation and ticket lifetimes.  The default asks the
+    /// operating system.
+    pub time_provider: Arc<dyn TimeProvider>,
+
     /// Ignore the client's ciphersuite order. Instead,
     /// choose the top ciphersuite in the server list
     /// which is supported by the client.
     pub ignore_client_order: bool,
 
+    /// What to do when a TLS1.2 client tries to renegotiate.
+    ///
+    /// The default is `RenegotiationPolicy::Decline`.
+    pub renegotiation_policy: RenegotiationPolicy,
+
     /// Our MTU.  If None, we don't limit TLS message sizes.
     pub mtu: Option<usize>,
 
+    /// Where sessions get their TLS buffers from.  If None, each
+    /// session allocates its own.
+    pub buffer_pool: Option<Arc<BufferPool>>,
+
+    /// Where sessions report handshakes, alerts and other events.
+    /// If None, they aren't reported.
+    pub connection_events: Option<Arc<dyn ConnectionEvents>>,
+
+    /// What sessions count their handshakes, failures and so on into.
+    #[cfg(feature = "metrics")]
+    pub metrics: Arc<Metrics>,
+
     /// How to store client sessions.
+    ///
+    /// The default is a `ServerSessionMemoryCache` holding up to 256
+    /// sessions.  Use `ServerSessionMemoryCache::with_lifetime` to
+    /// also limit how long they're kept, or your own implementation
+    /// to share sessions between servers.
     pub session_storage: Arc<dyn StoresServerSessions + Send + Sync>,
 
     /// How to produce tickets.
     pub ticketer: Arc<dyn ProducesTickets>,
 
+    /// Whether to resume TLS1.2 sessions by session ID, storing
+    /// them in `session_storage`.
+    ///
+    /// The default is true.
+    pub enable_session_id_resumption: bool,
+
+    /// Whether to issue and accept TLS1.2 tickets made by `ticketer`.
+    ///
+    /// The default is true, but `ticketer` doesn't make tickets
+    /// unless you change it.
+    pub enable_tickets: bool,
+
+    /// Whether to issue and accept TLS1.3 tickets.  These are made
+    /// by `ticketer` if it makes tickets, or else are keys into
+    /// `session_storage`.
+    ///
+    /// The default is true.
+    pub enable_tls13_resumption: bool,
+
     /// How to choose a server cert and key.
     pub cert_resolver: Arc<dyn ResolvesServerCert>,
 
+    /// How to choose the self-signed certificate for an ACME
+    /// TLS-ALPN-01 challenge (RFC8737).  If set, a client offering
+    /// the `ACME_TLS_ALPN_PROTOCOL` gets the certificate this
+    /// chooses instead of one from `cert_resolver` or a
+    /// `VirtualHost`.  The handshake negotiates that protocol, never
+    /// resumes, and the session is closed once it completes.
+    ///
+    /// If None, `acme-tls/1` is treated like any other protocol.
+    pub acme_challenge_resolver: Option<Arc<dyn ResolvesServerCert>>,
+
+    /// How to get fresh OCSP responses for the chosen cert.
+    /// The default does nothing, so only responses attached to
+    /// the chosen `CertifiedKey` are stapled.
+    pub ocsp_responses: Arc<dyn ProducesOcspResponses>,
+
     /// Protocol names we support, most preferred first.
     /// If empty we don't do ALPN at all.
     pub alpn_protocols: Vec<Vec<u8>>,
 
-    /// Supported protocol versions, in no particular order.
-    /// The default is all supported versions.
-    pub versions: Vec<ProtocolVersion>,
+    /// How to choose an ALPN protocol per connection.  If set,
+    /// this is used instead of `alpn_protocols`.
+    pub alpn_selector: Option<Arc<dyn SelectsAlpnProtocol>>,
+
+    /// SRTP protection profiles we support for DTLS-SRTP, most
+    /// preferred first.  If empty we don't negotiate SRTP.
+    ///
+    /// This only affects DTLS connections.
+    pub srtp_profiles: Vec<SRTPProtectionProfile>,
+
+    /// What answers ClientHello extensions rustls doesn't handle.
+    /// If None, they are ignored.
+    pub custom_extension_handler: Option<Arc<dyn HandlesCustomExtensions>>,
+
+    /// Supported protocol versions.  Change this with
+    /// `set_protocol_versions`.
+    pub(crate) versions: EnabledVersions,
+
+    /// Which signature schemes to offer and accept, if not all those
+    /// supported.  This restricts our own signatures, and those we
+    /// accept from the peer.
+    ///
+    /// The default is `None`: no restriction.
+    pub signature_schemes: Option<Vec<SignatureScheme>>,
 
     /// How to verify client certificates.
     verifier: Arc<dyn verify::ClientCertVerifier>,
 
+    /// How to check whether client certificates have been
+    /// revoked, after `verifier` accepts them.  The default does
+    /// nothing.
+    pub revocation_checker: Arc<dyn verify::RevocationChecker>,
+
+    /// The most bytes of CA names to send in a CertificateRequest.
+    /// If those from `ClientCertVerifier::client_auth_root_subjects`
+    /// would take more, none are sent, and the client chooses its
+    /// certificate without them.  This keeps the handshake small
+    /// when clients are accepted from very many CAs.  `Some(0)`
+    /// never sends any.
+    ///
+    /// The default is `None`: no limit.
+    pub client_auth_ca_names_limit: Option<usize>,
+
     /// How to output key material for debugging.  The default
     /// does nothing.
     pub key_log: Arc<dyn KeyLog>,
 
+    /// Per-SNI overrides of the settings above.
+    virtual_hosts: collections::HashMap<DnsName, VirtualHost>,
+
     /// Amount of early data to accept; 0 to disable.
     #[cfg(feature = "quic")] // TLS support unimplemented
     #[doc(hidden)]
@@ -200,6 +540,12 @@ pub struct ServerConfig {
 }
 
 impl ServerConfig {
+    /// Start building a `ServerConfig`.  See the `ConfigBuilder`
+    /// documentation for the steps.
+    pub fn builder() -> ConfigBuilder<ServerConfig, WantsCipherSuites> {
+        ConfigBuilder::new()
+    }
+
     /// Make a `ServerConfig` with a default set of ciphersuites,
     /// no keys/certificates, and no ALPN protocols.  Session resumption
     /// is enabled by storing up to 256 recent sessions in memory. Tickets are
@@ -214,7 +560,7 @@ impl ServerConfig {
     /// default, requiring client authentication, requires additional
     /// configuration that we cannot provide reasonable defaults for.
     pub fn new(client_cert_verifier: Arc<dyn verify::ClientCertVerifier>) -> ServerConfig {
-        ServerConfig::with_ciphersuites(client_cert_verifier, DEFAULT_CIPHERSUITES)
+        ServerConfig::with_ciphersuites(client_cert_verifier, default_ciphersuites())
     }
 
     /// Make a `ServerConfig` with a custom set of ciphersuites,
@@ -236,16 +582,37 @@ impl ServerConfig {
     ) -> ServerConfig {
         ServerConfig {
             ciphersuites: ciphersuites.to_vec(),
+            suite_table: SuiteTable::new(ciphersuites),
             kx_groups: ALL_KX_GROUPS.to_vec(),
+            kx_provider: Arc::new(RingKeyExchange),
+            random_provider: Arc::new(RingRandom),
+            time_provider: Arc::new(SystemTimeProvider),
             ignore_client_order: false,
+            renegotiation_policy: RenegotiationPolicy::Decline,
             mtu: None,
+            buffer_pool: None,
+            connection_events: None,
+            #[cfg(feature = "metrics")]
+            metrics: Metrics::new(),
             session_storage: handy::ServerSessionMemoryCache::new(256),
             ticketer: Arc::new(handy::NeverProducesTickets {}),
+            enable_session_id_resumption: true,
+            enable_tickets: true,
+            enable_tls13_resumption: true,
             alpn_protocols: Vec::new(),
+            srtp_profiles: Vec::new(),
+            alpn_selector: None,
+            custom_extension_handler: None,
             cert_resolver: Arc::new(handy::FailResolveChain {}),
-            versions: vec![ProtocolVersion::TLSv1_3, ProtocolVersion::TLSv1_2],
+            acme_challenge_resolver: None,
+            ocsp_responses: Arc::new(handy::NeverProducesOcspResponses {}),
+            versions: EnabledVersions::new(DEFAULT_VERSIONS),
+            signature_schemes: None,
             verifier: client_cert_verifier,
+            revocation_checker: Arc::new(verify::NoRevocationChecking {}),
+            client_auth_ca_names_limit: None,
             key_log: Arc::new(NoKeyLog {}),
+            virtual_hosts: collections::HashMap::new(),
             #[cfg(feature = "quic")]
             max_early_data_size: 0,
         }
@@ -256,18 +623,96 @@ impl ServerConfig {
     /// versions *and* at least one ciphersuite for this version is
     /// also configured.
     pub fn supports_version(&self, v: ProtocolVersion) -> bool {
-        self.versions.contains(&v)
+        self.versions.contains(v)
             && self
                 .ciphersuites
                 .iter()
                 .any(|cs| cs.usable_for_version(v))
     }
 
+    /// Our ciphersuites, sorted for choosing from.  This is done
+    /// when the config is made; it is only done again here, for each
+    /// handshake, if `ciphersuites` has been changed since.
+    pub(crate) fn suite_table(&self) -> Cow<'_, SuiteTable> {
+        if self
+            .suite_table
+            .is_for(&self.ciphersuites)
+        {
+            Cow::Borrowed(&self.suite_table)
+        } else {
+            Cow::Owned(SuiteTable::new(&self.ciphersuites))
+        }
+    }
+
     #[doc(hidden)]
     pub fn get_verifier(&self) -> &dyn verify::ClientCertVerifier {
         self.verifier.as_ref()
     }
 
+    /// Whether `signature_schemes` allows `scheme`.
+    pub(crate) fn permits_signature_scheme(&self, scheme: SignatureScheme) -> bool {
+        match &self.signature_schemes {
+            Some(schemes) => schemes.contains(&scheme),
+            None => true,
+        }
+    }
+
+    /// The CA names to send in a CertificateRequest, given those
+    /// the verifier offered: `names`, or none if they're over
+    /// `client_auth_ca_names_limit`.
+    pub(crate) fn limit_ca_names(&self, names: DistinguishedNames) -> DistinguishedNames {
+        let limit = match self.client_auth_ca_names_limit {
+            Some(limit) => limit,
+            None => return names,
+        };
+
+        let len: usize = names
+            .iter()
+            .map(|name| 2 + name.0.len())
+            .sum();
+        if len > limit {
+            debug!("Not sending {} bytes of CA names in CertificateRequest", len);
+            Vec::new()
+        } else {
+            names
+        }
+    }
+
+    /// The virtual host configured for `sni`, if any.
+    pub(crate) fn get_virtual_host(&self, sni: Option<&DnsName>) -> Option<&VirtualHost> {
+        sni.and_then(|name| self.virtual_hosts.get(name))
+    }
+
+    /// The `ClientCertVerifier` to use for connections to `sni`.
+    pub(crate) fn get_verifier_for_sni(
+        &self,
+        sni: Option<&DnsName>,
+    ) -> &dyn verify::ClientCertVerifier {
+        self.get_virtual_host(sni)
+            .and_then(|vhost| vhost.client_cert_verifier.as_ref())
+            .unwrap_or(&self.verifier)
+            .as_ref()
+    }
+
+    /// The `ProducesTickets` to use for connections to `sni`.
+    pub(crate) fn get_ticketer_for_sni(&self, sni: Option<&DnsName>) -> &dyn ProducesTickets {
+        self.get_virtual_host(sni)
+            .and_then(|vhost| vhost.ticketer.as_ref())
+            .unwrap_or(&self.ticketer)
+            .as_ref()
+    }
+
+    /// Whether any connection might be asked for a client
+    /// certificate, before we know its SNI.
+    pub(crate) fn may_offer_client_auth(&self) -> bool {
+        self.verifier.offer_client_auth()
+            || self
+                .virtual_hosts
+                .values()
+                .filter_map(|vhost| vhost.client_cert_verifier.as_ref())
+                .any(|verifier| verifier.offer_client_auth())
+    }
+
     /// Sets the session persistence layer to `persist`.
     pub fn set_persistence(&mut self, persist: Arc<dyn StoresServerSessions + Send + Sync>) {
         self.session_storage = persist;
@@ -285,7 +730,9 @@ impl ServerConfig {
     /// `cert_chain` is a vector of DER-encoded certificates.
     /// `key_der` is a DER-encoded RSA, ECDSA, or Ed25519 private key.
     ///
-    /// This function fails if `key_der` is invalid.
+    /// This function fails if `key_der` is invalid, if it doesn't match
+    /// the end-entity certificate, or if `cert_chain` is empty or out
+    /// of order.
     pub fn set_single_cert(
         &mut self,
         cert_chain: Vec<key::Certificate>,
@@ -306,7 +753,9 @@ impl ServerConfig {
     /// `scts` is an `SignedCertificateTimestampList` encoding (see RFC6962)
     /// and is ignored if empty.
     ///
-    /// This function fails if `key_der` is invalid.
+    /// This function fails if `key_der` is invalid, if it doesn't match
+    /// the end-entity certificate, or if `cert_chain` is empty or out
+    /// of order.
     pub fn set_single_cert_with_ocsp_and_sct(
         &mut self,
         cert_chain: Vec<key::Certificate>,
@@ -320,6 +769,27 @@ impl ServerConfig {
         Ok(())
     }
 
+    /// Sets how fresh OCSP responses are obtained.  See
+    /// `ProducesOcspResponses`.
+    pub fn set_ocsp_responses(&mut self, producer: Arc<dyn ProducesOcspResponses>) {
+        self.ocsp_responses = producer;
+    }
+
+    /// Enable only the protocol versions in `versions`.  The default
+    /// is `DEFAULT_VERSIONS`.
+    ///
+    /// A version is only used if at least one of the configured
+    /// cipher suites can be used with it.
+    pub fn set_protocol_versions(&mut self, versions: &[&'static SupportedProtocolVersion]) {
+        self.versions = EnabledVersions::new(versions);
+    }
+
+    /// Sets how client certificates are checked for revocation.
+    /// See `RevocationChecker`.
+    pub fn set_revocation_checker(&mut self, checker: Arc<dyn verify::RevocationChecker>) {
+        self.revocation_checker = checker;
+    }
+
     /// Set the ALPN protocol list to the given protocol names.
     /// Overwrites any existing configured protocols.
     ///
@@ -331,6 +801,13 @@ impl ServerConfig {
             .extend_from_slice(protocols);
     }
 
+    /// Sets a callback to choose the ALPN protocol for each
+    /// connection.  This takes precedence over any protocols set
+    /// with `set_protocols`.
+    pub fn set_alpn_selector(&mut self, selector: Arc<dyn SelectsAlpnProtocol>) {
+        self.alpn_selector = Some(selector);
+    }
+
     /// Overrides the default `ClientCertVerifier` with something else.
     pub fn set_client_certificate_verifier(
         &mut self,
@@ -338,14 +815,37 @@ impl ServerConfig {
     ) {
         self.verifier = verifier;
     }
+
+    /// Adds `vhost` as the settings for connections whose SNI
+    /// is `name`, replacing any earlier virtual host of that name.
+    ///
+    /// This function fails if `name` is not a valid DNS name, or if
+    /// the virtual host has a certificate which isn't valid for `name`.
+    pub fn add_virtual_host(&mut self, name: &str, vhost: VirtualHost) -> Result<(), TlsError> {
+        let name = webpki::DNSNameRef::try_from_ascii_str(name)
+            .map(|name| DnsName(name.into()))
+            .map_err(|_| TlsError::General("Bad DNS name".into()))?;
+
+        if let Some(ref ck) = vhost.certified_key {
+            ck.cross_check_end_entity_cert(Some(&name))?;
+        }
+
+        self.virtual_hosts.insert(name, vhost);
+        Ok(())
+    }
 }
 
 pub struct ServerSessionImpl {
     pub config: Arc<ServerConfig>,
     pub common: SessionCommon,
-    sni: Option<webpki::DNSName>,
+    sni: Option<DnsName>,
+    /// The cipher suites from the client's first ClientHello.
+    pub client_cipher_suites: Vec<CipherSuite>,
+    /// The extensions we didn't understand in the client's first
+    /// ClientHello.  A boxed slice rather than a `Vec`, to keep
+    /// sessions small.
+    pub client_unknown_extensions: Box<[(u16, Vec<u8>)]>,
     pub alpn_protocol: Option<Vec<u8>>,
-    pub quic_params: Option<Vec<u8>>,
     pub received_resumption_data: Option<Vec<u8>>,
     pub resumption_data: Vec<u8>,
     pub error: Option<TlsError>,
@@ -353,6 +853,10 @@ pub struct ServerSessionImpl {
     pub client_cert_chain: Option<Vec<key::Certificate>>,
     /// Whether to reject early data even if it would otherwise be accepted
     pub reject_early_data: bool,
+    /// Whether this handshake answers an ACME TLS-ALPN-01 challenge.
+    pub acme_challenge: bool,
+    /// Where secrets go: `config.key_log` unless overridden.
+    pub key_log: Arc<dyn KeyLog>,
 }
 
 impl fmt::Debug for ServerSessionImpl {
@@ -367,12 +871,25 @@ impl ServerSessionImpl {
         server_config: &Arc<ServerConfig>,
         extra_exts: Vec<ServerExtension>,
     ) -> ServerSessionImpl {
+        #[allow(unused_mut)]
+        let mut common = SessionCommon::new(
+            server_config.mtu,
+            server_config.buffer_pool.as_ref(),
+            server_config.connection_events.clone(),
+            false,
+        );
+        #[cfg(feature = "metrics")]
+        {
+            common.metrics = Some(server_config.metrics.clone());
+        }
+
         ServerSessionImpl {
             config: server_config.clone(),
-            common: SessionCommon::new(server_config.mtu, false),
+            common,
             sni: None,
+            client_cipher_suites: Vec::new(),
+            client_unknown_extensions: Box::default(),
             alpn_protocol: None,
-            quic_params: None,
             received_resumption_data: None,
             resumption_data: Vec::new(),
             error: None,
@@ -382,6 +899,8 @@ impl ServerSessionImpl {
             ))),
             client_cert_chain: None,
             reject_early_data: false,
+            acme_challenge: false,
+            key_log: server_config.key_log.clone(),
         }
     }
 
@@ -407,6 +926,38 @@ impl ServerSessionImpl {
         self.common.set_buffer_limit(len)
     }
 
+    fn process_incoming(&mut self, incoming: Incoming) -> Result<(), TlsError> {
+        match incoming {
+            Incoming::Record(msg) => self.process_msg(msg),
+            Incoming::Decrypted(msg) => self.process_plaintext_msg(msg),
+        }
+    }
+
+    /// Process a received record, or the error from receiving it.
+    /// Errors are remembered, so every later call fails the same way.
+    pub fn process_received(
+        &mut self,
+        incoming: Result<Incoming, TlsError>,
+    ) -> Result<(), TlsError> {
+        if let Some(ref err) = self.error {
+            return Err(err.clone());
+        }
+
+        let rc = incoming.and_then(|incoming| self.process_incoming(incoming));
+        if let Err(err) = rc {
+            #[cfg(feature = "metrics")]
+            {
+                if !self.common.traffic {
+                    self.config.metrics.handshake_failed(&err);
+                }
+            }
+            self.error = Some(err.clone());
+            return Err(err);
+        }
+
+        Ok(())
+    }
+
     pub fn process_msg(&mut self, mut msg: Message) -> Result<(), TlsError> {
         // TLS1.3: drop CCS at any time during handshaking
         if let MiddleboxCCS::Drop = self.common.filter_tls13_ccs(&msg)? {
@@ -420,6 +971,11 @@ impl ServerSessionImpl {
             msg = dm;
         }
 
+        self.process_plaintext_msg(msg)
+    }
+
+    /// Process `msg`, which is already decrypted if need be.
+    fn process_plaintext_msg(&mut self, mut msg: Message) -> Result<(), TlsError> {
         // For handshake messages, we need to join them before parsing
         // and processing.
         if self
@@ -482,16 +1038,24 @@ impl ServerSessionImpl {
             && !self.common.is_tls13()
             && msg.is_handshake_type(HandshakeType::ClientHello)
         {
-            self.common
-                .send_warning_alert(AlertDescription::NoRenegotiation);
-            return Ok(());
+            return self
+                .common
+                .refuse_renegotiation(self.config.renegotiation_policy);
         }
 
+        let was_handshaking = !self.common.traffic;
         let state = self.state.take().unwrap();
         let maybe_next_state = state.handle(self, msg);
         let next_state = self.maybe_send_unexpected_alert(maybe_next_state)?;
         self.state = Some(next_state);
 
+        // RFC8737: the challenge connection is done with once the
+        // handshake completes.
+        if self.acme_challenge && was_handshaking && self.common.traffic {
+            debug!("ACME challenge handshake complete; closing");
+            self.common.send_close_notify();
+        }
+
         Ok(())
     }
 
@@ -504,24 +1068,101 @@ impl ServerSessionImpl {
             return Err(TlsError::CorruptMessage);
         }
 
-        while let Some(msg) = self
-            .common
-            .message_deframer
-            .frames
-            .pop_front()
+        // Leave any further messages queued while the handshake is
+        // paused for the application to supply a certificate, a
+        // signature or a session.
+        while !self.wants_certified_key()
+            && self.signature_request().is_none()
+            && self.session_lookup_request().is_none()
         {
-            match self.process_msg(msg) {
-                Ok(_) => {}
-                Err(err) => {
-                    self.error = Some(err.clone());
-                    return Err(err);
-                }
+            match self.common.next_incoming().transpose() {
+                Some(incoming) => self.process_received(incoming)?,
+                None => break,
             }
         }
 
         Ok(())
     }
 
+    pub fn wants_certified_key(&self) -> bool {
+        matches!(self.state, Some(ref state) if state.wants_certified_key())
+    }
+
+    pub fn supply_certified_key(
+        &mut self,
+        key: Option<sign::CertifiedKey>,
+    ) -> Result<(), TlsError> {
+        if let Some(ref err) = self.error {
+            return Err(err.clone());
+        }
+
+        if !self.wants_certified_key() {
+            return Err(TlsError::General(
+                "no certificate resolution is pending".to_string(),
+            ));
+        }
+
+        let state = self.state.take().unwrap();
+        let maybe_next_state = state.supply_certified_key(self, key);
+        self.resume_handshake(maybe_next_state)
+    }
+
+    pub fn signature_request(&self) -> Option<(SignatureScheme, &[u8])> {
+        self.state
+            .as_ref()
+            .and_then(|state| state.signature_request())
+    }
+
+    pub fn supply_signature(&mut self, sig: Option<Vec<u8>>) -> Result<(), TlsError> {
+        if let Some(ref err) = self.error {
+            return Err(err.clone());
+        }
+
+        if self.signature_request().is_none() {
+            return Err(TlsError::General("no signature is pending".to_string()));
+        }
+
+        let state = self.state.take().unwrap();
+        let maybe_next_state = state.supply_signature(self, sig);
+        self.resume_handshake(maybe_next_state)
+    }
+
+    pub fn session_lookup_request(&self) -> Option<&[u8]> {
+        self.state
+            .as_ref()
+            .and_then(|state| state.session_lookup_request())
+    }
+
+    pub fn supply_session_value(&mut self, value: Option<Vec<u8>>) -> Result<(), TlsError> {
+        if let Some(ref err) = self.error {
+            return Err(err.clone());
+        }
+
+        if self.session_lookup_request().is_none() {
+            return Err(TlsError::General(
+                "no session lookup is pending".to_string(),
+            ));
+        }
+
+        let state = self.state.take().unwrap();
+        let maybe_next_state = state.supply_session_value(self, value);
+        self.resume_handshake(maybe_next_state)
+    }
+
+    /// Continue after the handshake was paused, processing any
+    /// messages received in the meantime.
+    fn resume_handshake(&mut self, maybe_next_state: hs::NextStateOrError) -> Result<(), TlsError> {
+        match self.maybe_send_unexpected_alert(maybe_next_state) {
+            Ok(next_state) => self.state = Some(next_state),
+            Err(err) => {
+                self.error = Some(err.clone());
+                return Err(err);
+            }
+        }
+
+        self.process_new_packets()
+    }
+
     pub fn get_peer_certificates(&self) -> Option<Vec<key::Certificate>> {
         self.client_cert_chain
             .as_ref()
@@ -542,11 +1183,23 @@ impl ServerSessionImpl {
         self.common.get_suite()
     }
 
-    pub fn get_sni(&self) -> Option<&webpki::DNSName> {
+    pub fn get_negotiated_kx_group(&self) -> Option<&'static SupportedKxGroup> {
+        self.common.kx_group
+    }
+
+    pub fn get_peer_signature_scheme(&self) -> Option<SignatureScheme> {
+        self.common.peer_signature_scheme
+    }
+
+    pub fn handshake_kind(&self) -> Option<HandshakeKind> {
+        self.common.handshake_kind()
+    }
+
+    pub fn get_sni(&self) -> Option<&DnsName> {
         self.sni.as_ref()
     }
 
-    pub fn set_sni(&mut self, value: webpki::DNSName) {
+    pub fn set_sni(&mut self, value: DnsName) {
         // The SNI hostname is immutable once set.
         assert!(self.sni.is_none());
         self.sni = Some(value)
@@ -564,12 +1217,120 @@ impl ServerSessionImpl {
             .and_then(|st| st.export_keying_material(output, label, context))
     }
 
+    #[cfg(feature = "secret_extraction")]
+    fn extract_secrets(&self) -> Result<ExtractedSecrets, TlsError> {
+        let secrets = self
+            .state
+            .as_ref()
+            .ok_or(TlsError::HandshakeNotComplete)
+            .and_then(|st| st.extract_secrets(self));
+        self.common.extract_secrets(secrets)
+    }
+
+    #[cfg(feature = "session_export")]
+    fn export(&self) -> Result<Vec<u8>, TlsError> {
+        if self.common.protocol != Protocol::Tls13 {
+            return Err(TlsError::General(
+                "session export is not supported for this protocol".into(),
+            ));
+        }
+        self.common.check_quiescent()?;
+
+        let keys = self
+            .state
+            .as_ref()
+            .ok_or(TlsError::HandshakeNotComplete)
+            .and_then(|st| st.export_keys())?;
+
+        let exported = persist::ExportedServerSession {
+            cipher_suite: self.common.get_suite_assert().suite,
+            write_seq: self.common.record_layer.write_seq(),
+            read_seq: self.common.record_layer.read_seq(),
+            keys,
+            sni: self.sni.clone(),
+            alpn: self
+                .alpn_protocol
+                .clone()
+                .map(PayloadU8::new),
+            client_cert_chain: self.client_cert_chain.clone(),
+        };
+        Ok(exported.get_encoding())
+    }
+
+    #[cfg(feature = "session_export")]
+    fn import(config: &Arc<ServerConfig>, exported: &[u8]) -> Result<ServerSessionImpl, TlsError> {
+        let invalid = || TlsError::General("invalid exported session".into());
+        let exported = persist::ExportedServerSession::read_bytes(exported).ok_or_else(invalid)?;
+
+        let version = exported.keys.version();
+        let suite = config
+            .ciphersuites
+            .iter()
+            .copied()
+            .find(|scs| scs.suite == exported.cipher_suite && scs.usable_for_version(version))
+            .ok_or_else(|| {
+                TlsError::General("exported session's cipher suite is not enabled".into())
+            })?;
+        if !config.supports_version(version) {
+            return Err(TlsError::General(
+                "exported session's protocol version is not enabled".into(),
+            ));
+        }
+
+        let mut imp = ServerSessionImpl::new(config, Vec::new());
+        imp.common.negotiated_version = Some(version);
+        imp.common.set_suite(suite);
+
+        let state = match exported.keys {
+            #[cfg(feature = "tls12")]
+            persist::ExportedKeys::Tls12 {
+                client_random,
+                server_random,
+                master_secret,
+            } => tls12::ExpectTraffic::import(&mut imp, client_random, server_random, &master_secret.0)?,
+            #[cfg(feature = "tls13")]
+            persist::ExportedKeys::Tls13 {
+                master_secret,
+                client_traffic_secret,
+                server_traffic_secret,
+                exporter_secret,
+            } => {
+                let secrets = TrafficSecrets {
+                    master: master_secret.0,
+                    client: client_traffic_secret.0,
+                    server: server_traffic_secret.0,
+                    exporter: exporter_secret.0,
+                };
+                tls13::ExpectTraffic::import(&mut imp, secrets)?
+            }
+            #[allow(unreachable_patterns)]
+            _ => return Err(invalid()),
+        };
+
+        imp.common
+            .record_layer
+            .set_write_seq(exported.write_seq);
+        imp.common
+            .record_layer
+            .set_read_seq(exported.read_seq);
+        imp.common.traffic = true;
+        imp.state = Some(state);
+        imp.sni = exported.sni;
+        imp.alpn_protocol = exported.alpn.map(|alpn| alpn.0);
+        imp.client_cert_chain = exported.client_cert_chain;
+        Ok(imp)
+    }
+
     fn send_some_plaintext(&mut self, buf: &[u8]) -> usize {
+        self.perhaps_write_key_update();
+        self.common.send_some_plaintext(buf)
+    }
+
+    pub(crate) fn perhaps_write_key_update(&mut self) {
         let mut st = self.state.take();
         st.as_mut()
             .map(|st| st.perhaps_write_key_update(self));
         self.state = st;
-        self.common.send_some_plaintext(buf)
     }
 }
 
@@ -592,6 +1353,17 @@ impl ServerSession {
         }
     }
 
+    /// Give secrets derived from now on to `key_log` instead of
+    /// `config.key_log`.  Pass `Arc::new(NoKeyLog)` to stop logging
+    /// this session's secrets.
+    ///
+    /// This allows logging secrets for some sessions but not others,
+    /// without making a separate `ServerConfig` for each.  Call this
+    /// before the first `process_new_packets` to cover every secret.
+    pub fn set_key_log(&mut self, key_log: Arc<dyn KeyLog>) {
+        self.imp.key_log = key_log;
+    }
+
     /// Retrieves the SNI hostname, if any, used to select the certificate and
     /// private key.
     ///
@@ -611,7 +1383,158 @@ impl ServerSession {
     pub fn get_sni_hostname(&self) -> Option<&str> {
         self.imp
             .get_sni()
-            .map(|s| s.as_ref().into())
+            .map(DnsName::as_ref)
+    }
+
+    /// Retrieves the cipher suites offered by the client, in its
+    /// order of preference, exactly as they appeared in its ClientHello.
+    ///
+    /// This includes suites rustls doesn't support, and signalling
+    /// values like `TLS_EMPTY_RENEGOTIATION_INFO_SCSV`.  If the client
+    /// sent a second ClientHello after a HelloRetryRequest, this is the
+    /// list from the first one.
+    ///
+    /// The list is empty until a ClientHello has been received.
+    pub fn get_client_cipher_suites(&self) -> &[CipherSuite] {
+        &self.imp.client_cipher_suites
+    }
+
+    /// Retrieves the extensions in the client's ClientHello that rustls
+    /// doesn't understand, as pairs of extension type and payload.
+    ///
+    /// This is for logging and analytics, and doesn't need a
+    /// `custom_extension_handler`.  Like `get_client_cipher_suites`,
+    /// it is empty until a ClientHello has been received, and is from
+    /// the first ClientHello if there was a HelloRetryRequest.
+    pub fn get_client_unknown_extensions(&self) -> &[(u16, Vec<u8>)] {
+        &self.imp.client_unknown_extensions
+    }
+
+    /// Returns true if the handshake is paused because the
+    /// `ResolvesServerCert` returned `CertResolution::Pending`.
+    ///
+    /// Supply the certificate chain and key with `supply_certified_key`.
+    /// Until then, received TLS data is buffered but not processed.
+    pub fn wants_certified_key(&self) -> bool {
+        self.imp.wants_certified_key()
+    }
+
+    /// Continue a handshake paused by `CertResolution::Pending`,
+    /// using `key` as the certificate chain and signing key.
+    /// Passing `None` aborts the handshake.
+    ///
+    /// This also processes any TLS data received in the meantime,
+    /// like `process_new_packets`.  It fails if the handshake isn't
+    /// paused waiting for a certificate.
+    pub fn supply_certified_key(
+        &mut self,
+        key: Option<sign::CertifiedKey>,
+    ) -> Result<(), TlsError> {
+        self.imp.supply_certified_key(key)
+    }
+
+    /// Returns the signature scheme and message to sign if the
+    /// handshake is paused because a `sign::Signer` deferred signing.
+    ///
+    /// Sign the message with the private key using that scheme, and pass
+    /// the signature to `supply_signature`.  Until then, received TLS
+    /// data is buffered but not processed.
+    pub fn signature_request(&self) -> Option<(SignatureScheme, &[u8])> {
+        self.imp.signature_request()
+    }
+
+    /// Continue a handshake paused by a deferred signature, using
+    /// `sig` as the signature over the message from `signature_request`.
+    /// Passing `None` aborts the handshake.
+    ///
+    /// This also processes any TLS data received in the meantime,
+    /// like `process_new_packets`.  It fails if the handshake isn't
+    /// paused waiting for a signature.
+    pub fn supply_signature(&mut self, sig: Option<Vec<u8>>) -> Result<(), TlsError> {
+        self.imp.supply_signature(sig)
+    }
+
+    /// Returns the key of the session to look up if the handshake
+    /// is paused because the `StoresServerSessions` returned
+    /// `SessionLookup::Pending`.
+    ///
+    /// Pass the value stored against that key to `supply_session_value`.
+    /// Until then, received TLS data is buffered but not processed.
+    pub fn session_lookup_request(&self) -> Option<&[u8]> {
+        self.imp.session_lookup_request()
+    }
+
+    /// Continue a handshake paused by `SessionLookup::Pending`, using
+    /// `value` as the value stored against the key from
+    /// `session_lookup_request`.  Passing `None` means there was no
+    /// such session, and the handshake continues as a full one.
+    ///
+    /// This also processes any TLS data received in the meantime,
+    /// like `process_new_packets`.  It fails if the handshake isn't
+    /// paused waiting for a session.
+    pub fn supply_session_value(&mut self, value: Option<Vec<u8>>) -> Result<(), TlsError> {
+        self.imp.supply_session_value(value)
+    }
+
+    /// Extract the negotiated traffic keys and sequence numbers, so that
+    /// record protection can be handed over to something else -- typically
+    /// the kernel, via kTLS.
+    ///
+    /// This consumes the session: once the secrets are in use elsewhere,
+    /// this session's sequence numbers are stale and it must not be used
+    /// to send or receive any more data.
+    ///
+    /// This fails if the handshake is not complete, if there is TLS data
+    /// waiting to be written or plaintext waiting to be read, or for QUIC
+    /// and DTLS sessions.
+    ///
+    /// These are secrets: mishandling them breaks the security of the
+    /// connection, so take care over where they end up.
+    #[cfg(feature = "secret_extraction")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "secret_extraction")))]
+    pub fn dangerous_extract_secrets(self) -> Result<ExtractedSecrets, TlsError> {
+        self.imp.extract_secrets()
+    }
+
+    /// Export everything needed to carry on this session somewhere
+    /// else -- for example, in a new process during a zero-downtime
+    /// restart.  `dangerous_import` turns the result back into a
+    /// working session.
+    ///
+    /// The result is versioned, so it can be handed between builds of
+    /// rustls: an import fails cleanly if the format has changed.  It
+    /// holds the session's traffic secrets, sequence numbers, cipher
+    /// suite, SNI, ALPN protocol and client certificates.
+    ///
+    /// This consumes the session, like `dangerous_extract_secrets`,
+    /// and fails in the same circumstances.  It also fails while a
+    /// TLS1.3 key update is pending.
+    ///
+    /// The result contains secrets: anyone who sees it can read and
+    /// forge the connection's traffic, so protect it accordingly.
+    #[cfg(feature = "session_export")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "session_export")))]
+    pub fn dangerous_export(self) -> Result<Vec<u8>, TlsError> {
+        self.imp.export()
+    }
+
+    /// Rebuild a session from the output of `dangerous_export`, to
+    /// carry on the connection using `config`.  The connection's TLS
+    /// data must be fed to the new session from exactly where the
+    /// exported one left off.
+    ///
+    /// This fails if `exported` is malformed or from an incompatible
+    /// version of rustls, or if `config` doesn't enable the session's
+    /// protocol version and cipher suite.
+    #[cfg(feature = "session_export")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "session_export")))]
+    pub fn dangerous_import(
+        config: &Arc<ServerConfig>,
+        exported: &[u8],
+    ) -> Result<ServerSession, TlsError> {
+        Ok(ServerSession {
+            imp: ServerSessionImpl::import(config, exported)?,
+        })
     }
 
     /// Application-controlled portion of the resumption ticket supplied by the client, if any.
@@ -651,6 +1574,16 @@ impl ServerSession {
         );
         self.imp.reject_early_data = true;
     }
+
+    /// Returns true if this handshake answers an ACME TLS-ALPN-01
+    /// challenge with a certificate from
+    /// `ServerConfig::acme_challenge_resolver`.
+    ///
+    /// Such sessions are closed once the handshake completes, so
+    /// there's no need to hand them to the application protocol.
+    pub fn is_acme_challenge(&self) -> bool {
+        self.imp.acme_challenge
+    }
 }
 
 impl Session for ServerSession {
@@ -658,6 +1591,10 @@ impl Session for ServerSession {
         self.imp.common.read_tls(rd)
     }
 
+    fn read_tls_buf(&mut self, rd: &mut dyn io::BufRead) -> io::Result<usize> {
+        self.imp.common.read_tls_buf(rd)
+    }
+
     /// Writes TLS messages to `wr`.
     fn write_tls(&mut self, wr: &mut dyn io::Write) -> io::Result<usize> {
         self.imp.common.write_tls(wr)
@@ -712,6 +1649,22 @@ impl Session for ServerSession {
     fn get_negotiated_ciphersuite(&self) -> Option<&'static SupportedCipherSuite> {
         self.imp.get_negotiated_ciphersuite()
     }
+
+    fn get_negotiated_kx_group(&self) -> Option<&'static SupportedKxGroup> {
+        self.imp.get_negotiated_kx_group()
+    }
+
+    fn get_peer_signature_scheme(&self) -> Option<SignatureScheme> {
+        self.imp.get_peer_signature_scheme()
+    }
+
+    fn handshake_kind(&self) -> Option<HandshakeKind> {
+        self.imp.handshake_kind()
+    }
+
+    fn get_sent_fatal_alert(&self) -> Option<AlertDescription> {
+        self.imp.common.get_sent_fatal_alert()
+    }
 }
 
 impl io::Read for ServerSession {
@@ -732,6 +1685,20 @@ impl io::Read for ServerSession {
     }
 }
 
+impl io::BufRead for ServerSession {
+    /// Obtain plaintext data received from the peer, without copying
+    /// it out of the session's buffer.  This returns an empty slice if
+    /// there is none yet, and fails like `read` once the peer has
+    /// closed the session cleanly.
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        self.imp.common.fill_buf()
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.imp.common.consume(amt)
+    }
+}
+
 impl io::Write for ServerSession {
     /// Send the plaintext `buf` to the peer, encrypting
     /// and authenticating it.  Once this function succeeds
@@ -740,19 +1707,25 @@ impl io::Write for ServerSession {
     ///
     /// This function buffers plaintext sent before the
     /// TLS handshake completes, and sends it as soon
-    /// as it can.  This buffer is of *unlimited size* so
-    /// writing much data before it can be sent will
-    /// cause excess memory usage.
+    /// as it can.  This buffer is of unlimited size unless
+    /// `set_buffer_limit` is used, so writing much data before
+    /// it can be sent will cause excess memory usage.
+    ///
+    /// If the buffer limit has been reached this fails with
+    /// `io::ErrorKind::WouldBlock`.
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
-        Ok(self.imp.send_some_plaintext(buf))
+        let len = self.imp.send_some_plaintext(buf);
+        plaintext_written(len, buf.len())
     }
 
     fn write_vectored(&mut self, bufs: &[IoSlice<'_>]) -> io::Result<usize> {
         let mut sz = 0;
+        let mut requested = 0;
         for buf in bufs {
             sz += self.imp.send_some_plaintext(buf);
+            requested += buf.len();
         }
-        Ok(sz)
+        plaintext_written(sz, requested)
     }
 
     fn flush(&mut self) -> io::Result<()> {
@@ -760,3 +1733,250 @@ impl io::Write for ServerSession {
         Ok(())
     }
 }
+
+/// Reads a client's ClientHello before a `ServerConfig` is chosen for
+/// the session.
+///
+/// This lets a server choose a configuration per connection (for
+/// example, per tenant) based on the SNI, ALPN protocols, cipher suites
+/// and so on offered by the client.
+///
+/// Feed the acceptor TLS bytes with `read_tls`, and call `accept` until
+/// it returns an `Accepted`.  Then inspect `Accepted::client_hello`, and
+/// call `Accepted::into_session` with the chosen config to continue the
+/// handshake as usual.
+///
+/// Errors found by the acceptor are not reported to the client with an
+/// alert, because the acceptor never writes any TLS data.
+pub struct Acceptor {
+    deframer: MessageDeframer,
+    joiner: HandshakeJoiner,
+
+    /// The records received so far, to replay into the `ServerSession`.
+    records: Vec<Message>,
+
+    accepted: bool,
+}
+
+impl Default for Acceptor {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Acceptor {
+    /// Make a new acceptor.
+    pub fn new() -> Acceptor {
+        Acceptor {
+            deframer: MessageDeframer::new(),
+            joiner: HandshakeJoiner::new(),
+            records: Vec::new(),
+            accepted: false,
+        }
+    }
+
+    /// Returns true if the acceptor needs more TLS bytes to read
+    /// the ClientHello.
+    pub fn wants_read(&self) -> bool {
+        !self.accepted && !self.deframer.has_message()
+    }
+
+    /// Read TLS bytes from `rd`.  See `Session::read_tls`.
+    pub fn read_tls(&mut self, rd: &mut dyn io::Read) -> io::Result<usize> {
+        self.deframer.read(rd)
+    }
+
+    /// Process the TLS bytes read so far.
+    ///
+    /// Returns `Ok(None)` if more bytes are needed to read the whole
+    /// ClientHello, and an `Accepted` once it has been read.  After
+    /// that the acceptor is spent and further calls fail.
+    pub fn accept(&mut self) -> Result<Option<Accepted>, TlsError> {
+        if self.accepted {
+            return Err(TlsError::General("Acceptor polled after completion".into()));
+        }
+
+        if self.deframer.desynced {
+            return Err(TlsError::CorruptMessage);
+        }
+
+        while let Some(msg) = self.deframer.pop() {
+            if !self.joiner.want_message(&msg) {
+                return Err(TlsError::InappropriateMessage {
+                    expect_types: vec![ContentType::Handshake],
+                    got_type: msg.typ,
+                });
+            }
+
+            // Records from the deframer are always opaque.
+            if let MessagePayload::Opaque(payload) = &msg.payload {
+                self.records.push(Message {
+                    typ: msg.typ,
+                    version: msg.version,
+                    payload: MessagePayload::new_opaque(payload.0.clone()),
+                });
+            }
+
+            self.joiner
+                .take_message(msg)
+                .ok_or(TlsError::CorruptMessagePayload(ContentType::Handshake))?;
+
+            if let Some(hello) = self.joiner.frames.pop_front() {
+                match hello.payload {
+                    MessagePayload::Handshake(HandshakeMessagePayload {
+                        payload: HandshakePayload::ClientHello(payload),
+                        ..
+                    }) => {
+                        self.accepted = true;
+                        return Ok(Some(Accepted {
+                            payload,
+                            records: mem::take(&mut self.records),
+                            deframer: mem::take(&mut self.deframer),
+                        }));
+                    }
+                    MessagePayload::Handshake(hs) => {
+                        return Err(TlsError::InappropriateHandshakeMessage {
+                            expect_types: vec![HandshakeType::ClientHello],
+                            got_type: hs.typ,
+                        });
+                    }
+                    _ => unreachable!(),
+                }
+            }
+        }
+
+        Ok(None)
+    }
+}
+
+/// A ClientHello read by an `Acceptor`.
+pub struct Accepted {
+    payload: ClientHelloPayload,
+    records: Vec<Message>,
+    deframer: MessageDeframer,
+}
+
+impl Accepted {
+    /// Get the contents of the ClientHello.
+    ///
+    /// Unlike the `ClientHello` given to a `ResolvesServerCert`, the
+    /// signature schemes are all those offered by the client: they
+    /// haven't been reduced to those compatible with a configuration.
+    pub fn client_hello(&self) -> ClientHello {
+        let sni = self
+            .payload
+            .get_sni_extension()
+            .and_then(|sni| sni.get_single_hostname());
+        let sigschemes = self
+            .payload
+            .get_sigalgs_extension()
+            .map(Vec::as_slice)
+            .unwrap_or(&[]);
+
+        ClientHello::new(
+            sni,
+            sigschemes,
+            self.payload.get_alpn_extension(),
+            &self.payload.cipher_suites,
+            self.payload
+                .get_namedgroups_extension()
+                .map(Vec::as_slice),
+            self.payload
+                .get_versions_extension()
+                .map(Vec::as_slice),
+            &self.payload.extensions,
+        )
+    }
+
+    /// Make a `ServerSession` using `config` to continue the
+    /// handshake.
+    ///
+    /// The session will process the ClientHello on the next call to
+    /// `process_new_packets`.
+    pub fn into_session(self, config: &Arc<ServerConfig>) -> ServerSession {
+        let mut sess = ServerSession::new(config);
+
+        let mut deframer = self.deframer;
+        for record in self.records.into_iter().rev() {
+            deframer.frames.push_front(record);
+        }
+        sess.imp.common.message_deframer = deframer;
+
+        sess
+    }
+}
+
+/// The parts of a ClientHello that `peek_client_hello` reads.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PeekedClientHello {
+    /// The server name indicator, or `None` if the client didn't
+    /// send one.
+    pub server_name: Option<String>,
+
+    /// The protocols offered with ALPN, in the client's order of
+    /// preference.  Empty if the client didn't use ALPN.
+    pub alpn_protocols: Vec<Vec<u8>>,
+
+    /// The version in the ClientHello body.  Since TLS1.3 this is
+    /// always TLS1.2, and the real versions are in
+    /// `supported_versions`.
+    pub client_version: ProtocolVersion,
+
+    /// The versions offered in the supported_versions extension, or
+    /// `None` if the client didn't include it, meaning it doesn't
+    /// support TLS1.3.
+    pub supported_versions: Option<Vec<ProtocolVersion>>,
+}
+
+/// Read the SNI, ALPN protocols and versions from the ClientHello at
+/// the start of `bytes`, without making a session.
+///
+/// This is for routers and proxies that pick a backend by looking at
+/// the first bytes a client sends, and then pass the connection on
+/// untouched.  `bytes` is everything received so far; they aren't
+/// consumed.
+///
+/// Returns `Ok(None)` if `bytes` ends before the ClientHello does:
+/// read more and call this again.  Returns an error if `bytes` don't
+/// start with a ClientHello, for example because the client isn't
+/// speaking TLS.  Use an `Acceptor` to go on to handshake with the
+/// client yourself.
+pub fn peek_client_hello(mut bytes: &[u8]) -> Result<Option<PeekedClientHello>, TlsError> {
+    let mut acceptor = Acceptor::new();
+
+    loop {
+        if let Some(accepted) = acceptor.accept()? {
+            let hello = &accepted.payload;
+            return Ok(Some(PeekedClientHello {
+                server_name: hello
+                    .get_sni_extension()
+                    .and_then(|sni| sni.get_single_hostname())
+                    .map(|name| <&str>::from(name).to_string()),
+                alpn_protocols: hello
+                    .get_alpn_extension()
+                    .map(|protocols| {
+                        protocols
+                            .iter()
+                            .map(|proto| proto.0.clone())
+                            .collect()
+                    })
+                    .unwrap_or_default(),
+                client_version: hello.client_version,
+                supported_versions: hello.get_versions_extension().cloned(),
+            }));
+        }
+
+        if bytes.is_empty() {
+            return Ok(None);
+        }
+
+        // The deframer reads nothing if a record's length is out of range.
+        if acceptor
+            .read_tls(&mut bytes)
+            .map_err(|_| TlsError::CorruptMessage)?
+            == 0
+        {
+            return Err(TlsError::CorruptMessage);
+        }
+    }
+}
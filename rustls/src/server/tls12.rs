@@ -1,8 +1,12 @@
 use crate::check::check_message;
-use crate::error::TlsError;
+use crate::error::{PeerMisbehaved, TlsError};
+#[cfg(feature = "secret_extraction")]
+use crate::suites::ConnectionTrafficSecrets;
 #[cfg(feature = "logging")]
 use crate::log::{debug, trace};
 use crate::msgs::base::Payload;
+#[cfg(feature = "session_export")]
+use crate::msgs::base::PayloadU8;
 use crate::msgs::ccs::ChangeCipherSpecPayload;
 use crate::msgs::codec::Codec;
 use crate::msgs::enums::AlertDescription;
@@ -58,7 +62,7 @@ impl hs::State for ExpectCertificate {
         // If we can't determine if the auth is mandatory, abort
         let mandatory = sess
             .config
-            .verifier
+            .get_verifier_for_sni(sess.get_sni())
             .client_auth_mandatory(sess.get_sni())
             .ok_or_else(|| {
                 debug!("could not determine if client auth is mandatory based on SNI");
@@ -83,16 +87,8 @@ impl hs::State for ExpectCertificate {
             Some(chain) => chain,
         };
 
-        let now = std::time::SystemTime::now();
-        sess.config
-            .verifier
-            .verify_client_cert(end_entity, intermediates, sess.get_sni(), now)
-            .or_else(|err| {
-                hs::incompatible(sess, "certificate invalid");
-                Err(err)
-            })?;
-
-        let cert = ClientCertDetails::new(cert_chain.clone());
+        let verified = hs::check_client_cert(sess, end_entity, intermediates)?;
+        let cert = ClientCertDetails::new(cert_chain.clone(), verified);
         Ok(self.into_expect_tls12_client_kx(Some(cert)))
     }
 }
@@ -131,6 +127,7 @@ impl hs::State for ExpectClientKX {
                     .send_fatal_alert(AlertDescription::DecodeError);
                 TlsError::CorruptMessagePayload(ContentType::Handshake)
             })?;
+        sess.common.kx_group = Some(kxd.group);
 
         let suite = sess
             .common
@@ -149,7 +146,7 @@ impl hs::State for ExpectClientKX {
         } else {
             SessionSecrets::new(&self.randoms, suite, &kxd.shared_secret)
         };
-        sess.config.key_log.log(
+        sess.key_log.log(
             "CLIENT_RANDOM",
             &secrets.randoms.client,
             &secrets.master_secret,
@@ -204,6 +201,22 @@ impl hs::State for ExpectCertificateVerify {
         sess: &mut ServerSessionImpl,
         m: Message,
     ) -> hs::NextStateOrError {
+        if !self.client_cert.verified {
+            require_handshake_msg!(
+                m,
+                HandshakeType::CertificateVerify,
+                HandshakePayload::CertificateVerify
+            )?;
+            debug!("ignoring CertificateVerify for rejected client certificate");
+            self.handshake
+                .transcript
+                .abandon_client_auth();
+            self.handshake
+                .transcript
+                .add_message(&m);
+            return Ok(self.into_expect_tls12_ccs());
+        }
+
         let rc = {
             let sig = require_handshake_msg!(
                 m,
@@ -216,15 +229,26 @@ impl hs::State for ExpectCertificateVerify {
                 .take_handshake_buf();
             let certs = &self.client_cert.cert_chain;
 
+            if !sess.config.permits_signature_scheme(sig.scheme) {
+                return Err(hs::illegal_param(
+                    sess,
+                    PeerMisbehaved::SignedHandshakeWithUnofferedSigScheme,
+                ));
+            }
+
             sess.config
-                .get_verifier()
+                .get_verifier_for_sni(sess.get_sni())
                 .verify_tls12_signature(&handshake_msgs, &certs[0], sig)
+                .map(|_| sig.scheme)
         };
 
-        if let Err(e) = rc {
-            sess.common
-                .send_fatal_alert(AlertDescription::AccessDenied);
-            return Err(e);
+        match rc {
+            Ok(scheme) => sess.common.peer_signature_scheme = Some(scheme),
+            Err(e) => {
+                sess.common
+                    .send_fatal_alert(AlertDescription::AccessDenied);
+                return Err(e);
+            }
         }
 
         trace!("client CertificateVerify OK");
@@ -308,12 +332,17 @@ pub fn emit_ticket(
     // If we can't produce a ticket for some reason, we can't
     // report an error. Send an empty one.
     let plain = get_server_session_value_tls12(secrets, using_ems, sess).get_encoding();
-    let ticket = sess
+    let ticketer = sess
         .config
-        .ticketer
+        .get_ticketer_for_sni(sess.get_sni());
+    let ticket = ticketer
         .encrypt(&plain)
         .unwrap_or_else(Vec::new);
-    let ticket_lifetime = sess.config.ticketer.get_lifetime();
+    let ticket_lifetime = ticketer.get_lifetime();
+    if !ticket.is_empty() {
+        sess.common
+            .emit(|ev| ev.ticket_issued(ticket_lifetime));
+    }
 
     let m = Message {
         typ: ContentType::Handshake,
@@ -446,7 +475,41 @@ pub struct ExpectTraffic {
     _fin_verified: verify::FinishedMessageVerified,
 }
 
-impl ExpectTraffic {}
+impl ExpectTraffic {
+    /// Carry on a session exported by `hs::State::export_keys`,
+    /// installing its keys in `sess`.
+    #[cfg(feature = "session_export")]
+    pub fn import(
+        sess: &mut ServerSessionImpl,
+        client_random: [u8; 32],
+        server_random: [u8; 32],
+        master_secret: &[u8],
+    ) -> hs::NextStateOrError {
+        if master_secret.len() != 48 {
+            return Err(TlsError::General("invalid exported session".into()));
+        }
+
+        let randoms = SessionRandoms {
+            we_are_client: false,
+            client: client_random,
+            server: server_random,
+        };
+        let suite = sess.common.get_suite_assert();
+        let secrets = SessionSecrets::new_resume(&randoms, suite, master_secret);
+        sess.common.start_encryption_tls12(&secrets);
+        sess.common
+            .record_layer
+            .start_encrypting();
+        sess.common
+            .record_layer
+            .start_decrypting();
+
+        Ok(Box::new(ExpectTraffic {
+            secrets,
+            _fin_verified: verify::FinishedMessageVerified::assertion(),
+        }))
+    }
+}
 
 impl hs::State for ExpectTraffic {
     fn handle(
@@ -467,7 +530,24 @@ impl hs::State for ExpectTraffic {
         context: Option<&[u8]>,
     ) -> Result<(), TlsError> {
         self.secrets
-            .export_keying_material(output, label, context);
-        Ok(())
+            .export_keying_material(output, label, context)
+    }
+
+    #[cfg(feature = "secret_extraction")]
+    fn extract_secrets(
+        &self,
+        _sess: &ServerSessionImpl,
+    ) -> Result<(ConnectionTrafficSecrets, ConnectionTrafficSecrets), TlsError> {
+        let (client, server) = self.secrets.extract_secrets();
+        Ok((server, client))
+    }
+
+    #[cfg(feature = "session_export")]
+    fn export_keys(&self) -> Result<persist::ExportedKeys, TlsError> {
+        Ok(persist::ExportedKeys::Tls12 {
+            client_random: self.secrets.randoms.client,
+            server_random: self.secrets.randoms.server,
+            master_secret: PayloadU8::new(self.secrets.get_master_secret()),
+        })
     }
 }
@@ -1,10 +1,14 @@
 use crate::check::check_message;
 use crate::{cipher, SupportedCipherSuite};
-use crate::error::TlsError;
+use crate::error::{PeerMisbehaved, TlsError};
+#[cfg(feature = "secret_extraction")]
+use crate::suites::ConnectionTrafficSecrets;
 use crate::key_schedule::{
     KeyScheduleEarly, KeyScheduleHandshake, KeyScheduleNonSecret, KeyScheduleTraffic,
     KeyScheduleTrafficWithClientFinishedPending,
 };
+#[cfg(feature = "session_export")]
+use crate::key_schedule::TrafficSecrets;
 #[cfg(feature = "logging")]
 use crate::log::{debug, trace, warn};
 use crate::msgs::base::{Payload, PayloadU8};
@@ -36,6 +40,7 @@ use crate::msgs::message::{Message, MessagePayload};
 use crate::msgs::persist;
 use crate::rand;
 use crate::server::ServerSessionImpl;
+use crate::session::HandshakeKind;
 use crate::session::SessionRandoms;
 use crate::sign;
 use crate::kx;
@@ -58,6 +63,7 @@ pub struct CompleteClientHelloHandling {
 impl CompleteClientHelloHandling {
     fn check_binder(
         &self,
+        sess: &ServerSessionImpl,
         suite: &'static SupportedCipherSuite,
         client_hello: &Message,
         psk: &[u8],
@@ -74,7 +80,7 @@ impl CompleteClientHelloHandling {
             .transcript
             .get_hash_given(suite_hash, &binder_plaintext);
 
-        let key_schedule = KeyScheduleEarly::new(suite.hkdf_algorithm, &psk);
+        let key_schedule = KeyScheduleEarly::new(sess.common.protocol, suite.hkdf_algorithm, psk);
         let real_binder =
             key_schedule.resumption_psk_binder_key_and_sign_verify_data(&handshake_hash);
 
@@ -84,9 +90,11 @@ impl CompleteClientHelloHandling {
     fn into_expect_retried_client_hello(self) -> hs::NextState {
         Box::new(hs::ExpectClientHello {
             handshake: self.handshake,
+            #[cfg(feature = "tls12")]
             using_ems: false,
             done_retry: true,
             send_ticket: self.send_ticket,
+            supplied_key: None,
         })
     }
 
@@ -127,13 +135,17 @@ impl CompleteClientHelloHandling {
 
         // Do key exchange
         let kxr = kx::KeyExchange::choose(share.group, &sess.config.kx_groups)
-            .and_then(kx::KeyExchange::start)
+            .and_then(|skxg| kx::KeyExchange::start(skxg, sess.config.kx_provider.as_ref()))
             .and_then(|kx| kx.complete(&share.payload.0))
-            .ok_or_else(|| TlsError::PeerMisbehavedError("key exchange failed".to_string()))?;
+            .ok_or(TlsError::PeerMisbehavedError(PeerMisbehaved::InvalidKeyShare))?;
+        sess.common.kx_group = Some(kxr.group);
 
         let kse = KeyShareEntry::new(share.group, kxr.pubkey.as_ref());
         extensions.push(ServerExtension::KeyShare(kse));
-        extensions.push(ServerExtension::SupportedVersions(ProtocolVersion::TLSv1_3));
+        extensions.push(ServerExtension::SupportedVersions(
+            sess.common
+                .wire_version(ProtocolVersion::TLSv1_3),
+        ));
 
         if let Some(psk_idx) = chosen_psk_idx {
             extensions.push(ServerExtension::PresharedKey(psk_idx as u16));
@@ -145,7 +157,9 @@ impl CompleteClientHelloHandling {
             payload: MessagePayload::Handshake(HandshakeMessagePayload {
                 typ: HandshakeType::ServerHello,
                 payload: HandshakePayload::ServerHello(ServerHelloPayload {
-                    legacy_version: ProtocolVersion::TLSv1_2,
+                    legacy_version: sess
+                        .common
+                        .wire_version(ProtocolVersion::TLSv1_2),
                     random: Random::from_slice(&self.randoms.server),
                     session_id: *session_id,
                     cipher_suite: suite.suite,
@@ -171,7 +185,7 @@ impl CompleteClientHelloHandling {
 
         // Start key schedule
         let mut key_schedule = if let Some(psk) = resuming_psk {
-            let early_key_schedule = KeyScheduleEarly::new(suite.hkdf_algorithm, psk);
+            let early_key_schedule = KeyScheduleEarly::new(sess.common.protocol, suite.hkdf_algorithm, psk);
 
             #[cfg(feature = "quic")]
             {
@@ -179,7 +193,7 @@ impl CompleteClientHelloHandling {
                     let client_early_traffic_secret = early_key_schedule
                         .client_early_traffic_secret(
                             &client_hello_hash,
-                            &*sess.config.key_log,
+                            &*sess.key_log,
                             &self.randoms.client,
                         );
                     // If 0-RTT should be rejected, this will be clobbered by ExtensionProcessing
@@ -190,7 +204,7 @@ impl CompleteClientHelloHandling {
 
             early_key_schedule.into_handshake(&kxr.shared_secret)
         } else {
-            KeyScheduleNonSecret::new(suite.hkdf_algorithm).into_handshake(&kxr.shared_secret)
+            KeyScheduleNonSecret::new(sess.common.protocol, suite.hkdf_algorithm).into_handshake(&kxr.shared_secret)
         };
 
         let handshake_hash = self
@@ -199,7 +213,7 @@ impl CompleteClientHelloHandling {
             .get_current_hash();
         let write_key = key_schedule.server_handshake_traffic_secret(
             &handshake_hash,
-            &*sess.config.key_log,
+            &*sess.key_log,
             &self.randoms.client,
         );
         sess.common
@@ -208,7 +222,7 @@ impl CompleteClientHelloHandling {
 
         let read_key = key_schedule.client_handshake_traffic_secret(
             &handshake_hash,
-            &*sess.config.key_log,
+            &*sess.key_log,
             &self.randoms.client,
         );
         sess.common
@@ -227,7 +241,7 @@ impl CompleteClientHelloHandling {
     }
 
     fn emit_fake_ccs(&mut self, sess: &mut ServerSessionImpl) {
-        if sess.common.is_quic() {
+        if sess.common.is_quic() || sess.common.is_dtls() {
             return;
         }
         let m = Message {
@@ -239,8 +253,11 @@ impl CompleteClientHelloHandling {
     }
 
     fn emit_hello_retry_request(&mut self, suite: &'static SupportedCipherSuite, sess: &mut ServerSessionImpl, group: NamedGroup) {
+        sess.common.handshake_kind = Some(HandshakeKind::FullWithHelloRetryRequest);
         let mut req = HelloRetryRequest {
-            legacy_version: ProtocolVersion::TLSv1_2,
+            legacy_version: sess
+                .common
+                .wire_version(ProtocolVersion::TLSv1_2),
             session_id: SessionID::empty(),
             cipher_suite: suite.suite,
             extensions: Vec::new(),
@@ -250,7 +267,8 @@ impl CompleteClientHelloHandling {
             .push(HelloRetryExtension::KeyShare(group));
         req.extensions
             .push(HelloRetryExtension::SupportedVersions(
-                ProtocolVersion::TLSv1_3,
+                sess.common
+                    .wire_version(ProtocolVersion::TLSv1_3),
             ));
 
         let m = Message {
@@ -303,7 +321,10 @@ impl CompleteClientHelloHandling {
         &mut self,
         sess: &mut ServerSessionImpl,
     ) -> Result<bool, TlsError> {
-        if !sess.config.verifier.offer_client_auth() {
+        let client_auth = sess.config.get_verifier_for_sni(sess.get_sni());
+        if !client_auth.offer_client_auth()
+            || !client_auth.offer_client_auth_for_sni(sess.get_sni())
+        {
             return Ok(false);
         }
 
@@ -312,16 +333,14 @@ impl CompleteClientHelloHandling {
             extensions: Vec::new(),
         };
 
-        let schemes = sess
-            .config
-            .get_verifier()
-            .supported_verify_schemes();
+        let mut schemes = client_auth.supported_verify_schemes();
+        schemes.retain(|scheme| sess.config.permits_signature_scheme(*scheme));
         cr.extensions
-            .push(CertReqExtension::SignatureAlgorithms(schemes.to_vec()));
+            .push(CertReqExtension::SignatureAlgorithms(schemes));
 
         let names = sess
             .config
-            .verifier
+            .get_verifier_for_sni(sess.get_sni())
             .client_auth_root_subjects(sess.get_sni())
             .ok_or_else(|| {
                 debug!("could not determine root subjects based on SNI");
@@ -329,6 +348,7 @@ impl CompleteClientHelloHandling {
                     .send_fatal_alert(AlertDescription::AccessDenied);
                 TlsError::General("client rejected by client_auth_root_subjects".into())
             })?;
+        let names = sess.config.limit_ca_names(names);
 
         if !names.is_empty() {
             cr.extensions
@@ -367,9 +387,22 @@ impl CompleteClientHelloHandling {
             cert_entries.push(entry);
         }
 
+        // Apply any OCSP responses we have for intermediates.
+        let intermediate_ocsp = server_key.take_intermediate_ocsp();
+        for (entry, ocsp) in cert_entries
+            .iter_mut()
+            .skip(1)
+            .zip(intermediate_ocsp)
+        {
+            if let Some(ocsp) = ocsp {
+                entry
+                    .exts
+                    .push(CertificateExtension::CertificateStatus(CertificateStatus::new(ocsp)));
+            }
+        }
+
         if let Some(end_entity_cert) = cert_entries.first_mut() {
-            // Apply OCSP response to first certificate (we don't support OCSP
-            // except for leaf certs).
+            // Apply OCSP response to first certificate.
             if let Some(ocsp) = server_key.take_ocsp() {
                 let cst = CertificateStatus::new(ocsp);
                 end_entity_cert
@@ -405,24 +438,9 @@ impl CompleteClientHelloHandling {
     fn emit_certificate_verify_tls13(
         &mut self,
         sess: &mut ServerSessionImpl,
-        server_key: &mut sign::CertifiedKey,
-        schemes: &[SignatureScheme],
-    ) -> Result<(), TlsError> {
-        let message = verify::construct_tls13_server_verify_message(
-            &self
-                .handshake
-                .transcript
-                .get_current_hash(),
-        );
-
-        let signing_key = &server_key.key;
-        let signer = signing_key
-            .choose_scheme(schemes)
-            .ok_or_else(|| hs::incompatible(sess, "no overlapping sigschemes"))?;
-
-        let scheme = signer.get_scheme();
-        let sig = signer.sign(&message)?;
-
+        scheme: SignatureScheme,
+        sig: Vec<u8>,
+    ) {
         let cv = DigitallySignedStruct::new(scheme, sig);
 
         let m = Message {
@@ -439,7 +457,24 @@ impl CompleteClientHelloHandling {
             .transcript
             .add_message(&m);
         sess.common.send_msg(m, true);
-        Ok(())
+    }
+
+    /// Send the rest of our flight, once any CertificateVerify
+    /// is signed.
+    fn complete_server_flight(
+        mut self,
+        sess: &mut ServerSessionImpl,
+        key_schedule: KeyScheduleHandshake,
+        doing_client_auth: bool,
+    ) -> hs::NextStateOrError {
+        hs::check_aligned_handshake(sess)?;
+        let key_schedule_traffic = self.emit_finished_tls13(sess, key_schedule);
+
+        if doing_client_auth {
+            Ok(self.into_expect_certificate(key_schedule_traffic))
+        } else {
+            Ok(self.into_expect_finished(key_schedule_traffic))
+        }
     }
 
     fn emit_finished_tls13(
@@ -480,7 +515,7 @@ impl CompleteClientHelloHandling {
         let suite = sess.common.get_suite_assert();
         let write_key = key_schedule_traffic.server_application_traffic_secret(
             &hash_at_server_fin,
-            &*sess.config.key_log,
+            &*sess.key_log,
             &self.randoms.client,
         );
         sess.common
@@ -489,13 +524,13 @@ impl CompleteClientHelloHandling {
 
         key_schedule_traffic.exporter_master_secret(
             &hash_at_server_fin,
-            &*sess.config.key_log,
+            &*sess.key_log,
             &self.randoms.client,
         );
 
         let _read_key = key_schedule_traffic.client_application_traffic_secret(
             &hash_at_server_fin,
-            &*sess.config.key_log,
+            &*sess.key_log,
             &self.randoms.client,
         );
 
@@ -515,9 +550,11 @@ impl CompleteClientHelloHandling {
         sess: &mut ServerSessionImpl,
         ticket: &[u8],
     ) -> Option<persist::ServerSessionValue> {
-        if sess.config.ticketer.enabled() {
-            sess.config
-                .ticketer
+        let ticketer = sess
+            .config
+            .get_ticketer_for_sni(sess.get_sni());
+        if ticketer.enabled() {
+            ticketer
                 .decrypt(ticket)
                 .and_then(|plain| persist::ServerSessionValue::read_bytes(&plain))
         } else {
@@ -542,7 +579,7 @@ impl CompleteClientHelloHandling {
         )?;
 
         if client_hello.compression_methods.len() != 1 {
-            return Err(hs::illegal_param(sess, "client offered wrong compressions"));
+            return Err(hs::illegal_param(sess, PeerMisbehaved::OfferedIncorrectCompressions));
         }
 
         let groups_ext = client_hello
@@ -555,14 +592,16 @@ impl CompleteClientHelloHandling {
             .clone();
 
         let tls13_schemes = sign::supported_sign_tls13();
-        sigschemes_ext.retain(|scheme| tls13_schemes.contains(scheme));
+        sigschemes_ext.retain(|scheme| {
+            tls13_schemes.contains(scheme) && sess.config.permits_signature_scheme(*scheme)
+        });
 
         let shares_ext = client_hello
             .get_keyshare_extension()
             .ok_or_else(|| hs::incompatible(sess, "client didn't send keyshares"))?;
 
         if client_hello.has_keyshare_extension_with_duplicates() {
-            return Err(hs::illegal_param(sess, "client sent duplicate keyshares"));
+            return Err(hs::illegal_param(sess, PeerMisbehaved::OfferedDuplicateKeyShares));
         }
 
         // choose a share that we support
@@ -586,7 +625,26 @@ impl CompleteClientHelloHandling {
 
                 if let Some(group) = retry_group_maybe {
                     if self.done_retry {
-                        return Err(hs::illegal_param(sess, "did not follow retry request"));
+                        return Err(hs::illegal_param(
+                            sess,
+                            PeerMisbehaved::RefusedToFollowHelloRetryRequest,
+                        ));
+                    }
+
+                    #[cfg(feature = "quic")]
+                    {
+                        if sess.common.protocol == Protocol::Quic
+                            && client_hello.early_data_extension_offered()
+                        {
+                            sess.common.quic.zero_rtt = quic::ZeroRttStatus::Rejected;
+                        }
+                    }
+
+                    #[cfg(feature = "metrics")]
+                    {
+                        if client_hello.early_data_extension_offered() {
+                            sess.config.metrics.early_data_rejected.incr();
+                        }
                     }
 
                     self.emit_hello_retry_request(suite, sess, group.name);
@@ -601,44 +659,49 @@ impl CompleteClientHelloHandling {
         let mut chosen_psk_index = None;
         let mut resumedata = None;
         if let Some(psk_offer) = client_hello.get_psk() {
+            sess.common.resumption_offered = true;
             if !client_hello.check_psk_ext_is_last() {
-                return Err(hs::illegal_param(sess, "psk extension in wrong position"));
+                return Err(hs::illegal_param(sess, PeerMisbehaved::PskExtensionMustBeLast));
             }
 
             if psk_offer.binders.is_empty() {
-                return Err(hs::decode_error(sess, "psk extension missing binder"));
+                return Err(hs::decode_error(sess, PeerMisbehaved::MissingBinderInPskExtension));
             }
 
             if psk_offer.binders.len() != psk_offer.identities.len() {
                 return Err(hs::illegal_param(
                     sess,
-                    "psk extension mismatched ids/binders",
+                    PeerMisbehaved::PskExtensionWithMismatchedIdsAndBinders,
                 ));
             }
 
-            for (i, psk_id) in psk_offer.identities.iter().enumerate() {
-                let resume = match self.attempt_tls13_ticket_decryption(sess, &psk_id.identity.0)
-                    .and_then(|resumedata| hs::can_resume(sess, false, resumedata))
-                {
-                    Some(resume) => resume,
-                    None => continue,
-                };
-
-                if !self.check_binder(suite, chm, &resume.master_secret.0, &psk_offer.binders[i].0) {
-                    sess.common
-                        .send_fatal_alert(AlertDescription::DecryptError);
-                    return Err(TlsError::PeerMisbehavedError(
-                        "client sent wrong binder".to_string(),
-                    ));
-                }
+            if !sess.config.enable_tls13_resumption {
+                debug!("Not resuming: TLS1.3 resumption is disabled");
+            } else {
+                for (i, psk_id) in psk_offer.identities.iter().enumerate() {
+                    let resume = match self.attempt_tls13_ticket_decryption(sess, &psk_id.identity.0)
+                        .and_then(|resumedata| hs::can_resume(sess, false, resumedata))
+                    {
+                        Some(resume) => resume,
+                        None => continue,
+                    };
+
+                    if !self.check_binder(sess, suite, chm, &resume.master_secret.0, &psk_offer.binders[i].0) {
+                        sess.common
+                            .send_fatal_alert(AlertDescription::DecryptError);
+                        return Err(TlsError::PeerMisbehavedError(PeerMisbehaved::IncorrectBinder));
+                    }
 
-                chosen_psk_index = Some(i);
-                resumedata = Some(resume);
-                break;
+                    chosen_psk_index = Some(i);
+                    resumedata = Some(resume);
+                    break;
+                }
             }
         }
 
-        if !client_hello.psk_mode_offered(PSKKeyExchangeMode::PSK_DHE_KE) {
+        if !sess.config.enable_tls13_resumption || sess.acme_challenge {
+            self.send_ticket = false;
+        } else if !client_hello.psk_mode_offered(PSKKeyExchangeMode::PSK_DHE_KE) {
             debug!("Client unwilling to resume, DHE_KE not offered");
             self.send_ticket = false;
             chosen_psk_index = None;
@@ -648,6 +711,7 @@ impl CompleteClientHelloHandling {
         }
 
         if let Some(ref resume) = resumedata {
+            sess.common.handshake_kind = Some(HandshakeKind::Resumed);
             sess.received_resumption_data = Some(resume.application_data.0.clone());
             sess.client_cert_chain = resume.client_cert_chain.clone();
         }
@@ -674,19 +738,82 @@ impl CompleteClientHelloHandling {
         let doing_client_auth = if full_handshake {
             let client_auth = self.emit_certificate_req_tls13(sess)?;
             self.emit_certificate_tls13(sess, &mut server_key);
-            self.emit_certificate_verify_tls13(sess, &mut server_key, &sigschemes_ext)?;
+
+            let message = verify::construct_tls13_server_verify_message(
+                &self
+                    .handshake
+                    .transcript
+                    .get_current_hash(),
+            );
+            let signer = server_key
+                .key
+                .choose_scheme(&sigschemes_ext)
+                .ok_or_else(|| hs::incompatible(sess, "no overlapping sigschemes"))?;
+            let scheme = signer.get_scheme();
+
+            match signer.sign_or_defer(&message)? {
+                Some(sig) => self.emit_certificate_verify_tls13(sess, scheme, sig),
+                None => {
+                    debug!("CertificateVerify signature deferred");
+                    return Ok(Box::new(ExpectCertificateVerifySignature {
+                        next: self,
+                        key_schedule,
+                        doing_client_auth: client_auth,
+                        scheme,
+                        message,
+                    }));
+                }
+            }
+
             client_auth
         } else {
             false
         };
 
-        hs::check_aligned_handshake(sess)?;
-        let key_schedule_traffic = self.emit_finished_tls13(sess, key_schedule);
+        self.complete_server_flight(sess, key_schedule, doing_client_auth)
+    }
+}
 
-        if doing_client_auth {
-            Ok(self.into_expect_certificate(key_schedule_traffic))
-        } else {
-            Ok(self.into_expect_finished(key_schedule_traffic))
+/// The handshake is paused until the application supplies the
+/// deferred signature for our CertificateVerify.
+pub struct ExpectCertificateVerifySignature {
+    next: CompleteClientHelloHandling,
+    key_schedule: KeyScheduleHandshake,
+    doing_client_auth: bool,
+    scheme: SignatureScheme,
+    message: Vec<u8>,
+}
+
+impl hs::State for ExpectCertificateVerifySignature {
+    fn handle(
+        self: Box<Self>,
+        _sess: &mut ServerSessionImpl,
+        m: Message,
+    ) -> hs::NextStateOrError {
+        // The client has nothing to send until it gets our reply.
+        Err(TlsError::InappropriateMessage {
+            expect_types: vec![],
+            got_type: m.typ,
+        })
+    }
+
+    fn signature_request(&self) -> Option<(SignatureScheme, &[u8])> {
+        Some((self.scheme, &self.message))
+    }
+
+    fn supply_signature(
+        self: Box<Self>,
+        sess: &mut ServerSessionImpl,
+        sig: Option<Vec<u8>>,
+    ) -> hs::NextStateOrError {
+        let st = *self;
+        let mut next = st.next;
+        match sig {
+            Some(sig) => {
+                next.emit_certificate_verify_tls13(sess, st.scheme, sig);
+                next.complete_server_flight(sess, st.key_schedule, st.doing_client_auth)
+            }
+            None => Err(hs::no_signature(sess)),
         }
     }
 }
@@ -737,16 +864,14 @@ impl hs::State for ExpectCertificate {
         // We don't send any CertificateRequest extensions, so any extensions
         // here are illegal.
         if certp.any_entry_has_extension() {
-            return Err(TlsError::PeerMisbehavedError(
-                "client sent unsolicited cert extension".to_string(),
-            ));
+            return Err(TlsError::PeerMisbehavedError(PeerMisbehaved::UnsolicitedCertExtension));
         }
 
         let cert_chain = certp.convert();
 
         let mandatory = sess
             .config
-            .verifier
+            .get_verifier_for_sni(sess.get_sni())
             .client_auth_mandatory(sess.get_sni())
             .ok_or_else(|| {
                 debug!("could not determine if client auth is mandatory based on SNI");
@@ -770,16 +895,8 @@ impl hs::State for ExpectCertificate {
             Some(chain) => chain,
         };
 
-        let now = std::time::SystemTime::now();
-        sess.config
-            .get_verifier()
-            .verify_client_cert(end_entity, intermediates, sess.get_sni(), now)
-            .or_else(|err| {
-                hs::incompatible(sess, "certificate invalid");
-                Err(err)
-            })?;
-
-        let cert = ClientCertDetails::new(cert_chain);
+        let verified = hs::check_client_cert(sess, end_entity, intermediates)?;
+        let cert = ClientCertDetails::new(cert_chain, verified);
         Ok(self.into_expect_certificate_verify(cert))
     }
 }
@@ -809,6 +926,22 @@ impl hs::State for ExpectCertificateVerify {
         sess: &mut ServerSessionImpl,
         m: Message,
     ) -> hs::NextStateOrError {
+        if !self.client_cert.verified {
+            require_handshake_msg!(
+                m,
+                HandshakeType::CertificateVerify,
+                HandshakePayload::CertificateVerify
+            )?;
+            debug!("ignoring CertificateVerify for rejected client certificate");
+            self.handshake
+                .transcript
+                .abandon_client_auth();
+            self.handshake
+                .transcript
+                .add_message(&m);
+            return Ok(self.into_expect_finished());
+        }
+
         let rc = {
             let sig = require_handshake_msg!(
                 m,
@@ -825,15 +958,26 @@ impl hs::State for ExpectCertificateVerify {
             let certs = &self.client_cert.cert_chain;
             let msg = verify::construct_tls13_client_verify_message(&handshake_hash);
 
+            if !sess.config.permits_signature_scheme(sig.scheme) {
+                return Err(hs::illegal_param(
+                    sess,
+                    PeerMisbehaved::SignedHandshakeWithUnofferedSigScheme,
+                ));
+            }
+
             sess.config
-                .get_verifier()
+                .get_verifier_for_sni(sess.get_sni())
                 .verify_tls13_signature(&msg, &certs[0], sig)
+                .map(|_| sig.scheme)
         };
 
-        if let Err(e) = rc {
-            sess.common
-                .send_fatal_alert(AlertDescription::AccessDenied);
-            return Err(e);
+        match rc {
+            Ok(scheme) => sess.common.peer_signature_scheme = Some(scheme),
+            Err(e) => {
+                sess.common
+                    .send_fatal_alert(AlertDescription::AccessDenied);
+                return Err(e);
+            }
         }
 
         trace!("client CertificateVerify OK");
@@ -895,18 +1039,21 @@ impl ExpectFinished {
         sess: &mut ServerSessionImpl,
         key_schedule: &KeyScheduleTraffic,
     ) -> Result<(), rand::GetRandomFailed> {
-        let nonce = rand::random_vec(32)?;
+        let nonce = rand::random_vec(sess.config.random_provider.as_ref(), 32)?;
         let plain = get_server_session_value(handshake, key_schedule, sess, &nonce).get_encoding();
 
-        let stateless = sess.config.ticketer.enabled();
+        let ticketer = sess
+            .config
+            .get_ticketer_for_sni(sess.get_sni());
+        let stateless = ticketer.enabled();
         let (ticket, lifetime) = if stateless {
-            let ticket = match sess.config.ticketer.encrypt(&plain) {
+            let ticket = match ticketer.encrypt(&plain) {
                 Some(t) => t,
                 None => return Ok(()),
             };
-            (ticket, sess.config.ticketer.get_lifetime())
+            (ticket, ticketer.get_lifetime())
         } else {
-            let id = rand::random_vec(32)?;
+            let id = rand::random_vec(sess.config.random_provider.as_ref(), 32)?;
             let stored = sess
                 .config
                 .session_storage
@@ -919,7 +1066,7 @@ impl ExpectFinished {
             (id, stateful_lifetime)
         };
 
-        let age_add = rand::random_u32()?; // nb, we don't do 0-RTT data, so whatever
+        let age_add = rand::random_u32(sess.config.random_provider.as_ref())?; // nb, we don't do 0-RTT data, so whatever
         #[allow(unused_mut)]
         let mut payload = NewSessionTicketPayloadTLS13::new(lifetime, age_add, nonce, ticket);
         #[cfg(feature = "quic")]
@@ -944,6 +1091,8 @@ impl ExpectFinished {
         trace!("sending new ticket {:?} (stateless: {})", m, stateless);
         handshake.transcript.add_message(&m);
         sess.common.send_msg(m, true);
+        sess.common
+            .emit(|ev| ev.ticket_issued(lifetime));
         Ok(())
     }
 }
@@ -989,7 +1138,7 @@ impl hs::State for ExpectFinished {
             .key_schedule
             .client_application_traffic_secret(
                 self.handshake.hash_at_server_fin.as_ref().unwrap(),
-                &*sess.config.key_log,
+                &*sess.key_log,
                 &self.randoms.client,
             );
         sess.common
@@ -1006,7 +1155,7 @@ impl hs::State for ExpectFinished {
 
         #[cfg(feature = "quic")]
         {
-            if sess.common.protocol == Protocol::Quic {
+            if sess.common.protocol != Protocol::Tls13 {
                 return Ok(Box::new(ExpectQUICTraffic {
                     key_schedule: key_schedule_traffic,
                     _fin_verified: fin,
@@ -1026,6 +1175,33 @@ pub struct ExpectTraffic {
 }
 
 impl ExpectTraffic {
+    /// Carry on a session exported by `hs::State::export_keys`,
+    /// installing its keys in `sess`.
+    #[cfg(feature = "session_export")]
+    pub fn import(
+        sess: &mut ServerSessionImpl,
+        secrets: TrafficSecrets,
+    ) -> hs::NextStateOrError {
+        let suite = sess.common.get_suite_assert();
+        let key_schedule =
+            KeyScheduleTraffic::import(sess.common.protocol, suite.hkdf_algorithm, secrets)
+                .ok_or_else(|| TlsError::General("invalid exported session".into()))?;
+
+        let (read_key, write_key) = key_schedule.current_traffic_secrets();
+        sess.common
+            .record_layer
+            .set_message_decrypter(cipher::new_tls13_read(suite, read_key));
+        sess.common
+            .record_layer
+            .set_message_encrypter(cipher::new_tls13_write(suite, write_key));
+
+        Ok(Box::new(ExpectTraffic {
+            key_schedule,
+            want_write_key_update: false,
+            _fin_verified: verify::FinishedMessageVerified::assertion(),
+        }))
+    }
+
     fn handle_traffic(&self, sess: &mut ServerSessionImpl, mut m: Message) -> Result<(), TlsError> {
         sess.common
             .take_received_plaintext(m.take_opaque_payload().unwrap());
@@ -1039,12 +1215,13 @@ impl ExpectTraffic {
     ) -> Result<(), TlsError> {
         #[cfg(feature = "quic")]
         {
-            if let Protocol::Quic = sess.common.protocol {
+            if sess.common.protocol != Protocol::Tls13 {
                 sess.common
                     .send_fatal_alert(AlertDescription::UnexpectedMessage);
-                let msg = "KeyUpdate received in QUIC connection".to_string();
-                warn!("{}", msg);
-                return Err(TlsError::PeerMisbehavedError(msg));
+                warn!("KeyUpdate received in {:?} connection", sess.common.protocol);
+                return Err(TlsError::PeerMisbehavedError(
+                    PeerMisbehaved::KeyUpdateReceivedInQuicOrDtls,
+                ));
             }
         }
 
@@ -1070,6 +1247,8 @@ impl ExpectTraffic {
         sess.common
             .record_layer
             .set_message_decrypter(cipher::new_tls13_read(suite, &new_read_key));
+        sess.common
+            .emit(|ev| ev.key_update_received());
 
         Ok(())
     }
@@ -1108,6 +1287,34 @@ impl hs::State for ExpectTraffic {
             .export_keying_material(output, label, context)
     }
 
+    #[cfg(feature = "secret_extraction")]
+    fn extract_secrets(
+        &self,
+        sess: &ServerSessionImpl,
+    ) -> Result<(ConnectionTrafficSecrets, ConnectionTrafficSecrets), TlsError> {
+        if self.want_write_key_update {
+            return Err(TlsError::General("a key update is pending".into()));
+        }
+        let (client, server) = self
+            .key_schedule
+            .extract_secrets(sess.common.get_suite_assert());
+        Ok((server, client))
+    }
+
+    #[cfg(feature = "session_export")]
+    fn export_keys(&self) -> Result<persist::ExportedKeys, TlsError> {
+        if self.want_write_key_update {
+            return Err(TlsError::General("a key update is pending".into()));
+        }
+        let secrets = self.key_schedule.export();
+        Ok(persist::ExportedKeys::Tls13 {
+            master_secret: PayloadU8::new(secrets.master.clone()),
+            client_traffic_secret: PayloadU8::new(secrets.client.clone()),
+            server_traffic_secret: PayloadU8::new(secrets.server.clone()),
+            exporter_secret: PayloadU8::new(secrets.exporter.clone()),
+        })
+    }
+
     fn perhaps_write_key_update(&mut self, sess: &mut ServerSessionImpl) {
         if self.want_write_key_update {
             self.want_write_key_update = false;
@@ -1121,6 +1328,8 @@ impl hs::State for ExpectTraffic {
             sess.common
                 .record_layer
                 .set_message_encrypter(cipher::new_tls13_write(scs, &write_key));
+            sess.common
+                .emit(|ev| ev.key_update_sent());
         }
     }
 }
@@ -0,0 +1,121 @@
+use std::convert::TryFrom;
+use std::error::Error;
+use std::fmt;
+use std::net::IpAddr;
+
+/// The name of the server a client connects to.
+///
+/// This is either a DNS name, or an IP address when connecting
+/// to a server by address.  The server's certificate is validated
+/// against it: DNS names are matched against the certificate's
+/// dNSName subject alternative names, and IP addresses against its
+/// iPAddress subject alternative names.
+///
+/// Make one from a string with `ServerName::try_from`:
+///
+/// ```
+/// use std::convert::TryFrom;
+/// use rustls::ServerName;
+///
+/// assert!(matches!(ServerName::try_from("example.com"), Ok(ServerName::DnsName(_))));
+/// assert!(matches!(ServerName::try_from("192.0.2.1"), Ok(ServerName::IpAddress(_))));
+/// assert!(ServerName::try_from("not a name").is_err());
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum ServerName {
+    /// A DNS name.  This is sent to the server using SNI.
+    DnsName(DnsName),
+
+    /// An IPv4 or IPv6 address.  SNI isn't used when connecting
+    /// by address.
+    IpAddress(IpAddr),
+}
+
+impl ServerName {
+    /// The DNS name, if this is one.
+    pub(crate) fn dns_name(&self) -> Option<webpki::DNSNameRef<'_>> {
+        match self {
+            ServerName::DnsName(name) => Some(name.as_webpki()),
+            ServerName::IpAddress(_) => None,
+        }
+    }
+
+    /// The name in the form used to key the client session cache.
+    pub(crate) fn to_key_string(&self) -> String {
+        match self {
+            ServerName::DnsName(name) => name.as_ref().to_string(),
+            ServerName::IpAddress(ip) => ip.to_string(),
+        }
+    }
+}
+
+impl TryFrom<&str> for ServerName {
+    type Error = InvalidDnsNameError;
+
+    /// Parse `s` as an IP address, or failing that as a DNS name.
+    fn try_from(s: &str) -> Result<Self, Self::Error> {
+        if let Ok(ip) = s.parse() {
+            return Ok(ServerName::IpAddress(ip));
+        }
+
+        webpki::DNSNameRef::try_from_ascii_str(s)
+            .map(|name| ServerName::DnsName(DnsName(name.into())))
+            .map_err(|_| InvalidDnsNameError)
+    }
+}
+
+impl From<IpAddr> for ServerName {
+    fn from(ip: IpAddr) -> Self {
+        ServerName::IpAddress(ip)
+    }
+}
+
+/// A syntactically valid DNS name, as used in a `ServerName`
+/// or received from a client using SNI.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct DnsName(pub(crate) webpki::DNSName);
+
+impl DnsName {
+    pub(crate) fn as_webpki(&self) -> webpki::DNSNameRef<'_> {
+        self.0.as_ref()
+    }
+}
+
+impl AsRef<str> for DnsName {
+    fn as_ref(&self) -> &str {
+        AsRef::<str>::as_ref(&self.0)
+    }
+}
+
+/// The provided input could not be parsed as a DNS name
+/// or IP address.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InvalidDnsNameError;
+
+impl fmt::Display for InvalidDnsNameError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid dns name")
+    }
+}
+
+impl Error for InvalidDnsNameError {}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_try_from_str() {
+        let name = ServerName::try_from("example.com").unwrap();
+        assert_eq!(name.to_key_string(), "example.com");
+        assert!(name.dns_name().is_some());
+
+        let ip = ServerName::try_from("2001:db8::1").unwrap();
+        assert_eq!(ip, ServerName::IpAddress("2001:db8::1".parse().unwrap()));
+        assert!(ip.dns_name().is_none());
+
+        assert_eq!(ServerName::try_from("example.com:443"), Err(InvalidDnsNameError));
+        assert_eq!(ServerName::try_from(""), Err(InvalidDnsNameError));
+    }
+}
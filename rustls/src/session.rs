@@ -1,27 +1,44 @@
+use crate::bufpool::BufferPool;
 use crate::cipher;
-use crate::error::TlsError;
+use crate::error::{PeerMisbehaved, TlsError};
+use crate::events::ConnectionEvents;
+#[cfg(feature = "metrics")]
+use crate::metrics::Metrics;
 use crate::key;
+#[cfg(feature = "tls13")]
+use crate::key_schedule::ExporterSecret;
+use crate::kx::SupportedKxGroup;
 #[cfg(feature = "logging")]
 use crate::log::{debug, error, warn};
 use crate::msgs::base::Payload;
 use crate::msgs::codec::Codec;
 use crate::msgs::deframer::MessageDeframer;
-use crate::msgs::enums::{AlertDescription, AlertLevel, ContentType, ProtocolVersion};
+use crate::msgs::enums::{AlertDescription, AlertLevel, ContentType, ProtocolVersion, SignatureScheme};
+#[cfg(feature = "quic")]
+use crate::msgs::enums::SRTPProtectionProfile;
 use crate::msgs::fragmenter::{MessageFragmenter, MAX_FRAGMENT_LEN};
 use crate::msgs::hsjoiner::HandshakeJoiner;
-use crate::msgs::message::{BorrowMessage, Message, MessagePayload};
+use crate::msgs::message::{BorrowMessage, BorrowMessageMut, Message, MessagePayload};
+#[cfg(feature = "tls12")]
 use crate::prf;
 use crate::quic;
 use crate::rand;
 use crate::record_layer;
 use crate::suites::SupportedCipherSuite;
+#[cfg(feature = "secret_extraction")]
+use crate::suites::{ConnectionTrafficSecrets, ExtractedSecrets};
 use crate::vecbuf::ChunkVecBuffer;
-use ring;
-use std::io::{Read, Write};
+use std::io::{BufRead, Read, Write};
 
 use std::collections::VecDeque;
 use std::io;
+use std::mem;
+use std::sync::Arc;
+use std::task::Poll;
+#[cfg(feature = "tls12")]
 use ring::digest::Digest;
+#[cfg(feature = "tls13")]
+use ring::hkdf::KeyType as _;
 
 /// Generalises `ClientSession` and `ServerSession`
 pub trait Session: quic::QuicExt + Read + Write + Send + Sync {
@@ -38,8 +55,23 @@ pub trait Session: quic::QuicExt + Read + Write + Send + Sync {
     /// This function returns `Ok(0)` when the underlying `rd` does
     /// so.  This typically happens when a socket is cleanly closed,
     /// or a file is at EOF.
+    ///
+    /// To bound the memory a slow reader can cost, this fails without
+    /// reading anything while more than 16KB of received plaintext is
+    /// waiting to be `read`.  Callers which only read TLS data when
+    /// `wants_read` says so never see this.
     fn read_tls(&mut self, rd: &mut dyn Read) -> Result<usize, io::Error>;
 
+    /// Like `read_tls`, but parse TLS messages straight out of `rd`'s
+    /// buffer where possible, rather than first copying them into the
+    /// session's own.
+    ///
+    /// This reads at most the contents of one `fill_buf()`.
+    fn read_tls_buf(&mut self, rd: &mut dyn BufRead) -> Result<usize, io::Error> {
+        let mut rd = rd;
+        self.read_tls(&mut rd)
+    }
+
     /// Writes TLS messages to `wr`.
     ///
     /// On success the function returns `Ok(n)` where `n` is a number
@@ -50,6 +82,11 @@ pub trait Session: quic::QuicExt + Read + Write + Send + Sync {
     /// yet fully flushed. [`wants_write`] function can be used
     /// to check if output buffer is not empty.
     ///
+    /// All the queued TLS records are offered to `wr` in one call to
+    /// `write_vectored`, so a socket sends them with a single `writev`.
+    /// Writers which don't override `write_vectored` only take the
+    /// first record per call.
+    ///
     /// [`wants_write`]: #tymethod.wants_write
     fn write_tls(&mut self, wr: &mut dyn Write) -> Result<usize, io::Error>;
 
@@ -86,6 +123,11 @@ pub trait Session: quic::QuicExt + Read + Write + Send + Sync {
     /// unsent plaintext (prior to completing the TLS handshake)
     /// and unsent TLS records.
     ///
+    /// Once the limit is reached, `write` accepts only what fits,
+    /// and fails with `io::ErrorKind::WouldBlock` if nothing does.
+    /// Call `write_tls` to make room.  This gives backpressure
+    /// when the transport is slower than the application.
+    ///
     /// By default, there is no limit.  The limit can be set
     /// at any time, even if the current buffer use is higher.
     fn set_buffer_limit(&mut self, limit: usize);
@@ -134,7 +176,9 @@ pub trait Session: quic::QuicExt + Read + Write + Send + Sync {
     /// "early" exporter at any point.
     ///
     /// This function fails if called prior to the handshake completing;
-    /// check with `is_handshaking()` first.
+    /// check with `is_handshaking()` first.  It also fails if `output` is
+    /// too long (in TLS1.3, more than 255 times the hash length of the
+    /// ciphersuite) or, in TLS1.2, if `context` is longer than 65535 bytes.
     fn export_keying_material(
         &self,
         output: &mut [u8],
@@ -147,6 +191,79 @@ pub trait Session: quic::QuicExt + Read + Write + Send + Sync {
     /// This returns None until the ciphersuite is agreed.
     fn get_negotiated_ciphersuite(&self) -> Option<&'static SupportedCipherSuite>;
 
+    /// Retrieves the key exchange group used with the peer.
+    ///
+    /// This returns None until the key exchange is done, and for
+    /// TLS1.2 resumptions, which don't do one.
+    fn get_negotiated_kx_group(&self) -> Option<&'static SupportedKxGroup>;
+
+    /// Retrieves the signature scheme the peer authenticated the
+    /// handshake with: in its CertificateVerify, or a TLS1.2 server's
+    /// ServerKeyExchange.
+    ///
+    /// This returns None until the peer's signature is verified, and
+    /// if it never sends one: a client without a certificate, or any
+    /// resumption.
+    fn get_peer_signature_scheme(&self) -> Option<SignatureScheme>;
+
+    /// Says what kind of handshake this session did: full, full with a
+    /// HelloRetryRequest, or a resumption.
+    ///
+    /// This returns None until the handshake is complete.
+    fn handshake_kind(&self) -> Option<HandshakeKind>;
+
+    /// Retrieves the fatal alert this session sent to the peer, if
+    /// it has sent one.
+    ///
+    /// An error from this session's methods is usually accompanied by
+    /// a fatal alert, which says what rustls told the peer was wrong.
+    /// Errors from alerts the peer sent are `TlsError::AlertReceived`.
+    fn get_sent_fatal_alert(&self) -> Option<AlertDescription>;
+
+    /// Returns the "tls-exporter" channel binding for this connection,
+    /// as defined in RFC9266.
+    ///
+    /// This is the value SCRAM-PLUS and similar mechanisms bind their
+    /// authentication to: 32 bytes from `export_keying_material` with
+    /// the label `EXPORTER-Channel-Binding` and no context.
+    ///
+    /// RFC9266 only defines this binding for TLS1.3, and for TLS1.2
+    /// with the extended master secret extension.  Without that
+    /// extension a TLS1.2 connection isn't uniquely identified by its
+    /// exporter output, so callers wanting strong binding should only
+    /// enable TLS1.3.
+    ///
+    /// This function fails if called prior to the handshake completing.
+    fn get_tls_exporter_channel_binding(&self) -> Result<[u8; 32], TlsError> {
+        let mut binding = [0u8; 32];
+        self.export_keying_material(&mut binding, b"EXPORTER-Channel-Binding", None)?;
+        Ok(binding)
+    }
+
+    /// Derives an `ExporterSecret`, from which a protocol layered on
+    /// this session can derive further secrets with HKDF-Expand-Label
+    /// and the hash of the negotiated ciphersuite.
+    ///
+    /// The secret is the output of `export_keying_material` for
+    /// `label` and `context`, as long as the hash.  Each protocol
+    /// should use its own `label`, so its secrets are independent of
+    /// other uses of the exporter.
+    ///
+    /// This function fails if called prior to the handshake completing.
+    #[cfg(feature = "tls13")]
+    fn exporter_secret(
+        &self,
+        label: &[u8],
+        context: Option<&[u8]>,
+    ) -> Result<ExporterSecret, TlsError> {
+        let suite = self
+            .get_negotiated_ciphersuite()
+            .ok_or(TlsError::HandshakeNotComplete)?;
+        let mut secret = vec![0u8; suite.hkdf_algorithm.len()];
+        self.export_keying_material(&mut secret, label, context)?;
+        Ok(ExporterSecret::new(suite.hkdf_algorithm, &secret))
+    }
+
     /// This function uses `io` to complete any outstanding IO for
     /// this session.
     ///
@@ -215,47 +332,164 @@ pub trait Session: quic::QuicExt + Read + Write + Send + Sync {
             }
         }
     }
+
+    /// Like `complete_io`, but only drive the handshake: perform IO on
+    /// `io` until `is_handshaking()` is false, then write out anything
+    /// the handshake left queued, and return.  Unlike `complete_io`, it
+    /// never waits to read application data.
+    ///
+    /// This lets blocking callers tell handshake failures apart from
+    /// errors in the application IO which follows.  If the handshake is
+    /// already complete this only writes queued TLS data.
+    ///
+    /// The return value is the number of bytes read from and written
+    /// to `io`, respectively.  Errors are as for `complete_io`.
+    fn complete_handshake<T>(&mut self, io: &mut T) -> Result<(usize, usize), io::Error>
+    where
+        Self: Sized,
+        T: Read + Write,
+    {
+        let mut rdlen = 0;
+        let mut wrlen = 0;
+
+        while self.is_handshaking() {
+            let (rd, wr) = self.complete_io(io)?;
+            rdlen += rd;
+            wrlen += wr;
+        }
+
+        while self.wants_write() {
+            wrlen += self.write_tls(io)?;
+        }
+
+        Ok((rdlen, wrlen))
+    }
+
+    /// Like `read_tls`, but for a nonblocking `rd`: this returns
+    /// `Poll::Pending` where `rd` fails with `io::ErrorKind::WouldBlock`.
+    ///
+    /// The `poll_` functions are for adapting rustls to async runtimes.
+    /// Wrap the runtime's transport in something which implements
+    /// `Read` and `Write` by polling it, failing with `WouldBlock` once
+    /// it has arranged for the task to be woken.  Then a `Pending`
+    /// result from these functions can be passed straight back to the
+    /// runtime.
+    fn poll_read_tls(&mut self, rd: &mut dyn Read) -> Poll<Result<usize, io::Error>> {
+        match self.read_tls(rd) {
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => Poll::Pending,
+            result => Poll::Ready(result),
+        }
+    }
+
+    /// Like `write_tls`, but for a nonblocking `wr`: this returns
+    /// `Poll::Pending` where `wr` fails with `io::ErrorKind::WouldBlock`.
+    fn poll_write_tls(&mut self, wr: &mut dyn Write) -> Poll<Result<usize, io::Error>> {
+        match self.write_tls(wr) {
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => Poll::Pending,
+            result => Poll::Ready(result),
+        }
+    }
+
+    /// Like `complete_handshake`, but for a nonblocking `io`: drive
+    /// the handshake as far as `io` allows, returning `Poll::Pending`
+    /// when it would block.  Call this again once `io` is ready.
+    ///
+    /// This returns `Poll::Ready(Ok(()))` once the handshake is complete
+    /// and everything it queued has been written.  Errors are as for
+    /// `complete_io`.
+    fn poll_handshake<T>(&mut self, io: &mut T) -> Poll<Result<(), io::Error>>
+    where
+        Self: Sized,
+        T: Read + Write,
+    {
+        loop {
+            while self.wants_write() {
+                match self.poll_write_tls(io) {
+                    Poll::Ready(Ok(0)) => return Poll::Ready(Err(io::ErrorKind::WriteZero.into())),
+                    Poll::Ready(Ok(_)) => {}
+                    Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                    Poll::Pending => return Poll::Pending,
+                }
+            }
+
+            if !self.is_handshaking() {
+                return Poll::Ready(Ok(()));
+            }
+
+            match self.poll_read_tls(io) {
+                Poll::Ready(Ok(0)) => return Poll::Ready(Err(io::ErrorKind::UnexpectedEof.into())),
+                Poll::Ready(Ok(_)) => {}
+                Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                Poll::Pending => return Poll::Pending,
+            }
+
+            if let Err(e) = self.process_new_packets() {
+                // As in `complete_io`, try to send any alert describing
+                // the error, without letting that mask it.
+                let _ignored = self.write_tls(io);
+                return Poll::Ready(Err(io::Error::new(io::ErrorKind::InvalidData, e)));
+            }
+        }
+    }
 }
 
-#[derive(Copy, Clone, Eq, PartialEq)]
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
 pub enum Protocol {
     Tls13,
     #[cfg(feature = "quic")]
     Quic,
+    #[cfg(feature = "dtls")]
+    Dtls,
+}
+
+impl Protocol {
+    /// The prefix of HKDF-Expand-Label labels in this protocol's key schedule.
+    pub fn label_prefix(self) -> &'static [u8] {
+        match self {
+            #[cfg(feature = "dtls")]
+            Protocol::Dtls => b"dtls13",
+            _ => b"tls13 ",
+        }
+    }
 }
 
 #[derive(Clone, Debug)]
 pub struct SessionRandoms {
+    #[cfg(feature = "tls12")]
     pub we_are_client: bool,
     pub client: [u8; 32],
     pub server: [u8; 32],
 }
 
+#[cfg(feature = "tls12")]
 static TLS12_DOWNGRADE_SENTINEL: &[u8] = &[0x44, 0x4f, 0x57, 0x4e, 0x47, 0x52, 0x44, 0x01];
 
 impl SessionRandoms {
-    pub fn for_server() -> Result<SessionRandoms, rand::GetRandomFailed> {
+    pub fn for_server(rng: &dyn rand::ProvidesRandom) -> Result<SessionRandoms, rand::GetRandomFailed> {
         let mut ret = SessionRandoms {
+            #[cfg(feature = "tls12")]
             we_are_client: false,
             client: [0u8; 32],
             server: [0u8; 32],
         };
 
-        rand::fill_random(&mut ret.server)?;
+        rng.fill(&mut ret.server)?;
         Ok(ret)
     }
 
-    pub fn for_client() -> Result<SessionRandoms, rand::GetRandomFailed> {
+    pub fn for_client(rng: &dyn rand::ProvidesRandom) -> Result<SessionRandoms, rand::GetRandomFailed> {
         let mut ret = SessionRandoms {
+            #[cfg(feature = "tls12")]
             we_are_client: true,
             client: [0u8; 32],
             server: [0u8; 32],
         };
 
-        rand::fill_random(&mut ret.client)?;
+        rng.fill(&mut ret.client)?;
         Ok(ret)
     }
 
+    #[cfg(feature = "tls12")]
     pub fn set_tls12_downgrade_marker(&mut self) {
         assert!(!self.we_are_client);
         self.server[24..]
@@ -264,6 +498,7 @@ impl SessionRandoms {
             .unwrap();
     }
 
+    #[cfg(feature = "tls12")]
     pub fn has_tls12_downgrade_marker(&mut self) -> bool {
         assert!(self.we_are_client);
         // both the server random and TLS12_DOWNGRADE_SENTINEL are
@@ -272,6 +507,7 @@ impl SessionRandoms {
     }
 }
 
+#[cfg(feature = "tls12")]
 fn join_randoms(first: &[u8], second: &[u8]) -> [u8; 64] {
     let mut randoms = [0u8; 64];
     randoms
@@ -286,12 +522,14 @@ fn join_randoms(first: &[u8], second: &[u8]) -> [u8; 64] {
 }
 
 /// TLS1.2 per-session keying material
+#[cfg(feature = "tls12")]
 pub struct SessionSecrets {
     pub randoms: SessionRandoms,
     suite: &'static SupportedCipherSuite,
     pub master_secret: [u8; 48],
 }
 
+#[cfg(feature = "tls12")]
 impl SessionSecrets {
     pub fn new(
         randoms: &SessionRandoms,
@@ -376,6 +614,26 @@ impl SessionSecrets {
         self.suite
     }
 
+    /// Returns the client and server traffic secrets, in that order.
+    #[cfg(feature = "secret_extraction")]
+    pub(crate) fn extract_secrets(&self) -> (ConnectionTrafficSecrets, ConnectionTrafficSecrets) {
+        // Chop up the key block the same way as `cipher::new_tls12`.
+        let scs = self.suite;
+        let key_block = self.make_key_block(scs.key_block_len());
+        let (client_key, rest) = key_block.split_at(scs.enc_key_len);
+        let (server_key, rest) = rest.split_at(scs.enc_key_len);
+        let (client_iv, rest) = rest.split_at(scs.fixed_iv_len);
+        let (server_iv, explicit_nonce) = rest.split_at(scs.fixed_iv_len);
+
+        let secrets = |key: &[u8], iv: &[u8]| {
+            let mut nonce = iv.to_vec();
+            nonce.extend_from_slice(explicit_nonce);
+            ConnectionTrafficSecrets::new(&scs.bulk, key, &nonce)
+        };
+
+        (secrets(client_key, client_iv), secrets(server_key, server_iv))
+    }
+
     pub fn get_master_secret(&self) -> Vec<u8> {
         let mut ret = Vec::new();
         ret.extend_from_slice(&self.master_secret);
@@ -404,17 +662,25 @@ impl SessionSecrets {
         self.make_verify_data(handshake_hash, b"server finished")
     }
 
-    pub fn export_keying_material(&self, output: &mut [u8], label: &[u8], context: Option<&[u8]>) {
+    pub fn export_keying_material(
+        &self,
+        output: &mut [u8],
+        label: &[u8],
+        context: Option<&[u8]>,
+    ) -> Result<(), TlsError> {
         let mut randoms = Vec::new();
         randoms.extend_from_slice(&self.randoms.client);
         randoms.extend_from_slice(&self.randoms.server);
         if let Some(context) = context {
-            assert!(context.len() <= 0xffff);
+            if context.len() > 0xffff {
+                return Err(TlsError::General("exporter context too long".to_string()));
+            }
             (context.len() as u16).encode(&mut randoms);
             randoms.extend_from_slice(context);
         }
 
-        prf::prf(output, self.suite.hmac_algorithm(), &self.master_secret, label, &randoms)
+        prf::prf(output, self.suite.hmac_algorithm(), &self.master_secret, label, &randoms);
+        Ok(())
     }
 }
 
@@ -425,6 +691,22 @@ enum Limit {
     No,
 }
 
+/// How much received plaintext may wait to be read before
+/// `read_tls` refuses to read more.
+const RECEIVED_PLAINTEXT_LIMIT: usize = 16 * 1024;
+
+/// The result of a plaintext `write` of `requested` bytes, of which
+/// `accepted` fit in the buffers.  Accepting none of a non-empty write
+/// means the buffer limit was reached: that is `WouldBlock`, because
+/// `io::Write` callers take a zero-length write as fatal.
+pub(crate) fn plaintext_written(accepted: usize, requested: usize) -> io::Result<usize> {
+    if accepted == 0 && requested > 0 {
+        Err(io::ErrorKind::WouldBlock.into())
+    } else {
+        Ok(accepted)
+    }
+}
+
 /// For TLS1.3 middlebox compatibility mode, how to handle
 /// a received ChangeCipherSpec message.
 pub enum MiddleboxCCS {
@@ -435,15 +717,61 @@ pub enum MiddleboxCCS {
     Drop,
 }
 
+/// The kind of handshake a session did.  See `Session::handshake_kind`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HandshakeKind {
+    /// A full handshake.
+    Full,
+    /// A full TLS1.3 handshake, which took an extra round trip because
+    /// the server asked for a different key share with a
+    /// HelloRetryRequest.
+    FullWithHelloRetryRequest,
+    /// A resumption of an earlier session.  This is reported even if
+    /// there was also a HelloRetryRequest.
+    Resumed,
+}
+
+/// What to do when a TLS1.2 peer tries to renegotiate: a server
+/// sending a HelloRequest, or a client sending a new ClientHello
+/// once the handshake is complete.
+///
+/// rustls never renegotiates.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RenegotiationPolicy {
+    /// Send a warning no_renegotiation alert, and carry on using
+    /// the session.  This is the default.
+    Decline,
+    /// Send a fatal no_renegotiation alert, and fail the session.
+    Abort,
+}
+
+/// A received record, as returned by `SessionCommon::next_incoming`.
+pub enum Incoming {
+    /// A record as it came off the wire.
+    Record(Message),
+    /// A record which has already been decrypted.
+    Decrypted(Message),
+}
+
 pub struct SessionCommon {
     pub negotiated_version: Option<ProtocolVersion>,
     pub is_client: bool,
     pub record_layer: record_layer::RecordLayer,
     suite: Option<&'static SupportedCipherSuite>,
+    pub kx_group: Option<&'static SupportedKxGroup>,
+    pub peer_signature_scheme: Option<SignatureScheme>,
+    /// Set during the handshake if it isn't a plain full one.
+    pub handshake_kind: Option<HandshakeKind>,
+    /// Whether the client offered to resume a previous session.
+    pub resumption_offered: bool,
+    events: Option<Arc<dyn ConnectionEvents>>,
+    /// Where to count handshakes and bytes encrypted: `config.metrics`.
+    #[cfg(feature = "metrics")]
+    pub metrics: Option<Arc<Metrics>>,
     peer_eof: bool,
     pub traffic: bool,
     pub early_traffic: bool,
-    sent_fatal_alert: bool,
+    sent_fatal_alert: Option<AlertDescription>,
     received_middlebox_ccs: bool,
     pub message_deframer: MessageDeframer,
     pub handshake_joiner: HandshakeJoiner,
@@ -458,23 +786,49 @@ pub struct SessionCommon {
 }
 
 impl SessionCommon {
-    pub fn new(mtu: Option<usize>, client: bool) -> SessionCommon {
+    pub fn new(
+        mtu: Option<usize>,
+        buffer_pool: Option<&Arc<BufferPool>>,
+        events: Option<Arc<dyn ConnectionEvents>>,
+        client: bool,
+    ) -> SessionCommon {
+        let (message_deframer, mut received_plaintext, sendable_tls) = match buffer_pool {
+            Some(pool) => (
+                MessageDeframer::new_pooled(pool.clone()),
+                ChunkVecBuffer::new_pooled(pool.clone()),
+                ChunkVecBuffer::new_pooled(pool.clone()),
+            ),
+            None => (
+                MessageDeframer::new(),
+                ChunkVecBuffer::new(),
+                ChunkVecBuffer::new(),
+            ),
+        };
+        received_plaintext.set_limit(RECEIVED_PLAINTEXT_LIMIT);
+
         SessionCommon {
             negotiated_version: None,
             is_client: client,
             record_layer: record_layer::RecordLayer::new(),
             suite: None,
+            kx_group: None,
+            peer_signature_scheme: None,
+            handshake_kind: None,
+            resumption_offered: false,
+            events,
+            #[cfg(feature = "metrics")]
+            metrics: None,
             peer_eof: false,
             traffic: false,
             early_traffic: false,
-            sent_fatal_alert: false,
+            sent_fatal_alert: None,
             received_middlebox_ccs: false,
-            message_deframer: MessageDeframer::new(),
+            message_deframer,
             handshake_joiner: HandshakeJoiner::new(),
             message_fragmenter: MessageFragmenter::new(mtu.unwrap_or(MAX_FRAGMENT_LEN)),
-            received_plaintext: ChunkVecBuffer::new(),
+            received_plaintext,
             sendable_plaintext: ChunkVecBuffer::new(),
-            sendable_tls: ChunkVecBuffer::new(),
+            sendable_tls,
             protocol: Protocol::Tls13,
             #[cfg(feature = "quic")]
             quic: Quic::new(),
@@ -492,14 +846,95 @@ impl SessionCommon {
         self.suite
     }
 
+    pub fn handshake_kind(&self) -> Option<HandshakeKind> {
+        if !self.traffic {
+            return None;
+        }
+
+        Some(
+            self.handshake_kind
+                .unwrap_or(HandshakeKind::Full),
+        )
+    }
+
+    pub fn get_sent_fatal_alert(&self) -> Option<AlertDescription> {
+        self.sent_fatal_alert
+    }
+
     pub fn get_suite_assert(&self) -> &'static SupportedCipherSuite {
         self.suite.as_ref().unwrap()
     }
 
+    /// Pair the (tx, rx) traffic `secrets` with our sequence numbers, for
+    /// handing record protection over to someone else.
+    ///
+    /// This fails if any data would be lost by doing so.
+    #[cfg(feature = "secret_extraction")]
+    pub(crate) fn extract_secrets(
+        &self,
+        secrets: Result<(ConnectionTrafficSecrets, ConnectionTrafficSecrets), TlsError>,
+    ) -> Result<ExtractedSecrets, TlsError> {
+        if self.protocol != Protocol::Tls13 {
+            return Err(TlsError::General(
+                "secret extraction is not supported for this protocol".into(),
+            ));
+        }
+
+        self.check_quiescent()?;
+
+        let (tx, rx) = secrets?;
+        Ok(ExtractedSecrets {
+            tx: (self.record_layer.write_seq(), tx),
+            rx: (self.record_layer.read_seq(), rx),
+        })
+    }
+
+    /// Check the handshake is complete and no data is buffered in
+    /// either direction, so the session can be carried on elsewhere
+    /// without losing any.
+    #[cfg(feature = "secret_extraction")]
+    pub(crate) fn check_quiescent(&self) -> Result<(), TlsError> {
+        if !self.traffic {
+            return Err(TlsError::HandshakeNotComplete);
+        }
+
+        if !self.sendable_plaintext.is_empty() || !self.sendable_tls.is_empty() {
+            return Err(TlsError::General(
+                "data is still waiting to be written".into(),
+            ));
+        }
+
+        if !self.received_plaintext.is_empty() || self.message_deframer.has_pending() {
+            return Err(TlsError::General(
+                "received data is still waiting to be read".into(),
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// Count `len` bytes of plaintext going into `config.metrics`.
+    #[cfg(feature = "metrics")]
+    pub fn count_encrypted(&self, len: usize) {
+        if let Some(metrics) = &self.metrics {
+            metrics.bytes_encrypted.add(len);
+        }
+    }
+
+    /// Tell `config.connection_events`, if any, about something.
+    pub fn emit<F: FnOnce(&dyn ConnectionEvents)>(&self, f: F) {
+        if let Some(events) = &self.events {
+            f(events.as_ref());
+        }
+    }
+
     pub fn set_suite(&mut self, suite: &'static SupportedCipherSuite) -> bool {
         match self.suite {
             None => {
                 self.suite = Some(suite);
+                if let Some(version) = self.negotiated_version {
+                    self.emit(|ev| ev.parameters_chosen(version, suite.suite));
+                }
                 true
             }
             Some(s) if s == suite => {
@@ -521,9 +956,7 @@ impl SessionCommon {
         }
 
         if self.received_middlebox_ccs {
-            Err(TlsError::PeerMisbehavedError(
-                "illegal middlebox CCS received".into(),
-            ))
+            Err(TlsError::PeerMisbehavedError(PeerMisbehaved::IllegalMiddleboxChangeCipherSpec))
         } else {
             self.received_middlebox_ccs = true;
             Ok(MiddleboxCCS::Drop)
@@ -545,6 +978,82 @@ impl SessionCommon {
         rc
     }
 
+    /// Like `decrypt_incoming`, but decrypt `encr` where it lies.
+    pub fn decrypt_in_place<'a>(
+        &mut self,
+        encr: BorrowMessageMut<'a>,
+    ) -> Result<BorrowMessage<'a>, TlsError> {
+        if self
+            .record_layer
+            .wants_close_before_decrypt()
+        {
+            self.send_close_notify();
+        }
+
+        let rc = self.record_layer.decrypt_in_place(encr);
+        if let Err(TlsError::PeerSentOversizedRecord) = rc {
+            self.send_fatal_alert(AlertDescription::RecordOverflow);
+        }
+        rc
+    }
+
+    /// Take the next received record for the state machine, or `None`
+    /// if there are no whole records left.
+    ///
+    /// Once traffic keys are in use, application data is decrypted where
+    /// it lies in the deframer's buffer and copied straight into our
+    /// received plaintext, without going to the state machine at all.
+    pub fn next_incoming(&mut self) -> Result<Option<Incoming>, TlsError> {
+        while self.traffic
+            && self.record_layer.is_decrypting()
+            && self.message_deframer.peek_borrowable() == Some(ContentType::ApplicationData)
+        {
+            if self
+                .record_layer
+                .wants_close_before_decrypt()
+            {
+                self.send_close_notify();
+            }
+
+            let capacity_hint = self.message_deframer.buffered();
+            let encr = self
+                .message_deframer
+                .pop_borrowed()
+                .unwrap();
+            let plain = match self.record_layer.decrypt_in_place(encr) {
+                Ok(plain) => plain,
+                Err(err) => {
+                    if let TlsError::PeerSentOversizedRecord = err {
+                        self.send_fatal_alert(AlertDescription::RecordOverflow);
+                    }
+                    return Err(err);
+                }
+            };
+
+            if plain.typ != ContentType::ApplicationData {
+                return Ok(Some(Incoming::Decrypted(Message {
+                    typ: plain.typ,
+                    version: plain.version,
+                    payload: MessagePayload::new_opaque(plain.payload.to_vec()),
+                })));
+            }
+
+            self.received_plaintext
+                .append_copy(plain.payload, capacity_hint);
+        }
+
+        let next = self
+            .message_deframer
+            .pop()
+            .map(Incoming::Record);
+        if next.is_none() && self.traffic {
+            // Once the handshake is done, don't hold a record buffer
+            // between reads.
+            self.message_deframer.release_buffer();
+        }
+        Ok(next)
+    }
+
     pub fn has_readable_plaintext(&self) -> bool {
         !self.received_plaintext.is_empty()
     }
@@ -556,6 +1065,8 @@ impl SessionCommon {
 
     pub fn process_alert(&mut self, msg: Message) -> Result<(), TlsError> {
         if let MessagePayload::Alert(ref alert) = msg.payload {
+            self.emit(|ev| ev.alert_received(alert.description, alert.level == AlertLevel::Fatal));
+
             // Reject unknown AlertLevels.
             if let AlertLevel::Unknown(_) = alert.level {
                 self.send_fatal_alert(AlertDescription::IllegalParameter);
@@ -580,7 +1091,10 @@ impl SessionCommon {
             }
 
             error!("TLS alert received: {:#?}", msg);
-            Err(TlsError::AlertReceived(alert.description))
+            Err(TlsError::AlertReceived {
+                description: alert.description,
+                fatal: alert.level == AlertLevel::Fatal,
+            })
         } else {
             Err(TlsError::CorruptMessagePayload(ContentType::Alert))
         }
@@ -594,7 +1108,11 @@ impl SessionCommon {
             .fragment(m, &mut plain_messages);
 
         for m in plain_messages {
-            self.send_single_fragment(m.to_borrowed());
+            let mut record = self
+                .sendable_tls
+                .new_chunk(m.payload.length() + cipher::MAX_RECORD_OVERHEAD);
+            self.send_single_fragment(m.to_borrowed(), &mut record);
+            self.queue_tls_bytes(record);
         }
     }
 
@@ -619,20 +1137,29 @@ impl SessionCommon {
             &mut plain_messages,
         );
 
+        // Encrypt every fragment into one buffer, so a write costs
+        // one allocation however many records it makes.
+        let mut records = self
+            .sendable_tls
+            .new_chunk(len + plain_messages.len() * cipher::MAX_RECORD_OVERHEAD);
         for m in plain_messages {
-            self.send_single_fragment(m);
+            self.send_single_fragment(m, &mut records);
         }
+        self.queue_tls_bytes(records);
 
         len
     }
 
-    fn send_single_fragment(&mut self, m: BorrowMessage) {
+    /// Encrypt `m`, appending the record to `records`.
+    fn send_single_fragment(&mut self, m: BorrowMessage, records: &mut Vec<u8>) {
         // Close connection once we start to run out of
         // sequence space.
         if self
             .record_layer
             .wants_close_before_encrypt()
         {
+            // The close_notify must follow what's already encrypted.
+            self.queue_tls_bytes(mem::take(records));
             self.send_close_notify();
         }
 
@@ -642,8 +1169,10 @@ impl SessionCommon {
             return;
         }
 
-        let em = self.record_layer.encrypt_outgoing(m);
-        self.queue_tls_message(em);
+        #[cfg(feature = "metrics")]
+        self.count_encrypted(m.payload.len());
+        self.record_layer
+            .encrypt_outgoing(m, records);
     }
 
     /// Are we done? i.e., have we processed all received messages,
@@ -657,9 +1186,29 @@ impl SessionCommon {
     /// buffering, so `rd` can supply TLS messages in arbitrary-
     /// sized chunks (like a socket or pipe might).
     pub fn read_tls(&mut self, rd: &mut dyn Read) -> io::Result<usize> {
+        self.check_received_plaintext_space()?;
         self.message_deframer.read(rd)
     }
 
+    pub fn read_tls_buf(&mut self, rd: &mut dyn BufRead) -> io::Result<usize> {
+        self.check_received_plaintext_space()?;
+        self.message_deframer.read_buf(rd)
+    }
+
+    /// Refuse to take more TLS data while too much plaintext is
+    /// waiting to be read, so a slow reader can't make us buffer
+    /// without bound.
+    fn check_received_plaintext_space(&self) -> io::Result<()> {
+        if self.received_plaintext.is_full() {
+            Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "received plaintext buffer full",
+            ))
+        } else {
+            Ok(())
+        }
+    }
+
     pub fn write_tls(&mut self, wr: &mut dyn Write) -> io::Result<usize> {
         self.sendable_tls.write_to(wr)
     }
@@ -718,6 +1267,31 @@ impl SessionCommon {
     pub fn start_traffic(&mut self) {
         self.traffic = true;
         self.flush_plaintext();
+
+        let kind = self
+            .handshake_kind
+            .unwrap_or(HandshakeKind::Full);
+        if kind == HandshakeKind::Resumed {
+            self.emit(|ev| ev.resumption_used());
+        } else if self.resumption_offered {
+            self.emit(|ev| ev.resumption_declined());
+        }
+        self.emit(|ev| ev.handshake_finished(kind));
+
+        #[cfg(feature = "metrics")]
+        {
+            if let Some(metrics) = &self.metrics {
+                metrics.handshakes_completed.incr();
+                if kind == HandshakeKind::Resumed {
+                    metrics.resumptions.incr();
+                }
+            }
+        }
+
+        // The queues sized for the handshake's flights of messages
+        // needn't stay that size for the rest of the session.
+        self.message_deframer.frames.shrink_to_fit();
+        self.handshake_joiner.shrink_to_fit();
     }
 
     /// Send any buffered plaintext.  Plaintext is buffered if
@@ -735,15 +1309,24 @@ impl SessionCommon {
 
     // Put m into sendable_tls for writing.
     fn queue_tls_message(&mut self, m: Message) {
-        self.sendable_tls
-            .append(m.get_encoding());
+        let mut record = self.sendable_tls.new_chunk(0);
+        m.encode(&mut record);
+        self.queue_tls_bytes(record);
+    }
+
+    // Put encoded records into sendable_tls for writing.
+    fn queue_tls_bytes(&mut self, records: Vec<u8>) {
+        if !records.is_empty() {
+            self.sendable_tls.append(records);
+        }
     }
 
     /// Send a raw TLS message, fragmenting it if needed.
     pub fn send_msg(&mut self, m: Message, must_encrypt: bool) {
         #[cfg(feature = "quic")]
         {
-            if let Protocol::Quic = self.protocol {
+            // QUIC and DTLS frame handshake messages themselves.
+            if self.protocol != Protocol::Tls13 {
                 if let MessagePayload::Alert(alert) = m.payload {
                     self.quic.alert = Some(alert.description);
                 } else {
@@ -753,7 +1336,7 @@ impl SessionCommon {
                         } else {
                             false
                         },
-                        "QUIC uses TLS for the cryptographic handshake only"
+                        "QUIC and DTLS use TLS for the cryptographic handshake only"
                     );
                     let mut bytes = Vec::new();
                     m.payload.encode(&mut bytes);
@@ -793,6 +1376,24 @@ impl SessionCommon {
         Ok(len)
     }
 
+    /// Like `read`, but return the received plaintext in place,
+    /// without consuming it.
+    pub fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        if self.received_plaintext.is_empty() && self.connection_at_eof() {
+            return Err(io::Error::new(
+                io::ErrorKind::ConnectionAborted,
+                "CloseNotify alert received",
+            ));
+        }
+
+        Ok(self.received_plaintext.chunk())
+    }
+
+    pub fn consume(&mut self, amt: usize) {
+        self.received_plaintext.consume(amt);
+    }
+
+    #[cfg(feature = "tls12")]
     pub fn start_encryption_tls12(&mut self, secrets: &SessionSecrets) {
         let (dec, enc) = cipher::new_tls12(secrets.suite(), secrets);
         self.record_layer
@@ -806,12 +1407,29 @@ impl SessionCommon {
         self.send_warning_alert_no_log(desc);
     }
 
+    /// Answer the peer's attempt to renegotiate, as `policy` says.
+    pub fn refuse_renegotiation(&mut self, policy: RenegotiationPolicy) -> Result<(), TlsError> {
+        match policy {
+            RenegotiationPolicy::Decline => {
+                self.send_warning_alert(AlertDescription::NoRenegotiation);
+                Ok(())
+            }
+            RenegotiationPolicy::Abort => {
+                self.send_fatal_alert(AlertDescription::NoRenegotiation);
+                Err(TlsError::PeerIncompatibleError(
+                    "peer attempted renegotiation".to_string(),
+                ))
+            }
+        }
+    }
+
     pub fn send_fatal_alert(&mut self, desc: AlertDescription) {
         warn!("Sending fatal alert {:?}", desc);
-        debug_assert!(!self.sent_fatal_alert);
+        debug_assert!(self.sent_fatal_alert.is_none());
         let m = Message::build_alert(AlertLevel::Fatal, desc);
         self.send_msg(m, self.record_layer.is_encrypting());
-        self.sent_fatal_alert = true;
+        self.sent_fatal_alert = Some(desc);
+        self.emit(|ev| ev.alert_sent(desc, true));
     }
 
     pub fn send_close_notify(&mut self) {
@@ -822,6 +1440,7 @@ impl SessionCommon {
     fn send_warning_alert_no_log(&mut self, desc: AlertDescription) {
         let m = Message::build_alert(AlertLevel::Warning, desc);
         self.send_msg(m, self.record_layer.is_encrypting());
+        self.emit(|ev| ev.alert_sent(desc, false));
     }
 
     pub fn is_quic(&self) -> bool {
@@ -832,15 +1451,55 @@ impl SessionCommon {
         #[cfg(not(feature = "quic"))]
         false
     }
+
+    pub fn is_dtls(&self) -> bool {
+        #[cfg(feature = "dtls")]
+        {
+            self.protocol == Protocol::Dtls
+        }
+        #[cfg(not(feature = "dtls"))]
+        false
+    }
+
+    /// Translate a TLS `version` to the one we put on the wire.
+    pub fn wire_version(&self, version: ProtocolVersion) -> ProtocolVersion {
+        match version {
+            ProtocolVersion::TLSv1_2 if self.is_dtls() => ProtocolVersion::DTLSv1_2,
+            ProtocolVersion::TLSv1_3 if self.is_dtls() => ProtocolVersion::DTLSv1_3,
+            _ => version,
+        }
+    }
+
+    /// Translate a `version` received from the peer to the TLS version it stands for.
+    ///
+    /// A DTLS session maps TLS versions to `Unknown`, so they are never accepted.
+    pub fn version_from_wire(&self, version: ProtocolVersion) -> ProtocolVersion {
+        if !self.is_dtls() {
+            return version;
+        }
+
+        match version {
+            ProtocolVersion::DTLSv1_2 => ProtocolVersion::TLSv1_2,
+            ProtocolVersion::DTLSv1_3 => ProtocolVersion::TLSv1_3,
+            _ => ProtocolVersion::Unknown(version.get_u16()),
+        }
+    }
 }
 
 #[cfg(feature = "quic")]
 pub(crate) struct Quic {
+    /// QUIC version in use, which determines the key derivation labels
+    pub version: quic::Version,
     /// QUIC transport parameters received from the peer during the handshake
     pub params: Option<Vec<u8>>,
+    /// DTLS connection ID the peer asked us to put in the records we send it
+    pub connection_id: Option<Vec<u8>>,
+    /// SRTP protection profile agreed for DTLS-SRTP
+    pub srtp_profile: Option<SRTPProtectionProfile>,
     pub alert: Option<AlertDescription>,
     pub hs_queue: VecDeque<(bool, Vec<u8>)>,
     pub early_secret: Option<ring::hkdf::Prk>,
+    pub zero_rtt: quic::ZeroRttStatus,
     pub hs_secrets: Option<quic::Secrets>,
     pub traffic_secrets: Option<quic::Secrets>,
     /// Whether keys derived from traffic_secrets have been passed to the QUIC implementation
@@ -851,13 +1510,24 @@ pub(crate) struct Quic {
 impl Quic {
     pub fn new() -> Self {
         Self {
+            version: quic::Version::V1,
             params: None,
+            connection_id: None,
+            srtp_profile: None,
             alert: None,
             hs_queue: VecDeque::new(),
             early_secret: None,
+            zero_rtt: quic::ZeroRttStatus::NotAttempted,
             hs_secrets: None,
             traffic_secrets: None,
             returned_traffic_keys: false,
         }
     }
+
+    /// Note that the server will not process 0-RTT data, so its keys are no longer needed.
+    pub fn reject_0rtt(&mut self) {
+        if self.early_secret.take().is_some() {
+            self.zero_rtt = quic::ZeroRttStatus::Rejected;
+        }
+    }
 }
@@ -1,10 +1,12 @@
 use crate::error::TlsError;
 use crate::key;
 use crate::msgs::enums::{SignatureAlgorithm, SignatureScheme};
+use crate::server_name::DnsName;
+use crate::x509;
 
 use ring::{
     self,
-    signature::{self, EcdsaKeyPair, Ed25519KeyPair, RsaKeyPair},
+    signature::{self, EcdsaKeyPair, Ed25519KeyPair, KeyPair, RsaKeyPair},
 };
 use webpki;
 
@@ -21,6 +23,16 @@ pub trait SigningKey: Send + Sync {
 
     /// What kind of key we have.
     fn algorithm(&self) -> SignatureAlgorithm;
+
+    /// The public half of this key, encoded as in the subjectPublicKey
+    /// field of a certificate: for example, a DER RSAPublicKey for RSA
+    /// keys.
+    ///
+    /// This is used to detect a certificate that doesn't match its key.
+    /// The default returns `None`, which skips that check.
+    fn public_key(&self) -> Option<&[u8]> {
+        None
+    }
 }
 
 /// A thing that can sign a message.
@@ -30,6 +42,19 @@ pub trait Signer: Send + Sync {
 
     /// Reveals which scheme will be used when you call `sign()`.
     fn get_scheme(&self) -> SignatureScheme;
+
+    /// Signs `message`, or defers signing to the application.
+    ///
+    /// Server sessions call this instead of `sign()`.  Return `Ok(None)`
+    /// to pause the handshake, for example when the private key is held
+    /// by a remote key server.  `ServerSession::signature_request` then
+    /// gives the message to sign, and the handshake continues once the
+    /// signature is passed to `ServerSession::supply_signature`.
+    ///
+    /// The default calls `sign()`.
+    fn sign_or_defer(&self, message: &[u8]) -> Result<Option<Vec<u8>>, TlsError> {
+        self.sign(message).map(Some)
+    }
 }
 
 /// A packaged-together certificate chain, matching `SigningKey` and
@@ -50,6 +75,11 @@ pub struct CertifiedKey {
     /// certificate is included on those logs.  This must be
     /// a `SignedCertificateTimestampList` encoding; see RFC6962.
     pub sct_list: Option<Vec<u8>>,
+
+    /// Optional OCSP responses for the intermediate certificates,
+    /// in the same order as `cert[1..]`.  These are only sent
+    /// in TLS1.3.
+    pub intermediate_ocsp: Vec<Option<Vec<u8>>>,
 }
 
 impl CertifiedKey {
@@ -63,6 +93,7 @@ impl CertifiedKey {
             key,
             ocsp: None,
             sct_list: None,
+            intermediate_ocsp: Vec::new(),
         }
     }
 
@@ -86,22 +117,50 @@ impl CertifiedKey {
         mem::replace(&mut self.ocsp, None)
     }
 
+    /// Steal ownership of the intermediate OCSP responses.
+    pub fn take_intermediate_ocsp(&mut self) -> Vec<Option<Vec<u8>>> {
+        mem::take(&mut self.intermediate_ocsp)
+    }
+
     /// Steal ownership of the SCT list.
     pub fn take_sct_list(&mut self) -> Option<Vec<u8>> {
         mem::replace(&mut self.sct_list, None)
     }
 
+    /// Return true if a certificate in the chain is one of, or was
+    /// issued by one of, `acceptable_issuers`.  These are DER-encoded
+    /// X.501 Names, as passed to `ResolvesClientCert::resolve`.
+    ///
+    /// An empty `acceptable_issuers` means the peer will accept any
+    /// issuer, so this returns true.  Certificates which don't parse
+    /// never match.
+    pub fn is_issued_by_any(&self, acceptable_issuers: &[&[u8]]) -> bool {
+        if acceptable_issuers.is_empty() {
+            return true;
+        }
+
+        self.cert.iter().any(|cert| match x509::CertInfo::parse(&cert.0) {
+            Ok(info) => acceptable_issuers
+                .iter()
+                .any(|name| *name == info.issuer || *name == info.subject),
+            Err(_) => false,
+        })
+    }
+
     /// Check the certificate chain for validity:
     /// - it should be non-empty list
     /// - the first certificate should be parsable as a x509v3,
     /// - the first certificate should quote the given server name
     ///   (if provided)
+    /// - the first certificate's public key should match our private
+    ///   key (if the `SigningKey` reveals its public key)
+    /// - each certificate should be issued by the next one
     ///
     /// These checks are not security-sensitive.  They are the
     /// *server* attempting to detect accidental misconfiguration.
     pub fn cross_check_end_entity_cert(
         &self,
-        name: Option<webpki::DNSNameRef>,
+        name: Option<&DnsName>,
     ) -> Result<(), TlsError> {
         // Always reject an empty certificate chain.
         let end_entity_cert = self.end_entity_cert().map_err(|()| {
@@ -125,7 +184,7 @@ impl CertifiedKey {
             // that the certificate is valid for, if the certificate is
             // valid.
             if end_entity_cert
-                .verify_is_valid_for_dns_name(name)
+                .verify_is_valid_for_dns_name(name.as_webpki())
                 .is_err()
             {
                 return Err(TlsError::General(
@@ -136,6 +195,47 @@ impl CertifiedKey {
             }
         }
 
+        self.check_key_matches()?;
+        self.check_chain_order()
+    }
+
+    fn check_key_matches(&self) -> Result<(), TlsError> {
+        let public_key = match self.key.public_key() {
+            Some(public_key) => public_key,
+            None => return Ok(()),
+        };
+
+        let end_entity = x509::CertInfo::parse(&self.cert[0].0).map_err(|_| {
+            TlsError::General("End-entity certificate in certificate chain is malformed".into())
+        })?;
+        if end_entity.public_key.key != public_key {
+            return Err(TlsError::General(
+                "End-entity certificate does not match the private key".into(),
+            ));
+        }
+
+        Ok(())
+    }
+
+    fn check_chain_order(&self) -> Result<(), TlsError> {
+        let infos = self
+            .cert
+            .iter()
+            .map(|cert| x509::CertInfo::parse(&cert.0))
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|_| TlsError::General("Certificate in certificate chain is malformed".into()))?;
+
+        for (i, pair) in infos.windows(2).enumerate() {
+            if pair[0].issuer != pair[1].subject {
+                return Err(TlsError::General(format!(
+                    "Certificate chain is out of order: certificate {} was not issued by \
+                     certificate {}",
+                    i,
+                    i + 1
+                )));
+            }
+        }
+
         Ok(())
     }
 }
@@ -143,9 +243,26 @@ impl CertifiedKey {
 /// Parse `der` as any supported key encoding/type, returning
 /// the first which works.
 pub fn any_supported_type(der: &key::PrivateKey) -> Result<Box<dyn SigningKey>, ()> {
-    if let Ok(rsa) = RsaSigningKey::new(der) {
+    any_supported_type_using(der, SigningRandom::System)
+}
+
+/// Like `any_supported_type`, but RSA-PSS and ECDSA signatures made
+/// with the key are a function of the key and message alone.
+#[cfg(feature = "deterministic")]
+pub(crate) fn any_supported_type_deterministic(
+    der: &key::PrivateKey,
+) -> Result<Box<dyn SigningKey>, ()> {
+    any_supported_type_using(der, SigningRandom::Fixed)
+}
+
+fn any_supported_type_using(
+    der: &key::PrivateKey,
+    random: SigningRandom,
+) -> Result<Box<dyn SigningKey>, ()> {
+    if let Ok(mut rsa) = RsaSigningKey::new(der) {
+        rsa.random = random;
         Ok(Box::new(rsa))
-    } else if let Ok(ecdsa) = any_ecdsa_type(der) {
+    } else if let Ok(ecdsa) = any_ecdsa_type_using(der, random) {
         Ok(ecdsa)
     } else {
         any_eddsa_type(der)
@@ -154,10 +271,18 @@ pub fn any_supported_type(der: &key::PrivateKey) -> Result<Box<dyn SigningKey>,
 
 /// Parse `der` as any ECDSA key type, returning the first which works.
 pub fn any_ecdsa_type(der: &key::PrivateKey) -> Result<Box<dyn SigningKey>, ()> {
+    any_ecdsa_type_using(der, SigningRandom::System)
+}
+
+fn any_ecdsa_type_using(
+    der: &key::PrivateKey,
+    random: SigningRandom,
+) -> Result<Box<dyn SigningKey>, ()> {
     if let Ok(ecdsa_p256) = ECDSASigningKey::new(
         der,
         SignatureScheme::ECDSA_NISTP256_SHA256,
         &signature::ECDSA_P256_SHA256_ASN1_SIGNING,
+        random,
     ) {
         return Ok(Box::new(ecdsa_p256));
     }
@@ -166,6 +291,7 @@ pub fn any_ecdsa_type(der: &key::PrivateKey) -> Result<Box<dyn SigningKey>, ()>
         der,
         SignatureScheme::ECDSA_NISTP384_SHA384,
         &signature::ECDSA_P384_SHA384_ASN1_SIGNING,
+        random,
     ) {
         return Ok(Box::new(ecdsa_p384));
     }
@@ -175,7 +301,7 @@ pub fn any_ecdsa_type(der: &key::PrivateKey) -> Result<Box<dyn SigningKey>, ()>
 
 /// Parse `der` as any EdDSA key type, returning the first which works.
 pub fn any_eddsa_type(der: &key::PrivateKey) -> Result<Box<dyn SigningKey>, ()> {
-    if let Ok(ed25519) = Ed25519SigningKey::new(der, SignatureScheme::ED25519) {
+    if let Ok(ed25519) = Ed25519SigningKey::new(der) {
         return Ok(Box::new(ed25519));
     }
 
@@ -184,9 +310,35 @@ pub fn any_eddsa_type(der: &key::PrivateKey) -> Result<Box<dyn SigningKey>, ()>
     Err(())
 }
 
+/// Where signers get the random material that RSA-PSS and
+/// ECDSA signatures need.
+#[derive(Clone, Copy)]
+enum SigningRandom {
+    /// The operating system RNG.
+    System,
+
+    /// A constant.  *ring* mixes the message, and a secret it
+    /// draws when the key is loaded, into ECDSA nonces, so these
+    /// stay distinct per message.
+    #[cfg(feature = "deterministic")]
+    Fixed,
+}
+
+impl SigningRandom {
+    fn with<T>(self, f: impl FnOnce(&dyn ring::rand::SecureRandom) -> T) -> T {
+        match self {
+            SigningRandom::System => f(&ring::rand::SystemRandom::new()),
+            #[cfg(feature = "deterministic")]
+            SigningRandom::Fixed => f(&ring::test::rand::FixedByteRandom { byte: 0x5a }),
+        }
+    }
+}
+
 /// A `SigningKey` for RSA-PKCS1 or RSA-PSS
 pub struct RsaSigningKey {
     key: Arc<RsaKeyPair>,
+    schemes: &'static [SignatureScheme],
+    random: SigningRandom,
 }
 
 static ALL_RSA_SCHEMES: &[SignatureScheme] = &[
@@ -198,28 +350,64 @@ static ALL_RSA_SCHEMES: &[SignatureScheme] = &[
     SignatureScheme::RSA_PKCS1_SHA256,
 ];
 
+static ALL_RSA_PSS_PSS_SCHEMES: &[SignatureScheme] = &[
+    SignatureScheme::RSA_PSS_PSS_SHA512,
+    SignatureScheme::RSA_PSS_PSS_SHA384,
+    SignatureScheme::RSA_PSS_PSS_SHA256,
+];
+
 impl RsaSigningKey {
     /// Make a new `RSASigningKey` from a DER encoding, in either
     /// PKCS#1 or PKCS#8 format.
+    ///
+    /// PKCS#8 keys with the id-RSASSA-PSS algorithm are accepted too.
+    /// They are only used for the `RSA_PSS_PSS_*` schemes, restricted
+    /// to the hash function in the key's parameters, if any.
     pub fn new(der: &key::PrivateKey) -> Result<RsaSigningKey, ()> {
+        if let Ok(Some(pss)) = x509::RsaPssKey::from_pkcs8(&der.0) {
+            let schemes = match pss.hash {
+                None => ALL_RSA_PSS_PSS_SCHEMES,
+                Some(x509::OID_SHA256) => &[SignatureScheme::RSA_PSS_PSS_SHA256],
+                Some(x509::OID_SHA384) => &[SignatureScheme::RSA_PSS_PSS_SHA384],
+                Some(x509::OID_SHA512) => &[SignatureScheme::RSA_PSS_PSS_SHA512],
+                Some(_) => return Err(()),
+            };
+
+            return RsaKeyPair::from_der(pss.key)
+                .map(|s| RsaSigningKey {
+                    key: Arc::new(s),
+                    schemes,
+                    random: SigningRandom::System,
+                })
+                .map_err(|_| ());
+        }
+
         RsaKeyPair::from_der(&der.0)
             .or_else(|_| RsaKeyPair::from_pkcs8(&der.0))
-            .map(|s| RsaSigningKey { key: Arc::new(s) })
+            .map(|s| RsaSigningKey {
+                key: Arc::new(s),
+                schemes: ALL_RSA_SCHEMES,
+                random: SigningRandom::System,
+            })
             .map_err(|_| ())
     }
 }
 
 impl SigningKey for RsaSigningKey {
     fn choose_scheme(&self, offered: &[SignatureScheme]) -> Option<Box<dyn Signer>> {
-        ALL_RSA_SCHEMES
+        self.schemes
             .iter()
             .find(|scheme| offered.contains(scheme))
-            .map(|scheme| RSASigner::new(self.key.clone(), *scheme))
+            .map(|scheme| RSASigner::new(self.key.clone(), *scheme, self.random))
     }
 
     fn algorithm(&self) -> SignatureAlgorithm {
         SignatureAlgorithm::RSA
     }
+
+    fn public_key(&self) -> Option<&[u8]> {
+        Some(self.key.public_key().as_ref())
+    }
 }
 
 #[doc(hidden)]
@@ -230,10 +418,15 @@ struct RSASigner {
     key: Arc<RsaKeyPair>,
     scheme: SignatureScheme,
     encoding: &'static dyn signature::RsaEncoding,
+    random: SigningRandom,
 }
 
 impl RSASigner {
-    fn new(key: Arc<RsaKeyPair>, scheme: SignatureScheme) -> Box<dyn Signer> {
+    fn new(
+        key: Arc<RsaKeyPair>,
+        scheme: SignatureScheme,
+        random: SigningRandom,
+    ) -> Box<dyn Signer> {
         let encoding: &dyn signature::RsaEncoding = match scheme {
             SignatureScheme::RSA_PKCS1_SHA256 => &signature::RSA_PKCS1_SHA256,
             SignatureScheme::RSA_PKCS1_SHA384 => &signature::RSA_PKCS1_SHA384,
@@ -241,6 +434,9 @@ impl RSASigner {
             SignatureScheme::RSA_PSS_SHA256 => &signature::RSA_PSS_SHA256,
             SignatureScheme::RSA_PSS_SHA384 => &signature::RSA_PSS_SHA384,
             SignatureScheme::RSA_PSS_SHA512 => &signature::RSA_PSS_SHA512,
+            SignatureScheme::RSA_PSS_PSS_SHA256 => &signature::RSA_PSS_SHA256,
+            SignatureScheme::RSA_PSS_PSS_SHA384 => &signature::RSA_PSS_SHA384,
+            SignatureScheme::RSA_PSS_PSS_SHA512 => &signature::RSA_PSS_SHA512,
             _ => unreachable!(),
         };
 
@@ -248,6 +444,7 @@ impl RSASigner {
             key,
             scheme,
             encoding,
+            random,
         })
     }
 }
@@ -256,9 +453,11 @@ impl Signer for RSASigner {
     fn sign(&self, message: &[u8]) -> Result<Vec<u8>, TlsError> {
         let mut sig = vec![0; self.key.public_modulus_len()];
 
-        let rng = ring::rand::SystemRandom::new();
-        self.key
-            .sign(self.encoding, &rng, message, &mut sig)
+        self.random
+            .with(|rng| {
+                self.key
+                    .sign(self.encoding, rng, message, &mut sig)
+            })
             .map(|_| sig)
             .map_err(|_| TlsError::General("signing failed".to_string()))
     }
@@ -282,20 +481,27 @@ impl Signer for RSASigner {
 struct ECDSASigningKey {
     key: Arc<EcdsaKeyPair>,
     scheme: SignatureScheme,
+    random: SigningRandom,
 }
 
 impl ECDSASigningKey {
-    /// Make a new `ECDSASigningKey` from a DER encoding in PKCS#8 format,
-    /// expecting a key usable with precisely the given signature scheme.
+    /// Make a new `ECDSASigningKey` from a DER encoding in PKCS#8 or SEC1
+    /// format, expecting a key usable with precisely the given signature scheme.
     pub fn new(
         der: &key::PrivateKey,
         scheme: SignatureScheme,
         sigalg: &'static signature::EcdsaSigningAlgorithm,
+        random: SigningRandom,
     ) -> Result<ECDSASigningKey, ()> {
         EcdsaKeyPair::from_pkcs8(sigalg, &der.0)
+            .or_else(|_| {
+                let pkcs8 = x509::sec1_to_pkcs8(&der.0).map_err(|_| ())?;
+                EcdsaKeyPair::from_pkcs8(sigalg, &pkcs8).map_err(|_| ())
+            })
             .map(|kp| ECDSASigningKey {
                 key: Arc::new(kp),
                 scheme,
+                random,
             })
             .map_err(|_| ())
     }
@@ -307,6 +513,7 @@ impl SigningKey for ECDSASigningKey {
             Some(Box::new(ECDSASigner {
                 key: self.key.clone(),
                 scheme: self.scheme,
+                random: self.random,
             }))
         } else {
             None
@@ -317,18 +524,22 @@ impl SigningKey for ECDSASigningKey {
         use crate::msgs::handshake::DecomposedSignatureScheme;
         self.scheme.sign()
     }
+
+    fn public_key(&self) -> Option<&[u8]> {
+        Some(self.key.public_key().as_ref())
+    }
 }
 
 struct ECDSASigner {
     key: Arc<EcdsaKeyPair>,
     scheme: SignatureScheme,
+    random: SigningRandom,
 }
 
 impl Signer for ECDSASigner {
     fn sign(&self, message: &[u8]) -> Result<Vec<u8>, TlsError> {
-        let rng = ring::rand::SystemRandom::new();
-        self.key
-            .sign(&rng, message)
+        self.random
+            .with(|rng| self.key.sign(rng, message))
             .map_err(|_| TlsError::General("signing failed".into()))
             .map(|sig| sig.as_ref().into())
     }
@@ -338,30 +549,21 @@ impl Signer for ECDSASigner {
     }
 }
 
-/// A SigningKey that uses exactly one TLS-level SignatureScheme
-/// and one ring-level signature::SigningAlgorithm.
-///
-/// Compare this to RSASigningKey, which for a particular key is
-/// willing to sign with several algorithms.  This is quite poor
-/// cryptography practice, but is necessary because a given RSA key
-/// is expected to work in TLS1.2 (PKCS#1 signatures) and TLS1.3
-/// (PSS signatures) -- nobody is willing to obtain certificates for
-/// different protocol versions.
-///
-/// Currently this is only implemented for Ed25519 keys.
-struct Ed25519SigningKey {
+/// A `SigningKey` for Ed25519 (RFC8032), usable in both TLS1.2
+/// (RFC8422) and TLS1.3.
+pub struct Ed25519SigningKey {
     key: Arc<Ed25519KeyPair>,
     scheme: SignatureScheme,
 }
 
 impl Ed25519SigningKey {
-    /// Make a new `Ed25519SigningKey` from a DER encoding in PKCS#8 format,
-    /// expecting a key usable with precisely the given signature scheme.
-    pub fn new(der: &key::PrivateKey, scheme: SignatureScheme) -> Result<Ed25519SigningKey, ()> {
+    /// Make a new `Ed25519SigningKey` from a DER encoding in PKCS#8
+    /// format, either v1 (RFC5208) or v2 (RFC5958).
+    pub fn new(der: &key::PrivateKey) -> Result<Ed25519SigningKey, ()> {
         Ed25519KeyPair::from_pkcs8_maybe_unchecked(&der.0)
             .map(|kp| Ed25519SigningKey {
                 key: Arc::new(kp),
-                scheme,
+                scheme: SignatureScheme::ED25519,
             })
             .map_err(|_| ())
     }
@@ -383,6 +585,10 @@ impl SigningKey for Ed25519SigningKey {
         use crate::msgs::handshake::DecomposedSignatureScheme;
         self.scheme.sign()
     }
+
+    fn public_key(&self) -> Option<&[u8]> {
+        Some(self.key.public_key().as_ref())
+    }
 }
 
 struct Ed25519Signer {
@@ -409,6 +615,9 @@ pub fn supported_sign_tls13() -> &'static [SignatureScheme] {
         SignatureScheme::RSA_PSS_SHA512,
         SignatureScheme::RSA_PSS_SHA384,
         SignatureScheme::RSA_PSS_SHA256,
+        SignatureScheme::RSA_PSS_PSS_SHA512,
+        SignatureScheme::RSA_PSS_PSS_SHA384,
+        SignatureScheme::RSA_PSS_PSS_SHA256,
         SignatureScheme::ED25519,
     ]
 }
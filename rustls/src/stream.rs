@@ -1,10 +1,15 @@
 use crate::session::Session;
-use std::io::{IoSlice, Read, Result, Write};
+use std::cmp;
+use std::io::{self, BufRead, IoSlice, Read, Result, Write};
+use std::sync::{Arc, Mutex, MutexGuard};
 
 /// This type implements `io::Read` and `io::Write`, encapsulating
 /// a Session `S` and an underlying transport `T`, such as a socket.
 ///
 /// This allows you to use a rustls Session like a normal stream.
+/// It also implements `io::BufRead` for `ClientSession` and
+/// `ServerSession`, reading straight out of the session's buffer of
+/// received plaintext.
 #[derive(Debug)]
 pub struct Stream<'a, S: 'a + Session + ?Sized, T: 'a + Read + Write + ?Sized> {
     /// Our session
@@ -38,14 +43,10 @@ where
 
         Ok(())
     }
-}
 
-impl<'a, S, T> Read for Stream<'a, S, T>
-where
-    S: 'a + Session,
-    T: 'a + Read + Write,
-{
-    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+    /// Do any prior IO, then read from the transport until there's
+    /// plaintext to read or it's at EOF.
+    fn complete_read_io(&mut self) -> Result<()> {
         self.complete_prior_io()?;
 
         // We call complete_io() in a loop since a single call may read only
@@ -56,10 +57,52 @@ where
         // read from the underlying transport.
         while self.sess.wants_read() && self.sess.complete_io(self.sock)?.0 != 0 {}
 
+        Ok(())
+    }
+}
+
+impl<'a, S, T> Stream<'a, S, T>
+where
+    S: 'a + Session + BufRead,
+    T: 'a + Read + Write,
+{
+    /// Read plaintext into `buf` like `read`, but leave it to be
+    /// read again.
+    ///
+    /// Like `TcpStream::peek`, this blocks until some plaintext has
+    /// been received.  It returns at most the contents of one TLS
+    /// record, so it may return less than is available.
+    pub fn peek(&mut self, buf: &mut [u8]) -> Result<usize> {
+        Ok(copy_prefix(self.fill_buf()?, buf))
+    }
+}
+
+impl<'a, S, T> Read for Stream<'a, S, T>
+where
+    S: 'a + Session,
+    T: 'a + Read + Write,
+{
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        self.complete_read_io()?;
         self.sess.read(buf)
     }
 }
 
+impl<'a, S, T> BufRead for Stream<'a, S, T>
+where
+    S: 'a + Session + BufRead,
+    T: 'a + Read + Write,
+{
+    fn fill_buf(&mut self) -> Result<&[u8]> {
+        self.complete_read_io()?;
+        self.sess.fill_buf()
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.sess.consume(amt)
+    }
+}
+
 impl<'a, S, T> Write for Stream<'a, S, T>
 where
     S: 'a + Session,
@@ -154,6 +197,41 @@ where
     }
 }
 
+impl<S, T> StreamOwned<S, T>
+where
+    S: Session,
+    T: Read + Write,
+    for<'b> &'b T: Read + Write,
+{
+    /// Split this stream into halves which can be used from different
+    /// threads, like `split` does.
+    ///
+    /// Both halves use the socket, which must be usable by shared
+    /// reference -- as `TcpStream` is.
+    #[allow(clippy::type_complexity)]
+    pub fn split(
+        self,
+    ) -> (
+        ReadHalf<S, SharedSocket<T>, SharedSocket<T>>,
+        WriteHalf<S, SharedSocket<T>>,
+    ) {
+        let sock = Arc::new(self.sock);
+        split(self.sess, SharedSocket(sock.clone()), SharedSocket(sock))
+    }
+}
+
+impl<S, T> StreamOwned<S, T>
+where
+    S: Session + BufRead,
+    T: Read + Write,
+{
+    /// Read plaintext into `buf` like `read`, but leave it to be
+    /// read again.  See `Stream::peek`.
+    pub fn peek(&mut self, buf: &mut [u8]) -> Result<usize> {
+        Ok(copy_prefix(self.fill_buf()?, buf))
+    }
+}
+
 impl<S, T> Read for StreamOwned<S, T>
 where
     S: Session,
@@ -164,6 +242,28 @@ where
     }
 }
 
+impl<S, T> BufRead for StreamOwned<S, T>
+where
+    S: Session + BufRead,
+    T: Read + Write,
+{
+    fn fill_buf(&mut self) -> Result<&[u8]> {
+        self.as_stream().complete_read_io()?;
+        self.sess.fill_buf()
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.sess.consume(amt)
+    }
+}
+
+/// Copy as much of `from` as fits into `to`, returning how much that was.
+fn copy_prefix(from: &[u8], to: &mut [u8]) -> usize {
+    let len = cmp::min(from.len(), to.len());
+    to[..len].copy_from_slice(&from[..len]);
+    len
+}
+
 impl<S, T> Write for StreamOwned<S, T>
 where
     S: Session,
@@ -178,9 +278,217 @@ where
     }
 }
 
+/// A socket shared by the halves made by `StreamOwned::split`.
+#[derive(Debug)]
+pub struct SharedSocket<T>(Arc<T>);
+
+impl<T> SharedSocket<T> {
+    /// Get a reference to the underlying socket
+    pub fn get_ref(&self) -> &T {
+        &self.0
+    }
+}
+
+impl<T> Read for SharedSocket<T>
+where
+    for<'b> &'b T: Read,
+{
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        (&*self.0).read(buf)
+    }
+}
+
+impl<T> Write for SharedSocket<T>
+where
+    for<'b> &'b T: Write,
+{
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        (&*self.0).write(buf)
+    }
+
+    fn write_vectored(&mut self, bufs: &[IoSlice<'_>]) -> Result<usize> {
+        (&*self.0).write_vectored(bufs)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        (&*self.0).flush()
+    }
+}
+
+/// The state shared by a `ReadHalf` and a `WriteHalf`.
+#[derive(Debug)]
+struct Shared<S, W> {
+    sess: Mutex<S>,
+    writer: Mutex<W>,
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> Result<MutexGuard<'_, T>> {
+    mutex
+        .lock()
+        .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "other half panicked"))
+}
+
+impl<S, W> Shared<S, W>
+where
+    S: Session,
+    W: Write,
+{
+    /// Write all the TLS data the session has queued.
+    ///
+    /// The writer lock is held throughout, so records go out in the
+    /// order the session made them.  The session lock is only held while
+    /// taking them, so the other half isn't blocked by a slow transport.
+    fn write_pending(&self) -> Result<()> {
+        let mut writer = lock(&self.writer)?;
+        let mut pending = Vec::new();
+        {
+            let mut sess = lock(&self.sess)?;
+            while sess.wants_write() {
+                sess.write_tls(&mut pending)?;
+            }
+        }
+        writer.write_all(&pending)
+    }
+}
+
+/// Split the Session `sess` into halves which read from `reader`
+/// and write to `writer`, and can be used from different threads.
+///
+/// This lets a full-duplex user (a proxy, say) block reading in one
+/// thread while writing in another.  The halves share `sess` behind a
+/// lock, which is only held while processing TLS records and never
+/// during IO.  `reader` and `writer` are typically two handles for the
+/// same socket, like those made by `TcpStream::try_clone`.
+///
+/// Any handshake still to do is driven by reading: writes before it is
+/// complete are buffered, and sent once the `ReadHalf` has read enough.
+pub fn split<S, R, W>(sess: S, reader: R, writer: W) -> (ReadHalf<S, R, W>, WriteHalf<S, W>)
+where
+    S: Session,
+    R: Read,
+    W: Write,
+{
+    let shared = Arc::new(Shared {
+        sess: Mutex::new(sess),
+        writer: Mutex::new(writer),
+    });
+
+    (
+        ReadHalf {
+            shared: shared.clone(),
+            reader,
+            eof: false,
+        },
+        WriteHalf { shared },
+    )
+}
+
+/// The half of a split Session which implements `io::Read`.
+///
+/// Made by `split`.  Reading may also write to the transport: for
+/// example, handshake messages and TLS1.3 key updates.
+#[derive(Debug)]
+pub struct ReadHalf<S, R, W> {
+    shared: Arc<Shared<S, W>>,
+    reader: R,
+    eof: bool,
+}
+
+impl<S, R, W> Read for ReadHalf<S, R, W>
+where
+    S: Session,
+    R: Read,
+    W: Write,
+{
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        loop {
+            let len = lock(&self.shared.sess)?.read(buf)?;
+            if len > 0 || buf.is_empty() || self.eof {
+                return Ok(len);
+            }
+
+            self.shared.write_pending()?;
+
+            let mut tls = [0u8; 4096];
+            let tls_len = self.reader.read(&mut tls)?;
+            if tls_len == 0 {
+                self.eof = true;
+                continue;
+            }
+
+            let result = {
+                let mut sess = lock(&self.shared.sess)?;
+                let mut rd = &tls[..tls_len];
+                let mut result = Ok(());
+                while !rd.is_empty() && result.is_ok() {
+                    sess.read_tls(&mut rd)?;
+                    result = sess.process_new_packets();
+                }
+                result
+            };
+
+            // Send any response, including an alert describing an error --
+            // but don't let a failure here mask that error.
+            let written = self.shared.write_pending();
+            result.map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+            written?;
+        }
+    }
+}
+
+/// The half of a split Session which implements `io::Write`.
+///
+/// Made by `split`.
+#[derive(Debug)]
+pub struct WriteHalf<S, W> {
+    shared: Arc<Shared<S, W>>,
+}
+
+impl<S, W> WriteHalf<S, W>
+where
+    S: Session,
+    W: Write,
+{
+    /// Send a close_notify alert, telling the peer nothing more will be
+    /// written.  The `ReadHalf` can still be used.
+    pub fn send_close_notify(&mut self) -> Result<()> {
+        lock(&self.shared.sess)?.send_close_notify();
+        self.shared.write_pending()
+    }
+}
+
+impl<S, W> Write for WriteHalf<S, W>
+where
+    S: Session,
+    W: Write,
+{
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        let len = lock(&self.shared.sess)?.write(buf)?;
+
+        // Try to write the underlying transport here, but don't let
+        // any errors mask the fact we've consumed `len` bytes.
+        // Callers will learn of permanent errors on the next call.
+        let _ = self.shared.write_pending();
+
+        Ok(len)
+    }
+
+    fn write_vectored(&mut self, bufs: &[IoSlice<'_>]) -> Result<usize> {
+        let len = lock(&self.shared.sess)?.write_vectored(bufs)?;
+        let _ = self.shared.write_pending();
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        lock(&self.shared.sess)?.flush()?;
+        self.shared.write_pending()?;
+        lock(&self.shared.writer)?.flush()
+    }
+}
+
 #[cfg(test)]
 mod tests {
-    use super::{Stream, StreamOwned};
+    use super::{ReadHalf, Stream, StreamOwned, WriteHalf};
     use crate::client::ClientSession;
     use crate::server::ServerSession;
     use crate::session::Session;
@@ -200,4 +508,11 @@ mod tests {
     fn streamowned_can_be_created_for_server_and_tcpstream() {
         type _Test = StreamOwned<ServerSession, TcpStream>;
     }
+
+    #[test]
+    fn halves_can_be_sent_to_other_threads() {
+        fn is_send<T: Send>() {}
+        is_send::<ReadHalf<ClientSession, TcpStream, TcpStream>>();
+        is_send::<WriteHalf<ServerSession, TcpStream>>();
+    }
 }
@@ -1,3 +1,4 @@
+#[cfg(feature = "tls12")]
 use crate::cipher;
 use crate::msgs::enums::{CipherSuite, HashAlgorithm, SignatureAlgorithm, SignatureScheme};
 use crate::msgs::enums::ProtocolVersion;
@@ -5,7 +6,10 @@ use crate::msgs::handshake::DecomposedSignatureScheme;
 use crate::msgs::handshake::KeyExchangeAlgorithm;
 
 use ring;
+#[cfg(feature = "secret_extraction")]
+use std::convert::TryInto;
 use std::fmt;
+use std::ptr;
 
 /// Bulk symmetric encryption scheme used by a cipher suite.
 #[allow(non_camel_case_types)]
@@ -60,8 +64,11 @@ pub struct SupportedCipherSuite {
     pub explicit_nonce_len: usize,
 
     pub(crate) hkdf_algorithm: ring::hkdf::Algorithm,
+    #[cfg(feature = "tls13")]
     pub(crate) aead_algorithm: &'static ring::aead::Algorithm,
+    #[cfg(feature = "tls12")]
     pub(crate) build_tls12_encrypter: Option<cipher::BuildTLS12Encrypter>,
+    #[cfg(feature = "tls12")]
     pub(crate) build_tls12_decrypter: Option<cipher::BuildTLS12Decrypter>,
 }
 
@@ -122,7 +129,9 @@ impl SupportedCipherSuite {
     /// Return true if this suite is usable for TLS `version`.
     pub fn usable_for_version(&self, version: ProtocolVersion) -> bool {
         match version {
-            ProtocolVersion::TLSv1_3 => self.build_tls12_encrypter.is_none(),
+            #[cfg(feature = "tls13")]
+            ProtocolVersion::TLSv1_3 => self.sign.is_none(),
+            #[cfg(feature = "tls12")]
             ProtocolVersion::TLSv1_2 => self.build_tls12_encrypter.is_some(),
             _ => false,
         }
@@ -160,6 +169,7 @@ impl SupportedCipherSuite {
     }
 }
 
+#[cfg(feature = "tls12")]
 static TLS12_ECDSA_SCHEMES: &[SignatureScheme] = &[
     SignatureScheme::ED25519,
     SignatureScheme::ECDSA_NISTP521_SHA512,
@@ -167,7 +177,11 @@ static TLS12_ECDSA_SCHEMES: &[SignatureScheme] = &[
     SignatureScheme::ECDSA_NISTP256_SHA256,
 ];
 
+#[cfg(feature = "tls12")]
 static TLS12_RSA_SCHEMES: &[SignatureScheme] = &[
+    SignatureScheme::RSA_PSS_PSS_SHA512,
+    SignatureScheme::RSA_PSS_PSS_SHA384,
+    SignatureScheme::RSA_PSS_PSS_SHA256,
     SignatureScheme::RSA_PSS_SHA512,
     SignatureScheme::RSA_PSS_SHA384,
     SignatureScheme::RSA_PSS_SHA256,
@@ -177,6 +191,7 @@ static TLS12_RSA_SCHEMES: &[SignatureScheme] = &[
 ];
 
 /// The TLS1.2 ciphersuite TLS_ECDHE_ECDSA_WITH_CHACHA20_POLY1305_SHA256.
+#[cfg(feature = "tls12")]
 pub static TLS_ECDHE_ECDSA_WITH_CHACHA20_POLY1305_SHA256: SupportedCipherSuite =
     SupportedCipherSuite {
         suite: CipherSuite::TLS_ECDHE_ECDSA_WITH_CHACHA20_POLY1305_SHA256,
@@ -188,12 +203,14 @@ pub static TLS_ECDHE_ECDSA_WITH_CHACHA20_POLY1305_SHA256: SupportedCipherSuite =
         fixed_iv_len: 12,
         explicit_nonce_len: 0,
         hkdf_algorithm: ring::hkdf::HKDF_SHA256,
+        #[cfg(feature = "tls13")]
         aead_algorithm: &ring::aead::CHACHA20_POLY1305,
         build_tls12_encrypter: Some(cipher::build_tls12_chacha_encrypter),
         build_tls12_decrypter: Some(cipher::build_tls12_chacha_decrypter),
     };
 
 /// The TLS1.2 ciphersuite TLS_ECDHE_RSA_WITH_CHACHA20_POLY1305_SHA256
+#[cfg(feature = "tls12")]
 pub static TLS_ECDHE_RSA_WITH_CHACHA20_POLY1305_SHA256: SupportedCipherSuite =
     SupportedCipherSuite {
         suite: CipherSuite::TLS_ECDHE_RSA_WITH_CHACHA20_POLY1305_SHA256,
@@ -205,12 +222,14 @@ pub static TLS_ECDHE_RSA_WITH_CHACHA20_POLY1305_SHA256: SupportedCipherSuite =
         fixed_iv_len: 12,
         explicit_nonce_len: 0,
         hkdf_algorithm: ring::hkdf::HKDF_SHA256,
+        #[cfg(feature = "tls13")]
         aead_algorithm: &ring::aead::CHACHA20_POLY1305,
         build_tls12_encrypter: Some(cipher::build_tls12_chacha_encrypter),
         build_tls12_decrypter: Some(cipher::build_tls12_chacha_decrypter),
     };
 
 /// The TLS1.2 ciphersuite TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256
+#[cfg(feature = "tls12")]
 pub static TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256: SupportedCipherSuite = SupportedCipherSuite {
     suite: CipherSuite::TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256,
     kx: KeyExchangeAlgorithm::ECDHE,
@@ -221,12 +240,14 @@ pub static TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256: SupportedCipherSuite = Support
     fixed_iv_len: 4,
     explicit_nonce_len: 8,
     hkdf_algorithm: ring::hkdf::HKDF_SHA256,
+    #[cfg(feature = "tls13")]
     aead_algorithm: &ring::aead::AES_128_GCM,
     build_tls12_encrypter: Some(cipher::build_tls12_gcm_128_encrypter),
     build_tls12_decrypter: Some(cipher::build_tls12_gcm_128_decrypter),
 };
 
 /// The TLS1.2 ciphersuite TLS_ECDHE_RSA_WITH_AES_256_GCM_SHA384
+#[cfg(feature = "tls12")]
 pub static TLS_ECDHE_RSA_WITH_AES_256_GCM_SHA384: SupportedCipherSuite = SupportedCipherSuite {
     suite: CipherSuite::TLS_ECDHE_RSA_WITH_AES_256_GCM_SHA384,
     kx: KeyExchangeAlgorithm::ECDHE,
@@ -237,12 +258,14 @@ pub static TLS_ECDHE_RSA_WITH_AES_256_GCM_SHA384: SupportedCipherSuite = Support
     fixed_iv_len: 4,
     explicit_nonce_len: 8,
     hkdf_algorithm: ring::hkdf::HKDF_SHA384,
+    #[cfg(feature = "tls13")]
     aead_algorithm: &ring::aead::AES_256_GCM,
     build_tls12_encrypter: Some(cipher::build_tls12_gcm_256_encrypter),
     build_tls12_decrypter: Some(cipher::build_tls12_gcm_256_decrypter),
 };
 
 /// The TLS1.2 ciphersuite TLS_ECDHE_ECDSA_WITH_AES_128_GCM_SHA256
+#[cfg(feature = "tls12")]
 pub static TLS_ECDHE_ECDSA_WITH_AES_128_GCM_SHA256: SupportedCipherSuite = SupportedCipherSuite {
     suite: CipherSuite::TLS_ECDHE_ECDSA_WITH_AES_128_GCM_SHA256,
     kx: KeyExchangeAlgorithm::ECDHE,
@@ -253,12 +276,14 @@ pub static TLS_ECDHE_ECDSA_WITH_AES_128_GCM_SHA256: SupportedCipherSuite = Suppo
     fixed_iv_len: 4,
     explicit_nonce_len: 8,
     hkdf_algorithm: ring::hkdf::HKDF_SHA256,
+    #[cfg(feature = "tls13")]
     aead_algorithm: &ring::aead::AES_128_GCM,
     build_tls12_encrypter: Some(cipher::build_tls12_gcm_128_encrypter),
     build_tls12_decrypter: Some(cipher::build_tls12_gcm_128_decrypter),
 };
 
 /// The TLS1.2 ciphersuite TLS_ECDHE_ECDSA_WITH_AES_256_GCM_SHA384
+#[cfg(feature = "tls12")]
 pub static TLS_ECDHE_ECDSA_WITH_AES_256_GCM_SHA384: SupportedCipherSuite = SupportedCipherSuite {
     suite: CipherSuite::TLS_ECDHE_ECDSA_WITH_AES_256_GCM_SHA384,
     kx: KeyExchangeAlgorithm::ECDHE,
@@ -269,12 +294,14 @@ pub static TLS_ECDHE_ECDSA_WITH_AES_256_GCM_SHA384: SupportedCipherSuite = Suppo
     fixed_iv_len: 4,
     explicit_nonce_len: 8,
     hkdf_algorithm: ring::hkdf::HKDF_SHA384,
+    #[cfg(feature = "tls13")]
     aead_algorithm: &ring::aead::AES_256_GCM,
     build_tls12_encrypter: Some(cipher::build_tls12_gcm_256_encrypter),
     build_tls12_decrypter: Some(cipher::build_tls12_gcm_256_decrypter),
 };
 
 /// The TLS1.3 ciphersuite TLS_CHACHA20_POLY1305_SHA256
+#[cfg(feature = "tls13")]
 pub static TLS13_CHACHA20_POLY1305_SHA256: SupportedCipherSuite = SupportedCipherSuite {
     suite: CipherSuite::TLS13_CHACHA20_POLY1305_SHA256,
     kx: KeyExchangeAlgorithm::BulkOnly,
@@ -285,12 +312,16 @@ pub static TLS13_CHACHA20_POLY1305_SHA256: SupportedCipherSuite = SupportedCiphe
     fixed_iv_len: 12,
     explicit_nonce_len: 0,
     hkdf_algorithm: ring::hkdf::HKDF_SHA256,
+    #[cfg(feature = "tls13")]
     aead_algorithm: &ring::aead::CHACHA20_POLY1305,
+    #[cfg(feature = "tls12")]
     build_tls12_encrypter: None,
+    #[cfg(feature = "tls12")]
     build_tls12_decrypter: None,
 };
 
 /// The TLS1.3 ciphersuite TLS_AES_256_GCM_SHA384
+#[cfg(feature = "tls13")]
 pub static TLS13_AES_256_GCM_SHA384: SupportedCipherSuite = SupportedCipherSuite {
     suite: CipherSuite::TLS13_AES_256_GCM_SHA384,
     kx: KeyExchangeAlgorithm::BulkOnly,
@@ -301,12 +332,16 @@ pub static TLS13_AES_256_GCM_SHA384: SupportedCipherSuite = SupportedCipherSuite
     fixed_iv_len: 12,
     explicit_nonce_len: 0,
     hkdf_algorithm: ring::hkdf::HKDF_SHA384,
+    #[cfg(feature = "tls13")]
     aead_algorithm: &ring::aead::AES_256_GCM,
+    #[cfg(feature = "tls12")]
     build_tls12_encrypter: None,
+    #[cfg(feature = "tls12")]
     build_tls12_decrypter: None,
 };
 
 /// The TLS1.3 ciphersuite TLS_AES_128_GCM_SHA256
+#[cfg(feature = "tls13")]
 pub static TLS13_AES_128_GCM_SHA256: SupportedCipherSuite = SupportedCipherSuite {
     suite: CipherSuite::TLS13_AES_128_GCM_SHA256,
     kx: KeyExchangeAlgorithm::BulkOnly,
@@ -317,24 +352,36 @@ pub static TLS13_AES_128_GCM_SHA256: SupportedCipherSuite = SupportedCipherSuite
     fixed_iv_len: 12,
     explicit_nonce_len: 0,
     hkdf_algorithm: ring::hkdf::HKDF_SHA256,
+    #[cfg(feature = "tls13")]
     aead_algorithm: &ring::aead::AES_128_GCM,
+    #[cfg(feature = "tls12")]
     build_tls12_encrypter: None,
+    #[cfg(feature = "tls12")]
     build_tls12_decrypter: None,
 };
 
 /// A list of all the cipher suites supported by rustls.
 pub static ALL_CIPHERSUITES: &[&SupportedCipherSuite] = &[
     // TLS1.3 suites
+    #[cfg(feature = "tls13")]
     &TLS13_AES_256_GCM_SHA384,
+    #[cfg(feature = "tls13")]
     &TLS13_AES_128_GCM_SHA256,
+    #[cfg(feature = "tls13")]
     &TLS13_CHACHA20_POLY1305_SHA256,
 
     // TLS1.2 suites
+    #[cfg(feature = "tls12")]
     &TLS_ECDHE_ECDSA_WITH_AES_256_GCM_SHA384,
+    #[cfg(feature = "tls12")]
     &TLS_ECDHE_ECDSA_WITH_AES_128_GCM_SHA256,
+    #[cfg(feature = "tls12")]
     &TLS_ECDHE_ECDSA_WITH_CHACHA20_POLY1305_SHA256,
+    #[cfg(feature = "tls12")]
     &TLS_ECDHE_RSA_WITH_AES_256_GCM_SHA384,
+    #[cfg(feature = "tls12")]
     &TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256,
+    #[cfg(feature = "tls12")]
     &TLS_ECDHE_RSA_WITH_CHACHA20_POLY1305_SHA256,
 ];
 
@@ -342,61 +389,154 @@ pub static ALL_CIPHERSUITES: &[&SupportedCipherSuite] = &[
 ///
 /// This will be `ALL_CIPHERSUITES` sans any supported cipher suites that
 /// shouldn't be enabled by most applications.
+///
+/// This order prefers AES-GCM, which is best on machines with AES
+/// hardware.  `default_ciphersuites` picks the order for this machine.
 pub static DEFAULT_CIPHERSUITES: &[&SupportedCipherSuite] = ALL_CIPHERSUITES;
 
-// These both O(N^2)!
-pub fn choose_ciphersuite_preferring_client(
-    client_suites: &[CipherSuite],
-    server_suites: &[&'static SupportedCipherSuite],
-) -> Option<&'static SupportedCipherSuite> {
-    for client_suite in client_suites {
-        if let Some(selected) = server_suites
-            .iter()
-            .find(|x| *client_suite == x.suite)
-        {
-            return Some(*selected);
-        }
+/// `DEFAULT_CIPHERSUITES`, but preferring ChaCha20-Poly1305, which is
+/// several times faster than AES-GCM done in software.
+static CHACHA_FIRST_CIPHERSUITES: &[&SupportedCipherSuite] = &[
+    // TLS1.3 suites
+    #[cfg(feature = "tls13")]
+    &TLS13_CHACHA20_POLY1305_SHA256,
+    #[cfg(feature = "tls13")]
+    &TLS13_AES_256_GCM_SHA384,
+    #[cfg(feature = "tls13")]
+    &TLS13_AES_128_GCM_SHA256,
+
+    // TLS1.2 suites
+    #[cfg(feature = "tls12")]
+    &TLS_ECDHE_ECDSA_WITH_CHACHA20_POLY1305_SHA256,
+    #[cfg(feature = "tls12")]
+    &TLS_ECDHE_ECDSA_WITH_AES_256_GCM_SHA384,
+    #[cfg(feature = "tls12")]
+    &TLS_ECDHE_ECDSA_WITH_AES_128_GCM_SHA256,
+    #[cfg(feature = "tls12")]
+    &TLS_ECDHE_RSA_WITH_CHACHA20_POLY1305_SHA256,
+    #[cfg(feature = "tls12")]
+    &TLS_ECDHE_RSA_WITH_AES_256_GCM_SHA384,
+    #[cfg(feature = "tls12")]
+    &TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256,
+];
+
+/// The cipher suites in `DEFAULT_CIPHERSUITES`, ordered for this machine.
+///
+/// That is `DEFAULT_CIPHERSUITES` itself if the CPU has instructions for
+/// AES-GCM.  Otherwise ChaCha20-Poly1305 is preferred, as BoringSSL does.
+/// This is what `ServerConfig::new` and
+/// `ConfigBuilder::with_safe_default_cipher_suites` use.
+pub fn default_ciphersuites() -> &'static [&'static SupportedCipherSuite] {
+    if has_aes_hardware() {
+        DEFAULT_CIPHERSUITES
+    } else {
+        CHACHA_FIRST_CIPHERSUITES
     }
+}
 
-    None
+/// Whether the CPU can do AES-GCM in hardware: it needs AES and
+/// carry-less multiplication instructions.  The standard library
+/// caches the answer.
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+fn has_aes_hardware() -> bool {
+    is_x86_feature_detected!("aes") && is_x86_feature_detected!("pclmulqdq")
 }
 
-pub fn choose_ciphersuite_preferring_server(
-    client_suites: &[CipherSuite],
-    server_suites: &[&'static SupportedCipherSuite],
-) -> Option<&'static SupportedCipherSuite> {
-    if let Some(selected) = server_suites
-        .iter()
-        .find(|x| client_suites.contains(&x.suite))
-    {
-        return Some(*selected);
-    }
+#[cfg(target_arch = "aarch64")]
+fn has_aes_hardware() -> bool {
+    std::arch::is_aarch64_feature_detected!("aes")
+        && std::arch::is_aarch64_feature_detected!("pmull")
+}
 
-    None
+#[cfg(not(any(target_arch = "x86", target_arch = "x86_64", target_arch = "aarch64")))]
+fn has_aes_hardware() -> bool {
+    false
 }
 
-/// Return a list of the ciphersuites in `all` with the suites
-/// incompatible with `SignatureAlgorithm` `sigalg` removed.
-pub fn reduce_given_sigalg(
-    all: &[&'static SupportedCipherSuite],
-    sigalg: SignatureAlgorithm,
-) -> Vec<&'static SupportedCipherSuite> {
-    all.iter()
-        .filter(|&&suite| suite.usable_for_sigalg(sigalg))
-        .cloned()
-        .collect()
+/// A server's cipher suites, sorted by suite code once so that
+/// choosing one for a ClientHello is a binary search per suite it
+/// offers.  A ClientHello can offer hundreds of suites (many of
+/// them GREASE), so comparing every pair would be slow.
+#[derive(Clone, Debug)]
+pub struct SuiteTable {
+    /// The suites, most preferred first.
+    suites: Vec<&'static SupportedCipherSuite>,
+
+    /// The code of each suite and its index in `suites`, sorted
+    /// by code.
+    by_code: Vec<(u16, usize)>,
 }
 
-/// Return a list of the ciphersuites in `all` with the suites
-/// incompatible with the chosen `version` removed.
-pub fn reduce_given_version(
-    all: &[&'static SupportedCipherSuite],
-    version: ProtocolVersion,
-) -> Vec<&'static SupportedCipherSuite> {
-    all.iter()
-        .filter(|&&suite| suite.usable_for_version(version))
-        .cloned()
-        .collect()
+impl SuiteTable {
+    pub fn new(suites: &[&'static SupportedCipherSuite]) -> SuiteTable {
+        let mut by_code = suites
+            .iter()
+            .enumerate()
+            .map(|(i, scs)| (scs.suite.get_u16(), i))
+            .collect::<Vec<_>>();
+        by_code.sort_unstable();
+
+        SuiteTable {
+            suites: suites.to_vec(),
+            by_code,
+        }
+    }
+
+    /// Was this table made from exactly `suites`?
+    pub fn is_for(&self, suites: &[&'static SupportedCipherSuite]) -> bool {
+        self.suites.len() == suites.len()
+            && self
+                .suites
+                .iter()
+                .zip(suites)
+                .all(|(&ours, &theirs)| ptr::eq(ours, theirs))
+    }
+
+    /// The index in `suites` of `suite`, if we have it.
+    fn position(&self, suite: &CipherSuite) -> Option<usize> {
+        self.by_code
+            .binary_search_by_key(&suite.get_u16(), |&(code, _)| code)
+            .ok()
+            .map(|i| self.by_code[i].1)
+    }
+
+    /// Those of our suites which are in `client_suites`, in the
+    /// client's order.
+    pub fn offered<'a>(
+        &'a self,
+        client_suites: &'a [CipherSuite],
+    ) -> impl Iterator<Item = &'static SupportedCipherSuite> + 'a {
+        client_suites
+            .iter()
+            .filter_map(move |suite| self.position(suite))
+            .map(move |i| self.suites[i])
+    }
+
+    /// Of our suites for which `usable` is true, choose the one
+    /// `client_suites` lists first.
+    pub fn choose_preferring_client(
+        &self,
+        client_suites: &[CipherSuite],
+        usable: impl Fn(&SupportedCipherSuite) -> bool,
+    ) -> Option<&'static SupportedCipherSuite> {
+        self.offered(client_suites)
+            .find(|scs| usable(scs))
+    }
+
+    /// Of our suites for which `usable` is true and which are in
+    /// `client_suites`, choose the one we prefer.
+    pub fn choose_preferring_server(
+        &self,
+        client_suites: &[CipherSuite],
+        usable: impl Fn(&SupportedCipherSuite) -> bool,
+    ) -> Option<&'static SupportedCipherSuite> {
+        client_suites
+            .iter()
+            .filter_map(|suite| self.position(suite))
+            .filter(|&i| usable(self.suites[i]))
+            .min()
+            .map(|i| self.suites[i])
+    }
 }
 
 /// Return true if `sigscheme` is usable by any of the given suites.
@@ -410,11 +550,84 @@ pub fn compatible_sigscheme_for_suites(
         .any(|&suite| suite.usable_for_sigalg(sigalg))
 }
 
+/// Secrets for transmitting/receiving data over a TLS session.
+///
+/// After performing a handshake with rustls, these secrets can be extracted
+/// to configure kTLS for a socket, and have the kernel take over encryption
+/// and/or decryption.
+#[cfg(feature = "secret_extraction")]
+pub struct ExtractedSecrets {
+    /// Sequence number and secrets for the "tx" (transmit) direction.
+    pub tx: (u64, ConnectionTrafficSecrets),
+
+    /// Sequence number and secrets for the "rx" (receive) direction.
+    pub rx: (u64, ConnectionTrafficSecrets),
+}
+
+/// Traffic secrets for one direction of a TLS session, in the form
+/// the kernel's TLS implementation expects them.
+#[cfg(feature = "secret_extraction")]
+#[non_exhaustive]
+pub enum ConnectionTrafficSecrets {
+    /// Secrets for the AES_128_GCM AEAD algorithm.
+    Aes128Gcm {
+        /// The key.
+        key: [u8; 16],
+        /// The first 4 bytes of the nonce.
+        salt: [u8; 4],
+        /// The remaining 8 bytes of the nonce.
+        iv: [u8; 8],
+    },
+
+    /// Secrets for the AES_256_GCM AEAD algorithm.
+    Aes256Gcm {
+        /// The key.
+        key: [u8; 32],
+        /// The first 4 bytes of the nonce.
+        salt: [u8; 4],
+        /// The remaining 8 bytes of the nonce.
+        iv: [u8; 8],
+    },
+
+    /// Secrets for the CHACHA20_POLY1305 AEAD algorithm.
+    Chacha20Poly1305 {
+        /// The key.
+        key: [u8; 32],
+        /// The nonce.
+        iv: [u8; 12],
+    },
+}
+
+#[cfg(feature = "secret_extraction")]
+impl ConnectionTrafficSecrets {
+    /// Make secrets for `bulk` from a `key` and a 12-byte nonce `iv`.
+    pub(crate) fn new(bulk: &BulkAlgorithm, key: &[u8], iv: &[u8]) -> ConnectionTrafficSecrets {
+        // Lengths are fixed by the suite, so these conversions can't fail.
+        match bulk {
+            BulkAlgorithm::AES_128_GCM => ConnectionTrafficSecrets::Aes128Gcm {
+                key: key.try_into().unwrap(),
+                salt: iv[..4].try_into().unwrap(),
+                iv: iv[4..].try_into().unwrap(),
+            },
+            BulkAlgorithm::AES_256_GCM => ConnectionTrafficSecrets::Aes256Gcm {
+                key: key.try_into().unwrap(),
+                salt: iv[..4].try_into().unwrap(),
+                iv: iv[4..].try_into().unwrap(),
+            },
+            BulkAlgorithm::CHACHA20_POLY1305 => ConnectionTrafficSecrets::Chacha20Poly1305 {
+                key: key.try_into().unwrap(),
+                iv: iv.try_into().unwrap(),
+            },
+        }
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
     use crate::msgs::enums::CipherSuite;
 
+    #[cfg(feature = "tls12")]
     #[test]
     fn test_client_pref() {
         let client = vec![
@@ -425,11 +638,12 @@ mod test {
             &TLS_ECDHE_RSA_WITH_AES_256_GCM_SHA384,
             &TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256,
         ];
-        let chosen = choose_ciphersuite_preferring_client(&client, &server);
+        let chosen = SuiteTable::new(&server).choose_preferring_client(&client, |_| true);
         assert!(chosen.is_some());
         assert_eq!(chosen.unwrap(), &TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256);
     }
 
+    #[cfg(feature = "tls12")]
     #[test]
     fn test_server_pref() {
         let client = vec![
@@ -440,26 +654,88 @@ mod test {
             &TLS_ECDHE_RSA_WITH_AES_256_GCM_SHA384,
             &TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256,
         ];
-        let chosen = choose_ciphersuite_preferring_server(&client, &server);
+        let chosen = SuiteTable::new(&server).choose_preferring_server(&client, |_| true);
         assert!(chosen.is_some());
         assert_eq!(chosen.unwrap(), &TLS_ECDHE_RSA_WITH_AES_256_GCM_SHA384);
     }
 
     #[test]
-    fn test_pref_fails() {
-        assert!(
-            choose_ciphersuite_preferring_client(
-                &[CipherSuite::TLS_NULL_WITH_NULL_NULL],
-                ALL_CIPHERSUITES
-            )
-            .is_none()
+    fn test_pref_with_many_client_suites() {
+        let mut client = (0..500u16)
+            .map(|i| CipherSuite::Unknown(0x0a0a + 0x1010 * (i % 16)))
+            .collect::<Vec<_>>();
+        client.extend(
+            ALL_CIPHERSUITES
+                .iter()
+                .rev()
+                .map(|scs| scs.suite),
         );
-        assert!(
-            choose_ciphersuite_preferring_server(
-                &[CipherSuite::TLS_NULL_WITH_NULL_NULL],
-                ALL_CIPHERSUITES
-            )
-            .is_none()
+
+        let table = SuiteTable::new(ALL_CIPHERSUITES);
+        let last = *ALL_CIPHERSUITES.last().unwrap();
+        let chosen = table.choose_preferring_client(&client, |_| true);
+        assert_eq!(chosen.unwrap(), last);
+
+        let first = ALL_CIPHERSUITES[0];
+        let chosen = table.choose_preferring_server(&client, |_| true);
+        assert_eq!(chosen.unwrap(), first);
+
+        // suites which aren't usable are skipped.
+        let chosen = table.choose_preferring_client(&client, |scs| scs != last);
+        assert_eq!(
+            chosen.unwrap(),
+            ALL_CIPHERSUITES[ALL_CIPHERSUITES.len() - 2]
+        );
+        let chosen = table.choose_preferring_server(&client, |scs| scs != first);
+        assert_eq!(chosen.unwrap(), ALL_CIPHERSUITES[1]);
+    }
+
+    #[test]
+    fn test_pref_fails() {
+        let table = SuiteTable::new(ALL_CIPHERSUITES);
+        let null = [CipherSuite::TLS_NULL_WITH_NULL_NULL];
+        assert!(table
+            .choose_preferring_client(&null, |_| true)
+            .is_none());
+        assert!(table
+            .choose_preferring_server(&null, |_| true)
+            .is_none());
+
+        let all = ALL_CIPHERSUITES
+            .iter()
+            .map(|scs| scs.suite)
+            .collect::<Vec<_>>();
+        assert!(table
+            .choose_preferring_client(&all, |_| false)
+            .is_none());
+        assert!(table
+            .choose_preferring_server(&all, |_| false)
+            .is_none());
+    }
+
+    #[test]
+    fn test_suite_table_is_for() {
+        let table = SuiteTable::new(ALL_CIPHERSUITES);
+        assert!(table.is_for(ALL_CIPHERSUITES));
+        assert!(!table.is_for(&ALL_CIPHERSUITES[1..]));
+
+        let mut reversed = ALL_CIPHERSUITES.to_vec();
+        reversed.reverse();
+        assert!(!table.is_for(&reversed));
+    }
+
+    #[test]
+    fn test_default_ciphersuites_reorders_defaults() {
+        for suites in &[default_ciphersuites(), CHACHA_FIRST_CIPHERSUITES] {
+            assert_eq!(suites.len(), DEFAULT_CIPHERSUITES.len());
+            for scs in DEFAULT_CIPHERSUITES {
+                assert!(suites.contains(scs));
+            }
+        }
+
+        assert_eq!(
+            CHACHA_FIRST_CIPHERSUITES[0].bulk,
+            BulkAlgorithm::CHACHA20_POLY1305
         );
     }
 
@@ -470,33 +746,48 @@ mod test {
 
     #[test]
     fn test_usable_for_version() {
+        #[cfg(feature = "tls13")]
         fn ok_tls13(scs: &SupportedCipherSuite) {
             assert!(!scs.usable_for_version(ProtocolVersion::TLSv1_0));
             assert!(!scs.usable_for_version(ProtocolVersion::TLSv1_2));
             assert!(scs.usable_for_version(ProtocolVersion::TLSv1_3));
         }
 
+        #[cfg(feature = "tls12")]
         fn ok_tls12(scs: &SupportedCipherSuite) {
             assert!(!scs.usable_for_version(ProtocolVersion::TLSv1_0));
             assert!(scs.usable_for_version(ProtocolVersion::TLSv1_2));
             assert!(!scs.usable_for_version(ProtocolVersion::TLSv1_3));
         }
 
-        ok_tls13(&TLS13_CHACHA20_POLY1305_SHA256);
-        ok_tls13(&TLS13_AES_256_GCM_SHA384);
-        ok_tls13(&TLS13_AES_128_GCM_SHA256);
+        #[cfg(feature = "tls13")]
+        {
+            ok_tls13(&TLS13_CHACHA20_POLY1305_SHA256);
+            ok_tls13(&TLS13_AES_256_GCM_SHA384);
+            ok_tls13(&TLS13_AES_128_GCM_SHA256);
+        }
 
-        ok_tls12(&TLS_ECDHE_ECDSA_WITH_CHACHA20_POLY1305_SHA256);
-        ok_tls12(&TLS_ECDHE_RSA_WITH_CHACHA20_POLY1305_SHA256);
-        ok_tls12(&TLS_ECDHE_ECDSA_WITH_AES_256_GCM_SHA384);
-        ok_tls12(&TLS_ECDHE_ECDSA_WITH_AES_128_GCM_SHA256);
-        ok_tls12(&TLS_ECDHE_RSA_WITH_AES_256_GCM_SHA384);
+        #[cfg(feature = "tls12")]
+        {
+            ok_tls12(&TLS_ECDHE_ECDSA_WITH_CHACHA20_POLY1305_SHA256);
+            ok_tls12(&TLS_ECDHE_RSA_WITH_CHACHA20_POLY1305_SHA256);
+            ok_tls12(&TLS_ECDHE_ECDSA_WITH_AES_256_GCM_SHA384);
+            ok_tls12(&TLS_ECDHE_ECDSA_WITH_AES_128_GCM_SHA256);
+            ok_tls12(&TLS_ECDHE_RSA_WITH_AES_256_GCM_SHA384);
+        }
     }
 
+    #[cfg(feature = "tls13")]
     #[test]
     fn test_can_resume_to() {
         assert!(TLS13_CHACHA20_POLY1305_SHA256.can_resume_to(&TLS13_AES_128_GCM_SHA256));
         assert!(!TLS13_CHACHA20_POLY1305_SHA256.can_resume_to(&TLS13_AES_256_GCM_SHA384));
+    }
+
+    #[cfg(feature = "tls12")]
+    #[test]
+    fn test_can_resume_to_tls12() {
+        #[cfg(feature = "tls13")]
         assert!(
             !TLS13_CHACHA20_POLY1305_SHA256
                 .can_resume_to(&TLS_ECDHE_ECDSA_WITH_CHACHA20_POLY1305_SHA256)
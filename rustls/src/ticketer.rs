@@ -1,18 +1,18 @@
-use crate::rand;
+use crate::rand::{self, ProvidesRandom, RingRandom};
 use crate::server::ProducesTickets;
 
 use ring::aead;
+use std::collections::VecDeque;
+use std::convert::TryFrom;
 use std::mem;
 use std::sync::{Arc, Mutex};
-use std::time;
+use crate::time::{self, SystemTimeProvider};
 
-/// The timebase for expiring and rolling tickets and ticketing
-/// keys.  This is UNIX wall time in seconds.
+/// The timebase for rolling ticketing keys.  This is UNIX wall
+/// time in seconds, or zero where the current time cannot be
+/// determined.
 pub fn timebase() -> u64 {
-    time::SystemTime::now()
-        .duration_since(time::UNIX_EPOCH)
-        .unwrap()
-        .as_secs()
+    time::unix_time(&SystemTimeProvider).unwrap_or(0)
 }
 
 /// This is a `ProducesTickets` implementation which uses
@@ -23,6 +23,7 @@ pub struct AEADTicketer {
     alg: &'static aead::Algorithm,
     key: aead::LessSafeKey,
     lifetime: u32,
+    random: Arc<dyn ProvidesRandom>,
 }
 
 impl AEADTicketer {
@@ -40,14 +41,34 @@ impl AEADTicketer {
             alg,
             key: aead::LessSafeKey::new(key),
             lifetime: lifetime_seconds,
+            random: Arc::new(RingRandom),
         }
     }
 
     /// Make a ticketer with recommended configuration and a random key.
     pub fn new() -> Result<AEADTicketer, rand::GetRandomFailed> {
-        let mut key = [0u8; 32];
+        AEADTicketer::new_random(&aead::CHACHA20_POLY1305)
+    }
+
+    /// Make a ticketer using `alg` with a random key.
+    fn new_random(alg: &'static aead::Algorithm) -> Result<AEADTicketer, rand::GetRandomFailed> {
+        let mut key = vec![0u8; alg.key_len()];
         rand::fill_random(&mut key)?;
-        Ok(AEADTicketer::new_custom(&aead::CHACHA20_POLY1305, &key, 60 * 60 * 12))
+        Ok(AEADTicketer::new_custom(alg, &key, 60 * 60 * 12))
+    }
+
+    /// Make a ticketer with recommended configuration, drawing its
+    /// key and nonces from `random`.
+    #[cfg(feature = "deterministic")]
+    pub(crate) fn new_with_random(
+        random: Arc<dyn ProvidesRandom>,
+    ) -> Result<AEADTicketer, rand::GetRandomFailed> {
+        let alg = &aead::CHACHA20_POLY1305;
+        let mut key = vec![0u8; alg.key_len()];
+        random.fill(&mut key)?;
+        let mut ticketer = AEADTicketer::new_custom(alg, &key, 60 * 60 * 12);
+        ticketer.random = random;
+        Ok(ticketer)
     }
 }
 
@@ -63,7 +84,7 @@ impl ProducesTickets for AEADTicketer {
     fn encrypt(&self, message: &[u8]) -> Option<Vec<u8>> {
         // Random nonce, because a counter is a privacy leak.
         let mut nonce_buf = [0u8; 12];
-        rand::fill_random(&mut nonce_buf).unwrap();
+        self.random.fill(&mut nonce_buf).ok()?;
         let nonce = ring::aead::Nonce::assume_unique_for_key(nonce_buf);
         let aad = ring::aead::Aad::empty();
 
@@ -113,16 +134,19 @@ impl ProducesTickets for AEADTicketer {
 
 struct TicketSwitcherState {
     current: Box<dyn ProducesTickets>,
-    previous: Option<Box<dyn ProducesTickets>>,
+    /// Demoted ticketers, most recent first.
+    previous: VecDeque<Box<dyn ProducesTickets>>,
     next_switch_time: u64,
 }
 
-/// A ticketer that has a 'current' sub-ticketer and a single
-/// 'previous' ticketer.  It creates a new ticketer every so
-/// often, demoting the current ticketer.
+/// A ticketer that has a 'current' sub-ticketer and some
+/// 'previous' ticketers.  It creates a new ticketer every so
+/// often, demoting the current ticketer and erasing the oldest
+/// previous one.
 pub struct TicketSwitcher {
     generator: fn() -> Result<Box<dyn ProducesTickets>, rand::GetRandomFailed>,
     lifetime: u32,
+    previous_keys: usize,
     state: Mutex<TicketSwitcherState>,
 }
 
@@ -132,12 +156,24 @@ impl TicketSwitcher {
     /// longer than twice this duration.  `generator` produces a new
     /// `ProducesTickets` implementation.
     pub fn new(lifetime: u32, generator: fn() -> Result<Box<dyn ProducesTickets>, rand::GetRandomFailed>) -> Result<TicketSwitcher, rand::GetRandomFailed> {
+        TicketSwitcher::new_with_previous_keys(lifetime, 1, generator)
+    }
+
+    /// Like `new`, but `previous_keys` demoted ticketers are kept
+    /// for decryption, rather than one.  Tickets are then accepted
+    /// for no longer than `previous_keys + 1` times `lifetime`.
+    pub fn new_with_previous_keys(
+        lifetime: u32,
+        previous_keys: usize,
+        generator: fn() -> Result<Box<dyn ProducesTickets>, rand::GetRandomFailed>,
+    ) -> Result<TicketSwitcher, rand::GetRandomFailed> {
         Ok(TicketSwitcher {
             generator,
             lifetime,
+            previous_keys,
             state: Mutex::new(TicketSwitcherState {
                 current: generator()?,
-                previous: None,
+                previous: VecDeque::new(),
                 next_switch_time: timebase() + u64::from(lifetime),
             }),
         })
@@ -150,11 +186,16 @@ impl TicketSwitcher {
     /// Calling this regularly will ensure timely key erasure.  Otherwise,
     /// key erasure will be delayed until the next encrypt/decrypt call.
     pub fn maybe_roll(&self) -> Result<(), rand::GetRandomFailed> {
+        self.maybe_roll_at(timebase())
+    }
+
+    fn maybe_roll_at(&self, now: u64) -> Result<(), rand::GetRandomFailed> {
         let mut state = self.state.lock().unwrap();
-        let now = timebase();
 
         if now > state.next_switch_time {
-            state.previous = Some(mem::replace(&mut state.current, (self.generator)()?));
+            let demoted = mem::replace(&mut state.current, (self.generator)()?);
+            state.previous.push_front(demoted);
+            state.previous.truncate(self.previous_keys);
             state.next_switch_time = now + u64::from(self.lifetime);
         }
         Ok(())
@@ -163,7 +204,8 @@ impl TicketSwitcher {
 
 impl ProducesTickets for TicketSwitcher {
     fn get_lifetime(&self) -> u32 {
-        self.lifetime * 2
+        let generations = u32::try_from(self.previous_keys + 1).unwrap_or(u32::MAX);
+        self.lifetime.saturating_mul(generations)
     }
 
     fn enabled(&self) -> bool {
@@ -183,16 +225,139 @@ impl ProducesTickets for TicketSwitcher {
     fn decrypt(&self, ciphertext: &[u8]) -> Option<Vec<u8>> {
         self.maybe_roll().unwrap();
 
-        // Decrypt with the current key; if that fails, try with the previous.
+        // Decrypt with the current key; if that fails, try with the previous
+        // ones, newest first.
         let state = self.state.lock().unwrap();
         state.current.decrypt(ciphertext)
             .or_else(|| {
-                state.previous.as_ref()
-                    .and_then(|previous| previous.decrypt(ciphertext))
+                state.previous.iter()
+                    .find_map(|previous| previous.decrypt(ciphertext))
             })
     }
 }
 
+/// The AEAD algorithms tickets can be encrypted with.
+///
+/// ChaCha20-Poly1305 is the default.  The AES-GCM ones are for
+/// matching the ticket format of other servers, or where only
+/// FIPS-approved algorithms may be used.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TicketCipher {
+    /// ChaCha20-Poly1305, with a 32 byte key.
+    Chacha20Poly1305,
+
+    /// AES-128-GCM, with a 16 byte key.
+    Aes128Gcm,
+
+    /// AES-256-GCM, with a 32 byte key.
+    Aes256Gcm,
+}
+
+impl TicketCipher {
+    /// The length of this cipher's keys, in bytes.
+    pub fn key_len(self) -> usize {
+        self.algorithm().key_len()
+    }
+
+    fn algorithm(self) -> &'static aead::Algorithm {
+        match self {
+            TicketCipher::Chacha20Poly1305 => &aead::CHACHA20_POLY1305,
+            TicketCipher::Aes128Gcm => &aead::AES_128_GCM,
+            TicketCipher::Aes256Gcm => &aead::AES_256_GCM,
+        }
+    }
+
+    fn generator(self) -> fn() -> Result<Box<dyn ProducesTickets>, rand::GetRandomFailed> {
+        match self {
+            TicketCipher::Chacha20Poly1305 => generate_inner,
+            TicketCipher::Aes128Gcm => generate_aes_128_gcm,
+            TicketCipher::Aes256Gcm => generate_aes_256_gcm,
+        }
+    }
+}
+
+/// A ticket encryption key, and the name it is known by.
+#[derive(Clone)]
+pub struct TicketKey {
+    /// Identifies the key.  It is sent in the clear at the start of
+    /// each ticket, so it must not reveal anything about `secret`.
+    pub name: [u8; 16],
+
+    /// The algorithm `secret` is a key for.
+    pub cipher: TicketCipher,
+
+    /// The key, which must be `cipher.key_len()` bytes long.
+    pub secret: Vec<u8>,
+}
+
+impl TicketKey {
+    fn ticketer(&self) -> Option<AEADTicketer> {
+        if self.secret.len() != self.cipher.key_len() {
+            return None;
+        }
+        Some(AEADTicketer::new_custom(self.cipher.algorithm(), &self.secret, 0))
+    }
+}
+
+/// A source of ticket encryption keys, such as a key management
+/// service shared by the servers behind a load balancer.
+///
+/// Servers which share their ticket keys can resume each other's
+/// sessions.  Pass an implementation to `Ticketer::new_with_key_provider`.
+///
+/// Both methods are called for every ticket, so they should answer
+/// from a local copy of the keys rather than asking the service
+/// each time.  As with `TicketSwitcher`, keys should be rotated
+/// regularly, and erased once their tickets are no longer accepted.
+pub trait ProducesTicketKeys: Send + Sync {
+    /// The key to encrypt new tickets with.  Return `None` to send
+    /// an empty ticket, which the client won't use.
+    fn current_key(&self) -> Option<TicketKey>;
+
+    /// The key called `name`, if tickets encrypted with it should
+    /// still be accepted.
+    fn key_named(&self, name: &[u8; 16]) -> Option<TicketKey>;
+
+    /// The lifetime in seconds to advertise for tickets issued now.
+    fn get_lifetime(&self) -> u32;
+}
+
+/// Encrypts tickets with keys from a `ProducesTicketKeys`, prefixing
+/// each with the name of its key.
+struct KeyProviderTicketer {
+    keys: Arc<dyn ProducesTicketKeys>,
+}
+
+impl ProducesTickets for KeyProviderTicketer {
+    fn enabled(&self) -> bool {
+        true
+    }
+
+    fn get_lifetime(&self) -> u32 {
+        self.keys.get_lifetime()
+    }
+
+    fn encrypt(&self, message: &[u8]) -> Option<Vec<u8>> {
+        let key = self.keys.current_key()?;
+        let mut ticket = key.name.to_vec();
+        ticket.extend(key.ticketer()?.encrypt(message)?);
+        Some(ticket)
+    }
+
+    fn decrypt(&self, ciphertext: &[u8]) -> Option<Vec<u8>> {
+        if ciphertext.len() < 16 {
+            return None;
+        }
+
+        let (name, rest) = ciphertext.split_at(16);
+        let name = <[u8; 16]>::try_from(name).unwrap();
+        self.keys
+            .key_named(&name)?
+            .ticketer()?
+            .decrypt(rest)
+    }
+}
+
 /// A concrete, safe ticket creation mechanism.
 pub struct Ticketer {}
 
@@ -200,6 +365,14 @@ fn generate_inner() -> Result<Box<dyn ProducesTickets>, rand::GetRandomFailed> {
     Ok(Box::new(AEADTicketer::new()?))
 }
 
+fn generate_aes_128_gcm() -> Result<Box<dyn ProducesTickets>, rand::GetRandomFailed> {
+    Ok(Box::new(AEADTicketer::new_random(&aead::AES_128_GCM)?))
+}
+
+fn generate_aes_256_gcm() -> Result<Box<dyn ProducesTickets>, rand::GetRandomFailed> {
+    Ok(Box::new(AEADTicketer::new_random(&aead::AES_256_GCM)?))
+}
+
 impl Ticketer {
     /// Make the recommended Ticketer.  This produces tickets
     /// with a 12 hour life and randomly generated keys.
@@ -208,6 +381,34 @@ impl Ticketer {
     pub fn new() -> Result<Arc<dyn ProducesTickets>, rand::GetRandomFailed> {
         Ok(Arc::new(TicketSwitcher::new(6 * 60 * 60, generate_inner)?))
     }
+
+    /// Make a Ticketer like `new`, but which encrypts tickets
+    /// with `cipher`.
+    pub fn new_with_cipher(cipher: TicketCipher) -> Result<Arc<dyn ProducesTickets>, rand::GetRandomFailed> {
+        Ticketer::new_with_schedule(cipher, 6 * 60 * 60, 1)
+    }
+
+    /// Make a Ticketer which encrypts tickets with `cipher`, uses
+    /// a new key every `key_lifetime` seconds, and keeps
+    /// `previous_keys` old keys for decrypting tickets issued earlier.
+    pub fn new_with_schedule(
+        cipher: TicketCipher,
+        key_lifetime: u32,
+        previous_keys: usize,
+    ) -> Result<Arc<dyn ProducesTickets>, rand::GetRandomFailed> {
+        Ok(Arc::new(TicketSwitcher::new_with_previous_keys(
+            key_lifetime,
+            previous_keys,
+            cipher.generator(),
+        )?))
+    }
+
+    /// Make a Ticketer which takes its keys from `keys`, rather
+    /// than generating them itself.  Each ticket starts with the
+    /// name of the key it was encrypted with.
+    pub fn new_with_key_provider(keys: Arc<dyn ProducesTicketKeys>) -> Arc<dyn ProducesTickets> {
+        Arc::new(KeyProviderTicketer { keys })
+    }
 }
 
 #[test]
@@ -218,3 +419,84 @@ fn basic_pairwise_test() {
     let plain = t.decrypt(&cipher).unwrap();
     assert_eq!(plain, b"hello world");
 }
+
+#[test]
+fn aes_gcm_pairwise_test() {
+    for cipher in &[TicketCipher::Aes128Gcm, TicketCipher::Aes256Gcm] {
+        let t = Ticketer::new_with_cipher(*cipher).unwrap();
+        let ciphertext = t.encrypt(b"hello world").unwrap();
+        assert_eq!(t.decrypt(&ciphertext).unwrap(), b"hello world");
+    }
+}
+
+#[test]
+fn key_of_wrong_length_is_unusable() {
+    let key = TicketKey {
+        name: [1u8; 16],
+        cipher: TicketCipher::Aes256Gcm,
+        secret: vec![2u8; 16],
+    };
+    assert!(key.ticketer().is_none());
+}
+
+#[test]
+fn switcher_keeps_previous_keys() {
+    let t = TicketSwitcher::new_with_previous_keys(100, 2, generate_inner).unwrap();
+    assert_eq!(t.get_lifetime(), 300);
+    let cipher = t.encrypt(b"hello world").unwrap();
+
+    let start = timebase();
+    t.maybe_roll_at(start + 1000).unwrap();
+    t.maybe_roll_at(start + 2000).unwrap();
+    assert_eq!(t.decrypt(&cipher).unwrap(), b"hello world");
+
+    t.maybe_roll_at(start + 3000).unwrap();
+    assert_eq!(t.decrypt(&cipher), None);
+}
+
+#[test]
+fn key_provider_ticketer_finds_key_by_name() {
+    struct TwoKeys(TicketKey, TicketKey);
+
+    impl ProducesTicketKeys for TwoKeys {
+        fn current_key(&self) -> Option<TicketKey> {
+            Some(self.0.clone())
+        }
+
+        fn key_named(&self, name: &[u8; 16]) -> Option<TicketKey> {
+            [&self.0, &self.1]
+                .iter()
+                .find(|key| &key.name == name)
+                .map(|key| (*key).clone())
+        }
+
+        fn get_lifetime(&self) -> u32 {
+            3600
+        }
+    }
+
+    let old = TicketKey {
+        name: [1u8; 16],
+        cipher: TicketCipher::Chacha20Poly1305,
+        secret: vec![2u8; 32],
+    };
+    let new = TicketKey {
+        name: [3u8; 16],
+        cipher: TicketCipher::Aes128Gcm,
+        secret: vec![4u8; 16],
+    };
+    let old_ticketer = Ticketer::new_with_key_provider(Arc::new(TwoKeys(old.clone(), new.clone())));
+    let new_ticketer = Ticketer::new_with_key_provider(Arc::new(TwoKeys(new, old)));
+    assert_eq!(new_ticketer.get_lifetime(), 3600);
+
+    let cipher = old_ticketer.encrypt(b"hello world").unwrap();
+    assert_eq!(&cipher[..16], &[1u8; 16]);
+    assert_eq!(new_ticketer.decrypt(&cipher).unwrap(), b"hello world");
+    let cipher = new_ticketer.encrypt(b"hello world").unwrap();
+    assert_eq!(old_ticketer.decrypt(&cipher).unwrap(), b"hello world");
+
+    let mut renamed = cipher.clone();
+    renamed[0] = 5;
+    assert_eq!(new_ticketer.decrypt(&renamed), None);
+    assert_eq!(new_ticketer.decrypt(&cipher[..15]), None);
+}
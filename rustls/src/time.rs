@@ -0,0 +1,50 @@
+use crate::error::TlsError;
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Supplies the current wall-clock time.
+///
+/// rustls uses this to check certificate validity periods, and to
+/// track the lifetime and obfuscated age of session tickets.  The
+/// default, `SystemTimeProvider`, asks the operating system.  Implement
+/// this trait on devices without a reliable clock, or to fix the time
+/// in tests.
+pub trait TimeProvider: Send + Sync {
+    /// Return the current time, or `None` if it is not known.
+    fn current_time(&self) -> Option<SystemTime>;
+}
+
+/// The default `TimeProvider` implementation, using `SystemTime::now()`.
+///
+/// wasm32-unknown-unknown has no clock without calling out to
+/// JavaScript, and `SystemTime::now()` panics there.  On that target
+/// this provider reports that the time is not known.
+pub struct SystemTimeProvider;
+
+impl TimeProvider for SystemTimeProvider {
+    #[cfg(not(all(target_arch = "wasm32", target_os = "unknown")))]
+    fn current_time(&self) -> Option<SystemTime> {
+        Some(SystemTime::now())
+    }
+
+    #[cfg(all(target_arch = "wasm32", target_os = "unknown"))]
+    fn current_time(&self) -> Option<SystemTime> {
+        None
+    }
+}
+
+/// The current time according to `provider`.
+pub fn now(provider: &dyn TimeProvider) -> Result<SystemTime, TlsError> {
+    provider
+        .current_time()
+        .ok_or(TlsError::FailedToGetCurrentTime)
+}
+
+/// The current UNIX wall time in seconds according to `provider`.
+/// This is the timebase for ticket lifetimes and ages.
+pub fn unix_time(provider: &dyn TimeProvider) -> Result<u64, TlsError> {
+    now(provider)?
+        .duration_since(UNIX_EPOCH)
+        .map(|since_epoch| since_epoch.as_secs())
+        .map_err(|_| TlsError::FailedToGetCurrentTime)
+}
@@ -0,0 +1,461 @@
+//! A session API which works on buffers owned by the caller.
+//!
+//! A `ClientSession` or `ServerSession` normally queues TLS data and
+//! plaintext internally, and is driven with `read_tls`/`write_tls` and
+//! `io::Read`/`io::Write`.  An `UnbufferedSession` instead takes TLS
+//! records from a slice the caller supplies, writes any plaintext they
+//! carry straight into another, and encrypts plaintext straight into an
+//! output slice.  Every call says exactly how much of each buffer it
+//! used, or how much more it needs.
+//!
+//! ```no_run
+//! # fn run(session: rustls::ClientSession) -> Result<(), rustls::TlsError> {
+//! use rustls::unbuffered::{ProcessStatus, UnbufferedSession};
+//!
+//! let mut session = UnbufferedSession::new(session);
+//! let mut incoming = Vec::new();
+//! let mut plaintext = [0u8; 16384 + 2048];
+//! let mut outgoing = [0u8; 16384 + 2048];
+//!
+//! loop {
+//!     while session.wants_write() {
+//!         let len = session.write_tls_into(&mut outgoing).unwrap();
+//!         // ... send `outgoing[..len]` to the peer ...
+//!     }
+//!
+//!     // ... receive more data from the peer into `incoming` ...
+//!
+//!     match session.process_tls_record(&incoming, &mut plaintext)? {
+//!         ProcessStatus::Processed { consumed, plaintext: len } => {
+//!             incoming.drain(..consumed);
+//!             // ... use `plaintext[..len]` ...
+//!         }
+//!         ProcessStatus::NeedsInput { .. } => {}
+//!         ProcessStatus::NeedsOutputSpace { .. } => unreachable!(),
+//!     }
+//! }
+//! # }
+//! ```
+//!
+//! This only supports TLS over a reliable transport: not QUIC or DTLS.
+
+use crate::client::ClientSession;
+use crate::error::TlsError;
+use crate::msgs::codec::Reader;
+use crate::msgs::enums::{ContentType, ProtocolVersion};
+use crate::msgs::message::{BorrowMessageMut, Message, MessagePayload};
+use crate::server::ServerSession;
+use crate::session::{Incoming, Session, SessionCommon};
+
+use std::error::Error as StdError;
+use std::fmt;
+
+/// Content type, version and length.
+const HEADER_SIZE: usize = 1 + 2 + 2;
+
+mod sealed {
+    use crate::error::TlsError;
+    use crate::session::{Incoming, SessionCommon};
+
+    /// Access to the parts of a session which `UnbufferedSession`
+    /// drives directly.
+    pub trait Internals {
+        fn common(&self) -> &SessionCommon;
+        fn common_mut(&mut self) -> &mut SessionCommon;
+        fn perhaps_write_key_update(&mut self);
+        fn process_received(
+            &mut self,
+            incoming: Result<Incoming, TlsError>,
+        ) -> Result<(), TlsError>;
+    }
+}
+
+impl sealed::Internals for ClientSession {
+    fn common(&self) -> &SessionCommon {
+        &self.imp.common
+    }
+
+    fn common_mut(&mut self) -> &mut SessionCommon {
+        &mut self.imp.common
+    }
+
+    fn perhaps_write_key_update(&mut self) {
+        self.imp.perhaps_write_key_update();
+    }
+
+    fn process_received(&mut self, incoming: Result<Incoming, TlsError>) -> Result<(), TlsError> {
+        self.imp.process_received(incoming)
+    }
+}
+
+impl sealed::Internals for ServerSession {
+    fn common(&self) -> &SessionCommon {
+        &self.imp.common
+    }
+
+    fn common_mut(&mut self) -> &mut SessionCommon {
+        &mut self.imp.common
+    }
+
+    fn perhaps_write_key_update(&mut self) {
+        self.imp.perhaps_write_key_update();
+    }
+
+    fn process_received(&mut self, incoming: Result<Incoming, TlsError>) -> Result<(), TlsError> {
+        self.imp.process_received(incoming)
+    }
+}
+
+/// The outcome of `UnbufferedSession::process_tls_record`.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum ProcessStatus {
+    /// One record was processed.
+    Processed {
+        /// How many bytes of the input were used.  The caller should
+        /// discard these before the next call.
+        consumed: usize,
+
+        /// How many bytes of plaintext were written to the output.
+        plaintext: usize,
+    },
+
+    /// The input doesn't hold a whole record.  Nothing was used.
+    NeedsInput {
+        /// At least this many more bytes of input are needed.
+        needed: usize,
+    },
+
+    /// The output is too small for the plaintext the next record might
+    /// carry.  Nothing was used.
+    NeedsOutputSpace {
+        /// The output must be at least this long.
+        required: usize,
+    },
+}
+
+/// An error from encoding TLS data into a caller's buffer.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum EncodeError {
+    /// The output is too small.  Nothing was written.
+    InsufficientSize {
+        /// The output must be at least this long.
+        required: usize,
+    },
+
+    /// The session has queued TLS data of its own, which must be
+    /// written with `write_tls_into` first.
+    TlsPending,
+
+    /// Application data can't be sent until the handshake is complete.
+    HandshakeNotComplete,
+
+    /// Encrypting the plaintext would take the session past the number
+    /// of records it can safely encrypt with its keys.  Nothing was
+    /// written.  A close_notify alert is only queued if the session
+    /// has reached that number already: call `send_close_notify`
+    /// otherwise.
+    EncryptExhausted,
+}
+
+impl fmt::Display for EncodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EncodeError::InsufficientSize { required } => {
+                write!(f, "output buffer too small: {} bytes required", required)
+            }
+            EncodeError::TlsPending => write!(f, "queued TLS data must be written first"),
+            EncodeError::HandshakeNotComplete => write!(f, "handshake not complete"),
+            EncodeError::EncryptExhausted => write!(f, "encryption sequence space exhausted"),
+        }
+    }
+}
+
+impl StdError for EncodeError {}
+
+/// A `ClientSession` or `ServerSession` driven through caller-owned
+/// buffers.  See the module documentation.
+#[derive(Debug)]
+pub struct UnbufferedSession<S> {
+    sess: S,
+}
+
+impl<S> UnbufferedSession<S>
+where
+    S: Session + sealed::Internals,
+{
+    /// Drive `sess` through caller-owned buffers.  `sess` should be
+    /// freshly made: anything already queued in it is still sent or
+    /// received, but through the calls here.
+    pub fn new(sess: S) -> UnbufferedSession<S> {
+        UnbufferedSession { sess }
+    }
+
+    /// Get a reference to the session, to learn about the connection.
+    pub fn get_ref(&self) -> &S {
+        &self.sess
+    }
+
+    /// Return the session, to drive it with `read_tls` and `write_tls`
+    /// again.
+    pub fn into_inner(self) -> S {
+        self.sess
+    }
+
+    /// Returns true if the handshake is still in progress.
+    pub fn is_handshaking(&self) -> bool {
+        self.sess.is_handshaking()
+    }
+
+    /// Returns true if the session has TLS data to send: call
+    /// `write_tls_into` until this is false.
+    pub fn wants_write(&self) -> bool {
+        self.sess.wants_write()
+    }
+
+    /// Returns true once the peer's close_notify alert has been processed.
+    pub fn peer_has_closed(&self) -> bool {
+        self.sess
+            .common()
+            .connection_at_eof()
+    }
+
+    /// Process the first TLS record in `input`, writing any plaintext
+    /// it carries to `plaintext`.
+    ///
+    /// An encrypted record is copied to `plaintext` and decrypted
+    /// there, so it is only processed if `plaintext` is at least the
+    /// record's payload length.  A `plaintext` of 18432 bytes is
+    /// always enough.
+    ///
+    /// Errors are fatal to the session.  An alert may have been queued
+    /// describing the error, so call `write_tls_into` to send it.
+    pub fn process_tls_record(
+        &mut self,
+        input: &[u8],
+        plaintext: &mut [u8],
+    ) -> Result<ProcessStatus, TlsError> {
+        if input.len() < HEADER_SIZE {
+            return Ok(ProcessStatus::NeedsInput {
+                needed: HEADER_SIZE - input.len(),
+            });
+        }
+
+        let mut rd = Reader::init(input);
+        let (typ, version, len) = match Message::read_header(&mut rd) {
+            Ok(header) => header,
+            Err(_) => {
+                self.sess
+                    .common_mut()
+                    .message_deframer
+                    .desynced = true;
+                self.sess.process_new_packets()?;
+                return Err(TlsError::CorruptMessage);
+            }
+        };
+
+        let consumed = HEADER_SIZE + len as usize;
+        if input.len() < consumed {
+            return Ok(ProcessStatus::NeedsInput {
+                needed: consumed - input.len(),
+            });
+        }
+        let payload = &input[HEADER_SIZE..consumed];
+
+        // Anything left from before must be delivered first, and a
+        // desynced session must keep failing.
+        let common = self.sess.common_mut();
+        let queued = common.message_deframer.has_pending()
+            || common.has_readable_plaintext()
+            || common.message_deframer.desynced;
+
+        // Records which aren't encrypted go to the state machine as
+        // they are.  TLS1.3 middlebox CCSes are never encrypted.
+        if queued || !common.record_layer.is_decrypting() || typ == ContentType::ChangeCipherSpec {
+            return self.process_queued(typ, version, payload, consumed, plaintext);
+        }
+
+        if plaintext.len() < payload.len() {
+            return Ok(ProcessStatus::NeedsOutputSpace {
+                required: payload.len(),
+            });
+        }
+
+        let encr = BorrowMessageMut {
+            typ,
+            version,
+            payload: &mut plaintext[..payload.len()],
+        };
+        encr.payload.copy_from_slice(payload);
+        let plain = match common.decrypt_in_place(encr) {
+            Ok(plain) => plain,
+            Err(err) => {
+                self.sess
+                    .process_received(Err(err.clone()))?;
+                return Err(err);
+            }
+        };
+
+        // Application data is ready where it lies.  Anything else is
+        // for the state machine, as is application data which arrives
+        // before the handshake is complete.
+        let len = plain.payload.len();
+        if plain.typ == ContentType::ApplicationData && common.traffic {
+            return Ok(ProcessStatus::Processed {
+                consumed,
+                plaintext: len,
+            });
+        }
+
+        let msg = Message {
+            typ: plain.typ,
+            version: plain.version,
+            payload: MessagePayload::new_opaque(plain.payload.to_vec()),
+        };
+        self.sess
+            .process_received(Ok(Incoming::Decrypted(msg)))?;
+
+        Ok(ProcessStatus::Processed {
+            consumed,
+            plaintext: 0,
+        })
+    }
+
+    /// Process a record through the session's own buffers, behind
+    /// anything already queued there.
+    fn process_queued(
+        &mut self,
+        typ: ContentType,
+        version: ProtocolVersion,
+        payload: &[u8],
+        consumed: usize,
+        plaintext: &mut [u8],
+    ) -> Result<ProcessStatus, TlsError> {
+        if typ == ContentType::ApplicationData && plaintext.len() < payload.len() {
+            return Ok(ProcessStatus::NeedsOutputSpace {
+                required: payload.len(),
+            });
+        }
+
+        let msg = Message {
+            typ,
+            version,
+            payload: MessagePayload::new_opaque(payload.to_vec()),
+        };
+        self.sess
+            .common_mut()
+            .message_deframer
+            .frames
+            .push_back(msg);
+        self.sess.process_new_packets()?;
+
+        // This only fails once the peer has closed and there's no more
+        // plaintext, which is what zero means here.
+        let len = self
+            .sess
+            .common_mut()
+            .read(plaintext)
+            .unwrap_or(0);
+
+        Ok(ProcessStatus::Processed {
+            consumed,
+            plaintext: len,
+        })
+    }
+
+    /// Write whole TLS records queued by the session to `out`, returning
+    /// how many bytes were written.
+    ///
+    /// This writes as many records as fit.  It fails if not even the
+    /// first one does.
+    pub fn write_tls_into(&mut self, out: &mut [u8]) -> Result<usize, EncodeError> {
+        let tls = &mut self.sess.common_mut().sendable_tls;
+        let used = tls.take_whole_chunks(out);
+        if used == 0 && !tls.is_empty() {
+            return Err(EncodeError::InsufficientSize {
+                required: tls.first_len(),
+            });
+        }
+        Ok(used)
+    }
+
+    /// Encrypt all of `plaintext` into `out`, returning how many bytes
+    /// were written.  Each record is encrypted in place in `out`.
+    ///
+    /// This fails without writing anything if `out` is too small, and
+    /// says how large it needs to be.
+    pub fn encrypt(&mut self, plaintext: &[u8], out: &mut [u8]) -> Result<usize, EncodeError> {
+        if !self.sess.common().traffic {
+            return Err(EncodeError::HandshakeNotComplete);
+        }
+
+        self.sess.perhaps_write_key_update();
+        let common = self.sess.common_mut();
+        if !common.sendable_tls.is_empty() {
+            return Err(EncodeError::TlsPending);
+        }
+
+        let fragments = |common: &SessionCommon| {
+            common.message_fragmenter.iter_borrow(
+                ContentType::ApplicationData,
+                ProtocolVersion::TLSv1_2,
+                plaintext,
+            )
+        };
+
+        let (records, required) = fragments(common).fold((0, 0), |(records, len), m| {
+            (
+                records + 1,
+                len + common
+                    .record_layer
+                    .encrypted_len(m.payload.len()),
+            )
+        });
+        if required > out.len() {
+            return Err(EncodeError::InsufficientSize { required });
+        }
+
+        if common
+            .record_layer
+            .wants_close_within(records)
+        {
+            if common
+                .record_layer
+                .wants_close_before_encrypt()
+            {
+                common.send_close_notify();
+            }
+            return Err(EncodeError::EncryptExhausted);
+        }
+
+        let mut used = 0;
+        for m in fragments(common) {
+            #[cfg(feature = "metrics")]
+            common.count_encrypted(m.payload.len());
+            used += common
+                .record_layer
+                .encrypt_outgoing_into(m, &mut out[used..]);
+        }
+
+        debug_assert_eq!(used, required);
+        Ok(used)
+    }
+
+    /// Queue a close_notify alert, telling the peer nothing more will be
+    /// sent.  Call `write_tls_into` to send it.
+    pub fn send_close_notify(&mut self) {
+        self.sess.send_close_notify();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::UnbufferedSession;
+    use crate::client::ClientSession;
+    use crate::server::ServerSession;
+
+    #[test]
+    fn unbuffered_session_can_be_created_for_client_and_server() {
+        type _Client = UnbufferedSession<ClientSession>;
+        type _Server = UnbufferedSession<ServerSession>;
+    }
+}
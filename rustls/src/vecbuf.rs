@@ -2,6 +2,9 @@ use std::cmp;
 use std::collections::VecDeque;
 use std::io;
 use std::io::Read;
+use std::sync::Arc;
+
+use crate::bufpool::BufferPool;
 
 /// This is a byte buffer that is built from a vector
 /// of byte vectors.  This avoids extra copies when
@@ -10,6 +13,7 @@ use std::io::Read;
 pub struct ChunkVecBuffer {
     chunks: VecDeque<Vec<u8>>,
     limit: usize,
+    pool: Option<Arc<BufferPool>>,
 }
 
 impl ChunkVecBuffer {
@@ -17,6 +21,33 @@ impl ChunkVecBuffer {
         ChunkVecBuffer {
             chunks: VecDeque::new(),
             limit: 0,
+            pool: None,
+        }
+    }
+
+    /// Like `new`, but chunks are returned to `pool` once
+    /// they've been read out, and `new_chunk` takes from it.
+    pub fn new_pooled(pool: Arc<BufferPool>) -> ChunkVecBuffer {
+        ChunkVecBuffer {
+            chunks: VecDeque::new(),
+            limit: 0,
+            pool: Some(pool),
+        }
+    }
+
+    /// Get an empty vector with room for `capacity` bytes,
+    /// for building a chunk to `append`.
+    pub fn new_chunk(&self, capacity: usize) -> Vec<u8> {
+        match &self.pool {
+            Some(pool) => pool.take_chunk(capacity),
+            None => Vec::with_capacity(capacity),
+        }
+    }
+
+    /// We're finished with `chunk`.
+    fn recycle(&self, chunk: Vec<u8>) {
+        if let Some(pool) = &self.pool {
+            pool.put_chunk(chunk);
         }
     }
 
@@ -36,6 +67,12 @@ impl ChunkVecBuffer {
         self.chunks.is_empty()
     }
 
+    /// If we're storing more than our `limit`.  This is never
+    /// true without a limit.
+    pub fn is_full(&self) -> bool {
+        self.limit != 0 && self.len() > self.limit
+    }
+
     /// How many bytes we're storing
     pub fn len(&self) -> usize {
         let mut len = 0;
@@ -76,12 +113,60 @@ impl ChunkVecBuffer {
         len
     }
 
+    /// Append a copy of `bytes`, ignoring any limit.  They go
+    /// at the end of the last chunk if it has room, else in a
+    /// new chunk with room for at least `capacity_hint` bytes,
+    /// so runs of small appends share a chunk.
+    pub fn append_copy(&mut self, bytes: &[u8], capacity_hint: usize) -> usize {
+        if bytes.is_empty() {
+            return 0;
+        }
+
+        if let Some(last) = self.chunks.back_mut() {
+            if last.capacity() - last.len() >= bytes.len() {
+                last.extend_from_slice(bytes);
+                return bytes.len();
+            }
+        }
+
+        let mut chunk = self.new_chunk(cmp::max(bytes.len(), capacity_hint));
+        chunk.extend_from_slice(bytes);
+        self.append(chunk)
+    }
+
     /// Take one of the chunks from this object.  This
     /// function panics if the object `is_empty`.
     pub fn take_one(&mut self) -> Vec<u8> {
         self.chunks.pop_front().unwrap()
     }
 
+    /// The length of the first chunk, or zero if there are none.
+    pub fn first_len(&self) -> usize {
+        self.chunks
+            .front()
+            .map_or(0, |ch| ch.len())
+    }
+
+    /// The first chunk, or an empty slice if there are none.
+    pub fn chunk(&self) -> &[u8] {
+        self.chunks
+            .front()
+            .map_or(&[], |ch| ch.as_slice())
+    }
+
+    /// Copy whole chunks into `out`, stopping at the first one
+    /// that doesn't fit.  Returns the number of bytes copied.
+    pub fn take_whole_chunks(&mut self, out: &mut [u8]) -> usize {
+        let mut used = 0;
+        while !self.is_empty() && used + self.first_len() <= out.len() {
+            let chunk = self.take_one();
+            out[used..used + chunk.len()].copy_from_slice(&chunk);
+            used += chunk.len();
+            self.recycle(chunk);
+        }
+        used
+    }
+
     /// Read data out of this object, writing it into `buf`
     /// and returning how many bytes were written there.
     pub fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
@@ -99,13 +184,15 @@ impl ChunkVecBuffer {
         Ok(offs)
     }
 
-    fn consume(&mut self, mut used: usize) {
+    /// Discard the first `used` bytes, as if they'd been read.
+    pub fn consume(&mut self, mut used: usize) {
         while used > 0 && !self.is_empty() {
             if used >= self.chunks[0].len() {
                 used -= self.chunks[0].len();
-                self.take_one();
+                let chunk = self.take_one();
+                self.recycle(chunk);
             } else {
-                self.chunks[0] = self.chunks[0].split_off(used);
+                self.chunks[0].drain(..used);
                 used = 0;
             }
         }
@@ -129,9 +216,20 @@ impl ChunkVecBuffer {
     }
 }
 
+impl Drop for ChunkVecBuffer {
+    fn drop(&mut self) {
+        if let Some(pool) = &self.pool {
+            for chunk in self.chunks.drain(..) {
+                pool.put_chunk(chunk);
+            }
+        }
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::ChunkVecBuffer;
+    use crate::bufpool::BufferPool;
 
     #[test]
     fn short_append_copy_with_limit() {
@@ -146,4 +244,66 @@ mod test {
         assert_eq!(cvb.read(&mut buf).unwrap(), 12);
         assert_eq!(buf.to_vec(), b"helloworldhe".to_vec());
     }
+
+    #[test]
+    fn append_copy_fills_last_chunk() {
+        let mut cvb = ChunkVecBuffer::new();
+        assert_eq!(cvb.append_copy(b"", 8), 0);
+        assert!(cvb.is_empty());
+
+        assert_eq!(cvb.append_copy(b"hello", 8), 5);
+        assert_eq!(cvb.append_copy(b"wor", 8), 3);
+        assert_eq!(cvb.first_len(), 8);
+        assert_eq!(cvb.append_copy(b"ld", 0), 2);
+        assert_eq!(cvb.first_len(), 8);
+
+        let mut buf = [0u8; 10];
+        assert_eq!(cvb.read(&mut buf).unwrap(), 10);
+        assert_eq!(&buf, b"helloworld");
+    }
+
+    #[test]
+    fn full_once_over_limit() {
+        let mut cvb = ChunkVecBuffer::new();
+        cvb.append(b"hello".to_vec());
+        assert!(!cvb.is_full());
+
+        cvb.set_limit(5);
+        assert!(!cvb.is_full());
+        cvb.append(b"!".to_vec());
+        assert!(cvb.is_full());
+    }
+
+    #[test]
+    fn pooled_chunks_are_recycled_once_read() {
+        let pool = BufferPool::new(4);
+        let mut cvb = ChunkVecBuffer::new_pooled(pool.clone());
+        let mut chunk = cvb.new_chunk(5);
+        chunk.extend_from_slice(b"hello");
+        cvb.append(chunk);
+        cvb.append(b"world".to_vec());
+
+        let mut buf = [0u8; 7];
+        assert_eq!(cvb.read(&mut buf).unwrap(), 7);
+        assert_eq!(pool.idle_buffers(), 1);
+
+        drop(cvb);
+        assert_eq!(pool.idle_buffers(), 2);
+    }
+
+    #[test]
+    fn take_whole_chunks_stops_at_partial_fit() {
+        let mut cvb = ChunkVecBuffer::new();
+        cvb.append(b"hello".to_vec());
+        cvb.append(b"world".to_vec());
+
+        let mut buf = [0u8; 8];
+        assert_eq!(cvb.take_whole_chunks(&mut buf), 5);
+        assert_eq!(&buf[..5], b"hello");
+        assert_eq!(cvb.first_len(), 5);
+
+        assert_eq!(cvb.take_whole_chunks(&mut buf[..4]), 0);
+        assert_eq!(cvb.take_whole_chunks(&mut buf), 5);
+        assert!(cvb.is_empty());
+    }
 }
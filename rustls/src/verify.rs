@@ -1,19 +1,27 @@
 use sct;
 use std;
+use std::collections;
+use std::net::IpAddr;
 use std::sync::Arc;
 use std::time::SystemTime;
 use webpki;
 
 use crate::anchors::OwnedTrustAnchor;
 use crate::anchors::{DistinguishedNames, RootCertStore};
-use crate::error::TlsError;
-use crate::error::WebPKIOp;
+use crate::crl::CrlSet;
+use crate::error::{PeerMisbehaved, TlsError};
+use crate::error::CertificateError;
 use crate::key::Certificate;
 #[cfg(feature = "logging")]
 use crate::log::{debug, trace, warn};
 use crate::msgs::enums::SignatureScheme;
 use crate::msgs::handshake::DigitallySignedStruct;
+use crate::ocsp;
+use crate::server_name::{DnsName, ServerName};
+use crate::x509;
+#[cfg(feature = "tls13")]
 use ring::digest::Digest;
+use ring::signature;
 
 type SignatureAlgorithms = &'static [&'static webpki::SignatureAlgorithm];
 
@@ -58,29 +66,149 @@ impl FinishedMessageVerified {
     }
 }
 
-/// Zero-sized marker type representing verification of a server cert chain.
-pub struct ServerCertVerified(());
+/// A signed certificate timestamp (SCT) which was successfully
+/// validated against one of the configured CT logs.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ValidatedSct {
+    /// The identifier of the log which signed the SCT.
+    pub log_id: [u8; 32],
+
+    /// When the log promised to include the certificate,
+    /// in milliseconds since the unix epoch.
+    pub timestamp: u64,
+}
+
+impl ValidatedSct {
+    /// Recover the log id and timestamp from a v1 SCT which has
+    /// already been validated by `sct::verify_sct`.
+    fn from_verified(sct: &[u8]) -> Option<ValidatedSct> {
+        let mut log_id = [0u8; 32];
+        log_id.copy_from_slice(sct.get(1..33)?);
+
+        let mut timestamp = [0u8; 8];
+        timestamp.copy_from_slice(sct.get(33..41)?);
+
+        Some(ValidatedSct {
+            log_id,
+            timestamp: u64::from_be_bytes(timestamp),
+        })
+    }
+}
+
+/// Marker type representing verification of a server cert chain.
+pub struct ServerCertVerified {
+    scts: Vec<ValidatedSct>,
+    intermediates: Option<Vec<Certificate>>,
+}
+
 impl ServerCertVerified {
     /// Make a `ServerCertVerified`
     pub fn assertion() -> Self {
-        Self { 0: () }
+        Self {
+            scts: Vec::new(),
+            intermediates: None,
+        }
+    }
+
+    /// Record the SCTs which were validated along with the
+    /// certificate chain.  These are made available to the
+    /// application via `ClientSession::get_validated_scts`.
+    pub fn with_scts(self, scts: Vec<ValidatedSct>) -> Self {
+        Self { scts, ..self }
+    }
+
+    /// Record the intermediate certificates on the path that was
+    /// verified, starting with the end-entity certificate's issuer.
+    /// These are given to the `RevocationChecker` instead of the
+    /// certificates the server sent.
+    pub fn with_intermediates(self, intermediates: Vec<Certificate>) -> Self {
+        Self {
+            intermediates: Some(intermediates),
+            ..self
+        }
+    }
+
+    pub(crate) fn scts(&self) -> &[ValidatedSct] {
+        &self.scts
     }
+
+    pub(crate) fn intermediates(&self) -> Option<&[Certificate]> {
+        self.intermediates.as_deref()
+    }
+}
+
+/// Marker type representing verification of a client cert chain.
+pub struct ClientCertVerified {
+    intermediates: Option<Vec<Certificate>>,
 }
 
-/// Zero-sized marker type representing verification of a client cert chain.
-pub struct ClientCertVerified(());
 impl ClientCertVerified {
     /// Make a `ClientCertVerified`
     pub fn assertion() -> Self {
-        Self { 0: () }
+        Self {
+            intermediates: None,
+        }
+    }
+
+    /// Record the intermediate certificates on the path that was
+    /// verified, starting with the end-entity certificate's issuer.
+    /// These are given to the `RevocationChecker` instead of the
+    /// certificates the client sent.
+    pub fn with_intermediates(self, intermediates: Vec<Certificate>) -> Self {
+        Self {
+            intermediates: Some(intermediates),
+        }
     }
+
+    pub(crate) fn intermediates(&self) -> Option<&[Certificate]> {
+        self.intermediates.as_deref()
+    }
+}
+
+/// The details of a server's certificate chain given to a
+/// `ServerCertPolicy`.
+pub struct ServerCertInfo<'a> {
+    /// The server's end-entity certificate.
+    pub end_entity: &'a Certificate,
+
+    /// The intermediate certificates the server sent, in the order
+    /// it sent them.  This may be empty.
+    pub intermediates: &'a [Certificate],
+
+    /// The name or address we are connecting to.
+    pub server_name: &'a ServerName,
+
+    /// The OCSP response stapled by the server, or empty if
+    /// there wasn't one.
+    pub ocsp_response: &'a [u8],
+
+    /// The current time.
+    pub now: SystemTime,
+}
+
+/// A custom policy deciding whether to trust a server's certificate
+/// chain, for example to implement a private PKI.
+///
+/// This replaces the usual webpki chain validation, but unlike
+/// implementing `ServerCertVerifier` (which needs the
+/// `dangerous_configuration` feature) it can only decide whether the
+/// certificates are acceptable.  rustls still checks the server's
+/// handshake signature against the end-entity certificate, so that a
+/// server can't present a certificate it doesn't have the key for.
+///
+/// Install a policy with `ClientConfig::new_with_policy`.
+pub trait ServerCertPolicy: Send + Sync {
+    /// Return `Ok(())` if the server's certificate chain described
+    /// by `cert` is acceptable.  Otherwise return an error: rustls
+    /// will send an alert and abort the handshake.
+    fn verify_server_cert(&self, cert: &ServerCertInfo) -> Result<(), TlsError>;
 }
 
 /// Something that can verify a server certificate chain, and verify
 /// signatures made by certificates.
 pub trait ServerCertVerifier: Send + Sync {
-    /// Verify the end-entity certificate `end_entity` is valid for the
-    /// hostname `dns_name` and chains to at least one trust anchor.
+    /// Verify the end-entity certificate `end_entity` is valid for
+    /// `server_name` and chains to at least one trust anchor.
     ///
     /// `intermediates` contains the intermediate certificates the client sent
     /// along with the end-entity certificate; it is in the same order that the
@@ -92,7 +220,7 @@ pub trait ServerCertVerifier: Send + Sync {
         &self,
         end_entity: &Certificate,
         intermediates: &[Certificate],
-        dns_name: webpki::DNSNameRef,
+        server_name: &ServerName,
         scts: &mut dyn Iterator<Item=&[u8]>,
         ocsp_response: &[u8],
         now: SystemTime,
@@ -176,16 +304,38 @@ pub trait ClientCertVerifier: Send + Sync {
         true
     }
 
+    /// Returns `true` to request a client certificate from a client which
+    /// quoted `sni` in its ClientHello.  This is only consulted if
+    /// `offer_client_auth` returned `true`.  Defaults to `self.offer_client_auth()`.
+    ///
+    /// `sni` has been validated as a proper DNS name but is otherwise untrusted.
+    fn offer_client_auth_for_sni(&self, _sni: Option<&DnsName>) -> bool {
+        self.offer_client_auth()
+    }
+
     /// Return `Some(true)` to require a client certificate and `Some(false)` to make
     /// client authentication optional. Return `None` to abort the connection.
     /// Defaults to `Some(self.offer_client_auth())`.
     ///
     /// `sni` is the server name quoted by the client in its ClientHello; it has
     /// been validated as a proper DNS name but is otherwise untrusted.
-    fn client_auth_mandatory(&self, _sni: Option<&webpki::DNSName>) -> Option<bool> {
+    fn client_auth_mandatory(&self, _sni: Option<&DnsName>) -> Option<bool> {
         Some(self.offer_client_auth())
     }
 
+    /// Return `false` to continue the handshake when the client's
+    /// certificate was rejected with `err`, by `verify_client_cert` or
+    /// the `ServerConfig`'s `revocation_checker`.  The session then
+    /// proceeds as if the client had sent no certificate: its
+    /// CertificateVerify is ignored, and it has no peer certificates.
+    /// Return `true` to abort the connection.  Defaults to `true`.
+    ///
+    /// `sni` is the server name quoted by the client in its ClientHello; it has
+    /// been validated as a proper DNS name but is otherwise untrusted.
+    fn client_cert_failure_is_fatal(&self, _err: &TlsError, _sni: Option<&DnsName>) -> bool {
+        true
+    }
+
     /// Returns the subject names of the client authentication trust anchors to
     /// share with the client when requesting client authentication.
     ///
@@ -195,7 +345,7 @@ pub trait ClientCertVerifier: Send + Sync {
     /// been validated as a proper DNS name but is otherwise untrusted.
     fn client_auth_root_subjects(
         &self,
-        sni: Option<&webpki::DNSName>,
+        sni: Option<&DnsName>,
     ) -> Option<DistinguishedNames>;
 
     /// Verify the end-entity certificate `end_entity` is valid for the
@@ -211,7 +361,7 @@ pub trait ClientCertVerifier: Send + Sync {
         &self,
         end_entity: &Certificate,
         intermediates: &[Certificate],
-        sni: Option<&webpki::DNSName>,
+        sni: Option<&DnsName>,
         now: SystemTime,
     ) -> Result<ClientCertVerified, TlsError>;
 
@@ -280,13 +430,14 @@ impl ServerCertVerifier for WebPkiVerifier {
     /// Will verify the certificate is valid in the following ways:
     /// - Signed by a  trusted `RootCertStore` CA
     /// - Not Expired
-    /// - Valid for DNS entry
-    /// - OCSP data is present
+    /// - Valid for DNS entry, or IP address
+    /// - Not revoked, if a stapled OCSP response is present
+    /// - Not revoked by any configured CRLs
     fn verify_server_cert(
         &self,
         end_entity: &Certificate,
         intermediates: &[Certificate],
-        dns_name: webpki::DNSNameRef,
+        server_name: &ServerName,
         scts: &mut dyn Iterator<Item=&[u8]>,
         ocsp_response: &[u8],
         now: SystemTime,
@@ -303,17 +454,79 @@ impl ServerCertVerifier for WebPkiVerifier {
                 &chain,
                 webpki_now,
             )
-            .map_err(|e| TlsError::WebPKIError(e, WebPKIOp::ValidateServerCert))
+            .map_err(invalid_certificate)
             .map(|_| cert)?;
 
-        verify_scts(end_entity, now, scts, &self.ct_logs)?;
+        let path = x509::build_chain(&end_entity.0, intermediates, &self.roots);
+        if let Some(crls) = &self.crls {
+            let path = path
+                .as_ref()
+                .map_err(|err| TlsError::CRLError((*err).into()))?;
+            crls.check(path, unix_time_millis(now)? / 1000)
+                .map_err(TlsError::CRLError)?;
+        }
+
+        let scts = verify_scts(end_entity, now, scts, &self.ct_logs)?;
 
         if !ocsp_response.is_empty() {
-            trace!("Unvalidated OCSP response: {:?}", ocsp_response.to_vec());
+            trace!("Checking OCSP response: {:?}", ocsp_response.to_vec());
+            let path = path
+                .as_ref()
+                .map_err(|err| TlsError::InvalidOCSPResponse((*err).into()))?;
+            ocsp::verify_ocsp_response(ocsp_response, &path[0], unix_time_millis(now)? / 1000)
+                .map_err(TlsError::InvalidOCSPResponse)?;
+        }
+
+        match server_name {
+            ServerName::DnsName(dns_name) => cert
+                .verify_is_valid_for_dns_name(dns_name.as_webpki())
+                .map_err(|e| match e {
+                    webpki::Error::CertNotValidForName => {
+                        name_mismatch(end_entity, dns_name.as_ref().to_string())
+                    }
+                    _ => invalid_certificate(e),
+                })?,
+            ServerName::IpAddress(ip) => verify_is_valid_for_ip_address(end_entity, *ip)?,
         }
 
-        cert.verify_is_valid_for_dns_name(dns_name)
-            .map_err(|e| TlsError::WebPKIError(e, WebPKIOp::ValidateForDNSName))
+        let verified = ServerCertVerified::assertion().with_scts(scts);
+        Ok(match path {
+            Ok(path) => verified.with_intermediates(path_intermediates(&path)),
+            Err(_) => verified,
+        })
+    }
+}
+
+/// Adapts a `ServerCertPolicy` to be a `ServerCertVerifier`, using the
+/// default handshake signature verification.
+pub(crate) struct PolicyVerifier {
+    policy: Arc<dyn ServerCertPolicy>,
+}
+
+impl PolicyVerifier {
+    pub(crate) fn new(policy: Arc<dyn ServerCertPolicy>) -> Self {
+        Self { policy }
+    }
+}
+
+impl ServerCertVerifier for PolicyVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &Certificate,
+        intermediates: &[Certificate],
+        server_name: &ServerName,
+        _scts: &mut dyn Iterator<Item=&[u8]>,
+        ocsp_response: &[u8],
+        now: SystemTime,
+    ) -> Result<ServerCertVerified, TlsError> {
+        self.policy
+            .verify_server_cert(&ServerCertInfo {
+                end_entity,
+                intermediates,
+                server_name,
+                ocsp_response,
+                now,
+            })
             .map(|_| ServerCertVerified::assertion())
     }
 }
@@ -322,6 +535,7 @@ impl ServerCertVerifier for WebPkiVerifier {
 pub struct WebPkiVerifier {
     roots: RootCertStore,
     ct_logs: &'static [&'static sct::Log<'static>],
+    crls: Option<CrlSet>,
 }
 
 impl WebPkiVerifier {
@@ -338,7 +552,24 @@ impl WebPkiVerifier {
     {
         Self {
             roots,
-            ct_logs
+            ct_logs,
+            crls: None,
+        }
+    }
+
+    /// Constructs a new `WebPKIVerifier` which additionally checks
+    /// server certificate chains against `crls`.
+    ///
+    /// `roots` and `ct_logs` are as for `WebPkiVerifier::new`.
+    pub fn new_with_crls(roots: RootCertStore,
+                         ct_logs: &'static [&'static sct::Log<'static>],
+                         crls: CrlSet)
+        -> Self
+    {
+        Self {
+            roots,
+            ct_logs,
+            crls: Some(crls),
         }
     }
 
@@ -352,6 +583,9 @@ impl WebPkiVerifier {
             SignatureScheme::RSA_PSS_SHA512,
             SignatureScheme::RSA_PSS_SHA384,
             SignatureScheme::RSA_PSS_SHA256,
+            SignatureScheme::RSA_PSS_PSS_SHA512,
+            SignatureScheme::RSA_PSS_PSS_SHA384,
+            SignatureScheme::RSA_PSS_PSS_SHA256,
             SignatureScheme::RSA_PKCS1_SHA512,
             SignatureScheme::RSA_PKCS1_SHA384,
             SignatureScheme::RSA_PKCS1_SHA256,
@@ -371,7 +605,7 @@ fn prepare<'a, 'b>(
     roots: &'b RootCertStore,
 ) -> Result<CertChainAndRoots<'a, 'b>, TlsError> {
     // EE cert must appear first.
-    let cert = webpki::EndEntityCert::from(&end_entity.0).map_err(|e| TlsError::WebPKIError(e, WebPKIOp::ParseEndEntity))?;
+    let cert = webpki::EndEntityCert::from(&end_entity.0).map_err(invalid_certificate)?;
 
     let intermediates: Vec<&'a [u8]> = intermediates.iter().map(|cert| cert.0.as_ref()).collect();
 
@@ -384,10 +618,37 @@ fn prepare<'a, 'b>(
     Ok((cert, intermediates, trustroots))
 }
 
+/// webpki can't validate a certificate for an IP address, so we
+/// look for a matching iPAddress subject alternative name ourselves.
+fn verify_is_valid_for_ip_address(cert: &Certificate, ip: IpAddr) -> Result<(), TlsError> {
+    let octets = match ip {
+        IpAddr::V4(ip) => ip.octets().to_vec(),
+        IpAddr::V6(ip) => ip.octets().to_vec(),
+    };
+
+    match x509::has_ip_address_san(&cert.0, &octets) {
+        Ok(true) => Ok(()),
+        Ok(false) => Err(name_mismatch(cert, ip.to_string())),
+        Err(_) => Err(TlsError::InvalidCertificate(CertificateError::BadEncoding)),
+    }
+}
+
+fn name_mismatch(cert: &Certificate, expected: String) -> TlsError {
+    TlsError::InvalidCertificate(CertificateError::NameMismatch {
+        expected,
+        found: x509::subject_alt_names(&cert.0).unwrap_or_default(),
+    })
+}
+
+fn invalid_certificate(err: webpki::Error) -> TlsError {
+    TlsError::InvalidCertificate(err.into())
+}
+
 /// A `ClientCertVerifier` that will ensure that every client provides a trusted
 /// certificate, without any name checking.
 pub struct AllowAnyAuthenticatedClient {
     roots: RootCertStore,
+    crls: Option<CrlSet>,
 }
 
 impl AllowAnyAuthenticatedClient {
@@ -395,7 +656,15 @@ impl AllowAnyAuthenticatedClient {
     ///
     /// `roots` is the list of trust anchors to use for certificate validation.
     pub fn new(roots: RootCertStore) -> Arc<dyn ClientCertVerifier> {
-        Arc::new(AllowAnyAuthenticatedClient { roots })
+        Arc::new(AllowAnyAuthenticatedClient { roots, crls: None })
+    }
+
+    /// Construct a new `AllowAnyAuthenticatedClient` which additionally
+    /// checks client certificate chains against `crls`.
+    ///
+    /// `roots` is the list of trust anchors to use for certificate validation.
+    pub fn new_with_crls(roots: RootCertStore, crls: CrlSet) -> Arc<dyn ClientCertVerifier> {
+        Arc::new(AllowAnyAuthenticatedClient { roots, crls: Some(crls) })
     }
 }
 
@@ -404,13 +673,13 @@ impl ClientCertVerifier for AllowAnyAuthenticatedClient {
         true
     }
 
-    fn client_auth_mandatory(&self, _sni: Option<&webpki::DNSName>) -> Option<bool> {
+    fn client_auth_mandatory(&self, _sni: Option<&DnsName>) -> Option<bool> {
         Some(true)
     }
 
     fn client_auth_root_subjects(
         &self,
-        _sni: Option<&webpki::DNSName>,
+        _sni: Option<&DnsName>,
     ) -> Option<DistinguishedNames> {
         Some(self.roots.get_subjects())
     }
@@ -419,22 +688,45 @@ impl ClientCertVerifier for AllowAnyAuthenticatedClient {
         &self,
         end_entity: &Certificate,
         intermediates: &[Certificate],
-        _sni: Option<&webpki::DNSName>,
+        _sni: Option<&DnsName>,
         now: SystemTime,
     ) -> Result<ClientCertVerified, TlsError> {
         let (cert, chain, trustroots) = prepare(end_entity, intermediates, &self.roots)?;
-        let now = webpki::Time::try_from(now).map_err(|_| TlsError::FailedToGetCurrentTime)?;
+        let webpki_now = webpki::Time::try_from(now).map_err(|_| TlsError::FailedToGetCurrentTime)?;
         cert.verify_is_valid_tls_client_cert(
             SUPPORTED_SIG_ALGS,
             &webpki::TLSClientTrustAnchors(&trustroots),
             &chain,
-            now,
+            webpki_now,
         )
-        .map_err(|e| TlsError::WebPKIError(e, WebPKIOp::ValidateClientCert))
-        .map(|_| ClientCertVerified::assertion())
+        .map_err(invalid_certificate)?;
+
+        let path = x509::build_chain(&end_entity.0, intermediates, &self.roots);
+        if let Some(crls) = &self.crls {
+            let path = path
+                .as_ref()
+                .map_err(|err| TlsError::CRLError((*err).into()))?;
+            crls.check(path, unix_time_millis(now)? / 1000)
+                .map_err(TlsError::CRLError)?;
+        }
+
+        let verified = ClientCertVerified::assertion();
+        Ok(match path {
+            Ok(path) => verified.with_intermediates(path_intermediates(&path)),
+            Err(_) => verified,
+        })
     }
 }
 
+/// The intermediate certificates on `path`, in order from the
+/// end-entity certificate's issuer.
+fn path_intermediates(path: &[x509::ChainLink]) -> Vec<Certificate> {
+    path.iter()
+        .filter_map(|link| link.issuer.certificate)
+        .map(|der| Certificate(der.to_vec()))
+        .collect()
+}
+
 /// A `ClientCertVerifier` that will allow both anonymous and authenticated
 /// clients, without any name checking.
 ///
@@ -451,7 +743,17 @@ impl AllowAnyAnonymousOrAuthenticatedClient {
     /// `roots` is the list of trust anchors to use for certificate validation.
     pub fn new(roots: RootCertStore) -> Arc<dyn ClientCertVerifier> {
         Arc::new(AllowAnyAnonymousOrAuthenticatedClient {
-            inner: AllowAnyAuthenticatedClient { roots },
+            inner: AllowAnyAuthenticatedClient { roots, crls: None },
+        })
+    }
+
+    /// Construct a new `AllowAnyAnonymousOrAuthenticatedClient` which
+    /// additionally checks any client certificate chain against `crls`.
+    ///
+    /// `roots` is the list of trust anchors to use for certificate validation.
+    pub fn new_with_crls(roots: RootCertStore, crls: CrlSet) -> Arc<dyn ClientCertVerifier> {
+        Arc::new(AllowAnyAnonymousOrAuthenticatedClient {
+            inner: AllowAnyAuthenticatedClient { roots, crls: Some(crls) },
         })
     }
 }
@@ -461,13 +763,13 @@ impl ClientCertVerifier for AllowAnyAnonymousOrAuthenticatedClient {
         self.inner.offer_client_auth()
     }
 
-    fn client_auth_mandatory(&self, _sni: Option<&webpki::DNSName>) -> Option<bool> {
+    fn client_auth_mandatory(&self, _sni: Option<&DnsName>) -> Option<bool> {
         Some(false)
     }
 
     fn client_auth_root_subjects(
         &self,
-        sni: Option<&webpki::DNSName>,
+        sni: Option<&DnsName>,
     ) -> Option<DistinguishedNames> {
         self.inner.client_auth_root_subjects(sni)
     }
@@ -476,7 +778,7 @@ impl ClientCertVerifier for AllowAnyAnonymousOrAuthenticatedClient {
         &self,
         end_entity: &Certificate,
         intermediates: &[Certificate],
-        sni: Option<&webpki::DNSName>,
+        sni: Option<&DnsName>,
         now: SystemTime,
     ) -> Result<ClientCertVerified, TlsError> {
         self.inner
@@ -484,6 +786,88 @@ impl ClientCertVerifier for AllowAnyAnonymousOrAuthenticatedClient {
     }
 }
 
+/// A `ClientCertVerifier` which chooses another verifier based on the
+/// server name (SNI) the client asked for, so that different virtual hosts
+/// can trust different roots or make client authentication optional or
+/// mandatory.
+///
+/// The handshake signature checks and supported signature schemes are
+/// the default ones provided by the `ClientCertVerifier` trait.
+pub struct ClientCertVerifierUsingSni {
+    by_name: collections::HashMap<String, Arc<dyn ClientCertVerifier>>,
+    default: Arc<dyn ClientCertVerifier>,
+}
+
+impl ClientCertVerifierUsingSni {
+    /// Create a new verifier which uses `default` for clients which
+    /// don't send SNI, or ask for a name which hasn't been added.
+    pub fn new(default: Arc<dyn ClientCertVerifier>) -> ClientCertVerifierUsingSni {
+        ClientCertVerifierUsingSni {
+            by_name: collections::HashMap::new(),
+            default,
+        }
+    }
+
+    /// Use `verifier` for clients which ask for the SNI `name`.
+    ///
+    /// This function fails if `name` is not a valid DNS name.
+    pub fn add(&mut self, name: &str, verifier: Arc<dyn ClientCertVerifier>) -> Result<(), TlsError> {
+        webpki::DNSNameRef::try_from_ascii_str(name)
+            .map_err(|_| TlsError::General("Bad DNS name".into()))?;
+        self.by_name.insert(name.into(), verifier);
+        Ok(())
+    }
+
+    fn select(&self, sni: Option<&DnsName>) -> &dyn ClientCertVerifier {
+        let name: Option<&str> = sni.map(|name| name.as_ref());
+        name.and_then(|name| self.by_name.get(name))
+            .unwrap_or(&self.default)
+            .as_ref()
+    }
+}
+
+impl ClientCertVerifier for ClientCertVerifierUsingSni {
+    fn offer_client_auth(&self) -> bool {
+        self.default.offer_client_auth()
+            || self
+                .by_name
+                .values()
+                .any(|verifier| verifier.offer_client_auth())
+    }
+
+    fn offer_client_auth_for_sni(&self, sni: Option<&DnsName>) -> bool {
+        let verifier = self.select(sni);
+        verifier.offer_client_auth() && verifier.offer_client_auth_for_sni(sni)
+    }
+
+    fn client_auth_mandatory(&self, sni: Option<&DnsName>) -> Option<bool> {
+        self.select(sni).client_auth_mandatory(sni)
+    }
+
+    fn client_cert_failure_is_fatal(&self, err: &TlsError, sni: Option<&DnsName>) -> bool {
+        self.select(sni)
+            .client_cert_failure_is_fatal(err, sni)
+    }
+
+    fn client_auth_root_subjects(
+        &self,
+        sni: Option<&DnsName>,
+    ) -> Option<DistinguishedNames> {
+        self.select(sni).client_auth_root_subjects(sni)
+    }
+
+    fn verify_client_cert(
+        &self,
+        end_entity: &Certificate,
+        intermediates: &[Certificate],
+        sni: Option<&DnsName>,
+        now: SystemTime,
+    ) -> Result<ClientCertVerified, TlsError> {
+        self.select(sni)
+            .verify_client_cert(end_entity, intermediates, sni, now)
+    }
+}
+
 /// Turns off client authentication.
 pub struct NoClientAuth;
 
@@ -501,7 +885,7 @@ impl ClientCertVerifier for NoClientAuth {
 
     fn client_auth_root_subjects(
         &self,
-        _sni: Option<&webpki::DNSName>,
+        _sni: Option<&DnsName>,
     ) -> Option<DistinguishedNames> {
         unimplemented!();
     }
@@ -510,13 +894,56 @@ impl ClientCertVerifier for NoClientAuth {
         &self,
         _end_entity: &Certificate,
         _intermediates: &[Certificate],
-        _sni: Option<&webpki::DNSName>,
+        _sni: Option<&DnsName>,
         _now: SystemTime,
     ) -> Result<ClientCertVerified, TlsError> {
         unimplemented!();
     }
 }
 
+/// Something that can check whether any certificate in a peer's
+/// chain has been revoked.
+///
+/// This is called once the configured `ServerCertVerifier` or
+/// `ClientCertVerifier` has accepted the chain, so implementations can
+/// consult live OCSP responders, CRLite filters or CRLSets, or an
+/// internal revocation database, without reimplementing path building.
+/// It is not called for resumed sessions.
+pub trait RevocationChecker: Send + Sync {
+    /// Check the peer's `end_entity` certificate and `intermediates`
+    /// at time `now`.
+    ///
+    /// `intermediates` is the path the verifier accepted, starting
+    /// with `end_entity`'s issuer and not including the trust anchor.
+    /// The built-in verifiers always report this path; for a custom
+    /// verifier that doesn't (see `ServerCertVerified::with_intermediates`),
+    /// it is the certificates the peer sent, in the order it sent them.
+    ///
+    /// Return `TlsError::InvalidCertificate(CertificateError::Revoked)`
+    /// if a certificate has been revoked.
+    /// Any other error also aborts the handshake.
+    fn check_revocation(
+        &self,
+        end_entity: &Certificate,
+        intermediates: &[Certificate],
+        now: SystemTime,
+    ) -> Result<(), TlsError>;
+}
+
+/// A `RevocationChecker` which accepts every chain.  This is the default.
+pub struct NoRevocationChecking {}
+
+impl RevocationChecker for NoRevocationChecking {
+    fn check_revocation(
+        &self,
+        _end_entity: &Certificate,
+        _intermediates: &[Certificate],
+        _now: SystemTime,
+    ) -> Result<(), TlsError> {
+        Ok(())
+    }
+}
+
 static ECDSA_SHA256: SignatureAlgorithms =
     &[&webpki::ECDSA_P256_SHA256, &webpki::ECDSA_P384_SHA256];
 
@@ -532,6 +959,10 @@ static RSA_PSS_SHA256: SignatureAlgorithms = &[&webpki::RSA_PSS_2048_8192_SHA256
 static RSA_PSS_SHA384: SignatureAlgorithms = &[&webpki::RSA_PSS_2048_8192_SHA384_LEGACY_KEY];
 static RSA_PSS_SHA512: SignatureAlgorithms = &[&webpki::RSA_PSS_2048_8192_SHA512_LEGACY_KEY];
 
+// webpki can't use id-RSASSA-PSS keys, which are the only ones these
+// schemes are for.  Those are handled by verify_rsa_pss_key instead.
+static RSA_PSS_PSS: SignatureAlgorithms = &[];
+
 fn convert_scheme(scheme: SignatureScheme) -> Result<SignatureAlgorithms, TlsError> {
     match scheme {
         // nb. for TLS1.2 the curve is not fixed by SignatureScheme.
@@ -548,9 +979,15 @@ fn convert_scheme(scheme: SignatureScheme) -> Result<SignatureAlgorithms, TlsErr
         SignatureScheme::RSA_PSS_SHA384 => Ok(RSA_PSS_SHA384),
         SignatureScheme::RSA_PSS_SHA512 => Ok(RSA_PSS_SHA512),
 
+        SignatureScheme::RSA_PSS_PSS_SHA256
+        | SignatureScheme::RSA_PSS_PSS_SHA384
+        | SignatureScheme::RSA_PSS_PSS_SHA512 => Ok(RSA_PSS_PSS),
+
         _ => {
-            let error_msg = format!("received unadvertised sig scheme {:?}", scheme);
-            Err(TlsError::PeerMisbehavedError(error_msg))
+            warn!("received unadvertised sig scheme {:?}", scheme);
+            Err(TlsError::PeerMisbehavedError(
+                PeerMisbehaved::SignedHandshakeWithUnadvertisedSigScheme,
+            ))
         }
     }
 }
@@ -579,13 +1016,52 @@ fn verify_signed_struct(
     dss: &DigitallySignedStruct,
 ) -> Result<HandshakeSignatureValid, TlsError> {
     let possible_algs = convert_scheme(dss.scheme)?;
-    let cert = webpki::EndEntityCert::from(&cert.0).map_err(|e| TlsError::WebPKIError(e, WebPKIOp::ParseEndEntity))?;
+    if let Some(result) = verify_rsa_pss_key(message, cert, dss) {
+        return result;
+    }
+
+    let cert = webpki::EndEntityCert::from(&cert.0).map_err(invalid_certificate)?;
 
     verify_sig_using_any_alg(&cert, possible_algs, message, &dss.sig.0)
-        .map_err(|e| TlsError::WebPKIError(e, WebPKIOp::VerifySignature))
+        .map_err(invalid_certificate)
         .map(|_| HandshakeSignatureValid::assertion())
 }
 
+/// Verify a signature made by `cert`'s key, if it is an id-RSASSA-PSS
+/// key.  webpki only supports RSA keys with the rsaEncryption algorithm.
+///
+/// Returns None if `cert` has some other type of key.
+fn verify_rsa_pss_key(
+    message: &[u8],
+    cert: &Certificate,
+    dss: &DigitallySignedStruct,
+) -> Option<Result<HandshakeSignatureValid, TlsError>> {
+    let key = match x509::RsaPssKey::from_certificate(&cert.0) {
+        Ok(Some(key)) => key,
+        Ok(None) => return None,
+        Err(_) => return Some(Err(TlsError::InvalidCertificate(CertificateError::BadEncoding))),
+    };
+
+    let unsupported = TlsError::InvalidCertificate(CertificateError::UnsupportedSignatureAlgorithm);
+    let (alg, hash) = match dss.scheme {
+        SignatureScheme::RSA_PSS_PSS_SHA256 => (&signature::RSA_PSS_2048_8192_SHA256, x509::OID_SHA256),
+        SignatureScheme::RSA_PSS_PSS_SHA384 => (&signature::RSA_PSS_2048_8192_SHA384, x509::OID_SHA384),
+        SignatureScheme::RSA_PSS_PSS_SHA512 => (&signature::RSA_PSS_2048_8192_SHA512, x509::OID_SHA512),
+        _ => return Some(Err(unsupported)),
+    };
+
+    if !key.allows_hash(hash) {
+        return Some(Err(unsupported));
+    }
+
+    Some(
+        signature::UnparsedPublicKey::new(alg, key.key)
+            .verify(message, &dss.sig.0)
+            .map(|_| HandshakeSignatureValid::assertion())
+            .map_err(|_| TlsError::InvalidCertificate(CertificateError::BadSignature)),
+    )
+}
+
 fn convert_alg_tls13(
     scheme: SignatureScheme,
 ) -> Result<&'static webpki::SignatureAlgorithm, TlsError> {
@@ -598,23 +1074,31 @@ fn convert_alg_tls13(
         RSA_PSS_SHA256 => Ok(&webpki::RSA_PSS_2048_8192_SHA256_LEGACY_KEY),
         RSA_PSS_SHA384 => Ok(&webpki::RSA_PSS_2048_8192_SHA384_LEGACY_KEY),
         RSA_PSS_SHA512 => Ok(&webpki::RSA_PSS_2048_8192_SHA512_LEGACY_KEY),
+        RSA_PSS_PSS_SHA256 | RSA_PSS_PSS_SHA384 | RSA_PSS_PSS_SHA512 => Err(
+            TlsError::InvalidCertificate(CertificateError::UnsupportedSignatureAlgorithm),
+        ),
         _ => {
-            let error_msg = format!("received unsupported sig scheme {:?}", scheme);
-            Err(TlsError::PeerMisbehavedError(error_msg))
+            warn!("received unsupported sig scheme {:?}", scheme);
+            Err(TlsError::PeerMisbehavedError(
+                PeerMisbehaved::SignedHandshakeWithUnadvertisedSigScheme,
+            ))
         }
     }
 }
 
 /// Constructs the signature message specified in section 4.4.3 of RFC8446.
+#[cfg(feature = "tls13")]
 pub fn construct_tls13_client_verify_message(handshake_hash: &Digest) -> Vec<u8> {
     construct_tls13_verify_message(handshake_hash, b"TLS 1.3, client CertificateVerify\x00")
 }
 
 /// Constructs the signature message specified in section 4.4.3 of RFC8446.
+#[cfg(feature = "tls13")]
 pub fn construct_tls13_server_verify_message(handshake_hash: &Digest) -> Vec<u8> {
     construct_tls13_verify_message(handshake_hash, b"TLS 1.3, server CertificateVerify\x00")
 }
 
+#[cfg(feature = "tls13")]
 fn construct_tls13_verify_message(handshake_hash: &Digest, context_string_with_0: &[u8]) -> Vec<u8> {
     let mut msg = Vec::new();
     msg.resize(64, 0x20u8);
@@ -628,13 +1112,15 @@ fn verify_tls13(
     cert: &Certificate,
     dss: &DigitallySignedStruct,
 ) -> Result<HandshakeSignatureValid, TlsError> {
-    let alg = convert_alg_tls13(dss.scheme)?;
-
+    if let Some(result) = verify_rsa_pss_key(msg, cert, dss) {
+        return result;
+    }
 
-    let cert = webpki::EndEntityCert::from(&cert.0).map_err(|e| TlsError::WebPKIError(e, WebPKIOp::ParseEndEntity))?;
+    let alg = convert_alg_tls13(dss.scheme)?;
+    let cert = webpki::EndEntityCert::from(&cert.0).map_err(invalid_certificate)?;
 
     cert.verify_signature(alg, &msg, &dss.sig.0)
-        .map_err(|e| TlsError::WebPKIError(e, WebPKIOp::VerifySignature))
+        .map_err(invalid_certificate)
         .map(|_| HandshakeSignatureValid::assertion())
 }
 
@@ -652,13 +1138,14 @@ fn unix_time_millis(now: SystemTime) -> Result<u64, TlsError> {
 fn verify_scts(cert: &Certificate,
                now: SystemTime,
                scts: &mut dyn Iterator<Item=&[u8]>, logs: &[&sct::Log])
-    -> Result<(), TlsError>
+    -> Result<Vec<ValidatedSct>, TlsError>
 {
     if logs.is_empty() {
-        return Ok(());
+        return Ok(Vec::new());
     }
 
     let now = unix_time_millis(now)?;
+    let mut valid_scts = Vec::new();
     let mut last_sct_error = None;
     for sct in scts {
         #[cfg_attr(not(feature = "logging"), allow(unused_variables))]
@@ -668,9 +1155,14 @@ fn verify_scts(cert: &Certificate,
                     "Valid SCT signed by {} on {}",
                     logs[index].operated_by, logs[index].description
                 );
-                return Ok(());
+                valid_scts.extend(ValidatedSct::from_verified(sct));
             }
             Err(e) => {
+                // Once one SCT is valid, problems with the others don't matter.
+                if !valid_scts.is_empty() {
+                    debug!("SCT ignored because {:?}", e);
+                    continue;
+                }
                 if e.should_be_fatal() {
                     return Err(TlsError::InvalidSCT(e));
                 }
@@ -682,10 +1174,12 @@ fn verify_scts(cert: &Certificate,
 
     /* If we were supplied with some logs, and some SCTs,
      * but couldn't verify any of them, fail the handshake. */
-    if let Some(last_sct_error) = last_sct_error {
-        warn!("No valid SCTs provided");
-        return Err(TlsError::InvalidSCT(last_sct_error));
+    if valid_scts.is_empty() {
+        if let Some(last_sct_error) = last_sct_error {
+            warn!("No valid SCTs provided");
+            return Err(TlsError::InvalidSCT(last_sct_error));
+        }
     }
 
-    Ok(())
+    Ok(valid_scts)
 }
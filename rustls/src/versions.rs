@@ -0,0 +1,101 @@
+use crate::msgs::enums::ProtocolVersion;
+
+use std::fmt;
+
+/// A TLS protocol version supported by rustls.
+///
+/// All possible instances of this type are provided by the library in
+/// the `rustls::version` module, so an unsupported version can't be
+/// configured by mistake.
+#[derive(PartialEq)]
+#[non_exhaustive]
+pub struct SupportedProtocolVersion {
+    /// The TLS enumeration naming this version.
+    pub version: ProtocolVersion,
+}
+
+impl fmt::Debug for SupportedProtocolVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.version.fmt(f)
+    }
+}
+
+/// TLS1.2
+#[cfg(feature = "tls12")]
+pub static TLS12: SupportedProtocolVersion = SupportedProtocolVersion {
+    version: ProtocolVersion::TLSv1_2,
+};
+
+/// TLS1.3
+#[cfg(feature = "tls13")]
+pub static TLS13: SupportedProtocolVersion = SupportedProtocolVersion {
+    version: ProtocolVersion::TLSv1_3,
+};
+
+/// A list of all the protocol versions supported by rustls.
+pub static ALL_VERSIONS: &[&SupportedProtocolVersion] = &[
+    #[cfg(feature = "tls13")]
+    &TLS13,
+    #[cfg(feature = "tls12")]
+    &TLS12,
+];
+
+/// The protocol versions an application should enable by default.
+///
+/// This will be `ALL_VERSIONS` for now, but gives space in the future
+/// to support a new version but not enable it by default.
+pub static DEFAULT_VERSIONS: &[&SupportedProtocolVersion] = ALL_VERSIONS;
+
+/// The set of protocol versions enabled in a config.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) struct EnabledVersions {
+    #[cfg(feature = "tls12")]
+    tls12: bool,
+    #[cfg(feature = "tls13")]
+    tls13: bool,
+}
+
+impl EnabledVersions {
+    pub(crate) fn new(versions: &[&'static SupportedProtocolVersion]) -> EnabledVersions {
+        EnabledVersions {
+            #[cfg(feature = "tls12")]
+            tls12: versions.contains(&&TLS12),
+            #[cfg(feature = "tls13")]
+            tls13: versions.contains(&&TLS13),
+        }
+    }
+
+    pub(crate) fn contains(&self, version: ProtocolVersion) -> bool {
+        match version {
+            #[cfg(feature = "tls12")]
+            ProtocolVersion::TLSv1_2 => self.tls12,
+            #[cfg(feature = "tls13")]
+            ProtocolVersion::TLSv1_3 => self.tls13,
+            _ => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_enabled_versions() {
+        let all = EnabledVersions::new(ALL_VERSIONS);
+        assert_eq!(all.contains(ProtocolVersion::TLSv1_2), cfg!(feature = "tls12"));
+        assert_eq!(all.contains(ProtocolVersion::TLSv1_3), cfg!(feature = "tls13"));
+        assert!(!all.contains(ProtocolVersion::TLSv1_1));
+
+        #[cfg(feature = "tls13")]
+        {
+            let tls13 = EnabledVersions::new(&[&TLS13]);
+            assert!(!tls13.contains(ProtocolVersion::TLSv1_2));
+            assert!(tls13.contains(ProtocolVersion::TLSv1_3));
+        }
+
+        let none = EnabledVersions::new(&[]);
+        assert!(!none.contains(ProtocolVersion::TLSv1_2));
+        assert!(!none.contains(ProtocolVersion::TLSv1_3));
+    }
+}
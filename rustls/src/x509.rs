@@ -1,6 +1,61 @@
 // Additional x509/asn1 functions to those provided in webpki/ring.
 
+use crate::anchors::RootCertStore;
+use crate::key::Certificate;
+
+use ring::digest;
 use ring::io::der;
+use ring::signature;
+
+use std::net::IpAddr;
+
+const OID_RSA_ENCRYPTION: &[u8] = &[0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x01, 0x01];
+const OID_SHA256_WITH_RSA: &[u8] = &[0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x01, 0x0b];
+const OID_SHA384_WITH_RSA: &[u8] = &[0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x01, 0x0c];
+const OID_SHA512_WITH_RSA: &[u8] = &[0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x01, 0x0d];
+const OID_RSASSA_PSS: &[u8] = &[0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x01, 0x0a];
+const OID_MD5_WITH_RSA: &[u8] = &[0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x01, 0x04];
+const OID_SHA1_WITH_RSA: &[u8] = &[0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x01, 0x05];
+
+pub(crate) const OID_SHA1: &[u8] = &[0x2b, 0x0e, 0x03, 0x02, 0x1a];
+pub(crate) const OID_SHA256: &[u8] = &[0x60, 0x86, 0x48, 0x01, 0x65, 0x03, 0x04, 0x02, 0x01];
+pub(crate) const OID_SHA384: &[u8] = &[0x60, 0x86, 0x48, 0x01, 0x65, 0x03, 0x04, 0x02, 0x02];
+pub(crate) const OID_SHA512: &[u8] = &[0x60, 0x86, 0x48, 0x01, 0x65, 0x03, 0x04, 0x02, 0x03];
+
+const OID_EC_PUBLIC_KEY: &[u8] = &[0x2a, 0x86, 0x48, 0xce, 0x3d, 0x02, 0x01];
+const OID_ECDSA_WITH_SHA256: &[u8] = &[0x2a, 0x86, 0x48, 0xce, 0x3d, 0x04, 0x03, 0x02];
+const OID_ECDSA_WITH_SHA1: &[u8] = &[0x2a, 0x86, 0x48, 0xce, 0x3d, 0x04, 0x01];
+const OID_ECDSA_WITH_SHA384: &[u8] = &[0x2a, 0x86, 0x48, 0xce, 0x3d, 0x04, 0x03, 0x03];
+const OID_ECDSA_WITH_SHA512: &[u8] = &[0x2a, 0x86, 0x48, 0xce, 0x3d, 0x04, 0x03, 0x04];
+const OID_CURVE_P256: &[u8] = &[0x2a, 0x86, 0x48, 0xce, 0x3d, 0x03, 0x01, 0x07];
+const OID_CURVE_P384: &[u8] = &[0x2b, 0x81, 0x04, 0x00, 0x22];
+
+const OID_ED25519: &[u8] = &[0x2b, 0x65, 0x70];
+
+const OID_CE_SUBJECT_ALT_NAME: &[u8] = &[0x55, 0x1d, 0x11];
+
+pub(crate) const TAG_BOOLEAN: u8 = 0x01;
+pub(crate) const TAG_UTC_TIME: u8 = 0x17;
+pub(crate) const TAG_GENERALIZED_TIME: u8 = 0x18;
+pub(crate) const TAG_CONTEXT_0: u8 = 0xa0;
+pub(crate) const TAG_CONTEXT_1: u8 = 0xa1;
+pub(crate) const TAG_CONTEXT_3: u8 = 0xa3;
+
+/// GeneralName's `dNSName [2] IMPLICIT IA5String`.
+const TAG_DNS_NAME: u8 = 0x82;
+
+/// GeneralName's `iPAddress [7] IMPLICIT OCTET STRING`.
+const TAG_IP_ADDRESS: u8 = 0x87;
+
+/// Errors from the DER parsing and signature checking here.  Callers
+/// map these into their own public error types.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub(crate) enum Error {
+    BadDer,
+    UnsupportedSignatureAlgorithm,
+    BadSignature,
+    UnknownIssuer,
+}
 
 fn wrap_in_asn1_len(bytes: &mut Vec<u8>) {
     let len = bytes.len();
@@ -19,12 +74,754 @@ fn wrap_in_asn1_len(bytes: &mut Vec<u8>) {
     }
 }
 
+/// Encode `value` with the given single-byte `tag`.
+fn encode_tlv(tag: u8, value: &[u8]) -> Vec<u8> {
+    let mut bytes = value.to_vec();
+    wrap_in_asn1_len(&mut bytes);
+    bytes.insert(0, tag);
+    bytes
+}
+
 /// Prepend stuff to `bytes` to put it in a DER SEQUENCE.
 pub fn wrap_in_sequence(bytes: &mut Vec<u8>) {
     wrap_in_asn1_len(bytes);
     bytes.insert(0, der::Tag::Sequence as u8);
 }
 
+/// A minimal DER reader.  Only definite-length encodings
+/// with single-byte tags are accepted.
+pub(crate) struct Reader<'a> {
+    buf: &'a [u8],
+    offs: usize,
+}
+
+impl<'a> Reader<'a> {
+    pub(crate) fn new(buf: &'a [u8]) -> Reader<'a> {
+        Reader { buf, offs: 0 }
+    }
+
+    pub(crate) fn at_end(&self) -> bool {
+        self.offs == self.buf.len()
+    }
+
+    pub(crate) fn peek(&self, tag: u8) -> bool {
+        self.buf.get(self.offs) == Some(&tag)
+    }
+
+    pub(crate) fn take(&mut self, len: usize) -> Result<&'a [u8], Error> {
+        let end = self
+            .offs
+            .checked_add(len)
+            .ok_or(Error::BadDer)?;
+        let bytes = self
+            .buf
+            .get(self.offs..end)
+            .ok_or(Error::BadDer)?;
+        self.offs = end;
+        Ok(bytes)
+    }
+
+    /// Read one TLV, returning the tag, the value and
+    /// the entire encoding (including tag and length).
+    pub(crate) fn read_any(&mut self) -> Result<(u8, &'a [u8], &'a [u8]), Error> {
+        let start = self.offs;
+        let tag = self.take(1)?[0];
+        if tag & 0x1f == 0x1f {
+            return Err(Error::BadDer);
+        }
+
+        let first = self.take(1)?[0];
+        let len = match first {
+            0x00..=0x7f => first as usize,
+            0x81..=0x84 => {
+                let mut len = 0usize;
+                for byte in self.take((first & 0x7f) as usize)? {
+                    len = (len << 8) | (*byte as usize);
+                }
+                len
+            }
+            _ => return Err(Error::BadDer),
+        };
+
+        let value = self.take(len)?;
+        Ok((tag, value, &self.buf[start..self.offs]))
+    }
+
+    pub(crate) fn read_tlv(&mut self, tag: u8) -> Result<(&'a [u8], &'a [u8]), Error> {
+        let (got, value, whole) = self.read_any()?;
+        if got != tag {
+            return Err(Error::BadDer);
+        }
+        Ok((value, whole))
+    }
+
+    pub(crate) fn read(&mut self, tag: u8) -> Result<&'a [u8], Error> {
+        self.read_tlv(tag)
+            .map(|(value, _)| value)
+    }
+
+    pub(crate) fn read_sequence(&mut self) -> Result<Reader<'a>, Error> {
+        self.read(der::Tag::Sequence as u8)
+            .map(Reader::new)
+    }
+
+    pub(crate) fn read_optional(&mut self, tag: u8) -> Result<Option<&'a [u8]>, Error> {
+        if self.peek(tag) {
+            self.read(tag).map(Some)
+        } else {
+            Ok(None)
+        }
+    }
+
+    pub(crate) fn skip(&mut self) -> Result<(), Error> {
+        self.read_any().map(|_| ())
+    }
+
+    /// Read a BIT STRING which must be a whole number of bytes.
+    pub(crate) fn read_bit_string(&mut self) -> Result<&'a [u8], Error> {
+        match self.read(der::Tag::BitString as u8)?.split_first() {
+            Some((0, bits)) => Ok(bits),
+            _ => Err(Error::BadDer),
+        }
+    }
+
+    /// Read an AlgorithmIdentifier, returning the OID and any parameters.
+    pub(crate) fn read_algorithm(&mut self) -> Result<(&'a [u8], Option<&'a [u8]>), Error> {
+        let mut alg = self.read_sequence()?;
+        let oid = alg.read(der::Tag::OID as u8)?;
+        let params = alg.read_optional(der::Tag::OID as u8)?;
+        Ok((oid, params))
+    }
+
+    /// Read a UTCTime or GeneralizedTime, as seconds since the unix epoch.
+    pub(crate) fn read_time(&mut self) -> Result<u64, Error> {
+        if self.peek(TAG_UTC_TIME) {
+            parse_time(self.read(TAG_UTC_TIME)?, false)
+        } else {
+            parse_time(self.read(TAG_GENERALIZED_TIME)?, true)
+        }
+    }
+
+    pub(crate) fn finish(&self) -> Result<(), Error> {
+        if self.at_end() {
+            Ok(())
+        } else {
+            Err(Error::BadDer)
+        }
+    }
+}
+
+/// Parse the `YYMMDDHHMMSSZ` or `YYYYMMDDHHMMSSZ` forms required by DER.
+pub(crate) fn parse_time(bytes: &[u8], generalized: bool) -> Result<u64, Error> {
+    let expect_len = if generalized { 15 } else { 13 };
+    if bytes.len() != expect_len || bytes[expect_len - 1] != b'Z' {
+        return Err(Error::BadDer);
+    }
+
+    let mut digits = bytes[..expect_len - 1].iter();
+    let mut next = |count: usize| -> Result<u64, Error> {
+        let mut value = 0;
+        for _ in 0..count {
+            match digits.next() {
+                Some(d) if d.is_ascii_digit() => value = value * 10 + u64::from(d - b'0'),
+                _ => return Err(Error::BadDer),
+            }
+        }
+        Ok(value)
+    };
+
+    let year = if generalized {
+        next(4)?
+    } else {
+        match next(2)? {
+            yy if yy >= 50 => 1900 + yy,
+            yy => 2000 + yy,
+        }
+    };
+    let (month, day) = (next(2)?, next(2)?);
+    let (hour, minute, second) = (next(2)?, next(2)?, next(2)?);
+
+    if year < 1970
+        || !(1..=12).contains(&month)
+        || !(1..=31).contains(&day)
+        || hour > 23
+        || minute > 59
+        || second > 59
+    {
+        return Err(Error::BadDer);
+    }
+
+    Ok(days_since_epoch(year, month, day) * 86_400 + hour * 3600 + minute * 60 + second)
+}
+
+/// Days from 1970-01-01 to the given civil date, for dates not before 1970.
+fn days_since_epoch(year: u64, month: u64, day: u64) -> u64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year / 400;
+    let year_of_era = year - era * 400;
+    let month_index = (month + 9) % 12;
+    let day_of_year = (153 * month_index + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
+
+/// The parts of a subjectPublicKeyInfo we need to verify signatures.
+pub(crate) struct PublicKey<'a> {
+    pub(crate) algorithm: &'a [u8],
+    pub(crate) params: Option<&'a [u8]>,
+    pub(crate) key: &'a [u8],
+}
+
+impl<'a> PublicKey<'a> {
+    pub(crate) fn parse(spki: &mut Reader<'a>) -> Result<PublicKey<'a>, Error> {
+        let (algorithm, params) = spki.read_algorithm()?;
+        let key = spki.read_bit_string()?;
+        spki.finish()?;
+        Ok(PublicKey {
+            algorithm,
+            params,
+            key,
+        })
+    }
+
+    pub(crate) fn verify(&self, sig_alg: &[u8], message: &[u8], sig: &[u8]) -> Result<(), Error> {
+        let alg: &dyn signature::VerificationAlgorithm =
+            match (self.algorithm, self.params, sig_alg) {
+                (OID_RSA_ENCRYPTION, _, OID_SHA256_WITH_RSA) => {
+                    &signature::RSA_PKCS1_2048_8192_SHA256
+                }
+                (OID_RSA_ENCRYPTION, _, OID_SHA384_WITH_RSA) => {
+                    &signature::RSA_PKCS1_2048_8192_SHA384
+                }
+                (OID_RSA_ENCRYPTION, _, OID_SHA512_WITH_RSA) => {
+                    &signature::RSA_PKCS1_2048_8192_SHA512
+                }
+                (OID_EC_PUBLIC_KEY, Some(OID_CURVE_P256), OID_ECDSA_WITH_SHA256) => {
+                    &signature::ECDSA_P256_SHA256_ASN1
+                }
+                (OID_EC_PUBLIC_KEY, Some(OID_CURVE_P256), OID_ECDSA_WITH_SHA384) => {
+                    &signature::ECDSA_P256_SHA384_ASN1
+                }
+                (OID_EC_PUBLIC_KEY, Some(OID_CURVE_P384), OID_ECDSA_WITH_SHA256) => {
+                    &signature::ECDSA_P384_SHA256_ASN1
+                }
+                (OID_EC_PUBLIC_KEY, Some(OID_CURVE_P384), OID_ECDSA_WITH_SHA384) => {
+                    &signature::ECDSA_P384_SHA384_ASN1
+                }
+                (OID_ED25519, None, OID_ED25519) => &signature::ED25519,
+                _ => return Err(Error::UnsupportedSignatureAlgorithm),
+            };
+
+        signature::UnparsedPublicKey::new(alg, self.key)
+            .verify(message, sig)
+            .map_err(|_| Error::BadSignature)
+    }
+}
+
+/// An RSA key whose algorithm is id-RSASSA-PSS (RFC4055) rather than
+/// rsaEncryption.  Such keys may only make PSS signatures.
+pub(crate) struct RsaPssKey<'a> {
+    /// The DER-encoded RSAPublicKey or RSAPrivateKey.
+    pub(crate) key: &'a [u8],
+
+    /// The hash function the key is restricted to by its parameters.
+    /// None if the key has no parameters, and so no restriction.
+    pub(crate) hash: Option<&'a [u8]>,
+}
+
+impl<'a> RsaPssKey<'a> {
+    /// Look at the subjectPublicKeyInfo of the certificate `cert`.
+    /// Returns None if its key is not an id-RSASSA-PSS key.
+    pub(crate) fn from_certificate(cert: &'a [u8]) -> Result<Option<RsaPssKey<'a>>, Error> {
+        let mut spki = Reader::new(CertInfo::parse(cert)?.spki);
+        let mut algorithm = spki.read_sequence()?;
+        let key = spki.read_bit_string()?;
+        spki.finish()?;
+        Self::from_algorithm(&mut algorithm, key)
+    }
+
+    /// Look at the PKCS#8 PrivateKeyInfo `der`.  Returns None if
+    /// its key is not an id-RSASSA-PSS key.
+    pub(crate) fn from_pkcs8(der: &'a [u8]) -> Result<Option<RsaPssKey<'a>>, Error> {
+        let mut outer = Reader::new(der);
+        let mut info = outer.read_sequence()?;
+        outer.finish()?;
+
+        info.read(der::Tag::Integer as u8)?;
+        let mut algorithm = info.read_sequence()?;
+        let key = info.read(der::Tag::OctetString as u8)?;
+        Self::from_algorithm(&mut algorithm, key)
+    }
+
+    fn from_algorithm(algorithm: &mut Reader<'a>, key: &'a [u8]) -> Result<Option<RsaPssKey<'a>>, Error> {
+        if algorithm.read(der::Tag::OID as u8)? != OID_RSASSA_PSS {
+            return Ok(None);
+        }
+
+        // RSASSA-PSS-params' hashAlgorithm is `[0] EXPLICIT`, defaulting to SHA-1.
+        let hash = match algorithm.read_optional(der::Tag::Sequence as u8)? {
+            Some(params) => {
+                let mut params = Reader::new(params);
+                match params.read_optional(TAG_CONTEXT_0)? {
+                    Some(hash) => {
+                        let mut hash = Reader::new(hash);
+                        let (oid, _) = hash.read_algorithm()?;
+                        hash.finish()?;
+                        Some(oid)
+                    }
+                    None => Some(OID_SHA1),
+                }
+            }
+            None => None,
+        };
+        algorithm.finish()?;
+
+        Ok(Some(RsaPssKey { key, hash }))
+    }
+
+    /// May this key be used for PSS signatures with the hash function `hash`?
+    pub(crate) fn allows_hash(&self, hash: &[u8]) -> bool {
+        match self.hash {
+            Some(restricted) => restricted == hash,
+            None => true,
+        }
+    }
+}
+
+/// Wrap the SEC1 ECPrivateKey (RFC5915) `der` in a PKCS#8 PrivateKeyInfo,
+/// which is the only encoding *ring* accepts.  The curve is taken from
+/// the key's parameters, which must be present.
+pub(crate) fn sec1_to_pkcs8(der: &[u8]) -> Result<Vec<u8>, Error> {
+    let mut outer = Reader::new(der);
+    let mut key = outer.read_sequence()?;
+    outer.finish()?;
+
+    if key.read(der::Tag::Integer as u8)? != [1] {
+        return Err(Error::BadDer);
+    }
+    key.read(der::Tag::OctetString as u8)?;
+
+    let mut params = Reader::new(key.read(TAG_CONTEXT_0)?);
+    let curve = params.read(der::Tag::OID as u8)?;
+    params.finish()?;
+    if curve != OID_CURVE_P256 && curve != OID_CURVE_P384 {
+        return Err(Error::UnsupportedSignatureAlgorithm);
+    }
+
+    let mut algorithm = encode_tlv(der::Tag::OID as u8, OID_EC_PUBLIC_KEY);
+    algorithm.extend(encode_tlv(der::Tag::OID as u8, curve));
+    wrap_in_sequence(&mut algorithm);
+
+    let mut info = encode_tlv(der::Tag::Integer as u8, &[0]);
+    info.extend(algorithm);
+    info.extend(encode_tlv(der::Tag::OctetString as u8, der));
+    wrap_in_sequence(&mut info);
+    Ok(info)
+}
+
+/// The parts of an X.509 certificate needed to check revocation information.
+pub(crate) struct CertInfo<'a> {
+    pub(crate) tbs: &'a [u8],
+    pub(crate) serial: &'a [u8],
+    pub(crate) issuer: &'a [u8],
+    pub(crate) not_before: u64,
+    pub(crate) not_after: u64,
+    pub(crate) subject: &'a [u8],
+    pub(crate) spki: &'a [u8],
+    pub(crate) public_key: PublicKey<'a>,
+    pub(crate) extensions: Option<&'a [u8]>,
+    pub(crate) signature_alg: &'a [u8],
+    pub(crate) signature: &'a [u8],
+}
+
+impl<'a> CertInfo<'a> {
+    pub(crate) fn parse(der: &'a [u8]) -> Result<CertInfo<'a>, Error> {
+        let mut outer = Reader::new(der);
+        let mut cert = outer.read_sequence()?;
+        outer.finish()?;
+
+        let (tbs_value, tbs) = cert.read_tlv(der::Tag::Sequence as u8)?;
+        let (signature_alg, _) = cert.read_algorithm()?;
+        let signature = cert.read_bit_string()?;
+        cert.finish()?;
+
+        let mut tbs_reader = Reader::new(tbs_value);
+        tbs_reader.read_optional(TAG_CONTEXT_0)?;
+        let serial = tbs_reader.read(der::Tag::Integer as u8)?;
+        tbs_reader.skip()?;
+        let (_, issuer) = tbs_reader.read_tlv(der::Tag::Sequence as u8)?;
+        let mut validity = tbs_reader.read_sequence()?;
+        let not_before = validity.read_time()?;
+        let not_after = validity.read_time()?;
+        validity.finish()?;
+        let (_, subject) = tbs_reader.read_tlv(der::Tag::Sequence as u8)?;
+        let spki = tbs_reader.read(der::Tag::Sequence as u8)?;
+        let public_key = PublicKey::parse(&mut Reader::new(spki))?;
+
+        let mut extensions = None;
+        while !tbs_reader.at_end() {
+            let (tag, value, _) = tbs_reader.read_any()?;
+            if tag == TAG_CONTEXT_3 {
+                extensions = Some(value);
+            }
+        }
+
+        Ok(CertInfo {
+            tbs,
+            serial,
+            issuer,
+            not_before,
+            not_after,
+            subject,
+            spki,
+            public_key,
+            extensions,
+            signature_alg,
+            signature,
+        })
+    }
+}
+
+/// Does the certificate `cert` have an iPAddress subject alternative
+/// name equal to `ip`?  `ip` is 4 octets for IPv4, or 16 for IPv6.
+pub(crate) fn has_ip_address_san(cert: &[u8], ip: &[u8]) -> Result<bool, Error> {
+    let mut found = false;
+    for_each_san(cert, |tag, name| found |= tag == TAG_IP_ADDRESS && name == ip)?;
+    Ok(found)
+}
+
+/// The DNS names in the certificate `cert`'s subject alternative
+/// names, in lower case.
+pub(crate) fn dns_name_sans(cert: &[u8]) -> Result<Vec<String>, Error> {
+    let mut names = Vec::new();
+    for_each_san(cert, |tag, name| {
+        if tag == TAG_DNS_NAME {
+            names.push(String::from_utf8_lossy(name).to_ascii_lowercase());
+        }
+    })?;
+    Ok(names)
+}
+
+/// The DNS names and IP addresses in the certificate `cert`'s subject
+/// alternative names, for error messages.
+pub(crate) fn subject_alt_names(cert: &[u8]) -> Result<Vec<String>, Error> {
+    let mut names = Vec::new();
+    for_each_san(cert, |tag, name| match (tag, name.len()) {
+        (TAG_DNS_NAME, _) => names.push(String::from_utf8_lossy(name).into_owned()),
+        (TAG_IP_ADDRESS, 4) => {
+            let mut octets = [0u8; 4];
+            octets.copy_from_slice(name);
+            names.push(IpAddr::from(octets).to_string());
+        }
+        (TAG_IP_ADDRESS, 16) => {
+            let mut octets = [0u8; 16];
+            octets.copy_from_slice(name);
+            names.push(IpAddr::from(octets).to_string());
+        }
+        _ => {}
+    })?;
+    Ok(names)
+}
+
+/// Call `f` with the tag and contents of each GeneralName in the
+/// subject alternative names of the certificate `cert`.
+fn for_each_san<'a>(cert: &'a [u8], mut f: impl FnMut(u8, &'a [u8])) -> Result<(), Error> {
+    let extensions = match CertInfo::parse(cert)?.extensions {
+        Some(extensions) => extensions,
+        None => return Ok(()),
+    };
+
+    let mut outer = Reader::new(extensions);
+    let mut exts = outer.read_sequence()?;
+    outer.finish()?;
+
+    while !exts.at_end() {
+        let mut ext = exts.read_sequence()?;
+        let oid = ext.read(der::Tag::OID as u8)?;
+        ext.read_optional(TAG_BOOLEAN)?;
+        let value = ext.read(der::Tag::OctetString as u8)?;
+        ext.finish()?;
+
+        if oid != OID_CE_SUBJECT_ALT_NAME {
+            continue;
+        }
+
+        let mut outer = Reader::new(value);
+        let mut names = outer.read_sequence()?;
+        outer.finish()?;
+        while !names.at_end() {
+            let (tag, name, _) = names.read_any()?;
+            f(tag, name);
+        }
+    }
+
+    Ok(())
+}
+
+/// The hash function RFC5929 uses for the `tls-server-end-point`
+/// channel binding of `cert`: the one in its signature algorithm, except
+/// that MD5 and SHA-1 are replaced by SHA-256.
+///
+/// Signature algorithms without a single hash function, like Ed25519,
+/// are unsupported.
+pub(crate) fn end_point_hash(cert: &[u8]) -> Result<&'static digest::Algorithm, Error> {
+    let mut outer = Reader::new(cert);
+    let mut cert = outer.read_sequence()?;
+    outer.finish()?;
+
+    cert.read(der::Tag::Sequence as u8)?;
+    let mut algorithm = cert.read_sequence()?;
+    let oid = algorithm.read(der::Tag::OID as u8)?;
+
+    let hash = match oid {
+        OID_RSASSA_PSS => {
+            // RSASSA-PSS-params' hashAlgorithm is `[0] EXPLICIT`, defaulting to SHA-1.
+            let mut params = Reader::new(algorithm.read(der::Tag::Sequence as u8)?);
+            match params.read_optional(TAG_CONTEXT_0)? {
+                Some(hash) => Reader::new(hash).read_algorithm()?.0,
+                None => OID_SHA1,
+            }
+        }
+        OID_MD5_WITH_RSA | OID_SHA1_WITH_RSA | OID_ECDSA_WITH_SHA1 => OID_SHA1,
+        OID_SHA256_WITH_RSA | OID_ECDSA_WITH_SHA256 => OID_SHA256,
+        OID_SHA384_WITH_RSA | OID_ECDSA_WITH_SHA384 => OID_SHA384,
+        OID_SHA512_WITH_RSA | OID_ECDSA_WITH_SHA512 => OID_SHA512,
+        _ => return Err(Error::UnsupportedSignatureAlgorithm),
+    };
+
+    match hash {
+        OID_SHA1 | OID_SHA256 => Ok(&digest::SHA256),
+        OID_SHA384 => Ok(&digest::SHA384),
+        OID_SHA512 => Ok(&digest::SHA512),
+        _ => Err(Error::UnsupportedSignatureAlgorithm),
+    }
+}
+
+/// A certificate that can issue the certificate being checked: either a
+/// presented intermediate, or a trust anchor.
+pub(crate) struct Issuer<'a> {
+    pub(crate) subject: Vec<u8>,
+    pub(crate) public_key: PublicKey<'a>,
+
+    /// The issuer's certificate, if it is one of the intermediates.
+    pub(crate) certificate: Option<&'a [u8]>,
+}
+
+/// A certificate on a chain, and the certificate or trust anchor
+/// whose key verifies its signature.
+pub(crate) struct ChainLink<'a> {
+    pub(crate) cert: CertInfo<'a>,
+    pub(crate) issuer: Issuer<'a>,
+}
+
+/// Rebuild the path from `end_entity` to a trust anchor in `roots`,
+/// trying trust anchors before `intermediates` as webpki does.  Each
+/// certificate's issuer must have the right name and a key which
+/// verifies its signature, so this finds the path webpki accepted,
+/// rather than just the first certificate with a matching name.
+///
+/// The result starts with `end_entity` and ends with the certificate
+/// issued by the trust anchor.
+pub(crate) fn build_chain<'a>(
+    end_entity: &'a [u8],
+    intermediates: &'a [Certificate],
+    roots: &'a RootCertStore,
+) -> Result<Vec<ChainLink<'a>>, Error> {
+    let mut chain = Vec::new();
+    extend_chain(
+        &mut chain,
+        CertInfo::parse(end_entity)?,
+        intermediates,
+        roots,
+    )?;
+    Ok(chain)
+}
+
+fn extend_chain<'a>(
+    chain: &mut Vec<ChainLink<'a>>,
+    mut cert: CertInfo<'a>,
+    intermediates: &'a [Certificate],
+    roots: &'a RootCertStore,
+) -> Result<(), Error> {
+    for issuer in issuers_named(cert.issuer, intermediates, roots) {
+        if issuer
+            .public_key
+            .verify(cert.signature_alg, cert.tbs, cert.signature)
+            .is_err()
+        {
+            continue;
+        }
+
+        let next = match issuer.certificate {
+            Some(next) => next,
+            None => {
+                chain.push(ChainLink { cert, issuer });
+                return Ok(());
+            }
+        };
+
+        // Each intermediate may appear once, so this terminates.
+        if chain
+            .iter()
+            .any(|link| link.issuer.certificate == Some(next))
+        {
+            continue;
+        }
+
+        let next = match CertInfo::parse(next) {
+            Ok(next) => next,
+            Err(_) => continue,
+        };
+
+        chain.push(ChainLink { cert, issuer });
+        if extend_chain(chain, next, intermediates, roots).is_ok() {
+            return Ok(());
+        }
+        cert = chain.pop().unwrap().cert;
+    }
+
+    Err(Error::UnknownIssuer)
+}
+
+/// The trust anchors in `roots`, then the certificates in
+/// `intermediates`, whose subject is `name`.
+fn issuers_named<'a>(
+    name: &[u8],
+    intermediates: &'a [Certificate],
+    roots: &'a RootCertStore,
+) -> Vec<Issuer<'a>> {
+    let mut issuers = Vec::new();
+
+    for root in &roots.roots {
+        let anchor = root.to_trust_anchor();
+        let mut subject = anchor.subject.to_vec();
+        wrap_in_sequence(&mut subject);
+        if subject != name {
+            continue;
+        }
+        if let Ok(public_key) = PublicKey::parse(&mut Reader::new(anchor.spki)) {
+            issuers.push(Issuer {
+                subject,
+                public_key,
+                certificate: None,
+            });
+        }
+    }
+
+    for cert in intermediates {
+        if let Ok(info) = CertInfo::parse(&cert.0) {
+            if info.subject == name {
+                issuers.push(Issuer {
+                    subject: info.subject.to_vec(),
+                    public_key: info.public_key,
+                    certificate: Some(&cert.0),
+                });
+            }
+        }
+    }
+
+    issuers
+}
+
+#[test]
+fn test_parse_time() {
+    assert_eq!(parse_time(b"19700101000000Z", true), Ok(0));
+    assert_eq!(parse_time(b"20000229235959Z", true), Ok(951_868_799));
+    assert_eq!(parse_time(b"491231235959Z", false), Ok(2_524_607_999));
+    assert_eq!(parse_time(b"500101000000Z", false), Err(Error::BadDer));
+    assert_eq!(parse_time(b"20201301000000Z", true), Err(Error::BadDer));
+    assert_eq!(parse_time(b"2020010100000Z", true), Err(Error::BadDer));
+    assert_eq!(parse_time(b"20200101000000+0000", true), Err(Error::BadDer));
+}
+
+#[test]
+fn test_has_ip_address_san() {
+    let cert = include_bytes!("testdata/cert-google.0.der");
+    assert_eq!(has_ip_address_san(cert, &[127, 0, 0, 1]), Ok(false));
+    assert_eq!(has_ip_address_san(&cert[..100], &[127, 0, 0, 1]), Err(Error::BadDer));
+}
+
+#[test]
+fn test_subject_alt_names() {
+    let cert = include_bytes!("testdata/cert-google.0.der");
+    let names = subject_alt_names(cert).unwrap();
+    assert!(names.contains(&"*.google.com".to_string()));
+    assert_eq!(subject_alt_names(&cert[..100]), Err(Error::BadDer));
+}
+
+#[test]
+fn test_build_chain() {
+    let mut roots = RootCertStore::empty();
+    roots
+        .add(&Certificate(include_bytes!("testdata/ocsp-ca.der").to_vec()))
+        .unwrap();
+    let ee = include_bytes!("testdata/ocsp-end.der");
+    let inter = Certificate(include_bytes!("testdata/ocsp-inter.der").to_vec());
+    let unrelated = Certificate(include_bytes!("testdata/cert-google.1.der").to_vec());
+
+    let intermediates = [unrelated, inter.clone()];
+    let chain = build_chain(ee, &intermediates, &roots).unwrap();
+    assert_eq!(chain.len(), 2);
+    assert_eq!(chain[0].issuer.certificate, Some(&inter.0[..]));
+    assert_eq!(chain[1].issuer.certificate, None);
+
+    assert_eq!(
+        build_chain(ee, &intermediates[..1], &roots).err(),
+        Some(Error::UnknownIssuer)
+    );
+}
+
+#[test]
+fn test_rsa_pss_key_parameters() {
+    let end = RsaPssKey::from_certificate(include_bytes!("testdata/rsa-pss-end.der"))
+        .unwrap()
+        .unwrap();
+    assert!(end.hash.is_none());
+    assert!(end.allows_hash(OID_SHA256));
+
+    let client = RsaPssKey::from_certificate(include_bytes!("testdata/rsa-pss-client.der"))
+        .unwrap()
+        .unwrap();
+    assert_eq!(client.hash, Some(OID_SHA384));
+    assert!(!client.allows_hash(OID_SHA256));
+
+    let client_key = RsaPssKey::from_pkcs8(include_bytes!("testdata/rsa-pss-client.key.der"))
+        .unwrap()
+        .unwrap();
+    assert_eq!(client_key.hash, Some(OID_SHA384));
+
+    let rsa = RsaPssKey::from_certificate(include_bytes!("testdata/cert-google.1.der"));
+    assert!(rsa.unwrap().is_none());
+}
+
+#[test]
+fn test_sec1_to_pkcs8() {
+    // RFC5915 ECPrivateKey, with a (truncated) private key and P-256 parameters
+    let sec1 = [
+        0x30, 0x12, 0x02, 0x01, 0x01, 0x04, 0x01, 0x2a, 0xa0, 0x0a, 0x06, 0x08, 0x2a, 0x86, 0x48,
+        0xce, 0x3d, 0x03, 0x01, 0x07,
+    ];
+    let pkcs8 = sec1_to_pkcs8(&sec1).unwrap();
+
+    let mut outer = Reader::new(&pkcs8);
+    let mut info = outer.read_sequence().unwrap();
+    assert!(outer.finish().is_ok());
+    assert_eq!(info.read(der::Tag::Integer as u8), Ok(&[0u8][..]));
+    assert_eq!(info.read_algorithm(), Ok((OID_EC_PUBLIC_KEY, Some(OID_CURVE_P256))));
+    assert_eq!(info.read(der::Tag::OctetString as u8), Ok(&sec1[..]));
+    assert!(info.finish().is_ok());
+
+    // the curve is required
+    assert_eq!(sec1_to_pkcs8(&[0x30, 0x06, 0x02, 0x01, 0x01, 0x04, 0x01, 0x2a]), Err(Error::BadDer));
+    // and must be one we support
+    let mut p521 = sec1.to_vec();
+    p521.truncate(10);
+    p521[1] = 0x0f;
+    p521[9] = 0x07;
+    p521.extend(&[0x06, 0x05, 0x2b, 0x81, 0x04, 0x00, 0x23]);
+    assert_eq!(sec1_to_pkcs8(&p521), Err(Error::UnsupportedSignatureAlgorithm));
+}
+
 #[test]
 fn test_empty() {
     let mut val = Vec::new();
@@ -1,11 +1,15 @@
 // Assorted public API tests.
 use std::env;
 use std::fmt;
-use std::io::{self, IoSlice, Read, Write};
+use std::io::{self, BufRead, IoSlice, Read, Write};
 use std::mem;
+use std::net::{IpAddr, TcpListener, TcpStream};
 use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
 use std::sync::Mutex;
+use std::task::Poll;
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 use rustls;
 
@@ -13,20 +17,28 @@ use rustls;
 use rustls::quic::{self, ClientQuicExt, QuicExt, ServerQuicExt};
 use rustls::sign;
 use rustls::ClientHello;
+use rustls::ConnectionEvents;
+use rustls::HandshakeKind;
 use rustls::KeyLog;
 use rustls::Session;
-use rustls::TlsError;
-use rustls::WebPKIOp;
-use rustls::{CipherSuite, ProtocolVersion, SignatureScheme};
+use rustls::{PeerMisbehaved, TlsError};
+use rustls::{CertificateError, CrlError, CrlSet, UnknownStatusPolicy};
+use rustls::RevocationChecker;
+use rustls::{ServerCertInfo, ServerCertPolicy, ServerName};
+use rustls::{AllowAnyAuthenticatedClient, ClientCertVerifierUsingSni, NoClientAuth};
+use rustls::{AlertDescription, CipherSuite, NamedGroup, ProtocolVersion, SignatureScheme};
 use rustls::{ClientConfig, ClientSession, ResolvesClientCert};
-use rustls::{ResolvesServerCert, ServerConfig, ServerSession};
+use rustls::{Acceptor, ResolvesServerCert, ServerConfig, ServerSession};
 use rustls::{Stream, StreamOwned};
 use rustls::{SupportedCipherSuite, ALL_CIPHERSUITES};
+use rustls::{ActiveKeyExchange, ProvidesKeyExchange, RingKeyExchange, SupportedKxGroup};
+use rustls::{GetRandomFailed, ProvidesRandom, RingRandom};
+use rustls::TimeProvider;
+use rustls::unbuffered::{EncodeError, ProcessStatus, UnbufferedSession};
 
 #[cfg(feature = "dangerous_configuration")]
 use rustls::ClientCertVerified;
 
-use webpki;
 
 #[allow(dead_code)]
 mod common;
@@ -82,9 +94,64 @@ fn alpn() {
     alpn_test(vec![b"PROTO".to_vec()], vec![b"proto".to_vec()], None);
 }
 
+struct H2OnlyFor {
+    host: &'static str,
+    offered: Mutex<Vec<Vec<u8>>>,
+}
+
+impl rustls::SelectsAlpnProtocol for H2OnlyFor {
+    fn select_protocol(&self, sni: Option<&rustls::DnsName>, offered: &[&[u8]]) -> Option<Vec<u8>> {
+        *self.offered.lock().unwrap() = offered.iter().map(|p| p.to_vec()).collect();
+
+        if sni.map(|name| name.as_ref()) == Some(self.host) {
+            Some(b"h2".to_vec())
+        } else {
+            Some(b"http/1.1".to_vec())
+        }
+    }
+}
+
+fn alpn_selector_test(host: &'static str, client_protos: Vec<Vec<u8>>, agreed: Option<&[u8]>) {
+    let mut client_config = make_client_config(KeyType::RSA);
+    let mut server_config = make_server_config(KeyType::RSA);
+
+    client_config.alpn_protocols = client_protos.clone();
+    // ignored in favour of the selector
+    server_config.alpn_protocols = vec![b"server-proto".to_vec()];
+    let selector = Arc::new(H2OnlyFor {
+        host,
+        offered: Mutex::new(vec![]),
+    });
+    server_config.set_alpn_selector(selector.clone());
+
+    let server_config = Arc::new(server_config);
+
+    for client_config in AllClientVersions::new(client_config) {
+        let (mut client, mut server) =
+            make_pair_for_arc_configs(&Arc::new(client_config), &server_config);
+        do_handshake(&mut client, &mut server);
+        assert_eq!(client.get_alpn_protocol(), agreed);
+        assert_eq!(server.get_alpn_protocol(), agreed);
+        assert_eq!(*selector.offered.lock().unwrap(), client_protos);
+    }
+}
+
+#[test]
+fn alpn_selector() {
+    let offered = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+
+    // chosen by SNI
+    alpn_selector_test("localhost", offered.clone(), Some(b"h2"));
+    alpn_selector_test("other.example.com", offered, Some(b"http/1.1"));
+
+    // selecting a protocol the client didn't offer negotiates nothing
+    alpn_selector_test("localhost", vec![b"http/1.1".to_vec()], None);
+    alpn_selector_test("other.example.com", vec![b"h2".to_vec()], None);
+}
+
 fn version_test(
-    client_versions: Vec<ProtocolVersion>,
-    server_versions: Vec<ProtocolVersion>,
+    client_versions: &[&'static rustls::SupportedProtocolVersion],
+    server_versions: &[&'static rustls::SupportedProtocolVersion],
     result: Option<ProtocolVersion>,
 ) {
     let mut client_config = make_client_config(KeyType::RSA);
@@ -96,11 +163,11 @@ fn version_test(
     );
 
     if !client_versions.is_empty() {
-        client_config.versions = client_versions;
+        client_config.set_protocol_versions(client_versions);
     }
 
     if !server_versions.is_empty() {
-        server_config.versions = server_versions;
+        server_config.set_protocol_versions(server_versions);
     }
 
     let (mut client, mut server) = make_pair_for_configs(client_config, server_config);
@@ -117,54 +184,166 @@ fn version_test(
     }
 }
 
+#[cfg(all(feature = "tls12", feature = "tls13"))]
 #[test]
 fn versions() {
     // default -> 1.3
-    version_test(vec![], vec![], Some(ProtocolVersion::TLSv1_3));
+    version_test(&[], &[], Some(ProtocolVersion::TLSv1_3));
 
     // client default, server 1.2 -> 1.2
     version_test(
-        vec![],
-        vec![ProtocolVersion::TLSv1_2],
+        &[],
+        &[&rustls::version::TLS12],
         Some(ProtocolVersion::TLSv1_2),
     );
 
     // client 1.2, server default -> 1.2
     version_test(
-        vec![ProtocolVersion::TLSv1_2],
-        vec![],
+        &[&rustls::version::TLS12],
+        &[],
         Some(ProtocolVersion::TLSv1_2),
     );
 
     // client 1.2, server 1.3 -> fail
     version_test(
-        vec![ProtocolVersion::TLSv1_2],
-        vec![ProtocolVersion::TLSv1_3],
+        &[&rustls::version::TLS12],
+        &[&rustls::version::TLS13],
         None,
     );
 
     // client 1.3, server 1.2 -> fail
     version_test(
-        vec![ProtocolVersion::TLSv1_3],
-        vec![ProtocolVersion::TLSv1_2],
+        &[&rustls::version::TLS13],
+        &[&rustls::version::TLS12],
         None,
     );
 
     // client 1.3, server 1.2+1.3 -> 1.3
     version_test(
-        vec![ProtocolVersion::TLSv1_3],
-        vec![ProtocolVersion::TLSv1_2, ProtocolVersion::TLSv1_3],
+        &[&rustls::version::TLS13],
+        &[&rustls::version::TLS12, &rustls::version::TLS13],
         Some(ProtocolVersion::TLSv1_3),
     );
 
     // client 1.2+1.3, server 1.2 -> 1.2
     version_test(
-        vec![ProtocolVersion::TLSv1_3, ProtocolVersion::TLSv1_2],
-        vec![ProtocolVersion::TLSv1_2],
+        &[&rustls::version::TLS13, &rustls::version::TLS12],
+        &[&rustls::version::TLS12],
         Some(ProtocolVersion::TLSv1_2),
     );
 }
 
+#[test]
+fn builder_configs_handshake() {
+    for kt in ALL_KEY_TYPES.iter() {
+        for version in rustls::ALL_VERSIONS {
+            let client_config = ClientConfig::builder()
+                .with_safe_default_cipher_suites()
+                .with_safe_default_kx_groups()
+                .with_protocol_versions(&[*version])
+                .unwrap()
+                .with_root_certificates(get_client_root_store(*kt), &[])
+                .with_single_cert(kt.get_client_chain(), kt.get_client_key())
+                .unwrap();
+
+            let server_config = ServerConfig::builder()
+                .with_safe_default_cipher_suites()
+                .with_safe_default_kx_groups()
+                .with_safe_default_protocol_versions()
+                .unwrap()
+                .with_client_cert_verifier(AllowAnyAuthenticatedClient::new(
+                    get_client_root_store(*kt),
+                ))
+                .with_single_cert(kt.get_chain(), kt.get_key())
+                .unwrap();
+
+            let (mut client, mut server) = make_pair_for_configs(client_config, server_config);
+            do_handshake(&mut client, &mut server);
+            assert_eq!(client.get_protocol_version(), Some(version.version));
+            assert!(server.get_peer_certificates().is_some());
+        }
+    }
+}
+
+#[cfg(feature = "tls13")]
+#[test]
+fn profile_configs_handshake() {
+    let profiles = [
+        rustls::Profile::Modern,
+        rustls::Profile::Intermediate,
+        rustls::Profile::LegacyCompat,
+    ];
+
+    for kt in ALL_KEY_TYPES.iter() {
+        for client_profile in profiles.iter() {
+            for server_profile in profiles.iter() {
+                let client_config = ClientConfig::builder()
+                    .with_profile(*client_profile)
+                    .with_root_certificates(get_client_root_store(*kt), &[])
+                    .with_no_client_auth();
+                let server_config = ServerConfig::builder()
+                    .with_profile(*server_profile)
+                    .with_no_client_auth()
+                    .with_single_cert(kt.get_chain(), kt.get_key())
+                    .unwrap();
+
+                let (mut client, mut server) = make_pair_for_configs(client_config, server_config);
+                do_handshake(&mut client, &mut server);
+                assert_eq!(client.get_protocol_version(), Some(ProtocolVersion::TLSv1_3));
+            }
+        }
+    }
+}
+
+#[cfg(feature = "tls12")]
+#[test]
+fn modern_profile_refuses_tls12() {
+    let kt = KeyType::RSA;
+    let client_config = ClientConfig::builder()
+        .with_profile(rustls::Profile::Modern)
+        .with_root_certificates(get_client_root_store(kt), &[])
+        .with_no_client_auth();
+    let mut server_config = make_server_config(kt);
+    server_config.set_protocol_versions(&[&rustls::version::TLS12]);
+
+    let (mut client, mut server) = make_pair_for_configs(client_config, server_config);
+    assert!(do_handshake_until_error(&mut client, &mut server).is_err());
+}
+
+#[test]
+fn signature_schemes_restrict_server_signatures() {
+    let kt = KeyType::RSA;
+    for client_config in AllClientVersions::new(make_client_config(kt)) {
+        let mut server_config = make_server_config(kt);
+        server_config.signature_schemes = Some(vec![SignatureScheme::ECDSA_NISTP256_SHA256]);
+
+        let (mut client, mut server) = make_pair_for_configs(client_config, server_config);
+        assert!(do_handshake_until_error(&mut client, &mut server).is_err());
+    }
+
+    for client_config in AllClientVersions::new(make_client_config(kt)) {
+        let mut client_config = client_config;
+        client_config.signature_schemes = Some(vec![SignatureScheme::RSA_PSS_SHA384]);
+
+        let (mut client, mut server) = make_pair_for_configs(client_config, make_server_config(kt));
+        do_handshake(&mut client, &mut server);
+    }
+}
+
+#[test]
+fn signature_schemes_restrict_client_auth() {
+    let kt = KeyType::ECDSA;
+    let mut server_config = make_server_config_with_mandatory_client_auth(kt);
+    server_config.signature_schemes = Some(vec![SignatureScheme::ED25519]);
+    let server_config = Arc::new(server_config);
+
+    for client_config in AllClientVersions::new(make_client_config_with_auth(kt)) {
+        let mut server = ServerSession::new(&server_config);
+        let mut client = ClientSession::new(&Arc::new(client_config), dns_name("localhost")).unwrap();
+        assert!(do_handshake_until_error(&mut client, &mut server).is_err());
+    }
+}
+
 fn check_read(reader: &mut dyn io::Read, bytes: &[u8]) {
     let mut buf = Vec::new();
     assert_eq!(bytes.len(), reader.read_to_end(&mut buf).unwrap());
@@ -266,6 +445,233 @@ fn client_can_get_server_cert_after_resumption() {
     }
 }
 
+#[test]
+fn client_has_no_ocsp_response_unless_stapled() {
+    for kt in ALL_KEY_TYPES.iter() {
+        for client_config in AllClientVersions::new(make_client_config(*kt)) {
+            let (mut client, mut server) =
+                make_pair_for_configs(client_config, make_server_config(*kt));
+            do_handshake(&mut client, &mut server);
+            assert_eq!(client.get_ocsp_response(), None);
+        }
+    }
+}
+
+#[cfg(feature = "dangerous_configuration")]
+#[test]
+fn client_can_get_stapled_ocsp_response() {
+    let ocsp_response = b"not really an ocsp response".to_vec();
+
+    for kt in ALL_KEY_TYPES.iter() {
+        let mut server_config = ServerConfig::new(rustls::NoClientAuth::new());
+        server_config
+            .set_single_cert_with_ocsp_and_sct(
+                kt.get_chain(),
+                kt.get_key(),
+                ocsp_response.clone(),
+                vec![],
+            )
+            .unwrap();
+        let server_config = Arc::new(server_config);
+
+        let mut client_config = make_client_config(*kt);
+        client_config
+            .dangerous()
+            .set_certificate_verifier(Arc::new(MockServerVerifier {
+                expected_ocsp_response: ocsp_response.clone(),
+            }));
+
+        for client_config in AllClientVersions::new(client_config) {
+            let (mut client, mut server) =
+                make_pair_for_arc_configs(&Arc::new(client_config), &server_config);
+            do_handshake(&mut client, &mut server);
+            assert_eq!(client.get_ocsp_response(), Some(&ocsp_response[..]));
+        }
+    }
+}
+
+#[cfg(feature = "dangerous_configuration")]
+#[test]
+fn server_staples_ocsp_response_from_producer() {
+    struct FixedOcspResponse(Vec<u8>);
+
+    impl rustls::ProducesOcspResponses for FixedOcspResponse {
+        fn get_ocsp_response(&self, cert_chain: &[rustls::Certificate]) -> Option<Vec<u8>> {
+            assert!(!cert_chain.is_empty());
+            Some(self.0.clone())
+        }
+    }
+
+    let fresh_ocsp_response = b"fresh ocsp response".to_vec();
+
+    for kt in ALL_KEY_TYPES.iter() {
+        let mut server_config = ServerConfig::new(rustls::NoClientAuth::new());
+        server_config
+            .set_single_cert_with_ocsp_and_sct(
+                kt.get_chain(),
+                kt.get_key(),
+                b"stale ocsp response".to_vec(),
+                vec![],
+            )
+            .unwrap();
+        server_config.set_ocsp_responses(rustls::OcspResponseCache::new(
+            Arc::new(FixedOcspResponse(fresh_ocsp_response.clone())),
+            std::time::Duration::from_secs(3600),
+        ));
+        let server_config = Arc::new(server_config);
+
+        let mut client_config = make_client_config(*kt);
+        client_config
+            .dangerous()
+            .set_certificate_verifier(Arc::new(MockServerVerifier {
+                expected_ocsp_response: fresh_ocsp_response.clone(),
+            }));
+
+        for client_config in AllClientVersions::new(client_config) {
+            let (mut client, mut server) =
+                make_pair_for_arc_configs(&Arc::new(client_config), &server_config);
+            do_handshake(&mut client, &mut server);
+            assert_eq!(client.get_ocsp_response(), Some(&fresh_ocsp_response[..]));
+        }
+    }
+}
+
+#[cfg(feature = "dangerous_configuration")]
+#[test]
+fn client_can_get_stapled_intermediate_ocsp_responses() {
+    struct AlwaysResolves(sign::CertifiedKey);
+
+    impl ResolvesServerCert for AlwaysResolves {
+        fn resolve(&self, _client_hello: ClientHello) -> Option<sign::CertifiedKey> {
+            Some(self.0.clone())
+        }
+    }
+
+    for kt in ALL_KEY_TYPES.iter() {
+        let chain = kt.get_chain();
+        let key = sign::any_supported_type(&kt.get_key()).unwrap();
+        let mut certkey = sign::CertifiedKey::new(chain.clone(), Arc::new(key));
+        certkey.ocsp = Some(b"end-entity ocsp".to_vec());
+        certkey.intermediate_ocsp = vec![Some(b"intermediate ocsp".to_vec())];
+
+        let mut server_config = make_server_config(*kt);
+        server_config.cert_resolver = Arc::new(AlwaysResolves(certkey));
+        let server_config = Arc::new(server_config);
+
+        let mut client_config = make_client_config(*kt);
+        client_config
+            .dangerous()
+            .set_certificate_verifier(Arc::new(MockServerVerifier {
+                expected_ocsp_response: b"end-entity ocsp".to_vec(),
+            }));
+
+        for client_config in AllClientVersions::new(client_config) {
+            let (mut client, mut server) =
+                make_pair_for_arc_configs(&Arc::new(client_config), &server_config);
+            do_handshake(&mut client, &mut server);
+
+            let mut expected = vec![None; chain.len() - 1];
+            if client.get_protocol_version() == Some(ProtocolVersion::TLSv1_3) {
+                expected[0] = Some(&b"intermediate ocsp"[..]);
+            }
+            assert_eq!(client.get_intermediate_ocsp_responses(), expected);
+        }
+    }
+}
+
+/// Make a CT log with a fresh key, and an encoded SCT list containing
+/// one SCT from that log for `cert`.
+fn make_ct_log_and_sct_list(
+    cert: &rustls::Certificate,
+    timestamp: u64,
+) -> (&'static sct::Log<'static>, Vec<u8>) {
+    use ring::signature::{EcdsaKeyPair, KeyPair, ECDSA_P256_SHA256_ASN1_SIGNING};
+
+    let rng = ring::rand::SystemRandom::new();
+    let pkcs8 = EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_ASN1_SIGNING, &rng).unwrap();
+    let key = EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_ASN1_SIGNING, pkcs8.as_ref()).unwrap();
+
+    let log = Box::leak(Box::new(sct::Log {
+        description: "test log",
+        url: "https://ct.example.com/",
+        operated_by: "test",
+        key: Box::leak(key.public_key().as_ref().to_vec().into_boxed_slice()),
+        id: [0x42; 32],
+        max_merge_delay: 86400,
+    }));
+
+    let mut signed = vec![0x00, 0x00];
+    signed.extend_from_slice(&timestamp.to_be_bytes());
+    signed.extend_from_slice(&[0x00, 0x00]);
+    signed.extend_from_slice(&(cert.0.len() as u32).to_be_bytes()[1..]);
+    signed.extend_from_slice(&cert.0);
+    signed.extend_from_slice(&[0x00, 0x00]);
+    let sig = key.sign(&rng, &signed).unwrap();
+
+    let mut sct = vec![0x00];
+    sct.extend_from_slice(&log.id);
+    sct.extend_from_slice(&timestamp.to_be_bytes());
+    sct.extend_from_slice(&[0x00, 0x00, 0x04, 0x03]);
+    sct.extend_from_slice(&(sig.as_ref().len() as u16).to_be_bytes());
+    sct.extend_from_slice(sig.as_ref());
+
+    let mut sct_list = ((sct.len() + 2) as u16).to_be_bytes().to_vec();
+    sct_list.extend_from_slice(&(sct.len() as u16).to_be_bytes());
+    sct_list.extend_from_slice(&sct);
+
+    (log, sct_list)
+}
+
+#[test]
+fn client_can_get_validated_scts() {
+    let timestamp = 1_600_000_000_000;
+
+    for kt in ALL_KEY_TYPES.iter() {
+        let (log, sct_list) = make_ct_log_and_sct_list(&kt.get_chain()[0], timestamp);
+        let logs = Box::leak(vec![log].into_boxed_slice());
+
+        let mut server_config = ServerConfig::new(rustls::NoClientAuth::new());
+        server_config
+            .set_single_cert_with_ocsp_and_sct(kt.get_chain(), kt.get_key(), vec![], sct_list)
+            .unwrap();
+        let server_config = Arc::new(server_config);
+
+        for client_config in AllClientVersions::new(make_client_config_with_ct_logs(*kt, logs)) {
+            let (mut client, mut server) =
+                make_pair_for_arc_configs(&Arc::new(client_config), &server_config);
+            do_handshake(&mut client, &mut server);
+
+            assert_eq!(
+                client.get_validated_scts(),
+                &[rustls::ValidatedSct {
+                    log_id: [0x42; 32],
+                    timestamp,
+                }]
+            );
+        }
+    }
+}
+
+#[test]
+fn client_has_no_validated_scts_without_ct_logs() {
+    for kt in ALL_KEY_TYPES.iter() {
+        let (_, sct_list) = make_ct_log_and_sct_list(&kt.get_chain()[0], 1_600_000_000_000);
+
+        let mut server_config = ServerConfig::new(rustls::NoClientAuth::new());
+        server_config
+            .set_single_cert_with_ocsp_and_sct(kt.get_chain(), kt.get_key(), vec![], sct_list)
+            .unwrap();
+        let server_config = Arc::new(server_config);
+
+        for client_config in AllClientVersions::new(make_client_config(*kt)) {
+            let (mut client, mut server) =
+                make_pair_for_arc_configs(&Arc::new(client_config), &server_config);
+            do_handshake(&mut client, &mut server);
+            assert!(client.get_validated_scts().is_empty());
+        }
+    }
+}
+
 #[test]
 fn server_can_get_client_cert() {
     for kt in ALL_KEY_TYPES.iter() {
@@ -313,52 +719,364 @@ fn server_can_get_client_cert_after_resumption() {
     }
 }
 
-fn check_read_and_close(reader: &mut dyn io::Read, expect: &[u8]) {
-    let mut buf = Vec::new();
-    buf.resize(expect.len(), 0u8);
-    assert_eq!(expect.len(), reader.read(&mut buf).unwrap());
-    assert_eq!(expect.to_vec(), buf);
+#[test]
+fn server_rejects_client_cert_without_crl_coverage() {
+    for kt in ALL_KEY_TYPES.iter() {
+        let client_config = make_client_config_with_auth(*kt);
+        let server_config = Arc::new(make_server_config_with_client_crls(*kt, CrlSet::new()));
 
-    let err = reader.read(&mut buf);
-    assert!(err.is_err());
-    assert_eq!(err.err().unwrap().kind(), io::ErrorKind::ConnectionAborted);
+        for client_config in AllClientVersions::new(client_config) {
+            let (mut client, mut server) =
+                make_pair_for_arc_configs(&Arc::new(client_config), &server_config);
+            let err = do_handshake_until_error(&mut client, &mut server);
+            assert_eq!(
+                err,
+                Err(TLSErrorFromPeer::Server(TlsError::CRLError(CrlError::UnknownStatus)))
+            );
+        }
+    }
 }
 
 #[test]
-fn server_close_notify() {
-    let kt = KeyType::RSA;
-    let mut client_config = make_client_config(kt);
-    client_config
-        .set_single_client_cert(kt.get_chain(), kt.get_key())
-        .unwrap();
+fn server_accepts_client_cert_when_crl_policy_allows() {
+    for kt in ALL_KEY_TYPES.iter() {
+        let client_config = make_client_config_with_auth(*kt);
+        let mut crls = CrlSet::new();
+        crls.unknown_status = UnknownStatusPolicy::Allow;
+        let server_config = Arc::new(make_server_config_with_client_crls(*kt, crls));
 
-    let server_config = Arc::new(make_server_config_with_mandatory_client_auth(kt));
+        for client_config in AllClientVersions::new(client_config) {
+            let (mut client, mut server) =
+                make_pair_for_arc_configs(&Arc::new(client_config), &server_config);
+            do_handshake(&mut client, &mut server);
+            assert!(server.get_peer_certificates().is_some());
+        }
+    }
+}
 
-    for client_config in AllClientVersions::new(client_config) {
-        let (mut client, mut server) =
-            make_pair_for_arc_configs(&Arc::new(client_config), &server_config);
-        do_handshake(&mut client, &mut server);
+#[test]
+fn client_rejects_server_cert_without_crl_coverage() {
+    for kt in ALL_KEY_TYPES.iter() {
+        let client_config = make_client_config_with_crls(*kt, CrlSet::new());
+        let server_config = Arc::new(make_server_config(*kt));
 
-        // check that alerts don't overtake appdata
-        assert_eq!(12, server.write(b"from-server!").unwrap());
-        assert_eq!(12, client.write(b"from-client!").unwrap());
-        server.send_close_notify();
+        for client_config in AllClientVersions::new(client_config) {
+            let (mut client, mut server) =
+                make_pair_for_arc_configs(&Arc::new(client_config), &server_config);
+            let err = do_handshake_until_error(&mut client, &mut server);
+            assert_eq!(
+                err,
+                Err(TLSErrorFromPeer::Client(TlsError::CRLError(CrlError::UnknownStatus)))
+            );
+        }
+    }
+}
 
-        transfer(&mut server, &mut client);
-        client.process_new_packets().unwrap();
-        check_read_and_close(&mut client, b"from-server!");
+struct ExpectChainAndRevoke {
+    expected_chain: Vec<rustls::Certificate>,
+    revoke: bool,
+    calls: AtomicUsize,
+}
 
-        transfer(&mut client, &mut server);
-        server.process_new_packets().unwrap();
-        check_read(&mut server, b"from-client!");
+impl ExpectChainAndRevoke {
+    fn new(expected_chain: Vec<rustls::Certificate>, revoke: bool) -> Arc<Self> {
+        Arc::new(ExpectChainAndRevoke {
+            expected_chain,
+            revoke,
+            calls: AtomicUsize::new(0),
+        })
     }
 }
 
-#[test]
-fn client_close_notify() {
-    let kt = KeyType::RSA;
-    let mut client_config = make_client_config(kt);
-    client_config
+impl RevocationChecker for ExpectChainAndRevoke {
+    fn check_revocation(
+        &self,
+        end_entity: &rustls::Certificate,
+        intermediates: &[rustls::Certificate],
+        _now: std::time::SystemTime,
+    ) -> Result<(), TlsError> {
+        self.calls.fetch_add(1, Ordering::SeqCst);
+        assert!(end_entity == &self.expected_chain[0]);
+        assert!(intermediates == &self.expected_chain[1..]);
+        if self.revoke {
+            Err(TlsError::InvalidCertificate(CertificateError::Revoked))
+        } else {
+            Ok(())
+        }
+    }
+}
+
+#[test]
+fn client_revocation_checker_can_reject_server_cert() {
+    use rustls::internal::msgs::enums::AlertDescription;
+
+    for kt in ALL_KEY_TYPES.iter() {
+        // the CA certificate the server sends is a trust anchor, so
+        // the verified path stops at the intermediate.
+        let checker = ExpectChainAndRevoke::new(kt.get_chain()[..2].to_vec(), true);
+        let mut client_config = make_client_config(*kt);
+        client_config.set_revocation_checker(checker.clone());
+        let server_config = Arc::new(make_server_config(*kt));
+
+        for client_config in AllClientVersions::new(client_config) {
+            let (mut client, mut server) =
+                make_pair_for_arc_configs(&Arc::new(client_config), &server_config);
+            let errs = do_handshake_until_both_error(&mut client, &mut server);
+            assert_eq!(
+                errs,
+                Err(vec![
+                    TLSErrorFromPeer::Client(TlsError::InvalidCertificate(CertificateError::Revoked)),
+                    TLSErrorFromPeer::Server(TlsError::AlertReceived {
+                        description: AlertDescription::CertificateRevoked,
+                        fatal: true,
+                    }),
+                ])
+            );
+        }
+
+        assert_eq!(checker.calls.load(Ordering::SeqCst), rustls::ALL_VERSIONS.len());
+    }
+}
+
+#[test]
+fn server_revocation_checker_can_reject_client_cert() {
+    use rustls::internal::msgs::enums::AlertDescription;
+
+    for kt in ALL_KEY_TYPES.iter() {
+        // the server trusts every certificate in the client's chain,
+        // so the verified path has no intermediates.
+        let checker = ExpectChainAndRevoke::new(kt.get_chain()[..1].to_vec(), true);
+        let mut client_config = make_client_config(*kt);
+        client_config
+            .set_single_client_cert(kt.get_chain(), kt.get_key())
+            .unwrap();
+        let mut server_config = make_server_config_with_mandatory_client_auth(*kt);
+        server_config.set_revocation_checker(checker.clone());
+        let server_config = Arc::new(server_config);
+
+        for client_config in AllClientVersions::new(client_config) {
+            let (mut client, mut server) =
+                make_pair_for_arc_configs(&Arc::new(client_config), &server_config);
+            let errs = do_handshake_until_both_error(&mut client, &mut server);
+            assert_eq!(
+                errs,
+                Err(vec![
+                    TLSErrorFromPeer::Server(TlsError::InvalidCertificate(CertificateError::Revoked)),
+                    TLSErrorFromPeer::Client(TlsError::AlertReceived {
+                        description: AlertDescription::CertificateRevoked,
+                        fatal: true,
+                    }),
+                ])
+            );
+        }
+
+        assert_eq!(checker.calls.load(Ordering::SeqCst), rustls::ALL_VERSIONS.len());
+    }
+}
+
+#[test]
+fn revocation_checker_not_called_on_resumption() {
+    for kt in ALL_KEY_TYPES.iter() {
+        let checker = ExpectChainAndRevoke::new(kt.get_chain()[..2].to_vec(), false);
+        let mut client_config = make_client_config(*kt);
+        client_config.set_revocation_checker(checker.clone());
+        let client_config = Arc::new(client_config);
+        let server_config = Arc::new(make_server_config(*kt));
+
+        let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
+        do_handshake(&mut client, &mut server);
+        assert_eq!(checker.calls.load(Ordering::SeqCst), 1);
+
+        let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
+        do_handshake(&mut client, &mut server);
+        assert_eq!(checker.calls.load(Ordering::SeqCst), 1);
+    }
+}
+
+struct ExpectChainPolicy {
+    expected_chain: Vec<rustls::Certificate>,
+    accept: bool,
+}
+
+impl ServerCertPolicy for ExpectChainPolicy {
+    fn verify_server_cert(&self, cert: &ServerCertInfo) -> Result<(), TlsError> {
+        assert_eq!(cert.server_name, &dns_name("localhost"));
+        assert!(cert.end_entity == &self.expected_chain[0]);
+        assert!(cert.intermediates == &self.expected_chain[1..]);
+        assert_eq!(cert.ocsp_response, b"");
+
+        if self.accept {
+            Ok(())
+        } else {
+            Err(TlsError::General("rejected by policy".into()))
+        }
+    }
+}
+
+fn make_client_config_with_policy(kt: KeyType, accept: bool) -> ClientConfig {
+    let policy = Arc::new(ExpectChainPolicy {
+        expected_chain: kt.get_chain(),
+        accept,
+    });
+    ClientConfig::new_with_policy(policy, rustls::DEFAULT_CIPHERSUITES)
+}
+
+#[test]
+fn client_policy_can_accept_server_cert() {
+    for kt in ALL_KEY_TYPES.iter() {
+        let client_config = make_client_config_with_policy(*kt, true);
+        let server_config = Arc::new(make_server_config(*kt));
+
+        for client_config in AllClientVersions::new(client_config) {
+            let (mut client, mut server) =
+                make_pair_for_arc_configs(&Arc::new(client_config), &server_config);
+            do_handshake(&mut client, &mut server);
+        }
+    }
+}
+
+#[test]
+fn client_policy_can_reject_server_cert() {
+    use rustls::internal::msgs::enums::AlertDescription;
+
+    for kt in ALL_KEY_TYPES.iter() {
+        let client_config = make_client_config_with_policy(*kt, false);
+        let server_config = Arc::new(make_server_config(*kt));
+
+        for client_config in AllClientVersions::new(client_config) {
+            let (mut client, mut server) =
+                make_pair_for_arc_configs(&Arc::new(client_config), &server_config);
+            let errs = do_handshake_until_both_error(&mut client, &mut server);
+            assert_eq!(
+                errs,
+                Err(vec![
+                    TLSErrorFromPeer::Client(TlsError::General("rejected by policy".into())),
+                    TLSErrorFromPeer::Server(TlsError::AlertReceived {
+                        description: AlertDescription::BadCertificate,
+                        fatal: true,
+                    }),
+                ])
+            );
+        }
+    }
+}
+
+fn make_server_config_with_client_auth_for_sni(kt: KeyType) -> Arc<ServerConfig> {
+    let mut verifier = ClientCertVerifierUsingSni::new(NoClientAuth::new());
+    assert!(verifier
+        .add("not a dns name", NoClientAuth::new())
+        .is_err());
+    verifier
+        .add(
+            "second.testserver.com",
+            AllowAnyAuthenticatedClient::new(get_client_root_store(kt)),
+        )
+        .unwrap();
+
+    let mut server_config = ServerConfig::new(Arc::new(verifier));
+    server_config
+        .set_single_cert(kt.get_chain(), kt.get_key())
+        .unwrap();
+    Arc::new(server_config)
+}
+
+#[test]
+fn server_client_auth_chosen_by_sni() {
+    for kt in ALL_KEY_TYPES.iter() {
+        let server_config = make_server_config_with_client_auth_for_sni(*kt);
+        let mut client_config = make_client_config(*kt);
+        client_config
+            .set_single_client_cert(kt.get_chain(), kt.get_key())
+            .unwrap();
+
+        for client_config in AllClientVersions::new(client_config) {
+            let client_config = Arc::new(client_config);
+
+            let mut client = ClientSession::new(&client_config, dns_name("testserver.com")).unwrap();
+            let mut server = ServerSession::new(&server_config);
+            do_handshake(&mut client, &mut server);
+            assert_eq!(server.get_peer_certificates(), None);
+
+            let mut client =
+                ClientSession::new(&client_config, dns_name("second.testserver.com")).unwrap();
+            let mut server = ServerSession::new(&server_config);
+            do_handshake(&mut client, &mut server);
+            assert_eq!(server.get_peer_certificates(), Some(kt.get_chain()));
+        }
+    }
+}
+
+#[test]
+fn server_client_auth_for_sni_requires_cert() {
+    for kt in ALL_KEY_TYPES.iter() {
+        let server_config = make_server_config_with_client_auth_for_sni(*kt);
+        let client_config = make_client_config(*kt);
+
+        for client_config in AllClientVersions::new(client_config) {
+            let client_config = Arc::new(client_config);
+
+            let mut client = ClientSession::new(&client_config, dns_name("testserver.com")).unwrap();
+            let mut server = ServerSession::new(&server_config);
+            do_handshake(&mut client, &mut server);
+
+            let mut client =
+                ClientSession::new(&client_config, dns_name("second.testserver.com")).unwrap();
+            let mut server = ServerSession::new(&server_config);
+            let err = do_handshake_until_error(&mut client, &mut server);
+            assert_eq!(
+                err,
+                Err(TLSErrorFromPeer::Server(TlsError::NoCertificatesPresented))
+            );
+        }
+    }
+}
+
+fn check_read_and_close(reader: &mut dyn io::Read, expect: &[u8]) {
+    let mut buf = Vec::new();
+    buf.resize(expect.len(), 0u8);
+    assert_eq!(expect.len(), reader.read(&mut buf).unwrap());
+    assert_eq!(expect.to_vec(), buf);
+
+    let err = reader.read(&mut buf);
+    assert!(err.is_err());
+    assert_eq!(err.err().unwrap().kind(), io::ErrorKind::ConnectionAborted);
+}
+
+#[test]
+fn server_close_notify() {
+    let kt = KeyType::RSA;
+    let mut client_config = make_client_config(kt);
+    client_config
+        .set_single_client_cert(kt.get_chain(), kt.get_key())
+        .unwrap();
+
+    let server_config = Arc::new(make_server_config_with_mandatory_client_auth(kt));
+
+    for client_config in AllClientVersions::new(client_config) {
+        let (mut client, mut server) =
+            make_pair_for_arc_configs(&Arc::new(client_config), &server_config);
+        do_handshake(&mut client, &mut server);
+
+        // check that alerts don't overtake appdata
+        assert_eq!(12, server.write(b"from-server!").unwrap());
+        assert_eq!(12, client.write(b"from-client!").unwrap());
+        server.send_close_notify();
+
+        transfer(&mut server, &mut client);
+        client.process_new_packets().unwrap();
+        check_read_and_close(&mut client, b"from-server!");
+
+        transfer(&mut client, &mut server);
+        server.process_new_packets().unwrap();
+        check_read(&mut server, b"from-client!");
+    }
+}
+
+#[test]
+fn client_close_notify() {
+    let kt = KeyType::RSA;
+    let mut client_config = make_client_config(kt);
+    client_config
         .set_single_client_cert(kt.get_chain(), kt.get_key())
         .unwrap();
 
@@ -389,6 +1107,9 @@ struct ServerCheckCertResolve {
     expected_sni: Option<String>,
     expected_sigalgs: Option<Vec<SignatureScheme>>,
     expected_alpn: Option<Vec<Vec<u8>>>,
+    expected_cipher_suites: Option<Vec<CipherSuite>>,
+    expected_named_groups: Option<Vec<NamedGroup>>,
+    expected_certificate_authorities: Option<Vec<Vec<u8>>>,
 }
 
 impl ResolvesServerCert for ServerCheckCertResolve {
@@ -398,10 +1119,9 @@ impl ResolvesServerCert for ServerCheckCertResolve {
         }
 
         if let Some(expected_sni) = &self.expected_sni {
-            let sni: &str = client_hello
+            let sni = client_hello
                 .server_name()
-                .expect("sni unexpectedly absent")
-                .into();
+                .expect("sni unexpectedly absent");
             assert_eq!(expected_sni, sni);
         }
 
@@ -416,9 +1136,10 @@ impl ResolvesServerCert for ServerCheckCertResolve {
         }
 
         if let Some(expected_alpn) = &self.expected_alpn {
-            let alpn = client_hello
+            let alpn: Vec<&[u8]> = client_hello
                 .alpn()
-                .expect("alpn unexpectedly absent");
+                .expect("alpn unexpectedly absent")
+                .collect();
             assert_eq!(alpn.len(), expected_alpn.len());
 
             for (got, wanted) in alpn.iter().zip(expected_alpn.iter()) {
@@ -426,10 +1147,76 @@ impl ResolvesServerCert for ServerCheckCertResolve {
             }
         }
 
+        if let Some(expected_cipher_suites) = &self.expected_cipher_suites {
+            assert_eq!(client_hello.cipher_suites(), &expected_cipher_suites[..]);
+        }
+
+        if let Some(expected_named_groups) = &self.expected_named_groups {
+            assert_eq!(client_hello.named_groups(), Some(&expected_named_groups[..]));
+        }
+
+        if let Some(expected_cas) = &self.expected_certificate_authorities {
+            let cas: Vec<&[u8]> = client_hello
+                .certificate_authorities()
+                .expect("certificate_authorities unexpectedly absent")
+                .collect();
+            assert_eq!(cas, expected_cas.iter().map(|ca| &ca[..]).collect::<Vec<_>>());
+        }
+
         None
     }
 }
 
+#[test]
+fn single_cert_rejects_mismatched_key() {
+    for kt in ALL_KEY_TYPES.iter() {
+        let other = if *kt == KeyType::ECDSA {
+            KeyType::RSA
+        } else {
+            KeyType::ECDSA
+        };
+
+        let mut server_config = ServerConfig::new(NoClientAuth::new());
+        assert_eq!(
+            server_config.set_single_cert(kt.get_chain(), other.get_key()),
+            Err(TlsError::General(
+                "End-entity certificate does not match the private key".into()
+            ))
+        );
+
+        let mut client_config = make_client_config(*kt);
+        assert!(client_config
+            .set_single_client_cert(kt.get_client_chain(), other.get_client_key())
+            .is_err());
+
+        // the chain's intermediates don't stand in for the end-entity cert
+        assert!(server_config
+            .set_single_cert(kt.get_chain()[1..].to_vec(), kt.get_key())
+            .is_err());
+    }
+}
+
+#[test]
+fn single_cert_rejects_misordered_chain() {
+    for kt in ALL_KEY_TYPES.iter() {
+        let mut chain = kt.get_chain();
+        chain.swap(1, 2);
+
+        let mut server_config = ServerConfig::new(NoClientAuth::new());
+        assert_eq!(
+            server_config.set_single_cert(chain, kt.get_key()),
+            Err(TlsError::General(
+                "Certificate chain is out of order: certificate 0 was not issued by certificate 1"
+                    .into()
+            ))
+        );
+
+        assert!(server_config
+            .set_single_cert(vec![], kt.get_key())
+            .is_err());
+    }
+}
+
 #[test]
 fn server_cert_resolve_with_sni() {
     for kt in ALL_KEY_TYPES.iter() {
@@ -470,6 +1257,62 @@ fn server_cert_resolve_with_alpn() {
     }
 }
 
+#[cfg(feature = "tls13")]
+#[test]
+fn server_cert_resolve_with_suites_and_groups() {
+    for kt in ALL_KEY_TYPES.iter() {
+        let mut client_config = make_client_config(*kt);
+        client_config.ciphersuites = vec![
+            find_suite(CipherSuite::TLS13_AES_256_GCM_SHA384),
+            find_suite(CipherSuite::TLS13_CHACHA20_POLY1305_SHA256),
+        ];
+        client_config.kx_groups = vec![&rustls::kx_group::SECP384R1, &rustls::kx_group::X25519];
+
+        let mut server_config = make_server_config(*kt);
+        server_config.cert_resolver = Arc::new(ServerCheckCertResolve {
+            expected_cipher_suites: Some(vec![
+                CipherSuite::TLS13_AES_256_GCM_SHA384,
+                CipherSuite::TLS13_CHACHA20_POLY1305_SHA256,
+                CipherSuite::TLS_EMPTY_RENEGOTIATION_INFO_SCSV,
+            ]),
+            expected_named_groups: Some(vec![NamedGroup::secp384r1, NamedGroup::X25519]),
+            ..Default::default()
+        });
+
+        let mut client = ClientSession::new(&Arc::new(client_config), dns_name("localhost")).unwrap();
+        let mut server = ServerSession::new(&Arc::new(server_config));
+
+        let err = do_handshake_until_error(&mut client, &mut server);
+        assert_eq!(err.is_err(), true);
+    }
+}
+
+#[cfg(feature = "tls13")]
+#[test]
+fn server_cert_resolve_with_certificate_authorities() {
+    for kt in ALL_KEY_TYPES.iter() {
+        let mut client_config = make_client_config(*kt);
+        client_config.certificate_authorities = get_client_root_store(*kt).get_subjects();
+        let expected_cas = client_config
+            .certificate_authorities
+            .iter()
+            .map(|ca| ca.0.clone())
+            .collect();
+
+        let mut server_config = make_server_config(*kt);
+        server_config.cert_resolver = Arc::new(ServerCheckCertResolve {
+            expected_certificate_authorities: Some(expected_cas),
+            ..Default::default()
+        });
+
+        let mut client = ClientSession::new(&Arc::new(client_config), dns_name("localhost")).unwrap();
+        let mut server = ServerSession::new(&Arc::new(server_config));
+
+        let err = do_handshake_until_error(&mut client, &mut server);
+        assert!(err.is_err());
+    }
+}
+
 #[test]
 fn client_trims_terminating_dot() {
     for kt in ALL_KEY_TYPES.iter() {
@@ -489,6 +1332,7 @@ fn client_trims_terminating_dot() {
     }
 }
 
+#[cfg(feature = "tls12")]
 fn check_sigalgs_reduced_by_ciphersuite(
     kt: KeyType,
     suite: CipherSuite,
@@ -511,6 +1355,7 @@ fn check_sigalgs_reduced_by_ciphersuite(
     assert_eq!(err.is_err(), true);
 }
 
+#[cfg(feature = "tls12")]
 #[test]
 fn server_cert_resolve_reduces_sigalgs_for_rsa_ciphersuite() {
     check_sigalgs_reduced_by_ciphersuite(
@@ -520,6 +1365,9 @@ fn server_cert_resolve_reduces_sigalgs_for_rsa_ciphersuite() {
             SignatureScheme::RSA_PSS_SHA512,
             SignatureScheme::RSA_PSS_SHA384,
             SignatureScheme::RSA_PSS_SHA256,
+            SignatureScheme::RSA_PSS_PSS_SHA512,
+            SignatureScheme::RSA_PSS_PSS_SHA384,
+            SignatureScheme::RSA_PSS_PSS_SHA256,
             SignatureScheme::RSA_PKCS1_SHA512,
             SignatureScheme::RSA_PKCS1_SHA384,
             SignatureScheme::RSA_PKCS1_SHA256,
@@ -527,6 +1375,7 @@ fn server_cert_resolve_reduces_sigalgs_for_rsa_ciphersuite() {
     );
 }
 
+#[cfg(feature = "tls12")]
 #[test]
 fn server_cert_resolve_reduces_sigalgs_for_ecdsa_ciphersuite() {
     check_sigalgs_reduced_by_ciphersuite(
@@ -550,10 +1399,202 @@ impl ResolvesServerCert for ServerCheckNoSNI {
     }
 }
 
-#[test]
-fn client_with_sni_disabled_does_not_send_sni() {
-    for kt in ALL_KEY_TYPES.iter() {
-        let mut client_config = make_client_config(*kt);
+/// Resolves the ACME challenge certificate, counting how often.
+struct AcmeChallengeResolver {
+    certkey: sign::CertifiedKey,
+    calls: AtomicUsize,
+}
+
+impl ResolvesServerCert for AcmeChallengeResolver {
+    fn resolve(&self, client_hello: ClientHello) -> Option<sign::CertifiedKey> {
+        assert_eq!(client_hello.server_name(), Some("localhost"));
+        self.calls.fetch_add(1, Ordering::SeqCst);
+        Some(self.certkey.clone())
+    }
+}
+
+#[test]
+fn server_answers_acme_tls_alpn_challenges() {
+    let kt = KeyType::RSA;
+    let resolver = Arc::new(AcmeChallengeResolver {
+        certkey: sign::CertifiedKey::new(
+            kt.get_chain(),
+            Arc::new(sign::any_supported_type(&kt.get_key()).unwrap()),
+        ),
+        calls: AtomicUsize::new(0),
+    });
+    let mut server_config = make_server_config(kt);
+    server_config.alpn_protocols = vec![b"h2".to_vec()];
+    server_config.acme_challenge_resolver = Some(resolver.clone());
+    let server_config = Arc::new(server_config);
+
+    let mut client_config = make_client_config(kt);
+    client_config.alpn_protocols = vec![rustls::ACME_TLS_ALPN_PROTOCOL.to_vec()];
+    let mut challenges = 0;
+    for client_config in AllClientVersions::new(client_config) {
+        let client_config = Arc::new(client_config);
+        // The second handshake doesn't resume.
+        for _ in 0..2 {
+            let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
+            do_handshake(&mut client, &mut server);
+            challenges += 1;
+            assert_eq!(resolver.calls.load(Ordering::SeqCst), challenges);
+            assert!(server.is_acme_challenge());
+            assert_eq!(client.handshake_kind(), Some(HandshakeKind::Full));
+            assert_eq!(client.get_alpn_protocol(), Some(rustls::ACME_TLS_ALPN_PROTOCOL));
+            assert_eq!(server.get_alpn_protocol(), Some(rustls::ACME_TLS_ALPN_PROTOCOL));
+
+            // The server closes the connection straight away.
+            transfer(&mut server, &mut client);
+            client.process_new_packets().unwrap();
+            let err = client.read(&mut [0u8; 1]).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::ConnectionAborted);
+        }
+    }
+
+    // Other clients are unaffected.
+    let mut client_config = make_client_config(kt);
+    client_config.alpn_protocols = vec![b"h2".to_vec()];
+    let (mut client, mut server) =
+        make_pair_for_arc_configs(&Arc::new(client_config), &server_config);
+    do_handshake(&mut client, &mut server);
+    assert!(!server.is_acme_challenge());
+    assert_eq!(client.get_alpn_protocol(), Some(&b"h2"[..]));
+    assert_eq!(resolver.calls.load(Ordering::SeqCst), challenges);
+}
+
+#[test]
+fn server_acceptor_reads_client_hello_before_choosing_config() {
+    for kt in ALL_KEY_TYPES.iter() {
+        let mut client_config = make_client_config(*kt);
+        client_config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+        client_config.custom_extensions = vec![(0xfe00, b"hi".to_vec())];
+        let client_config = Arc::new(client_config);
+        let server_config = Arc::new(make_server_config(*kt));
+
+        let mut client = ClientSession::new(&client_config, dns_name("localhost")).unwrap();
+        let mut buf = Vec::new();
+        client.write_tls(&mut buf).unwrap();
+
+        // Deliver the ClientHello a byte at a time.
+        let mut acceptor = Acceptor::new();
+        let mut offs = 0;
+        let accepted = loop {
+            assert!(acceptor.wants_read());
+            offs += acceptor
+                .read_tls(&mut &buf[offs..offs + 1])
+                .unwrap();
+            if let Some(accepted) = acceptor.accept().unwrap() {
+                break accepted;
+            }
+        };
+        assert_eq!(offs, buf.len());
+        assert!(!acceptor.wants_read());
+        assert!(acceptor.accept().is_err());
+
+        let hello = accepted.client_hello();
+        assert_eq!(hello.server_name(), Some("localhost"));
+        let alpn: Vec<&[u8]> = hello.alpn().unwrap().collect();
+        assert_eq!(alpn, vec![&b"h2"[..], &b"http/1.1"[..]]);
+        let versions: Vec<ProtocolVersion> = rustls::ALL_VERSIONS
+            .iter()
+            .map(|v| v.version)
+            .collect();
+        assert_eq!(hello.supported_versions(), Some(&versions[..]));
+        let unknown: Vec<(u16, &[u8])> = hello.unknown_extensions().collect();
+        assert_eq!(unknown, vec![(0xfe00, &b"hi"[..])]);
+        #[cfg(feature = "tls13")]
+        assert!(hello
+            .cipher_suites()
+            .contains(&CipherSuite::TLS13_CHACHA20_POLY1305_SHA256));
+
+        let mut server = accepted.into_session(&server_config);
+        do_handshake(&mut client, &mut server);
+        assert_eq!(server.get_sni_hostname(), Some("localhost"));
+    }
+}
+
+#[test]
+fn server_acceptor_rejects_non_handshake_records() {
+    use rustls::internal::msgs::enums::ContentType;
+
+    let mut acceptor = Acceptor::new();
+    acceptor
+        .read_tls(&mut &b"\x15\x03\x03\x00\x02\x02\x28"[..])
+        .unwrap();
+    assert_eq!(
+        acceptor.accept().err(),
+        Some(TlsError::InappropriateMessage {
+            expect_types: vec![ContentType::Handshake],
+            got_type: ContentType::Alert,
+        })
+    );
+
+    let mut acceptor = Acceptor::new();
+    acceptor
+        .read_tls(&mut &b"GET / HTTP/1.1\r\n\r\n"[..])
+        .unwrap();
+    assert_eq!(acceptor.accept().err(), Some(TlsError::CorruptMessage));
+}
+
+#[test]
+fn peek_client_hello_reads_sni_alpn_and_versions() {
+    let mut client_config = make_client_config(KeyType::RSA);
+    client_config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+    let mut client = ClientSession::new(&Arc::new(client_config), dns_name("localhost")).unwrap();
+    let mut buf = Vec::new();
+    client.write_tls(&mut buf).unwrap();
+
+    for len in 0..buf.len() {
+        assert_eq!(rustls::peek_client_hello(&buf[..len]), Ok(None));
+    }
+
+    // Bytes after the ClientHello are ignored.
+    buf.extend_from_slice(b"\x17\x03\x03\x00\x01\x00");
+    let hello = rustls::peek_client_hello(&buf)
+        .unwrap()
+        .unwrap();
+    assert_eq!(hello.server_name, Some("localhost".to_string()));
+    assert_eq!(
+        hello.alpn_protocols,
+        vec![b"h2".to_vec(), b"http/1.1".to_vec()]
+    );
+    assert_eq!(hello.client_version, ProtocolVersion::TLSv1_2);
+    let versions: Vec<ProtocolVersion> = rustls::ALL_VERSIONS
+        .iter()
+        .map(|v| v.version)
+        .collect();
+    assert_eq!(hello.supported_versions, Some(versions));
+}
+
+#[test]
+fn peek_client_hello_without_sni_or_alpn() {
+    let mut client_config = make_client_config(KeyType::RSA);
+    client_config.enable_sni = false;
+    let mut client = ClientSession::new(&Arc::new(client_config), dns_name("localhost")).unwrap();
+    let mut buf = Vec::new();
+    client.write_tls(&mut buf).unwrap();
+
+    let hello = rustls::peek_client_hello(&buf)
+        .unwrap()
+        .unwrap();
+    assert_eq!(hello.server_name, None);
+    assert!(hello.alpn_protocols.is_empty());
+}
+
+#[test]
+fn peek_client_hello_rejects_non_tls() {
+    assert_eq!(
+        rustls::peek_client_hello(b"GET / HTTP/1.1\r\n\r\n"),
+        Err(TlsError::CorruptMessage)
+    );
+    assert!(rustls::peek_client_hello(b"\x15\x03\x03\x00\x02\x02\x28").is_err());
+}
+
+#[test]
+fn client_with_sni_disabled_does_not_send_sni() {
+    for kt in ALL_KEY_TYPES.iter() {
+        let mut client_config = make_client_config(*kt);
         client_config.enable_sni = false;
 
         let mut server_config = make_server_config(*kt);
@@ -587,9 +1628,55 @@ fn client_checks_server_certificate_with_given_name() {
             let err = do_handshake_until_error(&mut client, &mut server);
             assert_eq!(
                 err,
-                Err(TLSErrorFromPeer::Client(TlsError::WebPKIError(
-                    webpki::Error::CertNotValidForName,
-                    WebPKIOp::ValidateForDNSName,
+                Err(TLSErrorFromPeer::Client(TlsError::InvalidCertificate(
+                    CertificateError::NameMismatch {
+                        expected: "not-the-right-hostname.com".to_string(),
+                        found: test_server_names(),
+                    }
+                )))
+            );
+        }
+    }
+}
+
+/// The subject alternative names of the test end-entity certificates.
+fn test_server_names() -> Vec<String> {
+    vec![
+        "testserver.com".to_string(),
+        "second.testserver.com".to_string(),
+        "localhost".to_string(),
+        "198.51.100.1".to_string(),
+        "2001:db8::1".to_string(),
+    ]
+}
+
+#[test]
+fn client_checks_server_certificate_with_ip_address() {
+    for kt in ALL_KEY_TYPES.iter() {
+        let client_config = make_client_config(*kt);
+        let server_config = Arc::new(make_server_config(*kt));
+
+        for client_config in AllClientVersions::new(client_config) {
+            let client_config = Arc::new(client_config);
+
+            for ip in &["198.51.100.1", "2001:db8::1"] {
+                let mut client = ClientSession::new(&client_config, dns_name(ip)).unwrap();
+                let mut server = ServerSession::new(&server_config);
+                do_handshake(&mut client, &mut server);
+                assert_eq!(server.get_sni_hostname(), None);
+            }
+
+            let ip: IpAddr = "192.0.2.1".parse().unwrap();
+            let mut client = ClientSession::new(&client_config, ServerName::from(ip)).unwrap();
+            let mut server = ServerSession::new(&server_config);
+            let err = do_handshake_until_error(&mut client, &mut server);
+            assert_eq!(
+                err,
+                Err(TLSErrorFromPeer::Client(TlsError::InvalidCertificate(
+                    CertificateError::NameMismatch {
+                        expected: "192.0.2.1".to_string(),
+                        found: test_server_names(),
+                    }
                 )))
             );
         }
@@ -648,7 +1735,7 @@ impl ResolvesClientCert for ClientCheckCertResolve {
 fn client_cert_resolve() {
     for kt in ALL_KEY_TYPES.iter() {
         let mut client_config = make_client_config(*kt);
-        client_config.client_auth_cert_resolver = Arc::new(ClientCheckCertResolve::new(2));
+        client_config.client_auth_cert_resolver = Arc::new(ClientCheckCertResolve::new(rustls::ALL_VERSIONS.len()));
 
         let server_config = Arc::new(make_server_config_with_mandatory_client_auth(*kt));
 
@@ -664,6 +1751,96 @@ fn client_cert_resolve() {
     }
 }
 
+#[test]
+fn client_cert_resolve_chooses_by_issuer() {
+    let certified_key = |kt: KeyType| {
+        sign::CertifiedKey::new(
+            kt.get_client_chain(),
+            Arc::new(sign::any_supported_type(&kt.get_client_key()).unwrap()),
+        )
+    };
+
+    for server_kt in ALL_KEY_TYPES.iter() {
+        let mut resolver = rustls::ResolvesClientCertUsingIssuers::new();
+        for kt in ALL_KEY_TYPES.iter() {
+            resolver.add(certified_key(*kt)).unwrap();
+        }
+        let mut client_config = make_client_config(*server_kt);
+        client_config.client_auth_cert_resolver = Arc::new(resolver);
+
+        let server_config = Arc::new(make_server_config_with_mandatory_client_auth(*server_kt));
+
+        for client_config in AllClientVersions::new(client_config) {
+            let (mut client, mut server) =
+                make_pair_for_arc_configs(&Arc::new(client_config), &server_config);
+            do_handshake(&mut client, &mut server);
+            assert_eq!(
+                server.get_peer_certificates(),
+                Some(server_kt.get_client_chain())
+            );
+        }
+    }
+
+    // With no acceptable issuers, any certificate will do.
+    let ck = certified_key(KeyType::RSA);
+    assert!(ck.is_issued_by_any(&[]));
+    assert!(!ck.is_issued_by_any(&[b"not a name"]));
+}
+
+struct ClientCountIssuers {
+    issuers: AtomicUsize,
+}
+
+impl ResolvesClientCert for ClientCountIssuers {
+    fn resolve(
+        &self,
+        acceptable_issuers: &[&[u8]],
+        _sigschemes: &[SignatureScheme],
+    ) -> Option<sign::CertifiedKey> {
+        self.issuers
+            .store(acceptable_issuers.len(), Ordering::SeqCst);
+        None
+    }
+
+    fn has_certs(&self) -> bool {
+        true
+    }
+}
+
+#[test]
+fn server_limits_ca_names_in_certificate_request() {
+    for kt in ALL_KEY_TYPES.iter() {
+        let expected_issuers = get_client_root_store(*kt).len();
+
+        for (limit, issuers) in &[
+            (None, expected_issuers),
+            (Some(100_000), expected_issuers),
+            (Some(10), 0),
+            (Some(0), 0),
+        ] {
+            let mut server_config = make_server_config(*kt);
+            server_config.set_client_certificate_verifier(
+                rustls::AllowAnyAnonymousOrAuthenticatedClient::new(get_client_root_store(*kt)),
+            );
+            server_config.client_auth_ca_names_limit = *limit;
+            let server_config = Arc::new(server_config);
+
+            for client_config in AllClientVersions::new(make_client_config(*kt)) {
+                let resolver = Arc::new(ClientCountIssuers {
+                    issuers: AtomicUsize::new(usize::MAX),
+                });
+                let mut client_config = client_config;
+                client_config.client_auth_cert_resolver = resolver.clone();
+
+                let (mut client, mut server) =
+                    make_pair_for_arc_configs(&Arc::new(client_config), &server_config);
+                do_handshake(&mut client, &mut server);
+                assert_eq!(resolver.issuers.load(Ordering::SeqCst), *issuers);
+            }
+        }
+    }
+}
+
 #[test]
 fn client_auth_works() {
     for kt in ALL_KEY_TYPES.iter() {
@@ -678,6 +1855,50 @@ fn client_auth_works() {
     }
 }
 
+#[test]
+fn peer_signature_scheme_is_reported() {
+    for kt in ALL_KEY_TYPES.iter() {
+        let client_config = make_client_config_with_auth(*kt);
+        let server_config = Arc::new(make_server_config_with_mandatory_client_auth(*kt));
+
+        for client_config in AllClientVersions::new(client_config) {
+            let (mut client, mut server) =
+                make_pair_for_arc_configs(&Arc::new(client_config), &server_config);
+            assert_eq!(client.get_peer_signature_scheme(), None);
+            assert_eq!(server.get_peer_signature_scheme(), None);
+            do_handshake(&mut client, &mut server);
+
+            let expected: &[SignatureScheme] = match kt {
+                KeyType::RSA | KeyType::RSA_PSS => &[
+                    SignatureScheme::RSA_PSS_SHA256,
+                    SignatureScheme::RSA_PSS_SHA384,
+                    SignatureScheme::RSA_PSS_SHA512,
+                    SignatureScheme::RSA_PKCS1_SHA256,
+                    SignatureScheme::RSA_PKCS1_SHA384,
+                    SignatureScheme::RSA_PKCS1_SHA512,
+                ],
+                KeyType::ECDSA => &[
+                    SignatureScheme::ECDSA_NISTP256_SHA256,
+                    SignatureScheme::ECDSA_NISTP384_SHA384,
+                ],
+                KeyType::ED25519 => &[SignatureScheme::ED25519],
+            };
+            for scheme in &[
+                client.get_peer_signature_scheme(),
+                server.get_peer_signature_scheme(),
+            ] {
+                assert!(expected.contains(&scheme.unwrap()));
+            }
+        }
+
+        let (mut client, mut server) =
+            make_pair_for_configs(make_client_config(*kt), make_server_config(*kt));
+        do_handshake(&mut client, &mut server);
+        assert!(client.get_peer_signature_scheme().is_some());
+        assert_eq!(server.get_peer_signature_scheme(), None);
+    }
+}
+
 #[cfg(feature = "dangerous_configuration")]
 mod test_clientverifier {
     use super::*;
@@ -791,9 +2012,10 @@ mod test_clientverifier {
                         TLSErrorFromPeer::Server(TlsError::General(
                             "client rejected by client_auth_root_subjects".into()
                         )),
-                        TLSErrorFromPeer::Client(TlsError::AlertReceived(
-                            AlertDescription::AccessDenied
-                        ))
+                        TLSErrorFromPeer::Client(TlsError::AlertReceived {
+                            description: AlertDescription::AccessDenied,
+                            fatal: true,
+                        })
                     ])
                 );
             }
@@ -830,9 +2052,10 @@ mod test_clientverifier {
                         TLSErrorFromPeer::Server(TlsError::General(
                             "client rejected by client_auth_root_subjects".into()
                         )),
-                        TLSErrorFromPeer::Client(TlsError::AlertReceived(
-                            AlertDescription::AccessDenied
-                        ))
+                        TLSErrorFromPeer::Client(TlsError::AlertReceived {
+                            description: AlertDescription::AccessDenied,
+                            fatal: true,
+                        })
                     ])
                 );
             }
@@ -859,7 +2082,6 @@ mod test_clientverifier {
             let client_config = make_client_config(*kt);
 
             for client_config in AllClientVersions::new(client_config) {
-                println!("Failing: {:?}", client_config.versions);
                 let mut server = ServerSession::new(&server_config);
                 let mut client =
                     ClientSession::new(&Arc::new(client_config), dns_name("localhost")).unwrap();
@@ -868,9 +2090,10 @@ mod test_clientverifier {
                     errs,
                     Err(vec![
                         TLSErrorFromPeer::Server(TlsError::NoCertificatesPresented),
-                        TLSErrorFromPeer::Client(TlsError::AlertReceived(
-                            AlertDescription::CertificateRequired
-                        ))
+                        TLSErrorFromPeer::Client(TlsError::AlertReceived {
+                            description: AlertDescription::CertificateRequired,
+                            fatal: true,
+                        })
                     ])
                 );
             }
@@ -941,14 +2164,83 @@ mod test_clientverifier {
                         TLSErrorFromPeer::Server(TlsError::General(
                             "client rejected by client_auth_mandatory".into()
                         )),
-                        TLSErrorFromPeer::Client(TlsError::AlertReceived(
-                            AlertDescription::AccessDenied
-                        ))
+                        TLSErrorFromPeer::Client(TlsError::AlertReceived {
+                            description: AlertDescription::AccessDenied,
+                            fatal: true,
+                        })
                     ])
                 );
             }
         }
     }
+
+    struct NonFatalVerifier(MockClientVerifier);
+
+    impl rustls::ClientCertVerifier for NonFatalVerifier {
+        fn client_auth_mandatory(&self, sni: Option<&rustls::DnsName>) -> Option<bool> {
+            self.0.client_auth_mandatory(sni)
+        }
+
+        fn client_auth_root_subjects(
+            &self,
+            sni: Option<&rustls::DnsName>,
+        ) -> Option<rustls::DistinguishedNames> {
+            self.0.client_auth_root_subjects(sni)
+        }
+
+        fn verify_client_cert(
+            &self,
+            end_entity: &rustls::Certificate,
+            intermediates: &[rustls::Certificate],
+            sni: Option<&rustls::DnsName>,
+            now: std::time::SystemTime,
+        ) -> Result<ClientCertVerified, TlsError> {
+            self.0
+                .verify_client_cert(end_entity, intermediates, sni, now)
+        }
+
+        fn client_cert_failure_is_fatal(
+            &self,
+            _err: &TlsError,
+            _sni: Option<&rustls::DnsName>,
+        ) -> bool {
+            false
+        }
+    }
+
+    #[test]
+    // A verifier can let the handshake continue without the rejected certificate
+    fn client_verifier_fails_non_fatally() {
+        for kt in ALL_KEY_TYPES.iter() {
+            let client_verifier = NonFatalVerifier(MockClientVerifier {
+                verified: ver_err,
+                subjects: Some(get_client_root_store(*kt).get_subjects()),
+                mandatory: Some(false),
+                offered_schemes: None,
+            });
+
+            let mut server_config = ServerConfig::new(Arc::new(client_verifier));
+            server_config
+                .set_single_cert(kt.get_chain(), kt.get_key())
+                .unwrap();
+
+            let server_config = Arc::new(server_config);
+            let client_config = make_client_config_with_auth(*kt);
+
+            for client_config in AllClientVersions::new(client_config) {
+                let (mut client, mut server) =
+                    make_pair_for_arc_configs(&Arc::new(client_config.clone()), &server_config);
+                let err = do_handshake_until_error(&mut client, &mut server);
+                assert_eq!(err, Ok(()));
+                assert_eq!(server.get_peer_certificates(), None);
+
+                client.write_all(b"hello").unwrap();
+                transfer(&mut client, &mut server);
+                server.process_new_packets().unwrap();
+                check_read(&mut server, b"hello");
+            }
+        }
+    }
 } // mod test_clientverifier
 
 #[test]
@@ -1038,6 +2330,7 @@ fn server_respects_buffer_limit_pre_handshake_with_vectored_write() {
     check_read(&mut client, b"01234567890123456789012345678901");
 }
 
+#[cfg(feature = "tls13")]
 #[test]
 fn server_respects_buffer_limit_post_handshake() {
     let (mut client, mut server) = make_pair(KeyType::RSA);
@@ -1114,6 +2407,7 @@ fn client_respects_buffer_limit_pre_handshake_with_vectored_write() {
     check_read(&mut server, b"01234567890123456789012345678901");
 }
 
+#[cfg(feature = "tls13")]
 #[test]
 fn client_respects_buffer_limit_post_handshake() {
     let (mut client, mut server) = make_pair(KeyType::RSA);
@@ -1140,6 +2434,71 @@ fn client_respects_buffer_limit_post_handshake() {
     check_read(&mut server, b"01234567890123456789012345");
 }
 
+#[test]
+fn write_would_block_when_buffer_limit_reached() {
+    let (mut client, mut server) = make_pair(KeyType::RSA);
+
+    client.set_buffer_limit(32);
+    assert_eq!(client.write(&[0u8; 40]).unwrap(), 32);
+    let err = client.write(b"more").unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+    let err = client
+        .write_vectored(&[IoSlice::new(b"more")])
+        .unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+
+    // Empty writes are still fine.
+    assert_eq!(client.write(b"").unwrap(), 0);
+
+    // Once the handshake completes and the records are written out,
+    // there is room again.
+    do_handshake(&mut client, &mut server);
+    transfer(&mut client, &mut server);
+    assert!(client.write(b"more").unwrap() > 0);
+}
+
+#[test]
+fn read_tls_refuses_while_plaintext_unread() {
+    let (mut client, mut server) = make_pair(KeyType::RSA);
+    do_handshake(&mut client, &mut server);
+
+    server.write_all(&[0u8; 64 * 1024]).unwrap();
+    let mut tls = Vec::new();
+    while server.wants_write() {
+        server.write_tls(&mut tls).unwrap();
+    }
+
+    // Feed the client TLS data without reading the plaintext: it
+    // stops accepting once enough is waiting.
+    let mut rd = &tls[..];
+    let err = loop {
+        match client.read_tls(&mut rd) {
+            Ok(_) => client.process_new_packets().unwrap(),
+            Err(err) => break err,
+        }
+    };
+    assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    assert!(!client.wants_read());
+    assert!(!rd.is_empty());
+
+    // Reading the plaintext makes room again.
+    let mut plaintext = Vec::new();
+    loop {
+        let mut buf = [0u8; 4096];
+        let len = client.read(&mut buf).unwrap();
+        if len == 0 && rd.is_empty() {
+            break;
+        }
+        plaintext.extend_from_slice(&buf[..len]);
+
+        if client.wants_read() && !rd.is_empty() {
+            client.read_tls(&mut rd).unwrap();
+            client.process_new_packets().unwrap();
+        }
+    }
+    assert_eq!(plaintext.len(), 64 * 1024);
+}
+
 struct OtherSession<'a> {
     sess: &'a mut dyn Session,
     pub reads: usize,
@@ -1245,33 +2604,165 @@ fn client_complete_io_for_handshake_eof() {
 }
 
 #[test]
-fn client_complete_io_for_write() {
+fn complete_handshake_stops_after_handshake() {
     for kt in ALL_KEY_TYPES.iter() {
         let (mut client, mut server) = make_pair(*kt);
 
-        do_handshake(&mut client, &mut server);
-
-        client
+        // Application data which `complete_io` would also read.
+        server
             .write(b"01234567890123456789")
             .unwrap();
-        client
-            .write(b"01234567890123456789")
+
+        let (rdlen, wrlen) = client
+            .complete_handshake(&mut OtherSession::new(&mut server))
             .unwrap();
-        {
-            let mut pipe = OtherSession::new(&mut server);
-            let (rdlen, wrlen) = client.complete_io(&mut pipe).unwrap();
-            assert!(rdlen == 0 && wrlen > 0);
-            println!("{:?}", pipe.writevs);
-            assert_eq!(pipe.writevs, vec![vec![42, 42]]);
-        }
-        check_read(&mut server, b"0123456789012345678901234567890123456789");
+        assert!(rdlen > 0 && wrlen > 0);
+        assert_eq!(false, client.is_handshaking());
+        assert_eq!(false, client.wants_write());
+        assert_eq!(false, server.is_handshaking());
+
+        // Nothing more to do.
+        let (rdlen, wrlen) = client
+            .complete_handshake(&mut OtherSession::new(&mut server))
+            .unwrap();
+        assert_eq!((rdlen, wrlen), (0, 0));
+
+        transfer(&mut server, &mut client);
+        client.process_new_packets().unwrap();
+        check_read(&mut client, b"01234567890123456789");
     }
 }
 
 #[test]
-fn client_complete_io_for_read() {
-    for kt in ALL_KEY_TYPES.iter() {
-        let (mut client, mut server) = make_pair(*kt);
+fn complete_handshake_eof() {
+    let (mut client, _) = make_pair(KeyType::RSA);
+    let mut input = io::Cursor::new(Vec::new());
+
+    let err = client
+        .complete_handshake(&mut input)
+        .unwrap_err();
+    assert_eq!(io::ErrorKind::UnexpectedEof, err.kind());
+}
+
+/// A nonblocking transport over a pair of buffers: reading fails with
+/// `WouldBlock` while `incoming` is empty.
+struct NonBlockingPipe<'a> {
+    incoming: &'a mut Vec<u8>,
+    outgoing: &'a mut Vec<u8>,
+}
+
+impl<'a> io::Read for NonBlockingPipe<'a> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.incoming.is_empty() {
+            return Err(io::ErrorKind::WouldBlock.into());
+        }
+        let len = (&self.incoming[..]).read(buf)?;
+        self.incoming.drain(..len);
+        Ok(len)
+    }
+}
+
+impl<'a> io::Write for NonBlockingPipe<'a> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.outgoing.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[test]
+fn poll_handshake_with_nonblocking_transport() {
+    for kt in ALL_KEY_TYPES.iter() {
+        let (mut client, mut server) = make_pair(*kt);
+        let mut client_to_server = Vec::new();
+        let mut server_to_client = Vec::new();
+
+        assert!(client
+            .poll_read_tls(&mut NonBlockingPipe {
+                incoming: &mut server_to_client,
+                outgoing: &mut client_to_server,
+            })
+            .is_pending());
+
+        let mut rounds = 0;
+        loop {
+            let client_done = client.poll_handshake(&mut NonBlockingPipe {
+                incoming: &mut server_to_client,
+                outgoing: &mut client_to_server,
+            });
+            let server_done = server.poll_handshake(&mut NonBlockingPipe {
+                incoming: &mut client_to_server,
+                outgoing: &mut server_to_client,
+            });
+            if let (Poll::Ready(client_done), Poll::Ready(server_done)) = (client_done, server_done)
+            {
+                client_done.unwrap();
+                server_done.unwrap();
+                break;
+            }
+
+            rounds += 1;
+            assert!(rounds < 10);
+        }
+
+        assert!(!client.is_handshaking());
+        assert!(!server.is_handshaking());
+
+        // Nothing more to do.
+        let mut empty = Vec::new();
+        assert!(matches!(
+            client.poll_handshake(&mut NonBlockingPipe {
+                incoming: &mut empty,
+                outgoing: &mut client_to_server,
+            }),
+            Poll::Ready(Ok(()))
+        ));
+    }
+}
+
+#[test]
+fn poll_handshake_eof() {
+    let (mut client, _) = make_pair(KeyType::RSA);
+    let mut input = io::Cursor::new(Vec::new());
+
+    match client.poll_handshake(&mut input) {
+        Poll::Ready(Err(err)) => assert_eq!(io::ErrorKind::UnexpectedEof, err.kind()),
+        _ => panic!("handshake didn't fail"),
+    }
+}
+
+#[cfg(feature = "tls13")]
+#[test]
+fn client_complete_io_for_write() {
+    for kt in ALL_KEY_TYPES.iter() {
+        let (mut client, mut server) = make_pair(*kt);
+
+        do_handshake(&mut client, &mut server);
+
+        client
+            .write(b"01234567890123456789")
+            .unwrap();
+        client
+            .write(b"01234567890123456789")
+            .unwrap();
+        {
+            let mut pipe = OtherSession::new(&mut server);
+            let (rdlen, wrlen) = client.complete_io(&mut pipe).unwrap();
+            assert!(rdlen == 0 && wrlen > 0);
+            println!("{:?}", pipe.writevs);
+            assert_eq!(pipe.writevs, vec![vec![42, 42]]);
+        }
+        check_read(&mut server, b"0123456789012345678901234567890123456789");
+    }
+}
+
+#[test]
+fn client_complete_io_for_read() {
+    for kt in ALL_KEY_TYPES.iter() {
+        let (mut client, mut server) = make_pair(*kt);
 
         do_handshake(&mut client, &mut server);
 
@@ -1314,6 +2805,7 @@ fn server_complete_io_for_handshake_eof() {
     assert_eq!(io::ErrorKind::UnexpectedEof, err.kind());
 }
 
+#[cfg(feature = "tls13")]
 #[test]
 fn server_complete_io_for_write() {
     for kt in ALL_KEY_TYPES.iter() {
@@ -1357,6 +2849,35 @@ fn server_complete_io_for_read() {
     }
 }
 
+fn transfer_buf(from: &mut dyn Session, to: &mut dyn Session) {
+    let mut bytes = Vec::new();
+    while from.wants_write() {
+        from.write_tls(&mut bytes).unwrap();
+    }
+
+    let mut rd = &bytes[..];
+    while !rd.is_empty() {
+        to.read_tls_buf(&mut rd).unwrap();
+    }
+    to.process_new_packets().unwrap();
+}
+
+#[test]
+fn read_tls_buf_handshake_and_data() {
+    for kt in ALL_KEY_TYPES.iter() {
+        let (mut client, mut server) = make_pair(*kt);
+
+        while client.is_handshaking() || server.is_handshaking() {
+            transfer_buf(&mut client, &mut server);
+            transfer_buf(&mut server, &mut client);
+        }
+
+        client.write_all(b"hello").unwrap();
+        transfer_buf(&mut client, &mut server);
+        check_read(&mut server, b"hello");
+    }
+}
+
 #[test]
 fn client_stream_write() {
     for kt in ALL_KEY_TYPES.iter() {
@@ -1473,6 +2994,81 @@ fn server_streamowned_read() {
     }
 }
 
+#[test]
+fn client_stream_buf_read() {
+    for kt in ALL_KEY_TYPES.iter() {
+        let (mut client, mut server) = make_pair(*kt);
+
+        server
+            .write_all(b"hello\r\nworld\r\n")
+            .unwrap();
+
+        {
+            let mut pipe = OtherSession::new(&mut server);
+            let mut stream = Stream::new(&mut client, &mut pipe);
+
+            let mut buf = [0u8; 5];
+            assert_eq!(stream.peek(&mut buf).unwrap(), 5);
+            assert_eq!(&buf, b"hello");
+
+            let mut line = String::new();
+            stream.read_line(&mut line).unwrap();
+            assert_eq!(line, "hello\r\n");
+            line.clear();
+            stream.read_line(&mut line).unwrap();
+            assert_eq!(line, "world\r\n");
+        }
+    }
+}
+
+#[test]
+fn server_streamowned_buf_read() {
+    for kt in ALL_KEY_TYPES.iter() {
+        let (mut client, server) = make_pair(*kt);
+
+        client.write_all(b"hello world").unwrap();
+
+        {
+            let pipe = OtherSession::new(&mut client);
+            let mut stream = StreamOwned::new(server, pipe);
+
+            let mut buf = [0u8; 32];
+            assert_eq!(stream.peek(&mut buf).unwrap(), 11);
+            assert_eq!(&buf[..11], b"hello world");
+
+            assert_eq!(stream.fill_buf().unwrap(), b"hello world");
+            stream.consume(6);
+            check_read(&mut stream, b"world");
+        }
+    }
+}
+
+#[test]
+fn session_buf_read_leaves_plaintext_until_consumed() {
+    let (mut client, mut server) = make_pair(KeyType::RSA);
+    do_handshake(&mut client, &mut server);
+    assert_eq!(server.fill_buf().unwrap(), b"");
+
+    client.write_all(b"hello").unwrap();
+    transfer(&mut client, &mut server);
+    server.process_new_packets().unwrap();
+
+    assert_eq!(server.fill_buf().unwrap(), b"hello");
+    assert_eq!(server.fill_buf().unwrap(), b"hello");
+    server.consume(2);
+    assert_eq!(server.fill_buf().unwrap(), b"llo");
+    server.consume(3);
+    assert_eq!(server.fill_buf().unwrap(), b"");
+
+    client.send_close_notify();
+    transfer(&mut client, &mut server);
+    server.process_new_packets().unwrap();
+    assert_eq!(
+        server.fill_buf().unwrap_err().kind(),
+        io::ErrorKind::ConnectionAborted
+    );
+}
+
 struct FailsWrites {
     errkind: io::ErrorKind,
     after: usize,
@@ -1532,16 +3128,23 @@ fn stream_write_swallows_underlying_io_error_after_plaintext_processed() {
 }
 
 fn make_disjoint_suite_configs() -> (ClientConfig, ServerConfig) {
+    #[cfg(feature = "tls13")]
+    let (server_suite, client_suite) = (
+        CipherSuite::TLS13_CHACHA20_POLY1305_SHA256,
+        CipherSuite::TLS13_AES_256_GCM_SHA384,
+    );
+    #[cfg(not(feature = "tls13"))]
+    let (server_suite, client_suite) = (
+        CipherSuite::TLS_ECDHE_RSA_WITH_CHACHA20_POLY1305_SHA256,
+        CipherSuite::TLS_ECDHE_RSA_WITH_AES_256_GCM_SHA384,
+    );
+
     let kt = KeyType::RSA;
     let mut server_config = make_server_config(kt);
-    server_config.ciphersuites = vec![find_suite(
-        CipherSuite::TLS_ECDHE_RSA_WITH_CHACHA20_POLY1305_SHA256,
-    )];
+    server_config.ciphersuites = vec![find_suite(server_suite)];
 
     let mut client_config = make_client_config(kt);
-    client_config.ciphersuites = vec![find_suite(
-        CipherSuite::TLS_ECDHE_RSA_WITH_AES_256_GCM_SHA384,
-    )];
+    client_config.ciphersuites = vec![find_suite(client_suite)];
 
     (client_config, server_config)
 }
@@ -1558,13 +3161,13 @@ fn client_stream_handshake_error() {
         assert!(rc.is_err());
         assert_eq!(
             format!("{:?}", rc),
-            "Err(Custom { kind: InvalidData, error: AlertReceived(HandshakeFailure) })"
+            "Err(Custom { kind: InvalidData, error: AlertReceived { description: HandshakeFailure, fatal: true } })"
         );
         let rc = client_stream.write(b"hello");
         assert!(rc.is_err());
         assert_eq!(
             format!("{:?}", rc),
-            "Err(Custom { kind: InvalidData, error: AlertReceived(HandshakeFailure) })"
+            "Err(Custom { kind: InvalidData, error: AlertReceived { description: HandshakeFailure, fatal: true } })"
         );
     }
 }
@@ -1580,13 +3183,13 @@ fn client_streamowned_handshake_error() {
     assert!(rc.is_err());
     assert_eq!(
         format!("{:?}", rc),
-        "Err(Custom { kind: InvalidData, error: AlertReceived(HandshakeFailure) })"
+        "Err(Custom { kind: InvalidData, error: AlertReceived { description: HandshakeFailure, fatal: true } })"
     );
     let rc = client_stream.write(b"hello");
     assert!(rc.is_err());
     assert_eq!(
         format!("{:?}", rc),
-        "Err(Custom { kind: InvalidData, error: AlertReceived(HandshakeFailure) })"
+        "Err(Custom { kind: InvalidData, error: AlertReceived { description: HandshakeFailure, fatal: true } })"
     );
 }
 
@@ -1628,6 +3231,195 @@ fn server_streamowned_handshake_error() {
     );
 }
 
+#[test]
+fn split_stream_full_duplex() {
+    let (client, server) = make_pair(KeyType::RSA);
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    let server_thread = thread::spawn(move || {
+        let (sock, _) = listener.accept().unwrap();
+        let mut stream = StreamOwned::new(server, sock);
+        let mut buf = [0u8; 5];
+        stream.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"hello");
+        stream.write_all(b"world").unwrap();
+        stream.flush().unwrap();
+    });
+
+    let sock = TcpStream::connect(addr).unwrap();
+    let (mut reader, mut writer) = rustls::split(client, sock.try_clone().unwrap(), sock);
+
+    // The write is made before the handshake, and sent once reading
+    // has completed it.
+    let writer_thread = thread::spawn(move || {
+        writer.write_all(b"hello").unwrap();
+        writer.flush().unwrap();
+        writer
+    });
+
+    let mut buf = [0u8; 5];
+    reader.read_exact(&mut buf).unwrap();
+    assert_eq!(&buf, b"world");
+
+    let mut writer = writer_thread.join().unwrap();
+    writer.send_close_notify().unwrap();
+    server_thread.join().unwrap();
+}
+
+#[test]
+fn streamowned_split_full_duplex() {
+    let (client, server) = make_pair(KeyType::RSA);
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    let server_thread = thread::spawn(move || {
+        let (sock, _) = listener.accept().unwrap();
+        let (mut reader, mut writer) = StreamOwned::new(server, sock).split();
+        let mut buf = [0u8; 5];
+        reader.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"hello");
+        writer.write_all(b"world").unwrap();
+        writer.flush().unwrap();
+    });
+
+    let sock = TcpStream::connect(addr).unwrap();
+    let (mut reader, mut writer) = StreamOwned::new(client, sock).split();
+
+    let writer_thread = thread::spawn(move || {
+        writer.write_all(b"hello").unwrap();
+        writer.flush().unwrap();
+        writer
+    });
+
+    let mut buf = [0u8; 5];
+    reader.read_exact(&mut buf).unwrap();
+    assert_eq!(&buf, b"world");
+
+    let mut writer = writer_thread.join().unwrap();
+    writer.send_close_notify().unwrap();
+    server_thread.join().unwrap();
+}
+
+macro_rules! unbuffered_transfer {
+    ($from:expr, $to:expr) => {{
+        let mut wire = Vec::new();
+        let mut buf = [0u8; 20000];
+        while $from.wants_write() {
+            let len = $from.write_tls_into(&mut buf).unwrap();
+            wire.extend_from_slice(&buf[..len]);
+        }
+
+        let mut received = Vec::new();
+        let mut plaintext = [0u8; 18432];
+        let mut offs = 0;
+        while offs < wire.len() {
+            match $to
+                .process_tls_record(&wire[offs..], &mut plaintext)
+                .unwrap()
+            {
+                ProcessStatus::Processed {
+                    consumed,
+                    plaintext: len,
+                } => {
+                    offs += consumed;
+                    received.extend_from_slice(&plaintext[..len]);
+                }
+                status => panic!("unexpected {:?}", status),
+            }
+        }
+        received
+    }};
+}
+
+#[test]
+fn unbuffered_session_exchanges_data() {
+    for version in rustls::ALL_VERSIONS {
+        let mut client_config = make_client_config(KeyType::RSA);
+        client_config.set_protocol_versions(&[version]);
+        let (client, server) =
+            make_pair_for_configs(client_config, make_server_config(KeyType::RSA));
+        let mut client = UnbufferedSession::new(client);
+        let mut server = UnbufferedSession::new(server);
+
+        let mut record = [0u8; 64];
+        assert_eq!(
+            Err(EncodeError::HandshakeNotComplete),
+            client.encrypt(b"hello", &mut record)
+        );
+
+        while client.is_handshaking() || server.is_handshaking() {
+            unbuffered_transfer!(client, server);
+            unbuffered_transfer!(server, client);
+        }
+
+        // Encrypting says exactly how much space it needs.
+        let len = client.encrypt(b"hello", &mut record).unwrap();
+        assert_eq!(
+            Err(EncodeError::InsufficientSize { required: len }),
+            client.encrypt(b"hello", &mut record[..len - 1])
+        );
+
+        // As does processing.
+        let mut plaintext = [0u8; 64];
+        assert_eq!(
+            Ok(ProcessStatus::NeedsInput { needed: 2 }),
+            server.process_tls_record(&record[..3], &mut plaintext)
+        );
+        assert_eq!(
+            Ok(ProcessStatus::NeedsInput { needed: 1 }),
+            server.process_tls_record(&record[..len - 1], &mut plaintext)
+        );
+        assert_eq!(
+            Ok(ProcessStatus::NeedsOutputSpace { required: len - 5 }),
+            server.process_tls_record(&record[..len], &mut plaintext[..4])
+        );
+        assert_eq!(
+            Ok(ProcessStatus::Processed {
+                consumed: len,
+                plaintext: 5
+            }),
+            server.process_tls_record(&record[..len], &mut plaintext)
+        );
+        assert_eq!(&plaintext[..5], b"hello");
+
+        let len = server.encrypt(b"world", &mut record).unwrap();
+        assert_eq!(
+            Ok(ProcessStatus::Processed {
+                consumed: len,
+                plaintext: 5
+            }),
+            client.process_tls_record(&record[..len], &mut plaintext)
+        );
+        assert_eq!(&plaintext[..5], b"world");
+
+        // Larger writes are split into several records.
+        let data = vec![0x55u8; 20000];
+        let mut records = vec![0u8; 20000 + 2 * 64];
+        let len = client.encrypt(&data, &mut records).unwrap();
+        let mut plaintext = [0u8; 18432];
+        let mut received = Vec::new();
+        let mut offs = 0;
+        while offs < len {
+            match server.process_tls_record(&records[offs..len], &mut plaintext) {
+                Ok(ProcessStatus::Processed {
+                    consumed,
+                    plaintext: got,
+                }) => {
+                    offs += consumed;
+                    received.extend_from_slice(&plaintext[..got]);
+                }
+                status => panic!("unexpected {:?}", status),
+            }
+        }
+        assert_eq!(received, data);
+
+        client.send_close_notify();
+        assert!(unbuffered_transfer!(client, server).is_empty());
+        assert!(server.peer_has_closed());
+    }
+}
+
 #[test]
 fn server_config_is_clone() {
     let _ = make_server_config(KeyType::RSA).clone();
@@ -1663,11 +3455,33 @@ fn server_complete_io_for_handshake_ending_with_alert() {
     assert!(!server.wants_write(), "but server did send its alert");
     assert_eq!(
         format!("{:?}", pipe.last_error),
-        "Some(AlertReceived(HandshakeFailure))",
+        "Some(AlertReceived { description: HandshakeFailure, fatal: true })",
         "which was received by client"
     );
 }
 
+#[test]
+fn sent_fatal_alert_is_reported() {
+    use rustls::AlertDescription;
+
+    let (client_config, server_config) = make_disjoint_suite_configs();
+    let (mut client, mut server) = make_pair_for_configs(client_config, server_config);
+
+    let errs = do_handshake_until_both_error(&mut client, &mut server).unwrap_err();
+    assert_eq!(
+        errs.last(),
+        Some(&TLSErrorFromPeer::Client(TlsError::AlertReceived {
+            description: AlertDescription::HandshakeFailure,
+            fatal: true,
+        }))
+    );
+    assert_eq!(
+        server.get_sent_fatal_alert(),
+        Some(AlertDescription::HandshakeFailure)
+    );
+    assert_eq!(client.get_sent_fatal_alert(), None);
+}
+
 #[test]
 fn server_exposes_offered_sni() {
     let kt = KeyType::RSA;
@@ -1757,6 +3571,369 @@ fn sni_resolver_works() {
     );
 }
 
+#[test]
+fn reloadable_resolver_rotates_certificate() {
+    let resolver =
+        rustls::ReloadableResolvesChain::new(KeyType::RSA.get_chain(), &KeyType::RSA.get_key())
+            .unwrap();
+
+    let mut server_config = make_server_config(KeyType::RSA);
+    server_config.cert_resolver = resolver.clone();
+    let server_config = Arc::new(server_config);
+
+    // no resumption: each handshake has a fresh client config
+    let client_config = || Arc::new(make_client_config(KeyType::RSA));
+
+    // this handshake resolves its certificate before the reload
+    let mut server1 = ServerSession::new(&server_config);
+    let mut client1 = ClientSession::new(&client_config(), dns_name("localhost")).unwrap();
+    transfer(&mut client1, &mut server1);
+    server1.process_new_packets().unwrap();
+
+    resolver
+        .reload(KeyType::RSA_PSS.get_chain(), &KeyType::RSA_PSS.get_key())
+        .unwrap();
+
+    do_handshake(&mut client1, &mut server1);
+    assert_eq!(client1.get_peer_certificates(), Some(KeyType::RSA.get_chain()));
+
+    let mut server2 = ServerSession::new(&server_config);
+    let mut client2 = ClientSession::new(&client_config(), dns_name("localhost")).unwrap();
+    do_handshake(&mut client2, &mut server2);
+    assert_eq!(client2.get_peer_certificates(), Some(KeyType::RSA_PSS.get_chain()));
+
+    // a failed reload leaves the previous certificate in place
+    assert!(resolver
+        .reload(vec![], &KeyType::RSA.get_key())
+        .is_err());
+    assert!(resolver
+        .reload(KeyType::RSA.get_chain(), &rustls::PrivateKey(vec![]))
+        .is_err());
+
+    let mut server3 = ServerSession::new(&server_config);
+    let mut client3 = ClientSession::new(&client_config(), dns_name("localhost")).unwrap();
+    do_handshake(&mut client3, &mut server3);
+    assert_eq!(client3.get_peer_certificates(), Some(KeyType::RSA_PSS.get_chain()));
+}
+
+#[test]
+fn sni_resolver_works_with_wildcards() {
+    let kt = KeyType::RSA;
+    let mut resolver = rustls::ResolvesServerCertUsingSni::new();
+    let signing_key = sign::RsaSigningKey::new(&kt.get_key()).unwrap();
+    let signing_key: Arc<Box<dyn sign::SigningKey>> = Arc::new(Box::new(signing_key));
+    resolver
+        .add(
+            "*.testserver.com",
+            sign::CertifiedKey::new(kt.get_chain(), signing_key.clone()),
+        )
+        .unwrap();
+
+    let mut server_config = make_server_config(kt);
+    server_config.cert_resolver = Arc::new(resolver);
+    let server_config = Arc::new(server_config);
+
+    let mut server1 = ServerSession::new(&server_config);
+    let mut client1 =
+        ClientSession::new(&Arc::new(make_client_config(kt)), dns_name("second.testserver.com"))
+            .unwrap();
+    let err = do_handshake_until_error(&mut client1, &mut server1);
+    assert_eq!(err, Ok(()));
+
+    // wildcards match exactly one label
+    let mut server2 = ServerSession::new(&server_config);
+    let mut client2 =
+        ClientSession::new(&Arc::new(make_client_config(kt)), dns_name("testserver.com")).unwrap();
+    let err = do_handshake_until_error(&mut client2, &mut server2);
+    assert_eq!(
+        err,
+        Err(TLSErrorFromPeer::Server(TlsError::General(
+            "no server certificate chain resolved".into()
+        )))
+    );
+}
+
+struct DeferringResolver;
+
+impl ResolvesServerCert for DeferringResolver {
+    fn resolve(&self, _client_hello: ClientHello) -> Option<sign::CertifiedKey> {
+        None
+    }
+
+    fn resolve_or_defer(&self, _client_hello: ClientHello) -> rustls::CertResolution {
+        rustls::CertResolution::Pending
+    }
+}
+
+#[test]
+fn server_deferred_cert_resolution() {
+    for kt in ALL_KEY_TYPES.iter() {
+        let mut server_config = make_server_config(*kt);
+        server_config.cert_resolver = Arc::new(DeferringResolver);
+        let server_config = Arc::new(server_config);
+
+        for client_config in AllClientVersions::new(make_client_config(*kt)) {
+            let (mut client, mut server) =
+                make_pair_for_arc_configs(&Arc::new(client_config), &server_config);
+            assert!(!server.wants_certified_key());
+            assert_eq!(
+                server.supply_certified_key(None),
+                Err(TlsError::General(
+                    "no certificate resolution is pending".into()
+                ))
+            );
+
+            transfer(&mut client, &mut server);
+            assert_eq!(server.process_new_packets(), Ok(()));
+            assert!(server.wants_certified_key());
+            assert!(!server.wants_write());
+            assert_eq!(server.get_sni_hostname(), Some("localhost"));
+
+            // still paused
+            assert_eq!(server.process_new_packets(), Ok(()));
+            assert!(server.wants_certified_key());
+
+            let key = sign::any_supported_type(&kt.get_key()).unwrap();
+            let certified_key = sign::CertifiedKey::new(kt.get_chain(), Arc::new(key));
+            assert_eq!(server.supply_certified_key(Some(certified_key)), Ok(()));
+            assert!(!server.wants_certified_key());
+            assert!(server.wants_write());
+
+            do_handshake(&mut client, &mut server);
+            assert!(!server.is_handshaking());
+        }
+    }
+}
+
+#[test]
+fn server_deferred_cert_resolution_can_fail() {
+    let kt = KeyType::RSA;
+    let mut server_config = make_server_config(kt);
+    server_config.cert_resolver = Arc::new(DeferringResolver);
+    let server_config = Arc::new(server_config);
+
+    let (mut client, mut server) =
+        make_pair_for_arc_configs(&Arc::new(make_client_config(kt)), &server_config);
+    transfer(&mut client, &mut server);
+    assert_eq!(server.process_new_packets(), Ok(()));
+    assert!(server.wants_certified_key());
+
+    let err = TlsError::General("no server certificate chain resolved".into());
+    assert_eq!(server.supply_certified_key(None), Err(err.clone()));
+    assert!(!server.wants_certified_key());
+    assert_eq!(server.process_new_packets(), Err(err));
+}
+
+struct DeferredSigningKey(Box<dyn sign::SigningKey>);
+
+impl sign::SigningKey for DeferredSigningKey {
+    fn choose_scheme(&self, offered: &[SignatureScheme]) -> Option<Box<dyn sign::Signer>> {
+        self.0
+            .choose_scheme(offered)
+            .map(|signer| Box::new(DeferredSigner(signer)) as Box<dyn sign::Signer>)
+    }
+
+    fn algorithm(&self) -> rustls::internal::msgs::enums::SignatureAlgorithm {
+        self.0.algorithm()
+    }
+}
+
+struct DeferredSigner(Box<dyn sign::Signer>);
+
+impl sign::Signer for DeferredSigner {
+    fn sign(&self, _message: &[u8]) -> Result<Vec<u8>, TlsError> {
+        panic!("sign_or_defer should be used");
+    }
+
+    fn get_scheme(&self) -> SignatureScheme {
+        self.0.get_scheme()
+    }
+
+    fn sign_or_defer(&self, _message: &[u8]) -> Result<Option<Vec<u8>>, TlsError> {
+        Ok(None)
+    }
+}
+
+fn make_server_config_with_deferred_signing(kt: KeyType) -> ServerConfig {
+    let key = sign::any_supported_type(&kt.get_key()).unwrap();
+    let key: Box<dyn sign::SigningKey> = Box::new(DeferredSigningKey(key));
+    let mut server_config = make_server_config(kt);
+    server_config.cert_resolver = Arc::new(AlwaysResolves(sign::CertifiedKey::new(
+        kt.get_chain(),
+        Arc::new(key),
+    )));
+    server_config
+}
+
+struct AlwaysResolves(sign::CertifiedKey);
+
+impl ResolvesServerCert for AlwaysResolves {
+    fn resolve(&self, _client_hello: ClientHello) -> Option<sign::CertifiedKey> {
+        Some(self.0.clone())
+    }
+}
+
+#[test]
+fn server_deferred_signing() {
+    for kt in ALL_KEY_TYPES.iter() {
+        let server_config = Arc::new(make_server_config_with_deferred_signing(*kt));
+        let real_key = sign::any_supported_type(&kt.get_key()).unwrap();
+
+        for client_config in AllClientVersions::new(make_client_config(*kt)) {
+            let (mut client, mut server) =
+                make_pair_for_arc_configs(&Arc::new(client_config), &server_config);
+            assert!(server.signature_request().is_none());
+            assert_eq!(
+                server.supply_signature(None),
+                Err(TlsError::General("no signature is pending".into()))
+            );
+
+            transfer(&mut client, &mut server);
+            assert_eq!(server.process_new_packets(), Ok(()));
+
+            let (scheme, message) = server.signature_request().unwrap();
+            let sig = real_key
+                .choose_scheme(&[scheme])
+                .unwrap()
+                .sign(message)
+                .unwrap();
+
+            // still paused
+            assert_eq!(server.process_new_packets(), Ok(()));
+            assert!(server.signature_request().is_some());
+
+            assert_eq!(server.supply_signature(Some(sig)), Ok(()));
+            assert!(server.signature_request().is_none());
+
+            do_handshake(&mut client, &mut server);
+            assert!(!server.is_handshaking());
+            assert!(!client.is_handshaking());
+        }
+    }
+}
+
+#[test]
+fn server_deferred_signing_can_fail() {
+    let kt = KeyType::RSA;
+    let server_config = Arc::new(make_server_config_with_deferred_signing(kt));
+
+    for client_config in AllClientVersions::new(make_client_config(kt)) {
+        let (mut client, mut server) =
+            make_pair_for_arc_configs(&Arc::new(client_config), &server_config);
+        transfer(&mut client, &mut server);
+        assert_eq!(server.process_new_packets(), Ok(()));
+        assert!(server.signature_request().is_some());
+
+        let err = TlsError::General("no signature supplied".into());
+        assert_eq!(server.supply_signature(None), Err(err.clone()));
+        assert!(server.signature_request().is_none());
+        assert_eq!(server.process_new_packets(), Err(err));
+    }
+}
+
+#[test]
+fn ed25519_signing_key_works_end_to_end() {
+    let kt = KeyType::ED25519;
+    assert!(sign::Ed25519SigningKey::new(&KeyType::RSA.get_key()).is_err());
+    assert!(sign::Ed25519SigningKey::new(&KeyType::ECDSA.get_key()).is_err());
+
+    let key: Box<dyn sign::SigningKey> =
+        Box::new(sign::Ed25519SigningKey::new(&kt.get_key()).unwrap());
+    assert!(key.choose_scheme(&[SignatureScheme::ED25519]).is_some());
+    assert!(key.choose_scheme(&[SignatureScheme::ECDSA_NISTP256_SHA256]).is_none());
+
+    let mut server_config = make_server_config_with_mandatory_client_auth(kt);
+    server_config.cert_resolver = Arc::new(AlwaysResolves(sign::CertifiedKey::new(
+        kt.get_chain(),
+        Arc::new(key),
+    )));
+    let server_config = Arc::new(server_config);
+
+    for client_config in AllClientVersions::new(make_client_config_with_auth(kt)) {
+        let (mut client, mut server) =
+            make_pair_for_arc_configs(&Arc::new(client_config), &server_config);
+        do_handshake(&mut client, &mut server);
+        assert!(server.get_peer_certificates().is_some());
+    }
+}
+
+#[test]
+fn rsa_pss_keys_work_end_to_end() {
+    let kt = KeyType::RSA_PSS;
+    let server_config = Arc::new(make_server_config_with_mandatory_client_auth(kt));
+
+    for client_config in AllClientVersions::new(make_client_config_with_auth(kt)) {
+        let (mut client, mut server) =
+            make_pair_for_arc_configs(&Arc::new(client_config), &server_config);
+        do_handshake(&mut client, &mut server);
+        assert!(server.get_peer_certificates().is_some());
+    }
+}
+
+#[test]
+fn rsa_pss_keys_only_make_pss_signatures() {
+    use sign::SigningKey;
+
+    let kt = KeyType::RSA_PSS;
+    let key = sign::RsaSigningKey::new(&kt.get_key()).unwrap();
+    assert!(key.choose_scheme(&[SignatureScheme::RSA_PKCS1_SHA256]).is_none());
+    assert!(key.choose_scheme(&[SignatureScheme::RSA_PSS_SHA256]).is_none());
+    assert_eq!(
+        key.choose_scheme(&[
+            SignatureScheme::RSA_PSS_SHA512,
+            SignatureScheme::RSA_PSS_PSS_SHA256
+        ])
+        .unwrap()
+        .get_scheme(),
+        SignatureScheme::RSA_PSS_PSS_SHA256
+    );
+
+    // this key's parameters restrict it to SHA-384
+    let key = sign::RsaSigningKey::new(&kt.get_client_key()).unwrap();
+    assert!(key.choose_scheme(&[SignatureScheme::RSA_PSS_PSS_SHA256]).is_none());
+    assert!(key.choose_scheme(&[SignatureScheme::RSA_PSS_PSS_SHA384]).is_some());
+
+    // and ordinary RSA keys can't make PSS_PSS signatures
+    let key = sign::RsaSigningKey::new(&KeyType::RSA.get_key()).unwrap();
+    assert!(key.choose_scheme(&[SignatureScheme::RSA_PSS_PSS_SHA256]).is_none());
+}
+
+#[test]
+fn sec1_ecdsa_keys_work_end_to_end() {
+    let kt = KeyType::ECDSA;
+    let sec1_key = |part| {
+        let pem = bytes_for("ecdsa", part);
+        let b64: String = std::str::from_utf8(pem)
+            .unwrap()
+            .lines()
+            .filter(|line| !line.starts_with("-----"))
+            .collect();
+        rustls::PrivateKey(base64::decode(b64).unwrap())
+    };
+
+    // end.sec1 is on P-256, client.sec1 is on P-384
+    assert!(sign::any_ecdsa_type(&sec1_key("end.sec1")).is_ok());
+    assert!(sign::any_supported_type(&sec1_key("client.sec1")).is_ok());
+
+    let mut server_config = make_server_config_with_mandatory_client_auth(kt);
+    server_config
+        .set_single_cert(kt.get_chain(), sec1_key("end.sec1"))
+        .unwrap();
+    let server_config = Arc::new(server_config);
+
+    let mut client_config = make_client_config(kt);
+    client_config
+        .set_single_client_cert(kt.get_client_chain(), sec1_key("client.sec1"))
+        .unwrap();
+
+    for client_config in AllClientVersions::new(client_config) {
+        let (mut client, mut server) =
+            make_pair_for_arc_configs(&Arc::new(client_config), &server_config);
+        do_handshake(&mut client, &mut server);
+        assert!(server.get_peer_certificates().is_some());
+    }
+}
+
 #[test]
 fn sni_resolver_rejects_wrong_names() {
     let kt = KeyType::RSA;
@@ -1818,6 +3995,115 @@ fn sni_resolver_rejects_bad_certs() {
     );
 }
 
+#[test]
+fn virtual_host_overrides_cert_and_alpn() {
+    let kt = KeyType::RSA;
+    let signing_key = sign::RsaSigningKey::new(&kt.get_key()).unwrap();
+    let signing_key: Arc<Box<dyn sign::SigningKey>> = Arc::new(Box::new(signing_key));
+
+    let mut server_config = ServerConfig::new(NoClientAuth::new());
+    server_config.set_protocols(&[b"http/1.1".to_vec()]);
+    server_config
+        .add_virtual_host(
+            "LOCALHOST",
+            rustls::VirtualHost {
+                certified_key: Some(sign::CertifiedKey::new(kt.get_chain(), signing_key)),
+                alpn_protocols: Some(vec![b"h2".to_vec(), b"http/1.1".to_vec()]),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+    let server_config = Arc::new(server_config);
+
+    let mut client_config = make_client_config(kt);
+    client_config.set_protocols(&[b"h2".to_vec(), b"http/1.1".to_vec()]);
+    let client_config = Arc::new(client_config);
+
+    let mut server = ServerSession::new(&server_config);
+    let mut client = ClientSession::new(&client_config, dns_name("localhost")).unwrap();
+    do_handshake(&mut client, &mut server);
+    assert_eq!(server.get_alpn_protocol(), Some(&b"h2"[..]));
+
+    // no certificate outside the virtual host
+    let mut server = ServerSession::new(&server_config);
+    let mut client = ClientSession::new(&client_config, dns_name("testserver.com")).unwrap();
+    assert_eq!(
+        do_handshake_until_error(&mut client, &mut server),
+        Err(TLSErrorFromPeer::Server(TlsError::General(
+            "no server certificate chain resolved".into()
+        )))
+    );
+}
+
+#[test]
+fn virtual_host_requires_client_auth() {
+    for kt in ALL_KEY_TYPES.iter() {
+        let mut server_config = make_server_config(*kt);
+        server_config
+            .add_virtual_host(
+                "testserver.com",
+                rustls::VirtualHost {
+                    client_cert_verifier: Some(AllowAnyAuthenticatedClient::new(
+                        get_client_root_store(*kt),
+                    )),
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+        let server_config = Arc::new(server_config);
+
+        for client_config in AllClientVersions::new(make_client_config(*kt)) {
+            let client_config = Arc::new(client_config);
+
+            let mut server = ServerSession::new(&server_config);
+            let mut client = ClientSession::new(&client_config, dns_name("localhost")).unwrap();
+            assert_eq!(do_handshake_until_error(&mut client, &mut server), Ok(()));
+            assert_eq!(server.get_peer_certificates(), None);
+
+            let mut server = ServerSession::new(&server_config);
+            let mut client =
+                ClientSession::new(&client_config, dns_name("testserver.com")).unwrap();
+            assert_eq!(
+                do_handshake_until_error(&mut client, &mut server),
+                Err(TLSErrorFromPeer::Server(TlsError::NoCertificatesPresented))
+            );
+        }
+
+        for client_config in AllClientVersions::new(make_client_config_with_auth(*kt)) {
+            let mut server = ServerSession::new(&server_config);
+            let mut client =
+                ClientSession::new(&Arc::new(client_config), dns_name("testserver.com")).unwrap();
+            assert_eq!(do_handshake_until_error(&mut client, &mut server), Ok(()));
+            assert!(server.get_peer_certificates().is_some());
+        }
+    }
+}
+
+#[test]
+fn virtual_host_rejects_wrong_names() {
+    let kt = KeyType::RSA;
+    let signing_key = sign::RsaSigningKey::new(&kt.get_key()).unwrap();
+    let signing_key: Arc<Box<dyn sign::SigningKey>> = Arc::new(Box::new(signing_key));
+    let mut server_config = ServerConfig::new(NoClientAuth::new());
+
+    assert_eq!(
+        Err(TlsError::General("Bad DNS name".into())),
+        server_config.add_virtual_host("not a name", rustls::VirtualHost::default())
+    );
+    assert_eq!(
+        Err(TlsError::General(
+            "The server certificate is not valid for the given name".into()
+        )),
+        server_config.add_virtual_host(
+            "not-localhost",
+            rustls::VirtualHost {
+                certified_key: Some(sign::CertifiedKey::new(kt.get_chain(), signing_key)),
+                ..Default::default()
+            }
+        )
+    );
+}
+
 fn do_exporter_test(client_config: ClientConfig, server_config: ServerConfig) {
     let mut client_secret = [0u8; 64];
     let mut server_secret = [0u8; 64];
@@ -1856,28 +4142,179 @@ fn do_exporter_test(client_config: ClientConfig, server_config: ServerConfig) {
     assert_eq!(client_secret.to_vec(), server_secret.to_vec());
 }
 
+#[cfg(feature = "tls12")]
+#[test]
+fn test_tls12_exporter() {
+    for kt in ALL_KEY_TYPES.iter() {
+        let mut client_config = make_client_config(*kt);
+        let server_config = make_server_config(*kt);
+        client_config.set_protocol_versions(&[&rustls::version::TLS12]);
+
+        do_exporter_test(client_config, server_config);
+    }
+}
+
+#[cfg(feature = "tls13")]
+#[test]
+fn test_tls13_exporter() {
+    for kt in ALL_KEY_TYPES.iter() {
+        let mut client_config = make_client_config(*kt);
+        let server_config = make_server_config(*kt);
+        client_config.set_protocol_versions(&[&rustls::version::TLS13]);
+
+        do_exporter_test(client_config, server_config);
+    }
+}
+
+#[test]
+fn test_tls_exporter_channel_binding() {
+    for version in rustls::ALL_VERSIONS {
+        let mut client_config = make_client_config(KeyType::RSA);
+        client_config.set_protocol_versions(&[version]);
+        let (mut client, mut server) =
+            make_pair_for_configs(client_config, make_server_config(KeyType::RSA));
+
+        assert_eq!(
+            Err(TlsError::HandshakeNotComplete),
+            client.get_tls_exporter_channel_binding()
+        );
+        do_handshake(&mut client, &mut server);
+
+        let mut expected = [0u8; 32];
+        client
+            .export_keying_material(&mut expected, b"EXPORTER-Channel-Binding", None)
+            .unwrap();
+        assert_eq!(Ok(expected), client.get_tls_exporter_channel_binding());
+        assert_eq!(Ok(expected), server.get_tls_exporter_channel_binding());
+
+        // A different connection has a different binding.
+        let (mut client, mut server) = make_pair(KeyType::RSA);
+        do_handshake(&mut client, &mut server);
+        assert_ne!(Ok(expected), client.get_tls_exporter_channel_binding());
+    }
+}
+
+#[cfg(feature = "tls13")]
 #[test]
-fn test_tls12_exporter() {
-    for kt in ALL_KEY_TYPES.iter() {
-        let mut client_config = make_client_config(*kt);
-        let server_config = make_server_config(*kt);
-        client_config.versions = vec![ProtocolVersion::TLSv1_2];
+fn test_exporter_secret_derivation() {
+    for version in rustls::ALL_VERSIONS {
+        let mut client_config = make_client_config(KeyType::RSA);
+        client_config.set_protocol_versions(&[version]);
+        let (mut client, mut server) =
+            make_pair_for_configs(client_config, make_server_config(KeyType::RSA));
 
-        do_exporter_test(client_config, server_config);
+        assert!(matches!(
+            client.exporter_secret(b"EXPORTER-test", None),
+            Err(TlsError::HandshakeNotComplete)
+        ));
+        do_handshake(&mut client, &mut server);
+
+        let client_secret = client
+            .exporter_secret(b"EXPORTER-test", Some(b"context"))
+            .unwrap();
+        let server_secret = server
+            .exporter_secret(b"EXPORTER-test", Some(b"context"))
+            .unwrap();
+        let expand = |secret: &rustls::ExporterSecret, label: &[u8]| {
+            let mut out = [0u8; 40];
+            secret
+                .expand_label(&mut out, label, b"")
+                .unwrap();
+            out.to_vec()
+        };
+        assert_eq!(expand(&client_secret, b"key"), expand(&server_secret, b"key"));
+        assert_ne!(expand(&client_secret, b"key"), expand(&client_secret, b"iv"));
+
+        let other_secret = client
+            .exporter_secret(b"EXPORTER-other", Some(b"context"))
+            .unwrap();
+        assert_ne!(expand(&client_secret, b"key"), expand(&other_secret, b"key"));
+
+        let client_next = client_secret
+            .derive_secret(b"next", b"")
+            .unwrap();
+        let server_next = server_secret
+            .derive_secret(b"next", b"")
+            .unwrap();
+        assert_eq!(client_next.hash_len(), client_secret.hash_len());
+        assert_eq!(expand(&client_next, b"key"), expand(&server_next, b"key"));
+        assert_ne!(expand(&client_next, b"key"), expand(&client_secret, b"key"));
+
+        let mut too_long = vec![0u8; 255 * client_secret.hash_len() + 1];
+        assert!(client_secret
+            .expand_label(&mut too_long, b"key", b"")
+            .is_err());
+        let mut out = [0u8; 16];
+        assert!(client_secret
+            .expand_label(&mut out, &[b'x'; 250], b"")
+            .is_err());
+        assert!(client_secret
+            .expand_label(&mut out, b"key", &[0u8; 256])
+            .is_err());
     }
 }
 
 #[test]
-fn test_tls13_exporter() {
+fn test_tls_server_end_point_channel_binding() {
     for kt in ALL_KEY_TYPES.iter() {
-        let mut client_config = make_client_config(*kt);
-        let server_config = make_server_config(*kt);
-        client_config.versions = vec![ProtocolVersion::TLSv1_3];
+        let (mut client, mut server) = make_pair(*kt);
+        assert_eq!(
+            Err(TlsError::HandshakeNotComplete),
+            client.get_tls_server_end_point_channel_binding()
+        );
+        do_handshake(&mut client, &mut server);
 
-        do_exporter_test(client_config, server_config);
+        let cert = &kt.get_chain()[0].0;
+        let binding = client.get_tls_server_end_point_channel_binding();
+        match kt {
+            // Both test end-entity certificates are signed with SHA-256.
+            KeyType::RSA | KeyType::ECDSA => assert_eq!(
+                Ok(ring::digest::digest(&ring::digest::SHA256, cert)
+                    .as_ref()
+                    .to_vec()),
+                binding
+            ),
+            _ => assert!(binding.is_err()),
+        }
     }
 }
 
+#[cfg(feature = "tls12")]
+#[test]
+fn test_tls12_exporter_refuses_long_context() {
+    let mut client_config = make_client_config(KeyType::RSA);
+    client_config.set_protocol_versions(&[&rustls::version::TLS12]);
+    let (mut client, mut server) =
+        make_pair_for_configs(client_config, make_server_config(KeyType::RSA));
+    do_handshake(&mut client, &mut server);
+
+    let mut secret = [0u8; 32];
+    let context = vec![0u8; 0x10000];
+    assert!(client
+        .export_keying_material(&mut secret, b"label", Some(&context))
+        .is_err());
+    assert_eq!(
+        Ok(()),
+        client.export_keying_material(&mut secret, b"label", Some(&context[..0xffff]))
+    );
+}
+
+#[cfg(feature = "tls13")]
+#[test]
+fn test_tls13_exporter_refuses_long_output() {
+    let mut client_config = make_client_config(KeyType::RSA);
+    client_config.set_protocol_versions(&[&rustls::version::TLS13]);
+    let (mut client, mut server) =
+        make_pair_for_configs(client_config, make_server_config(KeyType::RSA));
+    do_handshake(&mut client, &mut server);
+
+    // HKDF-Expand can produce at most 255 blocks.
+    let mut secret = vec![0u8; 255 * 64 + 1];
+    assert!(client
+        .export_keying_material(&mut secret, b"label", None)
+        .is_err());
+}
+
 fn do_suite_test(
     client_config: ClientConfig,
     server_config: ServerConfig,
@@ -1936,54 +4373,64 @@ fn find_suite(suite: CipherSuite) -> &'static SupportedCipherSuite {
     panic!("find_suite given unsupported suite");
 }
 
-static TEST_CIPHERSUITES: [(ProtocolVersion, KeyType, CipherSuite); 9] = [
+static TEST_CIPHERSUITES: &[(&rustls::SupportedProtocolVersion, KeyType, CipherSuite)] = &[
+    #[cfg(feature = "tls13")]
     (
-        ProtocolVersion::TLSv1_3,
+        &rustls::version::TLS13,
         KeyType::RSA,
         CipherSuite::TLS13_CHACHA20_POLY1305_SHA256,
     ),
+    #[cfg(feature = "tls13")]
     (
-        ProtocolVersion::TLSv1_3,
+        &rustls::version::TLS13,
         KeyType::RSA,
         CipherSuite::TLS13_AES_256_GCM_SHA384,
     ),
+    #[cfg(feature = "tls13")]
     (
-        ProtocolVersion::TLSv1_3,
+        &rustls::version::TLS13,
         KeyType::RSA,
         CipherSuite::TLS13_AES_128_GCM_SHA256,
     ),
+    #[cfg(feature = "tls12")]
     (
-        ProtocolVersion::TLSv1_2,
+        &rustls::version::TLS12,
         KeyType::ECDSA,
         CipherSuite::TLS_ECDHE_ECDSA_WITH_CHACHA20_POLY1305_SHA256,
     ),
+    #[cfg(feature = "tls12")]
     (
-        ProtocolVersion::TLSv1_2,
+        &rustls::version::TLS12,
         KeyType::RSA,
         CipherSuite::TLS_ECDHE_RSA_WITH_CHACHA20_POLY1305_SHA256,
     ),
+    #[cfg(feature = "tls12")]
     (
-        ProtocolVersion::TLSv1_2,
+        &rustls::version::TLS12,
         KeyType::ECDSA,
         CipherSuite::TLS_ECDHE_ECDSA_WITH_AES_256_GCM_SHA384,
     ),
+    #[cfg(feature = "tls12")]
     (
-        ProtocolVersion::TLSv1_2,
+        &rustls::version::TLS12,
         KeyType::ECDSA,
         CipherSuite::TLS_ECDHE_ECDSA_WITH_AES_128_GCM_SHA256,
     ),
+    #[cfg(feature = "tls12")]
     (
-        ProtocolVersion::TLSv1_2,
+        &rustls::version::TLS12,
         KeyType::RSA,
         CipherSuite::TLS_ECDHE_RSA_WITH_AES_256_GCM_SHA384,
     ),
+    #[cfg(feature = "tls12")]
     (
-        ProtocolVersion::TLSv1_2,
+        &rustls::version::TLS12,
         KeyType::RSA,
         CipherSuite::TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256,
     ),
 ];
 
+#[cfg(feature = "tls13")]
 #[test]
 fn negotiated_ciphersuite_default() {
     for kt in ALL_KEY_TYPES.iter() {
@@ -2008,9 +4455,9 @@ fn negotiated_ciphersuite_client() {
         let scs = find_suite(suite);
         let mut client_config = make_client_config(kt);
         client_config.ciphersuites = vec![scs];
-        client_config.versions = vec![version];
+        client_config.set_protocol_versions(&[version]);
 
-        do_suite_test(client_config, make_server_config(kt), scs, version);
+        do_suite_test(client_config, make_server_config(kt), scs, version.version);
     }
 }
 
@@ -2021,9 +4468,306 @@ fn negotiated_ciphersuite_server() {
         let scs = find_suite(suite);
         let mut server_config = make_server_config(kt);
         server_config.ciphersuites = vec![scs];
-        server_config.versions = vec![version];
+        server_config.set_protocol_versions(&[version]);
+
+        do_suite_test(make_client_config(kt), server_config, scs, version.version);
+    }
+}
+
+/// Protect `data` as a single record of `content_type`, using secrets
+/// extracted from a session, the way kTLS would.
+#[cfg(feature = "secret_extraction")]
+fn seal_with_extracted_secrets(
+    (seq, secrets): &(u64, rustls::ConnectionTrafficSecrets),
+    version: ProtocolVersion,
+    content_type: u8,
+    data: &[u8],
+) -> Vec<u8> {
+    use rustls::ConnectionTrafficSecrets;
+    use ring::aead;
+
+    let (alg, key, mut nonce) = match secrets {
+        ConnectionTrafficSecrets::Aes128Gcm { key, salt, iv } => {
+            (&aead::AES_128_GCM, &key[..], [salt.to_vec(), iv.to_vec()].concat())
+        }
+        ConnectionTrafficSecrets::Aes256Gcm { key, salt, iv } => {
+            (&aead::AES_256_GCM, &key[..], [salt.to_vec(), iv.to_vec()].concat())
+        }
+        ConnectionTrafficSecrets::Chacha20Poly1305 { key, iv } => {
+            (&aead::CHACHA20_POLY1305, &key[..], iv.to_vec())
+        }
+        _ => unreachable!(),
+    };
+    let key = aead::LessSafeKey::new(aead::UnboundKey::new(alg, key).unwrap());
+
+    // TLS1.2 GCM sends the nonce's last 8 bytes explicitly; we just use
+    // those we were given.  Everything else xors in the sequence number.
+    let explicit = version == ProtocolVersion::TLSv1_2 && alg != &aead::CHACHA20_POLY1305;
+    if !explicit {
+        for (n, s) in nonce[4..].iter_mut().zip(&seq.to_be_bytes()) {
+            *n ^= s;
+        }
+    }
+
+    let mut payload = data.to_vec();
+    let mut aad = Vec::new();
+    let outer_type = if version == ProtocolVersion::TLSv1_3 {
+        payload.push(content_type);
+        aad.extend_from_slice(&[0x17, 0x03, 0x03]);
+        aad.extend_from_slice(&((payload.len() + 16) as u16).to_be_bytes());
+        0x17
+    } else {
+        aad.extend_from_slice(&seq.to_be_bytes());
+        aad.extend_from_slice(&[content_type, 0x03, 0x03]);
+        aad.extend_from_slice(&(payload.len() as u16).to_be_bytes());
+        content_type
+    };
+    key.seal_in_place_append_tag(
+        aead::Nonce::try_assume_unique_for_key(&nonce).unwrap(),
+        aead::Aad::from(aad),
+        &mut payload,
+    )
+    .unwrap();
+
+    let mut body = if explicit { nonce[4..].to_vec() } else { Vec::new() };
+    body.extend_from_slice(&payload);
+    let mut record = vec![outer_type, 0x03, 0x03];
+    record.extend_from_slice(&(body.len() as u16).to_be_bytes());
+    record.extend_from_slice(&body);
+    record
+}
+
+#[cfg(feature = "secret_extraction")]
+#[test]
+fn test_secret_extraction() {
+    for item in TEST_CIPHERSUITES.iter() {
+        let (version, kt, suite) = *item;
+        let mut client_config = make_client_config(kt);
+        client_config.ciphersuites = vec![find_suite(suite)];
+        client_config.set_protocol_versions(&[version]);
+        let client_config = Arc::new(client_config);
+        let server_config = Arc::new(make_server_config(kt));
+
+        // Each side's tx secrets must be usable to send to the other side,
+        // after some records have been exchanged.
+        for client_extracts in &[true, false] {
+            let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
+            do_handshake(&mut client, &mut server);
+            client.write_all(b"request").unwrap();
+            transfer(&mut client, &mut server);
+            server.process_new_packets().unwrap();
+            check_read(&mut server, b"request");
+            server.write_all(b"response").unwrap();
+            transfer(&mut server, &mut client);
+            client.process_new_packets().unwrap();
+            check_read(&mut client, b"response");
+
+            let (record, mut peer): (_, Box<dyn Session>) = if *client_extracts {
+                let secrets = client.dangerous_extract_secrets().unwrap();
+                assert!(secrets.tx.0 > 0);
+                let record = seal_with_extracted_secrets(&secrets.tx, version.version, 0x17, b"extracted");
+                (record, Box::new(server))
+            } else {
+                let secrets = server.dangerous_extract_secrets().unwrap();
+                assert!(secrets.tx.0 > 0);
+                let record = seal_with_extracted_secrets(&secrets.tx, version.version, 0x17, b"extracted");
+                (record, Box::new(client))
+            };
+
+            peer.read_tls(&mut &record[..]).unwrap();
+            peer.process_new_packets().unwrap();
+            check_read(&mut peer, b"extracted");
+        }
+    }
+}
+
+#[cfg(feature = "secret_extraction")]
+#[test]
+fn test_secret_extraction_refused() {
+    let (mut client, mut server) = make_pair(KeyType::RSA);
+    transfer(&mut client, &mut server);
+    server.process_new_packets().unwrap();
+    assert!(matches!(
+        server.dangerous_extract_secrets(),
+        Err(TlsError::HandshakeNotComplete)
+    ));
+
+    let (mut client, mut server) = make_pair(KeyType::RSA);
+    do_handshake(&mut client, &mut server);
+    client.write_all(b"unsent").unwrap();
+    assert!(matches!(
+        client.dangerous_extract_secrets(),
+        Err(TlsError::General(_))
+    ));
+
+    let (mut client, mut server) = make_pair(KeyType::RSA);
+    do_handshake(&mut client, &mut server);
+    server.write_all(b"unread").unwrap();
+    transfer(&mut server, &mut client);
+    client.process_new_packets().unwrap();
+    assert!(matches!(
+        client.dangerous_extract_secrets(),
+        Err(TlsError::General(_))
+    ));
+}
+
+#[cfg(feature = "session_export")]
+#[test]
+fn server_session_export_and_import() {
+    for item in TEST_CIPHERSUITES.iter() {
+        let (version, kt, suite) = *item;
+        let mut client_config = make_client_config(kt);
+        client_config.ciphersuites = vec![find_suite(suite)];
+        client_config.set_protocol_versions(&[version]);
+        client_config.alpn_protocols = vec![b"proto".to_vec()];
+        let mut server_config = make_server_config(kt);
+        server_config.alpn_protocols = vec![b"proto".to_vec()];
+        let server_config = Arc::new(server_config);
+
+        let (mut client, mut server) =
+            make_pair_for_arc_configs(&Arc::new(client_config), &server_config);
+        do_handshake(&mut client, &mut server);
+        client.write_all(b"request").unwrap();
+        transfer(&mut client, &mut server);
+        server.process_new_packets().unwrap();
+        check_read(&mut server, b"request");
+
+        let mut exported_key = [0u8; 32];
+        server
+            .export_keying_material(&mut exported_key, b"label", None)
+            .unwrap();
+
+        let exported = server.dangerous_export().unwrap();
+        let mut server = ServerSession::dangerous_import(&server_config, &exported).unwrap();
+        assert!(!server.is_handshaking());
+        assert_eq!(server.get_negotiated_ciphersuite().unwrap().suite, suite);
+        assert_eq!(server.get_protocol_version(), Some(version.version));
+        assert_eq!(server.get_sni_hostname(), Some("localhost"));
+        assert_eq!(server.get_alpn_protocol(), Some(&b"proto"[..]));
+
+        let mut imported_key = [0u8; 32];
+        server
+            .export_keying_material(&mut imported_key, b"label", None)
+            .unwrap();
+        assert_eq!(exported_key, imported_key);
+
+        server.write_all(b"response").unwrap();
+        transfer(&mut server, &mut client);
+        client.process_new_packets().unwrap();
+        check_read(&mut client, b"response");
+
+        client.write_all(b"another request").unwrap();
+        transfer(&mut client, &mut server);
+        server.process_new_packets().unwrap();
+        check_read(&mut server, b"another request");
+    }
+}
+
+#[cfg(feature = "session_export")]
+#[test]
+fn server_session_export_refused() {
+    let (mut client, mut server) = make_pair(KeyType::RSA);
+    transfer(&mut client, &mut server);
+    server.process_new_packets().unwrap();
+    assert!(matches!(
+        server.dangerous_export(),
+        Err(TlsError::HandshakeNotComplete)
+    ));
+
+    let (mut client, mut server) = make_pair(KeyType::RSA);
+    do_handshake(&mut client, &mut server);
+    client.write_all(b"unread").unwrap();
+    transfer(&mut client, &mut server);
+    server.process_new_packets().unwrap();
+    assert!(matches!(
+        server.dangerous_export(),
+        Err(TlsError::General(_))
+    ));
+}
+
+#[cfg(feature = "session_export")]
+#[test]
+fn server_session_import_rejects_bad_input() {
+    let server_config = Arc::new(make_server_config(KeyType::RSA));
+    let (mut client, mut server) = make_pair_for_arc_configs(
+        &Arc::new(make_client_config(KeyType::RSA)),
+        &server_config,
+    );
+    do_handshake(&mut client, &mut server);
+    let suite = server
+        .get_negotiated_ciphersuite()
+        .unwrap()
+        .suite;
+    let exported = server.dangerous_export().unwrap();
+
+    let mut wrong_format = exported.clone();
+    wrong_format[0] ^= 0xff;
+    for bad in &[&b""[..], &exported[..exported.len() - 1], &wrong_format] {
+        assert!(matches!(
+            ServerSession::dangerous_import(&server_config, bad),
+            Err(TlsError::General(_))
+        ));
+    }
+
+    let mut other_config = make_server_config(KeyType::RSA);
+    other_config.ciphersuites = ALL_CIPHERSUITES
+        .iter()
+        .filter(|scs| scs.suite != suite)
+        .copied()
+        .collect();
+    assert!(ServerSession::dangerous_import(&Arc::new(other_config), &exported).is_err());
+}
+
+#[cfg(all(feature = "secret_extraction", feature = "tls12"))]
+#[test]
+fn tls12_renegotiation_follows_policy() {
+    use rustls::RenegotiationPolicy;
+
+    let kt = KeyType::RSA;
+    let aborted = Err(TlsError::PeerIncompatibleError(
+        "peer attempted renegotiation".into(),
+    ));
+
+    for policy in &[RenegotiationPolicy::Decline, RenegotiationPolicy::Abort] {
+        // The server sends a HelloRequest.
+        let mut client_config = make_client_config(kt);
+        client_config.set_protocol_versions(&[&rustls::version::TLS12]);
+        client_config.renegotiation_policy = *policy;
+        let (mut client, mut server) = make_pair_for_configs(client_config, make_server_config(kt));
+        do_handshake(&mut client, &mut server);
+
+        let secrets = server.dangerous_extract_secrets().unwrap();
+        let record =
+            seal_with_extracted_secrets(&secrets.tx, ProtocolVersion::TLSv1_2, 0x16, &[0, 0, 0, 0]);
+        client.read_tls(&mut &record[..]).unwrap();
+        if *policy == RenegotiationPolicy::Decline {
+            assert_eq!(client.process_new_packets(), Ok(()));
+            assert!(client.wants_write());
+            assert!(!client.is_handshaking());
+        } else {
+            assert_eq!(client.process_new_packets(), aborted);
+        }
+
+        // The client sends a new ClientHello.
+        let mut server_config = make_server_config(kt);
+        server_config.renegotiation_policy = *policy;
+        let mut client_config = make_client_config(kt);
+        client_config.set_protocol_versions(&[&rustls::version::TLS12]);
+        let (mut client, mut server) = make_pair_for_configs(client_config, server_config);
+        let mut hello = Vec::new();
+        client.write_tls(&mut hello).unwrap();
+        server.read_tls(&mut &hello[..]).unwrap();
+        do_handshake(&mut client, &mut server);
 
-        do_suite_test(make_client_config(kt), server_config, scs, version);
+        let secrets = client.dangerous_extract_secrets().unwrap();
+        let record = seal_with_extracted_secrets(&secrets.tx, ProtocolVersion::TLSv1_2, 0x16, &hello[5..]);
+        server.read_tls(&mut &record[..]).unwrap();
+        if *policy == RenegotiationPolicy::Decline {
+            assert_eq!(server.process_new_packets(), Ok(()));
+            assert!(server.wants_write());
+        } else {
+            assert_eq!(server.process_new_packets(), aborted);
+        }
     }
 }
 
@@ -2066,6 +4810,7 @@ impl KeyLog for KeyLogToVec {
     }
 }
 
+#[cfg(feature = "tls12")]
 #[test]
 fn key_log_for_tls12() {
     let client_key_log = Arc::new(KeyLogToVec::new("client"));
@@ -2073,7 +4818,7 @@ fn key_log_for_tls12() {
 
     let kt = KeyType::RSA;
     let mut client_config = make_client_config(kt);
-    client_config.versions = vec![ProtocolVersion::TLSv1_2];
+    client_config.set_protocol_versions(&[&rustls::version::TLS12]);
     client_config.key_log = client_key_log.clone();
     let client_config = Arc::new(client_config);
 
@@ -2103,6 +4848,7 @@ fn key_log_for_tls12() {
     assert_eq!(client_full_log[0].secret, client_resume_log[0].secret);
 }
 
+#[cfg(feature = "tls13")]
 #[test]
 fn key_log_for_tls13() {
     let client_key_log = Arc::new(KeyLogToVec::new("client"));
@@ -2110,7 +4856,7 @@ fn key_log_for_tls13() {
 
     let kt = KeyType::RSA;
     let mut client_config = make_client_config(kt);
-    client_config.versions = vec![ProtocolVersion::TLSv1_3];
+    client_config.set_protocol_versions(&[&rustls::version::TLS13]);
     client_config.key_log = client_key_log.clone();
     let client_config = Arc::new(client_config);
 
@@ -2165,6 +4911,43 @@ fn key_log_for_tls13() {
     assert_eq!(client_resume_log[4], server_resume_log[4]);
 }
 
+#[test]
+fn key_log_per_session() {
+    let client_config_log = Arc::new(KeyLogToVec::new("client config"));
+    let client_session_log = Arc::new(KeyLogToVec::new("client session"));
+    let server_config_log = Arc::new(KeyLogToVec::new("server config"));
+
+    let kt = KeyType::RSA;
+    let mut client_config = make_client_config(kt);
+    client_config.key_log = client_config_log.clone();
+    let client_config = Arc::new(client_config);
+
+    let mut server_config = make_server_config(kt);
+    server_config.key_log = server_config_log.clone();
+    let server_config = Arc::new(server_config);
+
+    // Overridden: the client logs elsewhere, and the server not at all.
+    let mut client =
+        ClientSession::new_with_key_log(&client_config, dns_name("localhost"), client_session_log.clone())
+            .unwrap();
+    let mut server = ServerSession::new(&server_config);
+    server.set_key_log(Arc::new(rustls::NoKeyLog));
+    do_handshake(&mut client, &mut server);
+
+    assert!(client_config_log.take().is_empty());
+    assert!(server_config_log.take().is_empty());
+    assert!(!client_session_log.take().is_empty());
+
+    // Not overridden.
+    let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
+    do_handshake(&mut client, &mut server);
+
+    assert!(!client_config_log.take().is_empty());
+    assert!(!server_config_log.take().is_empty());
+    assert!(client_session_log.take().is_empty());
+}
+
+#[cfg(feature = "tls13")]
 #[test]
 fn vectored_write_for_server_appdata() {
     let (mut client, mut server) = make_pair(KeyType::RSA);
@@ -2185,6 +4968,7 @@ fn vectored_write_for_server_appdata() {
     check_read(&mut client, b"0123456789012345678901234567890123456789");
 }
 
+#[cfg(feature = "tls13")]
 #[test]
 fn vectored_write_for_client_appdata() {
     let (mut client, mut server) = make_pair(KeyType::RSA);
@@ -2205,6 +4989,7 @@ fn vectored_write_for_client_appdata() {
     check_read(&mut server, b"0123456789012345678901234567890123456789");
 }
 
+#[cfg(feature = "tls13")]
 #[test]
 fn vectored_write_for_server_handshake() {
     let (mut client, mut server) = make_pair(KeyType::RSA);
@@ -2240,6 +5025,7 @@ fn vectored_write_for_server_handshake() {
     check_read(&mut client, b"012345678901234567890123456789");
 }
 
+#[cfg(feature = "tls13")]
 #[test]
 fn vectored_write_for_client_handshake() {
     let (mut client, mut server) = make_pair(KeyType::RSA);
@@ -2273,6 +5059,7 @@ fn vectored_write_for_client_handshake() {
     check_read(&mut server, b"012345678901234567890123456789");
 }
 
+#[cfg(feature = "tls13")]
 #[test]
 fn vectored_write_with_slow_client() {
     let (mut client, mut server) = make_pair(KeyType::RSA);
@@ -2407,11 +5194,60 @@ impl rustls::StoresClientSessions for ClientStorage {
     }
 }
 
+/// Keeps everything it's given, like a file would.
+#[derive(Default)]
+struct SavedClientSessions(Mutex<Vec<(Vec<u8>, Vec<u8>)>>);
+
+impl rustls::StoresClientSessions for SavedClientSessions {
+    fn put(&self, key: Vec<u8>, value: Vec<u8>) -> bool {
+        self.0.lock().unwrap().push((key, value));
+        true
+    }
+
+    fn get(&self, key: &[u8]) -> Option<Vec<u8>> {
+        self.0
+            .lock()
+            .unwrap()
+            .iter()
+            .rev()
+            .find(|(k, _)| k == key)
+            .map(|(_, v)| v.clone())
+    }
+}
+
+#[test]
+fn client_sessions_can_be_saved_and_restored() {
+    for client_config in AllClientVersions::new(make_client_config(KeyType::RSA)) {
+        let server_config = Arc::new(make_server_config(KeyType::RSA));
+
+        let saved = Arc::new(SavedClientSessions::default());
+        let mut before_restart = client_config.clone();
+        before_restart.session_persistence = saved.clone();
+        let (mut client, mut server) =
+            make_pair_for_arc_configs(&Arc::new(before_restart), &server_config);
+        do_handshake(&mut client, &mut server);
+        assert_eq!(client.handshake_kind(), Some(HandshakeKind::Full));
+
+        let restored: Arc<dyn rustls::StoresClientSessions> =
+            rustls::ClientSessionMemoryCache::new(32);
+        for (key, value) in saved.0.lock().unwrap().iter() {
+            restored.put(key.clone(), value.clone());
+        }
+        let mut after_restart = client_config;
+        after_restart.session_persistence = restored;
+        let (mut client, mut server) =
+            make_pair_for_arc_configs(&Arc::new(after_restart), &server_config);
+        do_handshake(&mut client, &mut server);
+        assert_eq!(client.handshake_kind(), Some(HandshakeKind::Resumed));
+    }
+}
+
+#[cfg(feature = "tls13")]
 #[test]
 fn tls13_stateful_resumption() {
     let kt = KeyType::RSA;
     let mut client_config = make_client_config(kt);
-    client_config.versions = vec![ProtocolVersion::TLSv1_3];
+    client_config.set_protocol_versions(&[&rustls::version::TLS13]);
     let client_config = Arc::new(client_config);
 
     let mut server_config = make_server_config(kt);
@@ -2463,11 +5299,12 @@ fn tls13_stateful_resumption() {
     );
 }
 
+#[cfg(feature = "tls13")]
 #[test]
 fn tls13_stateless_resumption() {
     let kt = KeyType::RSA;
     let mut client_config = make_client_config(kt);
-    client_config.versions = vec![ProtocolVersion::TLSv1_3];
+    client_config.set_protocol_versions(&[&rustls::version::TLS13]);
     let client_config = Arc::new(client_config);
 
     let mut server_config = make_server_config(kt);
@@ -2520,6 +5357,147 @@ fn tls13_stateless_resumption() {
     );
 }
 
+#[cfg(feature = "tls12")]
+struct DeferredServerStorage(Arc<dyn rustls::StoresServerSessions>);
+
+#[cfg(feature = "tls12")]
+impl rustls::StoresServerSessions for DeferredServerStorage {
+    fn put(&self, key: Vec<u8>, value: Vec<u8>) -> bool {
+        self.0.put(key, value)
+    }
+
+    fn get(&self, _key: &[u8]) -> Option<Vec<u8>> {
+        panic!("get_or_defer should be used");
+    }
+
+    fn take(&self, key: &[u8]) -> Option<Vec<u8>> {
+        self.0.take(key)
+    }
+
+    fn get_or_defer(&self, _key: &[u8]) -> rustls::SessionLookup {
+        rustls::SessionLookup::Pending
+    }
+}
+
+#[cfg(feature = "tls12")]
+#[test]
+fn tls12_deferred_session_lookup() {
+    let kt = KeyType::RSA;
+    let mut client_config = make_client_config(kt);
+    client_config.set_protocol_versions(&[&rustls::version::TLS12]);
+    let client_config = Arc::new(client_config);
+
+    let storage = Arc::new(DeferredServerStorage(
+        rustls::ServerSessionMemoryCache::new(1024),
+    ));
+    let mut server_config = make_server_config(kt);
+    server_config.session_storage = storage.clone();
+    let server_config = Arc::new(server_config);
+
+    for expected in &[HandshakeKind::Full, HandshakeKind::Resumed] {
+        let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
+        assert!(server.session_lookup_request().is_none());
+        assert_eq!(
+            server.supply_session_value(None),
+            Err(TlsError::General("no session lookup is pending".into()))
+        );
+
+        transfer(&mut client, &mut server);
+        assert_eq!(server.process_new_packets(), Ok(()));
+        let key = server
+            .session_lookup_request()
+            .unwrap()
+            .to_vec();
+
+        // still paused
+        assert_eq!(server.process_new_packets(), Ok(()));
+        assert!(server.session_lookup_request().is_some());
+
+        assert_eq!(server.supply_session_value(storage.0.get(&key)), Ok(()));
+        assert!(server.session_lookup_request().is_none());
+
+        do_handshake(&mut client, &mut server);
+        assert_eq!(client.handshake_kind(), Some(*expected));
+        assert_eq!(server.handshake_kind(), Some(*expected));
+    }
+}
+
+/// Do two handshakes, returning the kind of the second.
+fn second_handshake_kind(
+    client_config: ClientConfig,
+    server_config: ServerConfig,
+) -> Option<HandshakeKind> {
+    let client_config = Arc::new(client_config);
+    let server_config = Arc::new(server_config);
+    let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
+    do_handshake(&mut client, &mut server);
+    assert_eq!(server.handshake_kind(), Some(HandshakeKind::Full));
+
+    let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
+    do_handshake(&mut client, &mut server);
+    assert_eq!(client.handshake_kind(), server.handshake_kind());
+    server.handshake_kind()
+}
+
+#[cfg(feature = "tls12")]
+#[test]
+fn tls12_resumption_kinds_can_be_disabled() {
+    let kt = KeyType::RSA;
+    let configs = |client_session_ids, server_session_ids, client_tickets, server_tickets| {
+        let mut client_config = make_client_config(kt);
+        client_config.set_protocol_versions(&[&rustls::version::TLS12]);
+        client_config.enable_session_id_resumption = client_session_ids;
+        client_config.enable_tickets = client_tickets;
+        let mut server_config = make_server_config(kt);
+        server_config.ticketer = rustls::Ticketer::new().unwrap();
+        server_config.enable_session_id_resumption = server_session_ids;
+        server_config.enable_tickets = server_tickets;
+        (client_config, server_config)
+    };
+
+    let cases = [
+        (true, true, true, true, HandshakeKind::Resumed),
+        (true, true, false, true, HandshakeKind::Resumed),
+        (true, true, true, false, HandshakeKind::Resumed),
+        (false, true, false, true, HandshakeKind::Full),
+        (true, false, true, false, HandshakeKind::Full),
+        (false, true, true, true, HandshakeKind::Resumed),
+        (true, false, true, true, HandshakeKind::Resumed),
+        (false, false, true, false, HandshakeKind::Full),
+    ];
+    for (client_ids, server_ids, client_tickets, server_tickets, expected) in cases.iter() {
+        let (client_config, server_config) =
+            configs(*client_ids, *server_ids, *client_tickets, *server_tickets);
+        assert_eq!(
+            second_handshake_kind(client_config, server_config),
+            Some(*expected)
+        );
+    }
+}
+
+#[cfg(feature = "tls13")]
+#[test]
+fn tls13_resumption_can_be_disabled() {
+    let kt = KeyType::RSA;
+    for (client_enabled, server_enabled) in &[(true, true), (false, true), (true, false)] {
+        let mut client_config = make_client_config(kt);
+        client_config.set_protocol_versions(&[&rustls::version::TLS13]);
+        client_config.enable_tls13_resumption = *client_enabled;
+        let mut server_config = make_server_config(kt);
+        server_config.enable_tls13_resumption = *server_enabled;
+
+        let expected = if *client_enabled && *server_enabled {
+            HandshakeKind::Resumed
+        } else {
+            HandshakeKind::Full
+        };
+        assert_eq!(
+            second_handshake_kind(client_config, server_config),
+            Some(expected)
+        );
+    }
+}
+
 #[cfg(feature = "quic")]
 mod test_quic {
     use super::*;
@@ -2560,11 +5538,11 @@ mod test_quic {
 
         let kt = KeyType::RSA;
         let mut client_config = make_client_config(kt);
-        client_config.versions = vec![ProtocolVersion::TLSv1_3];
+        client_config.set_protocol_versions(&[&rustls::version::TLS13]);
         client_config.enable_early_data = true;
         let client_config = Arc::new(client_config);
         let mut server_config = make_server_config(kt);
-        server_config.versions = vec![ProtocolVersion::TLSv1_3];
+        server_config.set_protocol_versions(&[&rustls::version::TLS13]);
         server_config.max_early_data_size = 0xffffffff;
         server_config.alpn_protocols = vec!["foo".into()];
         let server_config = Arc::new(server_config);
@@ -2578,6 +5556,8 @@ mod test_quic {
         let client_initial = step(&mut client, &mut server).unwrap();
         assert!(client_initial.is_none());
         assert!(client.get_0rtt_keys().is_none());
+        assert_eq!(client.get_0rtt_status(), quic::ZeroRttStatus::NotAttempted);
+        assert_eq!(server.get_0rtt_status(), quic::ZeroRttStatus::NotAttempted);
         assert_eq!(server.get_quic_transport_parameters(), Some(client_params));
         let server_hs = step(&mut server, &mut client)
             .unwrap()
@@ -2619,9 +5599,11 @@ mod test_quic {
                 .get_negotiated_ciphersuite()
                 .is_some()
         );
+        assert_eq!(client.get_0rtt_status(), quic::ZeroRttStatus::Pending);
         let mut server = ServerSession::new_quic(&server_config, quic::Version::V1, server_params.into());
         step(&mut client, &mut server).unwrap();
         assert_eq!(client.get_quic_transport_parameters(), Some(server_params));
+        assert_eq!(server.get_0rtt_status(), quic::ZeroRttStatus::Accepted);
         {
             let client_early = client.get_0rtt_keys().unwrap();
             let server_early = server.get_0rtt_keys().unwrap();
@@ -2637,6 +5619,8 @@ mod test_quic {
             .unwrap()
             .unwrap();
         assert!(client.is_early_data_accepted());
+        assert_eq!(client.get_0rtt_status(), quic::ZeroRttStatus::Accepted);
+        assert!(client.get_0rtt_keys().is_some());
 
         // 0-RTT rejection
         {
@@ -2653,6 +5637,8 @@ mod test_quic {
             assert_eq!(client.get_quic_transport_parameters(), Some(server_params));
             assert!(client.get_0rtt_keys().is_some());
             assert!(server.get_0rtt_keys().is_none());
+            assert_eq!(client.get_0rtt_status(), quic::ZeroRttStatus::Pending);
+            assert_eq!(server.get_0rtt_status(), quic::ZeroRttStatus::Rejected);
             step(&mut server, &mut client)
                 .unwrap()
                 .unwrap();
@@ -2663,6 +5649,8 @@ mod test_quic {
                 .unwrap()
                 .unwrap();
             assert!(!client.is_early_data_accepted());
+            assert_eq!(client.get_0rtt_status(), quic::ZeroRttStatus::Rejected);
+            assert!(client.get_0rtt_keys().is_none());
         }
 
         // failed handshake
@@ -2691,12 +5679,12 @@ mod test_quic {
 
         for &kt in ALL_KEY_TYPES.iter() {
             let mut client_config = make_client_config(kt);
-            client_config.versions = vec![ProtocolVersion::TLSv1_3];
+            client_config.set_protocol_versions(&[&rustls::version::TLS13]);
             client_config.alpn_protocols = vec!["bar".into()];
             let client_config = Arc::new(client_config);
 
             let mut server_config = make_server_config(kt);
-            server_config.versions = vec![ProtocolVersion::TLSv1_3];
+            server_config.set_protocol_versions(&[&rustls::version::TLS13]);
             server_config.alpn_protocols = vec!["foo".into()];
             let server_config = Arc::new(server_config);
 
@@ -2722,15 +5710,56 @@ mod test_quic {
         }
     }
 
+    #[test]
+    fn test_quic_v2_handshake() {
+        fn nonce(keys: &quic::DirectionalKeys) -> Vec<u8> {
+            keys.packet.iv.nonce_for(42).as_ref().to_vec()
+        }
+
+        let mut client_config = make_client_config(KeyType::RSA);
+        client_config.set_protocol_versions(&[&rustls::version::TLS13]);
+        let mut server_config = make_server_config(KeyType::RSA);
+        server_config.set_protocol_versions(&[&rustls::version::TLS13]);
+        let client_params = &b"client params"[..];
+        let server_params = &b"server params"[..];
+
+        let mut client = ClientSession::new_quic(
+            &Arc::new(client_config),
+            quic::Version::V2,
+            dns_name("localhost"),
+            client_params.into(),
+        ).unwrap();
+        let mut server = ServerSession::new_quic(&Arc::new(server_config), quic::Version::V2, server_params.into());
+
+        step(&mut client, &mut server).unwrap();
+        let server_hs = step(&mut server, &mut client).unwrap().unwrap();
+        let client_hs = step(&mut client, &mut server).unwrap().unwrap();
+        assert_eq!(nonce(&server_hs.local), nonce(&client_hs.remote));
+        assert_eq!(nonce(&server_hs.remote), nonce(&client_hs.local));
+        step(&mut server, &mut client).unwrap().unwrap();
+        step(&mut client, &mut server).unwrap().unwrap();
+        assert!(!client.is_handshaking());
+        assert!(!server.is_handshaking());
+        assert_eq!(server.get_quic_transport_parameters(), Some(client_params));
+        assert_eq!(client.get_quic_transport_parameters(), Some(server_params));
+
+        let client_next = client.next_1rtt_keys();
+        let server_next = server.next_1rtt_keys();
+        assert_eq!(
+            client_next.local.iv.nonce_for(42).as_ref(),
+            server_next.remote.iv.nonce_for(42).as_ref()
+        );
+    }
+
     #[test]
     fn test_quic_exporter() {
         for &kt in ALL_KEY_TYPES.iter() {
             let mut client_config = make_client_config(kt);
-            client_config.versions = vec![ProtocolVersion::TLSv1_3];
+            client_config.set_protocol_versions(&[&rustls::version::TLS13]);
             client_config.alpn_protocols = vec!["bar".into()];
 
             let mut server_config = make_server_config(kt);
-            server_config.versions = vec![ProtocolVersion::TLSv1_3];
+            server_config.set_protocol_versions(&[&rustls::version::TLS13]);
             server_config.alpn_protocols = vec!["foo".into()];
 
             do_exporter_test(client_config, server_config);
@@ -2738,6 +5767,384 @@ mod test_quic {
     }
 } // mod test_quic
 
+#[cfg(feature = "dtls")]
+mod test_dtls {
+    use super::*;
+    use rustls::dtls::{self, DtlsClientConnection, DtlsConnection, DtlsServerConnection};
+    use rustls::SRTPProtectionProfile;
+    use std::time::Instant;
+
+    fn make_dtls_configs(kt: KeyType) -> (Arc<ClientConfig>, Arc<ServerConfig>) {
+        let mut client_config = make_client_config(kt);
+        client_config.set_protocol_versions(&[&rustls::version::TLS13]);
+        let mut server_config = make_server_config(kt);
+        server_config.set_protocol_versions(&[&rustls::version::TLS13]);
+        (Arc::new(client_config), Arc::new(server_config))
+    }
+
+    /// Deliver every datagram `send` has queued to `recv`, dropping those
+    /// for which `lose` returns true.  Returns the number delivered.
+    fn transfer_dtls(
+        send: &mut dyn DtlsConnection,
+        recv: &mut dyn DtlsConnection,
+        lose: &mut dyn FnMut(&[u8]) -> bool,
+    ) -> Result<usize, TlsError> {
+        let mut delivered = 0;
+        while let Some(datagram) = send.write_datagram(Instant::now()) {
+            if lose(&datagram) {
+                continue;
+            }
+            recv.read_datagram(&datagram)?;
+            delivered += 1;
+        }
+        Ok(delivered)
+    }
+
+    fn expire_timer(conn: &mut dyn DtlsConnection) {
+        let deadline = conn
+            .poll_timeout()
+            .expect("no retransmission timer");
+        conn.handle_timeout(deadline);
+    }
+
+    fn do_dtls_handshake(
+        client: &mut DtlsClientConnection,
+        server: &mut DtlsServerConnection,
+    ) -> Result<(), TlsError> {
+        for _ in 0..10 {
+            transfer_dtls(client, server, &mut |_| false)?;
+            transfer_dtls(server, client, &mut |_| false)?;
+            if !client.is_handshaking() && !server.is_handshaking() {
+                return Ok(());
+            }
+        }
+        panic!("DTLS handshake did not complete");
+    }
+
+    fn check_dtls_data(client: &mut DtlsClientConnection, server: &mut DtlsServerConnection) {
+        client
+            .send_application_data(b"hello")
+            .unwrap();
+        transfer_dtls(client, server, &mut |_| false).unwrap();
+        assert_eq!(server.recv_application_data(), Some(b"hello".to_vec()));
+        assert_eq!(server.recv_application_data(), None);
+
+        server
+            .send_application_data(b"world")
+            .unwrap();
+        transfer_dtls(server, client, &mut |_| false).unwrap();
+        assert_eq!(client.recv_application_data(), Some(b"world".to_vec()));
+    }
+
+    #[test]
+    fn test_dtls_handshake() {
+        for kt in ALL_KEY_TYPES.iter() {
+            let (client_config, server_config) = make_dtls_configs(*kt);
+            let mut client = DtlsClientConnection::new(&client_config, dns_name("localhost")).unwrap();
+            let mut server = DtlsServerConnection::new(&server_config);
+
+            assert_eq!(
+                client.send_application_data(b"early"),
+                Err(TlsError::HandshakeNotComplete)
+            );
+            do_dtls_handshake(&mut client, &mut server).unwrap();
+            assert!(client.get_peer_certificates().is_some());
+            assert_eq!(server.get_sni_hostname(), Some("localhost"));
+            check_dtls_data(&mut client, &mut server);
+
+            let mut client_secret = [0u8; 32];
+            let mut server_secret = [0u8; 32];
+            client
+                .export_keying_material(&mut client_secret, b"label", None)
+                .unwrap();
+            server
+                .export_keying_material(&mut server_secret, b"label", None)
+                .unwrap();
+            assert_eq!(client_secret, server_secret);
+
+            client.send_close_notify();
+            transfer_dtls(&mut client, &mut server, &mut |_| false).unwrap();
+            assert!(server.received_close_notify());
+        }
+    }
+
+    #[test]
+    fn test_dtls_handshake_fragments_to_mtu() {
+        let (client_config, server_config) = make_dtls_configs(KeyType::RSA);
+        let mut client = DtlsClientConnection::new(&client_config, dns_name("localhost")).unwrap();
+        let mut server = DtlsServerConnection::new(&server_config);
+        client.set_mtu(300);
+        server.set_mtu(300);
+
+        let mut largest = 0;
+        for _ in 0..10 {
+            transfer_dtls(&mut client, &mut server, &mut |d| {
+                largest = largest.max(d.len());
+                false
+            })
+            .unwrap();
+            transfer_dtls(&mut server, &mut client, &mut |d| {
+                largest = largest.max(d.len());
+                false
+            })
+            .unwrap();
+        }
+        assert!(largest <= 300);
+        assert!(!client.is_handshaking());
+        assert!(!server.is_handshaking());
+        check_dtls_data(&mut client, &mut server);
+
+        assert!(client
+            .send_application_data(&[0u8; 300])
+            .is_err());
+    }
+
+    #[test]
+    fn test_dtls_retransmits_lost_flights() {
+        let (client_config, server_config) = make_dtls_configs(KeyType::ECDSA);
+        let mut client = DtlsClientConnection::new(&client_config, dns_name("localhost")).unwrap();
+        let mut server = DtlsServerConnection::new(&server_config);
+        client.set_mtu(500);
+        server.set_mtu(500);
+
+        // Lose the ClientHello.
+        assert_eq!(transfer_dtls(&mut client, &mut server, &mut |_| true).unwrap(), 0);
+        assert!(!server.wants_write());
+        expire_timer(&mut client);
+        transfer_dtls(&mut client, &mut server, &mut |_| false).unwrap();
+
+        // Lose every other datagram of the server's flight; the client
+        // acknowledges what it got, and the server resends the rest.
+        let mut n = 0;
+        transfer_dtls(&mut server, &mut client, &mut |_| {
+            n += 1;
+            n % 2 == 0
+        })
+        .unwrap();
+        assert!(client.is_handshaking());
+        transfer_dtls(&mut client, &mut server, &mut |_| false).unwrap();
+        expire_timer(&mut server);
+        transfer_dtls(&mut server, &mut client, &mut |_| false).unwrap();
+        assert!(!client.is_handshaking());
+
+        // Lose the client's final flight.
+        transfer_dtls(&mut client, &mut server, &mut |_| true).unwrap();
+        assert!(server.is_handshaking());
+        expire_timer(&mut client);
+        do_dtls_handshake(&mut client, &mut server).unwrap();
+        check_dtls_data(&mut client, &mut server);
+
+        // Everything has been acknowledged.
+        assert_eq!(client.poll_timeout(), None);
+        assert_eq!(server.poll_timeout(), None);
+    }
+
+    #[test]
+    fn test_dtls_retransmit_timer_backs_off() {
+        let (client_config, _) = make_dtls_configs(KeyType::RSA);
+        let mut client = DtlsClientConnection::new(&client_config, dns_name("localhost")).unwrap();
+
+        // The timer starts when the flight is written.
+        assert_eq!(client.poll_timeout(), None);
+        let start = Instant::now();
+        assert!(client.write_datagram(start).is_some());
+        let deadline = client.poll_timeout().unwrap();
+        assert_eq!(deadline, start + dtls::INITIAL_RETRANSMIT_TIMEOUT);
+
+        client.handle_timeout(deadline - Duration::from_millis(1));
+        assert!(!client.wants_write());
+        client.handle_timeout(deadline);
+        assert!(client.wants_write());
+        assert_eq!(client.poll_timeout(), None);
+
+        assert!(client.write_datagram(deadline).is_some());
+        assert_eq!(
+            client.poll_timeout(),
+            Some(deadline + 2 * dtls::INITIAL_RETRANSMIT_TIMEOUT)
+        );
+
+        let mut now = deadline;
+        for _ in 0..10 {
+            now = client.poll_timeout().unwrap();
+            client.handle_timeout(now);
+            client.write_datagram(now).unwrap();
+        }
+        assert_eq!(
+            client.poll_timeout(),
+            Some(now + dtls::MAX_RETRANSMIT_TIMEOUT)
+        );
+    }
+
+    #[test]
+    fn test_dtls_connection_ids() {
+        let (client_config, server_config) = make_dtls_configs(KeyType::RSA);
+        let client_cid = vec![1, 2, 3];
+        let server_cid = vec![9, 8, 7, 6];
+        let mut client = DtlsClientConnection::with_connection_id(
+            &client_config,
+            dns_name("localhost"),
+            client_cid.clone(),
+        )
+        .unwrap();
+        let mut server = DtlsServerConnection::with_connection_id(&server_config, server_cid.clone());
+        do_dtls_handshake(&mut client, &mut server).unwrap();
+        assert_eq!(client.get_peer_connection_id(), Some(&server_cid[..]));
+        assert_eq!(server.get_peer_connection_id(), Some(&client_cid[..]));
+
+        client
+            .send_application_data(b"hello")
+            .unwrap();
+        let datagram = client.write_datagram(Instant::now()).unwrap();
+        assert_eq!(dtls::peek_connection_id(&datagram, 4), Some(&server_cid[..]));
+        server.read_datagram(&datagram).unwrap();
+        assert_eq!(server.recv_application_data(), Some(b"hello".to_vec()));
+
+        server
+            .send_application_data(b"world")
+            .unwrap();
+        let datagram = server.write_datagram(Instant::now()).unwrap();
+        assert_eq!(dtls::peek_connection_id(&datagram, 3), Some(&client_cid[..]));
+        client.read_datagram(&datagram).unwrap();
+        assert_eq!(client.recv_application_data(), Some(b"world".to_vec()));
+
+        // A record for another connection is ignored.
+        let mut other = datagram.clone();
+        other[1] ^= 0xff;
+        client.read_datagram(&other).unwrap();
+        assert_eq!(client.recv_application_data(), None);
+    }
+
+    #[test]
+    fn test_dtls_connection_ids_need_both_peers() {
+        let (client_config, server_config) = make_dtls_configs(KeyType::RSA);
+        let mut client =
+            DtlsClientConnection::with_connection_id(&client_config, dns_name("localhost"), vec![1])
+                .unwrap();
+        let mut server = DtlsServerConnection::new(&server_config);
+        do_dtls_handshake(&mut client, &mut server).unwrap();
+        assert_eq!(client.get_peer_connection_id(), None);
+        assert_eq!(server.get_peer_connection_id(), None);
+        check_dtls_data(&mut client, &mut server);
+
+        client
+            .send_application_data(b"hello")
+            .unwrap();
+        let datagram = client.write_datagram(Instant::now()).unwrap();
+        assert_eq!(dtls::peek_connection_id(&datagram, 1), None);
+    }
+
+    #[test]
+    fn test_dtls_hello_retry_request() {
+        let (_, server_config) = make_dtls_configs(KeyType::RSA);
+        let mut client_config = make_client_config(KeyType::RSA);
+        client_config.set_protocol_versions(&[&rustls::version::TLS13]);
+        client_config.kx_groups = vec![&rustls::kx_group::X25519, &rustls::kx_group::SECP384R1];
+        let mut server_config = (*server_config).clone();
+        server_config.kx_groups = vec![&rustls::kx_group::SECP384R1];
+
+        let mut client = DtlsClientConnection::new(&Arc::new(client_config), dns_name("localhost")).unwrap();
+        let mut server = DtlsServerConnection::new(&Arc::new(server_config));
+        do_dtls_handshake(&mut client, &mut server).unwrap();
+        check_dtls_data(&mut client, &mut server);
+    }
+
+    #[test]
+    fn test_dtls_resumption() {
+        let (client_config, server_config) = make_dtls_configs(KeyType::RSA);
+        let mut server_config = (*server_config).clone();
+        let storage = Arc::new(ServerStorage::new());
+        server_config.session_storage = storage.clone();
+        let server_config = Arc::new(server_config);
+
+        let mut client = DtlsClientConnection::new(&client_config, dns_name("localhost")).unwrap();
+        let mut server = DtlsServerConnection::new(&server_config);
+        do_dtls_handshake(&mut client, &mut server).unwrap();
+        // Deliver the tickets.
+        transfer_dtls(&mut server, &mut client, &mut |_| false).unwrap();
+        transfer_dtls(&mut client, &mut server, &mut |_| false).unwrap();
+        assert_eq!(storage.puts(), 1);
+        assert_eq!(storage.takes(), 0);
+
+        let mut client = DtlsClientConnection::new(&client_config, dns_name("localhost")).unwrap();
+        let mut server = DtlsServerConnection::new(&server_config);
+        do_dtls_handshake(&mut client, &mut server).unwrap();
+        assert_eq!(storage.takes(), 1);
+        check_dtls_data(&mut client, &mut server);
+    }
+
+    #[test]
+    fn test_dtls_rejects_tls_client() {
+        let (_, server_config) = make_dtls_configs(KeyType::RSA);
+        let mut client_config = make_client_config(KeyType::RSA);
+        client_config.set_protocol_versions(&[&rustls::version::TLS13]);
+        let mut client = ClientSession::new(&Arc::new(client_config), dns_name("localhost")).unwrap();
+        let mut server = DtlsServerConnection::new(&server_config);
+
+        let mut tls = Vec::new();
+        client.write_tls(&mut tls).unwrap();
+        // A TLS record isn't a DTLS record; it's dropped.
+        server.read_datagram(&tls).unwrap();
+        assert!(server.is_handshaking());
+        assert!(!server.wants_write());
+    }
+
+    #[test]
+    fn test_dtls_srtp() {
+        let (client_config, server_config) = make_dtls_configs(KeyType::RSA);
+        let mut client_config = (*client_config).clone();
+        client_config.srtp_profiles = vec![
+            SRTPProtectionProfile::SRTP_AES128_CM_HMAC_SHA1_80,
+            SRTPProtectionProfile::SRTP_AEAD_AES_256_GCM,
+        ];
+        let mut server_config = (*server_config).clone();
+        server_config.srtp_profiles = vec![
+            SRTPProtectionProfile::SRTP_AEAD_AES_256_GCM,
+            SRTPProtectionProfile::SRTP_AES128_CM_HMAC_SHA1_80,
+        ];
+
+        let mut client = DtlsClientConnection::new(&Arc::new(client_config), dns_name("localhost")).unwrap();
+        let mut server = DtlsServerConnection::new(&Arc::new(server_config));
+        assert_eq!(
+            client.export_srtp_keys(),
+            Err(TlsError::HandshakeNotComplete)
+        );
+        do_dtls_handshake(&mut client, &mut server).unwrap();
+
+        // The server's preference wins.
+        assert_eq!(
+            client.get_srtp_profile(),
+            Some(SRTPProtectionProfile::SRTP_AEAD_AES_256_GCM)
+        );
+        assert_eq!(server.get_srtp_profile(), client.get_srtp_profile());
+
+        let keys = client.export_srtp_keys().unwrap();
+        assert_eq!(keys.client_key.len(), 32);
+        assert_eq!(keys.client_salt.len(), 12);
+        assert_ne!(keys.client_key, keys.server_key);
+        assert_eq!(server.export_srtp_keys().unwrap(), keys);
+        check_dtls_data(&mut client, &mut server);
+    }
+
+    #[test]
+    fn test_dtls_srtp_no_common_profile() {
+        let (client_config, server_config) = make_dtls_configs(KeyType::RSA);
+        let mut client_config = (*client_config).clone();
+        client_config.srtp_profiles = vec![SRTPProtectionProfile::SRTP_AES128_CM_HMAC_SHA1_80];
+        let mut server_config = (*server_config).clone();
+        server_config.srtp_profiles = vec![SRTPProtectionProfile::SRTP_AEAD_AES_128_GCM];
+
+        let mut client = DtlsClientConnection::new(&Arc::new(client_config), dns_name("localhost")).unwrap();
+        let mut server = DtlsServerConnection::new(&Arc::new(server_config));
+        do_dtls_handshake(&mut client, &mut server).unwrap();
+        assert_eq!(client.get_srtp_profile(), None);
+        assert_eq!(server.get_srtp_profile(), None);
+        assert_eq!(
+            client.export_srtp_keys(),
+            Err(TlsError::General("SRTP was not negotiated".into()))
+        );
+    }
+} // mod test_dtls
+
 #[test]
 fn test_client_does_not_offer_sha1() {
     use rustls::internal::msgs::{
@@ -2778,26 +6185,347 @@ fn test_client_does_not_offer_sha1() {
     }
 }
 
+#[cfg(feature = "tls12")]
+#[test]
+fn server_rejects_fallback_scsv_when_it_supports_higher_version() {
+    use rustls::internal::msgs::{
+        codec::Codec, handshake::HandshakePayload, message::Message, message::MessagePayload,
+    };
+
+    let kt = KeyType::RSA;
+    for server_has_tls13 in &[true, false] {
+        let mut client_config = make_client_config(kt);
+        client_config.set_protocol_versions(&[&rustls::version::TLS12]);
+        let mut server_config = make_server_config(kt);
+        if !*server_has_tls13 {
+            server_config.set_protocol_versions(&[&rustls::version::TLS12]);
+        }
+        let (mut client, mut server) = make_pair_for_configs(client_config, server_config);
+
+        // Add the SCSV to the client's ClientHello, as a client retrying
+        // after a failed TLS1.3 handshake would.
+        let mut buf = Vec::new();
+        client.write_tls(&mut buf).unwrap();
+        let mut msg = Message::read_bytes(&buf).unwrap();
+        assert!(msg.decode_payload());
+        if let MessagePayload::Handshake(ref mut hs) = msg.payload {
+            if let HandshakePayload::ClientHello(ref mut ch) = hs.payload {
+                ch.cipher_suites
+                    .push(CipherSuite::TLS_FALLBACK_SCSV);
+            }
+        }
+        server
+            .read_tls(&mut &msg.get_encoding()[..])
+            .unwrap();
+
+        if *server_has_tls13 && cfg!(feature = "tls13") {
+            assert_eq!(
+                server.process_new_packets(),
+                Err(TlsError::PeerIncompatibleError(
+                    "client fell back to TLS1.2 when TLS1.3 is supported".into()
+                ))
+            );
+            let mut alert = Vec::new();
+            server.write_tls(&mut alert).unwrap();
+            assert_eq!(alert, vec![0x15, 0x03, 0x03, 0x00, 0x02, 0x02, 0x56]);
+        } else {
+            assert_eq!(server.process_new_packets(), Ok(()));
+        }
+    }
+}
+
+#[test]
+fn test_client_config_keyshare() {
+    let mut client_config = make_client_config(KeyType::RSA);
+    client_config.kx_groups = vec![ &rustls::kx_group::SECP384R1 ];
+    let mut server_config = make_server_config(KeyType::RSA);
+    server_config.kx_groups = vec![ &rustls::kx_group::SECP384R1 ];
+    let (mut client, mut server) = make_pair_for_configs(client_config, server_config);
+    do_handshake_until_error(&mut client, &mut server).unwrap();
+}
+
+#[test]
+fn test_client_config_keyshare_mismatch() {
+    let mut client_config = make_client_config(KeyType::RSA);
+    client_config.kx_groups = vec![ &rustls::kx_group::SECP384R1 ];
+    let mut server_config = make_server_config(KeyType::RSA);
+    server_config.kx_groups= vec![ &rustls::kx_group::X25519 ];
+    let (mut client, mut server) = make_pair_for_configs(client_config, server_config);
+    assert!(do_handshake_until_error(&mut client, &mut server).is_err());
+}
+
+#[test]
+fn test_negotiated_kx_group() {
+    for version in rustls::ALL_VERSIONS {
+        for group in &rustls::ALL_KX_GROUPS {
+            let mut client_config = make_client_config(KeyType::RSA);
+            client_config.set_protocol_versions(&[version]);
+            client_config.kx_groups = vec![*group];
+            let (mut client, mut server) =
+                make_pair_for_configs(client_config, make_server_config(KeyType::RSA));
+
+            assert!(client.get_negotiated_kx_group().is_none());
+            assert!(server.get_negotiated_kx_group().is_none());
+            do_handshake(&mut client, &mut server);
+            assert_eq!(client.get_negotiated_kx_group().unwrap().name, group.name);
+            assert_eq!(server.get_negotiated_kx_group().unwrap().name, group.name);
+        }
+    }
+}
+
+#[test]
+fn server_can_get_client_cipher_suites() {
+    let client_config = make_client_config(KeyType::RSA);
+    let mut offered: Vec<CipherSuite> = client_config
+        .ciphersuites
+        .iter()
+        .map(|scs| scs.suite)
+        .collect();
+    offered.push(CipherSuite::TLS_EMPTY_RENEGOTIATION_INFO_SCSV);
+
+    let (mut client, mut server) =
+        make_pair_for_configs(client_config, make_server_config(KeyType::RSA));
+    assert!(server.get_client_cipher_suites().is_empty());
+    do_handshake(&mut client, &mut server);
+    assert_eq!(server.get_client_cipher_suites(), &offered[..]);
+}
+
+#[test]
+fn server_can_get_client_unknown_extensions() {
+    let mut client_config = make_client_config(KeyType::RSA);
+    client_config.custom_extensions = vec![
+        (0xfe00, b"hello".to_vec()),
+        (0xfe01, Vec::new()),
+    ];
+
+    let (mut client, mut server) =
+        make_pair_for_configs(client_config, make_server_config(KeyType::RSA));
+    assert!(server.get_client_unknown_extensions().is_empty());
+    do_handshake(&mut client, &mut server);
+    assert_eq!(
+        server.get_client_unknown_extensions(),
+        &[(0xfe00, b"hello".to_vec()), (0xfe01, Vec::new())][..]
+    );
+}
+
+#[test]
+fn test_handshake_kind_full_then_resumed() {
+    for client_config in AllClientVersions::new(make_client_config(KeyType::RSA)) {
+        let server_config = make_server_config(KeyType::RSA);
+        let (mut client, mut server) =
+            make_pair_for_configs(client_config.clone(), server_config.clone());
+
+        assert_eq!(client.handshake_kind(), None);
+        assert_eq!(server.handshake_kind(), None);
+        do_handshake(&mut client, &mut server);
+        assert_eq!(client.handshake_kind(), Some(HandshakeKind::Full));
+        assert_eq!(server.handshake_kind(), Some(HandshakeKind::Full));
+
+        let (mut client, mut server) = make_pair_for_configs(client_config, server_config);
+        do_handshake(&mut client, &mut server);
+        assert_eq!(client.handshake_kind(), Some(HandshakeKind::Resumed));
+        assert_eq!(server.handshake_kind(), Some(HandshakeKind::Resumed));
+    }
+}
+
+#[cfg(feature = "tls13")]
+#[test]
+fn test_handshake_kind_with_helloretryrequest() {
+    let mut client_config = make_client_config(KeyType::RSA);
+    client_config.kx_groups = vec![
+        &rustls::kx_group::SECP384R1,
+        &rustls::kx_group::X25519,
+    ];
+    let mut server_config = make_server_config(KeyType::RSA);
+    server_config.kx_groups = vec![
+        &rustls::kx_group::X25519
+    ];
+
+    let (mut client, mut server) = make_pair_for_configs(client_config, server_config);
+    do_handshake(&mut client, &mut server);
+    assert_eq!(
+        client.handshake_kind(),
+        Some(HandshakeKind::FullWithHelloRetryRequest)
+    );
+    assert_eq!(
+        server.handshake_kind(),
+        Some(HandshakeKind::FullWithHelloRetryRequest)
+    );
+}
+
+#[derive(Debug, PartialEq)]
+enum Event {
+    Started,
+    ParametersChosen(ProtocolVersion),
+    Finished(HandshakeKind),
+    ResumptionUsed,
+    ResumptionDeclined,
+    TicketIssued,
+    AlertSent(AlertDescription, bool),
+    AlertReceived(AlertDescription, bool),
+}
+
+#[derive(Default)]
+struct RecordEvents(Mutex<Vec<Event>>);
+
+impl RecordEvents {
+    /// Everything recorded so far, except tickets: whether the
+    /// server issues one depends on the version.
+    fn take(&self) -> Vec<Event> {
+        mem::take(&mut *self.0.lock().unwrap())
+            .into_iter()
+            .filter(|ev| *ev != Event::TicketIssued)
+            .collect()
+    }
+
+    fn record(&self, ev: Event) {
+        self.0.lock().unwrap().push(ev);
+    }
+}
+
+impl ConnectionEvents for RecordEvents {
+    fn handshake_started(&self) {
+        self.record(Event::Started);
+    }
+
+    fn parameters_chosen(&self, version: ProtocolVersion, _suite: CipherSuite) {
+        self.record(Event::ParametersChosen(version));
+    }
+
+    fn handshake_finished(&self, kind: HandshakeKind) {
+        self.record(Event::Finished(kind));
+    }
+
+    fn resumption_used(&self) {
+        self.record(Event::ResumptionUsed);
+    }
+
+    fn resumption_declined(&self) {
+        self.record(Event::ResumptionDeclined);
+    }
+
+    fn ticket_issued(&self, _lifetime_secs: u32) {
+        self.record(Event::TicketIssued);
+    }
+
+    fn alert_sent(&self, description: AlertDescription, fatal: bool) {
+        self.record(Event::AlertSent(description, fatal));
+    }
+
+    fn alert_received(&self, description: AlertDescription, fatal: bool) {
+        self.record(Event::AlertReceived(description, fatal));
+    }
+}
+
 #[test]
-fn test_client_config_keyshare() {
-    let mut client_config = make_client_config(KeyType::RSA);
-    client_config.kx_groups = vec![ &rustls::kx_group::SECP384R1 ];
-    let mut server_config = make_server_config(KeyType::RSA);
-    server_config.kx_groups = vec![ &rustls::kx_group::SECP384R1 ];
-    let (mut client, mut server) = make_pair_for_configs(client_config, server_config);
-    do_handshake_until_error(&mut client, &mut server).unwrap();
+fn connection_events_are_reported() {
+    for mut client_config in AllClientVersions::new(make_client_config(KeyType::RSA)) {
+        let client_events = Arc::new(RecordEvents::default());
+        let server_events = Arc::new(RecordEvents::default());
+        client_config.session_persistence = rustls::ClientSessionMemoryCache::new(32);
+        client_config.connection_events = Some(client_events.clone());
+        let mut server_config = make_server_config(KeyType::RSA);
+        server_config.connection_events = Some(server_events.clone());
+
+        let (mut client, mut server) =
+            make_pair_for_configs(client_config.clone(), server_config.clone());
+        do_handshake(&mut client, &mut server);
+        let version = client.get_protocol_version().unwrap();
+        let full = vec![
+            Event::Started,
+            Event::ParametersChosen(version),
+            Event::Finished(HandshakeKind::Full),
+        ];
+        assert_eq!(client_events.take(), full);
+        assert_eq!(server_events.take(), full);
+
+        client.send_close_notify();
+        transfer(&mut client, &mut server);
+        server.process_new_packets().unwrap();
+        assert_eq!(
+            client_events.take(),
+            vec![Event::AlertSent(AlertDescription::CloseNotify, false)]
+        );
+        assert_eq!(
+            server_events.take(),
+            vec![Event::AlertReceived(AlertDescription::CloseNotify, false)]
+        );
+
+        let (mut client, mut server) =
+            make_pair_for_configs(client_config.clone(), server_config);
+        do_handshake(&mut client, &mut server);
+        let resumed = vec![
+            Event::Started,
+            Event::ParametersChosen(version),
+            Event::ResumptionUsed,
+            Event::Finished(HandshakeKind::Resumed),
+        ];
+        assert_eq!(client_events.take(), resumed);
+        assert_eq!(server_events.take(), resumed);
+
+        // A new server can't resume the client's session.
+        let mut server_config = make_server_config(KeyType::RSA);
+        server_config.connection_events = Some(server_events.clone());
+        let (mut client, mut server) = make_pair_for_configs(client_config, server_config);
+        do_handshake(&mut client, &mut server);
+        let declined = vec![
+            Event::Started,
+            Event::ParametersChosen(version),
+            Event::ResumptionDeclined,
+            Event::Finished(HandshakeKind::Full),
+        ];
+        assert_eq!(client_events.take(), declined);
+        // The TLS1.2 client offered only a session ID.
+        if version == ProtocolVersion::TLSv1_2 {
+            assert_eq!(server_events.take(), full);
+        } else {
+            assert_eq!(server_events.take(), declined);
+        }
+    }
 }
 
+#[cfg(feature = "metrics")]
 #[test]
-fn test_client_config_keyshare_mismatch() {
-    let mut client_config = make_client_config(KeyType::RSA);
-    client_config.kx_groups = vec![ &rustls::kx_group::SECP384R1 ];
-    let mut server_config = make_server_config(KeyType::RSA);
-    server_config.kx_groups= vec![ &rustls::kx_group::X25519 ];
-    let (mut client, mut server) = make_pair_for_configs(client_config, server_config);
-    assert!(do_handshake_until_error(&mut client, &mut server).is_err());
+fn metrics_count_handshakes() {
+    for mut client_config in AllClientVersions::new(make_client_config(KeyType::RSA)) {
+        client_config.session_persistence = rustls::ClientSessionMemoryCache::new(32);
+        client_config.metrics = rustls::metrics::Metrics::new();
+        let client_config = Arc::new(client_config);
+        let server_config = Arc::new(make_server_config(KeyType::RSA));
+
+        for _ in 0..2 {
+            let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
+            do_handshake(&mut client, &mut server);
+            client.write_all(b"hello").unwrap();
+            transfer(&mut client, &mut server);
+            server.process_new_packets().unwrap();
+        }
+
+        let mut client = ClientSession::new(&client_config, dns_name("not-localhost")).unwrap();
+        let mut server = ServerSession::new(&server_config);
+        assert!(do_handshake_until_both_error(&mut client, &mut server).is_err());
+
+        let client_metrics = client_config.metrics.snapshot();
+        assert_eq!(client_metrics.handshakes_completed, 2);
+        assert_eq!(client_metrics.resumptions, 1);
+        assert_eq!(client_metrics.handshakes_failed.invalid_certificate, 1);
+        assert!(client_metrics.bytes_encrypted >= 10);
+
+        let server_metrics = server_config.metrics.snapshot();
+        assert_eq!(server_metrics.handshakes_completed, 2);
+        assert_eq!(server_metrics.resumptions, 1);
+        assert_eq!(
+            server_metrics.handshakes_failed,
+            rustls::metrics::HandshakeFailures {
+                alert_received: 1,
+                ..Default::default()
+            }
+        );
+        assert_eq!(server_metrics.early_data_accepted, 0);
+    }
 }
 
+#[cfg(feature = "tls13")]
 #[test]
 fn test_client_sends_helloretryrequest() {
     // client sends a secp384r1 key share
@@ -2908,11 +6636,16 @@ fn test_client_mtu_reduction() {
     }
 }
 
+/// Somewhere for the key log tests to write, outside the source tree.
+fn key_log_file_path() -> std::path::PathBuf {
+    env::temp_dir().join("rustls-sslkeylogfile.txt")
+}
+
 #[test]
 fn exercise_key_log_file_for_client() {
     let server_config = Arc::new(make_server_config(KeyType::RSA));
     let mut client_config = make_client_config(KeyType::RSA);
-    env::set_var("SSLKEYLOGFILE", "./sslkeylogfile.txt");
+    env::set_var("SSLKEYLOGFILE", key_log_file_path());
     client_config.key_log = Arc::new(rustls::KeyLogFile::new());
 
     for client_config in AllClientVersions::new(client_config) {
@@ -2931,7 +6664,7 @@ fn exercise_key_log_file_for_client() {
 fn exercise_key_log_file_for_server() {
     let mut server_config = make_server_config(KeyType::RSA);
 
-    env::set_var("SSLKEYLOGFILE", "./sslkeylogfile.txt");
+    env::set_var("SSLKEYLOGFILE", key_log_file_path());
     server_config.key_log = Arc::new(rustls::KeyLogFile::new());
 
     let server_config = Arc::new(server_config);
@@ -2984,9 +6717,7 @@ fn test_server_rejects_duplicate_sni_names() {
     transfer_altered(&mut client, duplicate_sni_payload, &mut server);
     assert_eq!(
         server.process_new_packets(),
-        Err(TlsError::PeerMisbehavedError(
-            "ClientHello SNI contains duplicate name types".into()
-        ))
+        Err(TlsError::PeerMisbehavedError(PeerMisbehaved::DuplicateServerNameTypes))
     );
 }
 
@@ -3008,12 +6739,115 @@ fn test_server_rejects_empty_sni_extension() {
     transfer_altered(&mut client, empty_sni_payload, &mut server);
     assert_eq!(
         server.process_new_packets(),
-        Err(TlsError::PeerMisbehavedError(
-            "ClientHello SNI did not contain a hostname".into()
-        ))
+        Err(TlsError::PeerMisbehavedError(PeerMisbehaved::ServerNameMustContainOneHostName))
     );
 }
 
+#[cfg(feature = "tls12")]
+#[test]
+fn client_sends_custom_extensions_and_sees_replies() {
+    use rustls::internal::msgs::{
+        base::Payload, enums::ExtensionType, handshake::ServerExtension,
+        handshake::UnknownExtension,
+    };
+
+    fn check_client_hello(msg: &mut Message) {
+        if let MessagePayload::Handshake(hs) = &mut msg.payload {
+            if let HandshakePayload::ClientHello(ch) = &mut hs.payload {
+                let custom = ch
+                    .extensions
+                    .iter()
+                    .filter_map(|ext| match ext {
+                        ClientExtension::Unknown(u) if u.typ == ExtensionType::Unknown(0xfe00) => {
+                            Some(u.payload.0.clone())
+                        }
+                        _ => None,
+                    })
+                    .collect::<Vec<_>>();
+                assert_eq!(custom, vec![b"hello".to_vec()]);
+
+                // The custom extension with ALPN's type was left out.
+                let alpn = ch
+                    .extensions
+                    .iter()
+                    .filter(|ext| ext.get_type() == ExtensionType::ALProtocolNegotiation)
+                    .collect::<Vec<_>>();
+                assert_eq!(alpn.len(), 1);
+                assert!(!matches!(alpn[0], ClientExtension::Unknown(_)));
+            }
+        }
+    }
+
+    fn reply_to_custom_extension(msg: &mut Message) {
+        if let MessagePayload::Handshake(hs) = &mut msg.payload {
+            if let HandshakePayload::ServerHello(sh) = &mut hs.payload {
+                sh.extensions
+                    .push(ServerExtension::Unknown(UnknownExtension {
+                        typ: ExtensionType::Unknown(0xfe00),
+                        payload: Payload::new(b"world".to_vec()),
+                    }));
+            }
+        }
+    }
+
+    let mut client_config = make_client_config(KeyType::RSA);
+    client_config.set_protocol_versions(&[&rustls::version::TLS12]);
+    client_config.alpn_protocols = vec![b"h2".to_vec()];
+    client_config.custom_extensions = vec![
+        (0xfe00, b"hello".to_vec()),
+        (0x0010, b"ignored".to_vec()),
+    ];
+    let mut server_config = make_server_config(KeyType::RSA);
+    server_config.alpn_protocols = vec![b"h2".to_vec()];
+    let (mut client, mut server) = make_pair_for_configs(client_config, server_config);
+
+    transfer_altered(&mut client, check_client_hello, &mut server);
+    server.process_new_packets().unwrap();
+    assert_eq!(client.get_custom_extension(0xfe00), None);
+
+    transfer_altered(&mut server, reply_to_custom_extension, &mut client);
+    client.process_new_packets().unwrap();
+    assert_eq!(client.get_custom_extension(0xfe00), Some(&b"world"[..]));
+    assert_eq!(client.get_custom_extension(0xfe01), None);
+}
+
+/// Answers custom extension 0xfe00 with its payload reversed, and
+/// tries to send 0xfe01 unprompted.
+struct ReverseCustomExtension;
+
+impl rustls::HandlesCustomExtensions for ReverseCustomExtension {
+    fn handle_extensions(
+        &self,
+        _sni: Option<&rustls::DnsName>,
+        offered: &[(u16, &[u8])],
+    ) -> Vec<(u16, Vec<u8>)> {
+        let mut replies = vec![(0xfe01, b"unprompted".to_vec())];
+        for (typ, payload) in offered {
+            if *typ == 0xfe00 {
+                replies.push((0xfe00, payload.iter().rev().cloned().collect()));
+            }
+        }
+        replies
+    }
+}
+
+#[test]
+fn server_answers_custom_extensions() {
+    for version in rustls::ALL_VERSIONS {
+        let mut client_config = make_client_config(KeyType::RSA);
+        client_config.set_protocol_versions(&[version]);
+        client_config.custom_extensions = vec![(0xfe00, b"hello".to_vec())];
+        let mut server_config = make_server_config(KeyType::RSA);
+        server_config.custom_extension_handler = Some(Arc::new(ReverseCustomExtension));
+        let (mut client, mut server) = make_pair_for_configs(client_config, server_config);
+
+        do_handshake(&mut client, &mut server);
+        assert_eq!(client.get_custom_extension(0xfe00), Some(&b"olleh"[..]));
+        assert_eq!(client.get_custom_extension(0xfe01), None);
+    }
+}
+
+#[cfg(feature = "tls13")]
 #[test]
 fn test_server_rejects_clients_without_any_kx_group_overlap() {
     fn different_kx_group(msg: &mut Message) {
@@ -3041,7 +6875,369 @@ fn test_server_rejects_clients_without_any_kx_group_overlap() {
     );
 }
 
+/// Stands in for a secure element that only does P-256.
+struct OffloadedKeyExchange {
+    started: AtomicUsize,
+    completed: Arc<AtomicUsize>,
+}
+
+impl OffloadedKeyExchange {
+    fn new() -> Self {
+        OffloadedKeyExchange {
+            started: AtomicUsize::new(0),
+            completed: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+}
+
+struct OffloadedActiveKeyExchange {
+    inner: Box<dyn ActiveKeyExchange>,
+    completed: Arc<AtomicUsize>,
+}
+
+impl ProvidesKeyExchange for OffloadedKeyExchange {
+    fn start(
+        &self,
+        group: &'static SupportedKxGroup,
+    ) -> Option<Box<dyn ActiveKeyExchange>> {
+        if group.name != NamedGroup::secp256r1 {
+            return None;
+        }
+
+        self.started.fetch_add(1, Ordering::SeqCst);
+        let inner = RingKeyExchange.start(group)?;
+        Some(Box::new(OffloadedActiveKeyExchange {
+            inner,
+            completed: self.completed.clone(),
+        }))
+    }
+}
+
+impl ActiveKeyExchange for OffloadedActiveKeyExchange {
+    fn pub_key(&self) -> &[u8] {
+        self.inner.pub_key()
+    }
+
+    fn complete(self: Box<Self>, peer_pub_key: &[u8]) -> Option<Vec<u8>> {
+        self.completed.fetch_add(1, Ordering::SeqCst);
+        self.inner.complete(peer_pub_key)
+    }
+}
+
+#[test]
+fn key_exchange_can_be_offloaded() {
+    for version in rustls::ALL_VERSIONS {
+        let client_kx = Arc::new(OffloadedKeyExchange::new());
+        let server_kx = Arc::new(OffloadedKeyExchange::new());
+
+        let mut client_config = make_client_config(KeyType::RSA);
+        client_config.set_protocol_versions(&[version]);
+        client_config.kx_groups = vec![&rustls::kx_group::SECP256R1];
+        client_config.kx_provider = client_kx.clone();
+
+        let mut server_config = make_server_config(KeyType::RSA);
+        server_config.kx_provider = server_kx.clone();
+
+        let (mut client, mut server) =
+            make_pair_for_configs(client_config, server_config);
+        do_handshake(&mut client, &mut server);
+
+        for kx in &[client_kx, server_kx] {
+            assert_eq!(kx.started.load(Ordering::SeqCst), 1);
+            assert_eq!(kx.completed.load(Ordering::SeqCst), 1);
+        }
+    }
+}
+
+#[test]
+fn key_exchange_provider_refusing_group_fails_handshake() {
+    let mut client_config = make_client_config(KeyType::RSA);
+    client_config.kx_groups = vec![&rustls::kx_group::X25519];
+    client_config.kx_provider = Arc::new(OffloadedKeyExchange::new());
+
+    let (mut client, mut server) =
+        make_pair_for_configs(client_config, make_server_config(KeyType::RSA));
+    // no key share can be made, so none is offered and the server
+    // asks for one it cannot be given.
+    assert!(do_handshake_until_error(&mut client, &mut server).is_err());
+}
+
+struct CountingRandom {
+    calls: AtomicUsize,
+}
+
+impl ProvidesRandom for CountingRandom {
+    fn fill(&self, bytes: &mut [u8]) -> Result<(), GetRandomFailed> {
+        self.calls.fetch_add(1, Ordering::SeqCst);
+        RingRandom.fill(bytes)
+    }
+}
+
+struct BrokenRandom;
+
+impl ProvidesRandom for BrokenRandom {
+    fn fill(&self, _bytes: &mut [u8]) -> Result<(), GetRandomFailed> {
+        Err(GetRandomFailed)
+    }
+}
+
+#[test]
+fn random_material_comes_from_config() {
+    for version in rustls::ALL_VERSIONS {
+        let client_random = Arc::new(CountingRandom { calls: AtomicUsize::new(0) });
+        let server_random = Arc::new(CountingRandom { calls: AtomicUsize::new(0) });
+
+        let mut client_config = make_client_config(KeyType::RSA);
+        client_config.set_protocol_versions(&[version]);
+        client_config.random_provider = client_random.clone();
+
+        let mut server_config = make_server_config(KeyType::RSA);
+        server_config.random_provider = server_random.clone();
+
+        let (mut client, mut server) =
+            make_pair_for_configs(client_config, server_config);
+        do_handshake(&mut client, &mut server);
+
+        assert!(client_random.calls.load(Ordering::SeqCst) > 0);
+        assert!(server_random.calls.load(Ordering::SeqCst) > 0);
+    }
+}
+
+#[test]
+fn broken_random_provider_fails_handshake() {
+    let mut client_config = make_client_config(KeyType::RSA);
+    client_config.random_provider = Arc::new(BrokenRandom);
+    assert_eq!(
+        ClientSession::new(&Arc::new(client_config), dns_name("localhost")).err(),
+        Some(TlsError::FailedToGetRandomBytes)
+    );
+
+    let mut server_config = make_server_config(KeyType::RSA);
+    server_config.random_provider = Arc::new(BrokenRandom);
+    let (mut client, mut server) =
+        make_pair_for_configs(make_client_config(KeyType::RSA), server_config);
+    assert_eq!(
+        do_handshake_until_error(&mut client, &mut server),
+        Err(TLSErrorFromPeer::Server(TlsError::FailedToGetRandomBytes))
+    );
+}
+
+/// Move records from `left` to `right` until `left` has nothing
+/// more to say, appending them to `wire`.
+#[cfg(feature = "deterministic")]
+fn transfer_recording(left: &mut dyn Session, right: &mut dyn Session, wire: &mut Vec<u8>) {
+    let mut buf = Vec::new();
+    while left.wants_write() {
+        buf.clear();
+        left.write_tls(&mut buf).unwrap();
+        wire.extend_from_slice(&buf);
+
+        let mut rd = &buf[..];
+        while !rd.is_empty() {
+            right.read_tls(&mut rd).unwrap();
+        }
+        right.process_new_packets().unwrap();
+    }
+}
+
+/// Run a full handshake, then a resumption, between a client and
+/// server made deterministic with `seed`, and return every byte sent
+/// in either direction.
+#[cfg(feature = "deterministic")]
+fn deterministic_transcript(
+    kt: KeyType,
+    key: &sign::CertifiedKey,
+    version: &'static rustls::SupportedProtocolVersion,
+    seed: &[u8],
+    now: SystemTime,
+) -> Vec<u8> {
+    use rustls::deterministic;
+
+    let mut client_config = make_client_config(kt);
+    client_config.set_protocol_versions(&[version]);
+    deterministic::configure_client(&mut client_config, seed, now);
+
+    let mut server_config = make_server_config(kt);
+    server_config.cert_resolver = Arc::new(AlwaysResolves(key.clone()));
+    deterministic::configure_server(&mut server_config, seed, now);
+
+    let client_config = Arc::new(client_config);
+    let server_config = Arc::new(server_config);
+
+    let mut wire = Vec::new();
+    for _ in 0..2 {
+        let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
+        while client.is_handshaking() || server.is_handshaking() {
+            transfer_recording(&mut client, &mut server, &mut wire);
+            transfer_recording(&mut server, &mut client, &mut wire);
+        }
+
+        client.write_all(b"hello").unwrap();
+        transfer_recording(&mut client, &mut server, &mut wire);
+        check_read(&mut server, b"hello");
+        server.write_all(b"world").unwrap();
+        transfer_recording(&mut server, &mut client, &mut wire);
+        check_read(&mut client, b"world");
+    }
+    wire
+}
+
+#[cfg(feature = "deterministic")]
+#[test]
+fn deterministic_handshakes_are_reproducible() {
+    let now = SystemTime::now();
+
+    for kt in ALL_KEY_TYPES.iter() {
+        let key = rustls::deterministic::signing_key(&kt.get_key()).unwrap();
+        let key = sign::CertifiedKey::new(kt.get_chain(), Arc::new(key));
+
+        for version in rustls::ALL_VERSIONS {
+            let first = deterministic_transcript(*kt, &key, version, b"seed", now);
+            let second = deterministic_transcript(*kt, &key, version, b"seed", now);
+            assert_eq!(first, second);
+
+            let other = deterministic_transcript(*kt, &key, version, b"other seed", now);
+            assert_ne!(first, other);
+        }
+    }
+}
+
+struct FixedTime(Option<SystemTime>);
+
+impl TimeProvider for FixedTime {
+    fn current_time(&self) -> Option<SystemTime> {
+        self.0
+    }
+}
+
+#[test]
+fn client_validates_server_cert_at_configured_time() {
+    // 2500-01-01, long after the test certificates expire.
+    let far_future = UNIX_EPOCH + Duration::from_secs(16_725_225_600);
+
+    for client_config in AllClientVersions::new(make_client_config(KeyType::RSA)) {
+        let mut client_config = client_config;
+        client_config.time_provider = Arc::new(FixedTime(Some(far_future)));
+
+        let (mut client, mut server) =
+            make_pair_for_configs(client_config, make_server_config(KeyType::RSA));
+        assert_eq!(
+            do_handshake_until_error(&mut client, &mut server),
+            Err(TLSErrorFromPeer::Client(TlsError::InvalidCertificate(
+                CertificateError::Expired
+            )))
+        );
+    }
+}
+
+#[test]
+fn client_without_time_fails_handshake() {
+    for client_config in AllClientVersions::new(make_client_config(KeyType::RSA)) {
+        let mut client_config = client_config;
+        client_config.time_provider = Arc::new(FixedTime(None));
+
+        let (mut client, mut server) =
+            make_pair_for_configs(client_config, make_server_config(KeyType::RSA));
+        assert_eq!(
+            do_handshake_until_error(&mut client, &mut server),
+            Err(TLSErrorFromPeer::Client(TlsError::FailedToGetCurrentTime))
+        );
+    }
+}
+
+struct SettableTime(Mutex<SystemTime>);
+
+impl TimeProvider for SettableTime {
+    fn current_time(&self) -> Option<SystemTime> {
+        Some(*self.0.lock().unwrap())
+    }
+}
+
+#[test]
+fn client_expires_tickets_by_configured_time() {
+    let clock = Arc::new(SettableTime(Mutex::new(SystemTime::now())));
+    let mut client_config = make_client_config(KeyType::RSA);
+    client_config.time_provider = clock.clone();
+    let client_config = Arc::new(client_config);
+
+    let mut server_config = make_server_config(KeyType::RSA);
+    server_config.ticketer = rustls::Ticketer::new().unwrap();
+    let server_config = Arc::new(server_config);
+
+    // full handshake
+    let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
+    let (_, full_s2c) = do_handshake(&mut client, &mut server);
+
+    // resumed
+    let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
+    let (_, resume_s2c) = do_handshake(&mut client, &mut server);
+    assert!(resume_s2c < full_s2c);
+
+    // a week later, the ticket has expired
+    *clock.0.lock().unwrap() += Duration::from_secs(8 * 24 * 60 * 60);
+    let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
+    let (_, expired_s2c) = do_handshake(&mut client, &mut server);
+    assert_eq!(expired_s2c, full_s2c);
+}
+
 #[test]
 fn test_ownedtrustanchor_to_trust_anchor_is_public() {
     let _ = rustls::OwnedTrustAnchor::to_trust_anchor;
 }
+
+#[test]
+fn buffer_pool_is_shared_between_sessions() {
+    let pool = rustls::BufferPool::new(64);
+
+    let mut client_config = make_client_config(KeyType::RSA);
+    client_config.buffer_pool = Some(pool.clone());
+    let client_config = Arc::new(client_config);
+
+    let mut server_config = make_server_config(KeyType::RSA);
+    server_config.buffer_pool = Some(pool.clone());
+    let server_config = Arc::new(server_config);
+
+    let mut idle = Vec::new();
+    for _ in 0..3 {
+        let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
+        do_handshake(&mut client, &mut server);
+
+        client.write_all(b"hello").unwrap();
+        transfer(&mut client, &mut server);
+        server.process_new_packets().unwrap();
+        check_read(&mut server, b"hello");
+
+        drop((client, server));
+        idle.push(pool.idle_buffers());
+    }
+
+    // Later pairs reuse what earlier ones gave back, rather than
+    // adding more.
+    assert!(idle[0] >= 2);
+    assert_eq!(idle[0], idle[1]);
+    assert_eq!(idle[1], idle[2]);
+}
+
+#[test]
+fn established_session_gives_back_record_buffer() {
+    let pool = rustls::BufferPool::new(64);
+
+    let mut client_config = make_client_config(KeyType::RSA);
+    client_config.buffer_pool = Some(pool.clone());
+    let mut server_config = make_server_config(KeyType::RSA);
+    server_config.buffer_pool = Some(pool.clone());
+
+    let (mut client, mut server) = make_pair_for_configs(client_config, server_config);
+    do_handshake(&mut client, &mut server);
+
+    client.write_all(b"hello").unwrap();
+    transfer(&mut client, &mut server);
+    server.process_new_packets().unwrap();
+    check_read(&mut server, b"hello");
+
+    // the server already gave its record buffer back, so dropping
+    // it returns nothing more to the pool.
+    let idle = pool.idle_buffers();
+    drop(server);
+    assert_eq!(pool.idle_buffers(), idle);
+}
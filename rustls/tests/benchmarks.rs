@@ -2,15 +2,17 @@ use criterion::criterion_group;
 use criterion::criterion_main;
 /// Microbenchmarks go here.  Larger benchmarks of (e.g..) protocol
 /// performance go in examples/internal/bench.rs.
-use criterion::Criterion;
+use criterion::{BatchSize, BenchmarkId, Criterion, Throughput};
 
 #[allow(dead_code)]
 mod common;
 use crate::common::*;
 
-use rustls::{ServerSession, Session};
+use rustls::sign;
+use rustls::{ClientConfig, ServerConfig, ServerSession, Session};
+use rustls::{HandshakeKind, SupportedCipherSuite, ALL_CIPHERSUITES};
 
-use std::io;
+use std::io::{self, Read, Write};
 use std::sync::Arc;
 
 fn bench_ewouldblock(c: &mut Criterion) {
@@ -22,5 +24,134 @@ fn bench_ewouldblock(c: &mut Criterion) {
     });
 }
 
-criterion_group!(benches, bench_ewouldblock);
+fn key_type_name(kt: KeyType) -> &'static str {
+    match kt {
+        KeyType::RSA => "rsa",
+        KeyType::ECDSA => "ecdsa",
+        KeyType::ED25519 => "ed25519",
+        KeyType::RSA_PSS => "rsa-pss",
+    }
+}
+
+/// The key types whose certificates `suite` can be used with.
+fn key_types_for(suite: &SupportedCipherSuite) -> Vec<KeyType> {
+    ALL_KEY_TYPES
+        .iter()
+        .cloned()
+        .filter(|kt| {
+            let key = sign::any_supported_type(&kt.get_key()).unwrap();
+            suite.usable_for_sigalg(key.algorithm())
+        })
+        .collect()
+}
+
+fn make_configs_for_suite(
+    kt: KeyType,
+    suite: &'static SupportedCipherSuite,
+) -> (Arc<ClientConfig>, Arc<ServerConfig>) {
+    let mut client_config = make_client_config(kt);
+    client_config.ciphersuites = vec![suite];
+    (Arc::new(client_config), Arc::new(make_server_config(kt)))
+}
+
+fn bench_handshake(c: &mut Criterion) {
+    let mut group = c.benchmark_group("handshake");
+
+    for suite in ALL_CIPHERSUITES.iter() {
+        for kt in key_types_for(suite) {
+            let (client_config, server_config) = make_configs_for_suite(kt, suite);
+            let name = format!("{:?}/{}", suite.suite, key_type_name(kt));
+
+            group.bench_function(BenchmarkId::new("full", &name), |b| {
+                b.iter(|| {
+                    // A fresh client config each time, so there's
+                    // nothing to resume.
+                    let client_config = Arc::new((*client_config).clone());
+                    let (mut client, mut server) =
+                        make_pair_for_arc_configs(&client_config, &server_config);
+                    do_handshake(&mut client, &mut server);
+                })
+            });
+
+            // Prime the client's session cache.
+            let (mut client, mut server) =
+                make_pair_for_arc_configs(&client_config, &server_config);
+            do_handshake(&mut client, &mut server);
+
+            group.bench_function(BenchmarkId::new("resumed", &name), |b| {
+                b.iter(|| {
+                    let (mut client, mut server) =
+                        make_pair_for_arc_configs(&client_config, &server_config);
+                    do_handshake(&mut client, &mut server);
+                    assert_eq!(client.handshake_kind(), Some(HandshakeKind::Resumed));
+                })
+            });
+        }
+    }
+
+    group.finish();
+}
+
+const BULK_SIZE: usize = 1024 * 1024;
+
+fn bench_bulk(c: &mut Criterion) {
+    let mut group = c.benchmark_group("bulk");
+    group.throughput(Throughput::Bytes(BULK_SIZE as u64));
+    let plaintext = vec![0u8; BULK_SIZE];
+
+    for suite in ALL_CIPHERSUITES.iter() {
+        let kt = key_types_for(suite)[0];
+        let (client_config, server_config) = make_configs_for_suite(kt, suite);
+        let name = format!("{:?}", suite.suite);
+
+        let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
+        do_handshake(&mut client, &mut server);
+
+        group.bench_function(BenchmarkId::new("encrypt", &name), |b| {
+            b.iter(|| {
+                client.write_all(&plaintext).unwrap();
+                while client.wants_write() {
+                    client
+                        .write_tls(&mut io::sink())
+                        .unwrap();
+                }
+            })
+        });
+
+        group.bench_function(BenchmarkId::new("decrypt", &name), |b| {
+            b.iter_batched(
+                || {
+                    let (mut client, mut server) =
+                        make_pair_for_arc_configs(&client_config, &server_config);
+                    do_handshake(&mut client, &mut server);
+
+                    client.write_all(&plaintext).unwrap();
+                    let mut records = Vec::new();
+                    while client.wants_write() {
+                        client.write_tls(&mut records).unwrap();
+                    }
+                    (server, records)
+                },
+                |(mut server, records)| {
+                    let mut rd = &records[..];
+                    let mut buf = vec![0u8; BULK_SIZE];
+                    let mut received = 0;
+                    while !rd.is_empty() {
+                        server.read_tls(&mut rd).unwrap();
+                        server.process_new_packets().unwrap();
+                        received += server
+                            .read(&mut buf[received..])
+                            .unwrap();
+                    }
+                    assert_eq!(received, BULK_SIZE);
+                },
+                BatchSize::PerIteration,
+            )
+        });
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, bench_ewouldblock, bench_handshake, bench_bulk);
 criterion_main!(benches);
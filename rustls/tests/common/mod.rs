@@ -1,3 +1,4 @@
+use std::convert::TryFrom;
 use std::io;
 use std::sync::Arc;
 
@@ -5,21 +6,20 @@ use rustls;
 use rustls_pemfile;
 
 use rustls::internal::msgs::{codec::Codec, codec::Reader, message::Message};
-use rustls::{ProtocolVersion, DEFAULT_CIPHERSUITES};
+use rustls::DEFAULT_CIPHERSUITES;
 use rustls::Session;
 use rustls::TlsError;
-use rustls::{AllowAnyAuthenticatedClient, NoClientAuth, RootCertStore};
+use rustls::{AllowAnyAuthenticatedClient, CrlSet, NoClientAuth, RootCertStore};
 use rustls::{Certificate, PrivateKey};
 use rustls::{ClientConfig, ClientSession};
 use rustls::{ServerConfig, ServerSession};
 
 #[cfg(feature = "dangerous_configuration")]
 use rustls::{
-    ClientCertVerified, ClientCertVerifier, DistinguishedNames, SignatureScheme, WebPkiVerifier,
+    ClientCertVerified, ClientCertVerifier, DistinguishedNames, ServerCertVerified,
+    ServerCertVerifier, SignatureScheme, WebPkiVerifier,
 };
 
-use webpki;
-
 macro_rules! embed_files {
     (
         $(
@@ -51,11 +51,13 @@ embed_files! {
     (ECDSA_CLIENT_FULLCHAIN, "ecdsa", "client.fullchain");
     (ECDSA_CLIENT_KEY, "ecdsa", "client.key");
     (ECDSA_CLIENT_REQ, "ecdsa", "client.req");
+    (ECDSA_CLIENT_SEC1, "ecdsa", "client.sec1");
     (ECDSA_END_CERT, "ecdsa", "end.cert");
     (ECDSA_END_CHAIN, "ecdsa", "end.chain");
     (ECDSA_END_FULLCHAIN, "ecdsa", "end.fullchain");
     (ECDSA_END_KEY, "ecdsa", "end.key");
     (ECDSA_END_REQ, "ecdsa", "end.req");
+    (ECDSA_END_SEC1, "ecdsa", "end.sec1");
     (ECDSA_INTER_CERT, "ecdsa", "inter.cert");
     (ECDSA_INTER_KEY, "ecdsa", "inter.key");
     (ECDSA_INTER_REQ, "ecdsa", "inter.req");
@@ -97,6 +99,10 @@ embed_files! {
     (RSA_INTER_CERT, "rsa", "inter.cert");
     (RSA_INTER_KEY, "rsa", "inter.key");
     (RSA_INTER_REQ, "rsa", "inter.req");
+    (RSA_PSS_CLIENT_FULLCHAIN, "rsa-pss", "client.fullchain");
+    (RSA_PSS_CLIENT_KEY, "rsa-pss", "client.key");
+    (RSA_PSS_END_FULLCHAIN, "rsa-pss", "end.fullchain");
+    (RSA_PSS_END_KEY, "rsa-pss", "end.key");
 }
 
 pub fn transfer(left: &mut dyn Session, right: &mut dyn Session) -> usize {
@@ -161,10 +167,15 @@ where
 }
 
 #[derive(Clone, Copy, PartialEq)]
+#[allow(non_camel_case_types)]
 pub enum KeyType {
     RSA,
     ECDSA,
     ED25519,
+
+    /// id-RSASSA-PSS keys, issued by the RSA intermediate.  Not
+    /// in `ALL_KEY_TYPES`: they can only be used for some schemes.
+    RSA_PSS,
 }
 
 pub static ALL_KEY_TYPES: [KeyType; 3] = [KeyType::RSA, KeyType::ECDSA, KeyType::ED25519];
@@ -175,6 +186,8 @@ impl KeyType {
             KeyType::RSA => bytes_for("rsa", part),
             KeyType::ECDSA => bytes_for("ecdsa", part),
             KeyType::ED25519 => bytes_for("eddsa", part),
+            KeyType::RSA_PSS if part == "ca.cert" => bytes_for("rsa", part),
+            KeyType::RSA_PSS => bytes_for("rsa-pss", part),
         }
     }
 
@@ -190,14 +203,14 @@ impl KeyType {
             .clone())
     }
 
-    fn get_client_chain(&self) -> Vec<Certificate> {
+    pub fn get_client_chain(&self) -> Vec<Certificate> {
         rustls_pemfile::certs(&mut io::BufReader::new(self.bytes_for("client.fullchain"))).unwrap()
             .iter()
             .map(|v| Certificate(v.clone()))
             .collect()
     }
 
-    fn get_client_key(&self) -> PrivateKey {
+    pub fn get_client_key(&self) -> PrivateKey {
         PrivateKey(rustls_pemfile::pkcs8_private_keys(&mut io::BufReader::new(self.bytes_for("client.key"))).unwrap()
             [0]
         .clone())
@@ -233,12 +246,39 @@ pub fn make_server_config_with_mandatory_client_auth(kt: KeyType) -> ServerConfi
     cfg
 }
 
+pub fn make_server_config_with_client_crls(kt: KeyType, crls: CrlSet) -> ServerConfig {
+    let client_auth_roots = get_client_root_store(kt);
+
+    let client_auth = AllowAnyAuthenticatedClient::new_with_crls(client_auth_roots, crls);
+    let mut cfg = ServerConfig::new(NoClientAuth::new());
+    cfg.set_client_certificate_verifier(client_auth);
+    cfg.set_single_cert(kt.get_chain(), kt.get_key())
+        .unwrap();
+
+    cfg
+}
+
 pub fn make_client_config(kt: KeyType) -> ClientConfig {
+    make_client_config_with_ct_logs(kt, &[])
+}
+
+pub fn make_client_config_with_ct_logs(
+    kt: KeyType,
+    ct_logs: &'static [&'static sct::Log<'static>],
+) -> ClientConfig {
     let mut root_store = RootCertStore::empty();
     let mut rootbuf = io::BufReader::new(kt.bytes_for("ca.cert"));
     root_store
         .add_parsable_certificates(&rustls_pemfile::certs(&mut rootbuf).unwrap());
-    ClientConfig::new(root_store, &[], DEFAULT_CIPHERSUITES)
+    ClientConfig::new(root_store, ct_logs, DEFAULT_CIPHERSUITES)
+}
+
+pub fn make_client_config_with_crls(kt: KeyType, crls: CrlSet) -> ClientConfig {
+    let mut root_store = RootCertStore::empty();
+    let mut rootbuf = io::BufReader::new(kt.bytes_for("ca.cert"));
+    root_store
+        .add_parsable_certificates(&rustls_pemfile::certs(&mut rootbuf).unwrap());
+    ClientConfig::new_with_crls(root_store, &[], crls, DEFAULT_CIPHERSUITES)
 }
 
 pub fn make_client_config_with_auth(kt: KeyType) -> ClientConfig {
@@ -280,6 +320,13 @@ pub fn do_handshake(client: &mut ClientSession, server: &mut ServerSession) -> (
     (to_server, to_client)
 }
 
+static CLIENT_VERSIONS: &[&rustls::SupportedProtocolVersion] = &[
+    #[cfg(feature = "tls12")]
+    &rustls::version::TLS12,
+    #[cfg(feature = "tls13")]
+    &rustls::version::TLS13,
+];
+
 pub struct AllClientVersions {
     client_config: ClientConfig,
     index: usize,
@@ -298,20 +345,12 @@ impl Iterator for AllClientVersions {
     type Item = ClientConfig;
 
     fn next(&mut self) -> Option<ClientConfig> {
-        let mut config = self.client_config.clone();
+        let version = CLIENT_VERSIONS.get(self.index)?;
         self.index += 1;
 
-        match self.index {
-            1 => {
-                config.versions = vec![ProtocolVersion::TLSv1_2];
-                Some(config)
-            }
-            2 => {
-                config.versions = vec![ProtocolVersion::TLSv1_3];
-                Some(config)
-            }
-            _ => None,
-        }
+        let mut config = self.client_config.clone();
+        config.set_protocol_versions(&[*version]);
+        Some(config)
     }
 }
 
@@ -325,7 +364,7 @@ pub struct MockClientVerifier {
 
 #[cfg(feature = "dangerous_configuration")]
 impl ClientCertVerifier for MockClientVerifier {
-    fn client_auth_mandatory(&self, sni: Option<&webpki::DNSName>) -> Option<bool> {
+    fn client_auth_mandatory(&self, sni: Option<&rustls::DnsName>) -> Option<bool> {
         // This is just an added 'test' to make sure we plumb through the SNI,
         // although its valid for it to be None, its just our tests should (as of now) always provide it
         assert!(sni.is_some());
@@ -334,7 +373,7 @@ impl ClientCertVerifier for MockClientVerifier {
 
     fn client_auth_root_subjects(
         &self,
-        sni: Option<&webpki::DNSName>,
+        sni: Option<&rustls::DnsName>,
     ) -> Option<DistinguishedNames> {
         assert!(sni.is_some());
         self.subjects.as_ref().cloned()
@@ -344,7 +383,7 @@ impl ClientCertVerifier for MockClientVerifier {
         &self,
         _end_entity: &Certificate,
         _intermediates: &[Certificate],
-        sni: Option<&webpki::DNSName>,
+        sni: Option<&rustls::DnsName>,
         _now: std::time::SystemTime,
     ) -> Result<ClientCertVerified, TlsError> {
         assert!(sni.is_some());
@@ -360,6 +399,27 @@ impl ClientCertVerifier for MockClientVerifier {
     }
 }
 
+#[cfg(feature = "dangerous_configuration")]
+pub struct MockServerVerifier {
+    pub expected_ocsp_response: Vec<u8>,
+}
+
+#[cfg(feature = "dangerous_configuration")]
+impl ServerCertVerifier for MockServerVerifier {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &Certificate,
+        _intermediates: &[Certificate],
+        _server_name: &rustls::ServerName,
+        _scts: &mut dyn Iterator<Item = &[u8]>,
+        ocsp_response: &[u8],
+        _now: std::time::SystemTime,
+    ) -> Result<ServerCertVerified, TlsError> {
+        assert_eq!(ocsp_response, &self.expected_ocsp_response[..]);
+        Ok(ServerCertVerified::assertion())
+    }
+}
+
 #[derive(PartialEq, Debug)]
 pub enum TLSErrorFromPeer {
     Client(TlsError),
@@ -415,8 +475,8 @@ pub fn do_handshake_until_both_error(
     }
 }
 
-pub fn dns_name(name: &'static str) -> webpki::DNSNameRef<'_> {
-    webpki::DNSNameRef::try_from_ascii_str(name).unwrap()
+pub fn dns_name(name: &'static str) -> rustls::ServerName {
+    rustls::ServerName::try_from(name).unwrap()
 }
 
 pub struct FailsReads {
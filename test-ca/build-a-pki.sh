@@ -2,7 +2,7 @@
 
 set -xe
 
-rm -rf rsa/ ecdsa/ eddsa/
+rm -rf rsa/ rsa-pss/ ecdsa/ eddsa/
 mkdir -p rsa/ ecdsa/ eddsa/
 
 openssl req -nodes \
@@ -88,6 +88,14 @@ openssl req -nodes \
           -days 2000 \
           -subj "/CN=ponytown client"
 
+openssl ec \
+          -in ecdsa/end.key \
+          -out ecdsa/end.sec1
+
+openssl ec \
+          -in ecdsa/client.key \
+          -out ecdsa/client.sec1
+
 # eddsa
 
 # TODO: add support for Ed448
@@ -174,3 +182,58 @@ for kt in rsa ecdsa eddsa ; do
 
   openssl asn1parse -in $kt/ca.cert -out $kt/ca.der > /dev/null
 done
+
+# rsa-pss: end-entity keys with an id-RSASSA-PSS subjectPublicKeyInfo,
+# issued by the rsa intermediate.
+mkdir -p rsa-pss/
+
+openssl genpkey -algorithm RSA-PSS \
+          -pkeyopt rsa_keygen_bits:2048 \
+          -out rsa-pss/end.key
+
+openssl req -nodes \
+          -new \
+          -key rsa-pss/end.key \
+          -out rsa-pss/end.req \
+          -sha256 \
+          -batch \
+          -subj "/CN=testserver.com"
+
+# the client key is restricted to SHA-384 by its parameters.
+openssl genpkey -algorithm RSA-PSS \
+          -pkeyopt rsa_keygen_bits:2048 \
+          -pkeyopt rsa_pss_keygen_md:sha384 \
+          -pkeyopt rsa_pss_keygen_mgf1_md:sha384 \
+          -pkeyopt rsa_pss_keygen_saltlen:48 \
+          -out rsa-pss/client.key
+
+openssl req -nodes \
+          -new \
+          -key rsa-pss/client.key \
+          -out rsa-pss/client.req \
+          -sha384 \
+          -batch \
+          -subj "/CN=ponytown client"
+
+openssl x509 -req \
+          -in rsa-pss/end.req \
+          -out rsa-pss/end.cert \
+          -CA rsa/inter.cert \
+          -CAkey rsa/inter.key \
+          -sha256 \
+          -days 2000 \
+          -set_serial 457 \
+          -extensions v3_end -extfile openssl.cnf
+
+openssl x509 -req \
+          -in rsa-pss/client.req \
+          -out rsa-pss/client.cert \
+          -CA rsa/inter.cert \
+          -CAkey rsa/inter.key \
+          -sha256 \
+          -days 2000 \
+          -set_serial 790 \
+          -extensions v3_client -extfile openssl.cnf
+
+cat rsa-pss/end.cert rsa/inter.cert rsa/ca.cert > rsa-pss/end.fullchain
+cat rsa-pss/client.cert rsa/inter.cert rsa/ca.cert > rsa-pss/client.fullchain